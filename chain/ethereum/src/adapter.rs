@@ -21,6 +21,7 @@ use graph::{
 
 use crate::capabilities::NodeCapabilities;
 use crate::data_source::BlockHandlerFilter;
+use crate::trigger::{EthereumBlockTriggerType, EthereumTrigger};
 use crate::{data_source::DataSource, Chain};
 
 pub type EventSignature = H256;
@@ -132,6 +133,11 @@ pub(crate) struct EthereumLogFilter {
 
     // Event sigs with no associated address, matching on all addresses.
     wildcard_events: HashSet<EventSignature>,
+
+    // The (contract, event sig) pairs whose handlers want the receipt of
+    // the transaction that emitted the event; a contract of `None` stands
+    // for all contracts
+    events_with_receipts: HashSet<(Option<Address>, EventSignature)>,
 }
 
 impl EthereumLogFilter {
@@ -157,9 +163,31 @@ impl EthereumLogFilter {
         }
     }
 
+    /// Check if a handler for the specified `Log` needs the receipt of the
+    /// transaction that emitted it.
+    pub fn requires_transaction_receipt(&self, log: &Log) -> bool {
+        match log.topics.first() {
+            None => false,
+            Some(sig) => {
+                self.events_with_receipts
+                    .contains(&(Some(log.address), *sig))
+                    || self.events_with_receipts.contains(&(None, *sig))
+            }
+        }
+    }
+
+    /// Whether any handler needs transaction receipts.
+    pub fn requires_transaction_receipts(&self) -> bool {
+        !self.events_with_receipts.is_empty()
+    }
+
     pub fn from_data_sources<'a>(iter: impl IntoIterator<Item = &'a DataSource>) -> Self {
         let mut this = EthereumLogFilter::default();
         for ds in iter {
+            for handler in ds.mapping.event_handlers.iter().filter(|e| e.receipt) {
+                this.events_with_receipts
+                    .insert((ds.source.address, handler.topic0()));
+            }
             for event_sig in ds.mapping.event_handlers.iter().map(|e| e.topic0()) {
                 match ds.source.address {
                     Some(contract) => {
@@ -184,11 +212,13 @@ impl EthereumLogFilter {
         let EthereumLogFilter {
             contracts_and_events_graph,
             wildcard_events,
+            events_with_receipts,
         } = other;
         for (s, t, ()) in contracts_and_events_graph.all_edges() {
             self.contracts_and_events_graph.add_edge(s, t, ());
         }
         self.wildcard_events.extend(wildcard_events);
+        self.events_with_receipts.extend(events_with_receipts);
     }
 
     /// An empty filter is one that never matches.
@@ -197,6 +227,7 @@ impl EthereumLogFilter {
         let EthereumLogFilter {
             contracts_and_events_graph,
             wildcard_events,
+            events_with_receipts: _,
         } = self;
         contracts_and_events_graph.edge_count() == 0 && wildcard_events.is_empty()
     }
@@ -390,6 +421,9 @@ impl From<EthereumBlockFilter> for EthereumCallFilter {
 #[derive(Clone, Debug, Default)]
 pub(crate) struct EthereumBlockFilter {
     pub contract_addresses: HashSet<(BlockNumber, Address)>,
+    /// Addresses for which only blocks with a transaction sent to them
+    /// trigger the block handler
+    pub transaction_addresses: HashSet<(BlockNumber, Address)>,
     pub trigger_every_block: bool,
 }
 
@@ -408,6 +442,14 @@ impl EthereumBlockFilter {
                         _ => false,
                     });
 
+                let has_block_handler_with_transaction_filter = data_source
+                    .mapping
+                    .block_handlers
+                    .iter()
+                    .any(|block_handler| {
+                        block_handler.filter == Some(BlockHandlerFilter::Transaction)
+                    });
+
                 let has_block_handler_without_filter = data_source
                     .mapping
                     .block_handlers
@@ -427,6 +469,16 @@ impl EthereumBlockFilter {
                     } else {
                         HashSet::default()
                     },
+                    transaction_addresses: if has_block_handler_with_transaction_filter {
+                        vec![(
+                            data_source.source.start_block,
+                            data_source.source.address.unwrap().to_owned(),
+                        )]
+                        .into_iter()
+                        .collect()
+                    } else {
+                        HashSet::default()
+                    },
                 });
                 filter_opt
             })
@@ -453,6 +505,57 @@ impl EthereumBlockFilter {
                 addresses
             },
         );
+        for (start_block, address) in other.transaction_addresses {
+            let existing = self
+                .transaction_addresses
+                .iter()
+                .find(|(_, existing)| *existing == address)
+                .cloned();
+            match existing {
+                Some((existing_start, _)) if existing_start <= start_block => {}
+                Some(existing) => {
+                    self.transaction_addresses.remove(&existing);
+                    self.transaction_addresses.insert((start_block, address));
+                }
+                None => {
+                    self.transaction_addresses.insert((start_block, address));
+                }
+            }
+        }
+    }
+
+    /// The block triggers for the transactions in `block` that were sent to
+    /// one of the addresses with a transaction filter
+    pub fn transaction_triggers(&self, block: &LightEthereumBlock) -> Vec<EthereumTrigger> {
+        if self.transaction_addresses.is_empty() {
+            return vec![];
+        }
+        let number = match block.number {
+            Some(number) => number.as_u64() as BlockNumber,
+            None => return vec![],
+        };
+        let ptr = BlockPtr::from(block);
+        let mut addresses: Vec<Address> = block
+            .transactions
+            .iter()
+            .filter_map(|tx| tx.to)
+            .filter(|to| {
+                self.transaction_addresses
+                    .iter()
+                    .any(|(start_block, address)| address == to && *start_block <= number)
+            })
+            .collect();
+        addresses.sort();
+        addresses.dedup();
+        addresses
+            .into_iter()
+            .map(|address| {
+                EthereumTrigger::Block(
+                    ptr.clone(),
+                    EthereumBlockTriggerType::WithTransactionTo(address),
+                )
+            })
+            .collect()
     }
 
     fn requires_traces(&self) -> bool {
@@ -464,6 +567,8 @@ impl EthereumBlockFilter {
 pub struct ProviderEthRpcMetrics {
     request_duration: Box<HistogramVec>,
     errors: Box<CounterVec>,
+    throttled: Box<CounterVec>,
+    queued: Box<CounterVec>,
 }
 
 impl ProviderEthRpcMetrics {
@@ -483,9 +588,26 @@ impl ProviderEthRpcMetrics {
                 vec![String::from("method"), String::from("provider")],
             )
             .unwrap();
+        let throttled = registry
+            .new_counter_vec(
+                "eth_rpc_throttled",
+                "Counts eth rpc requests that were delayed by the provider's rate limit",
+                vec![String::from("provider")],
+            )
+            .unwrap();
+        let queued = registry
+            .new_counter_vec(
+                "eth_rpc_queued",
+                "Counts eth rpc requests that had to wait because the provider had \
+                 the maximum number of requests in flight",
+                vec![String::from("provider")],
+            )
+            .unwrap();
         Self {
             request_duration,
             errors,
+            throttled,
+            queued,
         }
     }
 
@@ -498,6 +620,14 @@ impl ProviderEthRpcMetrics {
     pub fn add_error(&self, method: &str, provider: &str) {
         self.errors.with_label_values(&[method, provider]).inc();
     }
+
+    pub fn add_throttled(&self, provider: &str) {
+        self.throttled.with_label_values(&[provider]).inc();
+    }
+
+    pub fn add_queued(&self, provider: &str) {
+        self.queued.with_label_values(&[provider]).inc();
+    }
 }
 
 #[derive(Clone)]
@@ -644,11 +774,12 @@ pub trait EthereumAdapter: Send + Sync + 'static {
 
 #[cfg(test)]
 mod tests {
-    use super::EthereumCallFilter;
+    use super::{EthereumBlockFilter, EthereumCallFilter, EthereumLogFilter};
+    use crate::trigger::{EthereumBlockTriggerType, EthereumTrigger};
 
     use graph::prelude::web3::types::Address;
-    use graph::prelude::EthereumCall;
-    use web3::types::Bytes;
+    use graph::prelude::{EthereumCall, LightEthereumBlock};
+    use web3::types::{Bytes, Log, Transaction, H256, U64};
 
     use std::collections::{HashMap, HashSet};
     use std::iter::FromIterator;
@@ -740,4 +871,95 @@ mod tests {
             Some(&(1, HashSet::from_iter(vec![[1u8; 4]])))
         );
     }
+
+    #[test]
+    fn log_filter_requires_receipts_for_handlers_that_ask() {
+        let address = |id: u64| Address::from_low_u64_be(id);
+        let log = |address: Address, sig: H256| Log {
+            address,
+            topics: vec![sig],
+            data: Bytes::default(),
+            block_hash: None,
+            block_number: None,
+            transaction_hash: None,
+            transaction_index: None,
+            log_index: None,
+            transaction_log_index: None,
+            log_type: None,
+            removed: None,
+        };
+        let transfer = H256::from_low_u64_be(1);
+        let approval = H256::from_low_u64_be(2);
+
+        let filter = EthereumLogFilter {
+            events_with_receipts: HashSet::from_iter(vec![
+                (Some(address(1)), transfer),
+                (None, approval),
+            ]),
+            ..Default::default()
+        };
+
+        assert!(filter.requires_transaction_receipts());
+        assert!(filter.requires_transaction_receipt(&log(address(1), transfer)));
+        assert!(!filter.requires_transaction_receipt(&log(address(2), transfer)));
+        assert!(filter.requires_transaction_receipt(&log(address(2), approval)));
+        assert!(!EthereumLogFilter::default().requires_transaction_receipts());
+    }
+
+    #[test]
+    fn transaction_triggers_for_block_filter() {
+        let address = |id: u64| Address::from_low_u64_be(id);
+        let tx = |to: Option<Address>| Transaction {
+            to,
+            ..Default::default()
+        };
+        let block = |number: u64, txs: Vec<Transaction>| LightEthereumBlock {
+            hash: Some(H256::from_low_u64_be(number)),
+            number: Some(U64::from(number)),
+            transactions: txs,
+            ..Default::default()
+        };
+        let with_transaction_to = |triggers: Vec<EthereumTrigger>| {
+            triggers
+                .into_iter()
+                .map(|trigger| match trigger {
+                    EthereumTrigger::Block(_, EthereumBlockTriggerType::WithTransactionTo(to)) => {
+                        to
+                    }
+                    _ => panic!("unexpected trigger {:?}", trigger),
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let filter = EthereumBlockFilter {
+            transaction_addresses: HashSet::from_iter(vec![(0, address(1)), (10, address(2))]),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            vec![address(1)],
+            with_transaction_to(filter.transaction_triggers(&block(
+                5,
+                vec![
+                    tx(Some(address(1))),
+                    tx(Some(address(1))),
+                    tx(Some(address(3)))
+                ]
+            ))),
+            "several transactions to the same address trigger once"
+        );
+        assert!(
+            filter
+                .transaction_triggers(&block(5, vec![tx(Some(address(2))), tx(None)]))
+                .is_empty(),
+            "transactions before the start block and contract creations are ignored"
+        );
+        assert_eq!(
+            vec![address(1), address(2)],
+            with_transaction_to(filter.transaction_triggers(&block(
+                10,
+                vec![tx(Some(address(2))), tx(Some(address(1)))]
+            ))),
+        );
+    }
 }
@@ -1,4 +1,4 @@
-use anyhow::{Context, Error};
+use anyhow::{anyhow, Context, Error};
 use graph::blockchain::BlockchainKind;
 use graph::data::subgraph::UnifiedMappingApiVersion;
 use graph::firehose::endpoints::FirehoseNetworkEndpoints;
@@ -22,14 +22,15 @@ use graph::{
     firehose::bstream,
     log::factory::{ComponentLoggerConfig, ElasticComponentLoggerConfig},
     prelude::{
-        async_trait, error, lazy_static, o, serde_json as json, web3::types::H256, BlockNumber,
-        ChainStore, EthereumBlockWithCalls, Future01CompatExt, Logger, LoggerFactory,
+        async_trait, error, futures03, lazy_static, o, serde_json as json, web3::types::H256,
+        BlockNumber, ChainStore, EthereumBlockWithCalls, Future01CompatExt, Logger, LoggerFactory,
         MetricsRegistry, NodeId, SubgraphStore,
     },
 };
 use prost::Message;
 use std::collections::HashSet;
 use std::iter::FromIterator;
+use std::ops::Range;
 use std::sync::Arc;
 
 use crate::data_source::DataSourceTemplate;
@@ -41,7 +42,7 @@ use crate::{
     data_source::{DataSource, UnresolvedDataSource},
     ethereum_adapter::{
         blocks_with_triggers, get_calls, parse_block_triggers, parse_call_triggers,
-        parse_log_triggers,
+        parse_log_triggers, BLOCK_BATCH_SIZE,
     },
     SubgraphEthRpcMetrics, TriggerFilter,
 };
@@ -323,7 +324,6 @@ impl Blockchain for Chain {
     }
 
     fn ingestor_adapter(&self) -> Arc<Self::IngestorAdapter> {
-        let eth_adapter = self.eth_adapters.cheapest().unwrap().clone();
         let logger = self
             .logger_factory
             .component_logger(
@@ -334,10 +334,10 @@ impl Blockchain for Chain {
                     }),
                 }),
             )
-            .new(o!("provider" => eth_adapter.provider().to_string()));
+            .new(o!("network" => self.name.clone()));
 
         let adapter = IngestorAdapter {
-            eth_adapter,
+            eth_adapters: self.eth_adapters.cheap_clone(),
             logger,
             ancestor_count: self.ancestor_count,
             chain_store: self.chain_store.clone(),
@@ -529,6 +529,10 @@ impl TriggersAdapterTrait<Chain> for TriggersAdapter {
         }))
     }
 
+    fn ancestor_ptr(&self, ptr: BlockPtr, offset: BlockNumber) -> Result<Option<BlockPtr>, Error> {
+        self.chain_store.ancestor_ptr(ptr, offset)
+    }
+
     async fn parent_ptr(&self, block: &BlockPtr) -> Result<Option<BlockPtr>, Error> {
         use futures::stream::Stream;
         use graph::prelude::LightEthereumBlockExt;
@@ -547,6 +551,19 @@ impl TriggersAdapterTrait<Chain> for TriggersAdapter {
 
         Ok(blocks[0].parent_ptr())
     }
+
+    async fn transaction_block(&self, hash: &[u8]) -> Result<Option<BlockPtr>, Error> {
+        if hash.len() != H256::len_bytes() {
+            return Err(anyhow!(
+                "a transaction hash must have {} bytes, but has {}",
+                H256::len_bytes(),
+                hash.len()
+            ));
+        }
+        self.eth_adapter
+            .transaction_block(H256::from_slice(hash))
+            .await
+    }
 }
 
 pub struct FirehoseMapper {}
@@ -636,10 +653,41 @@ impl FirehoseMapper {
 pub struct IngestorAdapter {
     logger: Logger,
     ancestor_count: i32,
-    eth_adapter: Arc<EthereumAdapter>,
+    eth_adapters: Arc<EthereumNetworkAdapters>,
     chain_store: Arc<dyn ChainStore>,
 }
 
+impl IngestorAdapter {
+    /// The adapter to use for the next request. This is chosen anew every
+    /// time so that the ingestor moves to another provider when the one it
+    /// was using becomes unhealthy
+    fn eth_adapter(&self) -> Arc<EthereumAdapter> {
+        // Unwrap: a chain always has at least one adapter
+        self.eth_adapters.cheapest().unwrap()
+    }
+
+    /// Load the receipts for `block` and store it in the database
+    async fn store_block(&self, block: LightEthereumBlock) -> Result<(), IngestorError> {
+        let ethereum_block = self
+            .eth_adapter()
+            .load_full_block(&self.logger, block)
+            .compat()
+            .await?;
+
+        // We need something that implements `Block` to store the block; the
+        // store does not care whether the block is final or not
+        let ethereum_block = BlockFinality::NonFinal(EthereumBlockWithCalls {
+            ethereum_block,
+            calls: None,
+        });
+
+        self.chain_store
+            .upsert_block(Arc::new(ethereum_block))
+            .await?;
+        Ok(())
+    }
+}
+
 #[async_trait]
 impl IngestorAdapterTrait<Chain> for IngestorAdapter {
     fn logger(&self) -> &Logger {
@@ -651,11 +699,24 @@ impl IngestorAdapterTrait<Chain> for IngestorAdapter {
     }
 
     async fn latest_block(&self) -> Result<BlockPtr, IngestorError> {
-        self.eth_adapter
-            .latest_block_header(&self.logger)
-            .compat()
-            .await
-            .map(|block| block.into())
+        let eth_adapter = self.eth_adapter();
+        let res = eth_adapter.latest_block_header(&self.logger).compat().await;
+        match res {
+            Ok(_) => self.eth_adapters.report_success(eth_adapter.provider()),
+            Err(_) => self.eth_adapters.report_failure(eth_adapter.provider()),
+        }
+        res.map(|block| block.into())
+    }
+
+    async fn new_heads(
+        &self,
+    ) -> Result<Option<futures03::stream::BoxStream<'static, Result<BlockPtr, Error>>>, Error> {
+        let eth_adapter = self.eth_adapter();
+        let res = eth_adapter.new_heads().await;
+        if res.is_err() {
+            self.eth_adapters.report_failure(eth_adapter.provider());
+        }
+        res
     }
 
     async fn ingest_block(
@@ -667,28 +728,14 @@ impl IngestorAdapterTrait<Chain> for IngestorAdapter {
 
         // Get the fully populated block
         let block = self
-            .eth_adapter
+            .eth_adapter()
             .block_by_hash(&self.logger, block_hash)
             .compat()
             .await?
             .ok_or_else(|| IngestorError::BlockUnavailable(block_hash))?;
-        let ethereum_block = self
-            .eth_adapter
-            .load_full_block(&self.logger, block)
-            .compat()
-            .await?;
-
-        // We need something that implements `Block` to store the block; the
-        // store does not care whether the block is final or not
-        let ethereum_block = BlockFinality::NonFinal(EthereumBlockWithCalls {
-            ethereum_block,
-            calls: None,
-        });
 
         // Store it in the database and try to advance the chain head pointer
-        self.chain_store
-            .upsert_block(Arc::new(ethereum_block))
-            .await?;
+        self.store_block(block).await?;
 
         self.chain_store
             .cheap_clone()
@@ -701,11 +748,25 @@ impl IngestorAdapterTrait<Chain> for IngestorAdapter {
             })
     }
 
-    fn chain_head_ptr(&self) -> Result<Option<BlockPtr>, Error> {
-        self.chain_store.chain_head_ptr()
+    async fn prefetch_blocks(&self, numbers: Range<BlockNumber>) -> Result<(), IngestorError> {
+        use futures03::{StreamExt, TryStreamExt};
+
+        let blocks = numbers.map(|number| async move {
+            let block = self
+                .eth_adapter()
+                .block_by_number(&self.logger, number)
+                .compat()
+                .await?
+                .ok_or_else(|| anyhow!("block {} not found", number))?;
+            self.store_block(block).await
+        });
+        futures03::stream::iter(blocks)
+            .buffer_unordered(*BLOCK_BATCH_SIZE)
+            .try_collect()
+            .await
     }
 
-    fn cleanup_cached_blocks(&self) -> Result<Option<(i32, usize)>, Error> {
-        self.chain_store.cleanup_cached_blocks(self.ancestor_count)
+    fn chain_head_ptr(&self) -> Result<Option<BlockPtr>, Error> {
+        self.chain_store.chain_head_ptr()
     }
 }
@@ -6,7 +6,7 @@ use graph::components::store::StoredDynamicDataSource;
 use graph::prelude::futures03::future::try_join;
 use graph::prelude::futures03::stream::FuturesOrdered;
 use graph::prelude::{Entity, Link, SubgraphManifestValidationError};
-use graph::slog::{o, trace};
+use graph::slog::{debug, o, trace};
 use std::collections::BTreeMap;
 use std::str::FromStr;
 use std::{convert::TryFrom, sync::Arc};
@@ -22,7 +22,7 @@ use graph::{
     },
 };
 
-use graph::data::subgraph::{calls_host_fn, DataSourceContext, Source};
+use graph::data::subgraph::{calls_host_fn, DataSourceContext, Source, API_VERSION_0_0_7};
 
 use crate::chain::Chain;
 use crate::trigger::{EthereumBlockTriggerType, EthereumTrigger, MappingTrigger};
@@ -64,6 +64,27 @@ impl blockchain::DataSource<Chain> for DataSource {
         self.match_and_decode(trigger, block, logger)
     }
 
+    fn declared_handler(&self, trigger: &EthereumTrigger) -> Option<String> {
+        if !self.matches_trigger_address(trigger) {
+            return None;
+        }
+        match trigger {
+            EthereumTrigger::Block(_, trigger_type) => self
+                .handler_for_block(trigger_type)
+                .map(|handler| handler.handler),
+            EthereumTrigger::Log(log, _) => self
+                .handlers_for_log(log)
+                .ok()?
+                .into_iter()
+                .next()
+                .map(|handler| handler.handler),
+            EthereumTrigger::Call(call) => self
+                .handler_for_call(call)
+                .ok()?
+                .map(|handler| handler.handler),
+        }
+    }
+
     fn name(&self) -> &str {
         &self.name
     }
@@ -107,6 +128,7 @@ impl blockchain::DataSource<Chain> for DataSource {
             && name == &other.name
             && source == &other.source
             && mapping.abis == other.mapping.abis
+            && mapping.fallback_abis == other.mapping.fallback_abis
             && mapping.event_handlers == other.mapping.event_handlers
             && mapping.call_handlers == other.mapping.call_handlers
             && mapping.block_handlers == other.mapping.block_handlers
@@ -179,22 +201,37 @@ impl blockchain::DataSource<Chain> for DataSource {
         let has_too_many_block_handlers = {
             let mut non_filtered_block_handler_count = 0;
             let mut call_filtered_block_handler_count = 0;
+            let mut transaction_filtered_block_handler_count = 0;
             self.mapping
                 .block_handlers
                 .iter()
-                .for_each(|block_handler| {
-                    if block_handler.filter.is_none() {
-                        non_filtered_block_handler_count += 1
-                    } else {
-                        call_filtered_block_handler_count += 1
+                .for_each(|block_handler| match block_handler.filter {
+                    None => non_filtered_block_handler_count += 1,
+                    Some(BlockHandlerFilter::Call) => call_filtered_block_handler_count += 1,
+                    Some(BlockHandlerFilter::Transaction) => {
+                        transaction_filtered_block_handler_count += 1
                     }
                 });
-            non_filtered_block_handler_count > 1 || call_filtered_block_handler_count > 1
+            non_filtered_block_handler_count > 1
+                || call_filtered_block_handler_count > 1
+                || transaction_filtered_block_handler_count > 1
         };
         if has_too_many_block_handlers {
             errors.push(anyhow!("data source has duplicated block handlers"));
         }
 
+        // Transaction receipts are only part of events from apiVersion 0.0.7 on
+        if self.mapping.api_version < API_VERSION_0_0_7 {
+            for handler in self.mapping.event_handlers.iter().filter(|h| h.receipt) {
+                errors.push(anyhow!(
+                    "event handler `{}` asks for transaction receipts, which requires \
+                     `apiVersion` {} or later",
+                    handler.handler,
+                    API_VERSION_0_0_7
+                ));
+            }
+        }
+
         errors
     }
 
@@ -288,15 +325,21 @@ impl DataSource {
                 .iter()
                 .find(move |handler| handler.filter == Some(BlockHandlerFilter::Call))
                 .cloned(),
+            EthereumBlockTriggerType::WithTransactionTo(_address) => self
+                .mapping
+                .block_handlers
+                .iter()
+                .find(move |handler| handler.filter == Some(BlockHandlerFilter::Transaction))
+                .cloned(),
         }
     }
 
-    /// Returns the contract event with the given signature, if it exists. A an event from the ABI
+    /// Returns the event with the given signature in `abi`, if it exists. A an event from the ABI
     /// will be matched if:
     /// 1. An event signature is equal to `signature`.
     /// 2. There are no equal matches, but there is exactly one event that equals `signature` if all
     ///    `indexed` modifiers are removed from the parameters.
-    fn contract_event_with_signature(&self, signature: &str) -> Option<&Event> {
+    fn event_with_signature<'a>(abi: &'a MappingABI, signature: &str) -> Option<&'a Event> {
         // Returns an `Event(uint256,address)` signature for an event, without `indexed` hints.
         fn ambiguous_event_signature(event: &Event) -> String {
             format!(
@@ -356,8 +399,7 @@ impl DataSource {
             }
         }
 
-        self.contract_abi
-            .contract
+        abi.contract
             .events()
             .find(|event| event_signature(event) == signature)
             .or_else(|| {
@@ -373,8 +415,7 @@ impl DataSource {
                 let parens = signature.find('(').unwrap_or(0);
                 let event_name = &signature[0..parens];
 
-                let matching_events = self
-                    .contract_abi
+                let matching_events = abi
                     .contract
                     .events()
                     .filter(|event| event.name == event_name)
@@ -429,8 +470,11 @@ impl DataSource {
 
         let trigger_address = match trigger {
             EthereumTrigger::Block(_, EthereumBlockTriggerType::WithCallTo(address)) => address,
+            EthereumTrigger::Block(_, EthereumBlockTriggerType::WithTransactionTo(address)) => {
+                address
+            }
             EthereumTrigger::Call(call) => &call.to,
-            EthereumTrigger::Log(log) => &log.address,
+            EthereumTrigger::Log(log, _) => &log.address,
 
             // Unfiltered block triggers match any data source address.
             EthereumTrigger::Block(_, EthereumBlockTriggerType::Every) => return true,
@@ -466,27 +510,39 @@ impl DataSource {
                     handler.handler,
                 )))
             }
-            EthereumTrigger::Log(log) => {
+            EthereumTrigger::Log(log, receipt) => {
                 let potential_handlers = self.handlers_for_log(log)?;
 
-                // Map event handlers to (event handler, event ABI) pairs; fail if there are
-                // handlers that don't exist in the contract ABI
+                // The contract ABI comes first; the fallback ABIs are only
+                // tried when it can not decode the event, e.g., because a
+                // proxy was upgraded to a contract with a different event layout
+                let abis: Vec<_> = std::iter::once(&self.contract_abi)
+                    .chain(self.mapping.fallback_abis.iter())
+                    .collect();
+
+                // Map event handlers to (event handler, event ABIs) pairs; fail if there are
+                // handlers whose event doesn't exist in any of the ABIs
                 let valid_handlers = potential_handlers
                     .into_iter()
                     .map(|event_handler| {
-                        // Identify the event ABI in the contract
-                        let event_abi = self
-                            .contract_event_with_signature(event_handler.event.as_str())
-                            .with_context(|| {
-                                anyhow!(
-                                    "Event with the signature \"{}\" not found in \
-                                            contract \"{}\" of data source \"{}\"",
-                                    event_handler.event,
-                                    self.contract_abi.name,
-                                    self.name,
-                                )
-                            })?;
-                        Ok((event_handler, event_abi))
+                        // Identify the event ABI in each contract ABI
+                        let event_abis: Vec<_> = abis
+                            .iter()
+                            .filter_map(|abi| {
+                                Self::event_with_signature(abi, event_handler.event.as_str())
+                                    .map(|event_abi| (abi.name.as_str(), event_abi))
+                            })
+                            .collect();
+                        if event_abis.is_empty() {
+                            return Err(anyhow!(
+                                "Event with the signature \"{}\" not found in \
+                                        contract \"{}\" of data source \"{}\"",
+                                event_handler.event,
+                                self.contract_abi.name,
+                                self.name,
+                            ));
+                        }
+                        Ok((event_handler, event_abis))
                     })
                     .collect::<Result<Vec<_>, anyhow::Error>>()?;
 
@@ -494,30 +550,34 @@ impl DataSource {
                 // params (this is common for overloaded events that have the same topic0
                 // but have indexed vs. non-indexed params that are encoded differently).
                 //
-                // Map (handler, event ABI) pairs to (handler, decoded params) pairs.
+                // Map (handler, event ABIs) pairs to (handler, decoded params, ABI name)
+                // triples, using the first ABI that can decode the params.
                 let mut matching_handlers = valid_handlers
                     .into_iter()
-                    .filter_map(|(event_handler, event_abi)| {
-                        event_abi
-                            .parse_log(RawLog {
-                                topics: log.topics.clone(),
-                                data: log.data.clone().0,
-                            })
-                            .map(|log| log.params)
-                            .map_err(|e| {
-                                trace!(
-                                    logger,
-                                    "Skipping handler because the event parameters do not \
-                                    match the event signature. This is typically the case \
-                                    when parameters are indexed in the event but not in the \
-                                    signature or the other way around";
-                                    "handler" => &event_handler.handler,
-                                    "event" => &event_handler.event,
-                                    "error" => format!("{}", e),
-                                );
-                            })
-                            .ok()
-                            .map(|params| (event_handler, params))
+                    .filter_map(|(event_handler, event_abis)| {
+                        event_abis.into_iter().find_map(|(abi_name, event_abi)| {
+                            event_abi
+                                .parse_log(RawLog {
+                                    topics: log.topics.clone(),
+                                    data: log.data.clone().0,
+                                })
+                                .map(|log| log.params)
+                                .map_err(|e| {
+                                    trace!(
+                                        logger,
+                                        "Skipping handler because the event parameters do not \
+                                        match the event signature. This is typically the case \
+                                        when parameters are indexed in the event but not in the \
+                                        signature or the other way around";
+                                        "handler" => &event_handler.handler,
+                                        "event" => &event_handler.event,
+                                        "abi" => abi_name,
+                                        "error" => format!("{}", e),
+                                    );
+                                })
+                                .ok()
+                                .map(|params| (event_handler.clone(), params, abi_name))
+                        })
                     })
                     .collect::<Vec<_>>();
 
@@ -526,7 +586,12 @@ impl DataSource {
                 }
 
                 // Process the event with the matching handler
-                let (event_handler, params) = matching_handlers.pop().unwrap();
+                let (event_handler, params, abi_name) = matching_handlers.pop().unwrap();
+                if abi_name != self.contract_abi.name {
+                    debug!(logger, "Decoded event with a fallback ABI";
+                                   "event" => &event_handler.event,
+                                   "abi" => abi_name);
+                }
 
                 ensure!(
                     matching_handlers.is_empty(),
@@ -555,9 +620,18 @@ impl DataSource {
                     }
                 };
 
+                // Only handlers that asked for it get the receipt; it can
+                // still be missing, e.g., for the Celo events above
+                let receipt = if event_handler.receipt {
+                    receipt.cheap_clone()
+                } else {
+                    None
+                };
+
                 let logging_extras = Arc::new(o! {
                     "signature" => event_handler.event.to_string(),
                     "address" => format!("{}", &log.address),
+                    "abi" => abi_name.to_string(),
                 });
                 Ok(Some(TriggerWithHandler::new_with_logging_extras(
                     MappingTrigger::Log {
@@ -565,6 +639,7 @@ impl DataSource {
                         transaction: Arc::new(transaction),
                         log: log.cheap_clone(),
                         params,
+                        receipt,
                     },
                     event_handler.handler,
                     logging_extras,
@@ -821,6 +896,10 @@ pub struct UnresolvedMapping {
     pub language: String,
     pub entities: Vec<String>,
     pub abis: Vec<UnresolvedMappingABI>,
+    /// Names of ABIs in `abis` to try in order when an event can not be
+    /// decoded with the ABI of the data source
+    #[serde(default)]
+    pub fallback_abis: Vec<String>,
     #[serde(default)]
     pub block_handlers: Vec<MappingBlockHandler>,
     #[serde(default)]
@@ -837,6 +916,7 @@ pub struct Mapping {
     pub language: String,
     pub entities: Vec<String>,
     pub abis: Vec<Arc<MappingABI>>,
+    pub fallback_abis: Vec<Arc<MappingABI>>,
     pub block_handlers: Vec<MappingBlockHandler>,
     pub call_handlers: Vec<MappingCallHandler>,
     pub event_handlers: Vec<MappingEventHandler>,
@@ -881,6 +961,7 @@ impl UnresolvedMapping {
             language,
             entities,
             abis,
+            fallback_abis,
             block_handlers,
             call_handlers,
             event_handlers,
@@ -908,12 +989,23 @@ impl UnresolvedMapping {
         )
         .await?;
 
+        let fallback_abis = fallback_abis
+            .iter()
+            .map(|name| {
+                abis.iter()
+                    .find(|abi| &abi.name == name)
+                    .cloned()
+                    .ok_or_else(|| anyhow!("fallback ABI `{}` is not listed in `abis`", name))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
         Ok(Mapping {
             kind,
             api_version,
             language,
             entities,
             abis,
+            fallback_abis,
             block_handlers: block_handlers.clone(),
             call_handlers: call_handlers.clone(),
             event_handlers: event_handlers.clone(),
@@ -969,6 +1061,10 @@ pub enum BlockHandlerFilter {
     // Call filter will trigger on all blocks where the data source contract
     // address has been called
     Call,
+    // Transaction filter will trigger on all blocks that contain a
+    // transaction sent directly to the data source contract address. Unlike
+    // the call filter, it does not need traces
+    Transaction,
 }
 
 #[derive(Clone, Debug, Hash, Eq, PartialEq, Deserialize)]
@@ -982,6 +1078,10 @@ pub struct MappingEventHandler {
     pub event: String,
     pub topic0: Option<H256>,
     pub handler: String,
+    /// Whether the handler receives the receipt of the transaction that
+    /// emitted the event
+    #[serde(default)]
+    pub receipt: bool,
 }
 
 impl MappingEventHandler {
@@ -1009,3 +1109,176 @@ fn string_to_h256(s: &str) -> H256 {
 pub struct TemplateSource {
     pub abi: String,
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use ethabi::{Contract, Token};
+    use graph::blockchain::DataSource as _;
+    use graph::prelude::{o, slog, LightEthereumBlock, Link, Logger};
+    use graph::semver::Version;
+    use web3::types::{Address, Bytes, Log, H256, U256};
+
+    use super::{
+        BlockHandlerFilter, DataSource, Mapping, MappingABI, MappingBlockHandler,
+        MappingEventHandler, Source,
+    };
+    use crate::trigger::EthereumTrigger;
+
+    /// The current version of the contract, which indexes the sender and
+    /// the receiver of a transfer
+    const TOKEN_V2: &str = r#"[
+        {
+            "type": "event",
+            "name": "Transfer",
+            "anonymous": false,
+            "inputs": [
+                { "name": "from", "type": "address", "indexed": true },
+                { "name": "to", "type": "address", "indexed": true },
+                { "name": "value", "type": "uint256", "indexed": false }
+            ]
+        }
+    ]"#;
+
+    /// The version of the contract before the proxy was upgraded, which
+    /// indexes none of the parameters
+    const TOKEN_V1: &str = r#"[
+        {
+            "type": "event",
+            "name": "Transfer",
+            "anonymous": false,
+            "inputs": [
+                { "name": "from", "type": "address", "indexed": false },
+                { "name": "to", "type": "address", "indexed": false },
+                { "name": "value", "type": "uint256", "indexed": false }
+            ]
+        }
+    ]"#;
+
+    fn abi(name: &str, json: &str) -> Arc<MappingABI> {
+        Arc::new(MappingABI {
+            name: name.to_string(),
+            contract: Contract::load(json.as_bytes()).unwrap(),
+        })
+    }
+
+    fn data_source(fallback_abis: Vec<Arc<MappingABI>>) -> DataSource {
+        let token_v2 = abi("TokenV2", TOKEN_V2);
+        let token_v1 = abi("TokenV1", TOKEN_V1);
+        DataSource {
+            kind: "ethereum/contract".to_string(),
+            network: Some("mainnet".to_string()),
+            name: "Token".to_string(),
+            source: Source {
+                address: None,
+                abi: "TokenV2".to_string(),
+                start_block: 0,
+            },
+            mapping: Mapping {
+                kind: "ethereum/events".to_string(),
+                api_version: Version::new(0, 0, 4),
+                language: "wasm/assemblyscript".to_string(),
+                entities: vec![],
+                abis: vec![token_v2.clone(), token_v1],
+                fallback_abis,
+                block_handlers: vec![],
+                call_handlers: vec![],
+                event_handlers: vec![MappingEventHandler {
+                    event: "Transfer(address,address,uint256)".to_string(),
+                    topic0: None,
+                    handler: "handleTransfer".to_string(),
+                    receipt: false,
+                }],
+                runtime: Arc::new(vec![]),
+                link: Link::default(),
+            },
+            context: Arc::new(None),
+            creation_block: None,
+            contract_abi: token_v2,
+        }
+    }
+
+    /// A `Transfer` log as the contract emitted it before the upgrade
+    fn transfer_v1(topic0: H256) -> EthereumTrigger {
+        let data = ethabi::encode(&[
+            Token::Address(Address::from_low_u64_be(1)),
+            Token::Address(Address::from_low_u64_be(2)),
+            Token::Uint(U256::from(100)),
+        ]);
+        let log = Log {
+            address: Address::from_low_u64_be(42),
+            topics: vec![topic0],
+            data: Bytes(data),
+            block_hash: Some(H256::zero()),
+            block_number: None,
+            transaction_hash: Some(H256::zero()),
+            transaction_index: None,
+            log_index: None,
+            transaction_log_index: None,
+            log_type: None,
+            removed: None,
+        };
+        EthereumTrigger::Log(Arc::new(log), None)
+    }
+
+    #[test]
+    fn decodes_event_with_fallback_abi() {
+        let logger = Logger::root(slog::Discard, o!());
+        let mut block = LightEthereumBlock::default();
+        block.hash = Some(H256::zero());
+        let block = Arc::new(block);
+
+        // Without a fallback, the log does not decode with the ABI of the
+        // data source and the handler is skipped
+        let ds = data_source(vec![]);
+        let trigger = transfer_v1(ds.mapping.event_handlers[0].topic0());
+        let decoded = ds
+            .match_and_decode(&trigger, block.clone(), &logger)
+            .unwrap();
+        assert!(decoded.is_none());
+        assert_eq!(
+            Some("handleTransfer".to_string()),
+            ds.declared_handler(&trigger)
+        );
+
+        let ds = data_source(vec![abi("TokenV1", TOKEN_V1)]);
+        let decoded = ds
+            .match_and_decode(&trigger, block, &logger)
+            .unwrap()
+            .expect("the fallback ABI decodes the event");
+        assert_eq!("handleTransfer", decoded.handler_name());
+    }
+
+    fn has_duplicated_block_handlers(filters: Vec<Option<BlockHandlerFilter>>) -> bool {
+        let mut ds = data_source(vec![]);
+        ds.source.address = Some(Address::from_low_u64_be(42));
+        ds.mapping.block_handlers = filters
+            .into_iter()
+            .map(|filter| MappingBlockHandler {
+                handler: "handleBlock".to_string(),
+                filter,
+            })
+            .collect();
+        ds.validate()
+            .iter()
+            .any(|e| e.to_string() == "data source has duplicated block handlers")
+    }
+
+    #[test]
+    fn allows_one_block_handler_per_filter() {
+        use BlockHandlerFilter::{Call, Transaction};
+
+        assert!(!has_duplicated_block_handlers(vec![
+            None,
+            Some(Call),
+            Some(Transaction)
+        ]));
+        assert!(has_duplicated_block_handlers(vec![None, None]));
+        assert!(has_duplicated_block_handlers(vec![Some(Call), Some(Call)]));
+        assert!(has_duplicated_block_handlers(vec![
+            Some(Transaction),
+            Some(Transaction)
+        ]));
+    }
+}
@@ -12,7 +12,12 @@ use graph::{
     prelude::{
         anyhow::{self, anyhow, bail},
         async_trait, debug, error, ethabi,
-        futures03::{self, compat::Future01CompatExt, FutureExt, StreamExt, TryStreamExt},
+        futures03::{
+            self,
+            compat::{Future01CompatExt, Stream01CompatExt},
+            stream::BoxStream,
+            FutureExt, StreamExt, TryStreamExt,
+        },
         hex, info, retry, serde_json as json, stream, tiny_keccak, trace, warn,
         web3::{
             self,
@@ -31,13 +36,15 @@ use graph::{
 };
 use itertools::Itertools;
 use lazy_static::lazy_static;
+use std::cmp;
 use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::convert::TryFrom;
 use std::iter::FromIterator;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::time::Instant;
 use web3::api::Web3;
 use web3::transports::batch::Batch;
+use web3::Transport as _;
 
 use crate::chain::BlockFinality;
 use crate::{
@@ -77,11 +84,20 @@ lazy_static! {
         .parse::<BlockNumber>()
         .expect("invalid number of parallel Ethereum block ranges to scan");
 
-    static ref BLOCK_BATCH_SIZE: usize = std::env::var("ETHEREUM_BLOCK_BATCH_SIZE")
+    pub(crate) static ref BLOCK_BATCH_SIZE: usize = std::env::var("ETHEREUM_BLOCK_BATCH_SIZE")
             .unwrap_or("10".into())
             .parse::<usize>()
             .expect("invalid ETHEREUM_BLOCK_BATCH_SIZE env var");
 
+    /// How many blocks to request in one JSON-RPC batch request. We send
+    /// as many batches at once as it takes to have about
+    /// `ETHEREUM_BLOCK_BATCH_SIZE` blocks in flight; see
+    /// `json_rpc_batch_concurrency`
+    static ref JSON_RPC_BATCH_SIZE: usize = std::env::var("GRAPH_ETHEREUM_JSON_RPC_BATCH_SIZE")
+            .unwrap_or("10".into())
+            .parse::<usize>()
+            .expect("invalid GRAPH_ETHEREUM_JSON_RPC_BATCH_SIZE env var");
+
     /// This should not be too large that it causes requests to timeout without us catching it, nor
     /// too small that it causes us to timeout requests that would've succeeded. We've seen
     /// successful `eth_getLogs` requests take over 120 seconds.
@@ -107,6 +123,13 @@ lazy_static! {
     };
 }
 
+/// How many JSON-RPC batches to have in flight at once so that we request
+/// about `ETHEREUM_BLOCK_BATCH_SIZE` blocks at the same time, like we did
+/// when we requested each block on its own
+fn json_rpc_batch_concurrency() -> usize {
+    (*BLOCK_BATCH_SIZE / (*JSON_RPC_BATCH_SIZE).max(1)).max(1)
+}
+
 /// Gas limit for `eth_call`. The value of 50_000_000 is a protocol-wide parameter so this
 /// should be changed only for debugging purposes and never on an indexer in the network. This
 /// value was chosen because it is the Geth default
@@ -181,6 +204,72 @@ impl EthereumAdapter {
         }
     }
 
+    /// Ask the provider for the latest block number once, without retries,
+    /// to see whether it responds
+    pub async fn check_health(&self) -> Result<(), Error> {
+        let start = Instant::now();
+        let res = self.web3.eth().block_number().compat().await;
+        self.metrics.observe_request(
+            start.elapsed().as_secs_f64(),
+            "eth_blockNumber",
+            &self.provider,
+        );
+        res.map(|_| ()).map_err(|e| {
+            self.metrics.add_error("eth_blockNumber", &self.provider);
+            anyhow!("health check failed: {}", e)
+        })
+    }
+
+    /// Subscribe to new chain heads with `eth_subscribe("newHeads")`.
+    /// Returns `None` if the provider can not push them to us because we
+    /// talk to it over HTTP. Dropping the stream cancels the subscription
+    /// with `eth_unsubscribe`
+    pub async fn new_heads(
+        &self,
+    ) -> Result<Option<BoxStream<'static, Result<BlockPtr, Error>>>, Error> {
+        if !self.web3.transport().supports_subscriptions() {
+            return Ok(None);
+        }
+
+        let heads = self
+            .web3
+            .eth_subscribe()
+            .subscribe_new_heads()
+            .compat()
+            .await
+            .map_err(|e| {
+                self.metrics.add_error("eth_subscribe", &self.provider);
+                anyhow!("failed to subscribe to new heads: {}", e)
+            })?;
+
+        // Dropping web3's subscription stream only stops listening for
+        // notifications; the provider keeps sending them until we tell it
+        // to stop
+        let id = web3::helpers::serialize(heads.id());
+        let transport = self.web3.transport().clone();
+        let logger = self.logger.clone();
+        let unsubscribe = move || {
+            let request = transport.execute("eth_unsubscribe", vec![id]);
+            graph::spawn(async move {
+                if let Err(e) = request.compat().await {
+                    debug!(logger, "Failed to unsubscribe from new heads"; "error" => e.to_string());
+                }
+            });
+        };
+
+        let heads = heads
+            .compat()
+            .map(|header| {
+                let header = header.map_err(|e| anyhow!("new heads subscription failed: {}", e))?;
+                match (header.hash, header.number) {
+                    (Some(hash), Some(number)) => Ok(BlockPtr::from((hash, number.as_u64()))),
+                    _ => Err(anyhow!("received a new head without a hash or number")),
+                }
+            })
+            .boxed();
+        Ok(Some(UnsubscribeOnDrop::new(heads, unsubscribe).boxed()))
+    }
+
     async fn traces(
         self,
         logger: Logger,
@@ -382,12 +471,18 @@ impl EthereumAdapter {
         to: BlockNumber,
         filter: EthGetLogsFilter,
     ) -> DynTryFuture<'static, Vec<Log>, Error> {
-        // Codes returned by Ethereum node providers if an eth_getLogs request is too heavy.
-        // The first one is for Infura when it hits the log limit, the rest for Alchemy timeouts.
+        // Codes and messages returned by Ethereum node providers if an eth_getLogs request is
+        // too heavy. The first one is for Infura when it hits the log limit, the next two for
+        // Alchemy timeouts, and the rest are what other providers say when a request has too
+        // many results or spans too many blocks.
         const TOO_MANY_LOGS_FINGERPRINTS: &[&str] = &[
             "ServerError(-32005)",
             "503 Service Unavailable",
             "ServerError(-32000)",
+            "query returned more than",
+            "Log response size exceeded",
+            "block range is too wide",
+            "exceed maximum block range",
         ];
 
         if from > to {
@@ -401,16 +496,18 @@ impl EthereumAdapter {
         let eth = self.cheap_clone();
         let filter = Arc::new(filter);
 
-        let step = match filter.contracts.is_empty() {
+        let max_step = match filter.contracts.is_empty() {
             // `to - from + 1`  blocks will be scanned.
             false => to - from,
             true => (to - from).min(*MAX_EVENT_ONLY_RANGE - 1),
         };
 
         // Typically this will loop only once and fetch the entire range in one request. But if the
-        // node returns an error that signifies the request is to heavy to process, the range will
-        // be broken down to smaller steps.
-        futures03::stream::try_unfold((from, step), move |(start, step)| {
+        // node returns an error that signifies the request is to heavy to process, or the request
+        // times out, the range is cut in half until requests succeed. Every successful request
+        // doubles the range again, up to the size we started with, so that one dense stretch of
+        // blocks does not slow down scanning the rest of the range.
+        futures03::stream::try_unfold((from, max_step), move |(start, step)| {
             let logger = logger.cheap_clone();
             let filter = filter.cheap_clone();
             let eth = eth.cheap_clone();
@@ -440,26 +537,28 @@ impl EthereumAdapter {
                 match res {
                     Err(e) => {
                         let string_err = e.to_string();
-
-                        // If the step is already 0, the request is too heavy even for a single
-                        // block. We hope this never happens, but if it does, make sure to error.
-                        if TOO_MANY_LOGS_FINGERPRINTS
-                            .iter()
-                            .any(|f| string_err.contains(f))
-                            && step > 0
-                        {
-                            // The range size for a request is `step + 1`. So it's ok if the step
-                            // goes down to 0, in that case we'll request one block at a time.
-                            let new_step = step / 10;
-                            debug!(logger, "Reducing block range size to scan for events";
+                        let too_heavy = e.is_elapsed()
+                            || TOO_MANY_LOGS_FINGERPRINTS
+                                .iter()
+                                .any(|f| string_err.contains(f));
+
+                        match next_log_step(step, max_step, too_heavy) {
+                            Some(new_step) if too_heavy => {
+                                debug!(logger, "Reducing block range size to scan for events";
                                                "new_size" => new_step + 1);
-                            Ok(Some((vec![], (start, new_step))))
-                        } else {
-                            warn!(logger, "Unexpected RPC error"; "error" => &string_err);
-                            Err(anyhow!("{}", string_err))
+                                Ok(Some((vec![], (start, new_step))))
+                            }
+                            _ => {
+                                warn!(logger, "Unexpected RPC error"; "error" => &string_err);
+                                Err(anyhow!("{}", string_err))
+                            }
                         }
                     }
-                    Ok(logs) => Ok(Some((logs, (end + 1, step)))),
+                    Ok(logs) => {
+                        // `next_log_step` only fails for requests that were too heavy
+                        let next_step = next_log_step(step, max_step, false).unwrap();
+                        Ok(Some((logs, (end + 1, next_step))))
+                    }
                 }
             }
         })
@@ -620,28 +719,141 @@ impl EthereumAdapter {
         ids: Vec<H256>,
     ) -> impl Stream<Item = Arc<LightEthereumBlock>, Error = Error> + Send {
         let web3 = self.web3.clone();
+        let batches = ids
+            .chunks((*JSON_RPC_BATCH_SIZE).max(1))
+            .map(|hashes| hashes.to_vec())
+            .collect::<Vec<_>>();
 
-        stream::iter_ok::<_, Error>(ids.into_iter().map(move |hash| {
+        stream::iter_ok::<_, Error>(batches.into_iter().map(move |hashes| {
             let web3 = web3.clone();
-            retry(format!("load block {}", hash), &logger)
-                .limit(*REQUEST_RETRIES)
-                .timeout_secs(*JSON_RPC_TIMEOUT)
-                .run(move || {
-                    web3.eth()
-                        .block_with_txs(BlockId::Hash(hash))
-                        .from_err::<Error>()
-                        .and_then(move |block| {
-                            block.map(|block| Arc::new(block)).ok_or_else(|| {
-                                anyhow::anyhow!("Ethereum node did not find block {:?}", hash)
+            // The blocks that earlier attempts found, so that retrying
+            // only asks for the ones that are still missing
+            let found = Arc::new(Mutex::new(HashMap::new()));
+            retry(
+                format!("load {} block(s) from {}", hashes.len(), hashes[0]),
+                &logger,
+            )
+            .limit(*REQUEST_RETRIES)
+            .timeout_secs(*JSON_RPC_TIMEOUT)
+            .run(move || {
+                let web3 = web3.clone();
+                let hashes = hashes.clone();
+                let found = found.clone();
+                async move {
+                    let missing: Vec<H256> = {
+                        let found = found.lock().unwrap();
+                        hashes
+                            .iter()
+                            .filter(|hash| !found.contains_key(*hash))
+                            .cloned()
+                            .collect()
+                    };
+
+                    let batching_web3 = Web3::new(Batch::new(web3.transport().clone()));
+                    let block_futures: Vec<_> = missing
+                        .iter()
+                        .map(|hash| {
+                            batching_web3
+                                .eth()
+                                .block_with_txs(BlockId::Hash(*hash))
+                                .compat()
+                        })
+                        .collect();
+                    batching_web3.transport().submit_batch().compat().await?;
+                    let blocks = futures03::future::join_all(block_futures).await;
+
+                    let mut found = found.lock().unwrap();
+                    for (hash, block) in missing.into_iter().zip(blocks) {
+                        if let Some(block) = block? {
+                            found.insert(hash, Arc::new(block));
+                        }
+                    }
+                    hashes
+                        .iter()
+                        .map(|hash| {
+                            found.get(hash).cloned().ok_or_else(|| {
+                                anyhow!("Ethereum node did not find block {:?}", hash)
                             })
                         })
-                        .compat()
-                })
-                .boxed()
-                .compat()
-                .from_err()
+                        .collect::<Result<Vec<_>, Error>>()
+                }
+            })
+            .boxed()
+            .compat()
+            .from_err()
         }))
-        .buffered(*BLOCK_BATCH_SIZE)
+        .buffered(json_rpc_batch_concurrency())
+        .map(stream::iter_ok)
+        .flatten()
+    }
+
+    /// Request the receipts of the transactions `hashes` in the block
+    /// `block_hash` through JSON-RPC.
+    fn load_transaction_receipts_rpc(
+        &self,
+        logger: Logger,
+        block_hash: H256,
+        hashes: Vec<H256>,
+    ) -> impl Stream<Item = Arc<TransactionReceipt>, Error = Error> + Send {
+        let web3 = self.web3.clone();
+        let batches = hashes
+            .chunks((*JSON_RPC_BATCH_SIZE).max(1))
+            .map(|hashes| hashes.to_vec())
+            .collect::<Vec<_>>();
+
+        stream::iter_ok::<_, Error>(batches.into_iter().map(move |hashes| {
+            let web3 = web3.clone();
+            retry(
+                format!(
+                    "load {} receipt(s) from block {:x}",
+                    hashes.len(),
+                    block_hash
+                ),
+                &logger,
+            )
+            .limit(*REQUEST_RETRIES)
+            .timeout_secs(*JSON_RPC_TIMEOUT)
+            .run(move || {
+                let batching_web3 = Web3::new(Batch::new(web3.transport().clone()));
+                let receipt_futures = hashes
+                    .iter()
+                    .map(|hash| {
+                        let hash = *hash;
+                        batching_web3
+                            .eth()
+                            .transaction_receipt(hash)
+                            .from_err::<Error>()
+                            .and_then(move |receipt| match receipt {
+                                Some(receipt) if receipt.block_hash == Some(block_hash) => {
+                                    Ok(Arc::new(receipt))
+                                }
+                                // A missing receipt or one from another
+                                // block means that the node has not caught
+                                // up with the block yet; retry
+                                _ => Err(anyhow!(
+                                    "Ethereum node did not find the receipt for transaction \
+                                     {:x} in block {:x}",
+                                    hash,
+                                    block_hash
+                                )),
+                            })
+                    })
+                    .collect::<Vec<_>>();
+
+                batching_web3
+                    .transport()
+                    .submit_batch()
+                    .from_err::<Error>()
+                    .and_then(move |_| stream::futures_ordered(receipt_futures).collect())
+                    .compat()
+            })
+            .boxed()
+            .compat()
+            .from_err()
+        }))
+        .buffered(json_rpc_batch_concurrency())
+        .map(stream::iter_ok)
+        .flatten()
     }
 
     /// Request blocks ptrs for numbers through JSON-RPC.
@@ -653,28 +865,78 @@ impl EthereumAdapter {
         block_nums: Vec<BlockNumber>,
     ) -> impl Stream<Item = BlockPtr, Error = Error> + Send {
         let web3 = self.web3.clone();
+        let batches = block_nums
+            .chunks((*JSON_RPC_BATCH_SIZE).max(1))
+            .map(|block_nums| block_nums.to_vec())
+            .collect::<Vec<_>>();
 
-        stream::iter_ok::<_, Error>(block_nums.into_iter().map(move |block_num| {
+        stream::iter_ok::<_, Error>(batches.into_iter().map(move |block_nums| {
             let web3 = web3.clone();
-            retry(format!("load block ptr {}", block_num), &logger)
-                .no_limit()
-                .timeout_secs(*JSON_RPC_TIMEOUT)
-                .run(move || {
-                    web3.eth()
-                        .block(BlockId::Number(Web3BlockNumber::Number(block_num.into())))
-                        .from_err::<Error>()
-                        .and_then(move |block| {
-                            block.ok_or_else(|| {
+            // The blocks that earlier attempts found, so that retrying
+            // only asks for the ones that are still missing
+            let found = Arc::new(Mutex::new(HashMap::new()));
+            retry(
+                format!(
+                    "load {} block ptr(s) from {}",
+                    block_nums.len(),
+                    block_nums[0]
+                ),
+                &logger,
+            )
+            .no_limit()
+            .timeout_secs(*JSON_RPC_TIMEOUT)
+            .run(move || {
+                let web3 = web3.clone();
+                let block_nums = block_nums.clone();
+                let found = found.clone();
+                async move {
+                    let missing: Vec<BlockNumber> = {
+                        let found = found.lock().unwrap();
+                        block_nums
+                            .iter()
+                            .filter(|block_num| !found.contains_key(*block_num))
+                            .cloned()
+                            .collect()
+                    };
+
+                    let batching_web3 = Web3::new(Batch::new(web3.transport().clone()));
+                    let block_futures: Vec<_> = missing
+                        .iter()
+                        .map(|block_num| {
+                            batching_web3
+                                .eth()
+                                .block(BlockId::Number(Web3BlockNumber::Number(
+                                    (*block_num).into(),
+                                )))
+                                .compat()
+                        })
+                        .collect();
+                    batching_web3.transport().submit_batch().compat().await?;
+                    let blocks = futures03::future::join_all(block_futures).await;
+
+                    let mut found = found.lock().unwrap();
+                    for (block_num, block) in missing.into_iter().zip(blocks) {
+                        if let Some(block) = block? {
+                            found.insert(block_num, block);
+                        }
+                    }
+                    block_nums
+                        .iter()
+                        .map(|block_num| {
+                            found.get(block_num).cloned().ok_or_else(|| {
                                 anyhow!("Ethereum node did not find block {:?}", block_num)
                             })
                         })
-                        .compat()
-                })
-                .boxed()
-                .compat()
-                .from_err()
+                        .collect::<Result<Vec<_>, Error>>()
+                }
+            })
+            .boxed()
+            .compat()
+            .from_err()
         }))
-        .buffered(*BLOCK_BATCH_SIZE)
+        .buffered(json_rpc_batch_concurrency())
+        .map(stream::iter_ok)
+        .flatten()
         .map(|b| b.into())
     }
 
@@ -702,6 +964,25 @@ impl EthereumAdapter {
             .map(|block_hash| block_hash == block_ptr.hash_as_h256())
     }
 
+    /// Get a pointer to the block that contains the transaction with hash
+    /// `hash`. Returns `None` if the Ethereum node does not know the
+    /// transaction or if it is still pending
+    pub(crate) async fn transaction_block(&self, hash: H256) -> Result<Option<BlockPtr>, Error> {
+        let receipt = self
+            .web3
+            .eth()
+            .transaction_receipt(hash)
+            .compat()
+            .await
+            .map_err(|e| anyhow!("failed to fetch receipt for transaction {:x}: {}", hash, e))?;
+        Ok(
+            receipt.and_then(|receipt| match (receipt.block_hash, receipt.block_number) {
+                (Some(hash), Some(number)) => Some(BlockPtr::from((hash, number.as_u64()))),
+                _ => None,
+            }),
+        )
+    }
+
     pub(crate) fn logs_in_block_range(
         &self,
         logger: &Logger,
@@ -1449,8 +1730,7 @@ pub(crate) async fn blocks_with_triggers(
             )
             .map_ok(|logs: Vec<Log>| {
                 logs.into_iter()
-                    .map(Arc::new)
-                    .map(EthereumTrigger::Log)
+                    .map(|log| EthereumTrigger::Log(Arc::new(log), None))
                     .collect()
             })
             .compat(),
@@ -1523,20 +1803,46 @@ pub(crate) async fn blocks_with_triggers(
             map
         });
 
+    // Whether a block has a transaction to an address in the transaction
+    // filter can only be seen from its transactions, and we therefore need
+    // to load all blocks from the first block that filter applies to
+    let block_filter = filter.block.clone();
+    if !block_filter.trigger_every_block {
+        if let Some(start) = block_filter
+            .transaction_addresses
+            .iter()
+            .map(|(start_block, _)| cmp::max(*start_block, from))
+            .min()
+            .filter(|start| *start <= to)
+        {
+            let ptrs = adapter
+                .block_range_to_ptrs(logger.clone(), start, to)
+                .compat()
+                .await?;
+            for ptr in ptrs {
+                triggers_by_block.entry(ptr.number).or_default();
+                block_hashes.insert(ptr.hash_as_h256());
+            }
+        }
+    }
+
     debug!(logger, "Found {} relevant block(s)", block_hashes.len());
 
     // Make sure `to` is included, even if empty.
     block_hashes.insert(to_hash);
     triggers_by_block.entry(to).or_insert(Vec::new());
 
-    let blocks = adapter
+    let mut blocks: Vec<_> = adapter
         .load_blocks(logger1, chain_store.clone(), block_hashes)
         .and_then(
             move |block| match triggers_by_block.remove(&(block.number() as BlockNumber)) {
-                Some(triggers) => Ok(BlockWithTriggers::new(
-                    BlockFinality::Final(block),
-                    triggers,
-                )),
+                Some(mut triggers) => {
+                    triggers.extend(block_filter.transaction_triggers(&block));
+                    Ok(BlockWithTriggers::new(
+                        BlockFinality::Final(block),
+                        triggers,
+                    ))
+                }
                 None => Err(anyhow!(
                     "block {:?} not found in `triggers_by_block`",
                     block
@@ -1547,6 +1853,10 @@ pub(crate) async fn blocks_with_triggers(
         .compat()
         .await?;
 
+    // Blocks we only loaded to look at their transactions are of no
+    // interest if none of them matched
+    blocks.retain(|block| !block.trigger_data.is_empty() || block.ptr().number == to);
+
     // Filter out call triggers that come from unsuccessful transactions
 
     let mut blocks = if unified_api_version
@@ -1564,6 +1874,20 @@ pub(crate) async fn blocks_with_triggers(
         blocks
     };
 
+    // Attach transaction receipts to the log triggers whose handlers asked
+    // for them
+    if filter.log.requires_transaction_receipts() {
+        let section = stopwatch_metrics.start_section("load_transaction_receipts");
+        // Each block loads its receipts in JSON-RPC batches; bound how
+        // many blocks do that at once like we do for loading blocks
+        blocks = futures03::stream::iter(blocks)
+            .map(|block| add_transaction_receipts(block, &eth, &filter.log, &logger))
+            .buffered(*BLOCK_BATCH_SIZE)
+            .try_collect()
+            .await?;
+        section.end();
+    }
+
     blocks.sort_by_key(|block| block.ptr().number);
 
     // Sanity check that the returned blocks are in the correct range.
@@ -1637,11 +1961,21 @@ pub(crate) fn parse_log_triggers(
         .transaction_receipts
         .iter()
         .flat_map(move |receipt| {
-            receipt
+            let logs: Vec<_> = receipt
                 .logs
                 .iter()
-                .filter(move |log| log_filter.matches(log))
-                .map(move |log| EthereumTrigger::Log(Arc::new(log.clone())))
+                .filter(|log| log_filter.matches(log))
+                .collect();
+            let receipt = if logs
+                .iter()
+                .any(|log| log_filter.requires_transaction_receipt(log))
+            {
+                Some(Arc::new(receipt.clone()))
+            } else {
+                None
+            };
+            logs.into_iter()
+                .map(move |log| EthereumTrigger::Log(Arc::new(log.clone()), receipt.clone()))
         })
         .collect()
 }
@@ -1673,7 +2007,7 @@ pub(crate) fn parse_block_triggers(
 ) -> Vec<EthereumTrigger> {
     let block_ptr = BlockPtr::from(&block.ethereum_block);
     let trigger_every_block = block_filter.trigger_every_block;
-    let call_filter = EthereumCallFilter::from(block_filter);
+    let call_filter = EthereumCallFilter::from(block_filter.clone());
     let block_ptr2 = block_ptr.cheap_clone();
     let mut triggers = match &block.calls {
         Some(calls) => calls
@@ -1688,6 +2022,7 @@ pub(crate) fn parse_block_triggers(
             .collect::<Vec<EthereumTrigger>>(),
         None => vec![],
     };
+    triggers.extend(block_filter.transaction_triggers(&block.ethereum_block.block));
     if trigger_every_block {
         triggers.push(EthereumTrigger::Block(
             block_ptr,
@@ -1714,6 +2049,88 @@ async fn fetch_receipt_from_ethereum_client(
     }
 }
 
+/// Load the receipts of the transactions that emitted log triggers in
+/// `block` that need them, and add them to the triggers
+async fn add_transaction_receipts(
+    mut block: BlockWithTriggers<crate::Chain>,
+    eth: &EthereumAdapter,
+    log_filter: &EthereumLogFilter,
+    logger: &Logger,
+) -> anyhow::Result<BlockWithTriggers<crate::Chain>> {
+    let transaction_hashes =
+        transactions_needing_receipts(&block, |log| log_filter.requires_transaction_receipt(log));
+    if transaction_hashes.is_empty() {
+        return Ok(block);
+    }
+
+    let receipts: HashMap<H256, Arc<TransactionReceipt>> = eth
+        .load_transaction_receipts_rpc(
+            logger.clone(),
+            block.ptr().hash_as_h256(),
+            transaction_hashes.into_iter().collect(),
+        )
+        .map(|receipt| (receipt.transaction_hash, receipt))
+        .collect()
+        .compat()
+        .await?
+        .into_iter()
+        .collect();
+
+    attach_receipts(&mut block, &receipts);
+    Ok(block)
+}
+
+/// The `step` for the next `eth_getLogs` request of `log_stream` after a
+/// request for `step + 1` blocks. A request that was `too_heavy` for the
+/// provider is retried with half the range, and the range of a successful
+/// one is doubled for the next request, up to `max_step`. Returns `None` if
+/// the request was too heavy even for a single block
+fn next_log_step(step: BlockNumber, max_step: BlockNumber, too_heavy: bool) -> Option<BlockNumber> {
+    match (too_heavy, step) {
+        // We hope this never happens, but if it does, make sure to error
+        (true, 0) => None,
+        // It's ok if the step goes down to 0, in that case we'll request
+        // one block at a time
+        (true, step) => Some(step / 2),
+        (false, step) => Some((2 * step + 1).min(max_step)),
+    }
+}
+
+/// The hashes of the transactions that emitted the log triggers in `block`
+/// for which `needs_receipt` is true and that do not have a receipt yet
+fn transactions_needing_receipts(
+    block: &BlockWithTriggers<crate::Chain>,
+    needs_receipt: impl Fn(&Log) -> bool,
+) -> BTreeSet<H256> {
+    // Celo Epoch Rewards events have no transaction, and their transaction
+    // hash is the block hash; there is no receipt for them
+    let block_hash = block.ptr().hash_as_h256();
+    block
+        .trigger_data
+        .iter()
+        .filter_map(|trigger| match trigger {
+            EthereumTrigger::Log(log, None) if needs_receipt(log) => log.transaction_hash,
+            _ => None,
+        })
+        .filter(|hash| *hash != block_hash)
+        .collect()
+}
+
+/// Add the receipts of their transactions to the log triggers in `block`
+/// that do not have a receipt yet
+fn attach_receipts(
+    block: &mut BlockWithTriggers<crate::Chain>,
+    receipts: &HashMap<H256, Arc<TransactionReceipt>>,
+) {
+    for trigger in block.trigger_data.iter_mut() {
+        if let EthereumTrigger::Log(log, receipt @ None) = trigger {
+            *receipt = log
+                .transaction_hash
+                .and_then(|hash| receipts.get(&hash).cloned());
+        }
+    }
+}
+
 async fn filter_call_triggers_from_unsuccessful_transactions(
     mut block: BlockWithTriggers<crate::Chain>,
     eth: &EthereumAdapter,
@@ -1849,3 +2266,161 @@ async fn filter_call_triggers_from_unsuccessful_transactions(
     }
     Ok(block)
 }
+
+/// A subscription stream that calls `unsubscribe` once when it is dropped
+struct UnsubscribeOnDrop<S, F: FnOnce()> {
+    stream: S,
+    unsubscribe: Option<F>,
+}
+
+impl<S, F: FnOnce()> UnsubscribeOnDrop<S, F> {
+    fn new(stream: S, unsubscribe: F) -> Self {
+        UnsubscribeOnDrop {
+            stream,
+            unsubscribe: Some(unsubscribe),
+        }
+    }
+}
+
+impl<S: futures03::Stream + Unpin, F: FnOnce() + Unpin> futures03::Stream
+    for UnsubscribeOnDrop<S, F>
+{
+    type Item = S::Item;
+
+    fn poll_next(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<S::Item>> {
+        std::pin::Pin::new(&mut self.stream).poll_next(cx)
+    }
+}
+
+impl<S, F: FnOnce()> Drop for UnsubscribeOnDrop<S, F> {
+    fn drop(&mut self) {
+        if let Some(unsubscribe) = self.unsubscribe.take() {
+            unsubscribe();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeSet, HashMap};
+    use std::iter::FromIterator;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use graph::blockchain::block_stream::BlockWithTriggers;
+    use graph::prelude::futures03::{executor::block_on, stream, StreamExt};
+    use graph::prelude::web3::types::{Block, Bytes, Log, TransactionReceipt, H256, U64};
+
+    use super::{attach_receipts, next_log_step, transactions_needing_receipts, UnsubscribeOnDrop};
+    use crate::chain::BlockFinality;
+    use crate::trigger::EthereumTrigger;
+
+    fn block_with_logs(
+        block_hash: H256,
+        logs: Vec<(u64, H256)>,
+    ) -> BlockWithTriggers<crate::Chain> {
+        let block = Block {
+            hash: Some(block_hash),
+            number: Some(U64::from(1)),
+            ..Default::default()
+        };
+        let triggers = logs
+            .into_iter()
+            .map(|(index, transaction_hash)| {
+                let log = Log {
+                    address: Default::default(),
+                    topics: vec![],
+                    data: Bytes::default(),
+                    block_hash: Some(block_hash),
+                    block_number: Some(U64::from(1)),
+                    transaction_hash: Some(transaction_hash),
+                    transaction_index: Some(U64::from(index)),
+                    log_index: Some(index.into()),
+                    transaction_log_index: Some(0.into()),
+                    log_type: None,
+                    removed: Some(false),
+                };
+                EthereumTrigger::Log(Arc::new(log), None)
+            })
+            .collect();
+        BlockWithTriggers::new(BlockFinality::Final(Arc::new(block)), triggers)
+    }
+
+    #[test]
+    fn receipts_are_attached_to_the_logs_that_need_them() {
+        let block_hash = H256::from_low_u64_be(100);
+        let tx1 = H256::from_low_u64_be(1);
+        let tx2 = H256::from_low_u64_be(2);
+        // The third log is a Celo epoch rewards event without a transaction
+        let mut block = block_with_logs(block_hash, vec![(0, tx1), (1, tx2), (2, block_hash)]);
+
+        let hashes = transactions_needing_receipts(&block, |_| true);
+        assert_eq!(BTreeSet::from_iter(vec![tx1, tx2]), hashes);
+        let hashes = transactions_needing_receipts(&block, |log| log.transaction_hash == Some(tx2));
+        assert_eq!(BTreeSet::from_iter(vec![tx2]), hashes);
+
+        let receipt = Arc::new(TransactionReceipt {
+            transaction_hash: tx1,
+            ..Default::default()
+        });
+        let receipts = HashMap::from_iter(vec![(tx1, receipt.clone())]);
+        attach_receipts(&mut block, &receipts);
+
+        let attached: Vec<_> = block
+            .trigger_data
+            .iter()
+            .map(|trigger| match trigger {
+                EthereumTrigger::Log(_, receipt) => receipt.clone(),
+                _ => unreachable!("the block only has log triggers"),
+            })
+            .collect();
+        assert_eq!(vec![Some(receipt), None, None], attached);
+
+        // Logs that have their receipt do not need it again
+        let hashes = transactions_needing_receipts(&block, |_| true);
+        assert_eq!(BTreeSet::from_iter(vec![tx2]), hashes);
+    }
+
+    #[test]
+    fn unsubscribes_once_when_dropped() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let calls2 = calls.clone();
+        let mut heads = UnsubscribeOnDrop::new(stream::iter(vec![1, 2]), move || {
+            calls2.fetch_add(1, Ordering::SeqCst);
+        });
+
+        assert_eq!(Some(1), block_on(heads.next()));
+        assert_eq!(0, calls.load(Ordering::SeqCst));
+        drop(heads);
+        assert_eq!(1, calls.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn log_step_shrinks_and_grows_back() {
+        let max_step = 1000;
+
+        // Too heavy requests are retried with half the range
+        assert_eq!(Some(500), next_log_step(max_step, max_step, true));
+        assert_eq!(Some(0), next_log_step(1, max_step, true));
+
+        // Successful requests double the range, but never beyond `max_step`
+        assert_eq!(Some(1), next_log_step(0, max_step, false));
+        assert_eq!(Some(3), next_log_step(1, max_step, false));
+        assert_eq!(Some(max_step), next_log_step(500, max_step, false));
+        assert_eq!(Some(max_step), next_log_step(max_step, max_step, false));
+
+        let mut step = 0;
+        for _ in 0..20 {
+            step = next_log_step(step, max_step, false).unwrap();
+        }
+        assert_eq!(max_step, step);
+    }
+
+    #[test]
+    fn log_step_fails_for_single_block() {
+        assert_eq!(None, next_log_step(0, 1000, true));
+    }
+}
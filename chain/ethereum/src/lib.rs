@@ -3,6 +3,7 @@ mod capabilities;
 pub mod codec;
 mod data_source;
 mod ethereum_adapter;
+mod limiter;
 pub mod network_indexer;
 pub mod runtime;
 mod transport;
@@ -0,0 +1,189 @@
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use graph::prelude::tokio::{
+    self,
+    sync::{OwnedSemaphorePermit, Semaphore},
+};
+
+use crate::adapter::ProviderEthRpcMetrics;
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Limits how many requests per second are sent to a provider and how many
+/// of them can be in flight at the same time. Requests over the limits are
+/// delayed rather than rejected, in the order in which they arrive
+pub(crate) struct RequestLimiter {
+    provider: String,
+    /// Requests per second, and the bucket of tokens that each request
+    /// takes one of. The bucket holds at most one second's worth of tokens
+    rate: Option<(f64, Mutex<Bucket>)>,
+    concurrency: Option<Arc<Semaphore>>,
+    metrics: Arc<ProviderEthRpcMetrics>,
+}
+
+impl RequestLimiter {
+    pub fn new(
+        provider: &str,
+        rate_limit: Option<f64>,
+        max_concurrent_requests: Option<usize>,
+        metrics: Arc<ProviderEthRpcMetrics>,
+    ) -> Self {
+        RequestLimiter {
+            provider: provider.to_string(),
+            rate: rate_limit.map(|rate| {
+                let bucket = Bucket {
+                    tokens: rate.max(1.0),
+                    updated: Instant::now(),
+                };
+                (rate, Mutex::new(bucket))
+            }),
+            concurrency: max_concurrent_requests.map(|max| Arc::new(Semaphore::new(max))),
+            metrics,
+        }
+    }
+
+    /// Take `calls` tokens from the bucket and return how long the caller
+    /// has to wait before it may send its requests. Tokens that are not
+    /// there yet are borrowed from the future, so that callers are let
+    /// through in the order in which they asked
+    fn reserve(&self, calls: usize, now: Instant) -> Duration {
+        let (rate, bucket) = match &self.rate {
+            Some((rate, bucket)) => (*rate, bucket),
+            None => return Duration::from_secs(0),
+        };
+        let mut bucket = bucket.lock().unwrap();
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(rate.max(1.0));
+        bucket.updated = now;
+        bucket.tokens -= calls as f64;
+        if bucket.tokens >= 0.0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_secs_f64(-bucket.tokens / rate)
+        }
+    }
+
+    /// Put `calls` tokens that were reserved but not used back into the
+    /// bucket
+    fn refund(&self, calls: usize) {
+        if let Some((rate, bucket)) = &self.rate {
+            let mut bucket = bucket.lock().unwrap();
+            bucket.tokens = (bucket.tokens + calls as f64).min(rate.max(1.0));
+        }
+    }
+
+    /// Wait until `calls` requests may be sent. The returned permit, if
+    /// any, must be held until the responses have arrived
+    pub async fn acquire(&self, calls: usize) -> Option<OwnedSemaphorePermit> {
+        // If the caller gives up while waiting, e.g., because its retry
+        // timed out, the requests are never sent and their tokens must not
+        // hold up the requests after them
+        let mut reservation = Reservation {
+            limiter: self,
+            calls,
+            sent: false,
+        };
+
+        let wait = self.reserve(calls, Instant::now());
+        if wait > Duration::from_secs(0) {
+            self.metrics.add_throttled(&self.provider);
+            tokio::time::sleep(wait).await;
+        }
+
+        let permit = match &self.concurrency {
+            Some(semaphore) => {
+                if semaphore.available_permits() == 0 {
+                    self.metrics.add_queued(&self.provider);
+                }
+                // Unwrap: the semaphore is never closed
+                Some(semaphore.clone().acquire_owned().await.unwrap())
+            }
+            None => None,
+        };
+        reservation.sent = true;
+        permit
+    }
+}
+
+/// Tokens that `RequestLimiter::acquire` took for requests that have not
+/// been let through yet. They are refunded when `acquire` is dropped
+/// before that
+struct Reservation<'a> {
+    limiter: &'a RequestLimiter,
+    calls: usize,
+    sent: bool,
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        if !self.sent {
+            self.limiter.refund(self.calls);
+        }
+    }
+}
+
+impl fmt::Debug for RequestLimiter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RequestLimiter")
+            .field("provider", &self.provider)
+            .field("rate", &self.rate.as_ref().map(|(rate, _)| rate))
+            .field("concurrency", &self.concurrency.as_ref().map(|_| "limited"))
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use graph::prelude::futures03::FutureExt;
+    use graph::prelude::{o, slog, tokio, Logger};
+    use graph::prometheus::Registry;
+    use graph_core::MetricsRegistry;
+
+    use super::RequestLimiter;
+    use crate::adapter::ProviderEthRpcMetrics;
+
+    #[test]
+    fn requests_over_the_rate_wait_their_turn() {
+        let logger = Logger::root(slog::Discard, o!());
+        let registry = MetricsRegistry::new(logger, Arc::new(Registry::new()));
+        let metrics = Arc::new(ProviderEthRpcMetrics::new(Arc::new(registry)));
+        let limiter = RequestLimiter::new("test", Some(2.0), None, metrics);
+        let start = Instant::now();
+
+        assert_eq!(Duration::from_secs(0), limiter.reserve(1, start));
+        assert_eq!(Duration::from_secs(0), limiter.reserve(1, start));
+        assert_eq!(Duration::from_millis(500), limiter.reserve(1, start));
+        // A batch of two calls has to wait for the call before it, too
+        assert_eq!(Duration::from_millis(1500), limiter.reserve(2, start));
+
+        let later = start + Duration::from_secs(5);
+        assert_eq!(Duration::from_secs(0), limiter.reserve(1, later));
+    }
+
+    #[tokio::test]
+    async fn abandoned_requests_give_their_tokens_back() {
+        let logger = Logger::root(slog::Discard, o!());
+        let registry = MetricsRegistry::new(logger, Arc::new(Registry::new()));
+        let metrics = Arc::new(ProviderEthRpcMetrics::new(Arc::new(registry)));
+        let limiter = RequestLimiter::new("test", Some(2.0), None, metrics);
+        let start = Instant::now();
+
+        assert_eq!(Duration::from_secs(0), limiter.reserve(2, start));
+        // These requests have to wait and are dropped while waiting, like
+        // requests whose retry timed out
+        for _ in 0..10 {
+            assert!(limiter.acquire(1).now_or_never().is_none());
+        }
+        // Only the tokens of the requests that were let through count
+        let wait = limiter.reserve(1, start);
+        assert!(wait <= Duration::from_millis(500), "waited {:?}", wait);
+    }
+}
@@ -1,8 +1,11 @@
 use anyhow::{anyhow, Context};
 use graph::cheap_clone::CheapClone;
 use graph::prelude::rand::{self, seq::IteratorRandom};
+use graph::prelude::{info, lazy_static, o, tokio, warn, Logger};
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 
 pub use graph::impl_slog_value;
 use graph::prelude::Error;
@@ -11,10 +14,51 @@ use crate::adapter::EthereumAdapter as _;
 use crate::capabilities::NodeCapabilities;
 use crate::EthereumAdapter;
 
+lazy_static! {
+    /// How often to check that Ethereum providers respond, in seconds.
+    /// Checks are turned off if this is 0
+    static ref HEALTH_CHECK_INTERVAL: u64 = std::env::var("GRAPH_ETHEREUM_HEALTH_CHECK_INTERVAL")
+        .ok()
+        .map(|s| {
+            s.parse::<u64>()
+                .expect("invalid GRAPH_ETHEREUM_HEALTH_CHECK_INTERVAL")
+        })
+        .unwrap_or(30);
+}
+
+/// How long a provider has to respond to a health check
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// After this many failures in a row, a provider is avoided until it
+/// responds successfully again
+const FAILURES_BEFORE_UNHEALTHY: usize = 3;
+
+/// Tracks how many requests to a provider failed in a row. It is shared
+/// between all clones of an `EthereumNetworkAdapter`
+#[derive(Default)]
+struct ProviderHealth {
+    failures: AtomicUsize,
+}
+
+impl ProviderHealth {
+    fn is_healthy(&self) -> bool {
+        self.failures.load(Ordering::SeqCst) < FAILURES_BEFORE_UNHEALTHY
+    }
+
+    fn success(&self) {
+        self.failures.store(0, Ordering::SeqCst);
+    }
+
+    fn failure(&self) {
+        self.failures.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
 #[derive(Clone)]
 pub struct EthereumNetworkAdapter {
     pub capabilities: NodeCapabilities,
     adapter: Arc<EthereumAdapter>,
+    health: Arc<ProviderHealth>,
 }
 
 #[derive(Clone)]
@@ -23,18 +67,45 @@ pub struct EthereumNetworkAdapters {
 }
 
 impl EthereumNetworkAdapters {
+    /// The adapters that should be used, in the order of their
+    /// capabilities. These are the healthy ones, unless no adapter is
+    /// healthy; in that case, it is better to try all of them than none
+    fn usable(&self) -> Vec<&EthereumNetworkAdapter> {
+        let healthy: Vec<_> = self
+            .adapters
+            .iter()
+            .filter(|adapter| adapter.health.is_healthy())
+            .collect();
+        if healthy.is_empty() {
+            self.adapters.iter().collect()
+        } else {
+            healthy
+        }
+    }
+
     pub fn cheapest_with(
         &self,
         required_capabilities: &NodeCapabilities,
     ) -> Result<Arc<EthereumAdapter>, Error> {
-        let cheapest_sufficient_capability = self
+        let sufficient =
+            |adapter: &&EthereumNetworkAdapter| &adapter.capabilities >= required_capabilities;
+
+        // Prefer healthy adapters, but fall back to unhealthy ones if no
+        // healthy adapter has the required capabilities
+        let mut candidates: Vec<_> = self
             .adapters
             .iter()
-            .find(|adapter| &adapter.capabilities >= required_capabilities)
-            .map(|adapter| &adapter.capabilities);
+            .filter(|adapter| adapter.health.is_healthy())
+            .filter(sufficient)
+            .collect();
+        if candidates.is_empty() {
+            candidates = self.adapters.iter().filter(sufficient).collect();
+        }
+        let cheapest_sufficient_capability =
+            candidates.first().map(|adapter| &adapter.capabilities);
 
         // Select randomly from the cheapest adapters that have sufficent capabilities.
-        self.adapters
+        candidates
             .iter()
             .filter(|adapter| Some(&adapter.capabilities) == cheapest_sufficient_capability)
             .choose(&mut rand::thread_rng())
@@ -50,12 +121,73 @@ impl EthereumNetworkAdapters {
     pub fn cheapest(&self) -> Option<Arc<EthereumAdapter>> {
         // EthereumAdapters are sorted by their NodeCapabilities when the EthereumNetworks
         // struct is instantiated so they do not need to be sorted here
-        self.adapters
-            .iter()
+        self.usable()
+            .into_iter()
             .next()
             .map(|ethereum_network_adapter| ethereum_network_adapter.adapter.clone())
     }
 
+    /// Record that a request to `provider` failed
+    pub fn report_failure(&self, provider: &str) {
+        self.adapters
+            .iter()
+            .filter(|adapter| adapter.adapter.provider() == provider)
+            .for_each(|adapter| adapter.health.failure());
+    }
+
+    /// Record that a request to `provider` succeeded
+    pub fn report_success(&self, provider: &str) {
+        self.adapters
+            .iter()
+            .filter(|adapter| adapter.adapter.provider() == provider)
+            .for_each(|adapter| adapter.health.success());
+    }
+
+    /// Check once that each provider responds
+    pub async fn check_health(&self, logger: &Logger) {
+        for adapter in &self.adapters {
+            let provider = adapter.adapter.provider();
+            let was_healthy = adapter.health.is_healthy();
+            match tokio::time::timeout(HEALTH_CHECK_TIMEOUT, adapter.adapter.check_health()).await {
+                Ok(Ok(())) => {
+                    adapter.health.success();
+                    if !was_healthy {
+                        info!(logger, "Ethereum provider is healthy again"; "provider" => provider);
+                    }
+                }
+                Ok(Err(e)) => {
+                    adapter.health.failure();
+                    warn!(logger, "Ethereum provider failed health check";
+                          "provider" => provider, "error" => e.to_string());
+                }
+                Err(_) => {
+                    adapter.health.failure();
+                    warn!(logger, "Ethereum provider timed out during health check";
+                          "provider" => provider);
+                }
+            }
+            if was_healthy && !adapter.health.is_healthy() {
+                warn!(logger, "Not using Ethereum provider until it is healthy again";
+                      "provider" => provider);
+            }
+        }
+    }
+
+    /// Check the health of all providers every
+    /// `GRAPH_ETHEREUM_HEALTH_CHECK_INTERVAL` seconds, forever
+    pub async fn monitor_health(self, logger: Logger) {
+        if *HEALTH_CHECK_INTERVAL == 0 || self.adapters.len() < 2 {
+            // With only one provider, there is nothing to fail over to
+            return;
+        }
+        let logger = logger.new(o!("component" => "EthereumHealthCheck"));
+        let mut interval = tokio::time::interval(Duration::from_secs(*HEALTH_CHECK_INTERVAL));
+        loop {
+            interval.tick().await;
+            self.check_health(&logger).await;
+        }
+    }
+
     pub fn remove(&mut self, provider: &str) {
         self.adapters
             .retain(|adapter| adapter.adapter.provider() != provider);
@@ -87,6 +219,7 @@ impl EthereumNetworks {
         network_adapters.adapters.push(EthereumNetworkAdapter {
             capabilities,
             adapter: adapter.clone(),
+            health: Arc::new(ProviderHealth::default()),
         });
     }
 
@@ -140,7 +273,24 @@ impl EthereumNetworks {
 
 #[cfg(test)]
 mod tests {
-    use super::NodeCapabilities;
+    use super::{NodeCapabilities, ProviderHealth, FAILURES_BEFORE_UNHEALTHY};
+
+    #[test]
+    fn provider_health_needs_repeated_failures() {
+        let health = ProviderHealth::default();
+        assert!(health.is_healthy());
+
+        for _ in 1..FAILURES_BEFORE_UNHEALTHY {
+            health.failure();
+        }
+        assert!(health.is_healthy());
+
+        health.failure();
+        assert!(!health.is_healthy());
+
+        health.success();
+        assert!(health.is_healthy());
+    }
 
     #[test]
     fn ethereum_capabilities_comparison() {
@@ -1,9 +1,11 @@
+use graph::prelude::web3::types::{Log, TransactionReceipt, H256};
 use graph::prelude::BigInt;
 use graph::runtime::{asc_get, asc_new, AscPtr, DeterministicHostError, FromAscObj, ToAscObj};
 use graph::runtime::{AscHeap, AscIndexId, AscType, IndexForAscTypeId};
 use graph_runtime_derive::AscType;
 use graph_runtime_wasm::asc_abi::class::{
-    Array, AscAddress, AscBigInt, AscEnum, AscH160, AscString, EthereumValueKind, Uint8Array,
+    Array, AscAddress, AscBigInt, AscEnum, AscH160, AscString, AscWrapped, EthereumValueKind,
+    Uint8Array,
 };
 use semver::Version;
 
@@ -44,6 +46,68 @@ impl AscIndexId for AscLogParamArray {
     const INDEX_ASC_TYPE_ID: IndexForAscTypeId = IndexForAscTypeId::ArrayEventParam;
 }
 
+pub struct AscTopicArray(Array<AscPtr<AscH256>>);
+
+impl AscType for AscTopicArray {
+    fn to_asc_bytes(&self) -> Result<Vec<u8>, DeterministicHostError> {
+        self.0.to_asc_bytes()
+    }
+    fn from_asc_bytes(
+        asc_obj: &[u8],
+        api_version: &Version,
+    ) -> Result<Self, DeterministicHostError> {
+        Ok(Self(Array::from_asc_bytes(asc_obj, api_version)?))
+    }
+}
+
+impl ToAscObj<AscTopicArray> for Vec<H256> {
+    fn to_asc_obj<H: AscHeap + ?Sized>(
+        &self,
+        heap: &mut H,
+    ) -> Result<AscTopicArray, DeterministicHostError> {
+        let topics = self
+            .iter()
+            .map(|topic| asc_new(heap, topic))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(AscTopicArray(Array::new(&topics, heap)?))
+    }
+}
+
+impl AscIndexId for AscTopicArray {
+    const INDEX_ASC_TYPE_ID: IndexForAscTypeId = IndexForAscTypeId::ArrayH256;
+}
+
+pub struct AscLogArray(Array<AscPtr<AscEthereumLog>>);
+
+impl AscType for AscLogArray {
+    fn to_asc_bytes(&self) -> Result<Vec<u8>, DeterministicHostError> {
+        self.0.to_asc_bytes()
+    }
+    fn from_asc_bytes(
+        asc_obj: &[u8],
+        api_version: &Version,
+    ) -> Result<Self, DeterministicHostError> {
+        Ok(Self(Array::from_asc_bytes(asc_obj, api_version)?))
+    }
+}
+
+impl ToAscObj<AscLogArray> for Vec<Log> {
+    fn to_asc_obj<H: AscHeap + ?Sized>(
+        &self,
+        heap: &mut H,
+    ) -> Result<AscLogArray, DeterministicHostError> {
+        let logs = self
+            .iter()
+            .map(|log| asc_new(heap, log))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(AscLogArray(Array::new(&logs, heap)?))
+    }
+}
+
+impl AscIndexId for AscLogArray {
+    const INDEX_ASC_TYPE_ID: IndexForAscTypeId = IndexForAscTypeId::ArrayLog;
+}
+
 #[repr(C)]
 #[derive(AscType)]
 pub struct AscUnresolvedContractCall_0_0_4 {
@@ -223,6 +287,67 @@ impl AscIndexId for AscEthereumEvent<AscEthereumTransaction_0_0_6, AscEthereumBl
     const INDEX_ASC_TYPE_ID: IndexForAscTypeId = IndexForAscTypeId::EthereumEvent;
 }
 
+#[repr(C)]
+#[derive(AscType)]
+pub(crate) struct AscEthereumEvent_0_0_7<T, B>
+where
+    T: AscType,
+    B: AscType,
+{
+    pub address: AscPtr<AscAddress>,
+    pub log_index: AscPtr<AscBigInt>,
+    pub transaction_log_index: AscPtr<AscBigInt>,
+    pub log_type: AscPtr<AscString>,
+    pub block: AscPtr<B>,
+    pub transaction: AscPtr<T>,
+    pub params: AscPtr<AscLogParamArray>,
+    pub receipt: AscPtr<AscEthereumTransactionReceipt>,
+}
+
+impl AscIndexId for AscEthereumEvent_0_0_7<AscEthereumTransaction_0_0_6, AscEthereumBlock_0_0_6> {
+    const INDEX_ASC_TYPE_ID: IndexForAscTypeId = IndexForAscTypeId::EthereumEvent;
+}
+
+#[repr(C)]
+#[derive(AscType)]
+pub(crate) struct AscEthereumLog {
+    pub address: AscPtr<AscAddress>,
+    pub topics: AscPtr<AscTopicArray>,
+    pub data: AscPtr<Uint8Array>,
+    pub block_hash: AscPtr<AscH256>,
+    pub block_number: AscPtr<AscBigInt>,
+    pub transaction_hash: AscPtr<AscH256>,
+    pub transaction_index: AscPtr<AscBigInt>,
+    pub log_index: AscPtr<AscBigInt>,
+    pub transaction_log_index: AscPtr<AscBigInt>,
+    pub log_type: AscPtr<AscString>,
+    pub removed: AscPtr<AscWrapped<bool>>,
+}
+
+impl AscIndexId for AscEthereumLog {
+    const INDEX_ASC_TYPE_ID: IndexForAscTypeId = IndexForAscTypeId::Log;
+}
+
+#[repr(C)]
+#[derive(AscType)]
+pub(crate) struct AscEthereumTransactionReceipt {
+    pub transaction_hash: AscPtr<AscH256>,
+    pub transaction_index: AscPtr<AscBigInt>,
+    pub block_hash: AscPtr<AscH256>,
+    pub block_number: AscPtr<AscBigInt>,
+    pub cumulative_gas_used: AscPtr<AscBigInt>,
+    pub gas_used: AscPtr<AscBigInt>,
+    pub contract_address: AscPtr<AscAddress>,
+    pub logs: AscPtr<AscLogArray>,
+    pub status: AscPtr<AscBigInt>,
+    pub root: AscPtr<AscH256>,
+    pub logs_bloom: AscPtr<Uint8Array>,
+}
+
+impl AscIndexId for AscEthereumTransactionReceipt {
+    const INDEX_ASC_TYPE_ID: IndexForAscTypeId = IndexForAscTypeId::TransactionReceipt;
+}
+
 #[repr(C)]
 #[derive(AscType)]
 pub(crate) struct AscLogParam {
@@ -422,6 +547,130 @@ where
     }
 }
 
+impl<T, B> ToAscObj<AscEthereumEvent_0_0_7<T, B>> for EthereumEventData
+where
+    T: AscType + AscIndexId,
+    B: AscType + AscIndexId,
+    EthereumTransactionData: ToAscObj<T>,
+    EthereumBlockData: ToAscObj<B>,
+{
+    fn to_asc_obj<H: AscHeap + ?Sized>(
+        &self,
+        heap: &mut H,
+    ) -> Result<AscEthereumEvent_0_0_7<T, B>, DeterministicHostError> {
+        let AscEthereumEvent {
+            address,
+            log_index,
+            transaction_log_index,
+            log_type,
+            block,
+            transaction,
+            params,
+        } = ToAscObj::<AscEthereumEvent<T, B>>::to_asc_obj(self, heap)?;
+        Ok(AscEthereumEvent_0_0_7 {
+            address,
+            log_index,
+            transaction_log_index,
+            log_type,
+            block,
+            transaction,
+            params,
+            receipt: match &self.receipt {
+                Some(receipt) => asc_new(heap, receipt.as_ref())?,
+                None => AscPtr::null(),
+            },
+        })
+    }
+}
+
+impl ToAscObj<AscEthereumLog> for Log {
+    fn to_asc_obj<H: AscHeap + ?Sized>(
+        &self,
+        heap: &mut H,
+    ) -> Result<AscEthereumLog, DeterministicHostError> {
+        Ok(AscEthereumLog {
+            address: asc_new(heap, &self.address)?,
+            topics: asc_new(heap, &self.topics)?,
+            data: asc_new(heap, self.data.0.as_slice())?,
+            block_hash: self
+                .block_hash
+                .map(|block_hash| asc_new(heap, &block_hash))
+                .unwrap_or(Ok(AscPtr::null()))?,
+            block_number: self
+                .block_number
+                .map(|block_number| asc_new(heap, &BigInt::from(block_number)))
+                .unwrap_or(Ok(AscPtr::null()))?,
+            transaction_hash: self
+                .transaction_hash
+                .map(|transaction_hash| asc_new(heap, &transaction_hash))
+                .unwrap_or(Ok(AscPtr::null()))?,
+            transaction_index: self
+                .transaction_index
+                .map(|transaction_index| asc_new(heap, &BigInt::from(transaction_index)))
+                .unwrap_or(Ok(AscPtr::null()))?,
+            log_index: self
+                .log_index
+                .map(|log_index| asc_new(heap, &BigInt::from_unsigned_u256(&log_index)))
+                .unwrap_or(Ok(AscPtr::null()))?,
+            transaction_log_index: self
+                .transaction_log_index
+                .map(|index| asc_new(heap, &BigInt::from_unsigned_u256(&index)))
+                .unwrap_or(Ok(AscPtr::null()))?,
+            log_type: self
+                .log_type
+                .as_ref()
+                .map(|log_type| asc_new(heap, log_type.as_str()))
+                .unwrap_or(Ok(AscPtr::null()))?,
+            removed: self
+                .removed
+                .map(|removed| asc_new(heap, &AscWrapped { inner: removed }))
+                .unwrap_or(Ok(AscPtr::null()))?,
+        })
+    }
+}
+
+impl ToAscObj<AscEthereumTransactionReceipt> for TransactionReceipt {
+    fn to_asc_obj<H: AscHeap + ?Sized>(
+        &self,
+        heap: &mut H,
+    ) -> Result<AscEthereumTransactionReceipt, DeterministicHostError> {
+        Ok(AscEthereumTransactionReceipt {
+            transaction_hash: asc_new(heap, &self.transaction_hash)?,
+            transaction_index: asc_new(heap, &BigInt::from(self.transaction_index))?,
+            block_hash: self
+                .block_hash
+                .map(|block_hash| asc_new(heap, &block_hash))
+                .unwrap_or(Ok(AscPtr::null()))?,
+            block_number: self
+                .block_number
+                .map(|block_number| asc_new(heap, &BigInt::from(block_number)))
+                .unwrap_or(Ok(AscPtr::null()))?,
+            cumulative_gas_used: asc_new(
+                heap,
+                &BigInt::from_unsigned_u256(&self.cumulative_gas_used),
+            )?,
+            gas_used: self
+                .gas_used
+                .map(|gas_used| asc_new(heap, &BigInt::from_unsigned_u256(&gas_used)))
+                .unwrap_or(Ok(AscPtr::null()))?,
+            contract_address: self
+                .contract_address
+                .map(|contract_address| asc_new(heap, &contract_address))
+                .unwrap_or(Ok(AscPtr::null()))?,
+            logs: asc_new(heap, &self.logs)?,
+            status: self
+                .status
+                .map(|status| asc_new(heap, &BigInt::from(status)))
+                .unwrap_or(Ok(AscPtr::null()))?,
+            root: self
+                .root
+                .map(|root| asc_new(heap, &root))
+                .unwrap_or(Ok(AscPtr::null()))?,
+            logs_bloom: asc_new(heap, self.logs_bloom.as_bytes())?,
+        })
+    }
+}
+
 impl ToAscObj<AscEthereumCall> for EthereumCallData {
     fn to_asc_obj<H: AscHeap + ?Sized>(
         &self,
@@ -490,3 +739,114 @@ impl ToAscObj<AscLogParam> for ethabi::LogParam {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use graph::data::subgraph::API_VERSION_0_0_7;
+    use graph::prelude::anyhow::anyhow;
+    use graph::prelude::web3::types::{Bytes, Log, TransactionReceipt, H256, U256, U64};
+    use graph::prelude::BigInt;
+    use graph::runtime::{asc_get, asc_new, AscHeap, AscPtr, DeterministicHostError};
+
+    use super::{AscEthereumLog, AscEthereumTransactionReceipt};
+
+    struct BytesHeap {
+        api_version: graph::semver::Version,
+        memory: Vec<u8>,
+    }
+
+    impl AscHeap for BytesHeap {
+        fn raw_new(&mut self, bytes: &[u8]) -> Result<u32, DeterministicHostError> {
+            self.memory.extend_from_slice(bytes);
+            Ok((self.memory.len() - bytes.len()) as u32)
+        }
+
+        fn get(&self, offset: u32, size: u32) -> Result<Vec<u8>, DeterministicHostError> {
+            let start = offset as usize;
+            let end = start + size as usize;
+            self.memory.get(start..end).map(Vec::from).ok_or_else(|| {
+                DeterministicHostError(anyhow!("{}..{} is out of bounds", start, end))
+            })
+        }
+
+        fn api_version(&self) -> graph::semver::Version {
+            self.api_version.clone()
+        }
+
+        fn asc_type_id(
+            &mut self,
+            type_id_index: graph::runtime::IndexForAscTypeId,
+        ) -> Result<u32, DeterministicHostError> {
+            Ok(type_id_index as u32)
+        }
+    }
+
+    fn log(transaction_hash: H256) -> Log {
+        Log {
+            address: Default::default(),
+            topics: vec![H256::from_low_u64_be(7)],
+            data: Bytes(vec![1, 2, 3]),
+            block_hash: Some(H256::from_low_u64_be(100)),
+            block_number: Some(U64::from(5)),
+            transaction_hash: Some(transaction_hash),
+            transaction_index: Some(U64::from(2)),
+            log_index: Some(U256::from(3)),
+            transaction_log_index: None,
+            log_type: None,
+            removed: Some(false),
+        }
+    }
+
+    #[test]
+    fn receipt_to_asc() {
+        let mut heap = BytesHeap {
+            api_version: API_VERSION_0_0_7,
+            memory: vec![],
+        };
+        let transaction_hash = H256::from_low_u64_be(1);
+        let receipt = TransactionReceipt {
+            transaction_hash,
+            transaction_index: U64::from(2),
+            block_hash: Some(H256::from_low_u64_be(100)),
+            block_number: Some(U64::from(5)),
+            cumulative_gas_used: U256::from(50_000),
+            gas_used: Some(U256::from(21_000)),
+            logs: vec![log(transaction_hash)],
+            status: Some(U64::from(1)),
+            ..Default::default()
+        };
+
+        let ptr: AscPtr<AscEthereumTransactionReceipt> = asc_new(&mut heap, &receipt).unwrap();
+        let asc = ptr.read_ptr(&heap).unwrap();
+
+        let hash: H256 = asc_get(&heap, asc.transaction_hash).unwrap();
+        assert_eq!(transaction_hash, hash);
+        let gas_used: BigInt = asc_get(&heap, asc.gas_used).unwrap();
+        assert_eq!(BigInt::from(21_000), gas_used);
+        let status: BigInt = asc_get(&heap, asc.status).unwrap();
+        assert_eq!(BigInt::from(1), status);
+        assert!(!asc.logs.is_null());
+        // Optional fields that are not set are null
+        assert!(asc.contract_address.is_null());
+        assert!(asc.root.is_null());
+    }
+
+    #[test]
+    fn log_to_asc() {
+        let mut heap = BytesHeap {
+            api_version: API_VERSION_0_0_7,
+            memory: vec![],
+        };
+        let transaction_hash = H256::from_low_u64_be(1);
+
+        let ptr: AscPtr<AscEthereumLog> = asc_new(&mut heap, &log(transaction_hash)).unwrap();
+        let asc = ptr.read_ptr(&heap).unwrap();
+
+        let hash: H256 = asc_get(&heap, asc.transaction_hash).unwrap();
+        assert_eq!(transaction_hash, hash);
+        let log_index: BigInt = asc_get(&heap, asc.log_index).unwrap();
+        assert_eq!(BigInt::from(3), log_index);
+        assert!(asc.transaction_log_index.is_null());
+        assert!(asc.log_type.is_null());
+    }
+}
@@ -7,11 +7,13 @@ use crate::{
 };
 use anyhow::{Context, Error};
 use blockchain::HostFn;
-use ethabi::{Address, Token};
+use ethabi::{Address, Function, Token};
 use graph::runtime::{AscIndexId, IndexForAscTypeId};
 use graph::{
     blockchain::{self, BlockPtr, HostFnCtx},
     cheap_clone::CheapClone,
+    components::subgraph::{host_call, HostCallKind, HostCalls},
+    data::subgraph::API_VERSION_0_0_7,
     prelude::{EthereumCallCache, Future01CompatExt},
     runtime::{asc_get, asc_new, AscPtr, HostExportError},
     semver::Version,
@@ -72,8 +74,10 @@ fn ethereum_call(
         call_cache,
         &ctx.logger,
         &ctx.block_ptr,
+        ctx.host_calls.as_deref(),
         call,
         abis,
+        &ctx.heap.api_version(),
     )?;
     match result {
         Some(tokens) => Ok(asc_new(ctx.heap, tokens.as_slice())?),
@@ -87,13 +91,90 @@ fn eth_call(
     call_cache: Arc<dyn EthereumCallCache>,
     logger: &Logger,
     block_ptr: &BlockPtr,
+    host_calls: Option<&HostCalls>,
     unresolved_call: UnresolvedContractCall,
     abis: &[Arc<MappingABI>],
+    api_version: &Version,
 ) -> Result<Option<Vec<Token>>, HostExportError> {
     let start_time = Instant::now();
 
+    let function =
+        resolve_function(abis, &unresolved_call).map_err(|e| mapping_error(api_version, e))?;
+
+    let call = EthereumContractCall {
+        address: unresolved_call.contract_address,
+        block_ptr: block_ptr.cheap_clone(),
+        function: function.clone(),
+        args: unresolved_call.function_args.clone(),
+    };
+
+    // Recordings hold the ABI encoded output of the call so that it can
+    // be decoded again when replaying
+    let request = (
+        unresolved_call.contract_address,
+        function.signature(),
+        format!("{:?}", unresolved_call.function_args),
+    );
+    let output = host_call(
+        host_calls,
+        block_ptr.number,
+        HostCallKind::EthereumCall,
+        &request,
+        || {
+            contract_call(
+                eth_adapter,
+                call_cache,
+                logger,
+                call,
+                &unresolved_call,
+                api_version,
+            )
+            .map(|tokens| tokens.map(|tokens| hex::encode(ethabi::encode(&tokens))))
+        },
+    )?;
+    let result = match output {
+        Some(output) => {
+            let output = hex::decode(output).map_err(Error::from)?;
+            Some(
+                function
+                    .decode_output(&output)
+                    .map_err(|e| mapping_error(api_version, Error::from(e)))?,
+            )
+        }
+        None => None,
+    };
+
+    trace!(logger, "Contract call finished";
+              "address" => &unresolved_call.contract_address.to_string(),
+              "contract" => &unresolved_call.contract_name,
+              "function" => &unresolved_call.function_name,
+              "function_signature" => &unresolved_call.function_signature,
+              "time" => format!("{}ms", start_time.elapsed().as_millis()));
+
+    Ok(result)
+}
+
+/// Problems with the ABI or with what the mapping asks for happen every
+/// time the mapping runs. From apiVersion 0.0.7 on they are deterministic
+/// errors. For older subgraphs they stay nondeterministic and halt the
+/// subgraph as they always did, since skipping the handler instead would
+/// change their entities and proof of indexing
+fn mapping_error(api_version: &Version, e: Error) -> HostExportError {
+    if api_version >= &API_VERSION_0_0_7 {
+        HostExportError::Deterministic(e)
+    } else {
+        HostExportError::Unknown(e)
+    }
+}
+
+/// Find the function that `unresolved_call` calls in the ABIs of the data
+/// source
+fn resolve_function(
+    abis: &[Arc<MappingABI>],
+    unresolved_call: &UnresolvedContractCall,
+) -> Result<Function, Error> {
     // Obtain the path to the contract ABI
-    let contract = abis
+    let contract = &abis
         .iter()
         .find(|abi| abi.name == unresolved_call.contract_name)
         .with_context(|| {
@@ -103,8 +184,7 @@ fn eth_call(
                 unresolved_call.contract_name
             )
         })?
-        .contract
-        .clone();
+        .contract;
 
     let function = match unresolved_call.function_signature {
         // Behavior for apiVersion < 0.0.4: look up function by name; for overloaded
@@ -142,59 +222,79 @@ fn eth_call(
                 )
             })?,
     };
+    Ok(function.clone())
+}
 
-    let call = EthereumContractCall {
-        address: unresolved_call.contract_address,
-        block_ptr: block_ptr.cheap_clone(),
-        function: function.clone(),
-        args: unresolved_call.function_args.clone(),
-    };
-
+/// Run `call` against the Ethereum node. Returns `Ok(None)` if the call
+/// was reverted.
+fn contract_call(
+    eth_adapter: &EthereumAdapter,
+    call_cache: Arc<dyn EthereumCallCache>,
+    logger: &Logger,
+    call: EthereumContractCall,
+    unresolved_call: &UnresolvedContractCall,
+    api_version: &Version,
+) -> Result<Option<Vec<Token>>, HostExportError> {
     // Run Ethereum call in tokio runtime
     let logger1 = logger.clone();
-    let call_cache = call_cache.clone();
-    let result = match graph::block_on(
-            eth_adapter.contract_call(&logger1, call, call_cache).compat()
-        ) {
-            Ok(tokens) => Ok(Some(tokens)),
-            Err(EthereumContractCallError::Revert(reason)) => {
-                info!(logger, "Contract call reverted"; "reason" => reason);
-                Ok(None)
-            }
-
-            // Any error reported by the Ethereum node could be due to the block no longer being on
-            // the main chain. This is very unespecific but we don't want to risk failing a
-            // subgraph due to a transient error such as a reorg.
-            Err(EthereumContractCallError::Web3Error(e)) => Err(HostExportError::PossibleReorg(anyhow::anyhow!(
-                "Ethereum node returned an error when calling function \"{}\" of contract \"{}\": {}",
+    match graph::block_on(
+        eth_adapter
+            .contract_call(&logger1, call, call_cache)
+            .compat(),
+    ) {
+        Ok(tokens) => Ok(Some(tokens)),
+        Err(e) => call_error(logger, unresolved_call, api_version, e),
+    }
+}
+
+/// Turn the error from calling `unresolved_call` into the result of the
+/// call. Reverted calls return `Ok(None)`
+fn call_error(
+    logger: &Logger,
+    unresolved_call: &UnresolvedContractCall,
+    api_version: &Version,
+    e: EthereumContractCallError,
+) -> Result<Option<Vec<Token>>, HostExportError> {
+    match e {
+        EthereumContractCallError::Revert(reason) => {
+            info!(logger, "Contract call reverted"; "reason" => reason);
+            Ok(None)
+        }
+
+        // Any error reported by the Ethereum node could be due to the block no longer being on
+        // the main chain. This is very unespecific but we don't want to risk failing a
+        // subgraph due to a transient error such as a reorg.
+        EthereumContractCallError::Web3Error(e) => {
+            Err(HostExportError::PossibleReorg(anyhow::anyhow!(
+                "Ethereum node returned an error when calling function \"{}\" \
+                 of contract \"{}\": {}",
                 unresolved_call.function_name,
                 unresolved_call.contract_name,
                 e
-            ))),
+            )))
+        }
 
-            // Also retry on timeouts.
-            Err(EthereumContractCallError::Timeout) => Err(HostExportError::PossibleReorg(anyhow::anyhow!(
-                "Ethereum node did not respond when calling function \"{}\" of contract \"{}\"",
-                unresolved_call.function_name,
-                unresolved_call.contract_name,
-            ))),
+        // Also retry on timeouts.
+        EthereumContractCallError::Timeout => Err(HostExportError::PossibleReorg(anyhow::anyhow!(
+            "Ethereum node did not respond when calling function \"{}\" of contract \"{}\"",
+            unresolved_call.function_name,
+            unresolved_call.contract_name,
+        ))),
 
-            Err(e) => Err(HostExportError::Unknown(anyhow::anyhow!(
+        // The arguments do not match the function's ABI; calling again
+        // will fail the same way
+        e @ EthereumContractCallError::ABIError(_)
+        | e @ EthereumContractCallError::TypeError(_, _)
+        | e @ EthereumContractCallError::EncodingError(_) => Err(mapping_error(
+            api_version,
+            anyhow::anyhow!(
                 "Failed to call function \"{}\" of contract \"{}\": {}",
                 unresolved_call.function_name,
                 unresolved_call.contract_name,
                 e
-            ))),
-        };
-
-    trace!(logger, "Contract call finished";
-              "address" => &unresolved_call.contract_address.to_string(),
-              "contract" => &unresolved_call.contract_name,
-              "function" => &unresolved_call.function_name,
-              "function_signature" => &unresolved_call.function_signature,
-              "time" => format!("{}ms", start_time.elapsed().as_millis()));
-
-    result
+            ),
+        )),
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -209,3 +309,129 @@ pub struct UnresolvedContractCall {
 impl AscIndexId for AscUnresolvedContractCall {
     const INDEX_ASC_TYPE_ID: IndexForAscTypeId = IndexForAscTypeId::SmartContractCall;
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use ethabi::{Contract, ParamType, Token};
+    use graph::prelude::{o, slog, Logger};
+    use graph::runtime::HostExportError;
+    use graph::semver::Version;
+
+    use super::{call_error, mapping_error, resolve_function, UnresolvedContractCall};
+    use crate::data_source::MappingABI;
+    use crate::EthereumContractCallError;
+
+    const ABI: &str = r#"[
+        {
+            "type": "function",
+            "name": "balanceOf",
+            "constant": true,
+            "inputs": [{ "name": "owner", "type": "address" }],
+            "outputs": [{ "name": "", "type": "uint256" }]
+        }
+    ]"#;
+
+    fn abis() -> Vec<Arc<MappingABI>> {
+        vec![Arc::new(MappingABI {
+            name: "Token".to_string(),
+            contract: Contract::load(ABI.as_bytes()).unwrap(),
+        })]
+    }
+
+    fn call(contract: &str, function: &str, signature: Option<&str>) -> UnresolvedContractCall {
+        UnresolvedContractCall {
+            contract_name: contract.to_string(),
+            contract_address: Default::default(),
+            function_name: function.to_string(),
+            function_signature: signature.map(str::to_string),
+            function_args: vec![Token::Address(Default::default())],
+        }
+    }
+
+    fn is_deterministic(e: &HostExportError) -> bool {
+        matches!(e, HostExportError::Deterministic(_))
+    }
+
+    fn is_unknown(e: &HostExportError) -> bool {
+        matches!(e, HostExportError::Unknown(_))
+    }
+
+    /// Errors that the mapping causes are only deterministic from
+    /// apiVersion 0.0.7 on; older subgraphs keep halting on them
+    fn assert_mapping_error(check: impl Fn(&Version) -> HostExportError) {
+        assert!(is_unknown(&check(&Version::new(0, 0, 6))));
+        assert!(is_deterministic(&check(&Version::new(0, 0, 7))));
+    }
+
+    #[test]
+    fn unknown_abi_or_function() {
+        let abis = abis();
+        let signature = "balanceOf(address):(uint256)";
+
+        assert!(resolve_function(&abis, &call("Token", "balanceOf", None)).is_ok());
+        assert!(resolve_function(&abis, &call("Token", "balanceOf", Some(signature))).is_ok());
+
+        for call in vec![
+            call("Other", "balanceOf", None),
+            call("Token", "transfer", None),
+            call(
+                "Token",
+                "transfer",
+                Some("transfer(address,uint256):(bool)"),
+            ),
+            call("Token", "balanceOf", Some("balanceOf(uint256):(uint256)")),
+        ] {
+            assert_mapping_error(|version| {
+                mapping_error(version, resolve_function(&abis, &call).unwrap_err())
+            });
+        }
+    }
+
+    #[test]
+    fn undecodable_output() {
+        let function = resolve_function(&abis(), &call("Token", "balanceOf", None)).unwrap();
+        assert_mapping_error(|version| {
+            let e = function.decode_output(&[1, 2, 3]).unwrap_err();
+            mapping_error(version, e.into())
+        });
+    }
+
+    #[test]
+    fn call_errors() {
+        let logger = Logger::root(slog::Discard, o!());
+        let unresolved = call("Token", "balanceOf", None);
+        let function = resolve_function(&abis(), &unresolved).unwrap();
+        let abi_error = || function.encode_input(&[Token::Bool(true)]).unwrap_err();
+
+        let mapping_errors: Vec<Box<dyn Fn() -> EthereumContractCallError>> = vec![
+            Box::new(|| EthereumContractCallError::ABIError(abi_error())),
+            Box::new(|| {
+                EthereumContractCallError::TypeError(Token::Bool(true), ParamType::Address)
+            }),
+            Box::new(|| EthereumContractCallError::EncodingError(abi_error())),
+        ];
+        for error in mapping_errors {
+            assert_mapping_error(|version| {
+                call_error(&logger, &unresolved, version, error()).unwrap_err()
+            });
+        }
+
+        let version = Version::new(0, 0, 7);
+        let reverted = EthereumContractCallError::Revert("out of gas".to_string());
+        assert!(matches!(
+            call_error(&logger, &unresolved, &version, reverted),
+            Ok(None)
+        ));
+        assert!(matches!(
+            call_error(
+                &logger,
+                &unresolved,
+                &version,
+                EthereumContractCallError::Timeout
+            ),
+            Err(HostExportError::PossibleReorg(_))
+        ));
+    }
+}
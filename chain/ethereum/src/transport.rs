@@ -1,19 +1,30 @@
 use jsonrpc_core::types::Call;
 use serde_json::Value;
 use std::env;
+use std::sync::{Arc, RwLock};
 
+use web3::api::SubscriptionId;
 pub use web3::transports::EventLoopHandle;
 use web3::transports::{http, ipc, ws};
 use web3::RequestId;
 
 use graph::prelude::*;
 
+use crate::adapter::ProviderEthRpcMetrics;
+use crate::limiter::RequestLimiter;
+
 /// Abstraction over the different web3 transports.
 #[derive(Clone, Debug)]
 pub enum Transport {
     RPC(http::Http),
     IPC(ipc::Ipc),
     WS(ws::WebSocket),
+    /// Another transport whose requests are subject to rate and
+    /// concurrency limits
+    Limited(Box<Transport>, Arc<RequestLimiter>),
+    /// Another transport that can be swapped out with `replace`, for
+    /// example, because the API key that it sends changed
+    Replaceable(Arc<RwLock<Transport>>),
 }
 
 impl Transport {
@@ -44,6 +55,52 @@ impl Transport {
             .map(|(event_loop, transport)| (event_loop, Transport::RPC(transport)))
             .expect("Failed to connect to Ethereum RPC")
     }
+
+    /// Send at most `rate_limit` requests per second to `provider` through
+    /// this transport, and have at most `max_concurrent_requests` of them
+    /// in flight at the same time. Requests over the limits wait until they
+    /// can be sent. Each call in a batch counts as one request
+    pub fn with_limits(
+        self,
+        provider: &str,
+        rate_limit: Option<f64>,
+        max_concurrent_requests: Option<usize>,
+        metrics: Arc<ProviderEthRpcMetrics>,
+    ) -> Self {
+        if rate_limit.is_none() && max_concurrent_requests.is_none() {
+            return self;
+        }
+        let limiter = RequestLimiter::new(provider, rate_limit, max_concurrent_requests, metrics);
+        Transport::Limited(Box::new(self), Arc::new(limiter))
+    }
+
+    /// Make it possible to swap out this transport later by calling
+    /// `replace` on the returned transport or any of its clones
+    pub fn replaceable(self) -> Self {
+        Transport::Replaceable(Arc::new(RwLock::new(self)))
+    }
+
+    /// Send all future requests through `transport`. Requests that are
+    /// in flight finish with the old transport. Does nothing if this
+    /// transport was not made with `replaceable`
+    pub fn replace(&self, transport: Transport) {
+        match self {
+            Transport::Replaceable(inner) => *inner.write().unwrap() = transport,
+            Transport::Limited(inner, _) => inner.replace(transport),
+            Transport::RPC(_) | Transport::IPC(_) | Transport::WS(_) => (),
+        }
+    }
+
+    /// Whether the provider can push notifications like new chain heads to
+    /// us over this transport. That is not possible over HTTP
+    pub fn supports_subscriptions(&self) -> bool {
+        match self {
+            Transport::RPC(_) => false,
+            Transport::IPC(_) | Transport::WS(_) => true,
+            Transport::Limited(inner, _) => inner.supports_subscriptions(),
+            Transport::Replaceable(inner) => inner.read().unwrap().supports_subscriptions(),
+        }
+    }
 }
 
 impl web3::Transport for Transport {
@@ -54,6 +111,8 @@ impl web3::Transport for Transport {
             Transport::RPC(http) => http.prepare(method, params),
             Transport::IPC(ipc) => ipc.prepare(method, params),
             Transport::WS(ws) => ws.prepare(method, params),
+            Transport::Limited(inner, _) => inner.prepare(method, params),
+            Transport::Replaceable(inner) => inner.read().unwrap().prepare(method, params),
         }
     }
 
@@ -62,6 +121,19 @@ impl web3::Transport for Transport {
             Transport::RPC(http) => Box::new(http.send(id, request)),
             Transport::IPC(ipc) => Box::new(ipc.send(id, request)),
             Transport::WS(ws) => Box::new(ws.send(id, request)),
+            Transport::Limited(inner, limiter) => {
+                let inner = inner.clone();
+                let limiter = limiter.clone();
+                Box::new(
+                    async move {
+                        let _permit = limiter.acquire(1).await;
+                        inner.send(id, request).compat().await
+                    }
+                    .boxed()
+                    .compat(),
+                )
+            }
+            Transport::Replaceable(inner) => inner.read().unwrap().send(id, request),
         }
     }
 }
@@ -80,6 +152,47 @@ impl web3::BatchTransport for Transport {
             Transport::RPC(http) => Box::new(http.send_batch(requests)),
             Transport::IPC(ipc) => Box::new(ipc.send_batch(requests)),
             Transport::WS(ws) => Box::new(ws.send_batch(requests)),
+            Transport::Limited(inner, limiter) => {
+                let inner = inner.clone();
+                let limiter = limiter.clone();
+                let requests: Vec<_> = requests.into_iter().collect();
+                Box::new(
+                    async move {
+                        let _permit = limiter.acquire(requests.len()).await;
+                        inner.send_batch(requests).compat().await
+                    }
+                    .boxed()
+                    .compat(),
+                )
+            }
+            Transport::Replaceable(inner) => inner.read().unwrap().send_batch(requests),
+        }
+    }
+}
+
+impl web3::DuplexTransport for Transport {
+    type NotificationStream = Box<dyn Stream<Item = Value, Error = web3::error::Error> + Send>;
+
+    fn subscribe(&self, id: &SubscriptionId) -> Self::NotificationStream {
+        match self {
+            Transport::RPC(_) => Box::new(stream::once(Err(web3::error::Error::Transport(
+                "subscriptions are not supported over HTTP".to_string(),
+            )))),
+            Transport::IPC(ipc) => Box::new(ipc.subscribe(id)),
+            Transport::WS(ws) => Box::new(ws.subscribe(id)),
+            // Notifications are not requests and therefore not limited
+            Transport::Limited(inner, _) => inner.subscribe(id),
+            Transport::Replaceable(inner) => inner.read().unwrap().subscribe(id),
+        }
+    }
+
+    fn unsubscribe(&self, id: &SubscriptionId) {
+        match self {
+            Transport::RPC(_) => (),
+            Transport::IPC(ipc) => ipc.unsubscribe(id),
+            Transport::WS(ws) => ws.unsubscribe(id),
+            Transport::Limited(inner, _) => inner.unsubscribe(id),
+            Transport::Replaceable(inner) => inner.read().unwrap().unsubscribe(id),
         }
     }
 }
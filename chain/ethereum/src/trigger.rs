@@ -17,13 +17,14 @@ use web3::types::H160;
 use web3::types::U128;
 use web3::types::U256;
 use web3::types::U64;
-use web3::types::{Address, Block, Log, Transaction, H256};
+use web3::types::{Address, Block, Log, Transaction, TransactionReceipt, H256};
 
 use crate::runtime::abi::AscEthereumBlock;
 use crate::runtime::abi::AscEthereumBlock_0_0_6;
 use crate::runtime::abi::AscEthereumCall;
 use crate::runtime::abi::AscEthereumCall_0_0_3;
 use crate::runtime::abi::AscEthereumEvent;
+use crate::runtime::abi::AscEthereumEvent_0_0_7;
 use crate::runtime::abi::AscEthereumTransaction_0_0_1;
 use crate::runtime::abi::AscEthereumTransaction_0_0_2;
 use crate::runtime::abi::AscEthereumTransaction_0_0_6;
@@ -37,6 +38,7 @@ pub enum MappingTrigger {
         transaction: Arc<Transaction>,
         log: Arc<Log>,
         params: Vec<LogParam>,
+        receipt: Option<Arc<TransactionReceipt>>,
     },
     Call {
         block: Arc<LightEthereumBlock>,
@@ -59,6 +61,7 @@ impl std::fmt::Debug for MappingTrigger {
                 transaction: Arc<Transaction>,
                 log: Arc<Log>,
                 params: Vec<LogParam>,
+                receipt: Option<Arc<TransactionReceipt>>,
             },
             Call {
                 transaction: Arc<Transaction>,
@@ -75,10 +78,12 @@ impl std::fmt::Debug for MappingTrigger {
                 transaction,
                 log,
                 params,
+                receipt,
             } => MappingTriggerWithoutBlock::Log {
                 transaction: transaction.cheap_clone(),
                 log: log.cheap_clone(),
                 params: params.clone(),
+                receipt: receipt.cheap_clone(),
             },
             MappingTrigger::Call {
                 block: _,
@@ -107,6 +112,7 @@ impl blockchain::MappingTrigger for MappingTrigger {
                 transaction,
                 log,
                 params,
+                receipt,
             } => {
                 let ethereum_event_data = EthereumEventData {
                     block: EthereumBlockData::from(block.as_ref()),
@@ -116,9 +122,20 @@ impl blockchain::MappingTrigger for MappingTrigger {
                     transaction_log_index: log.log_index.unwrap_or(U256::zero()),
                     log_type: log.log_type.clone(),
                     params,
+                    receipt,
                 };
                 let api_version = heap.api_version();
-                if api_version >= Version::new(0, 0, 6) {
+                if api_version >= Version::new(0, 0, 7) {
+                    asc_new::<
+                        AscEthereumEvent_0_0_7<
+                            AscEthereumTransaction_0_0_6,
+                            AscEthereumBlock_0_0_6,
+                        >,
+                        _,
+                        _,
+                    >(heap, &ethereum_event_data)?
+                    .erase()
+                } else if api_version >= Version::new(0, 0, 6) {
                     asc_new::<
                         AscEthereumEvent<AscEthereumTransaction_0_0_6, AscEthereumBlock_0_0_6>,
                         _,
@@ -188,7 +205,9 @@ impl blockchain::MappingTrigger for MappingTrigger {
 pub enum EthereumTrigger {
     Block(BlockPtr, EthereumBlockTriggerType),
     Call(Arc<EthereumCall>),
-    Log(Arc<Log>),
+    /// A log, and the receipt of the transaction that emitted it if a
+    /// handler for the log needs it
+    Log(Arc<Log>, Option<Arc<TransactionReceipt>>),
 }
 
 impl PartialEq for EthereumTrigger {
@@ -200,7 +219,7 @@ impl PartialEq for EthereumTrigger {
 
             (Self::Call(a), Self::Call(b)) => a == b,
 
-            (Self::Log(a), Self::Log(b)) => {
+            (Self::Log(a, _), Self::Log(b, _)) => {
                 a.transaction_hash == b.transaction_hash && a.log_index == b.log_index
             }
 
@@ -215,6 +234,7 @@ impl Eq for EthereumTrigger {}
 pub enum EthereumBlockTriggerType {
     Every,
     WithCallTo(Address),
+    WithTransactionTo(Address),
 }
 
 impl EthereumTrigger {
@@ -222,7 +242,9 @@ impl EthereumTrigger {
         match self {
             EthereumTrigger::Block(block_ptr, _) => block_ptr.number,
             EthereumTrigger::Call(call) => call.block_number,
-            EthereumTrigger::Log(log) => i32::try_from(log.block_number.unwrap().as_u64()).unwrap(),
+            EthereumTrigger::Log(log, _) => {
+                i32::try_from(log.block_number.unwrap().as_u64()).unwrap()
+            }
         }
     }
 
@@ -230,7 +252,7 @@ impl EthereumTrigger {
         match self {
             EthereumTrigger::Block(block_ptr, _) => block_ptr.hash_as_h256(),
             EthereumTrigger::Call(call) => call.block_hash,
-            EthereumTrigger::Log(log) => log.block_hash.unwrap(),
+            EthereumTrigger::Log(log, _) => log.block_hash.unwrap(),
         }
     }
 }
@@ -249,24 +271,24 @@ impl Ord for EthereumTrigger {
             (Self::Call(a), Self::Call(b)) => a.transaction_index.cmp(&b.transaction_index),
 
             // Events are ordered by their log index
-            (Self::Log(a), Self::Log(b)) => a.log_index.cmp(&b.log_index),
+            (Self::Log(a, _), Self::Log(b, _)) => a.log_index.cmp(&b.log_index),
 
             // Calls vs. events are logged by their tx index;
             // if they are from the same transaction, events come first
-            (Self::Call(a), Self::Log(b))
+            (Self::Call(a), Self::Log(b, _))
                 if a.transaction_index == b.transaction_index.unwrap().as_u64() =>
             {
                 Ordering::Greater
             }
-            (Self::Log(a), Self::Call(b))
+            (Self::Log(a, _), Self::Call(b))
                 if a.transaction_index.unwrap().as_u64() == b.transaction_index =>
             {
                 Ordering::Less
             }
-            (Self::Call(a), Self::Log(b)) => a
+            (Self::Call(a), Self::Log(b, _)) => a
                 .transaction_index
                 .cmp(&b.transaction_index.unwrap().as_u64()),
-            (Self::Log(a), Self::Call(b)) => a
+            (Self::Log(a, _), Self::Call(b)) => a
                 .transaction_index
                 .unwrap()
                 .as_u64()
@@ -284,7 +306,7 @@ impl PartialOrd for EthereumTrigger {
 impl TriggerData for EthereumTrigger {
     fn error_context(&self) -> std::string::String {
         let transaction_id = match self {
-            EthereumTrigger::Log(log) => log.transaction_hash,
+            EthereumTrigger::Log(log, _) => log.transaction_hash,
             EthereumTrigger::Call(call) => call.transaction_hash,
             EthereumTrigger::Block(..) => None,
         };
@@ -299,6 +321,15 @@ impl TriggerData for EthereumTrigger {
             None => String::new(),
         }
     }
+
+    fn transaction_hash(&self) -> Option<&[u8]> {
+        let hash = match self {
+            EthereumTrigger::Log(log, _) => log.transaction_hash.as_ref(),
+            EthereumTrigger::Call(call) => call.transaction_hash.as_ref(),
+            EthereumTrigger::Block(..) => None,
+        };
+        hash.map(|hash| hash.as_bytes())
+    }
 }
 
 /// Ethereum block data.
@@ -383,6 +414,7 @@ pub struct EthereumEventData {
     pub block: EthereumBlockData,
     pub transaction: EthereumTransactionData,
     pub params: Vec<LogParam>,
+    pub receipt: Option<Arc<TransactionReceipt>>,
 }
 
 impl Clone for EthereumEventData {
@@ -402,6 +434,7 @@ impl Clone for EthereumEventData {
                     value: log_param.value.clone(),
                 })
                 .collect(),
+            receipt: self.receipt.cheap_clone(),
         }
     }
 }
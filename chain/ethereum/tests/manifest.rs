@@ -238,6 +238,51 @@ specVersion: 0.0.2
     assert_eq!(true, required_capabilities.traces);
 }
 
+#[tokio::test]
+async fn parse_fallback_abis() {
+    const YAML: &str = "
+dataSources:
+  - kind: ethereum/contract
+    name: Token
+    network: mainnet
+    source:
+      abi: TokenV2
+    mapping:
+      kind: ethereum/events
+      apiVersion: 0.0.4
+      language: wasm/assemblyscript
+      entities:
+        - TestEntity
+      file:
+        /: /ipfs/Qmmapping
+      abis:
+        - name: TokenV2
+          file:
+            /: /ipfs/Qmabi
+        - name: TokenV1
+          file:
+            /: /ipfs/Qmabi
+      fallbackAbis:
+        - TokenV1
+schema:
+  file:
+    /: /ipfs/Qmschema
+specVersion: 0.0.2
+";
+
+    let manifest = resolve_manifest(YAML).await;
+    let data_source = &manifest.data_sources[0];
+
+    assert_eq!("TokenV2", data_source.contract_abi.name);
+    let fallback_abis: Vec<_> = data_source
+        .mapping
+        .fallback_abis
+        .iter()
+        .map(|abi| abi.name.as_str())
+        .collect();
+    assert_eq!(vec!["TokenV1"], fallback_abis);
+}
+
 #[test]
 fn undeclared_grafting_feature_causes_feature_validation_error() {
     const YAML: &str = "
@@ -388,9 +388,4 @@ impl IngestorAdapterTrait<Chain> for IngestorAdapter {
         // FIXME (NEAR):  Might not be necessary for NEAR support for now
         Ok(None)
     }
-
-    fn cleanup_cached_blocks(&self) -> Result<Option<(i32, usize)>, Error> {
-        // FIXME (NEAR):  Might not be necessary for NEAR support for now
-        Ok(None)
-    }
 }
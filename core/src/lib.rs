@@ -4,4 +4,7 @@ mod subgraph;
 
 pub use crate::link_resolver::LinkResolver;
 pub use crate::metrics::MetricsRegistry;
-pub use crate::subgraph::{SubgraphAssignmentProvider, SubgraphInstanceManager, SubgraphRegistrar};
+pub use crate::subgraph::{
+    IndexingShutdown, SubgraphAssignmentProvider, SubgraphInstanceManager, SubgraphRegistrar,
+    TransactionSimulator,
+};
@@ -5,6 +5,7 @@ use std::collections::HashMap;
 use std::env;
 use std::str::FromStr;
 
+use graph::util::memory_budget::{BudgetHandle, CacheKind, MEMORY_BUDGET};
 use graph::{blockchain::DataSource, prelude::*};
 use graph::{
     blockchain::{Block, Blockchain},
@@ -18,6 +19,10 @@ lazy_static! {
             .unwrap_or_else(|_| panic!("failed to parse env var GRAPH_SUBGRAPH_MAX_DATA_SOURCES")));
 }
 
+/// Warn once a subgraph has created this fraction of the data sources it
+/// is allowed to have
+const DATA_SOURCES_WARNING_RATIO: f64 = 0.9;
+
 pub struct SubgraphInstance<C: Blockchain, T: RuntimeHostBuilder<C>> {
     subgraph_id: DeploymentHash,
     network: String,
@@ -32,6 +37,18 @@ pub struct SubgraphInstance<C: Blockchain, T: RuntimeHostBuilder<C>> {
 
     /// Maps the hash of a module to a channel to the thread in which the module is instantiated.
     module_cache: HashMap<[u8; 32], Sender<T::Req>>,
+    /// The size of the modules in `module_cache` counts against the
+    /// memory budget. We use the size of the WASM code as an estimate of
+    /// the memory the compiled module needs
+    module_cache_budget: BudgetHandle,
+    module_cache_size: usize,
+
+    /// The maximum number of data sources, including dynamic ones, that
+    /// the subgraph may have
+    max_data_sources: Option<usize>,
+    /// Whether we already warned that the subgraph is getting close to
+    /// `max_data_sources`
+    warned_data_sources: bool,
 }
 
 impl<T, C: Blockchain> SubgraphInstance<C, T>
@@ -43,6 +60,7 @@ where
         manifest: SubgraphManifest<C>,
         host_builder: T,
         host_metrics: Arc<HostMetrics>,
+        max_data_sources: Option<usize>,
     ) -> Result<Self, Error> {
         let subgraph_id = manifest.id.clone();
         let network = manifest.network_name();
@@ -54,6 +72,10 @@ where
             network,
             hosts: Vec::new(),
             module_cache: HashMap::new(),
+            module_cache_budget: MEMORY_BUDGET.register(CacheKind::Module),
+            module_cache_size: 0,
+            max_data_sources: max_data_sources.or(*MAX_DATA_SOURCES),
+            warned_data_sources: false,
         };
 
         // Create a new runtime host for each data source in the subgraph manifest;
@@ -92,6 +114,8 @@ where
                     host_metrics.clone(),
                 )?;
                 self.module_cache.insert(module_hash, sender.clone());
+                self.module_cache_size += module_bytes.len();
+                self.module_cache_budget.report(self.module_cache_size, 0);
                 sender
             }
         };
@@ -187,10 +211,12 @@ where
         metrics: Arc<HostMetrics>,
     ) -> Result<Option<Arc<T::Host>>, Error> {
         // Protect against creating more than the allowed maximum number of data sources
-        if let Some(max_data_sources) = *MAX_DATA_SOURCES {
+        if let Some(max_data_sources) = self.max_data_sources {
             if self.hosts.len() >= max_data_sources {
                 anyhow::bail!(
-                    "Limit of {} data sources per subgraph exceeded",
+                    "Limit of {} data sources per subgraph exceeded; the limit for this \
+                     deployment can be raised in the `[deployment.max_data_sources]` \
+                     section of the configuration file",
                     max_data_sources,
                 );
             }
@@ -210,10 +236,33 @@ where
             None
         } else {
             self.hosts.push(host.clone());
+            self.check_data_source_count(logger);
             Some(host)
         })
     }
 
+    /// Warn once when the subgraph gets close to its limit of data sources
+    /// so that operators can act before it fails
+    fn check_data_source_count(&mut self, logger: &Logger) {
+        let max_data_sources = match self.max_data_sources {
+            Some(max_data_sources) => max_data_sources,
+            None => return,
+        };
+        if self.warned_data_sources
+            || (self.hosts.len() as f64) < DATA_SOURCES_WARNING_RATIO * max_data_sources as f64
+        {
+            return;
+        }
+        self.warned_data_sources = true;
+        warn!(
+            logger,
+            "Subgraph is close to its limit of data sources and will fail when it \
+             exceeds it";
+            "data_sources" => self.hosts.len(),
+            "max_data_sources" => max_data_sources,
+        );
+    }
+
     pub(crate) fn revert_data_sources(&mut self, reverted_block: BlockNumber) {
         // `hosts` is ordered by the creation block.
         // See also 8f1bca33-d3b7-4035-affc-fd6161a12448.
@@ -225,6 +274,11 @@ where
         {
             self.hosts.pop();
         }
+        if let Some(max_data_sources) = self.max_data_sources {
+            if (self.hosts.len() as f64) < DATA_SOURCES_WARNING_RATIO * max_data_sources as f64 {
+                self.warned_data_sources = false;
+            }
+        }
     }
 
     pub(crate) fn network(&self) -> &str {
@@ -1,13 +1,18 @@
 use super::loader::load_dynamic_data_sources;
+use super::progress::{BlocksBehind, SyncProgress};
 use super::SubgraphInstance;
 use atomic_refcell::AtomicRefCell;
 use fail::fail_point;
 use graph::blockchain::{BlockchainKind, DataSource};
 use graph::data::store::scalar::Bytes;
+use graph::data::subgraph::status::time_to_sync;
 use graph::data::subgraph::{UnifiedMappingApiVersion, MAX_SPEC_VERSION};
+use graph::data::timeseries::{Interval, Timeseries};
 use graph::prelude::TryStreamExt;
 use graph::prelude::{SubgraphInstanceManager as SubgraphInstanceManagerTrait, *};
+use graph::util::backoff::ExponentialBackoff;
 use graph::util::lfu_cache::LfuCache;
+use graph::util::memory_budget::{BudgetHandle, CacheKind, MEMORY_BUDGET};
 use graph::{blockchain::block_stream::BlockStreamMetrics, components::store::WritableStore};
 use graph::{blockchain::block_stream::BlockWithTriggers, data::subgraph::SubgraphFeature};
 use graph::{
@@ -21,16 +26,18 @@ use graph::{
 };
 use graph::{
     blockchain::{Block, BlockchainMap},
-    components::store::{DeploymentId, DeploymentLocator, ModificationsAndCache},
+    components::store::{DeploymentId, DeploymentLocator, EntityType, ModificationsAndCache},
 };
 use lazy_static::lazy_static;
-use std::collections::{BTreeSet, HashMap};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::{Arc, RwLock};
 use std::time::Instant;
 use tokio::task;
 
 lazy_static! {
-    /// Size limit of the entity LFU cache, in bytes.
+    /// Size limit of the entity LFU cache, in bytes. Only used when there is no global cache
+    /// memory budget.
     // Multiplied by 1000 because the env var is in KB.
     pub static ref ENTITY_CACHE_SIZE: usize = 1000
         * std::env::var("GRAPH_ENTITY_CACHE_SIZE")
@@ -38,14 +45,117 @@ lazy_static! {
             .parse::<usize>()
             .expect("invalid GRAPH_ENTITY_CACHE_SIZE");
 
+    /// Ceiling for the delay between retries of non-deterministic subgraph
+    /// errors, in seconds
+    static ref SUBGRAPH_ERROR_RETRY_CEIL_SECS: u64 =
+        std::env::var("GRAPH_SUBGRAPH_ERROR_RETRY_CEIL_SECS")
+            .unwrap_or("1800".into())
+            .parse::<u64>()
+            .expect("invalid GRAPH_SUBGRAPH_ERROR_RETRY_CEIL_SECS");
+
     // Keep deterministic errors non-fatal even if the subgraph is pending.
     // Used for testing Graph Node itself.
     pub static ref DISABLE_FAIL_FAST: bool =
         std::env::var("GRAPH_DISABLE_FAIL_FAST").is_ok();
+
+    /// How often to report the indexing rate of a subgraph, in seconds
+    static ref SUBGRAPH_PROGRESS_INTERVAL: Duration = Duration::from_secs(
+        std::env::var("GRAPH_SUBGRAPH_PROGRESS_INTERVAL")
+            .unwrap_or("60".into())
+            .parse::<u64>()
+            .expect("invalid GRAPH_SUBGRAPH_PROGRESS_INTERVAL")
+    );
+
+    /// Warn when a subgraph falls more than this many blocks behind the
+    /// chain head. Checking requires looking up the chain head
+    /// periodically, and is therefore off unless this is set
+    static ref SUBGRAPH_MAX_BLOCKS_BEHIND: Option<BlockNumber> =
+        std::env::var("GRAPH_SUBGRAPH_MAX_BLOCKS_BEHIND")
+            .ok()
+            .map(|s| s.parse::<BlockNumber>()
+                .expect("invalid GRAPH_SUBGRAPH_MAX_BLOCKS_BEHIND"));
+
+    /// How often to check how far a subgraph is behind the chain head, in
+    /// seconds
+    static ref SUBGRAPH_BLOCKS_BEHIND_INTERVAL: Duration = Duration::from_secs(
+        std::env::var("GRAPH_SUBGRAPH_BLOCKS_BEHIND_INTERVAL")
+            .unwrap_or("15".into())
+            .parse::<u64>()
+            .expect("invalid GRAPH_SUBGRAPH_BLOCKS_BEHIND_INTERVAL")
+    );
 }
 
 type SharedInstanceKeepAliveMap = Arc<RwLock<HashMap<DeploymentId, CancelGuard>>>;
 
+/// Allows stopping all subgraphs when the node shuts down, and waiting
+/// for them to commit or abandon the block they are currently processing.
+#[derive(Clone, Default)]
+pub struct IndexingShutdown {
+    inner: Arc<IndexingShutdownInner>,
+}
+
+#[derive(Default)]
+struct IndexingShutdownInner {
+    shutting_down: AtomicBool,
+    /// Number of subgraph threads that have not exited yet
+    running: AtomicUsize,
+    instances: SharedInstanceKeepAliveMap,
+}
+
+impl CheapClone for IndexingShutdown {}
+
+impl IndexingShutdown {
+    /// Stop all subgraphs and wait for up to `timeout` until the blocks
+    /// they were processing have been committed or discarded. Returns
+    /// `false` if some subgraphs were still running after `timeout`.
+    pub async fn shutdown(&self, logger: &Logger, timeout: Duration) -> bool {
+        self.inner.shutting_down.store(true, Ordering::SeqCst);
+
+        // Dropping the cancel guards stops the block streams; subgraphs
+        // that are in the middle of a block will not write it to the store
+        // unless they are already committing it
+        self.inner.instances.write().unwrap().clear();
+
+        let start = Instant::now();
+        loop {
+            let running = self.inner.running.load(Ordering::SeqCst);
+            if running == 0 {
+                return true;
+            }
+            if start.elapsed() >= timeout {
+                warn!(logger, "Subgraphs did not stop in time";
+                              "running" => running,
+                              "timeout_s" => timeout.as_secs());
+                return false;
+            }
+            info!(logger, "Waiting for subgraphs to stop"; "running" => running);
+            tokio::time::sleep(Duration::from_millis(500)).await;
+        }
+    }
+
+    fn is_shutting_down(&self) -> bool {
+        self.inner.shutting_down.load(Ordering::SeqCst)
+    }
+
+    fn instances(&self) -> SharedInstanceKeepAliveMap {
+        self.inner.instances.cheap_clone()
+    }
+
+    /// Count a subgraph thread as running until the returned guard is dropped
+    fn track(&self) -> RunningGuard {
+        self.inner.running.fetch_add(1, Ordering::SeqCst);
+        RunningGuard(self.cheap_clone())
+    }
+}
+
+struct RunningGuard(IndexingShutdown);
+
+impl Drop for RunningGuard {
+    fn drop(&mut self) {
+        self.0.inner.running.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
 struct IndexingInputs<C: Blockchain> {
     deployment: DeploymentLocator,
     features: BTreeSet<SubgraphFeature>,
@@ -55,14 +165,23 @@ struct IndexingInputs<C: Blockchain> {
     chain: Arc<C>,
     templates: Arc<Vec<C::DataSourceTemplate>>,
     unified_api_version: UnifiedMappingApiVersion,
+    /// The timeseries entity types of the subgraph, whose rollups are
+    /// updated at the end of each block
+    timeseries: Arc<Vec<Timeseries>>,
 }
 
 struct IndexingState<T: RuntimeHostBuilder<C>, C: Blockchain> {
     logger: Logger,
     instance: SubgraphInstance<C, T>,
     instances: SharedInstanceKeepAliveMap,
+    shutdown: IndexingShutdown,
     filter: C::TriggerFilter,
     entity_lfu_cache: LfuCache<EntityKey, Option<Entity>>,
+    entity_cache_budget: BudgetHandle,
+    /// The cache size configured for this deployment, if any. It takes
+    /// precedence over the memory budget and `GRAPH_ENTITY_CACHE_SIZE`
+    entity_cache_size: Option<usize>,
+    entity_caches: Arc<EntityCacheControl>,
 }
 
 struct IndexingContext<T: RuntimeHostBuilder<C>, C: Blockchain> {
@@ -89,6 +208,11 @@ pub struct SubgraphInstanceManager<S, M, L> {
     manager_metrics: SubgraphInstanceManagerMetrics,
     instances: SharedInstanceKeepAliveMap,
     link_resolver: Arc<L>,
+    shutdown: IndexingShutdown,
+    entity_caches: Arc<EntityCacheControl>,
+    /// Limits on the number of data sources for deployments that should
+    /// not use `GRAPH_SUBGRAPH_MAX_DATA_SOURCES`
+    max_data_sources: HashMap<DeploymentHash, usize>,
 }
 
 struct SubgraphInstanceManagerMetrics {
@@ -112,8 +236,16 @@ struct SubgraphInstanceMetrics {
     pub block_trigger_count: Box<Histogram>,
     pub block_processing_duration: Box<Histogram>,
     pub block_ops_transaction_duration: Box<Histogram>,
+    pub entity_cache_hits: Box<Counter>,
+    pub entity_cache_misses: Box<Counter>,
+    pub entity_cache_evicted: Box<Counter>,
+    pub blocks_per_second: Box<Gauge>,
+    pub sync_eta: Box<Gauge>,
+    pub blocks_behind: Box<Gauge>,
 
     trigger_processing_duration: Box<Histogram>,
+    block_stage_duration: Box<HistogramVec>,
+    block_latency: Box<Histogram>,
 }
 
 impl SubgraphInstanceMetrics {
@@ -150,12 +282,80 @@ impl SubgraphInstanceMetrics {
                 vec![0.01, 0.05, 0.1, 0.3, 0.7, 2.0],
             )
             .expect("failed to create `deployment_transact_block_operations_duration_{}");
+        let entity_cache_hits = registry
+            .new_deployment_counter(
+                "deployment_entity_cache_hits",
+                "Counts entity lookups by the mappings that were answered from the entity cache",
+                subgraph_hash,
+            )
+            .expect("failed to create `deployment_entity_cache_hits` counter");
+        let entity_cache_misses = registry
+            .new_deployment_counter(
+                "deployment_entity_cache_misses",
+                "Counts entity lookups by the mappings that had to be loaded from the store",
+                subgraph_hash,
+            )
+            .expect("failed to create `deployment_entity_cache_misses` counter");
+        let entity_cache_evicted = registry
+            .new_deployment_counter(
+                "deployment_entity_cache_evicted",
+                "Counts entities evicted from the entity cache",
+                subgraph_hash,
+            )
+            .expect("failed to create `deployment_entity_cache_evicted` counter");
+        let blocks_per_second = registry
+            .new_deployment_gauge(
+                "deployment_blocks_per_second",
+                "The number of blocks a subgraph deployment processes per second",
+                subgraph_hash,
+            )
+            .expect("failed to create `deployment_blocks_per_second` gauge");
+        let sync_eta = registry
+            .new_deployment_gauge(
+                "deployment_sync_eta",
+                "Estimated time until a subgraph deployment reaches the chain head, in seconds",
+                subgraph_hash,
+            )
+            .expect("failed to create `deployment_sync_eta` gauge");
+        let blocks_behind = registry
+            .new_deployment_gauge(
+                "deployment_blocks_behind",
+                "The number of blocks a subgraph deployment is behind the chain head",
+                subgraph_hash,
+            )
+            .expect("failed to create `deployment_blocks_behind` gauge");
+        let block_stage_duration = registry
+            .new_deployment_histogram_vec(
+                "deployment_block_stage_duration",
+                "Measures how long each stage of processing a block takes, from finding its triggers \
+                 to advancing the subgraph pointer",
+                subgraph_hash,
+                vec![String::from("stage")],
+                vec![0.01, 0.05, 0.1, 0.5, 1.5, 5.0, 10.0, 30.0, 120.0, 600.0],
+            )
+            .expect("failed to create `deployment_block_stage_duration` histogram");
+        let block_latency = registry
+            .new_deployment_histogram(
+                "deployment_block_latency",
+                "Measures the time from finding the triggers of a block to advancing the subgraph pointer past it",
+                subgraph_hash,
+                vec![0.05, 0.2, 0.7, 1.5, 4.0, 10.0, 60.0, 120.0, 240.0, 600.0],
+            )
+            .expect("failed to create `deployment_block_latency` histogram");
 
         Self {
             block_trigger_count,
             block_processing_duration,
             trigger_processing_duration,
             block_ops_transaction_duration,
+            entity_cache_hits,
+            entity_cache_misses,
+            entity_cache_evicted,
+            blocks_per_second,
+            sync_eta,
+            blocks_behind,
+            block_stage_duration,
+            block_latency,
         }
     }
 
@@ -163,11 +363,47 @@ impl SubgraphInstanceMetrics {
         self.trigger_processing_duration.observe(duration);
     }
 
+    /// Record how long the stages of processing a block took. The block's
+    /// triggers were found at `triggers_found`, processing started at
+    /// `started`, all handlers had run at `handlers_done`, and writing the
+    /// changes to the store started at `transact_started`. The subgraph
+    /// pointer has just been advanced past the block
+    pub fn observe_block_stages(
+        &self,
+        triggers_found: Instant,
+        started: Instant,
+        handlers_done: Instant,
+        transact_started: Instant,
+    ) {
+        let now = Instant::now();
+        let stages = [
+            ("queued", triggers_found, started),
+            ("handlers", started, handlers_done),
+            ("modifications", handlers_done, transact_started),
+            ("store", transact_started, now),
+        ];
+        for (stage, from, to) in stages.iter() {
+            self.block_stage_duration
+                .with_label_values(&[*stage])
+                .observe(to.duration_since(*from).as_secs_f64());
+        }
+        self.block_latency
+            .observe(now.duration_since(triggers_found).as_secs_f64());
+    }
+
     pub fn unregister<M: MetricsRegistry>(&self, registry: Arc<M>) {
         registry.unregister(self.block_processing_duration.clone());
         registry.unregister(self.block_trigger_count.clone());
         registry.unregister(self.trigger_processing_duration.clone());
         registry.unregister(self.block_ops_transaction_duration.clone());
+        registry.unregister(self.entity_cache_hits.clone());
+        registry.unregister(self.entity_cache_misses.clone());
+        registry.unregister(self.entity_cache_evicted.clone());
+        registry.unregister(self.blocks_per_second.clone());
+        registry.unregister(self.sync_eta.clone());
+        registry.unregister(self.blocks_behind.clone());
+        registry.unregister(self.block_stage_duration.clone());
+        registry.unregister(self.block_latency.clone());
     }
 }
 
@@ -184,6 +420,13 @@ where
         manifest: serde_yaml::Mapping,
     ) {
         let logger = self.logger_factory.subgraph_logger(&loc);
+        if self.shutdown.is_shutting_down() {
+            info!(
+                logger,
+                "Not starting subgraph since the node is shutting down"
+            );
+            return;
+        }
         let err_logger = logger.clone();
         let instance_manager = self.cheap_clone();
 
@@ -244,6 +487,9 @@ where
         chains: Arc<BlockchainMap>,
         metrics_registry: Arc<M>,
         link_resolver: Arc<L>,
+        shutdown: IndexingShutdown,
+        entity_caches: Arc<EntityCacheControl>,
+        max_data_sources: HashMap<DeploymentHash, usize>,
     ) -> Self {
         let logger = logger_factory.component_logger("SubgraphInstanceManager", None);
         let logger_factory = logger_factory.with_parent(logger.clone());
@@ -254,8 +500,11 @@ where
             chains,
             manager_metrics: SubgraphInstanceManagerMetrics::new(metrics_registry.cheap_clone()),
             metrics_registry,
-            instances: SharedInstanceKeepAliveMap::default(),
+            instances: shutdown.instances(),
             link_resolver,
+            shutdown,
+            entity_caches,
+            max_data_sources,
         }
     }
 
@@ -342,6 +591,7 @@ where
         let start_blocks = manifest.start_blocks();
 
         let templates = Arc::new(manifest.templates.clone());
+        let timeseries = Arc::new(Timeseries::for_document(&manifest.schema.document));
 
         // Create a subgraph instance from the manifest; this moves
         // ownership of the manifest and host builder into the new instance
@@ -393,8 +643,13 @@ where
 
         let features = manifest.features.clone();
         let unified_api_version = manifest.unified_mapping_api_version()?;
-        let instance =
-            SubgraphInstance::from_manifest(&logger, manifest, host_builder, host_metrics.clone())?;
+        let instance = SubgraphInstance::from_manifest(
+            &logger,
+            manifest,
+            host_builder,
+            host_metrics.clone(),
+            self.max_data_sources.get(&deployment.hash).cloned(),
+        )?;
 
         // The subgraph state tracks the state of the subgraph instance over time
         let ctx = IndexingContext {
@@ -407,13 +662,18 @@ where
                 chain,
                 templates,
                 unified_api_version,
+                timeseries,
             },
             state: IndexingState {
                 logger: logger.cheap_clone(),
                 instance,
                 instances: self.instances.cheap_clone(),
+                shutdown: self.shutdown.cheap_clone(),
                 filter,
                 entity_lfu_cache: LfuCache::new(),
+                entity_cache_budget: MEMORY_BUDGET.register(CacheKind::Entity),
+                entity_cache_size: self.entity_caches.size(&deployment.hash),
+                entity_caches: self.entity_caches.cheap_clone(),
             },
             subgraph_metrics,
             host_metrics,
@@ -433,7 +693,11 @@ where
         // scheduling. It is also logical in terms of performance to run this with `unconstrained`,
         // it has a dedicated OS thread so the OS will handle the preemption. See
         // https://github.com/tokio-rs/tokio/issues/3493.
+        let running_guard = self.shutdown.track();
+        let entity_caches = self.entity_caches.cheap_clone();
+        entity_caches.start(deployment.hash.clone());
         graph::spawn_thread(deployment.to_string(), move || {
+            let _running_guard = running_guard;
             if let Err(e) = graph::block_on(task::unconstrained(run_subgraph(ctx))) {
                 error!(
                     &logger,
@@ -441,6 +705,7 @@ where
                     format!("{:#}", e)
                 );
             }
+            entity_caches.stop(&deployment.hash);
             subgraph_metrics_unregister.unregister(registry);
         });
 
@@ -448,6 +713,112 @@ where
     }
 }
 
+/// Record how fast the subgraph is indexing and how long it will take to
+/// reach the chain head at that rate
+fn report_progress<T, C>(
+    logger: &Logger,
+    ctx: &IndexingContext<T, C>,
+    metrics: &SubgraphInstanceMetrics,
+    block_ptr: &BlockPtr,
+    blocks_per_second: f64,
+) where
+    T: RuntimeHostBuilder<C>,
+    C: Blockchain,
+{
+    let head = ctx
+        .inputs
+        .chain
+        .chain_store()
+        .chain_head_ptr()
+        .ok()
+        .flatten()
+        .map(|head| head.number);
+    let eta = head.and_then(|head| time_to_sync(block_ptr.number, head, blocks_per_second));
+
+    metrics.blocks_per_second.set(blocks_per_second);
+    metrics
+        .sync_eta
+        .set(eta.map(|eta| eta.as_secs_f64()).unwrap_or(-1.0));
+
+    info!(logger, "Indexing progress";
+        "block" => block_ptr.number,
+        "head" => head,
+        "blocks_per_second" => format!("{:.2}", blocks_per_second),
+        "eta_s" => eta.map(|eta| eta.as_secs()));
+
+    if let Err(e) = ctx.inputs.store.update_sync_rate(blocks_per_second) {
+        warn!(logger, "Failed to record indexing rate"; "error" => e.to_string());
+    }
+}
+
+/// Periodically record how far the subgraph is behind the chain head, and
+/// warn when it falls too far behind or catches up again. The check runs
+/// on its own timer so that it neither slows down block processing nor
+/// stops when the subgraph is stuck; it stops when the returned guard is
+/// dropped
+fn check_blocks_behind<T, C>(
+    logger: &Logger,
+    ctx: &IndexingContext<T, C>,
+    metrics: Arc<SubgraphInstanceMetrics>,
+    mut monitor: BlocksBehind,
+) -> CancelGuard
+where
+    T: RuntimeHostBuilder<C>,
+    C: Blockchain,
+{
+    let logger = logger.cheap_clone();
+    let store = ctx.inputs.store.cheap_clone();
+    let chain_store = ctx.inputs.chain.chain_store();
+    let guard = CancelGuard::new();
+
+    graph::spawn(
+        async move {
+            let mut interval = tokio::time::interval(*SUBGRAPH_BLOCKS_BEHIND_INTERVAL);
+            loop {
+                interval.tick().await;
+
+                let store = store.cheap_clone();
+                let chain_store = chain_store.cheap_clone();
+                let ptrs = graph::spawn_blocking_allow_panic(move || {
+                    let block = store.block_ptr().map_err(Error::from)?;
+                    let head = chain_store.chain_head_ptr()?;
+                    Ok::<_, Error>(block.zip(head))
+                })
+                .await
+                .map_err(Error::from)
+                .and_then(|ptrs| ptrs);
+                let (block, head) = match ptrs {
+                    Ok(Some((block, head))) => (block.number, head.number),
+                    Ok(None) => continue,
+                    Err(e) => {
+                        debug!(logger, "Failed to get chain head"; "error" => e.to_string());
+                        continue;
+                    }
+                };
+                let blocks_behind = (head - block).max(0);
+                metrics.blocks_behind.set(blocks_behind as f64);
+
+                match monitor.update(blocks_behind) {
+                    Some(true) => warn!(logger, "Subgraph fell behind the chain head";
+                        "block" => block,
+                        "head" => head,
+                        "blocks_behind" => blocks_behind,
+                        "max_blocks_behind" => monitor.max_blocks_behind()),
+                    Some(false) => info!(logger, "Subgraph caught up with the chain head";
+                        "block" => block,
+                        "head" => head,
+                        "blocks_behind" => blocks_behind,
+                        "max_blocks_behind" => monitor.max_blocks_behind()),
+                    None => {}
+                }
+            }
+        }
+        .cancelable(&guard, || ()),
+    );
+
+    guard
+}
+
 async fn run_subgraph<T, C>(mut ctx: IndexingContext<T, C>) -> Result<(), Error>
 where
     T: RuntimeHostBuilder<C>,
@@ -459,8 +830,28 @@ where
     let logger = ctx.state.logger.cheap_clone();
     let id_for_err = ctx.inputs.deployment.hash.clone();
     let mut first_run = true;
+    let mut retrying_error = false;
+    let mut backoff = ExponentialBackoff::new(
+        Duration::from_secs(30),
+        Duration::from_secs(*SUBGRAPH_ERROR_RETRY_CEIL_SECS),
+    );
+    let mut progress = SyncProgress::new(*SUBGRAPH_PROGRESS_INTERVAL);
+    let _blocks_behind = SUBGRAPH_MAX_BLOCKS_BEHIND.map(|max_blocks_behind| {
+        check_blocks_behind(
+            &logger,
+            &ctx,
+            subgraph_metrics.cheap_clone(),
+            BlocksBehind::new(max_blocks_behind),
+        )
+    });
 
     loop {
+        // Don't restart the block stream if the node is shutting down
+        if ctx.state.shutdown.is_shutting_down() {
+            debug!(logger, "Subgraph shut down cleanly");
+            return Ok(());
+        }
+
         debug!(logger, "Starting or restarting subgraph");
 
         let block_stream_canceler = CancelGuard::new();
@@ -565,6 +956,17 @@ where
 
             let block_ptr = block.ptr();
 
+            if ctx
+                .state
+                .entity_caches
+                .take_flush_request(&ctx.inputs.deployment.hash)
+            {
+                info!(&logger, "Flushing entity cache";
+                               "entities" => ctx.state.entity_lfu_cache.len());
+                ctx.state.entity_lfu_cache = LfuCache::new();
+                ctx.state.entity_cache_budget.report(0, 0);
+            }
+
             if block.trigger_count() > 0 {
                 subgraph_metrics
                     .block_trigger_count
@@ -594,25 +996,40 @@ where
                 ctx.inputs.store.unfail(current_ptr, parent_ptr)?;
             }
 
-            let res = process_block(
+            // Stop processing the block as soon as the subgraph is stopped,
+            // even in the middle of an RPC call or a mapping handler
+            let res = Box::pin(process_block(
                 &logger,
                 ctx.inputs.triggers_adapter.cheap_clone(),
-                ctx,
+                &mut ctx,
                 block_stream_cancel_handle.clone(),
                 block,
                 cursor.into(),
-            )
+            ))
+            .cancelable(&block_stream_cancel_handle, || {
+                Err(BlockProcessingError::Canceled)
+            })
             .await;
 
             let elapsed = start.elapsed().as_secs_f64();
             subgraph_metrics.block_processing_duration.observe(elapsed);
 
             match res {
-                Ok((c, needs_restart)) => {
-                    ctx = c;
+                Ok(needs_restart) => {
+                    // A block was processed after retrying a non-deterministic
+                    // error, so the error did not happen again
+                    if retrying_error {
+                        retrying_error = false;
+                        backoff.attempt = 0;
+                        ctx.inputs.store.unfail(Some(block_ptr.clone()), None)?;
+                    }
 
                     deployment_failed.set(0.0);
 
+                    if let Some(rate) = progress.advance(block_ptr.number) {
+                        report_progress(&logger, &ctx, &subgraph_metrics, &block_ptr, rate);
+                    }
+
                     // Notify the BlockStream implementation that a block was succesfully consumed
                     // and that its internal cursoring mechanism can be saved to memory.
                     //
@@ -642,13 +1059,14 @@ where
                 Err(e) => {
                     let message = format!("{:#}", e).replace("\n", "\t");
                     let err = anyhow!("{}, code: {}", message, LogCode::SubgraphSyncingFailure);
+                    let deterministic = e.is_deterministic();
 
                     let error = SubgraphError {
                         subgraph_id: id_for_err.clone(),
                         message,
-                        block_ptr: Some(block_ptr),
+                        block_ptr: Some(block_ptr.clone()),
                         handler: None,
-                        deterministic: e.is_deterministic(),
+                        deterministic,
                     };
                     deployment_failed.set(1.0);
 
@@ -657,7 +1075,43 @@ where
                         .await
                         .context("Failed to set subgraph status to `failed`")?;
 
-                    return Err(err);
+                    // Deterministic errors would happen again, so the
+                    // subgraph stays failed at this block
+                    if deterministic {
+                        return Err(err);
+                    }
+
+                    // Non-deterministic errors, like RPC or database
+                    // hiccups, are retried by processing the block again
+                    // after a delay. Undo what the failed block did to the
+                    // in-memory state first
+                    ctx.state.instance.revert_data_sources(block_ptr.number);
+                    ctx.state.entity_lfu_cache = LfuCache::new();
+
+                    let delay = backoff.delay();
+                    backoff.attempt += 1;
+                    retrying_error = true;
+                    error!(logger, "Subgraph failed with a non-deterministic error, retrying";
+                                   "error" => format!("{:#}", err),
+                                   "attempt" => backoff.attempt,
+                                   "retry_delay_s" => delay.as_secs());
+
+                    let wait = async move {
+                        tokio::time::sleep(delay).await;
+                        true
+                    };
+                    // The subgraph was stopped or the node is shutting down
+                    if !Box::pin(wait)
+                        .cancelable(&block_stream_cancel_handle, || false)
+                        .await
+                    {
+                        debug!(logger, "Subgraph stopped while waiting to retry");
+                        return Ok(());
+                    }
+
+                    // Restart the block stream so it goes back to the block
+                    // that failed
+                    break;
                 }
             }
         }
@@ -701,11 +1155,13 @@ impl From<StoreError> for BlockProcessingError {
 async fn process_block<T: RuntimeHostBuilder<C>, C: Blockchain>(
     logger: &Logger,
     triggers_adapter: Arc<C::TriggersAdapter>,
-    mut ctx: IndexingContext<T, C>,
+    ctx: &mut IndexingContext<T, C>,
     block_stream_cancel_handle: CancelHandle,
     block: BlockWithTriggers<C>,
     firehose_cursor: Option<String>,
-) -> Result<(IndexingContext<T, C>, bool), BlockProcessingError> {
+) -> Result<bool, BlockProcessingError> {
+    let started = Instant::now();
+    let triggers_found_at = block.triggers_found_at;
     let triggers = block.trigger_data;
     let block = Arc::new(block.block);
     let block_ptr = block.ptr();
@@ -779,7 +1235,7 @@ async fn process_block<T: RuntimeHostBuilder<C>, C: Blockchain>(
             // Losing the cache is a bit annoying but not an issue for correctness.
             //
             // See also b21fa73b-6453-4340-99fb-1a78ec62efb1.
-            return Ok((ctx, true));
+            return Ok(true);
         }
     };
 
@@ -800,7 +1256,7 @@ async fn process_block<T: RuntimeHostBuilder<C>, C: Blockchain>(
         // Instantiate dynamic data sources, removing them from the block state.
         let (data_sources, runtime_hosts) = create_dynamic_data_sources(
             logger.clone(),
-            &mut ctx,
+            ctx,
             host_metrics.clone(),
             block_state.drain_created_data_sources(),
         )?;
@@ -831,7 +1287,7 @@ async fn process_block<T: RuntimeHostBuilder<C>, C: Blockchain>(
         // and add runtimes for the data sources to the subgraph instance.
         persist_dynamic_data_sources(
             logger.clone(),
-            &mut ctx,
+            ctx,
             &mut block_state.entity_cache,
             data_sources,
         );
@@ -863,6 +1319,8 @@ async fn process_block<T: RuntimeHostBuilder<C>, C: Blockchain>(
         }
     }
 
+    let handlers_done = Instant::now();
+
     let has_errors = block_state.has_errors();
     let is_non_fatal_errors_active = ctx
         .inputs
@@ -892,19 +1350,45 @@ async fn process_block<T: RuntimeHostBuilder<C>, C: Blockchain>(
         modifications: mut mods,
         data_sources,
         entity_lfu_cache: mut cache,
+        cache_hits,
+        cache_misses,
     } = block_state
         .entity_cache
         .as_modifications()
         .map_err(|e| BlockProcessingError::Unknown(e.into()))?;
     section.end();
 
+    let section = ctx.host_metrics.stopwatch.start_section("update_rollups");
+    let rollups = update_rollups(
+        &logger,
+        &ctx.inputs.timeseries,
+        ctx.inputs.store.as_ref(),
+        &block_ptr,
+        &mut cache,
+        &mut mods,
+    )?;
+    section.end();
+
     let section = ctx
         .host_metrics
         .stopwatch
         .start_section("entity_cache_evict");
-    cache.evict(*ENTITY_CACHE_SIZE);
+    let entity_cache_budget = &ctx.state.entity_cache_budget;
+    let max_weight = ctx
+        .state
+        .entity_cache_size
+        .unwrap_or_else(|| entity_cache_budget.limit(*ENTITY_CACHE_SIZE));
+    let entities_before = cache.len();
+    let evicted = cache.evict(max_weight).map_or(0, |(evicted, _, _)| evicted);
+    entity_cache_budget.report(cache.total_weight(), evicted);
     section.end();
 
+    metrics.entity_cache_hits.inc_by(cache_hits as f64);
+    metrics.entity_cache_misses.inc_by(cache_misses as f64);
+    metrics
+        .entity_cache_evicted
+        .inc_by((entities_before - cache.len()) as f64);
+
     // Put the cache back in the ctx, asserting that the placeholder cache was not used.
     assert!(ctx.state.entity_lfu_cache.is_empty());
     ctx.state.entity_lfu_cache = cache;
@@ -922,6 +1406,13 @@ async fn process_block<T: RuntimeHostBuilder<C>, C: Blockchain>(
         );
     }
 
+    // To prevent a buggy pending version from replacing a current version, if errors are
+    // present the subgraph will be unassigned. This is checked before writing the block so
+    // that nothing needs to be awaited after the write: the caller stops processing the
+    // block at whatever await point it is at when the subgraph is stopped
+    let unassign =
+        has_errors && !*DISABLE_FAIL_FAST && !ctx.inputs.store.is_deployment_synced().await?;
+
     // Transact entity operations into the store and update the
     // subgraph's block stream pointer
     let _section = ctx.host_metrics.stopwatch.start_section("transact_block");
@@ -971,12 +1462,17 @@ async fn process_block<T: RuntimeHostBuilder<C>, C: Blockchain>(
                 return Err(BlockProcessingError::Deterministic(first_error.unwrap()));
             }
 
+            // The rollups are only cached once they were written, so that
+            // the cache never has rollups that are not in the store
+            for (key, rollup) in rollups {
+                ctx.state.entity_lfu_cache.insert(key, Some(rollup));
+            }
+
             let elapsed = start.elapsed().as_secs_f64();
             metrics.block_ops_transaction_duration.observe(elapsed);
+            metrics.observe_block_stages(triggers_found_at, started, handlers_done, start);
 
-            // To prevent a buggy pending version from replacing a current version, if errors are
-            // present the subgraph will be unassigned.
-            if has_errors && !*DISABLE_FAIL_FAST && !store.is_deployment_synced().await? {
+            if unassign {
                 store
                     .unassign_subgraph()
                     .map_err(|e| BlockProcessingError::Unknown(e.into()))?;
@@ -986,7 +1482,17 @@ async fn process_block<T: RuntimeHostBuilder<C>, C: Blockchain>(
                 return Err(BlockProcessingError::Canceled);
             }
 
-            Ok((ctx, needs_restart))
+            // The store may write changes in the background. Errors from
+            // writing a block that created data sources have to be seen
+            // while it is the current block so that its data sources get
+            // reverted from the in-memory state
+            if needs_restart {
+                store
+                    .flush()
+                    .map_err(|e| BlockProcessingError::Unknown(e.into()))?;
+            }
+
+            Ok(needs_restart)
         }
 
         Err(e) => Err(anyhow!("Error while processing block stream for a subgraph: {}", e).into()),
@@ -1040,6 +1546,150 @@ async fn update_proof_of_indexing(
     Ok(())
 }
 
+/// Add the data points from the timeseries entities that the block created
+/// to the rollups of their timeseries, and add the changed rollups to
+/// `mods`. Only newly created timeseries entities count as data points;
+/// changing or removing them later does not change their rollups. Return
+/// the changed rollups so that they can be put into `cache` once the block
+/// has been written. A data point without a valid timestamp is a
+/// deterministic error
+fn update_rollups(
+    logger: &Logger,
+    timeseries: &[Timeseries],
+    store: &dyn WritableStore,
+    block_ptr: &BlockPtr,
+    cache: &mut LfuCache<EntityKey, Option<Entity>>,
+    mods: &mut Vec<EntityModification>,
+) -> Result<Vec<(EntityKey, Entity)>, BlockProcessingError> {
+    if timeseries.is_empty() {
+        return Ok(vec![]);
+    }
+
+    // Rollups are maintained here; changes that mappings made to them
+    // would conflict with the ones we make
+    let rollup_types: HashSet<EntityType> = timeseries
+        .iter()
+        .flat_map(|ts| {
+            Interval::ALL
+                .iter()
+                .map(move |interval| ts.rollup_type(*interval))
+        })
+        .collect();
+    let mut ignored = Vec::new();
+    mods.retain(|modification| {
+        let key = modification.entity_key();
+        if rollup_types.contains(&key.entity_type) {
+            ignored.push(key.clone());
+            false
+        } else {
+            true
+        }
+    });
+    if !ignored.is_empty() {
+        warn!(
+            logger,
+            "Ignoring {} change(s) to rollup entities made by mappings",
+            ignored.len()
+        );
+        // The cache already holds what the mappings wrote; forget it so
+        // that the rollups are read from the store again
+        for key in &ignored {
+            cache.remove(key);
+        }
+    }
+
+    // The data points of this block, and the keys of the rollups that
+    // they go into
+    let mut points = Vec::new();
+    for modification in mods.iter() {
+        if let EntityModification::Insert { key, data } = modification {
+            if let Some(ts) = timeseries
+                .iter()
+                .find(|ts| ts.entity_type == key.entity_type)
+            {
+                let timestamp = Timeseries::timestamp(data).ok_or_else(|| {
+                    BlockProcessingError::Deterministic(SubgraphError {
+                        subgraph_id: key.subgraph_id.clone(),
+                        message: format!(
+                            "timeseries entity {}[{}] does not have a valid timestamp",
+                            key.entity_type, key.entity_id
+                        ),
+                        block_ptr: Some(block_ptr.clone()),
+                        handler: None,
+                        deterministic: true,
+                    })
+                })?;
+                for interval in Interval::ALL.iter() {
+                    let start = interval.start(timestamp);
+                    let rollup_key = EntityKey {
+                        subgraph_id: key.subgraph_id.clone(),
+                        entity_type: ts.rollup_type(*interval),
+                        entity_id: start.to_string(),
+                    };
+                    points.push((ts, start, rollup_key, data));
+                }
+            }
+        }
+    }
+    if points.is_empty() {
+        return Ok(vec![]);
+    }
+
+    // Look up the current rollups, first in the cache, then in the store
+    let mut rollups: HashMap<EntityKey, Option<Entity>> = HashMap::new();
+    let mut missing: BTreeMap<&EntityType, Vec<&str>> = BTreeMap::new();
+    for (_, _, key, _) in &points {
+        if rollups.contains_key(key) {
+            continue;
+        }
+        let rollup = cache.get(key).cloned();
+        if rollup.is_none() {
+            missing
+                .entry(&key.entity_type)
+                .or_default()
+                .push(&key.entity_id);
+        }
+        rollups.insert(key.clone(), rollup.flatten());
+    }
+    if !missing.is_empty() {
+        let subgraph_id = points[0].2.subgraph_id.clone();
+        let found = store
+            .get_many(missing)
+            .map_err(|e| BlockProcessingError::Unknown(e.into()))?;
+        for (entity_type, entities) in found {
+            for entity in entities {
+                let key = EntityKey {
+                    subgraph_id: subgraph_id.clone(),
+                    entity_type: entity_type.clone(),
+                    entity_id: entity.id()?,
+                };
+                rollups.insert(key, Some(entity));
+            }
+        }
+    }
+    let existing: HashSet<EntityKey> = rollups
+        .iter()
+        .filter(|(_, rollup)| rollup.is_some())
+        .map(|(key, _)| key.clone())
+        .collect();
+
+    for (ts, start, key, point) in points {
+        let rollup = rollups.get_mut(&key).expect("all rollups were looked up");
+        *rollup = Some(ts.add(rollup.take(), start, point));
+    }
+    let mut changed = Vec::with_capacity(rollups.len());
+    for (key, rollup) in rollups {
+        let data = rollup.expect("all rollups have data points");
+        changed.push((key.clone(), data.clone()));
+        if existing.contains(&key) {
+            mods.push(EntityModification::Overwrite { key, data });
+        } else {
+            mods.push(EntityModification::Insert { key, data });
+        }
+    }
+    Ok(changed)
+}
+
 async fn process_triggers<C: Blockchain>(
     logger: &Logger,
     mut block_state: BlockState<C>,
@@ -1150,3 +1800,92 @@ fn persist_dynamic_data_sources<T: RuntimeHostBuilder<C>, C: Blockchain>(
     // Merge filters from data sources into the block stream builder
     ctx.state.filter.extend(data_sources.iter());
 }
+
+#[cfg(test)]
+mod tests {
+    use graph::components::store::{EntityKey, EntityType};
+    use graph::data::timeseries::Timeseries;
+    use graph::mock::MockStore;
+    use graph::prelude::*;
+    use graph::util::lfu_cache::LfuCache;
+
+    use super::{update_rollups, BlockProcessingError};
+
+    fn trade(deployment: &DeploymentHash, id: &str, timestamp: Option<i32>) -> EntityModification {
+        let mut data = Entity::new();
+        data.set("id", id);
+        data.set("amount", 5);
+        if let Some(timestamp) = timestamp {
+            data.set("timestamp", timestamp);
+        }
+        EntityModification::Insert {
+            key: EntityKey {
+                subgraph_id: deployment.clone(),
+                entity_type: EntityType::new("Trade".to_owned()),
+                entity_id: id.to_owned(),
+            },
+            data,
+        }
+    }
+
+    #[test]
+    fn rollups() {
+        let logger = Logger::root(slog::Discard, o!());
+        let deployment = DeploymentHash::new("rollups").unwrap();
+        let document = graphql_parser::parse_schema::<String>(
+            "type Trade @entity(timeseries: true) {
+                id: ID!
+                timestamp: Int!
+                amount: Int!
+            }",
+        )
+        .unwrap();
+        let timeseries = Timeseries::for_document(&document);
+        let block_ptr = BlockPtr::from((web3::types::H256::zero(), 1 as BlockNumber));
+
+        let mut store = MockStore::new();
+        store
+            .expect_get_many_mock()
+            .returning(|_| Ok(BTreeMap::new()));
+
+        // Each data point goes into the rollups for its hour and day, which
+        // are only cached once the block has been written
+        let mut cache = LfuCache::new();
+        let mut mods = vec![trade(&deployment, "1", Some(3700))];
+        let rollups = update_rollups(
+            &logger,
+            &timeseries,
+            &store,
+            &block_ptr,
+            &mut cache,
+            &mut mods,
+        )
+        .unwrap();
+        let mut keys: Vec<_> = rollups
+            .iter()
+            .map(|(key, _)| (key.entity_type.as_str().to_owned(), key.entity_id.clone()))
+            .collect();
+        keys.sort();
+        assert_eq!(
+            vec![
+                ("TradeDay".to_owned(), "0".to_owned()),
+                ("TradeHour".to_owned(), "3600".to_owned())
+            ],
+            keys
+        );
+        assert_eq!(3, mods.len());
+        assert!(cache.is_empty());
+
+        // A data point without a timestamp fails the subgraph
+        let mut mods = vec![trade(&deployment, "2", None)];
+        let res = update_rollups(
+            &logger,
+            &timeseries,
+            &store,
+            &block_ptr,
+            &mut cache,
+            &mut mods,
+        );
+        assert!(matches!(res, Err(BlockProcessingError::Deterministic(_))));
+    }
+}
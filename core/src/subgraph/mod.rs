@@ -1,10 +1,13 @@
 mod instance;
 mod instance_manager;
 mod loader;
+mod progress;
 mod provider;
 mod registrar;
+mod simulator;
 
 pub use self::instance::SubgraphInstance;
-pub use self::instance_manager::SubgraphInstanceManager;
+pub use self::instance_manager::{IndexingShutdown, SubgraphInstanceManager};
 pub use self::provider::SubgraphAssignmentProvider;
 pub use self::registrar::SubgraphRegistrar;
+pub use self::simulator::TransactionSimulator;
@@ -0,0 +1,129 @@
+use std::time::{Duration, Instant};
+
+use graph::prelude::BlockNumber;
+
+/// Measures how fast a subgraph is indexing. The rate is computed over
+/// intervals of a fixed length and smoothed across intervals so that a
+/// single unusually slow or fast interval does not throw off the estimate
+/// of how long the subgraph will take to sync.
+pub(crate) struct SyncProgress {
+    interval: Duration,
+    /// When the current interval started and at which block
+    start: Option<(Instant, BlockNumber)>,
+    blocks_per_second: Option<f64>,
+}
+
+impl SyncProgress {
+    pub fn new(interval: Duration) -> Self {
+        SyncProgress {
+            interval,
+            start: None,
+            blocks_per_second: None,
+        }
+    }
+
+    /// Record that the subgraph advanced to `block`. At the end of each
+    /// interval, return the blocks per second the subgraph processed
+    pub fn advance(&mut self, block: BlockNumber) -> Option<f64> {
+        self.advance_at(Instant::now(), block)
+    }
+
+    fn advance_at(&mut self, now: Instant, block: BlockNumber) -> Option<f64> {
+        let (start, start_block) = match self.start {
+            Some(start) => start,
+            None => {
+                self.start = Some((now, block));
+                return None;
+            }
+        };
+
+        let elapsed = now.saturating_duration_since(start);
+        if elapsed < self.interval {
+            return None;
+        }
+
+        // Reverts can move the subgraph backwards
+        let rate = (block - start_block).max(0) as f64 / elapsed.as_secs_f64();
+        let rate = match self.blocks_per_second {
+            Some(previous) => (previous + rate) / 2.0,
+            None => rate,
+        };
+        self.blocks_per_second = Some(rate);
+        self.start = Some((now, block));
+        Some(rate)
+    }
+}
+
+/// Watches how far a subgraph is behind the chain head so that we can
+/// warn once when it falls more than `max_blocks_behind` blocks behind,
+/// rather than on every block, and again when it has caught up
+pub(crate) struct BlocksBehind {
+    max_blocks_behind: BlockNumber,
+    behind: bool,
+}
+
+impl BlocksBehind {
+    pub fn new(max_blocks_behind: BlockNumber) -> Self {
+        BlocksBehind {
+            max_blocks_behind,
+            behind: false,
+        }
+    }
+
+    pub fn max_blocks_behind(&self) -> BlockNumber {
+        self.max_blocks_behind
+    }
+
+    /// Record that the subgraph is `blocks_behind` blocks behind the chain
+    /// head. Return `Some(true)` if it just fell too far behind, and
+    /// `Some(false)` if it just caught up
+    pub fn update(&mut self, blocks_behind: BlockNumber) -> Option<bool> {
+        let behind = blocks_behind > self.max_blocks_behind;
+        if behind == self.behind {
+            return None;
+        }
+        self.behind = behind;
+        Some(behind)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rate_is_smoothed_across_intervals() {
+        let interval = Duration::from_secs(10);
+        let start = Instant::now();
+        let mut progress = SyncProgress::new(interval);
+
+        assert_eq!(None, progress.advance_at(start, 100));
+        // Nothing is reported before the interval is over
+        assert_eq!(None, progress.advance_at(start + interval / 2, 150));
+        assert_eq!(Some(10.0), progress.advance_at(start + interval, 200));
+        // 20 blocks/s in the second interval, averaged with the first
+        assert_eq!(Some(15.0), progress.advance_at(start + interval * 2, 400));
+    }
+
+    #[test]
+    fn rate_is_zero_when_stuck_or_reverting() {
+        let interval = Duration::from_secs(10);
+        let start = Instant::now();
+        let mut progress = SyncProgress::new(interval);
+
+        assert_eq!(None, progress.advance_at(start, 100));
+        assert_eq!(Some(0.0), progress.advance_at(start + interval, 100));
+        // A revert does not produce a negative rate
+        assert_eq!(Some(0.0), progress.advance_at(start + interval * 2, 90));
+    }
+
+    #[test]
+    fn blocks_behind_reports_changes_only() {
+        let mut monitor = BlocksBehind::new(10);
+        assert_eq!(None, monitor.update(5));
+        assert_eq!(Some(true), monitor.update(11));
+        assert_eq!(None, monitor.update(20));
+        assert_eq!(Some(false), monitor.update(10));
+        assert_eq!(None, monitor.update(0));
+    }
+}
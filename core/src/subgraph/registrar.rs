@@ -1,4 +1,5 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
 use std::time::Instant;
 
 use async_trait::async_trait;
@@ -13,6 +14,13 @@ use graph::prelude::{
     SubgraphRegistrar as SubgraphRegistrarTrait, *,
 };
 
+/// How often to check whether a paused deployment has stopped indexing
+const REWIND_PAUSE_POLL: Duration = Duration::from_secs(1);
+
+/// How long to wait for a paused deployment to stop indexing before giving
+/// up on rewinding it
+const REWIND_PAUSE_TIMEOUT: Duration = Duration::from_secs(300);
+
 pub struct SubgraphRegistrar<L, P, S, SM> {
     logger: Logger,
     logger_factory: LoggerFactory,
@@ -352,25 +360,174 @@ where
         hash: &DeploymentHash,
         node_id: &NodeId,
     ) -> Result<(), SubgraphRegistrarError> {
-        let locations = self.store.locators(hash)?;
-        let deployment = match locations.len() {
-            0 => return Err(SubgraphRegistrarError::DeploymentNotFound(hash.to_string())),
-            1 => locations[0].clone(),
-            _ => {
-                return Err(SubgraphRegistrarError::StoreError(
-                    anyhow!(
-                        "there are {} different deployments with id {}",
-                        locations.len(),
-                        hash.as_str()
-                    )
-                    .into(),
-                ))
-            }
-        };
+        let deployment = locate_deployment(self.store.as_ref(), hash)?;
         self.store.reassign_subgraph(&deployment, node_id)?;
 
         Ok(())
     }
+
+    async fn rewind_subgraph(
+        &self,
+        hash: &DeploymentHash,
+        block_ptr_to: BlockPtr,
+    ) -> Result<(), SubgraphRegistrarError> {
+        let deployment = locate_deployment(self.store.as_ref(), hash)?;
+
+        // Make sure the block is on the deployment's chain and that the
+        // deployment has gotten past it
+        let (network, chain_store) =
+            chain_store(self.store.as_ref(), self.chains.as_ref(), &deployment)?;
+        match chain_store.block_number(block_ptr_to.hash_as_h256())? {
+            Some((_, number)) if number == block_ptr_to.number => { /* ok */ }
+            Some((_, number)) => {
+                return Err(SubgraphRegistrarError::Unknown(anyhow!(
+                    "the block hash {} is for block number {} but block number {} was given",
+                    block_ptr_to.hash_hex(),
+                    number,
+                    block_ptr_to.number
+                )))
+            }
+            None => {
+                return Err(SubgraphRegistrarError::Unknown(anyhow!(
+                    "the chain {} does not have a block with hash {}",
+                    network,
+                    block_ptr_to.hash_hex()
+                )))
+            }
+        }
+        match self.store.least_block_ptr(hash)? {
+            None => {
+                return Err(SubgraphRegistrarError::Unknown(anyhow!(
+                    "deployment {} has not processed any blocks and can not be rewound",
+                    deployment
+                )))
+            }
+            Some(ptr) if ptr.number <= block_ptr_to.number => {
+                return Err(SubgraphRegistrarError::Unknown(anyhow!(
+                    "deployment {} is at block {} and can not be rewound to block {}",
+                    deployment,
+                    ptr.number,
+                    block_ptr_to.number
+                )))
+            }
+            Some(_) => { /* ok */ }
+        }
+
+        // Pausing only takes effect once the indexing node notices the
+        // changed assignment. Do the actual work in the background so that
+        // the caller does not have to wait for that
+        let logger = self.logger.clone();
+        let store = self.store.clone();
+        graph::spawn(async move {
+            if let Err(e) =
+                rewind_deployment(&logger, store.as_ref(), &deployment, block_ptr_to).await
+            {
+                error!(logger, "Failed to rewind deployment";
+                       "deployment" => deployment.to_string(), "error" => e.to_string());
+            }
+        });
+        Ok(())
+    }
+}
+
+/// Find the chain store for the network that `deployment` indexes
+fn chain_store<S: SubgraphStore>(
+    store: &S,
+    chains: &BlockchainMap,
+    deployment: &DeploymentLocator,
+) -> Result<(String, Arc<dyn ChainStore>), SubgraphRegistrarError> {
+    let network = store.network_name(deployment)?;
+    let chain_store = match chains.get::<graph_chain_ethereum::Chain>(network.clone()) {
+        Ok(chain) => chain.chain_store(),
+        Err(_) => chains
+            .get::<graph_chain_near::Chain>(network.clone())
+            .map_err(SubgraphRegistrarError::NetworkNotSupported)?
+            .chain_store(),
+    };
+    Ok((network, chain_store))
+}
+
+/// Pause `deployment`, rewind it to `block_ptr_to` and then resume it
+async fn rewind_deployment<S: SubgraphStore>(
+    logger: &Logger,
+    store: &S,
+    deployment: &DeploymentLocator,
+    block_ptr_to: BlockPtr,
+) -> Result<(), SubgraphRegistrarError> {
+    const PAUSED: &str = "paused_";
+
+    // Pause the deployment by assigning it to a node that does not
+    // exist, unless it is unassigned or already paused
+    let node = store
+        .assigned_node(deployment)?
+        .filter(|node| !node.as_str().starts_with(PAUSED));
+    if let Some(node) = &node {
+        let paused = NodeId::new(format!("{}{}", PAUSED, node)).map_err(|()| {
+            SubgraphRegistrarError::Unknown(anyhow!("invalid node id `{}{}`", PAUSED, node))
+        })?;
+        store.reassign_subgraph(deployment, &paused)?;
+        info!(logger, "Paused deployment for rewind";
+              "deployment" => deployment.to_string(), "node_id" => node.to_string());
+    }
+
+    let result = match wait_until_stopped(store, deployment).await {
+        Ok(()) => store
+            .rewind(deployment.hash.clone(), block_ptr_to.clone())
+            .map_err(SubgraphRegistrarError::from),
+        Err(e) => Err(e),
+    };
+
+    // Resume the deployment even if rewinding failed so that it does
+    // not stay paused
+    if let Some(node) = &node {
+        store.reassign_subgraph(deployment, node)?;
+    }
+    result?;
+
+    info!(logger, "Rewound deployment";
+          "deployment" => deployment.to_string(), "block" => block_ptr_to.to_string());
+    Ok(())
+}
+
+/// Wait until no graph-node process holds the indexing lock for
+/// `deployment` anymore, which it releases once it has stopped indexing
+async fn wait_until_stopped<S: SubgraphStore>(
+    store: &S,
+    deployment: &DeploymentLocator,
+) -> Result<(), SubgraphRegistrarError> {
+    let start = Instant::now();
+    while store.is_indexing(&deployment.hash)? {
+        if start.elapsed() >= REWIND_PAUSE_TIMEOUT {
+            return Err(SubgraphRegistrarError::Unknown(anyhow!(
+                "deployment {} did not stop indexing within {}s after it was paused",
+                deployment,
+                REWIND_PAUSE_TIMEOUT.as_secs()
+            )));
+        }
+        tokio::time::sleep(REWIND_PAUSE_POLL).await;
+    }
+    Ok(())
+}
+
+/// Find the one deployment with `hash`, failing if there is none or more
+/// than one
+fn locate_deployment<S: SubgraphStore>(
+    store: &S,
+    hash: &DeploymentHash,
+) -> Result<DeploymentLocator, SubgraphRegistrarError> {
+    let locations = store.locators(hash)?;
+    match locations.len() {
+        0 => Err(SubgraphRegistrarError::DeploymentNotFound(hash.to_string())),
+        1 => Ok(locations[0].clone()),
+        _ => Err(SubgraphRegistrarError::StoreError(
+            anyhow!(
+                "there are {} different deployments with id {}",
+                locations.len(),
+                hash.as_str()
+            )
+            .into(),
+        )),
+    }
 }
 
 async fn handle_assignment_event(
@@ -496,15 +653,23 @@ async fn create_subgraph_version<C: Blockchain, S: SubgraphStore, L: LinkResolve
     version_switching_mode: SubgraphVersionSwitchingMode,
     resolver: Arc<L>,
 ) -> Result<(), SubgraphRegistrarError> {
+    // Keep the manifest as it was deployed so that it can be looked up
+    // later without going to IPFS
+    let raw_yaml = serde_yaml::to_string(&raw).ok();
+    let unresolved_abis = unresolved_abis(&raw).map_err(SubgraphRegistrarError::ResolveError)?;
+    let resolver = Arc::new(RecordingResolver::new(resolver));
     let unvalidated = UnvalidatedSubgraphManifest::<C>::resolve(
         deployment,
         raw,
-        resolver,
+        resolver.cheap_clone(),
         &logger,
         MAX_SPEC_VERSION.clone(),
     )
     .map_err(SubgraphRegistrarError::ResolveError)
     .await?;
+    let abis = resolve_abis(&logger, unresolved_abis, resolver.as_ref())
+        .map_err(SubgraphRegistrarError::ResolveError)
+        .await?;
 
     let manifest = unvalidated
         .validate(store.cheap_clone(), true)
@@ -548,7 +713,10 @@ async fn create_subgraph_version<C: Blockchain, S: SubgraphStore, L: LinkResolve
 
     // Apply the subgraph versioning and deployment operations,
     // creating a new subgraph deployment if one doesn't exist.
-    let deployment = SubgraphDeploymentEntity::new(&manifest, false, start_block).graft(base_block);
+    let mut deployment =
+        SubgraphDeploymentEntity::new(&manifest, false, start_block).graft(base_block);
+    deployment.manifest.raw_yaml = raw_yaml;
+    deployment.manifest.abis = abis;
     deployment_store
         .create_subgraph_deployment(
             name,
@@ -561,3 +729,112 @@ async fn create_subgraph_version<C: Blockchain, S: SubgraphStore, L: LinkResolve
         .map_err(|e| SubgraphRegistrarError::SubgraphDeploymentError(e))
         .map(|_| ())
 }
+
+/// A `LinkResolver` that remembers the contents of every file it fetched
+/// so that they can be fetched again without going to IPFS
+struct RecordingResolver<L> {
+    resolver: Arc<L>,
+    files: Mutex<HashMap<String, Vec<u8>>>,
+}
+
+impl<L> RecordingResolver<L> {
+    fn new(resolver: Arc<L>) -> Self {
+        Self {
+            resolver,
+            files: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+#[async_trait]
+impl<L: LinkResolver> LinkResolver for RecordingResolver<L> {
+    // The timeout and retries are those of the wrapped resolver
+    fn with_timeout(self, _timeout: Duration) -> Self {
+        self
+    }
+
+    fn with_retries(self) -> Self {
+        self
+    }
+
+    async fn cat(&self, logger: &Logger, link: &Link) -> Result<Vec<u8>, Error> {
+        if let Some(bytes) = self.files.lock().unwrap().get(&link.link) {
+            return Ok(bytes.clone());
+        }
+        let bytes = self.resolver.cat(logger, link).await?;
+        self.files
+            .lock()
+            .unwrap()
+            .insert(link.link.clone(), bytes.clone());
+        Ok(bytes)
+    }
+
+    async fn json_stream(&self, logger: &Logger, link: &Link) -> Result<JsonValueStream, Error> {
+        self.resolver.json_stream(logger, link).await
+    }
+}
+
+#[derive(Deserialize)]
+struct UnresolvedAbi {
+    name: String,
+    file: Link,
+}
+
+/// The ABIs that the data sources and templates in the manifest `raw`
+/// reference
+fn unresolved_abis(
+    raw: &serde_yaml::Mapping,
+) -> Result<Vec<UnresolvedAbi>, SubgraphManifestResolveError> {
+    Ok(["dataSources", "templates"]
+        .iter()
+        .filter_map(|key| raw.get(&serde_yaml::Value::from(*key)))
+        .filter_map(|sources| sources.as_sequence())
+        .flatten()
+        .filter_map(|source| source.get("mapping"))
+        .filter_map(|mapping| mapping.get("abis"))
+        .filter_map(|abis| abis.as_sequence())
+        .flatten()
+        .map(|abi| serde_yaml::from_value::<UnresolvedAbi>(abi.clone()))
+        .collect::<Result<Vec<_>, _>>()?)
+}
+
+/// Fetch the ABIs `unresolved` and combine them into a JSON list of
+/// objects with the name, the file and the contents of each ABI, so that
+/// they can be stored with the deployment. ABIs with the same name and
+/// file are only listed once. The files should have been fetched while
+/// resolving the manifest with `resolver` already. Returns `None` if the
+/// manifest does not reference any ABIs
+async fn resolve_abis<L: LinkResolver>(
+    logger: &Logger,
+    unresolved: Vec<UnresolvedAbi>,
+    resolver: &L,
+) -> Result<Option<String>, SubgraphManifestResolveError> {
+    if unresolved.is_empty() {
+        return Ok(None);
+    }
+
+    let mut seen = HashSet::new();
+    let mut abis = Vec::new();
+    for abi in unresolved {
+        if !seen.insert((abi.name.clone(), abi.file.link.clone())) {
+            continue;
+        }
+        let bytes = resolver
+            .cat(logger, &abi.file)
+            .await
+            .map_err(SubgraphManifestResolveError::ResolveError)?;
+        let value: serde_json::Value = serde_json::from_slice(&bytes).map_err(|e| {
+            SubgraphManifestResolveError::ResolveError(anyhow!(
+                "ABI {} is not valid JSON: {}",
+                abi.name,
+                e
+            ))
+        })?;
+        abis.push(serde_json::json!({
+            "name": abi.name,
+            "file": abi.file.link,
+            "abi": value,
+        }));
+    }
+    Ok(Some(serde_json::Value::Array(abis).to_string()))
+}
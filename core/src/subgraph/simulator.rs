@@ -0,0 +1,330 @@
+//! Run the handlers of a subgraph for a single transaction against the
+//! current state of the subgraph without writing anything. This answers
+//! questions like "why did my transaction not show up in the subgraph"
+//! without having to redeploy or rewind the subgraph.
+
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use graph::blockchain::{
+    Block, Blockchain, BlockchainKind, BlockchainMap, NodeCapabilities, TriggerData,
+    TriggerFilter as _, TriggersAdapter,
+};
+use graph::components::subgraph::{
+    CausalityRegion, MappingError, TransactionSimulator as TransactionSimulatorTrait,
+};
+use graph::data::subgraph::status::{EntityDiff, EntityDiffOperation};
+use graph::data::subgraph::MAX_SPEC_VERSION;
+use graph::prelude::*;
+use graph::util::lfu_cache::LfuCache;
+
+use super::loader::load_dynamic_data_sources;
+use super::SubgraphInstance;
+use crate::MetricsRegistry;
+
+pub struct TransactionSimulator<S, L> {
+    subgraph_store: Arc<S>,
+    chains: Arc<BlockchainMap>,
+    link_resolver: Arc<L>,
+}
+
+impl<S, L> TransactionSimulator<S, L>
+where
+    S: SubgraphStore,
+    L: LinkResolver,
+{
+    pub fn new(subgraph_store: Arc<S>, chains: Arc<BlockchainMap>, link_resolver: Arc<L>) -> Self {
+        TransactionSimulator {
+            subgraph_store,
+            chains,
+            link_resolver,
+        }
+    }
+
+    async fn simulate<C: Blockchain>(
+        &self,
+        logger: &Logger,
+        deployment: &DeploymentHash,
+        raw: serde_yaml::Mapping,
+        transaction: &[u8],
+    ) -> Result<Vec<EntityDiff>, Error> {
+        let loc = self
+            .subgraph_store
+            .locators(deployment.as_str())?
+            .into_iter()
+            .next()
+            .ok_or_else(|| anyhow!("deployment {} does not exist", deployment))?;
+        let store = self
+            .subgraph_store
+            .cheap_clone()
+            .writable(logger.clone(), loc.id)
+            .await?;
+
+        let mut manifest = SubgraphManifest::<C>::resolve_from_raw(
+            deployment.cheap_clone(),
+            raw,
+            self.link_resolver.as_ref(),
+            logger,
+            MAX_SPEC_VERSION.clone(),
+        )
+        .await
+        .context("failed to resolve subgraph from IPFS")?;
+        let data_sources = load_dynamic_data_sources::<C>(
+            store.clone(),
+            logger.clone(),
+            manifest.templates.clone(),
+        )
+        .await
+        .context("failed to load dynamic data sources")?;
+        manifest.data_sources.extend(data_sources);
+
+        let network = manifest.network_name();
+        let chain = self
+            .chains
+            .get::<C>(network.clone())
+            .with_context(|| format!("no chain configured for network {}", network))?;
+
+        // Keep the metrics of simulations away from those of the running
+        // subgraph, which would otherwise be registered twice
+        let registry = Arc::new(MetricsRegistry::new(
+            logger.clone(),
+            Arc::new(Registry::new()),
+        ));
+        let stopwatch_metrics =
+            StopwatchMetrics::new(logger.clone(), deployment.clone(), registry.clone());
+
+        let required_capabilities = C::NodeCapabilities::from_data_sources(&manifest.data_sources);
+        let filter = C::TriggerFilter::from_data_sources(manifest.data_sources.iter());
+        let triggers_adapter = chain.triggers_adapter(
+            &loc,
+            &required_capabilities,
+            manifest.unified_mapping_api_version()?,
+            stopwatch_metrics.clone(),
+        )?;
+
+        let block_ptr = triggers_adapter
+            .transaction_block(transaction)
+            .await?
+            .ok_or_else(|| {
+                anyhow!(
+                    "transaction 0x{} is not known or not in a block yet",
+                    hex::encode(transaction)
+                )
+            })?;
+        let block = triggers_adapter
+            .scan_triggers(block_ptr.number, block_ptr.number, &filter)
+            .await?
+            .into_iter()
+            .find(|block| block.block.ptr() == block_ptr)
+            .ok_or_else(|| anyhow!("block {} is not on the main chain", block_ptr))?;
+        let triggers: Vec<_> = block
+            .trigger_data
+            .into_iter()
+            .filter(|trigger| trigger.transaction_hash() == Some(transaction))
+            .collect();
+        let block = Arc::new(block.block);
+        debug!(logger, "Simulating transaction";
+                       "block" => block_ptr.to_string(),
+                       "triggers" => triggers.len());
+
+        let host_metrics = Arc::new(HostMetrics::new(
+            registry,
+            deployment.as_str(),
+            stopwatch_metrics,
+        ));
+        let host_builder = graph_runtime_wasm::RuntimeHostBuilder::new(
+            chain.runtime_adapter(),
+            self.link_resolver.cheap_clone(),
+            self.subgraph_store.cheap_clone(),
+        );
+        let instance =
+            SubgraphInstance::from_manifest(logger, manifest, host_builder, host_metrics, None)?;
+        let causality_region = CausalityRegion::from_network(instance.network());
+
+        // The block state reads the current state of the deployment, and
+        // all changes stay in its entity cache since we never transact them
+        let mut block_state = BlockState::<C>::new(store, LfuCache::new());
+        for trigger in triggers {
+            block_state = instance
+                .process_trigger(
+                    logger,
+                    &block,
+                    &trigger,
+                    block_state,
+                    None,
+                    &causality_region,
+                )
+                .await
+                .map_err(|e| match e {
+                    MappingError::Unknown(e) | MappingError::PossibleReorg(e) => e,
+                })?;
+        }
+        entity_diffs(block_state)
+    }
+}
+
+/// The changes that the handlers made to the entities in `block_state`.
+/// They are taken from its entity cache without writing anything to the
+/// store
+fn entity_diffs<C: Blockchain>(block_state: BlockState<C>) -> Result<Vec<EntityDiff>, Error> {
+    if let Some(error) = block_state.deterministic_errors.first() {
+        return Err(anyhow!(
+            "the handlers failed with a deterministic error: {}",
+            error.message
+        ));
+    }
+
+    let modifications = block_state.entity_cache.as_modifications()?.modifications;
+    Ok(modifications
+        .into_iter()
+        .map(|modification| {
+            let (key, operation, data) = match modification {
+                EntityModification::Insert { key, data } => {
+                    (key, EntityDiffOperation::Created, Some(data))
+                }
+                EntityModification::Overwrite { key, data } => {
+                    (key, EntityDiffOperation::Updated, Some(data))
+                }
+                EntityModification::Remove { key } => (key, EntityDiffOperation::Deleted, None),
+            };
+            EntityDiff {
+                entity_type: key.entity_type.into_string(),
+                id: key.entity_id,
+                operation,
+                data,
+            }
+        })
+        .collect())
+}
+
+#[async_trait]
+impl<S, L> TransactionSimulatorTrait for TransactionSimulator<S, L>
+where
+    S: SubgraphStore,
+    L: LinkResolver,
+{
+    async fn simulate_transaction(
+        &self,
+        logger: &Logger,
+        deployment: &DeploymentHash,
+        transaction: &[u8],
+    ) -> Result<Vec<EntityDiff>, Error> {
+        let logger = logger.new(o!("subgraph_id" => deployment.to_string()));
+
+        // Prefer the manifest we stored when the subgraph was deployed
+        let stored = self.subgraph_store.raw_manifest(deployment)?;
+        let file_bytes = match stored {
+            Some(yaml) => yaml.into_bytes(),
+            None => {
+                self.link_resolver
+                    .cat(&logger, &deployment.to_ipfs_link())
+                    .await?
+            }
+        };
+        let raw: serde_yaml::Mapping = serde_yaml::from_slice(&file_bytes)?;
+
+        match BlockchainKind::from_manifest(&raw)? {
+            BlockchainKind::Ethereum => {
+                self.simulate::<graph_chain_ethereum::Chain>(&logger, deployment, raw, transaction)
+                    .await
+            }
+            BlockchainKind::Near => {
+                self.simulate::<graph_chain_near::Chain>(&logger, deployment, raw, transaction)
+                    .await
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use graph::components::store::EntityType;
+    use graph::data::subgraph::schema::SubgraphError;
+    use graph::data::subgraph::status::EntityDiffOperation;
+    use graph::prelude::*;
+    use graph::util::lfu_cache::LfuCache;
+    use graph_chain_ethereum::Chain;
+    use test_store::*;
+
+    use super::entity_diffs;
+
+    const SCHEMA: &str = "type User @entity { id: ID!, name: String! }";
+
+    fn user(id: &str, name: &str) -> Entity {
+        Entity::from(vec![("id", Value::from(id)), ("name", Value::from(name))])
+    }
+
+    fn key(deployment: &DeploymentHash, id: &str) -> EntityKey {
+        EntityKey {
+            subgraph_id: deployment.clone(),
+            entity_type: EntityType::new("User".to_owned()),
+            entity_id: id.to_owned(),
+        }
+    }
+
+    #[test]
+    fn simulation_does_not_write() {
+        run_test_sequentially(|store| async move {
+            let id = DeploymentHash::new("simulateTransaction").unwrap();
+            let deployment = create_test_subgraph(&id, SCHEMA);
+            transact_entity_operations(
+                &store.subgraph_store(),
+                &deployment,
+                GENESIS_PTR.clone(),
+                vec![EntityOperation::Set {
+                    key: key(&id, "1"),
+                    data: user("1", "Alice"),
+                }],
+            )
+            .unwrap();
+            let writable = store
+                .subgraph_store()
+                .writable(LOGGER.clone(), deployment.id)
+                .await
+                .unwrap();
+
+            let mut block_state = BlockState::<Chain>::new(writable.cheap_clone(), LfuCache::new());
+            block_state
+                .entity_cache
+                .set(key(&id, "1"), user("1", "Bob"));
+            block_state
+                .entity_cache
+                .set(key(&id, "2"), user("2", "Carol"));
+            let mut diffs: Vec<_> = entity_diffs(block_state)
+                .unwrap()
+                .into_iter()
+                .map(|diff| (diff.id, diff.operation))
+                .collect();
+            diffs.sort_by(|a, b| a.0.cmp(&b.0));
+            assert_eq!(
+                vec![
+                    ("1".to_owned(), EntityDiffOperation::Updated),
+                    ("2".to_owned(), EntityDiffOperation::Created),
+                ],
+                diffs
+            );
+
+            // The store is exactly as it was before the simulation
+            assert_eq!(
+                Some(user("1", "Alice")),
+                writable.get(&key(&id, "1")).unwrap()
+            );
+            assert_eq!(None, writable.get(&key(&id, "2")).unwrap());
+            assert_eq!(Some(GENESIS_PTR.clone()), writable.block_ptr().unwrap());
+
+            // Deterministic errors are reported instead of the changes
+            let mut block_state = BlockState::<Chain>::new(writable, LfuCache::new());
+            block_state
+                .entity_cache
+                .set(key(&id, "2"), user("2", "Carol"));
+            block_state.deterministic_errors.push(SubgraphError {
+                subgraph_id: id.clone(),
+                message: "boom".to_owned(),
+                block_ptr: None,
+                handler: None,
+                deterministic: true,
+            });
+            assert!(entity_diffs(block_state).is_err());
+        })
+    }
+}
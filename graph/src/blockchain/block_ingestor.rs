@@ -1,24 +1,31 @@
-use std::{sync::Arc, time::Duration};
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use crate::{
-    blockchain::{Blockchain, IngestorAdapter, IngestorError},
-    prelude::{info, lazy_static, tokio, trace, warn, Error, LogCode, Logger},
+    blockchain::{BlockPtr, Blockchain, IngestorAdapter, IngestorError},
+    components::store::ChainStore,
+    prelude::{info, tokio, trace, warn, BlockNumber, Error, LogCode, Logger},
 };
+use futures03::{stream::BoxStream, FutureExt, StreamExt};
 
-lazy_static! {
-    // graph_node::config disallows setting this in a store with multiple
-    // shards. See 8b6ad0c64e244023ac20ced7897fe666 for the reason
-    pub static ref CLEANUP_BLOCKS: bool = std::env::var("GRAPH_ETHEREUM_CLEANUP_BLOCKS")
-        .ok()
-        .map(|s| s.eq_ignore_ascii_case("true"))
-        .unwrap_or(false);
-}
+/// How often to record the provider's latest block in the store even if
+/// it has not changed, so that the status API can tell a stuck provider
+/// from one that is no longer being polled
+const PROVIDER_HEAD_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long to wait for the next head from a new heads subscription. A
+/// subscription that stays quiet for longer than that has most likely
+/// stopped working without telling us, and we poll and subscribe again
+const NEW_HEADS_TIMEOUT: Duration = Duration::from_secs(60);
 
 pub struct BlockIngestor<C>
 where
     C: Blockchain,
 {
     adapter: Arc<C::IngestorAdapter>,
+    chain_store: Arc<dyn ChainStore>,
     logger: Logger,
     polling_interval: Duration,
 }
@@ -29,94 +36,164 @@ where
 {
     pub fn new(
         adapter: Arc<C::IngestorAdapter>,
+        chain_store: Arc<dyn ChainStore>,
         polling_interval: Duration,
     ) -> Result<BlockIngestor<C>, Error> {
         let logger = adapter.logger().clone();
         Ok(BlockIngestor {
             adapter,
+            chain_store,
             logger,
             polling_interval,
         })
     }
 
     pub async fn into_polling_stream(self) {
+        let mut provider_head = None;
+        let mut heads = None;
+        let mut subscribed_at: Option<Instant> = None;
         loop {
-            match self.do_poll().await {
-                // Some polls will fail due to transient issues
-                Err(err @ IngestorError::BlockUnavailable(_)) => {
-                    info!(
-                        self.logger,
-                        "Trying again after block polling failed: {}", err
-                    );
-                }
-                Err(err @ IngestorError::ReceiptUnavailable(_, _)) => {
-                    info!(
-                        self.logger,
-                        "Trying again after block polling failed: {}", err
-                    );
+            // Try to subscribe to new heads again once in a while if the
+            // provider can not push them or the subscription stopped
+            // working
+            if heads.is_none() && subscribed_at.map_or(true, |at| at.elapsed() >= NEW_HEADS_TIMEOUT)
+            {
+                subscribed_at = Some(Instant::now());
+                heads = match self.adapter.new_heads().await {
+                    Ok(Some(heads)) => {
+                        info!(self.logger, "Subscribed to new chain heads");
+                        Some(heads)
+                    }
+                    Ok(None) => None,
+                    Err(e) => {
+                        warn!(
+                            self.logger,
+                            "Polling for new blocks since subscribing to new heads failed";
+                            "error" => e.to_string()
+                        );
+                        None
+                    }
+                };
+            }
+
+            let stream = match heads.as_mut() {
+                Some(stream) => stream,
+                None => {
+                    let latest_block = self.adapter.latest_block().await;
+                    self.ingest(&mut provider_head, latest_block).await;
+                    tokio::time::sleep(self.polling_interval).await;
+                    continue;
                 }
-                Err(IngestorError::Unknown(inner_err)) => {
-                    warn!(
-                        self.logger,
-                        "Trying again after block polling failed: {}", inner_err
-                    );
+            };
+
+            let error = match newest_head(stream, NEW_HEADS_TIMEOUT).await {
+                Ok(head) => {
+                    self.ingest(&mut provider_head, Ok(head)).await;
+                    continue;
                 }
-                Ok(()) => (),
-            }
+                Err(error) => error,
+            };
+            warn!(
+                self.logger,
+                "Polling for new blocks since the new heads subscription stopped working";
+                "error" => error
+            );
+            // Dropping the stream unsubscribes from new heads
+            heads = None;
+            let latest_block = self.adapter.latest_block().await;
+            self.ingest(&mut provider_head, latest_block).await;
+        }
+    }
 
-            if *CLEANUP_BLOCKS {
-                self.cleanup_cached_blocks()
+    /// Ingest `latest_block`, the latest block of the provider, if we
+    /// could get it. Errors are only logged since the next poll or new head
+    /// will try again
+    async fn ingest(
+        &self,
+        provider_head: &mut Option<(Result<BlockNumber, String>, Instant)>,
+        latest_block: Result<BlockPtr, IngestorError>,
+    ) {
+        let res = match latest_block {
+            Ok(latest_block) => {
+                self.record_provider_head(provider_head, Ok(latest_block.number));
+                self.do_poll(latest_block).await
             }
-
-            tokio::time::sleep(self.polling_interval).await;
+            Err(e) => {
+                self.record_provider_head(provider_head, Err(e.to_string()));
+                Err(e)
+            }
+        };
+        match res {
+            // Some polls will fail due to transient issues
+            Err(err @ IngestorError::BlockUnavailable(_)) => {
+                info!(
+                    self.logger,
+                    "Trying again after block polling failed: {}", err
+                );
+            }
+            Err(err @ IngestorError::ReceiptUnavailable(_, _)) => {
+                info!(
+                    self.logger,
+                    "Trying again after block polling failed: {}", err
+                );
+            }
+            Err(IngestorError::Unknown(inner_err)) => {
+                warn!(
+                    self.logger,
+                    "Trying again after block polling failed: {}", inner_err
+                );
+            }
+            Ok(()) => (),
         }
     }
 
-    fn cleanup_cached_blocks(&self) {
-        match self.adapter.cleanup_cached_blocks() {
-            Ok(Some((min_block, count))) => {
-                if count > 0 {
-                    info!(
-                        self.logger,
-                        "Cleaned {} blocks from the block cache. \
-                                 Only blocks with number greater than {} remain",
-                        count,
-                        min_block
-                    );
-                }
+    /// Record `head` in the store unless it is what we recorded last, and
+    /// that was recently. `recorded` keeps track of what we recorded last
+    fn record_provider_head(
+        &self,
+        recorded: &mut Option<(Result<BlockNumber, String>, Instant)>,
+        head: Result<BlockNumber, String>,
+    ) {
+        if let Some((last, at)) = recorded {
+            if last == &head && at.elapsed() < PROVIDER_HEAD_INTERVAL {
+                return;
             }
-            Ok(None) => { /* nothing was cleaned, ignore */ }
+        }
+        match self.chain_store.set_provider_head(head.clone()) {
+            Ok(()) => *recorded = Some((head, Instant::now())),
             Err(e) => warn!(
                 self.logger,
-                "Failed to clean blocks from block cache: {}", e
+                "Failed to record the provider's latest block";
+                "error" => e.to_string()
             ),
         }
     }
 
-    async fn do_poll(&self) -> Result<(), IngestorError> {
+    /// Ingest `latest_block`, the latest block of the provider, and its
+    /// ancestors. The caller gets `latest_block` by fetching only the
+    /// block header since that's cheaper than the full block. This is
+    /// worthwhile because most of the time there won't be a new block, as
+    /// we expect the poll interval to be much shorter than the block time.
+    async fn do_poll(&self, latest_block: BlockPtr) -> Result<(), IngestorError> {
         trace!(self.logger, "BlockIngestor::do_poll");
 
         // Get chain head ptr from store
         let head_block_ptr_opt = self.adapter.chain_head_ptr()?;
 
-        // To check if there is a new block or not, fetch only the block header since that's cheaper
-        // than the full block. This is worthwhile because most of the time there won't be a new
-        // block, as we expect the poll interval to be much shorter than the block time.
-        let latest_block = self.adapter.latest_block().await?;
-
         // If latest block matches head block in store, nothing needs to be done
         if Some(&latest_block) == head_block_ptr_opt.as_ref() {
             return Ok(());
         }
 
         // Compare latest block with head ptr, alert user if far behind
-        match head_block_ptr_opt {
+        let blocks_needed = match head_block_ptr_opt {
             None => {
                 info!(
                     self.logger,
                     "Downloading latest blocks from Ethereum. \
                                     This may take a few minutes..."
                 );
+                self.adapter.ancestor_count()
             }
             Some(head_block_ptr) => {
                 let latest_number = latest_block.number;
@@ -140,7 +217,17 @@ where
                         "code" => code,
                     );
                 }
+                blocks_needed
             }
+        };
+
+        // When we are more than a block behind, fetch the blocks we need
+        // concurrently rather than one parent at a time below
+        if blocks_needed > 1 {
+            let first = 0.max(latest_block.number - blocks_needed);
+            self.adapter
+                .prefetch_blocks(first..latest_block.number)
+                .await?;
         }
 
         // Store latest block in block store.
@@ -173,3 +260,79 @@ where
         Ok(())
     }
 }
+
+/// Wait up to `timeout` for the next head from `heads`, and then skip to
+/// the newest of the heads that are already waiting. Heads pile up while we
+/// ingest one, and ingesting the newest one also ingests its ancestors.
+/// Returns why the subscription stopped working if it did
+async fn newest_head(
+    heads: &mut BoxStream<'static, Result<BlockPtr, Error>>,
+    timeout: Duration,
+) -> Result<BlockPtr, String> {
+    let mut head = match tokio::time::timeout(timeout, heads.next()).await {
+        Ok(Some(Ok(head))) => head,
+        Ok(Some(Err(e))) => return Err(e.to_string()),
+        Ok(None) => return Err("the subscription ended".to_string()),
+        Err(_) => return Err("no new head arrived in time".to_string()),
+    };
+    loop {
+        match heads.next().now_or_never() {
+            Some(Some(Ok(newer))) => head = newer,
+            Some(Some(Err(e))) => return Err(e.to_string()),
+            Some(None) => return Err("the subscription ended".to_string()),
+            None => return Ok(head),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures03::{stream, StreamExt};
+    use web3::types::H256;
+
+    use super::newest_head;
+    use crate::blockchain::BlockPtr;
+    use crate::prelude::anyhow;
+
+    fn ptr(number: u64) -> BlockPtr {
+        BlockPtr::from((H256::from_low_u64_be(number), number))
+    }
+
+    #[tokio::test]
+    async fn skips_to_the_newest_head() {
+        let mut heads = stream::iter(vec![Ok(ptr(1)), Ok(ptr(2)), Ok(ptr(3))])
+            .chain(stream::pending())
+            .boxed();
+        assert_eq!(
+            Ok(ptr(3)),
+            newest_head(&mut heads, Duration::from_secs(1)).await
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_broken_subscriptions() {
+        let timeout = Duration::from_millis(10);
+
+        let mut heads = stream::pending().boxed();
+        assert_eq!(
+            Err("no new head arrived in time".to_string()),
+            newest_head(&mut heads, timeout).await
+        );
+
+        let mut heads = stream::iter(vec![Ok(ptr(1))]).boxed();
+        assert_eq!(
+            Err("the subscription ended".to_string()),
+            newest_head(&mut heads, timeout).await
+        );
+
+        let mut heads = stream::iter(vec![Ok(ptr(1)), Err(anyhow!("boom"))])
+            .chain(stream::pending())
+            .boxed();
+        assert_eq!(
+            Err("boom".to_string()),
+            newest_head(&mut heads, timeout).await
+        );
+    }
+}
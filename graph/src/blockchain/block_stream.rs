@@ -1,6 +1,7 @@
 use anyhow::Error;
 use futures03::Stream;
 use std::sync::Arc;
+use std::time::Instant;
 use thiserror::Error;
 
 use super::{Block, BlockPtr, Blockchain};
@@ -19,6 +20,9 @@ pub type FirehoseCursor = Option<String>;
 pub struct BlockWithTriggers<C: Blockchain> {
     pub block: C::Block,
     pub trigger_data: Vec<C::TriggerData>,
+    /// When the triggers for the block were found; used to measure how
+    /// long it takes until the block has been processed
+    pub triggers_found_at: Instant,
 }
 
 impl<C: Blockchain> BlockWithTriggers<C> {
@@ -28,6 +32,7 @@ impl<C: Blockchain> BlockWithTriggers<C> {
         Self {
             block,
             trigger_data,
+            triggers_found_at: Instant::now(),
         }
     }
 
@@ -49,6 +54,13 @@ pub trait TriggersAdapter<C: Blockchain>: Send + Sync {
     fn ancestor_block(&self, ptr: BlockPtr, offset: BlockNumber)
         -> Result<Option<C::Block>, Error>;
 
+    // Like `ancestor_block`, but only return a pointer to the ancestor.
+    // Adapters should override this if they can find the pointer without
+    // loading the blocks between `ptr` and the ancestor
+    fn ancestor_ptr(&self, ptr: BlockPtr, offset: BlockNumber) -> Result<Option<BlockPtr>, Error> {
+        Ok(self.ancestor_block(ptr, offset)?.map(|block| block.ptr()))
+    }
+
     // Returns a sequence of blocks in increasing order of block number.
     // Each block will include all of its triggers that match the given `filter`.
     // The sequence may omit blocks that contain no triggers,
@@ -76,6 +88,15 @@ pub trait TriggersAdapter<C: Blockchain>: Send + Sync {
 
     /// Get pointer to parent of `block`. This is called when reverting `block`.
     async fn parent_ptr(&self, block: &BlockPtr) -> Result<Option<BlockPtr>, Error>;
+
+    /// Get a pointer to the block that contains the transaction with hash
+    /// `hash`, or `None` if the transaction is not known or not in a block
+    /// yet
+    async fn transaction_block(&self, _hash: &[u8]) -> Result<Option<BlockPtr>, Error> {
+        Err(anyhow!(
+            "looking up transactions is not supported for this chain"
+        ))
+    }
 }
 
 pub trait FirehoseMapper<C: Blockchain>: Send + Sync {
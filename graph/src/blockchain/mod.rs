@@ -22,12 +22,13 @@ use crate::{
 use crate::{
     components::{
         store::{BlockNumber, ChainStore},
-        subgraph::DataSourceTemplateInfo,
+        subgraph::{DataSourceTemplateInfo, HostCalls},
     },
     prelude::{thiserror::Error, LinkResolver},
 };
 use anyhow::{anyhow, Context, Error};
 use async_trait::async_trait;
+use futures03::stream::BoxStream;
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use slog::Logger;
@@ -37,6 +38,7 @@ use std::{
     collections::{BTreeMap, HashMap},
     convert::TryFrom,
     fmt::{self, Debug},
+    ops::Range,
     str::FromStr,
     sync::Arc,
 };
@@ -86,7 +88,7 @@ pub trait Blockchain: Debug + Sized + Send + Sync + Unpin + 'static {
     type TriggersAdapter: TriggersAdapter<Self>;
 
     /// Trigger data as parsed from the triggers adapter.
-    type TriggerData: TriggerData + Ord;
+    type TriggerData: TriggerData + Ord + Send + Sync;
 
     /// Decoded trigger ready to be processed by the mapping.
     /// New implementations should have this be the same as `TriggerData`.
@@ -173,16 +175,26 @@ pub trait IngestorAdapter<C: Blockchain> {
     /// store it in the database
     async fn ingest_block(&self, hash: &BlockHash) -> Result<Option<BlockHash>, IngestorError>;
 
+    /// Retrieve the blocks with numbers in `numbers` from the chain, a few
+    /// at a time, and store them in the database without moving the chain
+    /// head. The block ingestor uses this to catch up when the chain head
+    /// in the database is far behind, since that is much faster than
+    /// walking back from the latest block one parent at a time
+    async fn prefetch_blocks(&self, _numbers: Range<BlockNumber>) -> Result<(), IngestorError> {
+        Ok(())
+    }
+
     /// Return the chain head that is stored locally, and therefore visible
     /// to the block streams of subgraphs
     fn chain_head_ptr(&self) -> Result<Option<BlockPtr>, Error>;
 
-    /// Remove old blocks from the database cache and return a pair
-    /// containing the number of the oldest block retained and the number of
-    /// blocks deleted if anything was removed. This is generally only used
-    /// in small test installations, and can remain a noop without
-    /// influencing correctness.
-    fn cleanup_cached_blocks(&self) -> Result<Option<(i32, usize)>, Error> {
+    /// Subscribe to new chain heads if the chain client can push them to
+    /// us. The block ingestor ingests every head that the stream yields
+    /// and polls for the latest block with `latest_block` when this returns
+    /// `None` or the stream stops yielding heads
+    async fn new_heads(
+        &self,
+    ) -> Result<Option<BoxStream<'static, Result<BlockPtr, Error>>>, Error> {
         Ok(None)
     }
 }
@@ -223,6 +235,14 @@ pub trait DataSource<C: Blockchain>:
         logger: &Logger,
     ) -> Result<Option<TriggerWithHandler<C>>, Error>;
 
+    /// The handler that this data source declares for `trigger`, found
+    /// without decoding the trigger. If `match_and_decode` does not match
+    /// a trigger that the data source declares a handler for, the trigger
+    /// was skipped, e.g., because its data could not be decoded
+    fn declared_handler(&self, _trigger: &C::TriggerData) -> Option<String> {
+        None
+    }
+
     fn is_duplicate_of(&self, other: &Self) -> bool;
 
     fn as_stored_dynamic_data_source(&self) -> StoredDynamicDataSource;
@@ -268,6 +288,12 @@ pub trait TriggerData {
     /// If there is an error when processing this trigger, this will called to add relevant context.
     /// For example an useful return is: `"block #<N> (<hash>), transaction <tx_hash>".
     fn error_context(&self) -> String;
+
+    /// The hash of the transaction that caused this trigger, if the
+    /// trigger belongs to a transaction
+    fn transaction_hash(&self) -> Option<&[u8]> {
+        None
+    }
 }
 
 pub trait MappingTrigger: Send + Sync {
@@ -279,6 +305,8 @@ pub trait MappingTrigger: Send + Sync {
 pub struct HostFnCtx<'a> {
     pub logger: Logger,
     pub block_ptr: BlockPtr,
+    /// Set when host calls are recorded or replayed
+    pub host_calls: Option<Arc<HostCalls>>,
     pub heap: &'a mut dyn AscHeap,
 }
 
@@ -376,7 +404,21 @@ impl BlockchainMap {
     pub fn get<C: Blockchain>(&self, network: String) -> Result<Arc<C>, Error> {
         self.0
             .get(&(C::KIND, network.clone()))
-            .with_context(|| format!("no network {} found on chain {}", network, C::KIND))?
+            .with_context(|| {
+                let mut networks: Vec<_> = self
+                    .0
+                    .keys()
+                    .filter(|(kind, _)| *kind == C::KIND)
+                    .map(|(_, network)| network.as_str())
+                    .collect();
+                networks.sort();
+                format!(
+                    "no network {} found on chain {}, this node supports [{}]",
+                    network,
+                    C::KIND,
+                    networks.join(", ")
+                )
+            })?
             .cheap_clone()
             .downcast()
             .map_err(|_| anyhow!("unable to downcast, wrong type for blockchain {}", C::KIND))
@@ -6,6 +6,8 @@ use std::pin::Pin;
 use std::sync::Arc;
 use std::task::{Context, Poll};
 use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio_retry::strategy::jitter;
 
 use super::block_stream::{
     BlockStream, BlockStreamEvent, BlockStreamMetrics, BlockWithTriggers, ChainHeadUpdateStream,
@@ -17,9 +19,16 @@ use crate::components::store::BlockNumber;
 use crate::components::store::WritableStore;
 use crate::data::subgraph::UnifiedMappingApiVersion;
 use crate::prelude::*;
+use crate::util::backoff::ExponentialBackoff;
 #[cfg(debug_assertions)]
 use fail::fail_point;
 
+lazy_static! {
+    /// Whether to scan the next range of blocks while the current one is
+    /// being processed when a subgraph is catching up
+    static ref PREFETCH_BLOCKS: bool = std::env::var("GRAPH_DISABLE_BLOCK_PREFETCH").is_err();
+}
+
 enum BlockStreamState<C>
 where
     C: Blockchain,
@@ -127,6 +136,9 @@ pub struct PollingBlockStream<C: Blockchain> {
     consecutive_err_count: u32,
     chain_head_update_stream: ChainHeadUpdateStream,
     ctx: PollingBlockStreamContext<C>,
+    /// The scan for the blocks after the given block pointer that runs
+    /// while the blocks up to that pointer are being yielded
+    prefetch: Option<(BlockPtr, JoinHandle<Result<Option<NextBlocks<C>>, Error>>)>,
 }
 
 // This is the same as `ReconciliationStep` but without retries.
@@ -167,6 +179,7 @@ where
             state: BlockStreamState::BeginReconciliation,
             consecutive_err_count: 0,
             chain_head_update_stream,
+            prefetch: None,
             ctx: PollingBlockStreamContext {
                 subgraph_store,
                 chain_store,
@@ -223,8 +236,6 @@ where
     /// Determine the next reconciliation step. Does not modify Store or ChainStore.
     async fn get_next_step(&self) -> Result<ReconciliationStep<C>, Error> {
         let ctx = self.clone();
-        let start_blocks = self.start_blocks.clone();
-        let max_block_range_size = self.max_block_range_size;
 
         // Get pointers from database for comparison
         let head_ptr_opt = ctx.chain_store.chain_head_ptr()?;
@@ -338,57 +349,10 @@ where
             // then we start with the genesis block
             let from = subgraph_ptr.map_or(0, |ptr| ptr.number + 1);
 
-            // Get the next subsequent data source start block to ensure the block
-            // range is aligned with data source. This is not necessary for
-            // correctness, but it avoids an ineffecient situation such as the range
-            // being 0..100 and the start block for a data source being 99, then
-            // `calls_in_block_range` would request unecessary traces for the blocks
-            // 0 to 98 because the start block is within the range.
-            let next_start_block: BlockNumber = start_blocks
-                .into_iter()
-                .filter(|block_num| block_num > &from)
-                .min()
-                .unwrap_or(BLOCK_NUMBER_MAX);
-
-            // End either just before the the next data source start_block or just
-            // prior to the reorg threshold. It isn't safe to go farther than the
-            // reorg threshold due to race conditions.
-            let to_limit = cmp::min(head_ptr.number - reorg_threshold, next_start_block - 1);
-
-            // Calculate the range size according to the target number of triggers,
-            // respecting the global maximum and also not increasing too
-            // drastically from the previous block range size.
-            //
-            // An example of the block range dynamics:
-            // - Start with a block range of 1, target of 1000.
-            // - Scan 1 block:
-            //   0 triggers found, max_range_size = 10, range_size = 10
-            // - Scan 10 blocks:
-            //   2 triggers found, 0.2 per block, range_size = 1000 / 0.2 = 5000
-            // - Scan 5000 blocks:
-            //   10000 triggers found, 2 per block, range_size = 1000 / 2 = 500
-            // - Scan 500 blocks:
-            //   1000 triggers found, 2 per block, range_size = 1000 / 2 = 500
-            let range_size_upper_limit =
-                max_block_range_size.min(ctx.previous_block_range_size * 10);
-            let range_size = if ctx.previous_triggers_per_block == 0.0 {
-                range_size_upper_limit
-            } else {
-                (self.target_triggers_per_block_range as f64 / ctx.previous_triggers_per_block)
-                    .max(1.0)
-                    .min(range_size_upper_limit as f64) as BlockNumber
-            };
-            let to = cmp::min(from + range_size - 1, to_limit);
-
             let section = ctx.metrics.stopwatch.start_section("scan_blocks");
-            info!(
-                ctx.logger,
-                "Scanning blocks [{}, {}]", from, to;
-                "range_size" => range_size
-            );
-
-            let blocks = self.adapter.scan_triggers(from, to, &self.filter).await?;
-
+            let (blocks, range_size) = self
+                .scan_descendants(from, &head_ptr, reorg_threshold)
+                .await?;
             section.end();
             Ok(ReconciliationStep::ProcessDescendantBlocks(
                 blocks, range_size,
@@ -425,14 +389,14 @@ where
             }
 
             // Precondition: subgraph_ptr.number < head_ptr.number
-            // Walk back to one block short of subgraph_ptr.number
-            let offset = head_ptr.number - subgraph_ptr.number - 1;
-
-            // In principle this block should be in the store, but we have seen this error for deep
-            // reorgs in ropsten.
-            let head_ancestor_opt = self.adapter.ancestor_block(head_ptr, offset)?;
-
-            match head_ancestor_opt {
+            // Walk back to subgraph_ptr.number. That only needs the hashes of
+            // the blocks in between, so we avoid loading entire blocks for
+            // what is usually just a check that there was no reorg
+            let offset = head_ptr.number - subgraph_ptr.number;
+
+            // In principle these blocks should be in the store, but we have seen this error for
+            // deep reorgs in ropsten.
+            match self.adapter.ancestor_ptr(head_ptr.clone(), offset)? {
                 None => {
                     // Block is missing in the block store.
                     // This generally won't happen often, but can happen if the head ptr has
@@ -441,31 +405,152 @@ where
                     // It's easiest to start over at this point.
                     Ok(ReconciliationStep::Retry)
                 }
-                Some(head_ancestor) => {
-                    // We stopped one block short, so we'll compare the parent hash to the
-                    // subgraph ptr.
-                    if head_ancestor.parent_hash().as_ref() == Some(&subgraph_ptr.hash) {
-                        // The subgraph ptr is an ancestor of the head block.
-                        // We cannot use an RPC call here to find the first interesting block
-                        // due to the race conditions previously mentioned,
-                        // so instead we will advance the subgraph ptr by one block.
-                        // Note that head_ancestor is a child of subgraph_ptr.
-                        let block = self
-                            .adapter
-                            .triggers_in_block(&self.logger, head_ancestor, &self.filter)
-                            .await?;
-                        Ok(ReconciliationStep::ProcessDescendantBlocks(vec![block], 1))
-                    } else {
-                        // The subgraph ptr is not on the main chain.
-                        // We will need to step back (possibly repeatedly) one block at a time
-                        // until we are back on the main chain.
-                        Ok(ReconciliationStep::Revert(subgraph_ptr))
+                Some(ancestor) if ancestor != subgraph_ptr => {
+                    // The subgraph ptr is not on the main chain.
+                    // We will need to step back (possibly repeatedly) one block at a time
+                    // until we are back on the main chain.
+                    Ok(ReconciliationStep::Revert(subgraph_ptr))
+                }
+                Some(_) => {
+                    // The subgraph ptr is an ancestor of the head block.
+                    // We cannot use an RPC call here to find the first interesting block
+                    // due to the race conditions previously mentioned,
+                    // so instead we will advance the subgraph ptr by one block, which
+                    // is the only block we need to load in full.
+                    match self.adapter.ancestor_block(head_ptr, offset - 1)? {
+                        None => Ok(ReconciliationStep::Retry),
+                        Some(child) => {
+                            let block = self
+                                .adapter
+                                .triggers_in_block(&self.logger, child, &self.filter)
+                                .await?;
+                            Ok(ReconciliationStep::ProcessDescendantBlocks(vec![block], 1))
+                        }
                     }
                 }
             }
         }
     }
 
+    /// Scan the blocks starting at `from` for triggers. The end of the
+    /// range is chosen based on the number of triggers found in the
+    /// previous range, and never goes past the next data source start block
+    /// or the reorg threshold. Returns the blocks with triggers and the
+    /// size of the range that was scanned. Callers are responsible for
+    /// timing the scan with the stopwatch since prefetching runs
+    /// concurrently with block processing
+    async fn scan_descendants(
+        &self,
+        from: BlockNumber,
+        head_ptr: &BlockPtr,
+        reorg_threshold: BlockNumber,
+    ) -> Result<(Vec<BlockWithTriggers<C>>, BlockNumber), Error> {
+        let max_block_range_size = self.max_block_range_size;
+
+        // Get the next subsequent data source start block to ensure the block
+        // range is aligned with data source. This is not necessary for
+        // correctness, but it avoids an ineffecient situation such as the range
+        // being 0..100 and the start block for a data source being 99, then
+        // `calls_in_block_range` would request unecessary traces for the blocks
+        // 0 to 98 because the start block is within the range.
+        let next_start_block: BlockNumber = self
+            .start_blocks
+            .iter()
+            .cloned()
+            .filter(|block_num| block_num > &from)
+            .min()
+            .unwrap_or(BLOCK_NUMBER_MAX);
+
+        // End either just before the the next data source start_block or just
+        // prior to the reorg threshold. It isn't safe to go farther than the
+        // reorg threshold due to race conditions.
+        let to_limit = cmp::min(head_ptr.number - reorg_threshold, next_start_block - 1);
+
+        // Calculate the range size according to the target number of triggers,
+        // respecting the global maximum and also not increasing too
+        // drastically from the previous block range size.
+        //
+        // An example of the block range dynamics:
+        // - Start with a block range of 1, target of 1000.
+        // - Scan 1 block:
+        //   0 triggers found, max_range_size = 10, range_size = 10
+        // - Scan 10 blocks:
+        //   2 triggers found, 0.2 per block, range_size = 1000 / 0.2 = 5000
+        // - Scan 5000 blocks:
+        //   10000 triggers found, 2 per block, range_size = 1000 / 2 = 500
+        // - Scan 500 blocks:
+        //   1000 triggers found, 2 per block, range_size = 1000 / 2 = 500
+        let range_size_upper_limit = max_block_range_size.min(self.previous_block_range_size * 10);
+        let range_size = if self.previous_triggers_per_block == 0.0 {
+            range_size_upper_limit
+        } else {
+            (self.target_triggers_per_block_range as f64 / self.previous_triggers_per_block)
+                .max(1.0)
+                .min(range_size_upper_limit as f64) as BlockNumber
+        };
+        let to = cmp::min(from + range_size - 1, to_limit);
+
+        info!(
+            self.logger,
+            "Scanning blocks [{}, {}]", from, to;
+            "range_size" => range_size
+        );
+
+        let blocks = self.adapter.scan_triggers(from, to, &self.filter).await?;
+        Ok((blocks, range_size))
+    }
+
+    /// Fetch the range of blocks that follows `ptr` while the blocks up to
+    /// `ptr` are still being processed. That is only possible while the
+    /// subgraph is more than the reorg threshold behind the chain head;
+    /// closer to the head, the blocks could still change, and `None`
+    /// indicates that regular reconciliation needs to decide the next step
+    async fn prefetch_blocks(&self, ptr: BlockPtr) -> Result<Option<NextBlocks<C>>, Error> {
+        let head_ptr = match self.chain_store.chain_head_ptr()? {
+            Some(head_ptr) => head_ptr,
+            None => return Ok(None),
+        };
+        let reorg_threshold = self.reorg_threshold.min(head_ptr.number);
+        if head_ptr.number - ptr.number <= reorg_threshold {
+            return Ok(None);
+        }
+
+        let (blocks, range_size) = self
+            .scan_descendants(ptr.number + 1, &head_ptr, reorg_threshold)
+            .await?;
+        Ok(Some(NextBlocks::Blocks(
+            blocks.into_iter().collect(),
+            range_size,
+        )))
+    }
+
+    /// Use the blocks that `prefetch` fetched after `ptr` if the subgraph
+    /// has in fact advanced to `ptr`, and fall back to regular
+    /// reconciliation otherwise
+    async fn next_blocks_prefetched(
+        &self,
+        ptr: BlockPtr,
+        prefetch: JoinHandle<Result<Option<NextBlocks<C>>, Error>>,
+    ) -> Result<NextBlocks<C>, Error> {
+        match prefetch.await {
+            Ok(Ok(Some(blocks))) => {
+                if self.subgraph_store.block_ptr()? == Some(ptr) {
+                    return Ok(blocks);
+                }
+            }
+            Ok(Ok(None)) => {}
+            Ok(Err(e)) => {
+                debug!(self.logger, "Prefetching blocks failed, scanning again";
+                                    "error" => e.to_string());
+            }
+            Err(e) => {
+                debug!(self.logger, "Prefetching blocks was aborted, scanning again";
+                                    "error" => e.to_string());
+            }
+        }
+        self.next_blocks().await
+    }
+
     /// Set subgraph deployment entity synced flag if and only if the subgraph block pointer is
     /// caught up to the head block pointer.
     fn update_subgraph_synced_status(&self) -> Result<(), StoreError> {
@@ -488,6 +573,14 @@ where
 
 impl<C: Blockchain> BlockStream<C> for PollingBlockStream<C> {}
 
+impl<C: Blockchain> Drop for PollingBlockStream<C> {
+    fn drop(&mut self) {
+        if let Some((_, handle)) = self.prefetch.take() {
+            handle.abort();
+        }
+    }
+}
+
 impl<C: Blockchain> Stream for PollingBlockStream<C> {
     type Item = Result<BlockStreamEvent<C>, Error>;
 
@@ -524,6 +617,21 @@ impl<C: Blockchain> Stream for PollingBlockStream<C> {
                                 debug!(self.ctx.logger, "Processing {} triggers", total_triggers);
                             }
 
+                            // Start scanning the next range of blocks while these
+                            // are being processed
+                            if *PREFETCH_BLOCKS {
+                                if let Some(last) = next_blocks.back() {
+                                    let ptr = last.ptr();
+                                    let ctx = self.ctx.clone();
+                                    let prefetch = ptr.clone();
+                                    let handle =
+                                        crate::spawn(
+                                            async move { ctx.prefetch_blocks(prefetch).await },
+                                        );
+                                    self.prefetch = Some((ptr, handle));
+                                }
+                            }
+
                             // Switch to yielding state until next_blocks is depleted
                             self.state = BlockStreamState::YieldingBlocks(Box::new(next_blocks));
 
@@ -557,11 +665,21 @@ impl<C: Blockchain> Stream for PollingBlockStream<C> {
                             self.ctx.previous_block_range_size = 1;
                             self.consecutive_err_count += 1;
 
-                            // Pause before trying again
-                            let secs = (5 * self.consecutive_err_count).max(120) as u64;
+                            // Pause before trying again, backing off exponentially from 5s
+                            // up to 2 minutes. The jitter keeps subgraphs that failed
+                            // because of the same provider outage from retrying in lockstep
+                            let mut backoff = ExponentialBackoff::new(
+                                Duration::from_secs(5),
+                                Duration::from_secs(120),
+                            );
+                            backoff.attempt = (self.consecutive_err_count - 1) as u64;
+                            let delay = backoff.delay() / 2 + jitter(backoff.delay() / 2);
+                            debug!(self.ctx.logger, "Retrying block stream after error";
+                                                    "attempt" => self.consecutive_err_count,
+                                                    "delay_ms" => delay.as_millis() as u64);
 
                             self.state = BlockStreamState::RetryAfterDelay(Box::pin(
-                                tokio::time::sleep(Duration::from_secs(secs)).map(Ok),
+                                tokio::time::sleep(delay).map(Ok),
                             ));
 
                             break Poll::Ready(Some(Err(e)));
@@ -580,10 +698,19 @@ impl<C: Blockchain> Stream for PollingBlockStream<C> {
                             ))));
                         }
 
-                        // Done yielding blocks
-                        None => {
-                            self.state = BlockStreamState::BeginReconciliation;
-                        }
+                        // Done yielding blocks; continue with the prefetched
+                        // blocks if there are any
+                        None => match self.prefetch.take() {
+                            Some((ptr, handle)) => {
+                                let ctx = self.ctx.clone();
+                                let fut =
+                                    async move { ctx.next_blocks_prefetched(ptr, handle).await };
+                                self.state = BlockStreamState::Reconciliation(fut.boxed());
+                            }
+                            None => {
+                                self.state = BlockStreamState::BeginReconciliation;
+                            }
+                        },
                     }
                 }
 
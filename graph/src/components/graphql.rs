@@ -1,6 +1,6 @@
 use futures::prelude::*;
 
-use crate::data::query::{CacheStatus, Query, QueryTarget};
+use crate::data::query::{CacheStatus, Query, QueryExecutionError, QueryTarget};
 use crate::data::subscription::{Subscription, SubscriptionError, SubscriptionResult};
 use crate::data::{graphql::effort::LoadManager, query::QueryResults};
 use crate::prelude::DeploymentHash;
@@ -42,6 +42,12 @@ pub trait GraphQlRunner: Send + Sync + 'static {
         target: QueryTarget,
     ) -> Result<SubscriptionResult, SubscriptionError>;
 
+    /// The deployment that queries for `target` go to
+    async fn deployment_for_target(
+        self: Arc<Self>,
+        target: QueryTarget,
+    ) -> Result<DeploymentHash, QueryExecutionError>;
+
     fn load_manager(&self) -> Arc<LoadManager>;
 }
 
@@ -1,7 +1,10 @@
-use crate::data::query::QueryError;
+use crate::data::query::{QueryError, QueryTarget};
+use crate::prelude::DeploymentHash;
 use futures::prelude::*;
 use std::error::Error;
 use std::fmt;
+use std::net::IpAddr;
+use std::time::Duration;
 
 use crate::components::store::StoreError;
 
@@ -69,3 +72,34 @@ pub trait GraphQLServer {
         ws_port: u16,
     ) -> Result<Box<dyn Future<Item = (), Error = ()> + Send>, Self::ServeError>;
 }
+
+/// What a `RequestGate` gets to see of a GraphQL request
+pub struct GatedRequest<'a> {
+    /// The subgraph or deployment the request wants to query
+    pub target: &'a QueryTarget,
+    /// The deployment that `target` resolved to
+    pub deployment: &'a DeploymentHash,
+    /// The address of the client that sent the request. Comes from the
+    /// `X-Forwarded-For` or `Forwarded` header if
+    /// `GRAPH_GRAPHQL_TRUST_FORWARDED_FOR` is set
+    pub client: Option<IpAddr>,
+    /// The token from an `Authorization: Bearer <token>` header
+    pub token: Option<&'a str>,
+}
+
+/// Why a `RequestGate` turned a request away
+#[derive(Clone, Debug, PartialEq)]
+pub enum GateRejection {
+    /// The request does not have valid credentials
+    Unauthorized(String),
+    /// The client should wait at least this long before sending the
+    /// request again
+    RateLimited(Duration),
+}
+
+/// A check that every GraphQL query over HTTP has to pass before it is
+/// run, for example to limit how many queries a client can send or to
+/// validate API keys. Operators can add their own gates to the server
+pub trait RequestGate: Send + Sync + 'static {
+    fn check(&self, request: &GatedRequest) -> Result<(), GateRejection>;
+}
@@ -39,6 +39,33 @@ lazy_static! {
             )))
             .map(Duration::from_millis)
             .unwrap_or_else(|| Duration::from_millis(1000));
+
+    /// Like `SUBSCRIPTION_THROTTLE_INTERVAL`, but for subgraphs that are
+    /// synced. The default of 0 delivers every event right away
+    pub static ref SUBSCRIPTION_BATCH_INTERVAL: Duration =
+        env::var("GRAPH_SUBSCRIPTION_BATCH_INTERVAL")
+            .ok()
+            .map(|s| u64::from_str(&s).unwrap_or_else(|_| panic!(
+                "failed to parse env var GRAPH_SUBSCRIPTION_BATCH_INTERVAL"
+            )))
+            .map(Duration::from_millis)
+            .unwrap_or_else(|| Duration::from_millis(0));
+
+    /// How many blocks behind the chain head a reorg can reach. Blocks
+    /// that are further back are considered final. Defaults to 50
+    pub static ref REORG_THRESHOLD: BlockNumber = env::var("ETHEREUM_REORG_THRESHOLD")
+        .ok()
+        .map(|s| BlockNumber::from_str(&s)
+            .unwrap_or_else(|_| panic!("failed to parse env var ETHEREUM_REORG_THRESHOLD")))
+        .unwrap_or(50);
+
+    /// Reversible order by. Change our `order by` clauses so that `asc`
+    /// and `desc` ordering produce reverse orders. Setting this
+    /// turns the new, correct behavior off
+    pub static ref REVERSIBLE_ORDER_BY_OFF: bool = env::var("REVERSIBLE_ORDER_BY_OFF")
+        .ok()
+        .map(|s| s == "1")
+        .unwrap_or(false);
 }
 
 /// The type name of an entity. This is the string that is used in the
@@ -167,6 +194,21 @@ pub enum EntityFilter {
     NotStartsWith(Attribute, Value),
     EndsWith(Attribute, Value),
     NotEndsWith(Attribute, Value),
+    Child(Child),
+}
+
+/// A filter on the entities a field of the parent entity refers to. The
+/// parent matches if at least one of these entities matches `filter`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Child {
+    /// The attribute of the parent that refers to the child or, if
+    /// `derived` is set, the attribute of the child that refers to the
+    /// parent
+    pub attr: Attribute,
+    pub entity_type: EntityType,
+    pub filter: Box<EntityFilter>,
+    /// Whether the parent's field is derived with `@derivedFrom`
+    pub derived: bool,
 }
 
 // Define some convenience methods
@@ -209,6 +251,68 @@ impl EntityFilter {
             None => self,
         }
     }
+
+    /// A filter for the entities that come after the entity `id` in a
+    /// collection sorted by `order`, where `value` is the value of the
+    /// sort attribute of that entity. Since the sort attribute is always
+    /// followed by the `id`, the position of each entity is unique. The
+    /// filter places `null` and breaks ties the same way that the store
+    /// sorts entities
+    pub fn after(order: &EntityOrder, value: Value, id: String) -> Self {
+        use EntityFilter as f;
+
+        let (attr, descending) = match order {
+            EntityOrder::Ascending(attr, _) => (attr.as_str(), false),
+            EntityOrder::Descending(attr, _) => (attr.as_str(), true),
+            EntityOrder::Default | EntityOrder::Unordered => ("id", false),
+        };
+        let after_id = |id: Value, descending: bool| {
+            if descending {
+                f::LessThan("id".to_owned(), id)
+            } else {
+                f::GreaterThan("id".to_owned(), id)
+            }
+        };
+        if attr == "id" {
+            return after_id(value, descending);
+        }
+
+        // The old order puts `null` last and breaks ties by ascending `id`.
+        // Otherwise, ties are broken in the direction of the sort, and, as
+        // is the default in Postgres, `null` is last when sorting in
+        // ascending and first when sorting in descending order
+        let (nulls_last, id_descending) = if *REVERSIBLE_ORDER_BY_OFF {
+            (true, false)
+        } else {
+            (!descending, descending)
+        };
+        let attr = attr.to_owned();
+        let same_value = |value: Value| {
+            f::And(vec![
+                f::Equal(attr.clone(), value),
+                after_id(Value::String(id.clone()), id_descending),
+            ])
+        };
+        match value {
+            Value::Null if nulls_last => same_value(Value::Null),
+            Value::Null => f::Or(vec![
+                f::Not(attr.clone(), Value::Null),
+                same_value(Value::Null),
+            ]),
+            value => {
+                let mut filters = vec![if descending {
+                    f::LessThan(attr.clone(), value.clone())
+                } else {
+                    f::GreaterThan(attr.clone(), value.clone())
+                }];
+                if nulls_last {
+                    filters.push(f::Equal(attr.clone(), Value::Null));
+                }
+                filters.push(same_value(value));
+                f::Or(filters)
+            }
+        }
+    }
 }
 
 /// The order in which entities should be restored from a store.
@@ -478,6 +582,23 @@ impl EntityQuery {
     }
 }
 
+/// A query for the number of entities of one type and for the sum,
+/// average, minimum and maximum of some of their numeric attributes. If
+/// `group_by` is set, the entities are grouped by the value of that
+/// attribute, and `range` limits the groups that are returned. Without
+/// `group_by`, there is exactly one group and `range` is ignored
+#[derive(Clone, Debug)]
+pub struct AggregateQuery {
+    pub subgraph_id: DeploymentHash,
+    pub block: BlockNumber,
+    pub entity_type: EntityType,
+    pub filter: Option<EntityFilter>,
+    pub group_by: Option<Attribute>,
+    /// The numeric attributes to aggregate
+    pub attributes: Vec<Attribute>,
+    pub range: EntityRange,
+}
+
 /// Operation types that lead to entity changes.
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Hash)]
 #[serde(rename_all = "lowercase")]
@@ -661,6 +782,11 @@ where
     /// on the returned stream as a single `StoreEvent`; the events are
     /// combined by using the maximum of all sources and the concatenation
     /// of the changes of the `StoreEvents` received during the interval.
+    ///
+    /// Once the deployment is synced, events are batched in the same way
+    /// over `SUBSCRIPTION_BATCH_INTERVAL` so that subgraphs that write a
+    /// lot do not flood subscribers with updates. When that is zero,
+    /// events are passed through as they arrive.
     pub async fn throttle_while_syncing(
         self,
         logger: &Logger,
@@ -671,6 +797,14 @@ where
         // subgraph becomes synced any existing subscriptions will continue to be throttled since
         // this is not re-checked.
         let synced = store.is_deployment_synced().await.unwrap_or(false);
+        let interval = if synced {
+            *SUBSCRIPTION_BATCH_INTERVAL
+        } else {
+            interval
+        };
+        if interval == Duration::from_millis(0) {
+            return StoreEventStream::new(Box::new(self.source));
+        }
 
         let mut pending_event: Option<StoreEvent> = None;
         let mut source = self.source.fuse();
@@ -686,10 +820,6 @@ where
                 return Err(());
             }
 
-            if synced {
-                return source.poll();
-            }
-
             // Check if interval has passed since the last time we sent something.
             // If it has, start a new delay timer
             let should_send = match futures::future::Future::poll(&mut delay) {
@@ -758,6 +888,11 @@ pub enum StoreError {
          which has an interface in common with `{0}`, exists with the same ID"
     )]
     ConflictingId(String, String, String), // (entity, id, conflicting_entity)
+    #[error(
+        "tried to change or remove entity of type `{0}` with ID \"{1}\", \
+         but the type is immutable"
+    )]
+    ImmutableEntityChanged(String, String), // (entity, id)
     #[error("unknown field '{0}'")]
     UnknownField(String),
     #[error("unknown table '{0}'")]
@@ -801,6 +936,27 @@ macro_rules! constraint_violation {
     }}
 }
 
+impl StoreError {
+    /// Whether the operation that failed with this error might succeed
+    /// when it is retried, because the error was caused by a problem with
+    /// the database connection rather than by the operation itself
+    pub fn is_transient(&self) -> bool {
+        use diesel::result::{DatabaseErrorKind, Error as DieselError};
+
+        match self {
+            StoreError::DatabaseUnavailable => true,
+            StoreError::Unknown(e) => matches!(
+                e.downcast_ref::<DieselError>(),
+                Some(DieselError::DatabaseError(
+                    DatabaseErrorKind::UnableToSendCommand,
+                    _
+                ))
+            ),
+            _ => false,
+        }
+    }
+}
+
 impl From<::diesel::result::Error> for StoreError {
     fn from(e: ::diesel::result::Error) -> Self {
         StoreError::Unknown(e.into())
@@ -953,6 +1109,15 @@ pub trait SubgraphStore: Send + Sync + 'static {
 
     fn assignments(&self, node: &NodeId) -> Result<Vec<DeploymentLocator>, StoreError>;
 
+    /// Revert the deployment with `id` to `block_ptr_to`, removing all
+    /// changes made by later blocks. The deployment must not be indexing
+    /// while it is being rewound
+    fn rewind(&self, id: DeploymentHash, block_ptr_to: BlockPtr) -> Result<(), StoreError>;
+
+    /// Return `true` if a graph-node process holds the lock for indexing
+    /// the deployment with `id`, i.e., if it is still indexing it
+    fn is_indexing(&self, id: &DeploymentHash) -> Result<bool, StoreError>;
+
     /// Return `true` if a subgraph `name` exists, regardless of whether the
     /// subgraph has any deployments attached to it
     fn subgraph_exists(&self, name: &SubgraphName) -> Result<bool, StoreError>;
@@ -960,6 +1125,16 @@ pub trait SubgraphStore: Send + Sync + 'static {
     /// Return the GraphQL schema supplied by the user
     fn input_schema(&self, subgraph_id: &DeploymentHash) -> Result<Arc<Schema>, StoreError>;
 
+    /// Return the manifest of the deployment as it was deployed. Returns
+    /// `None` for deployments that were created before the store kept
+    /// manifests
+    fn raw_manifest(&self, subgraph_id: &DeploymentHash) -> Result<Option<String>, StoreError>;
+
+    /// Return the ABIs that the manifest of the deployment references as a
+    /// JSON list of objects with the name, file and contents of each ABI. Returns
+    /// `None` if the ABIs were not stored when the deployment was created
+    fn abis(&self, subgraph_id: &DeploymentHash) -> Result<Option<String>, StoreError>;
+
     /// Return the GraphQL schema that was derived from the user's schema by
     /// adding a root query type etc. to it
     fn api_schema(&self, subgraph_id: &DeploymentHash) -> Result<Arc<ApiSchema>, StoreError>;
@@ -992,6 +1167,9 @@ pub trait SubgraphStore: Send + Sync + 'static {
 
     /// Find the deployment locators for the subgraph with the given hash
     fn locators(&self, hash: &str) -> Result<Vec<DeploymentLocator>, StoreError>;
+
+    /// Return the name of the network that `deployment` indexes
+    fn network_name(&self, deployment: &DeploymentLocator) -> Result<String, StoreError>;
 }
 
 /// A view of the store for indexing. All indexing-related operations need
@@ -1059,6 +1237,10 @@ pub trait WritableStore: Send + Sync + 'static {
     /// pending version so far
     fn deployment_synced(&self) -> Result<(), StoreError>;
 
+    /// Record how many blocks per second the deployment processed recently
+    /// so that the indexing status can report its progress
+    fn update_sync_rate(&self, blocks_per_second: f64) -> Result<(), StoreError>;
+
     /// Return true if the deployment with the given id is fully synced,
     /// and return false otherwise. Errors from the store are passed back up
     async fn is_deployment_synced(&self) -> Result<bool, StoreError>;
@@ -1068,6 +1250,12 @@ pub trait WritableStore: Send + Sync + 'static {
     /// Load the dynamic data sources for the given deployment
     async fn load_dynamic_data_sources(&self) -> Result<Vec<StoredDynamicDataSource>, StoreError>;
 
+    /// Wait until all changes passed to `transact_block_operations` have
+    /// been written to the database. Stores may queue changes and write
+    /// them in the background; an error that happened while writing them
+    /// is returned here if it has not been reported yet
+    fn flush(&self) -> Result<(), StoreError>;
+
     /// Report the name of the shard in which the subgraph is stored. This
     /// should only be used for reporting and monitoring
     fn shard(&self) -> &str;
@@ -1139,6 +1327,14 @@ impl SubgraphStore for MockStore {
         unimplemented!()
     }
 
+    fn rewind(&self, _: DeploymentHash, _: BlockPtr) -> Result<(), StoreError> {
+        unimplemented!()
+    }
+
+    fn is_indexing(&self, _: &DeploymentHash) -> Result<bool, StoreError> {
+        unimplemented!()
+    }
+
     fn assignments(&self, _: &NodeId) -> Result<Vec<DeploymentLocator>, StoreError> {
         unimplemented!()
     }
@@ -1151,6 +1347,14 @@ impl SubgraphStore for MockStore {
         unimplemented!()
     }
 
+    fn raw_manifest(&self, _: &DeploymentHash) -> Result<Option<String>, StoreError> {
+        unimplemented!()
+    }
+
+    fn abis(&self, _: &DeploymentHash) -> Result<Option<String>, StoreError> {
+        unimplemented!()
+    }
+
     fn api_schema(&self, _: &DeploymentHash) -> Result<Arc<ApiSchema>, StoreError> {
         unimplemented!()
     }
@@ -1182,6 +1386,10 @@ impl SubgraphStore for MockStore {
     fn locators(&self, _: &str) -> Result<Vec<DeploymentLocator>, StoreError> {
         unimplemented!()
     }
+
+    fn network_name(&self, _: &DeploymentLocator) -> Result<String, StoreError> {
+        unimplemented!()
+    }
 }
 
 // The store trait must be implemented manually because mockall does not support async_trait, nor borrowing from arguments.
@@ -1254,6 +1462,14 @@ impl WritableStore for MockStore {
         unimplemented!()
     }
 
+    fn update_sync_rate(&self, _: f64) -> Result<(), StoreError> {
+        unimplemented!()
+    }
+
+    fn flush(&self) -> Result<(), StoreError> {
+        unimplemented!()
+    }
+
     fn shard(&self) -> &str {
         unimplemented!()
     }
@@ -1304,6 +1520,10 @@ pub trait ChainStore: Send + Sync + 'static {
     /// The head block pointer will be None on initial set up.
     fn chain_head_ptr(&self) -> Result<Option<BlockPtr>, Error>;
 
+    /// Record the outcome of the block ingestor asking the chain's provider
+    /// for its latest block. An `Err` holds the error the provider returned
+    fn set_provider_head(&self, head: Result<BlockNumber, String>) -> Result<(), Error>;
+
     /// Returns the blocks present in the store.
     fn blocks(&self, hashes: &[H256]) -> Result<Vec<serde_json::Value>, Error>;
 
@@ -1318,15 +1538,14 @@ pub trait ChainStore: Send + Sync + 'static {
         offset: BlockNumber,
     ) -> Result<Option<serde_json::Value>, Error>;
 
-    /// Remove old blocks from the cache we maintain in the database and
-    /// return a pair containing the number of the oldest block retained
-    /// and the number of blocks deleted.
-    /// We will never remove blocks that are within `ancestor_count` of
-    /// the chain head.
-    fn cleanup_cached_blocks(
+    /// Like `ancestor_block`, but only return a pointer to the ancestor.
+    /// This only looks at block hashes and never loads the data of the
+    /// blocks in between, which makes it much cheaper than `ancestor_block`
+    fn ancestor_ptr(
         &self,
-        ancestor_count: BlockNumber,
-    ) -> Result<Option<(BlockNumber, usize)>, Error>;
+        block_ptr: BlockPtr,
+        offset: BlockNumber,
+    ) -> Result<Option<BlockPtr>, Error>;
 
     /// Return the hashes of all blocks with the given number
     fn block_hashes_by_block_number(&self, number: BlockNumber) -> Result<Vec<H256>, Error>;
@@ -1372,12 +1591,36 @@ pub trait QueryStore: Send + Sync {
         query: EntityQuery,
     ) -> Result<Vec<BTreeMap<String, r::Value>>, QueryExecutionError>;
 
+    /// Run an aggregation query. Each entry of the result describes one
+    /// group with the keys `group`, `count`, and `sum`, `avg`, `min` and
+    /// `max`, which map each aggregated attribute to its value
+    fn aggregate(
+        &self,
+        query: AggregateQuery,
+    ) -> Result<Vec<BTreeMap<String, r::Value>>, QueryExecutionError>;
+
     async fn is_deployment_synced(&self) -> Result<bool, Error>;
 
     fn block_ptr(&self) -> Result<Option<BlockPtr>, StoreError>;
 
     fn block_number(&self, block_hash: H256) -> Result<Option<BlockNumber>, StoreError>;
 
+    /// The number of the first block on the subgraph's chain whose
+    /// timestamp, in seconds since the epoch, is at or after `timestamp`.
+    /// Returns `None` if the chain store does not have such a block, or
+    /// does not have the blocks needed to tell which block that is
+    fn block_number_by_timestamp(&self, timestamp: u64) -> Result<Option<BlockNumber>, StoreError>;
+
+    /// The timestamp, in seconds since the epoch, of the block with the
+    /// given hash. Returns `None` if the chain store does not have that
+    /// block
+    fn block_timestamp(&self, block_hash: H256) -> Result<Option<u64>, StoreError>;
+
+    /// The number of the latest block on the subgraph's chain that is far
+    /// enough behind the chain head that we consider it final. Returns
+    /// `None` if we do not know the chain head yet
+    fn finalized_block_number(&self) -> Result<Option<BlockNumber>, StoreError>;
+
     fn wait_stats(&self) -> PoolWaitStats;
 
     /// If `block` is `None`, assumes the latest block.
@@ -1391,8 +1634,16 @@ pub trait QueryStore: Send + Sync {
 
     fn network_name(&self) -> &str;
 
+    /// The deployment that this store queries
+    fn deployment_id(&self) -> DeploymentHash;
+
     /// A permit should be acquired before starting query execution.
     async fn query_permit(&self) -> tokio::sync::OwnedSemaphorePermit;
+
+    /// Cancel the SQL query that is running for this store, if any, and
+    /// make all further calls to `find_query_values` fail. This is used
+    /// when nobody is waiting for the result of a GraphQL query anymore
+    fn cancel(&self);
 }
 
 /// A view of the store that can provide information about the indexing status
@@ -1404,6 +1655,10 @@ pub trait StatusStore: Send + Sync + 'static {
 
     fn status(&self, filter: status::Filter) -> Result<Vec<status::Info>, StoreError>;
 
+    /// The state of each chain in the block store and of the block
+    /// ingestor that follows it
+    fn chain_statuses(&self) -> Result<Vec<status::ChainStatus>, StoreError>;
+
     /// Support for the explorer-specific API
     fn version_info(&self, version_id: &str) -> Result<VersionInfo, StoreError>;
 
@@ -1434,6 +1689,32 @@ pub trait StatusStore: Send + Sync + 'static {
         indexer: &Option<Address>,
         block: BlockPtr,
     ) -> Result<Option<[u8; 32]>, StoreError>;
+
+    /// The entities of the deployment `subgraph_id` that are different at
+    /// block `to` from what they were at block `from`, ordered by entity
+    /// type and id. At most `first` entities are returned, starting after
+    /// the entity type and id in `after` if it is given
+    async fn entity_diff(
+        &self,
+        subgraph_id: &DeploymentHash,
+        from: BlockNumber,
+        to: BlockNumber,
+        after: Option<(String, String)>,
+        first: usize,
+    ) -> Result<Vec<status::EntityDiff>, StoreError>;
+
+    /// The latest block of the deployment `subgraph_id` that can not be
+    /// reverted by a reorg anymore, i.e., that is at least the reorg
+    /// threshold behind the chain head. Returns `None` if there is no such
+    /// block yet
+    fn finalized_block(
+        &self,
+        subgraph_id: &DeploymentHash,
+    ) -> Result<Option<BlockNumber>, StoreError>;
+
+    /// The number of entities and the size on disk of all deployments in
+    /// all shards, largest first
+    fn deployment_sizes(&self) -> Result<Vec<status::DeploymentSize>, StoreError>;
 }
 
 /// An entity operation that can be transacted into the store; as opposed to
@@ -1529,6 +1810,11 @@ pub struct EntityCache {
 
     data_sources: Vec<StoredDynamicDataSource>,
 
+    /// The number of lookups through `get` that were answered from
+    /// `current` and that had to go to the store, respectively
+    hits: usize,
+    misses: usize,
+
     /// The store is only used to read entities.
     pub store: Arc<dyn WritableStore>,
 }
@@ -1546,6 +1832,10 @@ pub struct ModificationsAndCache {
     pub modifications: Vec<EntityModification>,
     pub data_sources: Vec<StoredDynamicDataSource>,
     pub entity_lfu_cache: LfuCache<EntityKey, Option<Entity>>,
+    /// Lookups that were answered from the cache
+    pub cache_hits: usize,
+    /// Lookups that had to go to the store
+    pub cache_misses: usize,
 }
 
 impl EntityCache {
@@ -1556,6 +1846,8 @@ impl EntityCache {
             handler_updates: HashMap::new(),
             in_handler: false,
             data_sources: vec![],
+            hits: 0,
+            misses: 0,
             store,
         }
     }
@@ -1570,6 +1862,8 @@ impl EntityCache {
             handler_updates: HashMap::new(),
             in_handler: false,
             data_sources: vec![],
+            hits: 0,
+            misses: 0,
             store,
         }
     }
@@ -1597,6 +1891,12 @@ impl EntityCache {
     }
 
     pub fn get(&mut self, key: &EntityKey) -> Result<Option<Entity>, QueryExecutionError> {
+        if self.current.contains_key(key) {
+            self.hits += 1;
+        } else {
+            self.misses += 1;
+        }
+
         // Get the current entity, apply any updates from `updates`, then from `handler_updates`.
         let mut entity = self.current.get_entity(&*self.store, key)?;
         if let Some(op) = self.updates.get(key).cloned() {
@@ -1656,6 +1956,8 @@ impl EntityCache {
         assert!(!other.in_handler);
 
         self.current.extend(other.current);
+        self.hits += other.hits;
+        self.misses += other.misses;
         for (key, op) in other.updates {
             self.entity_op(key, op);
         }
@@ -1749,6 +2051,8 @@ impl EntityCache {
             modifications: mods,
             data_sources: self.data_sources,
             entity_lfu_cache: self.current,
+            cache_hits: self.hits,
+            cache_misses: self.misses,
         })
     }
 }
@@ -9,7 +9,10 @@ use futures::sync::mpsc;
 use crate::blockchain::TriggerWithHandler;
 use crate::prelude::*;
 use crate::{blockchain::Blockchain, components::subgraph::SharedProofOfIndexing};
-use crate::{components::metrics::HistogramVec, runtime::DeterministicHostError};
+use crate::{
+    components::metrics::{CounterVec, HistogramVec},
+    runtime::DeterministicHostError,
+};
 
 #[derive(Debug)]
 pub enum MappingError {
@@ -67,6 +70,7 @@ pub trait RuntimeHost<C: Blockchain>: Send + Sync + 'static {
 pub struct HostMetrics {
     handler_execution_time: Box<HistogramVec>,
     host_fn_execution_time: Box<HistogramVec>,
+    handler_triggers: Box<CounterVec>,
     pub stopwatch: StopwatchMetrics,
 }
 
@@ -94,9 +98,19 @@ impl HostMetrics {
                 vec![0.025, 0.05, 0.2, 2.0, 8.0, 20.0],
             )
             .expect("failed to create `deployment_host_fn_execution_time` histogram");
+        let handler_triggers = registry
+            .new_deployment_counter_vec(
+                "deployment_handler_triggers",
+                "Counts the triggers for each handler, by whether they were processed, \
+                 failed, or skipped because they could not be decoded",
+                subgraph,
+                vec![String::from("handler"), String::from("status")],
+            )
+            .expect("failed to create `deployment_handler_triggers` counter");
         Self {
             handler_execution_time,
             host_fn_execution_time,
+            handler_triggers,
             stopwatch,
         }
     }
@@ -107,6 +121,23 @@ impl HostMetrics {
             .observe(duration);
     }
 
+    /// Count a trigger that `handler` processed. A trigger failed if the
+    /// handler returned an error, whether deterministic or not
+    pub fn trigger_processed(&self, handler: &str, failed: bool) {
+        let status = if failed { "failed" } else { "processed" };
+        self.handler_triggers
+            .with_label_values(&[handler, status][..])
+            .inc();
+    }
+
+    /// Count a trigger that a data source declares `handler` for, but that
+    /// it did not match, e.g., because the trigger could not be decoded
+    pub fn trigger_skipped(&self, handler: &str) {
+        self.handler_triggers
+            .with_label_values(&[handler, "skipped"][..])
+            .inc();
+    }
+
     pub fn observe_host_fn_execution_time(&self, duration: f64, fn_name: &str) {
         self.host_fn_execution_time
             .with_label_values(&[fn_name][..])
@@ -0,0 +1,290 @@
+//! Record the results of host calls whose outcome depends on something
+//! other than the block being processed, i.e., `ethereum.call`, `ipfs.cat`
+//! and `store.get`, and replay them later. A recording is a file with one
+//! JSON object per line, and can be attached to a bug report so that the
+//! mappings of a subgraph can be run against exactly the inputs they saw
+//! when the bug happened.
+//!
+//! Recording is turned on by setting `GRAPH_RECORD_HOST_CALLS` to a
+//! directory; the calls of each deployment are appended to
+//! `<deployment>.jsonl` in that directory, optionally only for the blocks
+//! in `GRAPH_RECORD_HOST_CALLS_BLOCKS`. Replaying is turned on by setting
+//! `GRAPH_REPLAY_HOST_CALLS` to a directory with such files. Calls that are
+//! not in the recording are executed normally.
+
+use std::collections::{HashMap, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, Weak};
+
+use anyhow::{anyhow, Context, Error};
+use lazy_static::lazy_static;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::prelude::{BlockNumber, DeploymentHash};
+
+lazy_static! {
+    static ref RECORD_DIR: Option<PathBuf> =
+        std::env::var_os("GRAPH_RECORD_HOST_CALLS").map(PathBuf::from);
+
+    /// The inclusive range of blocks to record, in the form `FROM-TO`
+    static ref RECORD_BLOCKS: Option<(BlockNumber, BlockNumber)> =
+        std::env::var("GRAPH_RECORD_HOST_CALLS_BLOCKS").ok().map(|s| {
+            parse_block_range(&s).expect("invalid GRAPH_RECORD_HOST_CALLS_BLOCKS")
+        });
+
+    static ref REPLAY_DIR: Option<PathBuf> =
+        std::env::var_os("GRAPH_REPLAY_HOST_CALLS").map(PathBuf::from);
+
+    /// The recordings that are in use, so that all runtime hosts of a
+    /// deployment share the same one
+    static ref HOST_CALLS: Mutex<HashMap<DeploymentHash, Weak<HostCalls>>> =
+        Mutex::new(HashMap::new());
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HostCallKind {
+    EthereumCall,
+    IpfsCat,
+    StoreGet,
+}
+
+#[derive(Serialize, Deserialize)]
+struct HostCall {
+    block: BlockNumber,
+    kind: HostCallKind,
+    request: Value,
+    response: Value,
+}
+
+type ReplayKey = (BlockNumber, HostCallKind, String);
+
+enum Mode {
+    Record {
+        blocks: Option<(BlockNumber, BlockNumber)>,
+        out: Mutex<BufWriter<File>>,
+    },
+    Replay {
+        /// The recorded responses for each call, in the order in which
+        /// they were recorded
+        calls: Mutex<HashMap<ReplayKey, VecDeque<Value>>>,
+    },
+}
+
+pub struct HostCalls {
+    mode: Mode,
+}
+
+impl HostCalls {
+    /// The recording for `deployment` if recording or replaying host calls
+    /// is turned on
+    pub fn for_deployment(deployment: &DeploymentHash) -> Result<Option<Arc<Self>>, Error> {
+        let dir = match (RECORD_DIR.as_ref(), REPLAY_DIR.as_ref()) {
+            (None, None) => return Ok(None),
+            (Some(_), Some(_)) => {
+                return Err(anyhow!(
+                    "GRAPH_RECORD_HOST_CALLS and GRAPH_REPLAY_HOST_CALLS can not both be set"
+                ))
+            }
+            (Some(dir), None) | (None, Some(dir)) => dir,
+        };
+
+        let mut host_calls = HOST_CALLS.lock().unwrap();
+        if let Some(calls) = host_calls.get(deployment).and_then(Weak::upgrade) {
+            return Ok(Some(calls));
+        }
+
+        let path = dir.join(format!("{}.jsonl", deployment));
+        let mode = if RECORD_DIR.is_some() {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .with_context(|| {
+                    format!("failed to open {} to record host calls", path.display())
+                })?;
+            Mode::Record {
+                blocks: *RECORD_BLOCKS,
+                out: Mutex::new(BufWriter::new(file)),
+            }
+        } else if path.exists() {
+            let file = File::open(&path).with_context(|| {
+                format!("failed to open {} to replay host calls", path.display())
+            })?;
+            Mode::Replay {
+                calls: Mutex::new(load_calls(BufReader::new(file))?),
+            }
+        } else {
+            return Ok(None);
+        };
+
+        let calls = Arc::new(HostCalls { mode });
+        host_calls.insert(deployment.clone(), Arc::downgrade(&calls));
+        Ok(Some(calls))
+    }
+
+    /// Perform a host call by running `call`, unless we are replaying and
+    /// have a recorded response for it. When recording, the response is
+    /// written to the recording. Failed calls are neither recorded nor
+    /// replayed
+    pub fn call<Q, R, E>(
+        &self,
+        block: BlockNumber,
+        kind: HostCallKind,
+        request: &Q,
+        call: impl FnOnce() -> Result<R, E>,
+    ) -> Result<R, E>
+    where
+        Q: Serialize,
+        R: Serialize + DeserializeOwned,
+    {
+        match &self.mode {
+            Mode::Record { blocks, out } => {
+                let response = call()?;
+                let in_range = blocks
+                    .map(|(from, to)| from <= block && block <= to)
+                    .unwrap_or(true);
+                if in_range {
+                    // Not being able to record must not affect indexing
+                    let _ = record(out, block, kind, request, &response);
+                }
+                Ok(response)
+            }
+            Mode::Replay { calls } => {
+                // Go through `Value` so that the key matches what
+                // `load_calls` produced, which has object keys sorted
+                let request = serde_json::to_value(request).and_then(|v| serde_json::to_string(&v));
+                let response = request.ok().and_then(|request| {
+                    let mut calls = calls.lock().unwrap();
+                    let responses = calls.get_mut(&(block, kind, request))?;
+                    // When the mappings make the same call more often than
+                    // it was recorded, for example because the block was
+                    // processed again, keep answering with the last response
+                    if responses.len() > 1 {
+                        responses.pop_front()
+                    } else {
+                        responses.front().cloned()
+                    }
+                });
+                match response.and_then(|response| serde_json::from_value(response).ok()) {
+                    Some(response) => Ok(response),
+                    None => call(),
+                }
+            }
+        }
+    }
+}
+
+/// Like `HostCalls::call`, but only `call` is run if `host_calls` is `None`
+pub fn host_call<Q, R, E>(
+    host_calls: Option<&HostCalls>,
+    block: BlockNumber,
+    kind: HostCallKind,
+    request: &Q,
+    call: impl FnOnce() -> Result<R, E>,
+) -> Result<R, E>
+where
+    Q: Serialize,
+    R: Serialize + DeserializeOwned,
+{
+    match host_calls {
+        Some(host_calls) => host_calls.call(block, kind, request, call),
+        None => call(),
+    }
+}
+
+fn record<Q: Serialize, R: Serialize>(
+    out: &Mutex<BufWriter<File>>,
+    block: BlockNumber,
+    kind: HostCallKind,
+    request: &Q,
+    response: &R,
+) -> Result<(), Error> {
+    let call = HostCall {
+        block,
+        kind,
+        request: serde_json::to_value(request)?,
+        response: serde_json::to_value(response)?,
+    };
+    let mut out = out.lock().unwrap();
+    serde_json::to_writer(&mut *out, &call)?;
+    out.write_all(b"\n")?;
+    // Flush every call so that the recording is complete even if the
+    // process is killed
+    out.flush()?;
+    Ok(())
+}
+
+fn load_calls(reader: impl BufRead) -> Result<HashMap<ReplayKey, VecDeque<Value>>, Error> {
+    let mut calls: HashMap<_, VecDeque<_>> = HashMap::new();
+    for (number, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let call: HostCall = serde_json::from_str(&line)
+            .with_context(|| format!("invalid host call on line {}", number + 1))?;
+        let request = serde_json::to_string(&call.request)?;
+        calls
+            .entry((call.block, call.kind, request))
+            .or_default()
+            .push_back(call.response);
+    }
+    Ok(calls)
+}
+
+fn parse_block_range(s: &str) -> Result<(BlockNumber, BlockNumber), Error> {
+    let mut parts = s.splitn(2, '-');
+    let from = parts.next().unwrap_or("").trim().parse()?;
+    let to = parts
+        .next()
+        .ok_or_else(|| anyhow!("block range must have the form FROM-TO"))?
+        .trim()
+        .parse()?;
+    Ok((from, to))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn block_range() {
+        assert_eq!((10, 20), parse_block_range("10-20").unwrap());
+        assert!(parse_block_range("10").is_err());
+        assert!(parse_block_range("a-20").is_err());
+    }
+
+    #[test]
+    fn replay_recorded_calls() {
+        let recording = r#"
+{"block":1,"kind":"store_get","request":["Token","0x1"],"response":null}
+{"block":1,"kind":"ipfs_cat","request":"Qm1","response":[1,2]}
+{"block":1,"kind":"ipfs_cat","request":"Qm1","response":[3]}
+"#;
+        let calls = load_calls(recording.as_bytes()).unwrap();
+        let host_calls = HostCalls {
+            mode: Mode::Replay {
+                calls: Mutex::new(calls),
+            },
+        };
+        let live = || -> Result<Vec<u8>, ()> { Ok(vec![9]) };
+
+        let cat = |block| host_calls.call(block, HostCallKind::IpfsCat, &"Qm1", live);
+        assert_eq!(Ok(vec![1, 2]), cat(1));
+        assert_eq!(Ok(vec![3]), cat(1));
+        assert_eq!(Ok(vec![3]), cat(1));
+        // Calls that were not recorded are executed
+        assert_eq!(Ok(vec![9]), cat(2));
+
+        let entity: Result<Option<String>, ()> =
+            host_calls.call(1, HostCallKind::StoreGet, &("Token", "0x1"), || {
+                Ok(Some("live".to_string()))
+            });
+        assert_eq!(Ok(None), entity);
+    }
+}
@@ -1,6 +1,12 @@
-use std::sync::Arc;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+use anyhow::Error;
+use slog::Logger;
 
 use crate::components::store::DeploymentLocator;
+use crate::data::subgraph::status::EntityDiff;
+use crate::data::subgraph::DeploymentHash;
 
 /// A `SubgraphInstanceManager` loads and manages subgraph instances.
 ///
@@ -16,3 +22,77 @@ pub trait SubgraphInstanceManager: Send + Sync + 'static {
     );
     fn stop_subgraph(&self, deployment: DeploymentLocator);
 }
+
+/// Runs the handlers of a subgraph for a single transaction without
+/// writing anything, to find out what the transaction would do to the
+/// subgraph's entities.
+#[async_trait::async_trait]
+pub trait TransactionSimulator: Send + Sync + 'static {
+    /// Run the handlers of `deployment` for the triggers that the
+    /// transaction with hash `transaction` produces against the current
+    /// state of the deployment, and return the changes they would make
+    async fn simulate_transaction(
+        &self,
+        logger: &Logger,
+        deployment: &DeploymentHash,
+        transaction: &[u8],
+    ) -> Result<Vec<EntityDiff>, Error>;
+}
+
+/// Per-deployment settings for the entity cache that subgraphs use while
+/// indexing, shared between the instance manager and the admin server so
+/// that the cache of a running subgraph can be flushed.
+#[derive(Default)]
+pub struct EntityCacheControl {
+    /// Cache size in bytes for deployments that should not use the default
+    sizes: HashMap<DeploymentHash, usize>,
+    /// Deployments whose cache should be cleared before the next block
+    flush_requests: Mutex<HashSet<DeploymentHash>>,
+    /// Deployments that are currently being indexed on this node
+    running: Mutex<HashSet<DeploymentHash>>,
+}
+
+impl EntityCacheControl {
+    pub fn new(sizes: HashMap<DeploymentHash, usize>) -> Self {
+        EntityCacheControl {
+            sizes,
+            flush_requests: Mutex::new(HashSet::new()),
+            running: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Note that the subgraph for `deployment` started indexing
+    pub fn start(&self, deployment: DeploymentHash) {
+        self.running.lock().unwrap().insert(deployment);
+    }
+
+    /// Note that the subgraph for `deployment` stopped indexing and forget
+    /// about any flush that was requested for it
+    pub fn stop(&self, deployment: &DeploymentHash) {
+        self.running.lock().unwrap().remove(deployment);
+        self.flush_requests.lock().unwrap().remove(deployment);
+    }
+
+    /// The configured cache size in bytes for `deployment`, if there is one
+    pub fn size(&self, deployment: &DeploymentHash) -> Option<usize> {
+        self.sizes.get(deployment).cloned()
+    }
+
+    /// Ask the subgraph for `deployment` to clear its entity cache before
+    /// it processes the next block. Returns `false` if that subgraph is not
+    /// being indexed on this node
+    pub fn request_flush(&self, deployment: DeploymentHash) -> bool {
+        let running = self.running.lock().unwrap();
+        if !running.contains(&deployment) {
+            return false;
+        }
+        self.flush_requests.lock().unwrap().insert(deployment);
+        true
+    }
+
+    /// Return `true` if a flush was requested for `deployment` since the
+    /// last call, and clear the request
+    pub fn take_flush_request(&self, deployment: &DeploymentHash) -> bool {
+        self.flush_requests.lock().unwrap().remove(deployment)
+    }
+}
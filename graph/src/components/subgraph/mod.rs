@@ -1,4 +1,5 @@
 mod host;
+mod host_calls;
 mod instance;
 mod instance_manager;
 mod proof_of_indexing;
@@ -8,8 +9,11 @@ mod registrar;
 pub use crate::prelude::Entity;
 
 pub use self::host::{HostMetrics, MappingError, RuntimeHost, RuntimeHostBuilder};
+pub use self::host_calls::{host_call, HostCallKind, HostCalls};
 pub use self::instance::{BlockState, DataSourceTemplateInfo};
-pub use self::instance_manager::SubgraphInstanceManager;
+pub use self::instance_manager::{
+    EntityCacheControl, SubgraphInstanceManager, TransactionSimulator,
+};
 pub use self::proof_of_indexing::{
     BlockEventStream, CausalityRegion, ProofOfIndexing, ProofOfIndexingEvent,
     ProofOfIndexingFinisher, SharedProofOfIndexing,
@@ -40,4 +40,14 @@ pub trait SubgraphRegistrar: Send + Sync + 'static {
         hash: &DeploymentHash,
         node_id: &NodeId,
     ) -> Result<(), SubgraphRegistrarError>;
+
+    /// Rewind the deployment `hash` to `block_ptr_to`. The deployment is
+    /// paused while it is rewound and resumes indexing from the new block
+    /// afterwards. Only checks that the rewind is possible before
+    /// returning; the rewind itself happens in the background
+    async fn rewind_subgraph(
+        &self,
+        hash: &DeploymentHash,
+        block_ptr_to: BlockPtr,
+    ) -> Result<(), SubgraphRegistrarError>;
 }
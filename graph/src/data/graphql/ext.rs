@@ -18,6 +18,10 @@ lazy_static! {
 pub trait ObjectTypeExt {
     fn field(&self, name: &str) -> Option<&Field>;
     fn is_meta(&self) -> bool;
+    /// Whether the type is declared with `@entity(immutable: true)`;
+    /// entities of such a type can never be changed or removed once they
+    /// have been created
+    fn is_immutable(&self) -> bool;
 }
 
 impl ObjectTypeExt for ObjectType {
@@ -28,6 +32,12 @@ impl ObjectTypeExt for ObjectType {
     fn is_meta(&self) -> bool {
         self.name == META_FIELD_TYPE
     }
+
+    fn is_immutable(&self) -> bool {
+        self.find_directive("entity")
+            .and_then(|entity| entity.argument("immutable"))
+            .map_or(false, |value| value == &Value::Boolean(true))
+    }
 }
 
 impl ObjectTypeExt for InterfaceType {
@@ -38,6 +48,10 @@ impl ObjectTypeExt for InterfaceType {
     fn is_meta(&self) -> bool {
         false
     }
+
+    fn is_immutable(&self) -> bool {
+        false
+    }
 }
 
 pub trait DocumentExt {
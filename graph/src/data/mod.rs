@@ -10,6 +10,9 @@ pub mod schema;
 /// Data types for dealing with storing entities.
 pub mod store;
 
+/// Timeseries entities and their rollups.
+pub mod timeseries;
+
 /// Data types for dealing with GraphQL subscriptions.
 pub mod subscription;
 
@@ -10,9 +10,18 @@ use std::sync::Arc;
 
 use crate::data::graphql::SerializableValue;
 use crate::data::subgraph::*;
-use crate::prelude::q;
+use crate::prelude::{lazy_static, q, serde_json, BlockNumber};
 use crate::{components::store::StoreError, prelude::CacheWeight};
 
+lazy_static! {
+    /// Whether errors in query responses get an `extensions` object with
+    /// their kind. Responses are attested byte for byte, and indexers that
+    /// serve the same query have to agree on them; this therefore has to
+    /// stay off unless all indexers that answer a query turn it on
+    static ref ERROR_EXTENSIONS: bool =
+        std::env::var("GRAPH_GRAPHQL_ERROR_EXTENSIONS").is_ok();
+}
+
 #[derive(Debug)]
 pub struct CloneableAnyhowError(Arc<anyhow::Error>);
 
@@ -52,6 +61,8 @@ pub enum QueryExecutionError {
     MultipleSubscriptionFields,
     SubgraphDeploymentIdError(String),
     RangeArgumentsError(&'static str, u32, i64),
+    InvalidCursor(String),
+    BlockNotYetIndexed(DeploymentHash, String, BlockNumber), // (subgraph, block hash, subgraph head)
     InvalidFilterError,
     EntityFieldError(String, String),
     ListTypesError(String, Vec<String>),
@@ -61,13 +72,15 @@ pub enum QueryExecutionError {
     EntityParseError(String),
     StoreError(CloneableAnyhowError),
     Timeout,
+    Cancelled,
     EmptySelectionSet(String),
     AmbiguousDerivedFromResult(Pos, String, String, String),
     Unimplemented(String),
     EnumCoercionError(Pos, String, q::Value, String, Vec<String>),
     ScalarCoercionError(Pos, String, q::Value, String),
-    TooComplex(u64, u64), // (complexity, max_complexity)
-    TooDeep(u8),          // max_depth
+    TooComplex(u64, u64),  // (complexity, max_complexity)
+    TooDeep(u8),           // max_depth
+    TooWide(usize, usize), // (breadth, max_breadth)
     CyclicalFragment(String),
     TooExpensive,
     Throttled,
@@ -78,11 +91,119 @@ pub enum QueryExecutionError {
     EventStreamError,
     FulltextQueryRequiresFilter,
     DeploymentReverted,
+    QueriesDisabled(DeploymentHash),
     SubgraphManifestResolveError(Arc<SubgraphManifestResolveError>),
     InvalidSubgraphManifest,
     ResultTooBig(usize, usize),
 }
 
+/// How a client should treat an error in a query response. The kind is
+/// reported in the `extensions` of each error
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// The query is invalid or can not be answered from the subgraph's
+    /// data; sending it again will give the same error
+    User,
+    /// The error depends on the state of the server, e.g., its load or how
+    /// far the subgraph has indexed; the query might succeed when retried
+    Transient,
+    /// The subgraph ran into errors while indexing the blocks the query
+    /// was answered from
+    Indexing,
+    /// Something went wrong inside the server
+    Internal,
+}
+
+impl ErrorKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorKind::User => "user",
+            ErrorKind::Transient => "transient",
+            ErrorKind::Indexing => "indexing",
+            ErrorKind::Internal => "internal",
+        }
+    }
+}
+
+impl QueryExecutionError {
+    /// Whether the query might succeed when it is retried
+    pub fn is_transient(&self) -> bool {
+        match self {
+            QueryExecutionError::StoreError(e) => {
+                e.0.downcast_ref::<StoreError>()
+                    .map(StoreError::is_transient)
+                    .unwrap_or(false)
+            }
+            QueryExecutionError::BlockNotYetIndexed(..) => true,
+            _ => false,
+        }
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        use self::QueryExecutionError::*;
+
+        if self.is_transient() {
+            return ErrorKind::Transient;
+        }
+        match self {
+            OperationNameRequired
+            | OperationNotFound(_)
+            | NotSupported(_)
+            | NoRootSubscriptionObjectType
+            | InvalidArgumentError(_, _, _)
+            | MissingArgumentError(_, _)
+            | InvalidVariableTypeError(_, _)
+            | MissingVariableError(_, _)
+            | OrderByNotSupportedError(_, _)
+            | OrderByNotSupportedForType(_)
+            | FilterNotSupportedError(_, _)
+            | UnknownField(_, _, _)
+            | EmptyQuery
+            | MultipleSubscriptionFields
+            | RangeArgumentsError(_, _, _)
+            | InvalidCursor(_)
+            | InvalidFilterError
+            | EntityFieldError(_, _)
+            | ListTypesError(_, _)
+            | ListFilterError(_)
+            | ValueParseError(_, _)
+            | AttributeTypeError(_, _)
+            | EmptySelectionSet(_)
+            | AmbiguousDerivedFromResult(_, _, _, _)
+            | Unimplemented(_)
+            | EnumCoercionError(_, _, _, _, _)
+            | ScalarCoercionError(_, _, _, _)
+            | TooComplex(_, _)
+            | TooDeep(_)
+            | TooWide(_, _)
+            | CyclicalFragment(_)
+            | UndefinedFragment(_)
+            | FulltextQueryRequiresFilter
+            | ResultTooBig(_, _) => ErrorKind::User,
+            BlockNotYetIndexed(_, _, _)
+            | Timeout
+            | Cancelled
+            | TooExpensive
+            | Throttled
+            | EventStreamError
+            | DeploymentReverted
+            | QueriesDisabled(_) => ErrorKind::Transient,
+            NonNullError(_, _)
+            | ListValueError(_, _)
+            | NamedTypeError(_)
+            | AbstractTypeError(_)
+            | ResolveEntitiesError(_)
+            | SubgraphDeploymentIdError(_)
+            | EntityParseError(_)
+            | StoreError(_)
+            | IncorrectPrefetchResult { .. }
+            | Panic(_)
+            | SubgraphManifestResolveError(_)
+            | InvalidSubgraphManifest => ErrorKind::Internal,
+        }
+    }
+}
+
 impl Error for QueryExecutionError {
     fn description(&self) -> &str {
         "Query execution error"
@@ -156,6 +277,15 @@ impl fmt::Display for QueryExecutionError {
             RangeArgumentsError(arg, max, actual) => {
                 write!(f, "The `{}` argument must be between 0 and {}, but is {}", arg, max, actual)
             }
+            InvalidCursor(cursor) => {
+                write!(f, "The `after` argument `{}` is not a cursor for this query; \
+                           cursors must come from the `_cursor` field of entities returned \
+                           with the same `orderBy`", cursor)
+            }
+            BlockNotYetIndexed(subgraph, hash, head) => {
+                write!(f, "subgraph {} has only indexed up to block number {} and has not \
+                           processed block {} yet; retry the query later", subgraph, head, hash)
+            }
             InvalidFilterError => write!(f, "Filter must by an object"),
             EntityFieldError(e, a) => {
                 write!(f, "Entity `{}` has no attribute `{}`", e, a)
@@ -183,6 +313,7 @@ impl fmt::Display for QueryExecutionError {
                 write!(f, "Store error: {}", e.0)
             }
             Timeout => write!(f, "Query timed out"),
+            Cancelled => write!(f, "Query was cancelled"),
             EmptySelectionSet(entity_type) => {
                 write!(f, "Selection set for type `{}` is empty", entity_type)
             }
@@ -207,6 +338,11 @@ impl fmt::Display for QueryExecutionError {
                            return smaller collections", complexity, max_complexity)
             }
             TooDeep(max_depth) => write!(f, "query has a depth that exceeds the limit of `{}`", max_depth),
+            TooWide(breadth, max_breadth) => {
+                write!(f, "query selects `{}` fields in one selection set and thereby exceeds \
+                           the limit of `{}` fields. Split the query into several smaller \
+                           queries", breadth, max_breadth)
+            }
             CyclicalFragment(name) =>write!(f, "query has fragment cycle including `{}`", name),
             UndefinedFragment(frag_name) => write!(f, "fragment `{}` is not defined", frag_name),
             IncorrectPrefetchResult{ .. } => write!(f, "Running query with prefetch \
@@ -219,6 +355,7 @@ impl fmt::Display for QueryExecutionError {
             TooExpensive => write!(f, "query is too expensive"),
             Throttled=> write!(f, "service is overloaded and can not run the query right now. Please try again in a few minutes"),
             DeploymentReverted => write!(f, "the chain was reorganized while executing the query"),
+            QueriesDisabled(id) => write!(f, "queries for deployment `{}` are temporarily disabled for maintenance", id),
             SubgraphManifestResolveError(e) => write!(f, "failed to resolve subgraph manifest: {}", e),
             InvalidSubgraphManifest => write!(f, "invalid subgraph manifest file"),
             ResultTooBig(actual, limit) => write!(f, "the result size of {} is larger than the allowed limit of {}", actual, limit),
@@ -268,7 +405,19 @@ pub enum QueryError {
     EncodingError(FromUtf8Error),
     ParseError(Arc<anyhow::Error>),
     ExecutionError(QueryExecutionError),
-    IndexingError,
+    /// The subgraph has indexing errors at the block the query was answered
+    /// from
+    IndexingError(DeploymentHash, BlockNumber),
+}
+
+impl QueryError {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            QueryError::EncodingError(_) | QueryError::ParseError(_) => ErrorKind::User,
+            QueryError::ExecutionError(e) => e.kind(),
+            QueryError::IndexingError(_, _) => ErrorKind::Indexing,
+        }
+    }
 }
 
 impl From<FromUtf8Error> for QueryError {
@@ -305,13 +454,24 @@ impl fmt::Display for QueryError {
             QueryError::ParseError(ref e) => write!(f, "{}", e),
 
             // This error message is part of attestable responses.
-            QueryError::IndexingError => write!(f, "indexing_error"),
+            QueryError::IndexingError(_, _) => write!(f, "indexing_error"),
         }
     }
 }
 
 impl Serialize for QueryError {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        self.serialize_with(serializer, *ERROR_EXTENSIONS)
+    }
+}
+
+impl QueryError {
+    /// Serialize this error, adding `extensions` with the kind of the
+    /// error if `extensions` is `true`
+    fn serialize_with<S>(&self, serializer: S, extensions: bool) -> Result<S::Ok, S::Error>
     where
         S: Serializer,
     {
@@ -326,6 +486,11 @@ impl Serialize for QueryError {
             } else {
                 1
             };
+        let entry_count = if extensions {
+            entry_count + 1
+        } else {
+            entry_count
+        };
         let mut map = serializer.serialize_map(Some(entry_count))?;
 
         let msg = match self {
@@ -392,6 +557,17 @@ impl Serialize for QueryError {
         };
 
         map.serialize_entry("message", msg.as_str())?;
+
+        if extensions {
+            let mut extensions = serde_json::json!({ "kind": self.kind().as_str() });
+            if let QueryError::IndexingError(deployment, block) = self {
+                extensions["indexingError"] = serde_json::json!({
+                    "deployment": deployment.as_str(),
+                    "block": block,
+                });
+            }
+            map.serialize_entry("extensions", &extensions)?;
+        }
         map.end()
     }
 }
@@ -402,3 +578,30 @@ impl CacheWeight for QueryError {
         0
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::QueryError;
+    use crate::prelude::{serde_json::json, serde_json::value::Serializer, DeploymentHash};
+
+    #[test]
+    fn extensions_are_opt_in() {
+        let error = QueryError::IndexingError(DeploymentHash::new("QmTest").unwrap(), 7);
+
+        // This is part of attestable responses and must not change
+        assert_eq!(
+            json!({ "message": "indexing_error" }),
+            error.serialize_with(Serializer, false).unwrap()
+        );
+        assert_eq!(
+            json!({
+                "message": "indexing_error",
+                "extensions": {
+                    "kind": "indexing",
+                    "indexingError": { "deployment": "QmTest", "block": 7 }
+                }
+            }),
+            error.serialize_with(Serializer, true).unwrap()
+        );
+    }
+}
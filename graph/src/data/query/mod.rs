@@ -4,6 +4,6 @@ mod query;
 mod result;
 
 pub use self::cache_status::CacheStatus;
-pub use self::error::{QueryError, QueryExecutionError};
+pub use self::error::{ErrorKind, QueryError, QueryExecutionError};
 pub use self::query::{Query, QueryTarget, QueryVariables};
 pub use self::result::{QueryResult, QueryResults};
@@ -112,7 +112,11 @@ impl serde::ser::Serialize for QueryVariables {
 
 #[derive(Clone, Debug)]
 pub enum QueryTarget {
+    /// The current version of the subgraph with this name
     Name(SubgraphName),
+    /// The pending version of the subgraph with this name, i.e., the
+    /// deployment that will become current once it has synced
+    Pending(SubgraphName),
     Deployment(DeploymentHash),
 }
 
@@ -2,14 +2,25 @@ use super::error::{QueryError, QueryExecutionError};
 use crate::prelude::{r, CacheWeight, DeploymentHash};
 use http::header::{
     ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN,
-    CONTENT_TYPE,
+    CONTENT_TYPE, RETRY_AFTER,
 };
+use lazy_static::lazy_static;
 use serde::ser::*;
 use serde::Serialize;
 use std::collections::BTreeMap;
 use std::convert::TryFrom;
+use std::env;
 use std::sync::Arc;
 
+lazy_static! {
+    /// How many seconds clients should wait before retrying a query that
+    /// was declined because the node is overloaded
+    static ref THROTTLE_RETRY_AFTER: u64 = env::var("GRAPH_LOAD_RETRY_AFTER")
+        .unwrap_or("60".into())
+        .parse::<u64>()
+        .expect("invalid GRAPH_LOAD_RETRY_AFTER");
+}
+
 fn serialize_data<S>(data: &Option<Data>, serializer: S) -> Result<S::Ok, S::Error>
 where
     S: Serializer,
@@ -153,12 +164,35 @@ impl QueryResults {
         self.results.push(other);
     }
 
+    /// Return `true` if the query was not run at all because the load
+    /// manager declined it while the node is overloaded
+    pub fn is_throttled(&self) -> bool {
+        !self.results.is_empty()
+            && self.results.iter().all(|result| {
+                !result.has_data()
+                    && result.errors.iter().any(|e| {
+                        matches!(
+                            e,
+                            QueryError::ExecutionError(QueryExecutionError::Throttled)
+                        )
+                    })
+            })
+    }
+
     pub fn as_http_response<T: From<String>>(&self) -> http::Response<T> {
-        let status_code = http::StatusCode::OK;
         let json =
             serde_json::to_string(self).expect("Failed to serialize GraphQL response to JSON");
-        http::Response::builder()
-            .status(status_code)
+        let mut builder = http::Response::builder();
+        if self.is_throttled() {
+            // Shed load by telling clients to back off instead of having
+            // them retry immediately
+            builder = builder
+                .status(http::StatusCode::TOO_MANY_REQUESTS)
+                .header(RETRY_AFTER, THROTTLE_RETRY_AFTER.to_string());
+        } else {
+            builder = builder.status(http::StatusCode::OK);
+        }
+        builder
             .header(ACCESS_CONTROL_ALLOW_ORIGIN, "*")
             .header(ACCESS_CONTROL_ALLOW_HEADERS, "Content-Type, User-Agent")
             .header(ACCESS_CONTROL_ALLOW_METHODS, "GET, OPTIONS, POST")
@@ -211,6 +245,12 @@ impl QueryResult {
         self.data.is_some()
     }
 
+    /// Whether `self` and `other` are both successful and have the same
+    /// data
+    pub fn has_same_data(&self, other: &QueryResult) -> bool {
+        !self.has_errors() && !other.has_errors() && self.data == other.data
+    }
+
     pub fn to_result(self) -> Result<Option<r::Value>, Vec<QueryError>> {
         if self.has_errors() {
             Err(self.errors)
@@ -318,3 +358,17 @@ fn multiple_data_items() {
     let actual = serde_json::to_string(&res).unwrap();
     assert_eq!(expected, actual)
 }
+
+#[test]
+fn throttled_queries_get_429() {
+    let res = QueryResults::from(QueryExecutionError::Throttled);
+    assert!(res.is_throttled());
+    let response: http::Response<String> = res.as_http_response();
+    assert_eq!(http::StatusCode::TOO_MANY_REQUESTS, response.status());
+    assert_eq!("60", response.headers()[RETRY_AFTER]);
+
+    let res = QueryResults::from(QueryExecutionError::TooExpensive);
+    assert!(!res.is_throttled());
+    let response: http::Response<String> = res.as_http_response();
+    assert_eq!(http::StatusCode::OK, response.status());
+}
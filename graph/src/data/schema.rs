@@ -2,6 +2,7 @@ use crate::components::store::{EntityType, SubgraphStore};
 use crate::data::graphql::ext::{DirectiveExt, DirectiveFinder, DocumentExt, TypeExt, ValueExt};
 use crate::data::store::ValueType;
 use crate::data::subgraph::{DeploymentHash, SubgraphName};
+use crate::data::timeseries::{add_rollup_types, is_timeseries, TIMESTAMP_FIELD};
 use crate::prelude::{
     q::Value,
     s::{self, Definition, InterfaceType, ObjectType, TypeDefinition, *},
@@ -103,6 +104,12 @@ pub enum SchemaValidationError {
     FulltextIncludedFieldMissingRequiredProperty,
     #[error("Fulltext entity field, {0}, not found or not a string")]
     FulltextIncludedFieldInvalid(String),
+    #[error("Timeseries entity type `{0}` must have a `timestamp: Int!` field")]
+    TimeseriesTimestampMissing(String),
+    #[error("The rollup type `{1}` for timeseries `{0}` conflicts with an existing type")]
+    TimeseriesRollupTypeExists(String, String), // (timeseries, rollup type)
+    #[error("The `immutable` argument of `@entity` on type `{0}` must be `true` or `false`")]
+    ImmutableArgumentInvalid(String),
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -521,7 +528,8 @@ impl Schema {
     }
 
     pub fn parse(raw: &str, id: DeploymentHash) -> Result<Self, Error> {
-        let document = graphql_parser::parse_schema(raw)?.into_static();
+        let mut document = graphql_parser::parse_schema(raw)?.into_static();
+        add_rollup_types(&mut document)?;
 
         let (interfaces_for_type, types_for_interface) = Self::collect_interfaces(&document)?;
 
@@ -651,6 +659,8 @@ impl Schema {
             self.validate_schema_type_has_no_fields(),
             self.validate_directives_on_schema_type(),
             self.validate_reserved_types_usage(),
+            self.validate_timeseries(),
+            self.validate_immutable(),
         ])
         .filter(Result::is_err)
         // Safe unwrap due to the filter above
@@ -1146,6 +1156,42 @@ impl Schema {
         }
     }
 
+    fn validate_timeseries(&self) -> Result<(), SchemaValidationError> {
+        for object_type in self.document.get_object_type_definitions() {
+            if !is_timeseries(object_type) {
+                continue;
+            }
+            let has_timestamp = object_type.fields.iter().any(|field| {
+                field.name == TIMESTAMP_FIELD
+                    && field.field_type
+                        == s::Type::NonNullType(Box::new(s::Type::NamedType("Int".to_string())))
+            });
+            if !has_timestamp {
+                return Err(SchemaValidationError::TimeseriesTimestampMissing(
+                    object_type.name.clone(),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn validate_immutable(&self) -> Result<(), SchemaValidationError> {
+        for object_type in self.document.get_object_type_definitions() {
+            let immutable = object_type
+                .find_directive("entity")
+                .and_then(|entity| entity.argument("immutable"));
+            match immutable {
+                None | Some(s::Value::Boolean(_)) => {}
+                Some(_) => {
+                    return Err(SchemaValidationError::ImmutableArgumentInvalid(
+                        object_type.name.clone(),
+                    ))
+                }
+            }
+        }
+        Ok(())
+    }
+
     fn validate_derived_from(&self) -> Result<(), SchemaValidationError> {
         // Helper to construct a DerivedFromInvalid
         fn invalid(
@@ -1382,6 +1428,66 @@ fn invalid_interface_implementation() {
     );
 }
 
+#[test]
+fn timeseries_validation() {
+    let schema = "type Trade @entity(timeseries: true) { id: ID!, timestamp: Int!, amount: Int! }";
+    let schema = Schema::parse(schema, DeploymentHash::new("dummy").unwrap()).unwrap();
+    assert!(schema.validate(&HashMap::new()).is_ok());
+    assert!(schema
+        .document
+        .get_object_type_definition("TradeHour")
+        .is_some());
+
+    // The stored schema contains the rollup types, and has to parse again
+    let stored = schema.document.to_string();
+    let reloaded = Schema::parse(&stored, DeploymentHash::new("dummy").unwrap()).unwrap();
+    assert_eq!(stored, reloaded.document.to_string());
+
+    let schema = "type Trade @entity(timeseries: true) { id: ID!, timestamp: BigInt! }";
+    let schema = Schema::parse(schema, DeploymentHash::new("dummy").unwrap()).unwrap();
+    assert_eq!(
+        Err(vec![SchemaValidationError::TimeseriesTimestampMissing(
+            "Trade".to_owned()
+        )]),
+        schema.validate(&HashMap::new())
+    );
+
+    let schema = "type Trade @entity(timeseries: true) { id: ID!, timestamp: Int! }
+                  type TradeDay @entity { id: ID! }";
+    let res = Schema::parse(schema, DeploymentHash::new("dummy").unwrap());
+    assert_eq!(
+        SchemaValidationError::TimeseriesRollupTypeExists(
+            "Trade".to_owned(),
+            "TradeDay".to_owned()
+        ),
+        res.unwrap_err()
+            .downcast::<SchemaValidationError>()
+            .unwrap()
+    );
+}
+
+#[test]
+fn immutable_validation() {
+    use crate::data::graphql::ObjectTypeExt;
+
+    let schema = "type Transfer @entity(immutable: true) { id: ID!, amount: Int! }
+                  type Account @entity { id: ID! }";
+    let schema = Schema::parse(schema, DeploymentHash::new("dummy").unwrap()).unwrap();
+    assert!(schema.validate(&HashMap::new()).is_ok());
+    let object_type = |name| schema.document.get_object_type_definition(name).unwrap();
+    assert!(object_type("Transfer").is_immutable());
+    assert!(!object_type("Account").is_immutable());
+
+    let schema = "type Transfer @entity(immutable: \"yes\") { id: ID! }";
+    let schema = Schema::parse(schema, DeploymentHash::new("dummy").unwrap()).unwrap();
+    assert_eq!(
+        Err(vec![SchemaValidationError::ImmutableArgumentInvalid(
+            "Transfer".to_owned()
+        )]),
+        schema.validate(&HashMap::new())
+    );
+}
+
 #[test]
 fn test_derived_from_validation() {
     const OTHER_TYPES: &str = "
@@ -41,6 +41,11 @@ use std::sync::Arc;
 /// different API versions if at least one of them is equal to or higher than `0.0.5`.
 pub const API_VERSION_0_0_5: Version = Version::new(0, 0, 5);
 
+/// This version passes the receipt of the transaction that emitted an event to event handlers
+/// that ask for it with `receipt: true`, and makes errors in `ethereum.call` that the mapping
+/// causes, like unknown functions or arguments that do not match the ABI, deterministic.
+pub const API_VERSION_0_0_7: Version = Version::new(0, 0, 7);
+
 /// Before this check was introduced, there were already subgraphs in the wild with spec version
 /// 0.0.3, due to confusion with the api version. To avoid breaking those, we accept 0.0.3 though it
 /// doesn't exist. In the future we should not use 0.0.3 as version and skip to 0.0.4 to avoid
@@ -845,6 +850,15 @@ pub struct DeploymentState {
     pub max_reorg_depth: u32,
     /// The number of the last block that the subgraph has processed
     pub latest_ethereum_block_number: BlockNumber,
+    /// Whether an operator has turned off queries for this deployment
+    pub queries_disabled: bool,
+    /// If the deployment was pruned, the earliest block for which it still
+    /// has complete data
+    pub pruned_block: Option<BlockNumber>,
+    /// Whether the deployment has failed with a deterministic fatal error.
+    /// Such a deployment will not index any more blocks, unlike one that
+    /// failed with a non-deterministic error and will be retried
+    pub failed_deterministically: bool,
 }
 
 impl DeploymentState {
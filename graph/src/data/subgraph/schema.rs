@@ -159,6 +159,12 @@ pub struct SubgraphManifestEntity {
     pub repository: Option<String>,
     pub features: Vec<String>,
     pub schema: String,
+    /// The manifest as it was deployed. It is only known when the
+    /// deployment is created from the manifest's YAML
+    pub raw_yaml: Option<String>,
+    /// The ABIs that the manifest references, as a JSON list of objects
+    /// with the `name`, the `file` and the contents of each ABI
+    pub abis: Option<String>,
 }
 
 impl<'a, C: Blockchain> From<&'a super::SubgraphManifest<C>> for SubgraphManifestEntity {
@@ -169,6 +175,8 @@ impl<'a, C: Blockchain> From<&'a super::SubgraphManifest<C>> for SubgraphManifes
             repository: manifest.repository.clone(),
             features: manifest.features.iter().map(|f| f.to_string()).collect(),
             schema: manifest.schema.document.clone().to_string(),
+            raw_yaml: None,
+            abis: None,
         }
     }
 }
@@ -3,7 +3,9 @@
 use super::schema::{SubgraphError, SubgraphHealth};
 use crate::components::store::DeploymentId;
 use crate::data::graphql::{object, IntoValue};
-use crate::prelude::{r, web3::types::H256, BlockPtr, Value};
+use crate::prelude::{r, serde_json, web3::types::H256, BlockNumber, BlockPtr, Entity, Value};
+use std::collections::BTreeMap;
+use std::time::Duration;
 
 pub enum Filter {
     /// Get all versions for the named subgraph
@@ -86,6 +88,91 @@ impl IntoValue for ChainInfo {
     }
 }
 
+/// The state of a chain and of the block ingestor that follows it, as
+/// reported by the `chains` query of the index node API
+#[derive(Debug)]
+pub struct ChainStatus {
+    pub network: String,
+    /// The chain head in the block store, i.e., the latest block that
+    /// subgraphs can process
+    pub chain_head_block: Option<EthereumBlock>,
+    /// The latest block the block ingestor got from its provider
+    pub provider_head_block: Option<BlockNumber>,
+    /// How long ago the block ingestor last got the latest block from its
+    /// provider
+    pub provider_head_age: Option<Duration>,
+    /// The error from the block ingestor's last attempt to get the latest
+    /// block from its provider, if that attempt failed
+    pub provider_error: Option<String>,
+    /// The number of blocks in the block cache
+    pub cached_blocks: u64,
+}
+
+impl IntoValue for ChainStatus {
+    fn into_value(self) -> r::Value {
+        let ChainStatus {
+            network,
+            chain_head_block,
+            provider_head_block,
+            provider_head_age,
+            provider_error,
+            cached_blocks,
+        } = self;
+
+        let ingestor_lag = provider_head_block.map(|provider_head| {
+            let head = chain_head_block
+                .as_ref()
+                .map(|head| head.number())
+                .unwrap_or(0);
+            format!("{}", (provider_head - head).max(0))
+        });
+
+        object! {
+            __typename: "ChainStatus",
+            network: network,
+            chainHeadBlock: chain_head_block,
+            providerHeadBlockNumber: provider_head_block.map(|number| format!("{}", number)),
+            ingestorLag: ingestor_lag,
+            providerHealthy: provider_head_block.is_some() && provider_error.is_none(),
+            secondsSinceProviderHead: provider_head_age
+                .map(|age| age.as_secs().min(i32::MAX as u64) as i32),
+            providerError: provider_error,
+            cachedBlockCount: cached_blocks,
+        }
+    }
+}
+
+/// How large a deployment in one shard is, as reported by the
+/// `deploymentSizes` query of the index node API
+#[derive(Debug)]
+pub struct DeploymentSize {
+    pub subgraph: String,
+    pub shard: String,
+    pub entity_count: u64,
+    /// The approximate size of the tables and indexes of the deployment in
+    /// bytes when it was last measured, or `None` if it was never measured
+    pub size_bytes: Option<u64>,
+}
+
+impl IntoValue for DeploymentSize {
+    fn into_value(self) -> r::Value {
+        let DeploymentSize {
+            subgraph,
+            shard,
+            entity_count,
+            size_bytes,
+        } = self;
+
+        object! {
+            __typename: "DeploymentSize",
+            subgraph: subgraph,
+            shard: shard,
+            entityCount: format!("{}", entity_count),
+            sizeBytes: size_bytes.map(|size| format!("{}", size)),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Info {
     pub id: DeploymentId,
@@ -106,6 +193,25 @@ pub struct Info {
 
     /// ID of the Graph Node that the subgraph is indexed by.
     pub node: Option<String>,
+
+    /// How many blocks per second the subgraph processed recently, as last
+    /// reported by the node indexing it.
+    pub blocks_per_second: Option<f64>,
+}
+
+/// Estimate how long a subgraph that is at block `latest` and processes
+/// `blocks_per_second` will take to reach the chain head at `head`
+pub fn time_to_sync(
+    latest: BlockNumber,
+    head: BlockNumber,
+    blocks_per_second: f64,
+) -> Option<Duration> {
+    // Below one block a day, the estimate is meaningless
+    if blocks_per_second.is_nan() || blocks_per_second <= 1.0 / 86400.0 {
+        return None;
+    }
+    let behind = (head - latest).max(0) as f64;
+    Some(Duration::from_secs_f64(behind / blocks_per_second))
 }
 
 impl IntoValue for Info {
@@ -120,8 +226,18 @@ impl IntoValue for Info {
             node,
             non_fatal_errors,
             synced,
+            blocks_per_second,
         } = self;
 
+        let seconds_to_sync = chains
+            .first()
+            .and_then(|chain| {
+                let latest = chain.latest_block.as_ref()?.number();
+                let head = chain.chain_head_block.as_ref()?.number();
+                time_to_sync(latest, head, blocks_per_second?)
+            })
+            .map(|eta| eta.as_secs().min(i32::MAX as u64) as i32);
+
         fn subgraph_error_to_value(subgraph_error: SubgraphError) -> r::Value {
             let SubgraphError {
                 subgraph_id,
@@ -161,6 +277,91 @@ impl IntoValue for Info {
             chains: chains.into_iter().map(|chain| chain.into_value()).collect::<Vec<_>>(),
             entityCount: format!("{}", entity_count),
             node: node,
+            blocksPerSecond: blocks_per_second,
+            estimatedSecondsToSync: seconds_to_sync,
+        }
+    }
+}
+
+/// How an entity changed between two blocks
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EntityDiffOperation {
+    /// The entity did not exist at the first block
+    Created,
+    /// The entity existed at both blocks, but changed in between
+    Updated,
+    /// The entity existed at the first block, but not at the second
+    Deleted,
+}
+
+impl EntityDiffOperation {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EntityDiffOperation::Created => "CREATED",
+            EntityDiffOperation::Updated => "UPDATED",
+            EntityDiffOperation::Deleted => "DELETED",
+        }
+    }
+}
+
+/// An entity that changed between two blocks. `data` is the entity as of
+/// the second block, and `None` for deleted entities
+#[derive(Debug)]
+pub struct EntityDiff {
+    pub entity_type: String,
+    pub id: String,
+    pub operation: EntityDiffOperation,
+    pub data: Option<Entity>,
+}
+
+impl IntoValue for EntityDiff {
+    fn into_value(self) -> r::Value {
+        let EntityDiff {
+            entity_type,
+            id,
+            operation,
+            data,
+        } = self;
+
+        // Send the entity as a JSON object since the index node schema
+        // can't know the types of the entities of each subgraph
+        let data = data.map(|data| {
+            let data: BTreeMap<_, _> = data
+                .sorted()
+                .into_iter()
+                .map(|(attr, value)| (attr, r::Value::from(value)))
+                .collect();
+            serde_json::to_string(&r::Value::Object(data)).expect("entities can be serialized")
+        });
+
+        object! {
+            __typename: "EntityDiff",
+            entityType: entity_type,
+            id: id,
+            operation: r::Value::Enum(operation.as_str().to_string()),
+            data: data,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn time_to_sync_from_rate() {
+        assert_eq!(Some(Duration::from_secs(50)), time_to_sync(100, 200, 2.0));
+        // A subgraph at or past the head is synced
+        assert_eq!(Some(Duration::from_secs(0)), time_to_sync(200, 200, 2.0));
+        assert_eq!(Some(Duration::from_secs(0)), time_to_sync(210, 200, 2.0));
+    }
+
+    #[test]
+    fn time_to_sync_without_progress() {
+        assert_eq!(None, time_to_sync(100, 200, 0.0));
+        assert_eq!(None, time_to_sync(100, 200, -1.0));
+        assert_eq!(None, time_to_sync(100, 200, f64::NAN));
+        // Less than one block a day
+        assert_eq!(None, time_to_sync(100, 200, 1.0 / 100_000.0));
+    }
+}
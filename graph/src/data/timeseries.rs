@@ -0,0 +1,445 @@
+//! Timeseries entities record individual data points like trades or price
+//! observations. They are declared with `@entity(timeseries: true)` and
+//! must have a `timestamp: Int!` field that holds the time of the data
+//! point in seconds since the epoch.
+//!
+//! For each timeseries entity type `T`, the schema gets the rollup entity
+//! types `THour` and `TDay`. A rollup entity holds the number of data
+//! points in one hour or day, and the sum, minimum, maximum, and last value
+//! of each numeric field of those data points. Rollups are updated at the
+//! end of each block from the timeseries entities that the block created.
+use std::str::FromStr;
+
+use graphql_parser::Pos;
+
+use crate::components::store::EntityType;
+use crate::data::graphql::ext::{DirectiveExt, DirectiveFinder, DocumentExt, TypeExt};
+use crate::data::schema::SchemaValidationError;
+use crate::prelude::{s, BigInt, Entity, Value, ValueType};
+
+/// The field of a timeseries entity that holds the time of the data point
+pub const TIMESTAMP_FIELD: &str = "timestamp";
+
+/// The intervals for which timeseries are rolled up
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Interval {
+    Hour,
+    Day,
+}
+
+impl Interval {
+    pub const ALL: [Interval; 2] = [Interval::Hour, Interval::Day];
+
+    pub fn seconds(&self) -> i32 {
+        match self {
+            Interval::Hour => 3600,
+            Interval::Day => 86400,
+        }
+    }
+
+    /// The suffix that turns the name of a timeseries entity type into the
+    /// name of its rollup type for this interval
+    pub fn suffix(&self) -> &'static str {
+        match self {
+            Interval::Hour => "Hour",
+            Interval::Day => "Day",
+        }
+    }
+
+    /// The start of the interval that contains `timestamp`
+    pub fn start(&self, timestamp: i32) -> i32 {
+        timestamp - timestamp.rem_euclid(self.seconds())
+    }
+}
+
+/// A numeric field of a timeseries entity that gets rolled up
+#[derive(Clone, Debug, PartialEq)]
+pub struct RollupField {
+    pub name: String,
+    pub value_type: ValueType,
+}
+
+impl RollupField {
+    fn sum(&self) -> String {
+        format!("{}Sum", self.name)
+    }
+
+    fn min(&self) -> String {
+        format!("{}Min", self.name)
+    }
+
+    fn max(&self) -> String {
+        format!("{}Max", self.name)
+    }
+
+    fn last(&self) -> String {
+        format!("{}Last", self.name)
+    }
+
+    fn value_type(&self) -> &'static str {
+        match self.value_type {
+            ValueType::Int => "Int",
+            ValueType::BigDecimal => "BigDecimal",
+            _ => "BigInt",
+        }
+    }
+
+    /// The type of the sum of this field; sums of `Int` fields are
+    /// `BigInt` so that they can not overflow
+    fn sum_type(&self) -> &'static str {
+        match self.value_type {
+            ValueType::BigDecimal => "BigDecimal",
+            _ => "BigInt",
+        }
+    }
+}
+
+/// A timeseries entity type and the fields that get rolled up
+#[derive(Clone, Debug, PartialEq)]
+pub struct Timeseries {
+    pub entity_type: EntityType,
+    pub fields: Vec<RollupField>,
+}
+
+impl Timeseries {
+    fn new(object_type: &s::ObjectType) -> Self {
+        let fields = object_type
+            .fields
+            .iter()
+            .filter(|field| field.name != TIMESTAMP_FIELD)
+            .filter_map(|field| {
+                let value_type = match &field.field_type {
+                    s::Type::ListType(_) => return None,
+                    s::Type::NonNullType(inner) if matches!(**inner, s::Type::ListType(_)) => {
+                        return None
+                    }
+                    field_type => ValueType::from_str(field_type.get_base_type()).ok()?,
+                };
+                match value_type {
+                    ValueType::Int | ValueType::BigInt | ValueType::BigDecimal => {
+                        Some(RollupField {
+                            name: field.name.clone(),
+                            value_type,
+                        })
+                    }
+                    _ => None,
+                }
+            })
+            .collect();
+        Timeseries {
+            entity_type: EntityType::new(object_type.name.clone()),
+            fields,
+        }
+    }
+
+    /// All timeseries entity types in `document`
+    pub fn for_document(document: &s::Document) -> Vec<Timeseries> {
+        document
+            .get_object_type_definitions()
+            .into_iter()
+            .filter(|object_type| is_timeseries(object_type))
+            .map(Timeseries::new)
+            .collect()
+    }
+
+    /// The entity type that holds the rollups for `interval`
+    pub fn rollup_type(&self, interval: Interval) -> EntityType {
+        EntityType::new(format!(
+            "{}{}",
+            self.entity_type.as_str(),
+            interval.suffix()
+        ))
+    }
+
+    /// The time of the data point `point`, or `None` if it does not have
+    /// a valid timestamp
+    pub fn timestamp(point: &Entity) -> Option<i32> {
+        match point.get(TIMESTAMP_FIELD) {
+            Some(Value::Int(timestamp)) => Some(*timestamp),
+            _ => None,
+        }
+    }
+
+    /// Add the data point `point` to `rollup`, the rollup for the interval
+    /// that starts at `start`. If there is no rollup for that interval
+    /// yet, `rollup` is `None`
+    pub fn add(&self, rollup: Option<Entity>, start: i32, point: &Entity) -> Entity {
+        let mut rollup = rollup.unwrap_or_else(|| {
+            let mut rollup = Entity::new();
+            rollup.set("id", start.to_string());
+            rollup.set(TIMESTAMP_FIELD, start);
+            rollup
+        });
+
+        let count = match rollup.get("count") {
+            Some(Value::Int(count)) => *count,
+            _ => 0,
+        };
+        rollup.set("count", count + 1);
+
+        for field in &self.fields {
+            let value = match point.get(&field.name) {
+                None | Some(Value::Null) => continue,
+                Some(value) => value,
+            };
+
+            let sum = match (rollup.get(&field.sum()), value) {
+                (Some(Value::BigInt(sum)), Value::Int(value)) => {
+                    Value::BigInt(sum.clone() + BigInt::from(*value))
+                }
+                (Some(Value::BigInt(sum)), Value::BigInt(value)) => {
+                    Value::BigInt(sum.clone() + value.clone())
+                }
+                (Some(Value::BigDecimal(sum)), Value::BigDecimal(value)) => {
+                    Value::BigDecimal(sum.clone() + value.clone())
+                }
+                (_, Value::Int(value)) => Value::BigInt(BigInt::from(*value)),
+                (_, value) => value.clone(),
+            };
+            rollup.set(field.sum(), sum);
+
+            if !matches!(rollup.get(&field.min()), Some(min) if !less(value, min)) {
+                rollup.set(field.min(), value.clone());
+            }
+            if !matches!(rollup.get(&field.max()), Some(max) if !less(max, value)) {
+                rollup.set(field.max(), value.clone());
+            }
+            rollup.set(field.last(), value.clone());
+        }
+        rollup
+    }
+}
+
+/// Compare numeric values of the same type; values that can not be
+/// compared are never less than each other
+fn less(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Int(a), Value::Int(b)) => a < b,
+        (Value::BigInt(a), Value::BigInt(b)) => a < b,
+        (Value::BigDecimal(a), Value::BigDecimal(b)) => a < b,
+        _ => false,
+    }
+}
+
+/// Whether `object_type` is declared with `@entity(timeseries: true)`
+pub fn is_timeseries(object_type: &s::ObjectType) -> bool {
+    object_type
+        .find_directive("entity")
+        .and_then(|entity| entity.argument("timeseries"))
+        .map_or(false, |value| value == &s::Value::Boolean(true))
+}
+
+/// Add the rollup entity types for all timeseries entity types to
+/// `document`. Since the schema that is stored for a deployment already
+/// contains the rollup types, types that look exactly like the rollup type
+/// we would add are left alone; any other type with the name of a rollup
+/// type is an error
+pub(crate) fn add_rollup_types(document: &mut s::Document) -> Result<(), SchemaValidationError> {
+    let timeseries = Timeseries::for_document(document);
+    for timeseries in timeseries {
+        for interval in Interval::ALL.iter() {
+            let rollup_type = rollup_object_type(&timeseries, *interval);
+            match document.get_named_type(&rollup_type.name) {
+                None => (),
+                Some(s::TypeDefinition::Object(existing))
+                    if same_fields(existing, &rollup_type) =>
+                {
+                    continue
+                }
+                Some(_) => {
+                    return Err(SchemaValidationError::TimeseriesRollupTypeExists(
+                        timeseries.entity_type.to_string(),
+                        rollup_type.name,
+                    ))
+                }
+            }
+            document
+                .definitions
+                .push(s::Definition::TypeDefinition(s::TypeDefinition::Object(
+                    rollup_type,
+                )));
+        }
+    }
+    Ok(())
+}
+
+/// Whether `a` and `b` have fields with the same names and types, in the
+/// same order
+fn same_fields(a: &s::ObjectType, b: &s::ObjectType) -> bool {
+    a.fields.len() == b.fields.len()
+        && a.fields
+            .iter()
+            .zip(b.fields.iter())
+            .all(|(a, b)| a.name == b.name && a.field_type == b.field_type)
+}
+
+fn rollup_object_type(timeseries: &Timeseries, interval: Interval) -> s::ObjectType {
+    fn field(name: String, description: String, field_type: s::Type) -> s::Field {
+        s::Field {
+            position: Pos::default(),
+            description: Some(description),
+            name,
+            arguments: vec![],
+            field_type,
+            directives: vec![],
+        }
+    }
+
+    fn named(name: &str) -> s::Type {
+        s::Type::NamedType(name.to_string())
+    }
+
+    fn non_null(name: &str) -> s::Type {
+        s::Type::NonNullType(Box::new(named(name)))
+    }
+
+    let period = interval.suffix().to_lowercase();
+    let mut fields = vec![
+        field(
+            "id".to_string(),
+            format!("The start of the {} as a string", period),
+            non_null("ID"),
+        ),
+        field(
+            TIMESTAMP_FIELD.to_string(),
+            format!("The start of the {} in seconds since the epoch", period),
+            non_null("Int"),
+        ),
+        field(
+            "count".to_string(),
+            format!("The number of data points in the {}", period),
+            non_null("Int"),
+        ),
+    ];
+    for rollup_field in &timeseries.fields {
+        let value_type = rollup_field.value_type();
+        let name = &rollup_field.name;
+        fields.push(field(
+            rollup_field.sum(),
+            format!("The sum of `{}`", name),
+            named(rollup_field.sum_type()),
+        ));
+        fields.push(field(
+            rollup_field.min(),
+            format!("The minimum of `{}`", name),
+            named(value_type),
+        ));
+        fields.push(field(
+            rollup_field.max(),
+            format!("The maximum of `{}`", name),
+            named(value_type),
+        ));
+        fields.push(field(
+            rollup_field.last(),
+            format!("The value of `{}` for the last data point", name),
+            named(value_type),
+        ));
+    }
+
+    s::ObjectType {
+        position: Pos::default(),
+        description: Some(format!(
+            "Rollups of `{}` for each {}",
+            timeseries.entity_type, period
+        )),
+        name: timeseries.rollup_type(interval).into_string(),
+        implements_interfaces: vec![],
+        directives: vec![s::Directive {
+            position: Pos::default(),
+            name: "entity".to_string(),
+            arguments: vec![],
+        }],
+        fields,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prelude::BigDecimal;
+
+    #[test]
+    fn interval_start() {
+        assert_eq!(3600, Interval::Hour.start(7199));
+        assert_eq!(7200, Interval::Hour.start(7200));
+        assert_eq!(0, Interval::Day.start(86399));
+        assert_eq!(-86400, Interval::Day.start(-1));
+    }
+
+    #[test]
+    fn add_data_points() {
+        let document = graphql_parser::parse_schema::<String>(
+            "type Trade @entity(timeseries: true) {
+                id: ID!
+                timestamp: Int!
+                amount: Int!
+                price: BigDecimal
+                trader: String!
+            }",
+        )
+        .unwrap();
+        let timeseries = Timeseries::for_document(&document);
+        assert_eq!(1, timeseries.len());
+        let timeseries = &timeseries[0];
+        assert_eq!(
+            vec!["amount", "price"],
+            timeseries
+                .fields
+                .iter()
+                .map(|field| field.name.as_str())
+                .collect::<Vec<_>>()
+        );
+
+        let point = |amount: i32, price: Option<&str>| {
+            let mut point = Entity::new();
+            point.set(TIMESTAMP_FIELD, 3700);
+            point.set("amount", amount);
+            if let Some(price) = price {
+                point.set("price", BigDecimal::from_str(price).unwrap());
+            }
+            point
+        };
+
+        let rollup = timeseries.add(None, 3600, &point(5, Some("1.5")));
+        let rollup = timeseries.add(Some(rollup), 3600, &point(3, None));
+        let rollup = timeseries.add(Some(rollup), 3600, &point(7, Some("0.5")));
+
+        assert_eq!(Some(&Value::from("3600")), rollup.get("id"));
+        assert_eq!(Some(&Value::Int(3600)), rollup.get(TIMESTAMP_FIELD));
+        assert_eq!(Some(&Value::Int(3)), rollup.get("count"));
+        assert_eq!(
+            Some(&Value::BigInt(BigInt::from(15))),
+            rollup.get("amountSum")
+        );
+        assert_eq!(Some(&Value::Int(3)), rollup.get("amountMin"));
+        assert_eq!(Some(&Value::Int(7)), rollup.get("amountMax"));
+        assert_eq!(Some(&Value::Int(7)), rollup.get("amountLast"));
+        assert_eq!(
+            Some(&Value::BigDecimal(BigDecimal::from_str("2").unwrap())),
+            rollup.get("priceSum")
+        );
+        assert_eq!(
+            Some(&Value::BigDecimal(BigDecimal::from_str("0.5").unwrap())),
+            rollup.get("priceMin")
+        );
+        assert_eq!(
+            Some(&Value::BigDecimal(BigDecimal::from_str("1.5").unwrap())),
+            rollup.get("priceMax")
+        );
+
+        let mut document = document;
+        add_rollup_types(&mut document).unwrap();
+        assert!(document.get_object_type_definition("TradeHour").is_some());
+        assert!(document.get_object_type_definition("TradeDay").is_some());
+
+        // Adding the rollup types again, for example to the schema as it
+        // was stored, leaves the document alone
+        let types = document.definitions.len();
+        add_rollup_types(&mut document).unwrap();
+        let mut document = graphql_parser::parse_schema::<String>(&document.to_string())
+            .unwrap()
+            .into_static();
+        add_rollup_types(&mut document).unwrap();
+        assert_eq!(types, document.definitions.len());
+    }
+}
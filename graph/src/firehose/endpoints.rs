@@ -3,7 +3,11 @@ use anyhow::Context;
 use http::uri::{Scheme, Uri};
 use rand::prelude::IteratorRandom;
 use slog::Logger;
-use std::{collections::BTreeMap, fmt::Display, sync::Arc};
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    sync::{Arc, RwLock},
+};
 use tonic::{
     metadata::MetadataValue,
     transport::{Channel, ClientTlsConfig},
@@ -16,7 +20,7 @@ use super::bstream;
 pub struct FirehoseEndpoint {
     pub provider: String,
     pub uri: String,
-    pub token: Option<String>,
+    token: Arc<RwLock<Option<String>>>,
     channel: Channel,
     logger: Logger,
 }
@@ -60,16 +64,23 @@ impl FirehoseEndpoint {
             provider: provider.as_ref().to_string(),
             uri,
             channel,
-            token,
+            token: Arc::new(RwLock::new(token)),
             logger,
         })
     }
 
+    /// Use `token` to authenticate requests for streams that are started
+    /// after this call
+    pub fn set_token(&self, token: Option<String>) {
+        *self.token.write().unwrap() = token;
+    }
+
     pub async fn stream_blocks(
         self: Arc<Self>,
         request: bstream::BlocksRequestV2,
     ) -> Result<tonic::Streaming<bstream::BlockResponseV2>, anyhow::Error> {
-        let token_metadata = match self.token.clone() {
+        let token = self.token.read().unwrap().clone();
+        let token_metadata = match token {
             Some(token) => Some(MetadataValue::from_str(token.as_str())?),
             None => None,
         };
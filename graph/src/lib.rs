@@ -127,9 +127,9 @@ pub mod prelude {
         BLOCK_NUMBER_MAX, SUBSCRIPTION_THROTTLE_INTERVAL,
     };
     pub use crate::components::subgraph::{
-        BlockState, DataSourceTemplateInfo, HostMetrics, RuntimeHost, RuntimeHostBuilder,
-        SubgraphAssignmentProvider, SubgraphInstanceManager, SubgraphRegistrar,
-        SubgraphVersionSwitchingMode,
+        BlockState, DataSourceTemplateInfo, EntityCacheControl, HostMetrics, RuntimeHost,
+        RuntimeHostBuilder, SubgraphAssignmentProvider, SubgraphInstanceManager, SubgraphRegistrar,
+        SubgraphVersionSwitchingMode, TransactionSimulator,
     };
     pub use crate::components::{transaction_receipt, EventConsumer, EventProducer};
 
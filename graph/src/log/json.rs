@@ -0,0 +1,153 @@
+use std::fmt;
+use std::io::{self, Write};
+
+use chrono::prelude::{SecondsFormat, Utc};
+use serde_json::{Map, Value};
+use slog::*;
+
+/// An slog `Drain` that writes each log record to stderr as a JSON object
+/// on a single line. Key values of the record and its loggers become
+/// fields of the object; the subgraph ID and the component hierarchy are
+/// written to `subgraph_id` and `component` like in the text format
+pub struct JsonFormat;
+
+impl Drain for JsonFormat {
+    type Ok = ();
+    type Err = io::Error;
+
+    fn log(&self, record: &Record, values: &OwnedKVList) -> Result<Self::Ok, Self::Err> {
+        let mut serializer = JsonSerializer::new();
+        record.kv().serialize(record, &mut serializer)?;
+        values.serialize(record, &mut serializer)?;
+        let (mut fields, components) = serializer.finish();
+
+        fields.insert(
+            "time".to_string(),
+            Value::String(Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true)),
+        );
+        fields.insert(
+            "level".to_string(),
+            Value::String(record.level().as_str().to_string()),
+        );
+        fields.insert("msg".to_string(), Value::String(record.msg().to_string()));
+        if !components.is_empty() {
+            fields.insert(
+                "component".to_string(),
+                Value::String(components.join(" > ")),
+            );
+        }
+
+        let mut line = serde_json::to_vec(&fields)?;
+        line.push(b'\n');
+        // Write the line with a single call so that lines from different
+        // threads do not get mixed up
+        io::stderr().lock().write_all(&line)
+    }
+}
+
+struct JsonSerializer {
+    fields: Map<String, Value>,
+    components: Vec<String>,
+}
+
+impl JsonSerializer {
+    fn new() -> Self {
+        Self {
+            fields: Map::new(),
+            components: vec![],
+        }
+    }
+
+    fn finish(mut self) -> (Map<String, Value>, Vec<String>) {
+        // Reverse components so the parent components come first
+        self.components.reverse();
+        (self.fields, self.components)
+    }
+
+    fn emit(&mut self, key: Key, value: Value) -> slog::Result {
+        if key == "component" {
+            if let Value::String(component) = value {
+                self.components.push(component);
+            }
+        } else {
+            // Keys of the record come before those of its loggers and take
+            // precedence over them
+            self.fields.entry(key.to_string()).or_insert(value);
+        }
+        Ok(())
+    }
+}
+
+impl ser::Serializer for JsonSerializer {
+    fn emit_none(&mut self, key: Key) -> slog::Result {
+        self.emit(key, Value::Null)
+    }
+
+    fn emit_unit(&mut self, key: Key) -> slog::Result {
+        self.emit(key, Value::Null)
+    }
+
+    fn emit_bool(&mut self, key: Key, val: bool) -> slog::Result {
+        self.emit(key, Value::from(val))
+    }
+
+    fn emit_char(&mut self, key: Key, val: char) -> slog::Result {
+        self.emit(key, Value::String(val.to_string()))
+    }
+
+    fn emit_usize(&mut self, key: Key, val: usize) -> slog::Result {
+        self.emit(key, Value::from(val))
+    }
+
+    fn emit_isize(&mut self, key: Key, val: isize) -> slog::Result {
+        self.emit(key, Value::from(val))
+    }
+
+    fn emit_u8(&mut self, key: Key, val: u8) -> slog::Result {
+        self.emit(key, Value::from(val))
+    }
+
+    fn emit_i8(&mut self, key: Key, val: i8) -> slog::Result {
+        self.emit(key, Value::from(val))
+    }
+
+    fn emit_u16(&mut self, key: Key, val: u16) -> slog::Result {
+        self.emit(key, Value::from(val))
+    }
+
+    fn emit_i16(&mut self, key: Key, val: i16) -> slog::Result {
+        self.emit(key, Value::from(val))
+    }
+
+    fn emit_u32(&mut self, key: Key, val: u32) -> slog::Result {
+        self.emit(key, Value::from(val))
+    }
+
+    fn emit_i32(&mut self, key: Key, val: i32) -> slog::Result {
+        self.emit(key, Value::from(val))
+    }
+
+    fn emit_f32(&mut self, key: Key, val: f32) -> slog::Result {
+        self.emit(key, Value::from(val))
+    }
+
+    fn emit_u64(&mut self, key: Key, val: u64) -> slog::Result {
+        self.emit(key, Value::from(val))
+    }
+
+    fn emit_i64(&mut self, key: Key, val: i64) -> slog::Result {
+        self.emit(key, Value::from(val))
+    }
+
+    fn emit_f64(&mut self, key: Key, val: f64) -> slog::Result {
+        self.emit(key, Value::from(val))
+    }
+
+    fn emit_str(&mut self, key: Key, val: &str) -> slog::Result {
+        self.emit(key, Value::String(val.to_string()))
+    }
+
+    fn emit_arguments(&mut self, key: Key, val: &fmt::Arguments) -> slog::Result {
+        self.emit(key, Value::String(val.to_string()))
+    }
+}
@@ -28,12 +28,60 @@ use std::{env, fmt, io, result};
 pub mod codes;
 pub mod elastic;
 pub mod factory;
+pub mod json;
+pub mod sampling;
 pub mod split;
 
+/// How log messages are written to stderr
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human readable text, colored when running in a terminal
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+impl std::str::FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!(
+                "invalid log format `{}`, must be one of text or json",
+                s
+            )),
+        }
+    }
+}
+
 pub fn logger(show_debug: bool) -> Logger {
-    let use_color = isatty::stdout_isatty();
-    let decorator = slog_term::TermDecorator::new().build();
-    let drain = CustomFormat::new(decorator, use_color).fuse();
+    logger_with_format(show_debug, LogFormat::Text)
+}
+
+pub fn logger_with_format(show_debug: bool, format: LogFormat) -> Logger {
+    match format {
+        LogFormat::Text => {
+            let use_color = isatty::stdout_isatty();
+            let decorator = slog_term::TermDecorator::new().build();
+            root_logger(CustomFormat::new(decorator, use_color).fuse(), show_debug)
+        }
+        LogFormat::Json => root_logger(json::JsonFormat.fuse(), show_debug),
+    }
+}
+
+fn root_logger<D>(drain: D, show_debug: bool) -> Logger
+where
+    D: Drain<Ok = (), Err = Never> + Send + 'static,
+{
+    let rates = env::var("GRAPH_LOG_SAMPLING")
+        .ok()
+        .map(|spec| {
+            sampling::parse_rates(&spec).unwrap_or_else(|e| panic!("GRAPH_LOG_SAMPLING: {}", e))
+        })
+        .unwrap_or_default();
+    let drain = sampling::Sampling::new(drain, rates);
     let drain = slog_envlogger::LogBuilder::new(drain)
         .filter(
             None,
@@ -0,0 +1,99 @@
+use rand::Rng;
+use slog::*;
+
+/// Parse sampling rates in the form `module=rate,...`
+pub fn parse_rates(spec: &str) -> std::result::Result<Vec<(String, f64)>, String> {
+    spec.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let mut parts = entry.splitn(2, '=');
+            let module = parts.next().unwrap_or("").trim();
+            let rate = parts.next().map(str::trim);
+            match rate.map(|rate| rate.parse::<f64>()) {
+                Some(Ok(rate)) if !module.is_empty() && (0.0..=1.0).contains(&rate) => {
+                    Ok((module.to_string(), rate))
+                }
+                _ => Err(format!(
+                    "invalid log sampling rate `{}`, must be `module=rate` \
+                     with a rate between 0 and 1",
+                    entry
+                )),
+            }
+        })
+        .collect()
+}
+
+/// An slog `Drain` that only passes on a fraction of the debug and trace
+/// messages of some modules. Sampling rates are given as a list
+/// `module=rate,...`, for example `graph_chain_ethereum=0.01`; the rate
+/// for a message comes from the longest module path that is the message's
+/// module or one of its parents. Messages at level info and above are always
+/// passed on
+pub struct Sampling<D: Drain> {
+    drain: D,
+    rates: Vec<(String, f64)>,
+}
+
+impl<D: Drain> Sampling<D> {
+    pub fn new(drain: D, rates: Vec<(String, f64)>) -> Self {
+        Sampling { drain, rates }
+    }
+
+    fn rate(&self, module: &str) -> Option<f64> {
+        self.rates
+            .iter()
+            .filter(|(prefix, _)| {
+                module == prefix.as_str()
+                    || (module.starts_with(prefix.as_str())
+                        && module[prefix.len()..].starts_with("::"))
+            })
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, rate)| *rate)
+    }
+}
+
+impl<D: Drain<Ok = ()>> Drain for Sampling<D> {
+    type Ok = ();
+    type Err = D::Err;
+
+    fn log(
+        &self,
+        record: &Record,
+        values: &OwnedKVList,
+    ) -> std::result::Result<Self::Ok, Self::Err> {
+        if !record.level().is_at_least(Level::Info) {
+            if let Some(rate) = self.rate(record.module()) {
+                if rand::thread_rng().gen::<f64>() >= rate {
+                    return Ok(());
+                }
+            }
+        }
+        self.drain.log(record, values)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_rates() {
+        let rates = parse_rates("graph_chain_ethereum=0.01, graph=1").unwrap();
+        assert_eq!(
+            vec![
+                ("graph_chain_ethereum".to_string(), 0.01),
+                ("graph".to_string(), 1.0)
+            ],
+            rates
+        );
+        assert!(parse_rates("graph").is_err());
+        assert!(parse_rates("graph=2").is_err());
+        assert!(parse_rates("=0.5").is_err());
+
+        let sampling = Sampling::new(Discard, rates);
+        assert_eq!(Some(0.01), sampling.rate("graph_chain_ethereum::adapter"));
+        assert_eq!(Some(1.0), sampling.rate("graph::log"));
+        assert_eq!(None, sampling.rate("graph_core"));
+    }
+}
@@ -9,6 +9,7 @@ mod asc_ptr;
 pub use asc_heap::{asc_get, asc_new, try_asc_get, AscHeap, FromAscObj, ToAscObj, TryFromAscObj};
 pub use asc_ptr::AscPtr;
 
+use crate::data::query::QueryExecutionError;
 use anyhow::Error;
 use semver::Version;
 use std::convert::TryInto;
@@ -238,6 +239,12 @@ pub enum IndexForAscTypeId {
     NearChunkHeader = 84,
     NearBlock = 85,
     NearReceiptWithOutcome = 86,
+
+    // Reserved discriminant space for Ethereum type IDs: [1,000, 1,499]
+    TransactionReceipt = 1000,
+    Log = 1001,
+    ArrayH256 = 1002,
+    ArrayLog = 1003,
 }
 
 impl ToAscObj<u32> for IndexForAscTypeId {
@@ -270,6 +277,12 @@ pub enum HostExportError {
 
     #[error("{0:#}")]
     Deterministic(anyhow::Error),
+
+    /// The call failed because of a problem that usually goes away on its
+    /// own, like the database being briefly unavailable. The runtime
+    /// retries such calls before failing the handler
+    #[error("{0:#}")]
+    Transient(anyhow::Error),
 }
 
 impl From<anyhow::Error> for HostExportError {
@@ -278,6 +291,16 @@ impl From<anyhow::Error> for HostExportError {
     }
 }
 
+impl From<QueryExecutionError> for HostExportError {
+    fn from(e: QueryExecutionError) -> Self {
+        if e.is_transient() {
+            HostExportError::Transient(e.into())
+        } else {
+            HostExportError::Unknown(e.into())
+        }
+    }
+}
+
 impl From<DeterministicHostError> for HostExportError {
     fn from(value: DeterministicHostError) -> Self {
         HostExportError::Deterministic(value.0)
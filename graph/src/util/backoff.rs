@@ -33,7 +33,9 @@ impl ExponentialBackoff {
     }
 
     pub fn delay(&self) -> Duration {
-        let mut delay = self.base.saturating_mul(1 << self.attempt);
+        // Avoid overflowing the shift after many attempts
+        let factor = 1u32.checked_shl(self.attempt as u32).unwrap_or(u32::MAX);
+        let mut delay = self.base.saturating_mul(factor);
         if delay > self.ceiling {
             delay = self.ceiling;
         }
@@ -0,0 +1,379 @@
+//! A memory budget that is shared by the in-process caches. When
+//! `GRAPH_CACHE_MEMORY_BUDGET` is set, each kind of cache gets a share of
+//! that budget according to its weight, and caches with several instances,
+//! like the per-deployment entity caches, split their share evenly between
+//! all live instances. Without a budget, every cache keeps using its own
+//! configured size.
+//!
+//! Compiled WASM modules can not be evicted while a subgraph uses them.
+//! The memory they use is therefore taken off the top of the budget, and
+//! the caches that can evict entries split what is left.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::RwLock;
+
+use lazy_static::lazy_static;
+use prometheus::{CounterVec, GaugeVec};
+
+use crate::components::metrics::MetricsRegistry;
+
+lazy_static! {
+    pub static ref MEMORY_BUDGET: MemoryBudget = MemoryBudget::from_env();
+}
+
+/// The kinds of caches that are managed by the memory budget
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum CacheKind {
+    /// The entity caches used while indexing, one per deployment
+    Entity,
+    /// The GraphQL query result caches
+    Query,
+    /// The in-memory index of recent blocks, one per chain
+    Block,
+    /// The compiled WASM modules of subgraphs, one cache per subgraph
+    Module,
+}
+
+impl CacheKind {
+    const ALL: [CacheKind; 4] = [
+        CacheKind::Entity,
+        CacheKind::Query,
+        CacheKind::Block,
+        CacheKind::Module,
+    ];
+
+    fn default_weight(&self) -> usize {
+        match self {
+            CacheKind::Entity => 1,
+            CacheKind::Query => 1,
+            CacheKind::Block => 1,
+            CacheKind::Module => 0,
+        }
+    }
+
+    /// Whether caches of this kind can evict entries to stay within
+    /// their share of the budget
+    fn evictable(&self) -> bool {
+        !matches!(self, CacheKind::Module)
+    }
+}
+
+impl fmt::Display for CacheKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CacheKind::Entity => write!(f, "entity"),
+            CacheKind::Query => write!(f, "query"),
+            CacheKind::Block => write!(f, "block"),
+            CacheKind::Module => write!(f, "module"),
+        }
+    }
+}
+
+impl FromStr for CacheKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "entity" => Ok(CacheKind::Entity),
+            "query" => Ok(CacheKind::Query),
+            "block" => Ok(CacheKind::Block),
+            _ => Err(format!("unknown cache kind `{}`", s)),
+        }
+    }
+}
+
+struct CacheBudget {
+    weight: usize,
+    instances: AtomicUsize,
+    used: AtomicUsize,
+}
+
+struct BudgetMetrics {
+    used: Box<GaugeVec>,
+    limit: Box<GaugeVec>,
+    evicted: CounterVec,
+}
+
+pub struct MemoryBudget {
+    /// The total budget in bytes, if there is one
+    total: Option<usize>,
+    caches: HashMap<CacheKind, CacheBudget>,
+    metrics: RwLock<Option<BudgetMetrics>>,
+}
+
+impl MemoryBudget {
+    fn from_env() -> Self {
+        // The env var is in MB
+        let total = std::env::var("GRAPH_CACHE_MEMORY_BUDGET").ok().map(|s| {
+            1_000_000
+                * s.parse::<usize>()
+                    .expect("invalid GRAPH_CACHE_MEMORY_BUDGET")
+        });
+        let weights = std::env::var("GRAPH_CACHE_MEMORY_WEIGHTS")
+            .ok()
+            .map(|s| parse_weights(&s).expect("invalid GRAPH_CACHE_MEMORY_WEIGHTS"))
+            .unwrap_or_default();
+        Self::new(total, weights)
+    }
+
+    pub fn new(total: Option<usize>, weights: HashMap<CacheKind, usize>) -> Self {
+        let caches = CacheKind::ALL
+            .iter()
+            .map(|kind| {
+                let budget = CacheBudget {
+                    weight: weights
+                        .get(kind)
+                        .cloned()
+                        .unwrap_or_else(|| kind.default_weight()),
+                    instances: AtomicUsize::new(0),
+                    used: AtomicUsize::new(0),
+                };
+                (*kind, budget)
+            })
+            .collect();
+        MemoryBudget {
+            total,
+            caches,
+            metrics: RwLock::new(None),
+        }
+    }
+
+    /// The part of the total budget that caches of `kind` may use
+    /// together, or `None` if no budget was configured. Caches that can
+    /// not evict entries get no share; what they use is taken off the
+    /// total before it is split
+    pub fn share(&self, kind: CacheKind) -> Option<usize> {
+        if !kind.evictable() {
+            return self.total.map(|_| 0);
+        }
+        let total_weight: usize = self
+            .caches
+            .iter()
+            .filter(|(kind, _)| kind.evictable())
+            .map(|(_, cache)| cache.weight)
+            .sum();
+        let fixed: usize = self
+            .caches
+            .iter()
+            .filter(|(kind, _)| !kind.evictable())
+            .map(|(_, cache)| cache.used.load(Ordering::SeqCst))
+            .sum();
+        self.total.map(|total| {
+            total.saturating_sub(fixed) * self.caches[&kind].weight / total_weight.max(1)
+        })
+    }
+
+    /// Register a new instance of a cache of `kind`. The instance counts
+    /// against the budget until the returned handle is dropped.
+    pub fn register(&'static self, kind: CacheKind) -> BudgetHandle {
+        self.caches[&kind].instances.fetch_add(1, Ordering::SeqCst);
+        self.update_limit_metric(kind);
+        BudgetHandle {
+            budget: self,
+            kind,
+            used: AtomicUsize::new(0),
+        }
+    }
+
+    /// Register metrics for cache memory usage with `registry`
+    pub fn register_metrics(&self, registry: &dyn MetricsRegistry) {
+        let used = registry
+            .new_gauge_vec(
+                "cache_memory_used",
+                "Estimated memory used by each kind of in-process cache, in bytes",
+                vec!["cache".to_string()],
+            )
+            .expect("failed to create `cache_memory_used` gauge");
+        let limit = registry
+            .new_gauge_vec(
+                "cache_memory_limit",
+                "Memory limit for a single instance of each kind of in-process cache, in bytes",
+                vec!["cache".to_string()],
+            )
+            .expect("failed to create `cache_memory_limit` gauge");
+        let evicted = registry
+            .global_counter_vec(
+                "cache_memory_evicted",
+                "Estimated memory freed by evicting entries from in-process caches, in bytes",
+                &["cache"],
+            )
+            .expect("failed to create `cache_memory_evicted` counter");
+        *self.metrics.write().unwrap() = Some(BudgetMetrics {
+            used,
+            limit,
+            evicted,
+        });
+        for kind in CacheKind::ALL.iter() {
+            self.update_limit_metric(*kind);
+        }
+    }
+
+    fn instance_limit(&self, kind: CacheKind) -> Option<usize> {
+        let instances = self.caches[&kind].instances.load(Ordering::SeqCst);
+        self.share(kind).map(|share| share / instances.max(1))
+    }
+
+    fn update_limit_metric(&self, kind: CacheKind) {
+        if let (Some(metrics), Some(limit)) = (
+            self.metrics.read().unwrap().as_ref(),
+            self.instance_limit(kind),
+        ) {
+            metrics
+                .limit
+                .with_label_values(&[&kind.to_string()])
+                .set(limit as f64);
+        }
+    }
+
+    fn update_used(&self, kind: CacheKind, old: usize, new: usize, evicted: usize) {
+        let cache = &self.caches[&kind];
+        cache.used.fetch_add(new, Ordering::SeqCst);
+        let used = cache.used.fetch_sub(old, Ordering::SeqCst) - old;
+        if let Some(metrics) = self.metrics.read().unwrap().as_ref() {
+            let label = kind.to_string();
+            metrics.used.with_label_values(&[&label]).set(used as f64);
+            if evicted > 0 {
+                metrics
+                    .evicted
+                    .with_label_values(&[&label])
+                    .inc_by(evicted as f64);
+            }
+        }
+        if !kind.evictable() {
+            // What the other caches may use just changed
+            for kind in CacheKind::ALL.iter().filter(|kind| kind.evictable()) {
+                self.update_limit_metric(*kind);
+            }
+        }
+    }
+}
+
+/// The share of the memory budget for one instance of a cache
+pub struct BudgetHandle {
+    budget: &'static MemoryBudget,
+    kind: CacheKind,
+    /// The memory use that was last reported for this instance
+    used: AtomicUsize,
+}
+
+impl BudgetHandle {
+    /// The maximum weight this cache instance should have. That is
+    /// `default` if there is no memory budget.
+    pub fn limit(&self, default: usize) -> usize {
+        self.budget.instance_limit(self.kind).unwrap_or(default)
+    }
+
+    /// Report that the cache instance now uses `used` bytes after
+    /// evicting `evicted` bytes.
+    pub fn report(&self, used: usize, evicted: usize) {
+        let old = self.used.swap(used, Ordering::SeqCst);
+        self.budget.update_used(self.kind, old, used, evicted);
+    }
+}
+
+impl Drop for BudgetHandle {
+    fn drop(&mut self) {
+        self.report(0, 0);
+        self.budget.caches[&self.kind]
+            .instances
+            .fetch_sub(1, Ordering::SeqCst);
+        self.budget.update_limit_metric(self.kind);
+    }
+}
+
+/// Parse weights of the form `entity=3,query=1,block=1`
+fn parse_weights(s: &str) -> Result<HashMap<CacheKind, usize>, String> {
+    s.split(',')
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let mut parts = pair.splitn(2, '=');
+            let kind = parts.next().unwrap_or("").trim().parse::<CacheKind>()?;
+            let weight = parts
+                .next()
+                .ok_or_else(|| format!("missing weight for `{}`", kind))?
+                .trim()
+                .parse::<usize>()
+                .map_err(|e| format!("invalid weight for `{}`: {}", kind, e))?;
+            Ok((kind, weight))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn weights() {
+        let weights = parse_weights("entity=3, query=1").unwrap();
+        assert_eq!(Some(&3), weights.get(&CacheKind::Entity));
+        assert_eq!(Some(&1), weights.get(&CacheKind::Query));
+
+        assert!(parse_weights("blocks=1").is_err());
+        assert!(parse_weights("module=1").is_err());
+        assert!(parse_weights("entity").is_err());
+    }
+
+    #[test]
+    fn shares() {
+        let weights = parse_weights("entity=3,query=1,block=0").unwrap();
+        let budget = MemoryBudget::new(Some(4000), weights);
+        assert_eq!(Some(3000), budget.share(CacheKind::Entity));
+        assert_eq!(Some(1000), budget.share(CacheKind::Query));
+        assert_eq!(Some(0), budget.share(CacheKind::Block));
+        assert_eq!(Some(0), budget.share(CacheKind::Module));
+
+        let budget = MemoryBudget::new(None, HashMap::new());
+        assert_eq!(None, budget.share(CacheKind::Entity));
+    }
+
+    #[test]
+    fn instances_split_share() {
+        lazy_static! {
+            static ref BUDGET: MemoryBudget = MemoryBudget::new(Some(2000), HashMap::new());
+        }
+
+        let first = BUDGET.register(CacheKind::Entity);
+        assert_eq!(1000, first.limit(17));
+        let second = BUDGET.register(CacheKind::Entity);
+        assert_eq!(500, first.limit(17));
+        first.report(300, 0);
+        second.report(100, 0);
+        assert_eq!(
+            400,
+            BUDGET.caches[&CacheKind::Entity]
+                .used
+                .load(Ordering::SeqCst)
+        );
+        drop(second);
+        assert_eq!(1000, first.limit(17));
+        assert_eq!(
+            300,
+            BUDGET.caches[&CacheKind::Entity]
+                .used
+                .load(Ordering::SeqCst)
+        );
+    }
+
+    #[test]
+    fn modules_reduce_shares() {
+        lazy_static! {
+            static ref BUDGET: MemoryBudget = MemoryBudget::new(
+                Some(3000),
+                parse_weights("entity=1,query=1,block=1").unwrap()
+            );
+        }
+
+        assert_eq!(Some(1000), BUDGET.share(CacheKind::Entity));
+        let modules = BUDGET.register(CacheKind::Module);
+        modules.report(600, 0);
+        assert_eq!(Some(800), BUDGET.share(CacheKind::Entity));
+        assert_eq!(Some(800), BUDGET.share(CacheKind::Block));
+        drop(modules);
+        assert_eq!(Some(1000), BUDGET.share(CacheKind::Query));
+    }
+}
@@ -18,5 +18,7 @@ pub mod timed_rw_lock;
 
 pub mod jobs;
 
+pub mod memory_budget;
+
 /// Increasingly longer sleeps to back off some repeated operation
 pub mod backoff;
@@ -227,6 +227,15 @@ impl QueryBlockCache {
         cache_insert
     }
 
+    /// The total weight of the results in this cache
+    pub fn weight(&self) -> usize {
+        self.cache_by_network
+            .iter()
+            .flat_map(|(_, cache)| cache.iter())
+            .map(|cache_by_block| cache_by_block.weight)
+            .sum()
+    }
+
     pub fn get(
         &self,
         network: &str,
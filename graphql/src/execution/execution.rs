@@ -19,6 +19,7 @@ use graph::data::graphql::*;
 use graph::data::query::CacheStatus;
 use graph::prelude::*;
 use graph::util::lfu_cache::LfuCache;
+use graph::util::memory_budget::{BudgetHandle, CacheKind, MEMORY_BUDGET};
 
 use super::QueryHash;
 use crate::introspection::{
@@ -56,13 +57,16 @@ lazy_static! {
 
     /// Maximum total memory to be used by the cache. Each block has a max size of
     /// `QUERY_CACHE_MAX_MEM` / (`QUERY_CACHE_BLOCKS` * `GRAPH_QUERY_BLOCK_CACHE_SHARDS`).
-    /// The env var is in MB.
+    /// The env var is in MB. If there is a global cache memory budget, the query cache's
+    /// share of it is split evenly between the block cache and the LFU cache.
     static ref QUERY_CACHE_MAX_MEM: usize = {
-        1_000_000 *
-        std::env::var("GRAPH_QUERY_CACHE_MAX_MEM")
-        .unwrap_or("1000".to_string())
-        .parse::<usize>()
-        .expect("Invalid value for GRAPH_QUERY_CACHE_MAX_MEM environment variable")
+        MEMORY_BUDGET.share(CacheKind::Query).map(|share| share / 2).unwrap_or_else(|| {
+            1_000_000 *
+            std::env::var("GRAPH_QUERY_CACHE_MAX_MEM")
+            .unwrap_or("1000".to_string())
+            .parse::<usize>()
+            .expect("Invalid value for GRAPH_QUERY_CACHE_MAX_MEM environment variable")
+        })
     };
 
     static ref QUERY_CACHE_STALE_PERIOD: u64 = {
@@ -107,11 +111,19 @@ lazy_static! {
             }
             caches
     };
+    static ref QUERY_BLOCK_CACHE_BUDGET: Vec<BudgetHandle> = {
+        std::iter::repeat_with(|| MEMORY_BUDGET.register(CacheKind::Query))
+                    .take(*QUERY_BLOCK_CACHE_SHARDS as usize).collect()
+    };
     static ref QUERY_HERD_CACHE: QueryCache<Arc<QueryResult>> = QueryCache::new("query_herd_cache");
     static ref QUERY_LFU_CACHE: Vec<TimedMutex<LfuCache<QueryHash, WeightedResult>>> = {
         std::iter::repeat_with(|| TimedMutex::new(LfuCache::new(), "query_lfu_cache"))
                     .take(*QUERY_LFU_CACHE_SHARDS as usize).collect()
     };
+    static ref QUERY_LFU_CACHE_BUDGET: Vec<BudgetHandle> = {
+        std::iter::repeat_with(|| MEMORY_BUDGET.register(CacheKind::Query))
+                    .take(*QUERY_LFU_CACHE_SHARDS as usize).collect()
+    };
 }
 
 struct WeightedResult {
@@ -342,6 +354,25 @@ pub fn execute_root_selection_set_uncached(
     Ok(values)
 }
 
+/// Cancels what the resolver of a query is doing when it is dropped before
+/// it was disarmed, i.e., when the future that runs the query is dropped
+/// because nobody is waiting for its result anymore
+struct CancelOnDrop<R: Resolver>(Option<Arc<ExecutionContext<R>>>);
+
+impl<R: Resolver> CancelOnDrop<R> {
+    fn disarm(mut self) {
+        self.0 = None;
+    }
+}
+
+impl<R: Resolver> Drop for CancelOnDrop<R> {
+    fn drop(&mut self) {
+        if let Some(ctx) = &self.0 {
+            ctx.resolver.cancel();
+        }
+    }
+}
+
 /// Executes the root selection set of a query.
 pub async fn execute_root_selection_set<R: Resolver>(
     ctx: Arc<ExecutionContext<R>>,
@@ -389,25 +420,46 @@ pub async fn execute_root_selection_set<R: Resolver>(
     let execute_selection_set = selection_set.cheap_clone();
     let execute_root_type = root_type.cheap_clone();
     let run_query = async move {
-        let _permit = execute_ctx.resolver.query_permit().await;
+        let cancel = CancelOnDrop(Some(execute_ctx.cheap_clone()));
+        let deadline = execute_ctx.deadline;
 
         let logger = execute_ctx.logger.clone();
         let query_text = execute_ctx.query.query_text.cheap_clone();
         let variables_text = execute_ctx.query.variables_text.cheap_clone();
-        match graph::spawn_blocking_allow_panic(move || {
-            let mut query_res = QueryResult::from(execute_root_selection_set_uncached(
-                &execute_ctx,
-                &execute_selection_set,
-                &execute_root_type,
-            ));
-
-            // Unwrap: In practice should never fail, but if it does we will catch the panic.
-            execute_ctx.resolver.post_process(&mut query_res).unwrap();
-            query_res.deployment = Some(execute_ctx.query.schema.id().clone());
-            Arc::new(query_res)
-        })
-        .await
-        {
+        let execute = async move {
+            let permit = execute_ctx.resolver.query_permit().await;
+            graph::spawn_blocking_allow_panic(move || {
+                // Hold on to the permit until the work is done, even if
+                // nobody waits for its result anymore because the query
+                // timed out
+                let _permit = permit;
+                let mut query_res = QueryResult::from(execute_root_selection_set_uncached(
+                    &execute_ctx,
+                    &execute_selection_set,
+                    &execute_root_type,
+                ));
+
+                // Unwrap: In practice should never fail, but if it does we will catch the panic.
+                execute_ctx.resolver.post_process(&mut query_res).unwrap();
+                query_res.deployment = Some(execute_ctx.query.schema.id().clone());
+                Arc::new(query_res)
+            })
+            .await
+        };
+        // The deadline is also checked while the query runs, but that can
+        // not interrupt a slow database query; stop waiting for the result
+        // once the deadline has passed and let `cancel` stop the work that
+        // is still going on for the query
+        let result = match deadline {
+            Some(deadline) => match tokio::time::timeout_at(deadline.into(), execute).await {
+                Ok(result) => result,
+                Err(_) => return Arc::new(QueryResult::from(QueryExecutionError::Timeout)),
+            },
+            None => execute.await,
+        };
+        cancel.disarm();
+
+        match result {
             Ok(result) => result,
             Err(e) => {
                 let e = e.into_panic();
@@ -454,14 +506,23 @@ pub async fn execute_root_selection_set<R: Resolver>(
         // Calculate the weight outside the lock.
         let weight = result.weight();
         let shard = (key[0] as usize) % QUERY_BLOCK_CACHE.len();
-        let inserted = QUERY_BLOCK_CACHE[shard].lock(&ctx.logger).insert(
-            network,
-            block_ptr.clone(),
-            key,
-            result.cheap_clone(),
-            weight,
-            ctx.logger.cheap_clone(),
-        );
+        let inserted = {
+            let mut cache = QUERY_BLOCK_CACHE[shard].lock(&ctx.logger);
+            let before = cache.weight();
+            let inserted = cache.insert(
+                network,
+                block_ptr.clone(),
+                key,
+                result.cheap_clone(),
+                weight,
+                ctx.logger.cheap_clone(),
+            );
+            // Rotating out the oldest block evicts all its results
+            let after = cache.weight();
+            let added = if inserted { weight } else { 0 };
+            QUERY_BLOCK_CACHE_BUDGET[shard].report(after, (before + added).saturating_sub(after));
+            inserted
+        };
 
         if inserted {
             ctx.cache_status.store(CacheStatus::Insert);
@@ -469,7 +530,9 @@ pub async fn execute_root_selection_set<R: Resolver>(
             // Results that are too old for the QUERY_BLOCK_CACHE go into the QUERY_LFU_CACHE
             let mut cache = QUERY_LFU_CACHE[shard].lock(&ctx.logger);
             let max_mem = *QUERY_CACHE_MAX_MEM / (*QUERY_BLOCK_CACHE_SHARDS as usize);
-            cache.evict_with_period(max_mem, *QUERY_CACHE_STALE_PERIOD);
+            let evicted = cache
+                .evict_with_period(max_mem, *QUERY_CACHE_STALE_PERIOD)
+                .map_or(0, |(evicted, _, _)| evicted);
             cache.insert(
                 key,
                 WeightedResult {
@@ -477,6 +540,7 @@ pub async fn execute_root_selection_set<R: Resolver>(
                     weight,
                 },
             );
+            QUERY_LFU_CACHE_BUDGET[shard].report(cache.total_weight(), evicted);
             ctx.cache_status.store(CacheStatus::Insert);
         }
     }
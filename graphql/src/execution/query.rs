@@ -1,4 +1,5 @@
 use graphql_parser::Pos;
+use lazy_static::lazy_static;
 use std::collections::{HashMap, HashSet};
 use std::hash::{Hash, Hasher};
 use std::sync::Arc;
@@ -23,9 +24,20 @@ use crate::{
     schema::api::ErrorPolicy,
 };
 
+lazy_static! {
+    /// The maximum number of fields that a single selection set of a query
+    /// can select. Unlimited if not set
+    static ref MAX_BREADTH: Option<usize> = std::env::var("GRAPH_GRAPHQL_MAX_BREADTH")
+        .ok()
+        .map(|s| s
+            .parse::<usize>()
+            .unwrap_or_else(|_| panic!("failed to parse env var GRAPH_GRAPHQL_MAX_BREADTH")));
+}
+
 #[derive(Clone, Debug)]
 pub enum ComplexityError {
     TooDeep,
+    TooWide(usize),
     Overflow,
     Invalid,
     CyclicalFragment(String),
@@ -337,7 +349,9 @@ impl Query {
         max_complexity: Option<u64>,
         max_depth: u8,
     ) -> Result<(), Vec<QueryExecutionError>> {
-        let complexity = self.complexity(max_depth).map_err(|e| vec![e])?;
+        let complexity = self
+            .complexity(max_depth, *MAX_BREADTH)
+            .map_err(|e| vec![e])?;
         if let Some(max_complexity) = max_complexity {
             if complexity > max_complexity {
                 return Err(vec![QueryExecutionError::TooComplex(
@@ -354,19 +368,28 @@ impl Query {
     ///
     /// If the query is invalid, returns `Ok(0)` so that execution proceeds and
     /// gives a proper error.
-    fn complexity(&self, max_depth: u8) -> Result<u64, QueryExecutionError> {
+    fn complexity(
+        &self,
+        max_depth: u8,
+        max_breadth: Option<usize>,
+    ) -> Result<u64, QueryExecutionError> {
         let root_type = sast::get_root_query_type_def(self.schema.document()).unwrap();
 
         match self.complexity_inner(
             root_type,
             &self.selection_set,
             max_depth,
+            max_breadth,
             0,
             &HashSet::new(),
         ) {
             Ok(complexity) => Ok(complexity),
             Err(ComplexityError::Invalid) => Ok(0),
             Err(ComplexityError::TooDeep) => Err(QueryExecutionError::TooDeep(max_depth)),
+            Err(ComplexityError::TooWide(breadth)) => Err(QueryExecutionError::TooWide(
+                breadth,
+                max_breadth.unwrap_or(usize::MAX),
+            )),
             Err(ComplexityError::Overflow) => {
                 Err(QueryExecutionError::TooComplex(u64::max_value(), 0))
             }
@@ -464,11 +487,47 @@ impl Query {
             })
     }
 
+    /// Add the response keys of all fields that `selection_set` selects,
+    /// including the ones selected through fragments, to `response_keys`
+    fn collect_response_keys<'a>(
+        &'a self,
+        selection_set: &'a q::SelectionSet,
+        response_keys: &mut HashSet<&'a str>,
+        visited_fragments: &mut HashSet<&'a str>,
+    ) {
+        for selection in &selection_set.items {
+            match selection {
+                q::Selection::Field(field) => {
+                    response_keys.insert(field.alias.as_ref().unwrap_or(&field.name).as_str());
+                }
+                q::Selection::FragmentSpread(spread) => {
+                    if visited_fragments.insert(spread.fragment_name.as_str()) {
+                        if let Some(def) = self.fragments.get(&spread.fragment_name) {
+                            self.collect_response_keys(
+                                &def.selection_set,
+                                response_keys,
+                                visited_fragments,
+                            );
+                        }
+                    }
+                }
+                q::Selection::InlineFragment(fragment) => {
+                    self.collect_response_keys(
+                        &fragment.selection_set,
+                        response_keys,
+                        visited_fragments,
+                    );
+                }
+            }
+        }
+    }
+
     fn complexity_inner<'a>(
         &'a self,
         ty: &s::TypeDefinition,
         selection_set: &'a q::SelectionSet,
         max_depth: u8,
+        max_breadth: Option<usize>,
         depth: u8,
         visited_fragments: &'a HashSet<&'a str>,
     ) -> Result<u64, ComplexityError> {
@@ -478,6 +537,16 @@ impl Query {
             return Err(TooDeep);
         }
 
+        if let Some(max_breadth) = max_breadth {
+            // Count the fields that fragments contribute, too, the same way
+            // `collect_fields` merges them into one selection set
+            let mut response_keys = HashSet::new();
+            self.collect_response_keys(selection_set, &mut response_keys, &mut HashSet::new());
+            if response_keys.len() > max_breadth {
+                return Err(TooWide(response_keys.len()));
+            }
+        }
+
         selection_set
             .items
             .iter()
@@ -510,6 +579,7 @@ impl Query {
                                 .ok_or(Invalid)?,
                             &field.selection_set,
                             max_depth,
+                            max_breadth,
                             depth + 1,
                             visited_fragments,
                         )?;
@@ -519,10 +589,15 @@ impl Query {
                             return Ok(total_complexity + field_complexity);
                         }
 
-                        // For collection queries, check the `first` argument.
+                        // For collection queries, check the `first` argument,
+                        // which might be passed in a variable
                         let max_entities = qast::get_argument_value(&field.arguments, "first")
                             .and_then(|arg| match arg {
                                 q::Value::Int(n) => Some(n.as_i64()? as u64),
+                                q::Value::Variable(name) => match self.variables.get(name) {
+                                    Some(r::Value::Int(n)) => u64::try_from(*n).ok(),
+                                    _ => None,
+                                },
                                 _ => None,
                             })
                             .unwrap_or(100);
@@ -546,6 +621,7 @@ impl Query {
                             &ty,
                             &def.selection_set,
                             max_depth,
+                            max_breadth,
                             depth + 1,
                             &visited_fragments,
                         )
@@ -561,6 +637,7 @@ impl Query {
                             &ty,
                             &fragment.selection_set,
                             max_depth,
+                            max_breadth,
                             depth + 1,
                             visited_fragments,
                         )
@@ -650,3 +727,49 @@ fn coerce_variable(
         )]
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use graph::data::query::Query as GraphDataQuery;
+    use graph::data::schema::{ApiSchema, Schema};
+    use graph::prelude::{DeploymentHash, QueryExecutionError};
+
+    use super::Query;
+
+    fn api_schema() -> Arc<ApiSchema> {
+        const SCHEMA: &str = "type Query { a: String, b: String, c: String }";
+        let document = graphql_parser::parse_schema(SCHEMA).unwrap();
+        let id = DeploymentHash::new("test").unwrap();
+        Arc::new(ApiSchema::from_api_schema(Schema::new(id, document)).unwrap())
+    }
+
+    fn query(text: &str) -> Arc<Query> {
+        let logger = graph::log::logger(false);
+        let document = graphql_parser::parse_query(text).unwrap().into_static();
+        Query::new(
+            &logger,
+            api_schema(),
+            None,
+            GraphDataQuery::new(document, None),
+            None,
+            100,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn breadth_counts_fields_from_fragments() {
+        let query = query(
+            "query { a ...F ... on Query { a } } \
+             fragment F on Query { b c }",
+        );
+
+        match query.complexity(100, Some(2)) {
+            Err(QueryExecutionError::TooWide(3, 2)) => (),
+            other => panic!("expected TooWide(3, 2) but got {:?}", other),
+        }
+        assert!(query.complexity(100, Some(3)).is_ok());
+    }
+}
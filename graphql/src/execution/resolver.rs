@@ -123,4 +123,8 @@ pub trait Resolver: Sized + Send + Sync + 'static {
     fn post_process(&self, _result: &mut QueryResult) -> Result<(), Error> {
         Ok(())
     }
+
+    /// Stop the work the resolver is doing for a query because nobody is
+    /// waiting for its result anymore
+    fn cancel(&self) {}
 }
@@ -58,6 +58,15 @@ impl ValueExt for q::Value {
 pub enum BlockConstraint {
     Hash(H256),
     Number(BlockNumber),
+    /// The first block whose timestamp, in seconds since the epoch, is at
+    /// or after this value
+    Timestamp(u64),
+    /// The latest block that is too far behind the chain head to be
+    /// reverted
+    Finalized,
+    /// The latest block, but only if the subgraph has indexed at least
+    /// up to this block
+    Min(BlockNumber),
     Latest,
 }
 
@@ -82,6 +91,18 @@ impl TryFromValue for BlockConstraint {
             Ok(BlockConstraint::Number(BlockNumber::try_from_value(
                 number_value,
             )?))
+        } else if let Some(number_value) = map.get("number_gte") {
+            Ok(BlockConstraint::Min(BlockNumber::try_from_value(
+                number_value,
+            )?))
+        } else if let Some(timestamp) = map.get("timestamp_gte") {
+            Ok(BlockConstraint::Timestamp(u64::try_from_value(timestamp)?))
+        } else if let Some(finalized) = map.get("finalized") {
+            if bool::try_from_value(finalized)? {
+                Ok(BlockConstraint::Finalized)
+            } else {
+                Ok(BlockConstraint::Latest)
+            }
         } else {
             Err(anyhow!("invalid `BlockConstraint`"))
         }
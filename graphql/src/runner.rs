@@ -6,14 +6,16 @@ use std::time::{Duration, Instant};
 
 use crate::prelude::{QueryExecutionOptions, StoreResolver, SubscriptionExecutionOptions};
 use crate::query::execute_query;
+use crate::query::ext::BlockConstraint;
 use crate::subscription::execute_prepared_subscription;
 use graph::prelude::MetricsRegistry;
 use graph::prometheus::{Gauge, Histogram};
 use graph::{
     components::store::SubscriptionManager,
     prelude::{
-        async_trait, o, CheapClone, DeploymentState, GraphQlRunner as GraphQlRunnerTrait, Logger,
-        Query, QueryExecutionError, Subscription, SubscriptionError, SubscriptionResult,
+        async_trait, o, tokio, BlockNumber, CheapClone, DeploymentHash, DeploymentState,
+        GraphQlRunner as GraphQlRunnerTrait, Logger, Query, QueryExecutionError, Subscription,
+        SubscriptionError, SubscriptionResult,
     },
 };
 use graph::{data::graphql::effort::LoadManager, prelude::QueryStoreManager};
@@ -105,6 +107,13 @@ lazy_static! {
         .map(|s| u32::from_str(&s)
             .unwrap_or_else(|_| panic!("failed to parse env var GRAPH_GRAPHQL_MAX_SKIP")))
         .unwrap_or(std::u32::MAX);
+    // How long queries with a `number_gte` block constraint wait for the
+    // subgraph to reach that block before failing
+    static ref GRAPHQL_MIN_BLOCK_WAIT: Duration = env::var("GRAPH_GRAPHQL_MIN_BLOCK_WAIT")
+        .ok()
+        .map(|s| Duration::from_millis(u64::from_str(&s)
+            .unwrap_or_else(|_| panic!("failed to parse env var GRAPH_GRAPHQL_MIN_BLOCK_WAIT"))))
+        .unwrap_or(Duration::from_millis(0));
     // Allow skipping the check whether a deployment has changed while
     // we were running a query. Once we are sure that the check mechanism
     // is reliable, this variable should be removed
@@ -174,6 +183,36 @@ where
         Ok(())
     }
 
+    /// Return a store and its deployment state that can answer queries
+    /// that need data at least up to block `min`. If `store` is a read
+    /// replica that is behind, switch to the main database, and if that
+    /// is behind, too, wait up to `GRAPHQL_MIN_BLOCK_WAIT` for the subgraph
+    /// to get there. If it does not, resolving the block constraint fails
+    async fn store_at_block(
+        &self,
+        store: Arc<dyn QueryStore + Send + Sync>,
+        state: DeploymentState,
+        target: QueryTarget,
+        min: BlockNumber,
+    ) -> Result<(Arc<dyn QueryStore + Send + Sync>, DeploymentState), QueryExecutionError> {
+        if state.latest_ethereum_block_number >= min {
+            return Ok((store, state));
+        }
+
+        // Asking for a store for subscriptions gives us the main database
+        let store = self.store.query_store(target, true).await?;
+        let start = Instant::now();
+        loop {
+            let state = store.deployment_state().await?;
+            if state.latest_ethereum_block_number >= min
+                || start.elapsed() >= *GRAPHQL_MIN_BLOCK_WAIT
+            {
+                return Ok((store, state));
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+
     async fn execute(
         &self,
         query: Query,
@@ -193,8 +232,14 @@ where
         // while the query is running. `self.store` can not be used after this
         // point, and everything needs to go through the `store` we are
         // setting up here
-        let store = self.store.query_store(target, false).await?;
+        // Start the clock for the timeout now so that it also counts the
+        // time spent waiting for the store to catch up to a block constraint
+        let deadline = GRAPHQL_QUERY_TIMEOUT.map(|t| Instant::now() + t);
+        let store = self.store.query_store(target.clone(), false).await?;
         let state = store.deployment_state().await?;
+        if state.queries_disabled {
+            return Err(QueryExecutionError::QueriesDisabled(state.id).into());
+        }
         let network = Some(store.network_name().to_string());
         let schema = store.api_schema()?;
 
@@ -223,6 +268,21 @@ where
             )
             .to_result()?;
         let by_block_constraint = query.block_constraint()?;
+        // Clients that need to see the effects of a block ask for it with
+        // `number_gte`; make sure we use a store that has it. Nothing has
+        // been read from `store` yet, so switching to a different one
+        // still gives us a consistent view
+        let min_block = by_block_constraint
+            .keys()
+            .filter_map(|bc| match bc {
+                BlockConstraint::Min(number) => Some(*number),
+                _ => None,
+            })
+            .max();
+        let (store, state) = match min_block {
+            Some(min) => self.store_at_block(store, state, target, min).await?,
+            None => (store, state),
+        };
         let mut max_block = 0;
         let mut result: QueryResults = QueryResults::empty();
 
@@ -245,7 +305,7 @@ where
                 resolver.block_ptr.clone(),
                 QueryExecutionOptions {
                     resolver,
-                    deadline: GRAPHQL_QUERY_TIMEOUT.map(|t| Instant::now() + t),
+                    deadline,
                     max_first: max_first.unwrap_or(*GRAPHQL_MAX_FIRST),
                     max_skip: max_skip.unwrap_or(*GRAPHQL_MAX_SKIP),
                     load_manager: self.load_manager.clone(),
@@ -309,6 +369,12 @@ where
         target: QueryTarget,
     ) -> Result<SubscriptionResult, SubscriptionError> {
         let store = self.store.query_store(target, true).await?;
+        let state = store.deployment_state().await?;
+        if state.queries_disabled {
+            return Err(SubscriptionError::GraphQLError(vec![
+                QueryExecutionError::QueriesDisabled(state.id),
+            ]));
+        }
         let schema = store.api_schema()?;
         let network = store.network_name().to_string();
 
@@ -350,6 +416,14 @@ where
         .await
     }
 
+    async fn deployment_for_target(
+        self: Arc<Self>,
+        target: QueryTarget,
+    ) -> Result<DeploymentHash, QueryExecutionError> {
+        let store = self.store.query_store(target, false).await?;
+        Ok(store.deployment_id())
+    }
+
     fn load_manager(&self) -> Arc<LoadManager> {
         self.load_manager.clone()
     }
@@ -26,6 +26,26 @@ pub enum APISchemaError {
 
 const BLOCK_HEIGHT: &str = "Block_height";
 
+/// The suffix of the types for the rows that aggregation queries return
+pub(crate) const AGGREGATE_TYPE_SUFFIX: &str = "_aggregate";
+
+/// The suffix of the types that hold the sum, average, minimum, or maximum
+/// of the numeric fields of an entity type in aggregation queries
+pub(crate) const AGGREGATE_FIELDS_TYPE_SUFFIX: &str = "_aggregateFields";
+
+/// The field that holds the opaque cursor of an entity in a collection. It
+/// can be passed as the `after` argument of the collection to fetch the
+/// entities that follow it
+pub(crate) const CURSOR_FIELD: &str = "_cursor";
+
+lazy_static! {
+    /// Whether to add `<entities>Aggregate` fields that count entities and
+    /// aggregate their numeric fields to the `Query` type. Aggregations
+    /// can be expensive for large tables, and are therefore off by default
+    pub(crate) static ref ENABLE_AGGREGATIONS: bool =
+        std::env::var("GRAPH_GRAPHQL_ENABLE_AGGREGATIONS").is_ok();
+}
+
 const ERROR_POLICY_TYPE: &str = "_SubgraphErrorPolicy_";
 
 #[derive(Debug, PartialEq, Eq, Copy, Clone)]
@@ -83,6 +103,7 @@ pub fn api_schema(input_schema: &Document) -> Result<Document, APISchemaError> {
 
     // Refactor: Don't clone the schema.
     let mut schema = input_schema.clone();
+    add_cursor_fields(&mut schema);
     add_directives(&mut schema);
     add_builtin_scalar_types(&mut schema)?;
     add_order_direction_enum(&mut schema);
@@ -90,6 +111,9 @@ pub fn api_schema(input_schema: &Document) -> Result<Document, APISchemaError> {
     add_meta_field_type(&mut schema);
     add_types_for_object_types(&mut schema, &object_types)?;
     add_types_for_interface_types(&mut schema, &interface_types)?;
+    if *ENABLE_AGGREGATIONS {
+        add_aggregate_types(&mut schema, &object_types)?;
+    }
     add_field_arguments(&mut schema, input_schema)?;
     add_query_type(&mut schema, &object_types, &interface_types)?;
     add_subscription_type(&mut schema, &object_types, &interface_types)?;
@@ -227,6 +251,30 @@ fn add_block_height_type(schema: &mut Document) {
                 default_value: None,
                 directives: vec![],
             },
+            InputValue {
+                position: Pos::default(),
+                description: None,
+                name: "number_gte".to_owned(),
+                value_type: Type::NamedType("Int".to_owned()),
+                default_value: None,
+                directives: vec![],
+            },
+            InputValue {
+                position: Pos::default(),
+                description: None,
+                name: "timestamp_gte".to_owned(),
+                value_type: Type::NamedType("Int".to_owned()),
+                default_value: None,
+                directives: vec![],
+            },
+            InputValue {
+                position: Pos::default(),
+                description: None,
+                name: "finalized".to_owned(),
+                value_type: Type::NamedType("Boolean".to_owned()),
+                default_value: None,
+                directives: vec![],
+            },
         ],
     });
     let def = Definition::TypeDefinition(typedef);
@@ -248,6 +296,28 @@ fn add_meta_field_type(schema: &mut Document) {
         .extend(META_FIELD_SCHEMA.definitions.iter().cloned());
 }
 
+/// Adds the `_cursor` field to all entity types and interfaces of the
+/// input schema
+fn add_cursor_fields(schema: &mut Document) {
+    for definition in schema.definitions.iter_mut() {
+        let fields = match definition {
+            Definition::TypeDefinition(TypeDefinition::Object(t)) if t.name != SCHEMA_TYPE_NAME => {
+                &mut t.fields
+            }
+            Definition::TypeDefinition(TypeDefinition::Interface(t)) => &mut t.fields,
+            _ => continue,
+        };
+        if !fields.iter().any(|field| field.name == CURSOR_FIELD) {
+            fields.push(output_field(
+                CURSOR_FIELD,
+                "Opaque cursor for paging through a collection with `after`; \
+                 `null` for entities that are not part of a collection",
+                Type::NamedType("String".to_string()),
+            ));
+        }
+    }
+}
+
 fn add_types_for_object_types(
     schema: &mut Document,
     object_types: &Vec<&ObjectType>,
@@ -261,6 +331,111 @@ fn add_types_for_object_types(
     Ok(())
 }
 
+/// Adds the `<type_name>_aggregate` type for the rows of aggregation
+/// queries for each of the given object types to the schema. If the object
+/// type has numeric fields, also adds a `<type_name>_aggregateFields` type
+/// for the sum, average, minimum and maximum of those fields
+fn add_aggregate_types(
+    schema: &mut Document,
+    object_types: &[&ObjectType],
+) -> Result<(), APISchemaError> {
+    for object_type in object_types {
+        if object_type.name.eq(SCHEMA_TYPE_NAME) {
+            continue;
+        }
+
+        let mut fields = vec![
+            output_field(
+                "group",
+                "The value of the `groupBy` field for the entities in this group",
+                Type::NamedType("String".to_string()),
+            ),
+            output_field(
+                "count",
+                "The number of entities in this group",
+                Type::NonNullType(Box::new(Type::NamedType("Int".to_string()))),
+            ),
+        ];
+
+        let numeric_fields = object_type
+            .fields
+            .iter()
+            .filter(|field| is_numeric_field(field))
+            .map(|field| output_field(&field.name, "", Type::NamedType("BigDecimal".to_string())))
+            .collect::<Vec<_>>();
+        if !numeric_fields.is_empty() {
+            let fields_type_name = format!("{}{}", object_type.name, AGGREGATE_FIELDS_TYPE_SUFFIX);
+            add_object_type(schema, &fields_type_name, numeric_fields)?;
+            for (name, description) in &[
+                ("sum", "The sum of each numeric field"),
+                ("avg", "The average of each numeric field"),
+                ("min", "The minimum of each numeric field"),
+                ("max", "The maximum of each numeric field"),
+            ] {
+                fields.push(output_field(
+                    name,
+                    description,
+                    Type::NonNullType(Box::new(Type::NamedType(fields_type_name.clone()))),
+                ));
+            }
+        }
+
+        let type_name = format!("{}{}", object_type.name, AGGREGATE_TYPE_SUFFIX);
+        add_object_type(schema, &type_name, fields)?;
+    }
+    Ok(())
+}
+
+/// Whether `field` holds a single number that aggregation queries can sum up
+fn is_numeric_field(field: &Field) -> bool {
+    match &field.field_type {
+        Type::NonNullType(inner) => match inner.as_ref() {
+            Type::NamedType(name) => ["Int", "BigInt", "BigDecimal"].contains(&name.as_str()),
+            _ => false,
+        },
+        Type::NamedType(name) => ["Int", "BigInt", "BigDecimal"].contains(&name.as_str()),
+        Type::ListType(_) => false,
+    }
+}
+
+fn add_object_type(
+    schema: &mut Document,
+    type_name: &str,
+    fields: Vec<Field>,
+) -> Result<(), APISchemaError> {
+    match schema.get_named_type(type_name) {
+        None => {
+            let typedef = TypeDefinition::Object(ObjectType {
+                position: Pos::default(),
+                description: None,
+                name: type_name.to_owned(),
+                implements_interfaces: vec![],
+                directives: vec![],
+                fields,
+            });
+            let def = Definition::TypeDefinition(typedef);
+            schema.definitions.push(def);
+        }
+        Some(_) => return Err(APISchemaError::TypeExists(type_name.to_owned())),
+    }
+    Ok(())
+}
+
+fn output_field(name: &str, description: &str, field_type: Type) -> Field {
+    Field {
+        position: Pos::default(),
+        description: if description.is_empty() {
+            None
+        } else {
+            Some(description.to_owned())
+        },
+        name: name.to_owned(),
+        arguments: vec![],
+        field_type,
+        directives: vec![],
+    }
+}
+
 /// Adds `*_orderBy` and `*_filter` enum types for the given interfaces to the schema.
 fn add_types_for_interface_types(
     schema: &mut Document,
@@ -359,7 +534,7 @@ fn field_filter_input_values(
                 TypeDefinition::Object(_) | TypeDefinition::Interface(_) => {
                     // Only add `where` filter fields for object and interface fields
                     // if they are not @derivedFrom
-                    if ast::get_derived_from_directive(field).is_some() {
+                    let mut input_values = if ast::get_derived_from_directive(field).is_some() {
                         vec![]
                     } else {
                         // We allow filtering with `where: { other: "some-id" }` and
@@ -371,7 +546,9 @@ fn field_filter_input_values(
                             field,
                             &ScalarType::new(String::from("String")),
                         )
-                    }
+                    };
+                    input_values.extend(field_child_filter_input_value(field, named_type));
+                    input_values
                 }
                 TypeDefinition::Scalar(ref t) => field_scalar_filter_input_values(schema, field, t),
                 TypeDefinition::Enum(ref t) => field_enum_filter_input_values(schema, field, t),
@@ -463,7 +640,11 @@ fn field_list_filter_input_values(
         let input_field_type = match typedef {
             TypeDefinition::Interface(_) | TypeDefinition::Object(_) => {
                 if ast::get_derived_from_directive(field).is_some() {
-                    return None;
+                    return Some(
+                        field_child_filter_input_value(field, typedef)
+                            .into_iter()
+                            .collect(),
+                    );
                 } else {
                     Type::NamedType("String".into())
                 }
@@ -473,23 +654,43 @@ fn field_list_filter_input_values(
             TypeDefinition::InputObject(_) | TypeDefinition::Union(_) => return None,
         };
 
-        Some(
-            vec!["", "not", "contains", "not_contains"]
-                .into_iter()
-                .map(|filter_type| {
-                    input_value(
-                        &field.name,
-                        filter_type,
-                        Type::ListType(Box::new(Type::NonNullType(Box::new(
-                            input_field_type.clone(),
-                        )))),
-                    )
-                })
-                .collect(),
-        )
+        let mut input_values: Vec<_> = vec!["", "not", "contains", "not_contains"]
+            .into_iter()
+            .map(|filter_type| {
+                input_value(
+                    &field.name,
+                    filter_type,
+                    Type::ListType(Box::new(Type::NonNullType(Box::new(
+                        input_field_type.clone(),
+                    )))),
+                )
+            })
+            .collect();
+        input_values.extend(field_child_filter_input_value(field, typedef));
+        Some(input_values)
     })
 }
 
+/// Generates the `<field>_` input value that filters by conditions on the
+/// entities the field refers to. We only do that for object types since
+/// the child entities of an interface field could be in several tables
+fn field_child_filter_input_value(
+    field: &Field,
+    field_type: &TypeDefinition,
+) -> Option<InputValue> {
+    match field_type {
+        TypeDefinition::Object(t) => Some(InputValue {
+            position: Pos::default(),
+            description: None,
+            name: format!("{}_", field.name),
+            value_type: Type::NamedType(format!("{}_filter", t.name)),
+            default_value: None,
+            directives: vec![],
+        }),
+        _ => None,
+    }
+}
+
 /// Generates a `*_filter` input value for the given field name, suffix and value type.
 fn input_value(name: &str, suffix: &'static str, value_type: Type) -> InputValue {
     InputValue {
@@ -506,6 +707,23 @@ fn input_value(name: &str, suffix: &'static str, value_type: Type) -> InputValue
     }
 }
 
+/// The names and descriptions of the object and interface types that
+/// get fields on the `Query` and `Subscription` types
+fn entity_types<'a>(
+    object_types: &'a [&ObjectType],
+    interface_types: &'a [&InterfaceType],
+) -> impl Iterator<Item = (&'a str, Option<&'a String>)> {
+    object_types
+        .iter()
+        .filter(|t| !t.name.eq(SCHEMA_TYPE_NAME))
+        .map(|t| (t.name.as_str(), t.description.as_ref()))
+        .chain(
+            interface_types
+                .iter()
+                .map(|t| (t.name.as_str(), t.description.as_ref())),
+        )
+}
+
 /// Adds a root `Query` object type to the schema.
 fn add_query_type(
     schema: &mut Document,
@@ -518,13 +736,17 @@ fn add_query_type(
         return Err(APISchemaError::TypeExists(type_name));
     }
 
-    let mut fields = object_types
-        .iter()
-        .map(|t| &t.name)
-        .filter(|name| !name.eq(&SCHEMA_TYPE_NAME))
-        .chain(interface_types.iter().map(|t| &t.name))
-        .flat_map(|name| query_fields_for_type(name))
+    let mut fields = entity_types(object_types, interface_types)
+        .flat_map(|(name, description)| query_fields_for_type(name, description))
         .collect::<Vec<Field>>();
+    if *ENABLE_AGGREGATIONS {
+        fields.extend(
+            object_types
+                .iter()
+                .filter(|t| !t.name.eq(SCHEMA_TYPE_NAME))
+                .map(|t| aggregate_query_field(&t.name)),
+        );
+    }
     let mut fulltext_fields = schema
         .get_fulltext_directives()
         .map_err(|_| APISchemaError::FulltextSearchNonDeterministic)?
@@ -614,12 +836,8 @@ fn add_subscription_type(
         return Err(APISchemaError::TypeExists(type_name));
     }
 
-    let mut fields: Vec<Field> = object_types
-        .iter()
-        .map(|t| &t.name)
-        .filter(|name| !name.eq(&SCHEMA_TYPE_NAME))
-        .chain(interface_types.iter().map(|t| &t.name))
-        .flat_map(|name| query_fields_for_type(name))
+    let mut fields: Vec<Field> = entity_types(object_types, interface_types)
+        .flat_map(|(name, description)| query_fields_for_type(name, description))
         .collect();
     fields.push(meta_field());
 
@@ -641,9 +859,13 @@ fn block_argument() -> InputValue {
         position: Pos::default(),
         description: Some(
             "The block at which the query should be executed. \
-             Can either be an `{ number: Int }` containing the block number \
-             or a `{ hash: Bytes }` value containing a block hash. Defaults \
-             to the latest block when omitted."
+             Can either be an `{ number: Int }` containing the block number, \
+             a `{ hash: Bytes }` value containing a block hash, a \
+             `{ number_gte: Int }` for the latest block, as long as the \
+             subgraph has indexed at least that block, a \
+             `{ timestamp_gte: Int }` for the first block at or after a \
+             timestamp, or `{ finalized: true }` for the latest finalized \
+             block. Defaults to the latest block when omitted."
                 .to_owned(),
         ),
         name: "block".to_string(),
@@ -668,7 +890,9 @@ fn subgraph_error_argument() -> InputValue {
 }
 
 /// Generates `Query` fields for the given type name (e.g. `users` and `user`).
-fn query_fields_for_type(type_name: &str) -> Vec<Field> {
+/// The fields carry the `description` of the type so that the documentation
+/// in the subgraph schema shows up for them, too
+fn query_fields_for_type(type_name: &str, description: Option<&String>) -> Vec<Field> {
     let mut collection_arguments = collection_arguments_for_named_type(type_name);
     collection_arguments.push(block_argument());
 
@@ -690,7 +914,7 @@ fn query_fields_for_type(type_name: &str) -> Vec<Field> {
     vec![
         Field {
             position: Pos::default(),
-            description: None,
+            description: description.cloned(),
             name: type_name.to_camel_case(), // Name formatting must be updated in sync with `graph::data::schema::validate_fulltext_directive_name()`
             arguments: by_id_arguments,
             field_type: Type::NamedType(type_name.to_owned()),
@@ -698,7 +922,7 @@ fn query_fields_for_type(type_name: &str) -> Vec<Field> {
         },
         Field {
             position: Pos::default(),
-            description: None,
+            description: description.cloned(),
             name: type_name.to_plural().to_camel_case(), // Name formatting must be updated in sync with `graph::data::schema::validate_fulltext_directive_name()`
             arguments: collection_arguments,
             field_type: Type::NonNullType(Box::new(Type::ListType(Box::new(Type::NonNullType(
@@ -709,6 +933,51 @@ fn query_fields_for_type(type_name: &str) -> Vec<Field> {
     ]
 }
 
+/// Generates the `Query` field for aggregation queries over the given type
+/// name (e.g. `usersAggregate`)
+fn aggregate_query_field(type_name: &str) -> Field {
+    let mut skip = input_value(&"skip".to_string(), "", Type::NamedType("Int".to_string()));
+    skip.default_value = Some(Value::Int(0.into()));
+
+    let mut first = input_value(&"first".to_string(), "", Type::NamedType("Int".to_string()));
+    first.default_value = Some(Value::Int(100.into()));
+
+    let arguments = vec![
+        skip,
+        first,
+        input_value(
+            &"groupBy".to_string(),
+            "",
+            Type::NamedType(format!("{}_orderBy", type_name)),
+        ),
+        input_value(
+            &"where".to_string(),
+            "",
+            Type::NamedType(format!("{}_filter", type_name)),
+        ),
+        block_argument(),
+        subgraph_error_argument(),
+    ];
+
+    Field {
+        position: Pos::default(),
+        description: Some(format!(
+            "Count the `{}` entities that match `where`, and aggregate their numeric fields, \
+             either over all of them or grouped by the value of `groupBy`",
+            type_name
+        )),
+        name: format!("{}Aggregate", type_name.to_plural().to_camel_case()),
+        arguments,
+        field_type: Type::NonNullType(Box::new(Type::ListType(Box::new(Type::NonNullType(
+            Box::new(Type::NamedType(format!(
+                "{}{}",
+                type_name, AGGREGATE_TYPE_SUFFIX
+            ))),
+        ))))),
+        directives: vec![],
+    }
+}
+
 fn meta_field() -> Field {
     lazy_static! {
         static ref META_FIELD: Field = Field {
@@ -762,6 +1031,11 @@ fn collection_arguments_for_named_type(type_name: &str) -> Vec<InputValue> {
             "",
             Type::NamedType(format!("{}_filter", type_name)),
         ),
+        input_value(
+            &"after".to_string(),
+            "",
+            Type::NamedType("String".to_string()),
+        ),
     ];
 
     args
@@ -1007,6 +1281,7 @@ mod tests {
                 "pets_not",
                 "pets_contains",
                 "pets_not_contains",
+                "pets_",
                 "favoriteFurType",
                 "favoriteFurType_not",
                 "favoriteFurType_in",
@@ -1025,6 +1300,9 @@ mod tests {
                 "favoritePet_not_starts_with",
                 "favoritePet_ends_with",
                 "favoritePet_not_ends_with",
+                "favoritePet_",
+                "leastFavoritePet_",
+                "mostFavoritePets_",
             ]
             .iter()
             .map(ToString::to_string)
@@ -1093,6 +1371,7 @@ mod tests {
                 "orderBy",
                 "orderDirection",
                 "where",
+                "after",
                 "block",
                 "subgraphError",
             ]
@@ -1190,6 +1469,7 @@ mod tests {
                 "orderBy",
                 "orderDirection",
                 "where",
+                "after",
                 "block",
                 "subgraphError"
             ]
@@ -1236,4 +1516,40 @@ type Gravatar @entity {
         }
         .expect("\"metadata\" field is missing on Query type");
     }
+
+    #[test]
+    fn api_schema_keeps_descriptions() {
+        const SCHEMA: &str = r#"
+"A user of the app"
+type User @entity {
+  id: ID!
+  "The name the user chose"
+  name: String!
+}
+"#;
+        let input_schema = parse_schema(SCHEMA).expect("Failed to parse input schema");
+        let schema = api_schema(&input_schema).expect("Failed to derive API schema");
+
+        let user_type = match schema.get_named_type("User") {
+            Some(TypeDefinition::Object(t)) => t,
+            _ => panic!("User type is missing in derived API schema"),
+        };
+        assert_eq!(Some("A user of the app"), user_type.description.as_deref());
+        let name_field =
+            ast::get_field(user_type, &"name".to_string()).expect("\"name\" field is missing");
+        assert_eq!(
+            Some("The name the user chose"),
+            name_field.description.as_deref()
+        );
+
+        let query_type = match schema.get_named_type("Query") {
+            Some(TypeDefinition::Object(t)) => t,
+            _ => panic!("Query type is missing in derived API schema"),
+        };
+        for name in ["user", "users"].iter() {
+            let field = ast::get_field(query_type, &name.to_string())
+                .unwrap_or_else(|| panic!("\"{}\" field is missing on Query type", name));
+            assert_eq!(Some("A user of the app"), field.description.as_deref());
+        }
+    }
 }
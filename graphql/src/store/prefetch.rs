@@ -25,7 +25,9 @@ use graph::{
 use crate::execution::{ExecutionContext, Resolver};
 use crate::query::ast as qast;
 use crate::runner::ResultSizeMetrics;
+use crate::schema::api::{AGGREGATE_TYPE_SUFFIX, CURSOR_FIELD, ENABLE_AGGREGATIONS};
 use crate::schema::ast as sast;
+use crate::store::query::encode_cursor;
 use crate::store::{build_query, StoreResolver};
 
 lazy_static! {
@@ -553,12 +555,69 @@ fn execute_root_selection_set(
     ctx: &ExecutionContext<impl Resolver>,
     selection_set: &q::SelectionSet,
 ) -> Result<Vec<Node>, Vec<QueryExecutionError>> {
-    // Obtain the root Query type and fail if there isn't one
+    if !*ENABLE_AGGREGATIONS {
+        // Obtain the root Query type and fail if there isn't one
+        let query_type = ctx.query.schema.query_type.as_ref().into();
+        let grouped_field_set = collect_fields(ctx, query_type, once(selection_set));
+
+        // Execute the root selection set against the root query type
+        return execute_selection_set(resolver, ctx, make_root_node(), grouped_field_set);
+    }
+
+    // Aggregation fields do not return entities; each of them is resolved
+    // with one query and its rows become the children of the root node
+    let (data_set, aggregate_fields) = split_aggregate_fields(ctx, selection_set);
     let query_type = ctx.query.schema.query_type.as_ref().into();
-    let grouped_field_set = collect_fields(ctx, query_type, once(selection_set));
+    let grouped_field_set = collect_fields(ctx, query_type, once(&data_set));
+    let mut nodes = execute_selection_set(resolver, ctx, make_root_node(), grouped_field_set)?;
+    for (response_key, field) in aggregate_fields {
+        let rows = resolver
+            .aggregate(ctx, field)?
+            .into_iter()
+            .map(|row| Rc::new(Node::from(row)))
+            .collect();
+        // Unwrap: the root node list always has exactly one entry
+        nodes
+            .first_mut()
+            .unwrap()
+            .set_children(response_key.to_owned(), rows);
+    }
+    Ok(nodes)
+}
 
-    // Execute the root selection set against the root query type
-    execute_selection_set(resolver, ctx, make_root_node(), grouped_field_set)
+/// Split the root fields in `selection_set` into the fields for
+/// aggregation queries and everything else. Returns the other fields
+/// and the aggregation fields with their response keys
+fn split_aggregate_fields<'a>(
+    ctx: &'a ExecutionContext<impl Resolver>,
+    selection_set: &'a q::SelectionSet,
+) -> (q::SelectionSet, Vec<(&'a str, &'a q::Field)>) {
+    let query_type = ctx.query.schema.query_type.as_ref();
+    let mut data_set = q::SelectionSet {
+        span: selection_set.span,
+        items: Vec::new(),
+    };
+    let mut aggregate_fields = Vec::new();
+    for (response_key, fields) in
+        crate::execution::collect_fields(ctx, query_type, once(selection_set))
+    {
+        let is_aggregate = sast::get_field(query_type, &fields[0].name)
+            .map(|field| {
+                field
+                    .field_type
+                    .get_base_type()
+                    .ends_with(AGGREGATE_TYPE_SUFFIX)
+            })
+            .unwrap_or(false);
+        if is_aggregate {
+            aggregate_fields.push((response_key, fields[0]));
+        } else {
+            data_set
+                .items
+                .extend(fields.into_iter().map(|f| q::Selection::Field(f.clone())));
+        }
+    }
+    (data_set, aggregate_fields)
 }
 
 fn check_result_size(logger: &Logger, size: usize) -> Result<(), QueryExecutionError> {
@@ -621,6 +680,7 @@ fn execute_selection_set<'a>(
             // Group fields with the same response key, so we can execute them together
             let mut grouped_field_set =
                 collect_fields(ctx, child_type, fields.iter().map(|f| &f.selection_set));
+            let with_cursor = grouped_field_set.values().any(|key| key.selects_cursor);
 
             // "Select by Specific Attribute Names" is an experimental feature and can be disabled completely.
             // If this environment variable is set, the program will use an empty collection that,
@@ -643,6 +703,7 @@ fn execute_selection_set<'a>(
                 &fields[0],
                 field,
                 collected_columns,
+                with_cursor,
             ) {
                 Ok(children) => {
                     match execute_selection_set(resolver, ctx, children, grouped_field_set) {
@@ -682,6 +743,9 @@ struct CollectedResponseKey<'a> {
     iface_fields: Vec<&'a q::Field>,
     obj_types: IndexMap<ObjectCondition<'a>, Vec<&'a q::Field>>,
     collected_column_names: CollectedAttributeNames<'a>,
+    /// Whether the `_cursor` field is selected; it is computed from the
+    /// entity and not a column
+    selects_cursor: bool,
 }
 
 impl<'a> CollectedResponseKey<'a> {
@@ -720,7 +784,9 @@ impl<'a> CollectedResponseKey<'a> {
             });
 
         // collect the column name if field exists in schema
-        if schema_field.is_some() {
+        if field.name == CURSOR_FIELD {
+            self.selects_cursor = true;
+        } else if schema_field.is_some() {
             self.collected_column_names
                 .update(object_or_interface, &field)
         }
@@ -906,6 +972,7 @@ fn execute_field(
     field: &q::Field,
     field_definition: &s::Field,
     collected_column_names: AttributeNamesByObjectType<'_>,
+    with_cursor: bool,
 ) -> Result<Vec<Node>, Vec<QueryExecutionError>> {
     let argument_values = crate::execution::coerce_argument_values(&ctx.query, object_type, field)?;
     let multiplicity = if sast::is_list_or_non_null_list_field(field_definition) {
@@ -921,12 +988,13 @@ fn execute_field(
         &join,
         argument_values,
         multiplicity,
-        ctx.query.schema.types_for_interface(),
+        &ctx.query.schema,
         resolver.block_number(),
         ctx.max_first,
         ctx.max_skip,
         ctx.query.query_id.clone(),
         collected_column_names,
+        with_cursor,
     )
     .map_err(|e| vec![e])
 }
@@ -941,18 +1009,19 @@ fn fetch(
     join: &Join<'_>,
     arguments: HashMap<&str, r::Value>,
     multiplicity: ChildMultiplicity,
-    types_for_interface: &BTreeMap<EntityType, Vec<s::ObjectType>>,
+    schema: &ApiSchema,
     block: BlockNumber,
     max_first: u32,
     max_skip: u32,
     query_id: String,
     collected_column_names: AttributeNamesByObjectType<'_>,
+    with_cursor: bool,
 ) -> Result<Vec<Node>, QueryExecutionError> {
     let mut query = build_query(
         join.child_type,
         block,
         &arguments,
-        types_for_interface,
+        schema,
         max_first,
         max_skip,
         collected_column_names,
@@ -965,6 +1034,31 @@ fn fetch(
         query.order = EntityOrder::Unordered;
     }
 
+    // Cursors only make sense for collections, and fulltext search sorts
+    // by rank, which is not an attribute of the entities
+    let cursor_order = if with_cursor
+        && multiplicity == ChildMultiplicity::Many
+        && !arguments.contains_key("text")
+    {
+        // The cursor needs the attribute that the entities are sorted by
+        let order_attr = match &query.order {
+            EntityOrder::Ascending(attr, _) | EntityOrder::Descending(attr, _) => Some(attr),
+            EntityOrder::Default | EntityOrder::Unordered => None,
+        };
+        if let (Some(attr), EntityCollection::All(entity_types)) =
+            (order_attr, &mut query.collection)
+        {
+            for (_, column_names) in entity_types {
+                if let AttributeNames::Select(names) = column_names {
+                    names.insert(attr.clone());
+                }
+            }
+        }
+        Some(query.order.clone())
+    } else {
+        None
+    };
+
     query.logger = Some(logger);
     if let Some(r::Value::String(id)) = arguments.get(ARG_ID.as_str()) {
         query.filter = Some(
@@ -982,9 +1076,20 @@ fn fetch(
         }
         query.collection = EntityCollection::Window(windows);
     }
-    store
-        .find_query_values(query)
-        .map(|entities| entities.into_iter().map(|entity| entity.into()).collect())
+    store.find_query_values(query).map(|entities| {
+        entities
+            .into_iter()
+            .map(|mut entity| {
+                if let Some(cursor) = cursor_order
+                    .as_ref()
+                    .and_then(|order| encode_cursor(order, &entity))
+                {
+                    entity.insert(CURSOR_FIELD.to_owned(), r::Value::String(cursor));
+                }
+                entity.into()
+            })
+            .collect()
+    })
 }
 
 /// Represents a finished column collection operation, mapping each object type to the final set of
@@ -1,8 +1,12 @@
 use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::mem::discriminant;
 
+use graph::data::graphql::{DocumentExt, TypeExt};
 use graph::prelude::*;
-use graph::{components::store::EntityType, data::graphql::ObjectOrInterface};
+use graph::{
+    components::store::{AggregateQuery, EntityType},
+    data::graphql::ObjectOrInterface,
+};
 
 use crate::schema::ast as sast;
 use crate::store::prefetch::ObjectCondition;
@@ -20,7 +24,7 @@ pub fn build_query<'a>(
     entity: impl Into<ObjectOrInterface<'a>>,
     block: BlockNumber,
     arguments: &HashMap<&str, r::Value>,
-    types_for_interface: &'a BTreeMap<EntityType, Vec<s::ObjectType>>,
+    schema: &'a ApiSchema,
     max_first: u32,
     max_skip: u32,
     mut column_names: BTreeMap<ObjectCondition<'a>, AttributeNames>,
@@ -33,7 +37,7 @@ pub fn build_query<'a>(
                 .unwrap_or(AttributeNames::All);
             vec![((*object).into(), selected_columns)]
         }
-        ObjectOrInterface::Interface(interface) => types_for_interface
+        ObjectOrInterface::Interface(interface) => schema.types_for_interface()
             [&EntityType::from(*interface)]
             .iter()
             .map(|o| {
@@ -46,7 +50,7 @@ pub fn build_query<'a>(
     });
     let mut query = EntityQuery::new(parse_subgraph_id(entity)?, block, entity_types)
         .range(build_range(arguments, max_first, max_skip)?);
-    if let Some(filter) = build_filter(entity, arguments)? {
+    if let Some(filter) = build_filter(entity, arguments, schema)? {
         query = query.filter(filter);
     }
     let order = match (
@@ -61,10 +65,133 @@ pub fn build_query<'a>(
         }
         (None, _) => EntityOrder::Default,
     };
+    if let Some(filter) = build_cursor_filter(entity, arguments, &order)? {
+        query.filter = Some(filter.and_maybe(query.filter));
+    }
     query = query.order(order);
     Ok(query)
 }
 
+/// Encodes the position of `entity` in a collection sorted by `order` as
+/// an opaque cursor. Passing the cursor as the `after` argument returns the
+/// entities that follow `entity`. The value of the sort attribute is
+/// encoded as a string so that it survives the round trip through JSON
+/// unchanged, whatever its type
+pub(crate) fn encode_cursor(
+    order: &EntityOrder,
+    entity: &BTreeMap<String, r::Value>,
+) -> Option<String> {
+    let attr = match order {
+        EntityOrder::Ascending(attr, _) | EntityOrder::Descending(attr, _) => attr.as_str(),
+        EntityOrder::Default => "id",
+        EntityOrder::Unordered => return None,
+    };
+    let id = match entity.get("id") {
+        Some(r::Value::String(id)) => id,
+        _ => return None,
+    };
+    let value = match entity.get(attr).unwrap_or(&r::Value::Null) {
+        r::Value::Null => None,
+        r::Value::String(s) | r::Value::Enum(s) => Some(s.clone()),
+        r::Value::Int(n) => Some(n.to_string()),
+        r::Value::Float(f) => Some(f.to_string()),
+        r::Value::Boolean(b) => Some(b.to_string()),
+        r::Value::List(_) | r::Value::Object(_) => return None,
+    };
+    serde_json::to_vec(&(attr, value, id)).ok().map(hex::encode)
+}
+
+/// Decodes a cursor made by `encode_cursor` into the attribute that the
+/// collection was sorted by, the value of that attribute as a string, and
+/// the id of the entity
+fn decode_cursor(cursor: &str) -> Option<(String, Option<String>, String)> {
+    let bytes = hex::decode(cursor).ok()?;
+    serde_json::from_slice(&bytes).ok()
+}
+
+/// Turns the `after` argument into a filter for the entities that come
+/// after the cursor when sorted by `order`. Since the sort key is always
+/// followed by the `id`, the position of each entity is unique, and
+/// queries can use indexes instead of skipping over all earlier entities
+fn build_cursor_filter(
+    entity: ObjectOrInterface,
+    arguments: &HashMap<&str, r::Value>,
+    order: &EntityOrder,
+) -> Result<Option<EntityFilter>, QueryExecutionError> {
+    let cursor = match arguments.get("after") {
+        Some(r::Value::String(cursor)) => cursor,
+        Some(r::Value::Null) | None => return Ok(None),
+        _ => unreachable!("after is a String"),
+    };
+    if let Some(r::Value::Object(_)) = arguments.get("text") {
+        return Err(QueryExecutionError::NotSupported(
+            "cursors can not be used with fulltext search".to_owned(),
+        ));
+    }
+    let invalid = || QueryExecutionError::InvalidCursor(cursor.clone());
+
+    let order_attr = match order {
+        EntityOrder::Ascending(attr, _) | EntityOrder::Descending(attr, _) => attr.as_str(),
+        EntityOrder::Default | EntityOrder::Unordered => "id",
+    };
+    let (attr, value, id) = decode_cursor(cursor).ok_or_else(invalid)?;
+    if attr != order_attr {
+        return Err(invalid());
+    }
+    let field = sast::get_field(entity, &attr).ok_or_else(invalid)?;
+    let value = match value {
+        None => Value::Null,
+        Some(value) => match field.field_type.get_base_type() {
+            "Int" => Value::Int(value.parse().map_err(|_| invalid())?),
+            "Boolean" => Value::Bool(value.parse().map_err(|_| invalid())?),
+            _ => Value::from_query_value(&r::Value::String(value), &field.field_type)
+                .map_err(|_| invalid())?,
+        },
+    };
+    Ok(Some(EntityFilter::after(order, value, id)))
+}
+
+/// Builds an AggregateQuery over `entity` from the GraphQL arguments of an
+/// aggregation field. `attributes` are the numeric attributes to aggregate
+pub(crate) fn build_aggregate_query<'a>(
+    entity: impl Into<ObjectOrInterface<'a>>,
+    block: BlockNumber,
+    arguments: &HashMap<&str, r::Value>,
+    schema: &'a ApiSchema,
+    max_first: u32,
+    max_skip: u32,
+    attributes: Vec<String>,
+) -> Result<AggregateQuery, QueryExecutionError> {
+    let entity = entity.into();
+    let group_by = match arguments.get("groupBy") {
+        Some(r::Value::Enum(name)) => {
+            let field = sast::get_field(entity, name).ok_or_else(|| {
+                QueryExecutionError::EntityFieldError(entity.name().to_owned(), name.clone())
+            })?;
+            if sast::is_list_or_non_null_list_field(field)
+                || sast::get_derived_from_directive(field).is_some()
+            {
+                return Err(QueryExecutionError::NotSupported(format!(
+                    "can not group `{}` entities by the list or derived field `{}`",
+                    entity.name(),
+                    name
+                )));
+            }
+            Some(name.to_owned())
+        }
+        _ => None,
+    };
+    Ok(AggregateQuery {
+        subgraph_id: parse_subgraph_id(entity)?,
+        block,
+        entity_type: EntityType::new(entity.name().to_owned()),
+        filter: build_filter(entity, arguments, schema)?,
+        group_by,
+        attributes,
+        range: build_range(arguments, max_first, max_skip)?,
+    })
+}
+
 /// Parses GraphQL arguments into a EntityRange, if present.
 fn build_range(
     arguments: &HashMap<&str, r::Value>,
@@ -111,9 +238,10 @@ fn build_range(
 fn build_filter(
     entity: ObjectOrInterface,
     arguments: &HashMap<&str, r::Value>,
+    schema: &ApiSchema,
 ) -> Result<Option<EntityFilter>, QueryExecutionError> {
     match arguments.get("where") {
-        Some(r::Value::Object(object)) => build_filter_from_object(entity, object),
+        Some(r::Value::Object(object)) => build_filter_from_object(entity, object, schema),
         Some(r::Value::Null) => Ok(None),
         None => match arguments.get("text") {
             Some(r::Value::Object(filter)) => build_fulltext_filter_from_object(filter),
@@ -146,6 +274,7 @@ fn build_fulltext_filter_from_object(
 fn build_filter_from_object(
     entity: ObjectOrInterface,
     object: &BTreeMap<String, r::Value>,
+    schema: &ApiSchema,
 ) -> Result<Option<EntityFilter>, QueryExecutionError> {
     Ok(Some(EntityFilter::And({
         object
@@ -153,7 +282,7 @@ fn build_filter_from_object(
             .map(|(key, value)| {
                 use self::sast::FilterOp::*;
 
-                let (field_name, op) = sast::parse_field_as_filter(key);
+                let (field_name, op) = sast::parse_field_as_filter(entity, key);
 
                 let field = sast::get_field(entity, &field_name).ok_or_else(|| {
                     QueryExecutionError::EntityFieldError(
@@ -162,6 +291,12 @@ fn build_filter_from_object(
                     )
                 })?;
 
+                if let sast::FilterOp::Child = op {
+                    return build_child_filter_from_object(
+                        entity, field_name, field, value, schema,
+                    );
+                }
+
                 let ty = &field.field_type;
                 let store_value = Value::from_query_value(value, ty)?;
 
@@ -180,12 +315,66 @@ fn build_filter_from_object(
                     EndsWith => EntityFilter::EndsWith(field_name, store_value),
                     NotEndsWith => EntityFilter::NotEndsWith(field_name, store_value),
                     Equal => EntityFilter::Equal(field_name, store_value),
+                    Child => unreachable!("child filters were handled above"),
                 })
             })
             .collect::<Result<Vec<EntityFilter>, QueryExecutionError>>()?
     })))
 }
 
+/// Parses the filter `<field>_: { .. }` on the entities that `field` of
+/// `entity` refers to into an `EntityFilter::Child`. Child filters can
+/// not be nested
+fn build_child_filter_from_object(
+    entity: ObjectOrInterface,
+    field_name: String,
+    field: &s::Field,
+    value: &r::Value,
+    schema: &ApiSchema,
+) -> Result<EntityFilter, QueryExecutionError> {
+    let object = match value {
+        r::Value::Object(object) => object,
+        _ => return Err(QueryExecutionError::InvalidFilterError),
+    };
+
+    let child_type = field.field_type.get_base_type();
+    let child = schema
+        .document()
+        .get_object_type_definition(child_type)
+        .ok_or_else(|| {
+            QueryExecutionError::FilterNotSupportedError(
+                format!("{}_", field_name),
+                format!("child filters on `{}` are not supported", child_type),
+            )
+        })?;
+    if object.keys().any(|key| {
+        matches!(
+            sast::parse_field_as_filter(child.into(), key).1,
+            sast::FilterOp::Child
+        )
+    }) {
+        return Err(QueryExecutionError::NotSupported(format!(
+            "nested child filters are not supported, found one in the filter for `{}.{}`",
+            entity.name(),
+            field_name
+        )));
+    }
+    let filter = build_filter_from_object(child.into(), object, schema)?
+        .unwrap_or_else(|| EntityFilter::And(vec![]));
+
+    let (attr, derived) = match sast::get_derived_from_field(child, field) {
+        Some(child_field) => (child_field.name.clone(), true),
+        None => (field_name, false),
+    };
+
+    Ok(EntityFilter::Child(graph::components::store::Child {
+        attr,
+        entity_type: EntityType::from(child),
+        filter: Box::new(filter),
+        derived,
+    }))
+}
+
 /// Parses a list of GraphQL values into a vector of entity field values.
 fn list_values(value: Value, filter_type: &str) -> Result<Vec<Value>, QueryExecutionError> {
     match value {
@@ -256,7 +445,9 @@ fn build_fulltext_order_by_from_object(
     )
 }
 
-/// Parses GraphQL arguments into a EntityOrder, if present.
+/// Parses GraphQL arguments into a EntityOrder, if present. Fulltext
+/// searches are ordered by rank, and without an explicit direction, the
+/// best matches come first
 fn build_order_direction(
     arguments: &HashMap<&str, r::Value>,
 ) -> Result<OrderDirection, QueryExecutionError> {
@@ -267,7 +458,10 @@ fn build_order_direction(
             r::Value::Enum(name) if name == "desc" => OrderDirection::Descending,
             _ => OrderDirection::Ascending,
         })
-        .unwrap_or(OrderDirection::Ascending))
+        .unwrap_or_else(|| match arguments.get("text") {
+            Some(r::Value::Object(_)) => OrderDirection::Descending,
+            _ => OrderDirection::Ascending,
+        }))
 }
 
 /// Parses the subgraph ID from the ObjectType directives.
@@ -352,7 +546,7 @@ mod tests {
 
     use graph::prelude::*;
 
-    use super::build_query;
+    use super::{build_query, decode_cursor, encode_cursor};
 
     fn default_object() -> ObjectType {
         let subgraph_id_argument = (
@@ -417,6 +611,26 @@ mod tests {
         }
     }
 
+    fn api_schema() -> ApiSchema {
+        const SCHEMA: &str = r#"
+            type Query { id: ID }
+
+            type Token @subgraphId(id: "QmZ5dsusHwD1PEbx6L4dLCWkDsk1BLhrx9mPsGyPvTxPCM") {
+                id: ID!
+                symbol: String!
+                pools: [Pool!]! @derivedFrom(field: "token0")
+            }
+
+            type Pool @subgraphId(id: "QmZ5dsusHwD1PEbx6L4dLCWkDsk1BLhrx9mPsGyPvTxPCM") {
+                id: ID!
+                token0: Token!
+            }
+        "#;
+        let document = graphql_parser::parse_schema(SCHEMA).unwrap();
+        let id = DeploymentHash::new("test").unwrap();
+        ApiSchema::from_api_schema(Schema::new(id, document)).unwrap()
+    }
+
     fn default_arguments<'a>() -> HashMap<&'a str, r::Value> {
         let mut map = HashMap::new();
         let first = "first";
@@ -433,7 +647,7 @@ mod tests {
                 &object("Entity1"),
                 BLOCK_NUMBER_MAX,
                 &default_arguments(),
-                &BTreeMap::new(),
+                &api_schema(),
                 std::u32::MAX,
                 std::u32::MAX,
                 Default::default()
@@ -447,7 +661,7 @@ mod tests {
                 &object("Entity2"),
                 BLOCK_NUMBER_MAX,
                 &default_arguments(),
-                &BTreeMap::new(),
+                &api_schema(),
                 std::u32::MAX,
                 std::u32::MAX,
                 Default::default()
@@ -465,7 +679,7 @@ mod tests {
                 &default_object(),
                 BLOCK_NUMBER_MAX,
                 &default_arguments(),
-                &BTreeMap::new(),
+                &api_schema(),
                 std::u32::MAX,
                 std::u32::MAX,
                 Default::default()
@@ -486,7 +700,7 @@ mod tests {
                 &default_object(),
                 BLOCK_NUMBER_MAX,
                 &args,
-                &BTreeMap::new(),
+                &api_schema(),
                 std::u32::MAX,
                 std::u32::MAX,
                 Default::default()
@@ -503,7 +717,7 @@ mod tests {
                 &default_object(),
                 BLOCK_NUMBER_MAX,
                 &args,
-                &BTreeMap::new(),
+                &api_schema(),
                 std::u32::MAX,
                 std::u32::MAX,
                 Default::default()
@@ -514,6 +728,44 @@ mod tests {
         );
     }
 
+    #[test]
+    fn build_query_orders_fulltext_search_by_descending_rank() {
+        let mut search = BTreeMap::new();
+        search.insert("userSearch".to_string(), r::Value::String("Shaq:*".into()));
+        let mut args = default_arguments();
+        args.insert("text", r::Value::Object(search));
+        assert_eq!(
+            build_query(
+                &default_object(),
+                BLOCK_NUMBER_MAX,
+                &args,
+                &api_schema(),
+                std::u32::MAX,
+                std::u32::MAX,
+                Default::default()
+            )
+            .unwrap()
+            .order,
+            EntityOrder::Descending("userSearch".to_string(), ValueType::String)
+        );
+
+        args.insert("orderDirection", r::Value::Enum("asc".to_string()));
+        assert_eq!(
+            build_query(
+                &default_object(),
+                BLOCK_NUMBER_MAX,
+                &args,
+                &api_schema(),
+                std::u32::MAX,
+                std::u32::MAX,
+                Default::default()
+            )
+            .unwrap()
+            .order,
+            EntityOrder::Ascending("userSearch".to_string(), ValueType::String)
+        );
+    }
+
     #[test]
     fn build_query_ignores_order_by_from_non_enum_values() {
         let order_by = "orderBy".to_string();
@@ -524,7 +776,7 @@ mod tests {
                 &default_object(),
                 BLOCK_NUMBER_MAX,
                 &args,
-                &BTreeMap::new(),
+                &api_schema(),
                 std::u32::MAX,
                 std::u32::MAX,
                 Default::default()
@@ -541,7 +793,7 @@ mod tests {
                 &default_object(),
                 BLOCK_NUMBER_MAX,
                 &args,
-                &BTreeMap::new(),
+                &api_schema(),
                 std::u32::MAX,
                 std::u32::MAX,
                 Default::default()
@@ -564,7 +816,7 @@ mod tests {
                 &default_object(),
                 BLOCK_NUMBER_MAX,
                 &args,
-                &BTreeMap::new(),
+                &api_schema(),
                 std::u32::MAX,
                 std::u32::MAX,
                 Default::default()
@@ -582,7 +834,7 @@ mod tests {
                 &default_object(),
                 BLOCK_NUMBER_MAX,
                 &args,
-                &BTreeMap::new(),
+                &api_schema(),
                 std::u32::MAX,
                 std::u32::MAX,
                 Default::default()
@@ -603,7 +855,7 @@ mod tests {
                 &default_object(),
                 BLOCK_NUMBER_MAX,
                 &args,
-                &BTreeMap::new(),
+                &api_schema(),
                 std::u32::MAX,
                 std::u32::MAX,
                 Default::default()
@@ -624,7 +876,7 @@ mod tests {
                 &default_object(),
                 BLOCK_NUMBER_MAX,
                 &args,
-                &BTreeMap::new(),
+                &api_schema(),
                 std::u32::MAX,
                 std::u32::MAX,
                 Default::default()
@@ -647,7 +899,7 @@ mod tests {
                 &default_object(),
                 BLOCK_NUMBER_MAX,
                 &args,
-                &BTreeMap::new(),
+                &api_schema(),
                 std::u32::MAX,
                 std::u32::MAX,
                 Default::default()
@@ -665,7 +917,7 @@ mod tests {
                 &default_object(),
                 BLOCK_NUMBER_MAX,
                 &args,
-                &BTreeMap::new(),
+                &api_schema(),
                 std::u32::MAX,
                 std::u32::MAX,
                 Default::default()
@@ -683,7 +935,7 @@ mod tests {
                 &default_object(),
                 BLOCK_NUMBER_MAX,
                 &default_arguments(),
-                &BTreeMap::new(),
+                &api_schema(),
                 std::u32::MAX,
                 std::u32::MAX,
                 Default::default()
@@ -704,7 +956,7 @@ mod tests {
                 &default_object(),
                 BLOCK_NUMBER_MAX,
                 &args,
-                &BTreeMap::new(),
+                &api_schema(),
                 std::u32::MAX,
                 std::u32::MAX,
                 Default::default()
@@ -737,7 +989,7 @@ mod tests {
                 },
                 BLOCK_NUMBER_MAX,
                 &args,
-                &BTreeMap::new(),
+                &api_schema(),
                 std::u32::MAX,
                 std::u32::MAX,
                 Default::default()
@@ -750,4 +1002,250 @@ mod tests {
             )]))
         )
     }
+
+    #[test]
+    fn build_query_yields_equal_filters_for_fields_ending_in_underscore() {
+        let whre = "where".to_string();
+        let mut args = default_arguments();
+        args.insert(
+            &whre,
+            r::Value::Object(BTreeMap::from_iter(vec![(
+                "name_".to_string(),
+                r::Value::String("hello".to_string()),
+            )])),
+        );
+        assert_eq!(
+            build_query(
+                &ObjectType {
+                    fields: vec![field("name_", Type::NamedType("string".to_owned()))],
+                    ..default_object()
+                },
+                BLOCK_NUMBER_MAX,
+                &args,
+                &api_schema(),
+                std::u32::MAX,
+                std::u32::MAX,
+                Default::default()
+            )
+            .unwrap()
+            .filter,
+            Some(EntityFilter::And(vec![EntityFilter::Equal(
+                "name_".to_string(),
+                Value::String("hello".to_string()),
+            )]))
+        )
+    }
+
+    #[test]
+    fn build_query_yields_big_int_filters() {
+        let whre = "where".to_string();
+        let mut args = default_arguments();
+        args.insert(
+            &whre,
+            r::Value::Object(BTreeMap::from_iter(vec![(
+                "amount_gt".to_string(),
+                r::Value::String("1000000000000000000".to_string()),
+            )])),
+        );
+        assert_eq!(
+            build_query(
+                &ObjectType {
+                    fields: vec![field("amount", Type::NamedType("BigInt".to_owned()))],
+                    ..default_object()
+                },
+                BLOCK_NUMBER_MAX,
+                &args,
+                &api_schema(),
+                std::u32::MAX,
+                std::u32::MAX,
+                Default::default()
+            )
+            .unwrap()
+            .filter,
+            Some(EntityFilter::And(vec![EntityFilter::GreaterThan(
+                "amount".to_string(),
+                Value::BigInt(BigInt::from(1_000_000_000_000_000_000u64)),
+            )]))
+        )
+    }
+
+    #[test]
+    fn build_query_yields_cursor_filters() {
+        let object = ObjectType {
+            fields: vec![
+                field("id", Type::NamedType("ID".to_owned())),
+                field("name", Type::NamedType("String".to_owned())),
+            ],
+            ..default_object()
+        };
+        let entity = BTreeMap::from_iter(vec![
+            ("id".to_string(), r::Value::String("1".to_string())),
+            ("name".to_string(), r::Value::String("Alice".to_string())),
+        ]);
+        let order = EntityOrder::Descending("name".to_string(), ValueType::String);
+        let cursor = encode_cursor(&order, &entity).unwrap();
+
+        let mut args = default_arguments();
+        args.insert("orderBy", r::Value::Enum("name".to_string()));
+        args.insert("orderDirection", r::Value::Enum("desc".to_string()));
+        args.insert("after", r::Value::String(cursor.clone()));
+        let query = build_query(
+            &object,
+            BLOCK_NUMBER_MAX,
+            &args,
+            &api_schema(),
+            std::u32::MAX,
+            std::u32::MAX,
+            Default::default(),
+        )
+        .unwrap();
+        assert_eq!(query.order, order);
+        assert_eq!(
+            query.filter,
+            Some(EntityFilter::Or(vec![
+                EntityFilter::LessThan("name".to_string(), Value::from("Alice")),
+                EntityFilter::And(vec![
+                    EntityFilter::Equal("name".to_string(), Value::from("Alice")),
+                    EntityFilter::LessThan("id".to_string(), Value::from("1")),
+                ]),
+            ]))
+        );
+
+        // Without `orderBy`, entities are sorted by `id`
+        let cursor = encode_cursor(&EntityOrder::Default, &entity).unwrap();
+        let mut args = default_arguments();
+        args.insert("after", r::Value::String(cursor.clone()));
+        let query = build_query(
+            &object,
+            BLOCK_NUMBER_MAX,
+            &args,
+            &api_schema(),
+            std::u32::MAX,
+            std::u32::MAX,
+            Default::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            query.filter,
+            Some(EntityFilter::GreaterThan(
+                "id".to_string(),
+                Value::from("1")
+            ))
+        );
+
+        // A cursor for a different order or garbage are rejected
+        args.insert("orderBy", r::Value::Enum("name".to_string()));
+        let res = build_query(
+            &object,
+            BLOCK_NUMBER_MAX,
+            &args,
+            &api_schema(),
+            std::u32::MAX,
+            std::u32::MAX,
+            Default::default(),
+        );
+        assert!(matches!(res, Err(QueryExecutionError::InvalidCursor(_))));
+
+        args.insert("after", r::Value::String("not a cursor".to_string()));
+        let res = build_query(
+            &object,
+            BLOCK_NUMBER_MAX,
+            &args,
+            &api_schema(),
+            std::u32::MAX,
+            std::u32::MAX,
+            Default::default(),
+        );
+        assert!(matches!(res, Err(QueryExecutionError::InvalidCursor(_))));
+    }
+
+    #[test]
+    fn cursor_values_are_strings() {
+        let entity = BTreeMap::from_iter(vec![
+            ("id".to_string(), r::Value::String("1".to_string())),
+            ("age".to_string(), r::Value::Int(42)),
+            ("weight".to_string(), r::Value::Float(61.5)),
+        ]);
+        let cursor = |attr: &str| {
+            let order = EntityOrder::Ascending(attr.to_string(), ValueType::String);
+            decode_cursor(&encode_cursor(&order, &entity).unwrap()).unwrap()
+        };
+        assert_eq!(
+            ("age".to_string(), Some("42".to_string()), "1".to_string()),
+            cursor("age")
+        );
+        assert_eq!(
+            (
+                "weight".to_string(),
+                Some("61.5".to_string()),
+                "1".to_string()
+            ),
+            cursor("weight")
+        );
+        assert_eq!(
+            ("color".to_string(), None, "1".to_string()),
+            cursor("color")
+        );
+    }
+
+    #[test]
+    fn build_query_yields_child_filters() {
+        use graph::components::store::Child;
+        use graph::data::graphql::DocumentExt;
+
+        // Build the filter for `where: { <field>: { id: "x" } }`
+        fn child_filter(entity: &str, field: &str) -> Option<EntityFilter> {
+            let whre = "where";
+            let mut args = default_arguments();
+            args.insert(
+                whre,
+                r::Value::Object(BTreeMap::from_iter(vec![(
+                    field.to_string(),
+                    r::Value::Object(BTreeMap::from_iter(vec![(
+                        "id".to_string(),
+                        r::Value::String("x".to_string()),
+                    )])),
+                )])),
+            );
+            let schema = api_schema();
+            let object = schema
+                .document()
+                .get_object_type_definition(entity)
+                .unwrap();
+            build_query(
+                object,
+                BLOCK_NUMBER_MAX,
+                &args,
+                &schema,
+                std::u32::MAX,
+                std::u32::MAX,
+                Default::default(),
+            )
+            .unwrap()
+            .filter
+        }
+
+        let id_filter = Box::new(EntityFilter::And(vec![EntityFilter::Equal(
+            "id".to_string(),
+            Value::String("x".to_string()),
+        )]));
+        assert_eq!(
+            child_filter("Pool", "token0_"),
+            Some(EntityFilter::And(vec![EntityFilter::Child(Child {
+                attr: "token0".to_string(),
+                entity_type: EntityType::from("Token"),
+                filter: id_filter.clone(),
+                derived: false,
+            })]))
+        );
+        assert_eq!(
+            child_filter("Token", "pools_"),
+            Some(EntityFilter::And(vec![EntityFilter::Child(Child {
+                attr: "token0".to_string(),
+                entity_type: EntityType::from("Pool"),
+                filter: id_filter,
+                derived: true,
+            })]))
+        );
+    }
 }
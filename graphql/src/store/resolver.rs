@@ -3,18 +3,18 @@ use std::result;
 use std::sync::Arc;
 
 use graph::data::{
-    graphql::{object, ObjectOrInterface},
+    graphql::{object, DocumentExt, ObjectOrInterface, TypeExt},
     schema::META_FIELD_TYPE,
 };
 use graph::prelude::*;
 use graph::{components::store::*, data::schema::BLOCK_FIELD_TYPE};
 
+use crate::prelude::*;
 use crate::query::ext::BlockConstraint;
 use crate::runner::ResultSizeMetrics;
+use crate::schema::api::{ErrorPolicy, AGGREGATE_FIELDS_TYPE_SUFFIX, AGGREGATE_TYPE_SUFFIX};
 use crate::schema::ast as sast;
-use crate::{prelude::*, schema::api::ErrorPolicy};
-
-use crate::store::query::collect_entities_from_query_field;
+use crate::store::query::{build_aggregate_query, collect_entities_from_query_field};
 
 /// A resolver that fetches entities from a `Store`.
 #[derive(Clone)]
@@ -71,6 +71,7 @@ impl StoreResolver {
         deployment: DeploymentHash,
         result_size: Arc<ResultSizeMetrics>,
     ) -> Result<Self, QueryExecutionError> {
+        let latest = matches!(bc, BlockConstraint::Latest | BlockConstraint::Min(_));
         let store_clone = store.cheap_clone();
         let deployment2 = deployment.clone();
         let block_ptr = graph::spawn_blocking_allow_panic(move || {
@@ -80,6 +81,22 @@ impl StoreResolver {
         .map_err(|e| QueryExecutionError::Panic(e.to_string()))
         .and_then(|x| x)?; // Propagate panics.
 
+        // Queries for earlier blocks would silently miss entities that
+        // were removed by pruning
+        if !latest {
+            if let Some(pruned_block) = store.deployment_state().await?.pruned_block {
+                if block_ptr.number < pruned_block {
+                    return Err(QueryExecutionError::ValueParseError(
+                        "block".to_owned(),
+                        format!(
+                            "subgraph {} was pruned and only has data from block number {} on",
+                            deployment, pruned_block
+                        ),
+                    ));
+                }
+            }
+        }
+
         let has_non_fatal_errors = store
             .has_non_fatal_errors(Some(block_ptr.block_number()))
             .await?;
@@ -110,45 +127,91 @@ impl StoreResolver {
         subgraph: DeploymentHash,
     ) -> Result<BlockPtr, QueryExecutionError> {
         match bc {
-            BlockConstraint::Number(number) => store
+            BlockConstraint::Number(number) => {
+                Self::locate_block_number(store, number, "block.number", subgraph)
+            }
+            BlockConstraint::Hash(hash) => {
+                let ptr = store
+                    .block_ptr()
+                    .map_err(QueryExecutionError::from)?
+                    .expect("we should have already checked that the subgraph exists");
+                // Answering with the data at the subgraph head for a block
+                // that the subgraph has not processed yet would return
+                // stale data. Tell the client to retry instead
+                let not_yet_indexed = || {
+                    QueryExecutionError::BlockNotYetIndexed(
+                        subgraph.clone(),
+                        format!("{:#x}", hash),
+                        ptr.number,
+                    )
+                };
+                // A hash that is not in the block cache might be for a
+                // block that has not been ingested yet; we only reject
+                // hashes that we know are not on the subgraph's chain
+                let number = store.block_number(hash)?.ok_or_else(not_yet_indexed)?;
+                if number > ptr.number {
+                    return Err(not_yet_indexed());
+                }
+                // We can only cheaply check that the block is on the chain
+                // the subgraph indexed when it is the subgraph head; see
+                // `block_number` in the Postgres `QueryStore`
+                if number == ptr.number && hash != ptr.hash_as_h256() {
+                    return Err(QueryExecutionError::ValueParseError(
+                        "block.hash".to_owned(),
+                        format!(
+                            "block {:#x} is not on the chain that subgraph {} has indexed",
+                            hash, subgraph
+                        ),
+                    ));
+                }
+                Ok(BlockPtr::from((hash, number as u64)))
+            }
+            BlockConstraint::Timestamp(timestamp) => {
+                let number = store.block_number_by_timestamp(timestamp)?.ok_or_else(|| {
+                    QueryExecutionError::ValueParseError(
+                        "block.timestamp_gte".to_owned(),
+                        format!("no block with a timestamp at or after {} found", timestamp),
+                    )
+                })?;
+                Self::locate_block_number(store, number, "block.timestamp_gte", subgraph)
+            }
+            BlockConstraint::Finalized => {
+                let ptr = store
+                    .block_ptr()?
+                    .expect("we should have already checked that the subgraph exists");
+                let finalized = store.finalized_block_number()?.ok_or_else(|| {
+                    QueryExecutionError::ValueParseError(
+                        "block.finalized".to_owned(),
+                        "the chain head is not known yet".to_owned(),
+                    )
+                })?;
+                if ptr.number <= finalized {
+                    Ok(ptr)
+                } else {
+                    // See 7a7b9708-adb7-4fc2-acec-88680cb07ec1
+                    Ok(BlockPtr::from((
+                        web3::types::H256::zero(),
+                        finalized as u64,
+                    )))
+                }
+            }
+            BlockConstraint::Min(number) => store
                 .block_ptr()
                 .map_err(|e| StoreError::from(e).into())
                 .and_then(|ptr| {
                     let ptr = ptr.expect("we should have already checked that the subgraph exists");
                     if ptr.number < number {
-                        Err(QueryExecutionError::ValueParseError(
-                            "block.number".to_owned(),
+                        return Err(QueryExecutionError::ValueParseError(
+                            "block.number_gte".to_owned(),
                             format!(
                                 "subgraph {} has only indexed up to block number {} \
                                  and data for block number {} is therefore not yet available",
                                 subgraph, ptr.number, number
                             ),
-                        ))
-                    } else {
-                        // We don't have a way here to look the block hash up from
-                        // the database, and even if we did, there is no guarantee
-                        // that we have the block in our cache. We therefore
-                        // always return an all zeroes hash when users specify
-                        // a block number
-                        // See 7a7b9708-adb7-4fc2-acec-88680cb07ec1
-                        Ok(BlockPtr::from((web3::types::H256::zero(), number as u64)))
+                        ));
                     }
+                    Ok(ptr)
                 }),
-            BlockConstraint::Hash(hash) => {
-                store
-                    .block_number(hash)
-                    .map_err(Into::into)
-                    .and_then(|number| {
-                        number
-                            .ok_or_else(|| {
-                                QueryExecutionError::ValueParseError(
-                                    "block.hash".to_owned(),
-                                    "no block with that hash found".to_owned(),
-                                )
-                            })
-                            .map(|number| BlockPtr::from((hash, number as u64)))
-                    })
-            }
             BlockConstraint::Latest => store
                 .block_ptr()
                 .map_err(|e| StoreError::from(e).into())
@@ -159,15 +222,119 @@ impl StoreResolver {
         }
     }
 
+    /// Locate the block with the given `number`, making sure that the
+    /// subgraph has already indexed it. `field` names the part of the
+    /// block constraint the number came from for error messages
+    fn locate_block_number(
+        store: &dyn QueryStore,
+        number: BlockNumber,
+        field: &str,
+        subgraph: DeploymentHash,
+    ) -> Result<BlockPtr, QueryExecutionError> {
+        store
+            .block_ptr()
+            .map_err(|e| StoreError::from(e).into())
+            .and_then(|ptr| {
+                let ptr = ptr.expect("we should have already checked that the subgraph exists");
+                if ptr.number < number {
+                    Err(QueryExecutionError::ValueParseError(
+                        field.to_owned(),
+                        format!(
+                            "subgraph {} has only indexed up to block number {} \
+                             and data for block number {} is therefore not yet available",
+                            subgraph, ptr.number, number
+                        ),
+                    ))
+                } else {
+                    // We don't have a way here to look the block hash up from
+                    // the database, and even if we did, there is no guarantee
+                    // that we have the block in our cache. We therefore
+                    // always return an all zeroes hash when users specify
+                    // a block number
+                    // See 7a7b9708-adb7-4fc2-acec-88680cb07ec1
+                    Ok(BlockPtr::from((web3::types::H256::zero(), number as u64)))
+                }
+            })
+    }
+
+    /// Run the aggregation query for the root field `field` and turn the
+    /// rows it returns into objects of the field's `<type>_aggregate` type
+    pub(crate) fn aggregate(
+        &self,
+        ctx: &ExecutionContext<impl Resolver>,
+        field: &q::Field,
+    ) -> Result<Vec<BTreeMap<String, r::Value>>, Vec<QueryExecutionError>> {
+        let schema = ctx.query.schema.as_ref();
+        let query_type = schema.query_type.as_ref();
+        let type_name = sast::get_field(query_type, &field.name)
+            .expect("aggregation fields are on the query type")
+            .field_type
+            .get_base_type();
+        let entity_name = type_name
+            .strip_suffix(AGGREGATE_TYPE_SUFFIX)
+            .expect("aggregation types have the aggregate suffix");
+        let entity = schema
+            .document()
+            .get_object_type_definition(entity_name)
+            .ok_or_else(|| vec![QueryExecutionError::NamedTypeError(entity_name.to_owned())])?;
+        let fields_type_name = format!("{}{}", entity_name, AGGREGATE_FIELDS_TYPE_SUFFIX);
+        let attributes = schema
+            .document()
+            .get_object_type_definition(&fields_type_name)
+            .map(|t| t.fields.iter().map(|f| f.name.clone()).collect())
+            .unwrap_or_default();
+
+        let arguments = crate::execution::coerce_argument_values(&ctx.query, query_type, field)?;
+        let query = build_aggregate_query(
+            entity,
+            self.block_number(),
+            &arguments,
+            schema,
+            ctx.max_first,
+            ctx.max_skip,
+            attributes,
+        )
+        .map_err(|e| vec![e])?;
+        let rows = self
+            .store
+            .aggregate(query)
+            .map_err(|e| vec![e])?
+            .into_iter()
+            .map(|mut row| {
+                // The nested objects are stored as single-element lists
+                // since that is how `resolve_object` expects them
+                for name in &["sum", "avg", "min", "max"] {
+                    if let Some(r::Value::Object(mut values)) = row.remove(*name) {
+                        values.insert(
+                            "__typename".to_string(),
+                            r::Value::String(fields_type_name.clone()),
+                        );
+                        row.insert(
+                            name.to_string(),
+                            r::Value::List(vec![r::Value::Object(values)]),
+                        );
+                    }
+                }
+                row.insert(
+                    "__typename".to_string(),
+                    r::Value::String(type_name.to_owned()),
+                );
+                row
+            })
+            .collect();
+        Ok(rows)
+    }
+
     fn handle_meta(
         &self,
         prefetched_object: Option<r::Value>,
+        field: &q::Field,
         object_type: &ObjectOrInterface<'_>,
     ) -> Result<(Option<r::Value>, Option<r::Value>), QueryExecutionError> {
-        // Pretend that the whole `_meta` field was loaded by prefetch. Eager
-        // loading this is ok until we add more information to this field
-        // that would force us to query the database; when that happens, we
-        // need to switch to loading on demand
+        // Pretend that the whole `_meta` field was loaded by prefetch. All
+        // of it is eagerly loaded, except for the block timestamp, which
+        // needs a trip to the database and is only loaded when the query
+        // asks for it
         if object_type.is_meta() {
             let hash = self
                 .block_ptr
@@ -189,10 +356,25 @@ impl StoreResolver {
                 .as_ref()
                 .map(|ptr| r::Value::Int((ptr.number as i32).into()))
                 .unwrap_or(r::Value::Null);
+            // Like the hash, the timestamp is only known when we know which
+            // block the query ran against
+            let timestamp = match &self.block_ptr {
+                Some(ptr)
+                    if hash != r::Value::Null
+                        && may_select(&field.selection_set, &["block", "timestamp"]) =>
+                {
+                    self.store
+                        .block_timestamp(ptr.hash_as_h256())?
+                        .map(|ts| r::Value::Int(ts as i64))
+                        .unwrap_or(r::Value::Null)
+                }
+                _ => r::Value::Null,
+            };
             let mut map = BTreeMap::new();
             let block = object! {
                 hash: hash,
                 number: number,
+                timestamp: timestamp,
                 __typename: BLOCK_FIELD_TYPE
             };
             map.insert("prefetch:block".to_string(), r::Value::List(vec![block]));
@@ -214,6 +396,23 @@ impl StoreResolver {
     }
 }
 
+/// Whether `set` might select the nested field `path`, e.g.,
+/// `["block", "timestamp"]`. We do not look into fragment spreads and
+/// assume that they select it
+fn may_select(set: &q::SelectionSet, path: &[&str]) -> bool {
+    let (first, rest) = match path.split_first() {
+        Some(split) => split,
+        None => return true,
+    };
+    set.items.iter().any(|selection| match selection {
+        q::Selection::Field(field) => {
+            field.name == *first && may_select(&field.selection_set, rest)
+        }
+        q::Selection::InlineFragment(fragment) => may_select(&fragment.selection_set, path),
+        q::Selection::FragmentSpread(_) => true,
+    })
+}
+
 #[async_trait]
 impl Resolver for StoreResolver {
     const CACHEABLE: bool = true;
@@ -222,6 +421,10 @@ impl Resolver for StoreResolver {
         self.store.query_permit().await
     }
 
+    fn cancel(&self) {
+        self.store.cancel()
+    }
+
     fn prefetch(
         &self,
         ctx: &ExecutionContext<Self>,
@@ -258,7 +461,7 @@ impl Resolver for StoreResolver {
         object_type: ObjectOrInterface<'_>,
         _arguments: &HashMap<&str, r::Value>,
     ) -> Result<r::Value, QueryExecutionError> {
-        let (prefetched_object, meta) = self.handle_meta(prefetched_object, &object_type)?;
+        let (prefetched_object, meta) = self.handle_meta(prefetched_object, field, &object_type)?;
         if let Some(meta) = meta {
             return Ok(meta);
         }
@@ -316,7 +519,10 @@ impl Resolver for StoreResolver {
 
         // Add the "indexing_error" to the response.
         assert!(result.errors_mut().is_empty());
-        *result.errors_mut() = vec![QueryError::IndexingError];
+        *result.errors_mut() = vec![QueryError::IndexingError(
+            self.deployment.clone(),
+            self.block_number(),
+        )];
 
         match self.error_policy {
             // If indexing errors are denied, we omit results, except for the `_meta` response.
@@ -165,6 +165,12 @@ fn map_source_to_response_stream(
         result_size,
     } = options;
 
+    // Only send results that differ from the last one sent. Most store
+    // events change entities in ways that do not affect what the
+    // subscription selects, and clients should not have to sift through
+    // identical results
+    let mut last: Option<Arc<QueryResult>> = None;
+
     Box::new(
         trigger_stream
             .chain(source_stream.compat())
@@ -185,6 +191,15 @@ fn map_source_to_response_stream(
                     result_size.cheap_clone(),
                 )
                 .boxed(),
+            })
+            .filter(move |result| {
+                let changed = last
+                    .as_ref()
+                    .map_or(true, |last| !last.has_same_data(result));
+                if changed {
+                    last = Some(result.cheap_clone());
+                }
+                futures03::future::ready(changed)
             }),
     )
 }
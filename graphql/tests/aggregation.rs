@@ -0,0 +1,147 @@
+//! Tests for aggregation queries. They live in their own test binary since
+//! aggregations need to be turned on with an environment variable before
+//! the first API schema is generated
+use std::sync::Arc;
+
+use graph::{
+    components::store::DeploymentLocator,
+    data::graphql::object_value,
+    data::query::QueryTarget,
+    prelude::{
+        r, DeploymentHash, Entity, EntityKey, EntityOperation, GraphQlRunner as _, Query,
+        QueryResult, Value,
+    },
+};
+use graph_graphql::prelude::*;
+use test_store::{
+    run_test_sequentially, transact_entity_operations, GENESIS_PTR, LOAD_MANAGER, LOGGER,
+    METRICS_REGISTRY, STORE, SUBSCRIPTION_MANAGER,
+};
+
+const SCHEMA: &str = "
+    type Token @entity {
+        id: ID!
+        kind: String!
+        amount: BigInt!
+        decimals: Int!
+    }";
+
+fn insert_tokens(deployment: &DeploymentLocator) {
+    let token = |id: &str, kind: &str, amount: i32, decimals: i32| {
+        let data = Entity::from(vec![
+            ("__typename", Value::from("Token")),
+            ("id", Value::from(id)),
+            ("kind", Value::from(kind)),
+            ("amount", Value::BigInt(amount.into())),
+            ("decimals", Value::from(decimals)),
+        ]);
+        EntityOperation::Set {
+            key: EntityKey::data(deployment.hash.clone(), "Token".to_owned(), id.to_owned()),
+            data,
+        }
+    };
+    let ops = vec![
+        token("t1", "stable", 100, 6),
+        token("t2", "stable", 200, 18),
+        token("t3", "volatile", 300, 18),
+    ];
+    transact_entity_operations(
+        &STORE.subgraph_store(),
+        deployment,
+        GENESIS_PTR.clone(),
+        ops,
+    )
+    .unwrap();
+}
+
+async fn execute_query(id: &DeploymentHash, query: &str) -> QueryResult {
+    let runner = Arc::new(GraphQlRunner::new(
+        &*LOGGER,
+        STORE.clone(),
+        SUBSCRIPTION_MANAGER.clone(),
+        LOAD_MANAGER.clone(),
+        METRICS_REGISTRY.clone(),
+    ));
+    let query = Query::new(
+        graphql_parser::parse_query(query).unwrap().into_static(),
+        None,
+    );
+
+    runner
+        .run_query_with_complexity(
+            query,
+            QueryTarget::Deployment(id.clone()),
+            None,
+            None,
+            None,
+            None,
+        )
+        .await
+        .first()
+        .unwrap()
+        .duplicate()
+}
+
+#[test]
+fn aggregations() {
+    std::env::set_var("GRAPH_GRAPHQL_ENABLE_AGGREGATIONS", "1");
+
+    run_test_sequentially(|_| async move {
+        test_store::remove_subgraphs();
+        let id = DeploymentHash::new("graphqlTestsAggregation").unwrap();
+        let deployment = test_store::create_test_subgraph(&id, SCHEMA);
+        insert_tokens(&deployment);
+
+        let result = execute_query(
+            &id,
+            "query {
+                all: tokensAggregate { count sum { amount decimals } }
+                skipped: tokensAggregate(skip: 1) { count }
+                byKind: tokensAggregate(groupBy: kind, first: 1, skip: 1) {
+                    group count max { amount }
+                }
+                tokens(orderBy: id) { id }
+            }",
+        )
+        .await;
+
+        let data = match result.to_result() {
+            Ok(data) => data,
+            Err(errors) => panic!("Unexpected errors return for query: {:#?}", errors),
+        };
+        let s = |s: &str| r::Value::String(s.to_owned());
+        let expected = object_value(vec![
+            (
+                "all",
+                r::Value::List(vec![object_value(vec![
+                    ("count", r::Value::Int(3)),
+                    (
+                        "sum",
+                        object_value(vec![("amount", s("600")), ("decimals", s("42"))]),
+                    ),
+                ])]),
+            ),
+            (
+                "skipped",
+                r::Value::List(vec![object_value(vec![("count", r::Value::Int(3))])]),
+            ),
+            (
+                "byKind",
+                r::Value::List(vec![object_value(vec![
+                    ("group", s("volatile")),
+                    ("count", r::Value::Int(1)),
+                    ("max", object_value(vec![("amount", s("300"))])),
+                ])]),
+            ),
+            (
+                "tokens",
+                r::Value::List(vec![
+                    object_value(vec![("id", s("t1"))]),
+                    object_value(vec![("id", s("t2"))]),
+                    object_value(vec![("id", s("t3"))]),
+                ]),
+            ),
+        ]);
+        assert_eq!(Some(expected), data);
+    })
+}
@@ -12,7 +12,7 @@ use std::{
 };
 
 use graph::{
-    components::store::DeploymentLocator,
+    components::store::{BlockStore as _, DeploymentLocator},
     data::graphql::{object, object_value},
     data::subgraph::schema::SubgraphError,
     data::{
@@ -842,6 +842,62 @@ fn query_complexity() {
     })
 }
 
+#[test]
+fn query_complexity_with_variables() {
+    run_test_sequentially(|store| async move {
+        let deployment = setup(store.as_ref());
+        let query = |first: i64| {
+            Query::new(
+                graphql_parser::parse_query(
+                    "query members($first: Int) {
+                    musicians(orderBy: id) {
+                        name
+                        bands(first: $first, orderBy: id) {
+                            name
+                            members(first: $first, orderBy: id) {
+                                name
+                            }
+                        }
+                    }
+                }",
+                )
+                .unwrap()
+                .into_static(),
+                Some(QueryVariables::new(HashMap::from_iter(
+                    vec![(String::from("first"), r::Value::Int(first))].into_iter(),
+                ))),
+            )
+        };
+        let max_complexity = Some(1_010_100);
+
+        // Passing `first` in a variable costs as much as passing it directly
+        let result = first_result(
+            execute_subgraph_query_with_complexity(
+                query(100),
+                deployment.hash.clone().into(),
+                max_complexity,
+            )
+            .await,
+        )
+        .await;
+        assert!(!result.has_errors());
+
+        let result = first_result(
+            execute_subgraph_query_with_complexity(
+                query(101),
+                deployment.hash.into(),
+                max_complexity,
+            )
+            .await,
+        )
+        .await;
+        match result.to_result().unwrap_err()[0] {
+            QueryError::ExecutionError(QueryExecutionError::TooComplex(_, _)) => (),
+            _ => panic!("did not catch complexity"),
+        };
+    })
+}
+
 #[test]
 fn query_complexity_subscriptions() {
     run_test_sequentially(|store| async move {
@@ -1276,6 +1332,46 @@ fn cannot_filter_by_derved_relationship_fields() {
     })
 }
 
+#[test]
+fn can_filter_by_child_entities() {
+    run_test_sequentially(|store| async move {
+        let deployment = setup(store.as_ref());
+        let result = execute_query_document(
+            &deployment.hash,
+            graphql_parser::parse_query(
+                "
+        query {
+            musicians(orderBy: id, where: { mainBand_: { name: \"The Amateurs\" } }) { id }
+            writers: musicians(orderBy: id, where: { writtenSongs_: { title: \"Rock Tune\" } }) { id }
+            bands(orderBy: id, where: { originalSongs_: { title: \"Pop Tune\" } }) { id }
+            lisasBands: bands(orderBy: id, where: { members_: { name: \"Lisa\" } }) { id }
+        }
+        ",
+            )
+            .expect("invalid test query")
+            .into_static(),
+        )
+        .await;
+
+        let ids = |ids: &[&str]| {
+            r::Value::List(
+                ids.iter()
+                    .map(|id| object_value(vec![("id", r::Value::String(id.to_string()))]))
+                    .collect(),
+            )
+        };
+        assert_eq!(
+            extract_data!(result),
+            Some(object_value(vec![
+                ("musicians", ids(&["m3"])),
+                ("writers", ids(&["m2"])),
+                ("bands", ids(&["b2"])),
+                ("lisasBands", ids(&["b1"])),
+            ]))
+        );
+    })
+}
+
 #[test]
 fn subscription_gets_result_even_without_events() {
     run_test_sequentially(|store| async move {
@@ -1339,6 +1435,125 @@ fn subscription_gets_result_even_without_events() {
     })
 }
 
+#[test]
+fn subscription_skips_unchanged_results() {
+    use test_store::block_store::{BLOCK_THREE, BLOCK_TWO};
+
+    fn set_musician(deployment: &DeploymentLocator, block_ptr: BlockPtr, data: Entity) {
+        let key = EntityKey::data(
+            deployment.hash.clone(),
+            "Musician".to_string(),
+            data.get("id").unwrap().clone().as_string().unwrap(),
+        );
+        transact_entity_operations(
+            &STORE.subgraph_store(),
+            deployment,
+            block_ptr,
+            vec![EntityOperation::Set { key, data }],
+        )
+        .unwrap();
+    }
+
+    fn musicians(names: &[&str]) -> Option<r::Value> {
+        let musicians = names
+            .iter()
+            .map(|name| object_value(vec![("name", r::Value::String(name.to_string()))]))
+            .collect();
+        Some(object_value(vec![("musicians", r::Value::List(musicians))]))
+    }
+
+    run_test_sequentially(|store| async move {
+        let deployment = setup(store.as_ref());
+        let logger = Logger::root(slog::Discard, o!());
+        let store = STORE
+            .clone()
+            .query_store(deployment.hash.clone().into(), true)
+            .await
+            .unwrap();
+        let schema = STORE.subgraph_store().api_schema(&deployment.hash).unwrap();
+
+        let query = Query::new(
+            graphql_parser::parse_query(
+                "subscription {
+              musicians(orderBy: id, first: 2) {
+                name
+              }
+            }",
+            )
+            .unwrap()
+            .into_static(),
+            None,
+        );
+
+        let options = SubscriptionExecutionOptions {
+            logger: logger.clone(),
+            store,
+            subscription_manager: SUBSCRIPTION_MANAGER.clone(),
+            timeout: None,
+            max_complexity: None,
+            max_depth: 100,
+            max_first: std::u32::MAX,
+            max_skip: std::u32::MAX,
+            result_size: result_size_metrics(),
+        };
+        let mut stream = execute_subscription(Subscription { query }, schema, options)
+            .await
+            .unwrap();
+        let result = stream
+            .next()
+            .timeout(Duration::from_secs(3))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            extract_data!(result.duplicate()),
+            musicians(&["John", "Lisa"])
+        );
+
+        // Changing a musician the subscription does not select reruns the
+        // query, but the result is the same as before and is not sent
+        set_musician(
+            &deployment,
+            BLOCK_TWO.block_ptr(),
+            Entity::from(vec![
+                ("__typename", Value::from("Musician")),
+                ("id", Value::from("m3")),
+                ("name", Value::from("Tom")),
+                ("mainBand", Value::from("b1")),
+                (
+                    "bands",
+                    Value::List(vec![Value::from("b1"), Value::from("b2")]),
+                ),
+            ]),
+        );
+        set_musician(
+            &deployment,
+            BLOCK_THREE.block_ptr(),
+            Entity::from(vec![
+                ("__typename", Value::from("Musician")),
+                ("id", Value::from("m1")),
+                ("name", Value::from("Johnny")),
+                ("mainBand", Value::from("b1")),
+                (
+                    "bands",
+                    Value::List(vec![Value::from("b1"), Value::from("b2")]),
+                ),
+            ]),
+        );
+
+        let result = stream
+            .next()
+            .timeout(Duration::from_secs(3))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            extract_data!(result.duplicate()),
+            musicians(&["Johnny", "Lisa"])
+        );
+    })
+}
+
 #[test]
 fn can_use_nested_filter() {
     run_test_sequentially(|store| async move {
@@ -1476,7 +1691,8 @@ fn query_at_block() {
 
         const BLOCK_NOT_INDEXED: &str = "subgraph graphqlTestsQuery has only indexed \
          up to block number 1 and data for block number 7000 is therefore not yet available";
-        const BLOCK_HASH_NOT_FOUND: &str = "no block with that hash found";
+        const BLOCK_HASH_NOT_INDEXED: &str = "subgraph graphqlTestsQuery has only indexed \
+         up to block number 1 and has not processed block";
 
         let deployment = setup(store.as_ref());
         musicians_at(&deployment, "number: 7000", Err(BLOCK_NOT_INDEXED), "n7000").await;
@@ -1489,6 +1705,21 @@ fn query_at_block() {
         )
         .await;
 
+        musicians_at(
+            &deployment,
+            "number_gte: 0",
+            Ok(vec!["m1", "m2", "m3", "m4"]),
+            "ngte0",
+        )
+        .await;
+        musicians_at(
+            &deployment,
+            "number_gte: 7000",
+            Err(BLOCK_NOT_INDEXED),
+            "ngte7000",
+        )
+        .await;
+
         musicians_at(
             &deployment,
             &hash(&*GENESIS_BLOCK),
@@ -1506,17 +1737,41 @@ fn query_at_block() {
         musicians_at(
             &deployment,
             &hash(&*BLOCK_TWO),
-            Ok(vec!["m1", "m2", "m3", "m4"]),
+            Err(BLOCK_HASH_NOT_INDEXED),
             "h2",
         )
         .await;
         musicians_at(
             &deployment,
             &hash(&*BLOCK_THREE),
-            Err(BLOCK_HASH_NOT_FOUND),
+            Err(BLOCK_HASH_NOT_INDEXED),
             "h3",
         )
         .await;
+
+        // Knowing the chain head does not tell us that a hash that is not
+        // in the block cache will never be ingested
+        store
+            .block_store()
+            .chain_store(NETWORK_NAME)
+            .unwrap()
+            .attempt_chain_head_update(10)
+            .await
+            .unwrap();
+        musicians_at(
+            &deployment,
+            &hash(&*BLOCK_TWO),
+            Err(BLOCK_HASH_NOT_INDEXED),
+            "h2-head",
+        )
+        .await;
+        musicians_at(
+            &deployment,
+            &hash(&*BLOCK_THREE),
+            Err(BLOCK_HASH_NOT_INDEXED),
+            "h3-head",
+        )
+        .await;
     })
 }
 
@@ -1563,7 +1818,8 @@ fn query_at_block_with_vars() {
 
         const BLOCK_NOT_INDEXED: &str = "subgraph graphqlTestsQuery has only indexed \
          up to block number 1 and data for block number 7000 is therefore not yet available";
-        const BLOCK_HASH_NOT_FOUND: &str = "no block with that hash found";
+        const BLOCK_HASH_NOT_INDEXED: &str = "subgraph graphqlTestsQuery has only indexed \
+         up to block number 1 and has not processed block";
 
         let deployment = setup(store.as_ref());
         musicians_at_nr(&deployment, 7000, Err(BLOCK_NOT_INDEXED), "n7000").await;
@@ -1578,14 +1834,8 @@ fn query_at_block_with_vars() {
             "h1",
         )
         .await;
-        musicians_at_hash(
-            &deployment,
-            &BLOCK_TWO,
-            Ok(vec!["m1", "m2", "m3", "m4"]),
-            "h2",
-        )
-        .await;
-        musicians_at_hash(&deployment, &BLOCK_THREE, Err(BLOCK_HASH_NOT_FOUND), "h3").await;
+        musicians_at_hash(&deployment, &BLOCK_TWO, Err(BLOCK_HASH_NOT_INDEXED), "h2").await;
+        musicians_at_hash(&deployment, &BLOCK_THREE, Err(BLOCK_HASH_NOT_INDEXED), "h3").await;
     })
 }
 
@@ -1652,7 +1902,8 @@ fn query_detects_reorg() {
 fn can_query_meta() {
     run_test_sequentially(|store| async move {
         // metadata for the latest block (block 1)
-        let query = "query { _meta { deployment block { hash number __typename } __typename } }";
+        let query =
+            "query { _meta { deployment block { hash number timestamp __typename } __typename } }";
         let query = graphql_parser::parse_query(query)
             .expect("invalid test query")
             .into_static();
@@ -1664,6 +1915,7 @@ fn can_query_meta() {
                 block: object! {
                     hash: "0x8511fa04b64657581e3f00e14543c1d522d5d7e771b54aa3060b662ade47da13",
                     number: 1,
+                    timestamp: 0,
                     __typename: "_Block_"
                 },
                 deployment: "graphqlTestsQuery",
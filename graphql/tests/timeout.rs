@@ -0,0 +1,145 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use graph::data::graphql::ObjectOrInterface;
+use graph::prelude::{
+    async_trait, o, q, r, s, slog, tokio, ApiSchema, DeploymentHash, FutureExtension, Logger,
+    Query, QueryError, QueryExecutionError, Schema,
+};
+use graph_graphql::prelude::{
+    execute_query, ExecutionContext, Query as PreparedQuery, QueryExecutionOptions, Resolver,
+};
+use test_store::LOAD_MANAGER;
+
+/// A resolver whose `prefetch` behaves like a slow database query: it
+/// runs until the test releases it
+#[derive(Clone)]
+struct SlowResolver {
+    cancelled: Arc<AtomicBool>,
+    released: Arc<AtomicBool>,
+    permits: Arc<tokio::sync::Semaphore>,
+}
+
+impl SlowResolver {
+    fn new() -> Self {
+        SlowResolver {
+            cancelled: Arc::new(AtomicBool::new(false)),
+            released: Arc::new(AtomicBool::new(false)),
+            permits: Arc::new(tokio::sync::Semaphore::new(1)),
+        }
+    }
+}
+
+#[async_trait]
+impl Resolver for SlowResolver {
+    const CACHEABLE: bool = false;
+
+    fn prefetch(
+        &self,
+        _: &ExecutionContext<Self>,
+        _: &q::SelectionSet,
+    ) -> Result<Option<r::Value>, Vec<QueryExecutionError>> {
+        while !self.released.load(Ordering::SeqCst) {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        Err(vec![QueryExecutionError::Cancelled])
+    }
+
+    fn resolve_objects<'a>(
+        &self,
+        _: Option<r::Value>,
+        _field: &q::Field,
+        _field_definition: &s::Field,
+        _object_type: ObjectOrInterface<'_>,
+        _arguments: &HashMap<&str, r::Value>,
+    ) -> Result<r::Value, QueryExecutionError> {
+        Ok(r::Value::Null)
+    }
+
+    fn resolve_object(
+        &self,
+        __: Option<r::Value>,
+        _field: &q::Field,
+        _field_definition: &s::Field,
+        _object_type: ObjectOrInterface<'_>,
+        _arguments: &HashMap<&str, r::Value>,
+    ) -> Result<r::Value, QueryExecutionError> {
+        Ok(r::Value::Null)
+    }
+
+    async fn query_permit(&self) -> tokio::sync::OwnedSemaphorePermit {
+        self.permits.clone().acquire_owned().await.unwrap()
+    }
+
+    fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+}
+
+fn schema() -> Schema {
+    Schema::parse(
+        "
+             scalar ID
+
+             type User @entity {
+               id: ID!
+             }
+
+             type Query @entity {
+               allUsers: [User!]
+             }
+             ",
+        DeploymentHash::new("timeoutschema").unwrap(),
+    )
+    .unwrap()
+}
+
+#[tokio::test]
+async fn slow_query_times_out_and_is_cancelled() {
+    let resolver = SlowResolver::new();
+    let logger = Logger::root(slog::Discard, o!());
+    let query = Query::new(
+        graphql_parser::parse_query("{ allUsers { id } }")
+            .unwrap()
+            .into_static(),
+        None,
+    );
+    let options = QueryExecutionOptions {
+        resolver: resolver.clone(),
+        deadline: Some(Instant::now() + Duration::from_millis(100)),
+        max_first: std::u32::MAX,
+        max_skip: std::u32::MAX,
+        load_manager: LOAD_MANAGER.clone(),
+    };
+
+    let schema = Arc::new(ApiSchema::from_api_schema(schema()).unwrap());
+    let query = PreparedQuery::new(&logger, schema, None, query, None, 100).unwrap();
+    let result = execute_query(query, None, None, options).await;
+
+    match Arc::try_unwrap(result).unwrap().to_result() {
+        Err(errors) => assert!(
+            matches!(
+                errors.as_slice(),
+                [QueryError::ExecutionError(QueryExecutionError::Timeout)]
+            ),
+            "unexpected errors {:?}",
+            errors
+        ),
+        Ok(data) => panic!("expected a timeout but got {:?}", data),
+    }
+    assert!(resolver.cancelled.load(Ordering::SeqCst));
+
+    // The query still holds its permit while the blocking work goes on,
+    // and gives it back once that stops
+    assert_eq!(0, resolver.permits.available_permits());
+    resolver.released.store(true, Ordering::SeqCst);
+    let permit = resolver
+        .permits
+        .clone()
+        .acquire_owned()
+        .timeout(Duration::from_secs(3))
+        .await;
+    assert!(permit.is_ok(), "the query did not give its permit back");
+}
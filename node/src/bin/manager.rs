@@ -10,7 +10,7 @@ use structopt::StructOpt;
 
 use graph::{
     log::logger,
-    prelude::{info, o, slog, tokio, Logger, NodeId},
+    prelude::{info, o, slog, tokio, web3::types::Address, BlockNumber, Logger, NodeId},
 };
 use graph_node::{manager::PanicSubscriptionManager, store_builder::StoreBuilder};
 use graph_store_postgres::{
@@ -123,6 +123,38 @@ pub enum Command {
         /// The shard of the deployment if `id` itself is ambiguous
         shard: Option<String>,
     },
+    /// Stop serving queries for a deployment
+    ///
+    /// Queries for the deployment fail with a maintenance error until
+    /// queries are enabled again. Indexing is not affected
+    DisableQueries {
+        /// The id of the deployment
+        id: String,
+        /// The shard of the deployment if `id` itself is ambiguous
+        shard: Option<String>,
+    },
+    /// Resume serving queries for a deployment
+    EnableQueries {
+        /// The id of the deployment
+        id: String,
+        /// The shard of the deployment if `id` itself is ambiguous
+        shard: Option<String>,
+    },
+    /// Set how long SQL queries for a deployment may run
+    ///
+    /// The timeout overrides GRAPH_SQL_STATEMENT_TIMEOUT for the
+    /// deployment. Without `--seconds`, the deployment goes back to using
+    /// GRAPH_SQL_STATEMENT_TIMEOUT. Query nodes pick up the change within
+    /// GRAPH_QUERY_STATS_REFRESH_INTERVAL
+    StatementTimeout {
+        /// The timeout in seconds
+        #[structopt(long, short)]
+        seconds: Option<u32>,
+        /// The id of the deployment
+        id: String,
+        /// The shard of the deployment if `id` itself is ambiguous
+        shard: Option<String>,
+    },
     /// Rewind a subgraph to a specific block
     Rewind {
         /// Force rewinding even if the block hash is not found in the local
@@ -168,6 +200,82 @@ pub enum Command {
     Chain(ChainCommand),
     /// Manipulate internal subgraph statistics
     Stats(StatsCommand),
+    /// Remove old entity versions of a deployment
+    ///
+    /// Remove all entity versions that are only visible more than
+    /// `history` blocks before the current block of the deployment.
+    /// Queries for those blocks fail afterwards, and the deployment can
+    /// not be rewound to them anymore. Indexing continues while the
+    /// deployment is pruned
+    Prune {
+        /// How many blocks of history to keep; must be more than the reorg
+        /// threshold
+        #[structopt(long, default_value = "10000")]
+        history: BlockNumber,
+        /// The id of the deployment
+        id: String,
+        /// The shard of the deployment if `id` itself is ambiguous
+        shard: Option<String>,
+    },
+    /// Partition large entity tables of a deployment by block
+    ///
+    /// Replace tables with tables that are partitioned by the block at
+    /// which entity versions start. Without a list of tables, partition all
+    /// tables with at least `min-rows` rows. The deployment can be queried
+    /// and indexed while a table is copied; indexing only pauses while the
+    /// changes made during the copy are applied to the partitioned table
+    Partition {
+        /// How many blocks each partition covers
+        #[structopt(long, default_value = "100000")]
+        blocks: BlockNumber,
+        /// Only partition tables with at least this many rows
+        #[structopt(long, default_value = "10000000")]
+        min_rows: i64,
+        /// The shard of the deployment if `id` itself is ambiguous
+        #[structopt(long)]
+        shard: Option<String>,
+        /// The id of the deployment
+        id: String,
+        /// The entity types whose tables should be partitioned
+        tables: Vec<String>,
+    },
+    /// Check that the data of a deployment matches its block pointer
+    ///
+    /// Look for entity versions and dynamic data sources from blocks after
+    /// the block pointer of the deployment, which can be left behind by
+    /// crashes or incomplete database restores. Exits with an error if any
+    /// are found. With `--repair`, remove them; the deployment must not be
+    /// indexed while it is repaired
+    Check {
+        /// Remove data from blocks after the block pointer
+        #[structopt(long)]
+        repair: bool,
+        /// The id of the deployment
+        id: String,
+        /// The shard of the deployment if `id` itself is ambiguous
+        shard: Option<String>,
+    },
+    /// Manage additional indexes on entity tables
+    Index(IndexCommand),
+    /// Compare proofs of indexing with other indexers
+    Poi(PoiCommand),
+    /// Check that the environment is set up correctly for graph-node
+    ///
+    /// Connect to all databases, providers and IPFS nodes and report any
+    /// problems together with suggestions for how to fix them. Exits with
+    /// an error if any check fails
+    Doctor {
+        /// HTTP addresses of the IPFS nodes that graph-node uses
+        #[structopt(long, value_name = "HOST:PORT", env = "IPFS")]
+        ipfs: Vec<String>,
+        /// How many seconds to wait for each provider and IPFS node to respond
+        #[structopt(
+            long,
+            default_value = "10",
+            parse(try_from_str = parse_duration_in_secs)
+        )]
+        timeout: Duration,
+    },
 }
 
 impl Command {
@@ -249,11 +357,16 @@ pub enum CopyCommand {
     /// shard `shard` and will be assigned to `node` for indexing. The new
     /// subgraph will start as a copy of all blocks of `src` that are
     /// `offset` behind the current subgraph head of `src`. The offset
-    /// should be chosen such that only final blocks are copied
+    /// should be chosen such that only final blocks are copied. With
+    /// `--activate`, the copy replaces `src` for queries as soon as it has
+    /// caught up with it
     Create {
         /// How far behind `src` subgraph head to copy
         #[structopt(long, short, default_value = "200")]
         offset: u32,
+        /// Activate the copy once it has caught up with `src`
+        #[structopt(long)]
+        activate: bool,
         /// The IPFS hash of the source deployment
         src: String,
         /// The name of the database shard into which to copy
@@ -306,6 +419,27 @@ pub enum ChainCommand {
     /// There must be no deployments using that chain. If there are, the
     /// subgraphs and/or deployments using the chain must first be removed
     Remove { name: String },
+    /// Remove cached `eth_call` results for a range of blocks
+    ///
+    /// Calls for blocks in the range `from..=to` are sent to the Ethereum
+    /// node again the next time a mapping makes them. Chains whose data is
+    /// stored in the shared `public` namespace share their call cache with
+    /// all other chains in that shard; clearing it for them requires
+    /// `--all-chains`
+    ClearCallCache {
+        /// The first block whose cached calls should be removed
+        #[structopt(long)]
+        from: BlockNumber,
+        /// The last block whose cached calls should be removed
+        #[structopt(long)]
+        to: BlockNumber,
+        /// Also remove the cached calls of all other chains in the shard
+        /// if the chain shares its call cache with them
+        #[structopt(long)]
+        all_chains: bool,
+        /// The name of the chain
+        name: String,
+    },
 }
 
 #[derive(Clone, Debug, StructOpt)]
@@ -337,6 +471,78 @@ pub enum StatsCommand {
     },
 }
 
+#[derive(Clone, Debug, StructOpt)]
+pub enum PoiCommand {
+    /// Compare the proofs of indexing of a deployment with another indexer
+    ///
+    /// Fetch proofs of indexing from the index node API at `remote` and
+    /// find the first block in the given range where they differ from the
+    /// local ones. Then show the entities that changed at that block on
+    /// either side, using the `entityDiff` query of the remote index node
+    Compare {
+        /// The URL of the index node API of the other indexer, for example
+        /// `http://indexer.example.com:8030/graphql`
+        #[structopt(long, short)]
+        remote: String,
+        /// The indexer address to use for the proofs of indexing
+        #[structopt(long, short)]
+        indexer: Option<Address>,
+        /// The deployment, an id, schema name or subgraph name
+        deployment: String,
+        /// The first block to compare; the proofs of indexing must agree
+        /// at this block
+        from: BlockNumber,
+        /// The last block to compare; defaults to the latest block the
+        /// deployment has indexed locally
+        to: Option<BlockNumber>,
+    },
+}
+
+#[derive(Clone, Debug, StructOpt)]
+pub enum IndexCommand {
+    /// Create an index on attributes of an entity type
+    ///
+    /// The index is built concurrently so that indexing and queries can
+    /// continue while it is created. If creating it fails, `list` shows it
+    /// as invalid; drop it and try again
+    Create {
+        /// The index method, one of btree, hash, gist, spgist, gin or brin
+        #[structopt(long, short, default_value = "btree")]
+        method: String,
+        /// The shard of the deployment if `id` itself is ambiguous
+        #[structopt(long)]
+        shard: Option<String>,
+        /// The id of the deployment
+        id: String,
+        /// The entity type
+        entity: String,
+        /// The attributes to index, in the order they should appear in the
+        /// index
+        #[structopt(required = true)]
+        fields: Vec<String>,
+    },
+    /// List the indexes on the table of an entity type
+    List {
+        /// The shard of the deployment if `id` itself is ambiguous
+        #[structopt(long)]
+        shard: Option<String>,
+        /// The id of the deployment
+        id: String,
+        /// The entity type
+        entity: String,
+    },
+    /// Drop an index that was created with `graphman index create`
+    Drop {
+        /// The shard of the deployment if `id` itself is ambiguous
+        #[structopt(long)]
+        shard: Option<String>,
+        /// The id of the deployment
+        id: String,
+        /// The name of the index
+        name: String,
+    },
+}
+
 impl From<Opt> for config::Opt {
     fn from(opt: Opt) -> Self {
         let mut config_opt = config::Opt::default();
@@ -556,6 +762,15 @@ async fn main() {
         Reassign { id, node, shard } => {
             commands::assign::reassign(ctx.subgraph_store(), id, node, shard)
         }
+        DisableQueries { id, shard } => {
+            commands::maintenance::disable_queries(ctx.subgraph_store(), id, shard)
+        }
+        EnableQueries { id, shard } => {
+            commands::maintenance::enable_queries(ctx.subgraph_store(), id, shard)
+        }
+        StatementTimeout { seconds, id, shard } => {
+            commands::maintenance::statement_timeout(ctx.subgraph_store(), id, shard, seconds)
+        }
         Rewind {
             force,
             sleep,
@@ -595,8 +810,20 @@ async fn main() {
                     shard,
                     node,
                     offset,
+                    activate,
                     src_shard,
-                } => commands::copy::create(ctx.store(), src, src_shard, shard, node, offset).await,
+                } => {
+                    commands::copy::create(
+                        ctx.store(),
+                        src,
+                        src_shard,
+                        shard,
+                        node,
+                        offset,
+                        activate,
+                    )
+                    .await
+                }
                 Activate { deployment, shard } => {
                     commands::copy::activate(ctx.subgraph_store(), deployment, shard)
                 }
@@ -628,6 +855,15 @@ async fn main() {
                     let (block_store, primary) = ctx.block_store_and_primary_pool();
                     commands::chain::remove(primary, block_store, name)
                 }
+                ClearCallCache {
+                    from,
+                    to,
+                    all_chains,
+                    name,
+                } => {
+                    let (block_store, _) = ctx.block_store_and_primary_pool();
+                    commands::chain::clear_call_cache(block_store, name, from, to, all_chains)
+                }
             }
         }
         Stats(cmd) => {
@@ -639,6 +875,64 @@ async fn main() {
                 Show { nsp, table } => commands::stats::show(ctx.pools(), nsp, table),
             }
         }
+        Prune { history, id, shard } => {
+            commands::prune::run(ctx.subgraph_store(), id, shard, history)
+        }
+        Partition {
+            blocks,
+            min_rows,
+            shard,
+            id,
+            tables,
+        } => commands::partition::run(ctx.subgraph_store(), id, shard, tables, blocks, min_rows),
+        Check { repair, id, shard } => {
+            commands::check::run(ctx.subgraph_store(), id, shard, repair)
+        }
+        Index(cmd) => {
+            use IndexCommand::*;
+            match cmd {
+                Create {
+                    method,
+                    shard,
+                    id,
+                    entity,
+                    fields,
+                } => {
+                    commands::index::create(ctx.subgraph_store(), id, shard, entity, fields, method)
+                }
+                List { shard, id, entity } => {
+                    commands::index::list(ctx.subgraph_store(), id, shard, entity)
+                }
+                Drop { shard, id, name } => {
+                    commands::index::drop(ctx.subgraph_store(), id, shard, name)
+                }
+            }
+        }
+        Poi(cmd) => {
+            use PoiCommand::*;
+            match cmd {
+                Compare {
+                    remote,
+                    indexer,
+                    deployment,
+                    from,
+                    to,
+                } => {
+                    let logger = ctx.logger.clone();
+                    let config = ctx.config.clone();
+                    let registry = ctx.registry.clone();
+                    let (store, primary) = ctx.store_and_primary();
+                    commands::poi::compare(
+                        &logger, &config, registry, primary, store, deployment, remote, from, to,
+                        indexer,
+                    )
+                    .await
+                }
+            }
+        }
+        Doctor { ipfs, timeout } => {
+            commands::doctor::run(&ctx.logger, &ctx.config, ctx.registry, ipfs, timeout).await
+        }
     };
     if let Err(e) = result {
         die!("error: {}", e)
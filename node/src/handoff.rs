@@ -0,0 +1,92 @@
+//! Hand the deployments assigned to a node over from a running graph-node
+//! process to a new one with the same node id, for example during a
+//! rolling upgrade. The new process requests the handoff through the
+//! primary and waits; the running process notices the request, stops
+//! processing blocks, records that it has released its deployments, and
+//! exits. Only then does the new process start indexing
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use graph::prelude::{info, warn, Logger, NodeId};
+use graph_core::IndexingShutdown;
+use graph_store_postgres::{Handoff, SubgraphStore};
+
+/// How often to check the state of a handoff
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Ask the process currently running as `node` to stop indexing and wait
+/// until it has done so, or until `timeout` has passed, in which case we
+/// assume that there is no such process
+pub async fn take_over(logger: &Logger, store: &SubgraphStore, node: &NodeId, timeout: Duration) {
+    if let Err(e) = store.request_handoff(node) {
+        warn!(logger, "Failed to request handoff, starting subgraphs anyway";
+                      "error" => e.to_string());
+        return;
+    }
+    info!(logger, "Waiting for the running graph-node to release its subgraphs";
+                  "node_id" => node.as_str());
+
+    let start = Instant::now();
+    loop {
+        tokio::time::sleep(POLL_INTERVAL).await;
+        match store.handoff(node) {
+            Ok(Some(Handoff::Released)) => {
+                info!(logger, "Subgraphs were released, taking over");
+                break;
+            }
+            Ok(_) if start.elapsed() >= timeout => {
+                warn!(logger, "No graph-node released its subgraphs in time, taking over anyway";
+                              "timeout_s" => timeout.as_secs());
+                break;
+            }
+            Ok(_) => {}
+            Err(e) => warn!(logger, "Failed to check handoff"; "error" => e.to_string()),
+        }
+    }
+
+    if let Err(e) = store.clear_handoff(node) {
+        warn!(logger, "Failed to clear handoff"; "error" => e.to_string());
+    }
+}
+
+/// Watch for another process asking to take over `node`. When that
+/// happens, stop all subgraphs, tell the other process that it can start,
+/// and exit
+pub async fn watch(
+    logger: Logger,
+    store: Arc<SubgraphStore>,
+    node: NodeId,
+    shutdown: IndexingShutdown,
+    shutdown_timeout: Duration,
+) {
+    loop {
+        tokio::time::sleep(POLL_INTERVAL).await;
+        match store.handoff(&node) {
+            Ok(Some(Handoff::Requested)) => break,
+            Ok(_) => {}
+            Err(e) => warn!(logger, "Failed to check for handoff requests";
+                                    "error" => e.to_string()),
+        }
+    }
+
+    info!(
+        logger,
+        "Another graph-node is taking over, stopping subgraphs"
+    );
+    if !shutdown.shutdown(&logger, shutdown_timeout).await {
+        // Releasing now would let the new process index blocks that this
+        // one might still be writing
+        warn!(
+            logger,
+            "Subgraphs did not stop; the other graph-node will wait until it times out"
+        );
+    } else if let Err(e) = store.release_handoff(&node) {
+        warn!(logger, "Failed to release subgraphs"; "error" => e.to_string());
+    } else {
+        info!(logger, "Released subgraphs, shutting down");
+    }
+
+    // Returning from `main` would wait for blocking tasks that never finish
+    std::process::exit(0);
+}
@@ -18,16 +18,18 @@ use graph::blockchain::block_ingestor::BlockIngestor;
 use graph::blockchain::{
     BlockHash, Blockchain as _, BlockchainKind, BlockchainMap, ChainIdentifier,
 };
-use graph::components::store::BlockStore;
+use graph::components::store::{BlockStore, REORG_THRESHOLD};
 use graph::data::graphql::effort::LoadManager;
-use graph::log::logger;
+use graph::log::logger_with_format;
 use graph::prelude::{IndexNodeServer as _, JsonRpcServer as _, *};
+use graph::util::memory_budget::MEMORY_BUDGET;
 use graph::util::security::SafeDisplay;
 use graph_chain_ethereum::{self as ethereum, network_indexer, EthereumAdapterTrait, Transport};
 use graph_chain_near::{self as near};
 use graph_core::{
-    LinkResolver, MetricsRegistry, SubgraphAssignmentProvider as IpfsSubgraphAssignmentProvider,
-    SubgraphInstanceManager, SubgraphRegistrar as IpfsSubgraphRegistrar,
+    IndexingShutdown, LinkResolver, MetricsRegistry,
+    SubgraphAssignmentProvider as IpfsSubgraphAssignmentProvider, SubgraphInstanceManager,
+    SubgraphRegistrar as IpfsSubgraphRegistrar, TransactionSimulator,
 };
 use graph_graphql::prelude::GraphQlRunner;
 use graph_server_http::GraphQLServer as GraphQLQueryServer;
@@ -35,24 +37,32 @@ use graph_server_index_node::IndexNodeServer;
 use graph_server_json_rpc::JsonRpcServer;
 use graph_server_metrics::PrometheusMetricsServer;
 use graph_server_websocket::SubscriptionServer as GraphQLSubscriptionServer;
-use graph_store_postgres::{register_jobs as register_store_jobs, ChainHeadUpdateListener, Store};
+use graph_store_postgres::{
+    connection_pool, register_jobs as register_store_jobs, ChainHeadUpdateListener, Store,
+};
 
 mod config;
+mod handoff;
 mod opt;
+mod rotation;
 mod store_builder;
 
 use config::Config;
+use rotation::SecretRotation;
 use store_builder::StoreBuilder;
 
 use crate::config::ProviderDetails;
+use crate::opt::NodeRole;
 
 lazy_static! {
-    // Default to an Ethereum reorg threshold to 50 blocks
-    static ref REORG_THRESHOLD: BlockNumber = env::var("ETHEREUM_REORG_THRESHOLD")
+    // How long to wait for subgraphs to finish the block they are processing when
+    // shutting down. Defaults to 60 seconds.
+    static ref SHUTDOWN_TIMEOUT: Duration = env::var("GRAPH_SHUTDOWN_TIMEOUT")
         .ok()
-        .map(|s| BlockNumber::from_str(&s)
-            .unwrap_or_else(|_| panic!("failed to parse env var ETHEREUM_REORG_THRESHOLD")))
-        .unwrap_or(50);
+        .map(|s| u64::from_str(&s)
+            .unwrap_or_else(|_| panic!("failed to parse env var GRAPH_SHUTDOWN_TIMEOUT")))
+        .map(Duration::from_secs)
+        .unwrap_or(Duration::from_secs(60));
 
     // Default to an ancestor count of 50 blocks
     static ref ANCESTOR_COUNT: BlockNumber = env::var("ETHEREUM_ANCESTOR_COUNT")
@@ -109,7 +119,7 @@ async fn main() {
     let opt = opt::Opt::from_args();
 
     // Set up logger
-    let logger = logger(opt.debug);
+    let logger = logger_with_format(opt.debug, opt.log_format);
 
     // Log version information
     info!(
@@ -138,13 +148,30 @@ async fn main() {
         eprintln!("Successfully validated configuration");
         std::process::exit(0);
     }
+    if opt.check_migrations {
+        std::process::exit(check_migrations(&config));
+    }
+    let mut rotation = SecretRotation::new(&logger, opt.clone().into(), config.clone());
 
     let node_id =
         NodeId::new(opt.node_id.clone()).expect("Node ID must contain only a-z, A-Z, 0-9, and '_'");
-    let query_only = config.query_only(&node_id);
+    // Nodes that the configuration marks as query nodes never index
+    let node_role = match (config.query_only(&node_id), opt.node_role) {
+        (false, role) => role,
+        (true, NodeRole::Query) | (true, NodeRole::Both) => NodeRole::Query,
+        (true, NodeRole::Index) => {
+            eprintln!(
+                "configuration error: node `{}` is a query node, but was started with --node-role index",
+                node_id
+            );
+            std::process::exit(1);
+        }
+    };
+    let query_only = !node_role.indexes();
 
     // Obtain subgraph related command-line arguments
-    let subgraph = opt.subgraph.clone();
+    let subgraphs = opt.subgraph.clone();
+    let take_over = opt.handoff;
 
     // Obtain ports to use for the GraphQL server(s)
     let http_port = opt.http_port;
@@ -189,23 +216,34 @@ async fn main() {
     ));
     let mut metrics_server =
         PrometheusMetricsServer::new(&logger_factory, prometheus_registry.clone());
+    MEMORY_BUDGET.register_metrics(metrics_registry.as_ref());
 
     // Ethereum clients; query nodes ignore all ethereum clients and never
     // connect to them directly
     let eth_networks = if query_only {
         EthereumNetworks::new()
     } else {
-        create_ethereum_networks(logger.clone(), metrics_registry.clone(), config.clone())
-            .await
-            .expect("Failed to parse Ethereum networks")
+        create_ethereum_networks(
+            logger.clone(),
+            metrics_registry.clone(),
+            config.clone(),
+            &mut rotation,
+        )
+        .await
+        .expect("Failed to parse Ethereum networks")
     };
 
     let firehose_networks_by_kind = if query_only {
         BTreeMap::new()
     } else {
-        create_firehose_networks(logger.clone(), metrics_registry.clone(), &config)
-            .await
-            .expect("Failed to parse Firehose networks")
+        create_firehose_networks(
+            logger.clone(),
+            metrics_registry.clone(),
+            &config,
+            &mut rotation,
+        )
+        .await
+        .expect("Failed to parse Firehose networks")
     };
 
     let graphql_metrics_registry = metrics_registry.clone();
@@ -217,6 +255,23 @@ async fn main() {
     let store_builder =
         StoreBuilder::new(&logger, &node_id, &config, metrics_registry.cheap_clone()).await;
 
+    // Secrets can only change when they come from a configuration file
+    rotation.add_pools(store_builder.all_pools());
+    rotation.add_listeners(
+        store_builder.subscription_manager(),
+        store_builder.chain_head_update_listener(),
+    );
+    if opt.config.is_some() {
+        graph::spawn(rotation.watch());
+    }
+
+    let indexing_shutdown = IndexingShutdown::default();
+    let services_shutdown = indexing_shutdown.cheap_clone();
+    let handoff_shutdown = indexing_shutdown.cheap_clone();
+    let entity_caches = Arc::new(EntityCacheControl::new(
+        config.deployment.entity_cache_sizes(),
+    ));
+
     let launch_services = |logger: Logger| async move {
         let subscription_manager = store_builder.subscription_manager();
         let chain_head_update_listener = store_builder.chain_head_update_listener();
@@ -281,17 +336,28 @@ async fn main() {
         let subscription_server =
             GraphQLSubscriptionServer::new(&logger, graphql_runner.clone(), network_store.clone());
 
+        // Simulating transactions runs the handlers of a subgraph on
+        // request, and is therefore only available when asked for
+        let simulator = env::var_os("GRAPH_ALLOW_TRANSACTION_SIMULATION").map(|_| {
+            Arc::new(TransactionSimulator::new(
+                network_store.subgraph_store(),
+                blockchain_map.cheap_clone(),
+                link_resolver.clone(),
+            )) as Arc<dyn graph::components::subgraph::TransactionSimulator>
+        });
         let mut index_node_server = IndexNodeServer::new(
             &logger_factory,
             graphql_runner.clone(),
             network_store.clone(),
             link_resolver.clone(),
             network_store.subgraph_store().clone(),
+            simulator,
         );
 
         // Spawn Ethereum network indexers for all networks that are to be indexed
         opt.network_subgraphs
             .iter()
+            .filter(|_| node_role.indexes())
             .filter(|network_subgraph| network_subgraph.starts_with("ethereum/"))
             .for_each(|network_subgraph| {
                 let network_name = network_subgraph.replace("ethereum/", "");
@@ -326,7 +392,9 @@ async fn main() {
                 );
             });
 
-        if !opt.disable_block_ingestor {
+        // Query nodes do not write to the store; block ingestion and store
+        // maintenance are left to index nodes
+        if node_role.indexes() && !opt.disable_block_ingestor {
             if ethereum_chains.len() > 0 {
                 let block_polling_interval = Duration::from_millis(opt.ethereum_polling_interval);
 
@@ -350,6 +418,9 @@ async fn main() {
             blockchain_map.cheap_clone(),
             metrics_registry.clone(),
             link_resolver.cheap_clone(),
+            services_shutdown,
+            entity_caches.cheap_clone(),
+            config.deployment.max_data_sources(),
         );
 
         // Create IPFS-based subgraph provider
@@ -378,12 +449,41 @@ async fn main() {
             node_id.clone(),
             version_switching_mode,
         ));
-        graph::spawn(
-            subgraph_registrar
+        // Only start subgraphs once a graph-node that might still be
+        // running for this node id has stopped indexing them
+        let subgraph_store = network_store.subgraph_store();
+        let registrar = subgraph_registrar.cheap_clone();
+        let handoff_node = node_id.clone();
+        let handoff_logger = logger.clone();
+        let start_subgraphs = async move {
+            if take_over {
+                handoff::take_over(
+                    &handoff_logger,
+                    &subgraph_store,
+                    &handoff_node,
+                    *SHUTDOWN_TIMEOUT * 2,
+                )
+                .await;
+            } else if let Err(e) = subgraph_store.clear_handoff(&handoff_node) {
+                warn!(handoff_logger, "Failed to clear stale handoff"; "error" => e.to_string());
+            }
+            graph::spawn(handoff::watch(
+                handoff_logger,
+                subgraph_store,
+                handoff_node,
+                handoff_shutdown,
+                *SHUTDOWN_TIMEOUT,
+            ));
+
+            registrar
                 .start()
                 .map_err(|e| panic!("failed to initialize subgraph provider {}", e))
-                .compat(),
-        );
+                .compat()
+                .await
+        };
+        if node_role.indexes() {
+            graph::spawn(start_subgraphs);
+        }
 
         // Start admin JSON-RPC server.
         let json_rpc_server = JsonRpcServer::serve(
@@ -391,6 +491,7 @@ async fn main() {
             http_port,
             ws_port,
             subgraph_registrar.clone(),
+            entity_caches,
             node_id.clone(),
             logger.clone(),
         )
@@ -399,8 +500,10 @@ async fn main() {
         // Let the server run forever.
         std::mem::forget(json_rpc_server);
 
-        // Add the CLI subgraph with a REST request to the admin server.
-        if let Some(subgraph) = subgraph {
+        // Create and deploy the subgraphs from `--subgraph` unless they
+        // are already deployed, so that a fresh installation comes up with
+        // them without having to talk to the admin server
+        for subgraph in subgraphs {
             let (name, hash) = if subgraph.contains(':') {
                 let mut split = subgraph.split(':');
                 (split.next().unwrap(), split.next().unwrap().to_owned())
@@ -413,8 +516,20 @@ async fn main() {
             let subgraph_id =
                 DeploymentHash::new(hash).expect("Subgraph hash must be a valid IPFS hash");
 
+            let subgraph_registrar = subgraph_registrar.cheap_clone();
+            let subgraph_store = network_store.subgraph_store();
+            let node_id = node_id.clone();
+            let logger = logger.clone();
             graph::spawn(
                 async move {
+                    if subgraph_store.subgraph_exists(&name)?
+                        && subgraph_store.is_deployed(&subgraph_id)?
+                    {
+                        info!(logger, "Subgraph from `--subgraph` flag is already deployed";
+                                      "subgraph_name" => name.to_string(),
+                                      "deployment" => subgraph_id.to_string());
+                        return Ok(());
+                    }
                     subgraph_registrar.create_subgraph(name.clone()).await?;
                     subgraph_registrar
                         .create_subgraph_version(name, subgraph_id, node_id)
@@ -424,16 +539,18 @@ async fn main() {
             );
         }
 
-        // Serve GraphQL queries over HTTP
-        graph::spawn(
-            graphql_server
-                .serve(http_port, ws_port)
-                .expect("Failed to start GraphQL query server")
-                .compat(),
-        );
+        if node_role.serves_queries() {
+            // Serve GraphQL queries over HTTP
+            graph::spawn(
+                graphql_server
+                    .serve(http_port, ws_port)
+                    .expect("Failed to start GraphQL query server")
+                    .compat(),
+            );
 
-        // Serve GraphQL subscriptions over WebSockets
-        graph::spawn(subscription_server.serve(ws_port));
+            // Serve GraphQL subscriptions over WebSockets
+            graph::spawn(subscription_server.serve(ws_port));
+        }
 
         // Run the index node server
         graph::spawn(
@@ -487,7 +604,58 @@ async fn main() {
         }
     });
 
-    futures::future::pending::<()>().await;
+    shutdown_signal().await;
+
+    // Let subgraphs finish the block they are working on so that we don't
+    // exit in the middle of writing it
+    info!(logger, "Received shutdown signal, stopping subgraphs");
+    if indexing_shutdown.shutdown(&logger, *SHUTDOWN_TIMEOUT).await {
+        info!(logger, "All subgraphs stopped, shutting down");
+    }
+
+    // Returning from `main` would wait for blocking tasks that never finish
+    std::process::exit(0);
+}
+
+/// Dry-run the pending migrations for every shard and print them. Returns
+/// the exit code for the process
+fn check_migrations(config: &Config) -> i32 {
+    use diesel::{Connection, PgConnection};
+
+    let mut code = 0;
+    for (name, shard) in &config.stores {
+        let pending = PgConnection::establish(&shard.connection)
+            .map_err(|e| anyhow!("can not connect: {}", e))
+            .and_then(|conn| {
+                connection_pool::check_migrations(&conn)
+                    .map_err(|e| anyhow!("migrations would fail: {}", e))
+            });
+        match pending {
+            Ok(pending) if pending.is_empty() => println!("{}: no pending migrations", name),
+            Ok(pending) => {
+                println!("{}: {} pending migrations", name, pending.len());
+                for version in pending {
+                    println!("    {}", version);
+                }
+            }
+            Err(e) => {
+                eprintln!("{}: {}", name, e);
+                code = 1;
+            }
+        }
+    }
+    code
+}
+
+/// Resolves when the process receives SIGTERM or SIGINT
+async fn shutdown_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = signal(SignalKind::terminate()).expect("failed to listen for SIGTERM");
+    tokio::select! {
+        _ = terminate.recv() => {},
+        _ = tokio::signal::ctrl_c() => {},
+    }
 }
 
 /// Parses an Ethereum connection string and returns the network name and Ethereum adapter.
@@ -495,6 +663,7 @@ async fn create_ethereum_networks(
     logger: Logger,
     registry: Arc<MetricsRegistry>,
     config: Config,
+    rotation: &mut SecretRotation,
 ) -> Result<EthereumNetworks, anyhow::Error> {
     let eth_rpc_metrics = Arc::new(ProviderEthRpcMetrics::new(registry));
     let mut parsed_networks = EthereumNetworks::new();
@@ -518,7 +687,14 @@ async fn create_ethereum_networks(
                 use crate::config::Transport::*;
 
                 let (transport_event_loop, transport) = match web3.transport {
-                    Rpc => Transport::new_rpc(&web3.url, web3.headers),
+                    Rpc => {
+                        // Make it possible to switch to a new URL or new
+                        // headers when secrets are rotated
+                        let (event_loop, transport) = Transport::new_rpc(&web3.url, web3.headers);
+                        let transport = transport.replaceable();
+                        rotation.add_transport(&name, &provider.label, transport.clone());
+                        (event_loop, transport)
+                    }
                     Ipc => Transport::new_ipc(&web3.url),
                     Ws => Transport::new_ws(&web3.url),
                 };
@@ -527,6 +703,13 @@ async fn create_ethereum_networks(
                 // For now it's fine to just leak it.
                 std::mem::forget(transport_event_loop);
 
+                let transport = transport.with_limits(
+                    &provider.label,
+                    web3.rate_limit,
+                    web3.max_concurrent_requests,
+                    eth_rpc_metrics.clone(),
+                );
+
                 let supports_eip_1898 = !web3.features.contains("no_eip1898");
 
                 parsed_networks.insert(
@@ -555,6 +738,7 @@ async fn create_firehose_networks(
     logger: Logger,
     _registry: Arc<MetricsRegistry>,
     config: &Config,
+    rotation: &mut SecretRotation,
 ) -> Result<BTreeMap<BlockchainKind, FirehoseNetworks>, anyhow::Error> {
     debug!(
         logger,
@@ -582,11 +766,13 @@ async fn create_firehose_networks(
                     firehose.token.clone(),
                 )
                 .await?;
+                let endpoint = Arc::new(endpoint);
+                rotation.add_firehose_endpoint(name, &provider.label, endpoint.clone());
 
                 let parsed_networks = networks_by_kind
                     .entry(chain.protocol)
                     .or_insert_with(|| FirehoseNetworks::new());
-                parsed_networks.insert(name.to_string(), Arc::new(endpoint));
+                parsed_networks.insert(name.to_string(), endpoint);
             }
         }
     }
@@ -814,6 +1000,14 @@ fn ethereum_networks_as_chains(
         .map(|(network_name, eth_adapters, chain_store, is_ingestible)| {
             let firehose_endpoints = firehose_networks.and_then(|v| v.networks.get(network_name));
 
+            // Keep track of which providers respond so that the chain can
+            // avoid the ones that don't
+            graph::spawn(
+                eth_adapters
+                    .clone()
+                    .monitor_health(logger.new(o!("network" => network_name.clone()))),
+            );
+
             let chain = ethereum::Chain::new(
                 logger_factory.clone(),
                 network_name.clone(),
@@ -900,7 +1094,12 @@ fn start_block_ingestor(
     // otherwise BlockStream will not work properly.
     // BlockStream expects the blocks after the reorg threshold to be present in the
     // database.
-    assert!(*ANCESTOR_COUNT >= *REORG_THRESHOLD);
+    assert!(
+        *ANCESTOR_COUNT >= *REORG_THRESHOLD,
+        "ETHEREUM_ANCESTOR_COUNT ({}) must not be lower than ETHEREUM_REORG_THRESHOLD ({})",
+        *ANCESTOR_COUNT,
+        *REORG_THRESHOLD
+    );
 
     info!(
         logger,
@@ -931,6 +1130,7 @@ fn start_block_ingestor(
 
             let block_ingestor = BlockIngestor::<ethereum::Chain>::new(
                 chain.ingestor_adapter(),
+                chain.chain_store(),
                 block_polling_interval,
             )
             .expect("failed to create Ethereum block ingestor");
@@ -944,6 +1144,7 @@ fn start_block_ingestor(
 mod test {
     use super::create_ethereum_networks;
     use crate::config::{Config, Opt};
+    use crate::rotation::SecretRotation;
     use graph::log::logger;
     use graph::prelude::tokio;
     use graph::prometheus::Registry;
@@ -981,9 +1182,11 @@ mod test {
             prometheus_registry.clone(),
         ));
 
-        let ethereum_networks = create_ethereum_networks(logger, metrics_registry, config.clone())
-            .await
-            .expect("Correctly parse Ethereum network args");
+        let mut rotation = SecretRotation::new(&logger, Opt::default(), config.clone());
+        let ethereum_networks =
+            create_ethereum_networks(logger, metrics_registry, config.clone(), &mut rotation)
+                .await
+                .expect("Correctly parse Ethereum network args");
         let mut network_names = ethereum_networks.networks.keys().collect::<Vec<&String>>();
         network_names.sort();
 
@@ -3,16 +3,12 @@ use std::sync::Arc;
 use graph::blockchain::BlockPtr;
 use graph::prelude::BlockNumber;
 use graph::prelude::ChainStore as _;
-use graph::prelude::EthereumBlock;
-use graph::prelude::LightEthereumBlockExt as _;
 use graph::prelude::{anyhow, anyhow::bail};
-use graph::{
-    components::store::BlockStore as _, prelude::anyhow::Error, prelude::serde_json as json,
-};
-use graph_store_postgres::BlockStore;
+use graph::{components::store::BlockStore as _, prelude::anyhow::Error};
 use graph_store_postgres::{
     command_support::catalog::block_store, connection_pool::ConnectionPool,
 };
+use graph_store_postgres::{BlockStore, ClearCallCacheError};
 
 pub fn list(primary: ConnectionPool, store: Arc<BlockStore>) -> Result<(), Error> {
     let mut chains = {
@@ -83,11 +79,7 @@ pub fn info(
     let head_block = chain_store.chain_head_ptr()?;
     let ancestor = match &head_block {
         None => None,
-        Some(head_block) => chain_store
-            .ancestor_block(head_block.clone(), offset)?
-            .map(json::from_value::<EthereumBlock>)
-            .transpose()?
-            .map(|b| b.block.block_ptr()),
+        Some(head_block) => chain_store.ancestor_ptr(head_block.clone(), offset)?,
     };
 
     row("name", chain.name);
@@ -126,3 +118,34 @@ pub fn remove(primary: ConnectionPool, store: Arc<BlockStore>, name: String) ->
 
     Ok(())
 }
+
+pub fn clear_call_cache(
+    store: Arc<BlockStore>,
+    name: String,
+    from: BlockNumber,
+    to: BlockNumber,
+    all_chains: bool,
+) -> Result<(), Error> {
+    if from > to {
+        bail!(
+            "the first block {} must not be after the last block {}",
+            from,
+            to
+        );
+    }
+    let chain_store = store
+        .chain_store(&name)
+        .ok_or_else(|| anyhow!("unknown chain: {}", name))?;
+    let removed = match chain_store.clear_call_cache(from, to, all_chains) {
+        Ok(removed) => removed,
+        Err(e @ ClearCallCacheError::Shared(_)) => {
+            bail!("{}; use --all-chains to do that anyway", e)
+        }
+        Err(e) => return Err(e.into()),
+    };
+    println!(
+        "removed {} cached calls for blocks {} to {} of chain {}",
+        removed, from, to, name
+    );
+    Ok(())
+}
@@ -0,0 +1,47 @@
+use std::sync::Arc;
+
+use graph::anyhow::{bail, Error};
+use graph_store_postgres::SubgraphStore;
+
+use crate::manager::deployment::locate;
+
+pub fn run(
+    store: Arc<SubgraphStore>,
+    hash: String,
+    shard: Option<String>,
+    repair: bool,
+) -> Result<(), Error> {
+    let deployment = locate(store.as_ref(), hash, shard)?;
+
+    let found = store.check(&deployment.hash, repair)?;
+    match &found.head {
+        Some(head) => println!("{} is at block {}", deployment, head),
+        None => println!("{} has not processed any blocks", deployment),
+    }
+    if found.is_empty() {
+        println!("No inconsistencies found");
+        return Ok(());
+    }
+
+    println!("Found data from blocks after the block pointer:");
+    for (entity_type, versions) in &found.versions {
+        println!("  {:<30} {:>10} versions", entity_type.as_str(), versions);
+    }
+    if found.data_sources > 0 {
+        println!(
+            "  {:<30} {:>10}",
+            "dynamic data sources", found.data_sources
+        );
+    }
+
+    if repair {
+        println!("Removed the data from blocks after the block pointer");
+        Ok(())
+    } else {
+        bail!(
+            "the deployment is inconsistent; unassign it and run \
+             `graphman check --repair` to remove the data from blocks after \
+             the block pointer, or `graphman rewind` to go back further"
+        )
+    }
+}
@@ -57,6 +57,9 @@ pub fn pools(config: &Config, nodes: Vec<String>, shard: bool) -> Result<(), Err
         for (name, shard) in &config.stores {
             let size = shard.pool_size.size_for(node, name)?;
             shard_sizes.insert(name.to_string(), size);
+            if let Some(size) = shard.query_pool_size_for(node, name)? {
+                shard_sizes.insert(format!("{}.query", name), size);
+            }
             for (replica_name, replica) in &shard.replicas {
                 let qname = format!("{}.{}", name, replica_name);
                 let size = replica.pool_size.size_for(node, &qname)?;
@@ -81,6 +81,7 @@ pub async fn create(
     shard: String,
     node: String,
     block_offset: u32,
+    activate: bool,
 ) -> Result<(), Error> {
     let block_offset = block_offset as i32;
     let subgraph_store = store.subgraph_store();
@@ -120,6 +121,13 @@ pub async fn create(
     let dst = subgraph_store.copy_deployment(&src, shard, node, base_ptr)?;
 
     println!("created deployment {} as copy of {}", dst, src);
+    if activate {
+        subgraph_store.request_copy_activation(&dst)?;
+        println!(
+            "the copy will be activated once it has caught up with {}",
+            src
+        );
+    }
     Ok(())
 }
 
@@ -0,0 +1,409 @@
+//! Check the environment in which `graph-node` runs. Every check prints
+//! what it found, and when something is wrong, what can be done about it,
+//! so that setup problems show up before `graph-node` fails with an
+//! obscure error at runtime
+
+use std::fmt::Display;
+use std::sync::Arc;
+use std::time::Duration;
+
+use diesel::sql_types::{BigInt, Bool, Nullable, Text};
+use diesel::{sql_query, Connection, PgConnection, RunQueryDsl};
+use graph::ipfs_client::IpfsClient;
+use graph::prelude::{anyhow, o, tokio, Logger};
+use graph::util::security::SafeDisplay;
+use graph_chain_ethereum::{EthereumAdapter, EthereumAdapterTrait, ProviderEthRpcMetrics};
+use graph_core::MetricsRegistry;
+use graph_store_postgres::command_support::catalog::block_store;
+use graph_store_postgres::PRIMARY_SHARD;
+
+use crate::config::{Config, ProviderDetails};
+use crate::manager;
+
+/// The oldest version of Postgres we support, in the format of
+/// `server_version_num`
+const MIN_POSTGRES_VERSION: i32 = 90600;
+
+/// The extensions `graph-node` needs in every shard
+const EXTENSIONS: [&str; 4] = [
+    "pg_trgm",
+    "pg_stat_statements",
+    "btree_gist",
+    "postgres_fdw",
+];
+
+/// Tally of the problems found so far
+#[derive(Default)]
+struct Report {
+    failures: usize,
+    warnings: usize,
+}
+
+impl Report {
+    fn ok(&mut self, what: &str, msg: impl Display) {
+        println!("ok    {}: {}", what, msg);
+    }
+
+    fn warn(&mut self, what: &str, msg: impl Display, fix: impl Display) {
+        self.warnings += 1;
+        println!("warn  {}: {}", what, msg);
+        println!("      fix: {}", fix);
+    }
+
+    fn fail(&mut self, what: &str, msg: impl Display, fix: impl Display) {
+        self.failures += 1;
+        println!("FAIL  {}: {}", what, msg);
+        println!("      fix: {}", fix);
+    }
+}
+
+pub async fn run(
+    logger: &Logger,
+    config: &Config,
+    registry: Arc<MetricsRegistry>,
+    ipfs: Vec<String>,
+    timeout: Duration,
+) -> Result<(), anyhow::Error> {
+    let mut report = Report::default();
+
+    let mut chains = None;
+    for (name, shard) in &config.stores {
+        let conn = check_database(&mut report, name, &shard.connection, true);
+        if name == PRIMARY_SHARD.as_str() {
+            chains = conn.and_then(|conn| block_store::load_chains(&conn).ok());
+        }
+        for (replica_name, replica) in &shard.replicas {
+            let name = format!("{}.{}", name, replica_name);
+            check_database(&mut report, &name, &replica.connection, false);
+        }
+    }
+
+    check_providers(
+        &mut report,
+        logger,
+        config,
+        registry,
+        chains.unwrap_or_default(),
+        timeout,
+    )
+    .await;
+
+    check_ipfs(&mut report, ipfs, timeout).await;
+
+    println!(
+        "\n{} problem(s), {} warning(s)",
+        report.failures, report.warnings
+    );
+    if report.failures > 0 {
+        Err(anyhow!("{} check(s) failed", report.failures))
+    } else {
+        Ok(())
+    }
+}
+
+/// Check the database at `url`. For the main database of a shard, also
+/// check extensions and migrations; replicas get those from their
+/// primary. Returns the connection if it could be established
+fn check_database(report: &mut Report, name: &str, url: &str, main: bool) -> Option<PgConnection> {
+    #[derive(QueryableByName)]
+    struct Setting {
+        #[sql_type = "Text"]
+        setting: String,
+    }
+
+    #[derive(QueryableByName)]
+    struct Extension {
+        #[sql_type = "Text"]
+        name: String,
+        #[sql_type = "Bool"]
+        installed: bool,
+    }
+
+    #[derive(QueryableByName)]
+    struct Migrations {
+        #[sql_type = "BigInt"]
+        count: i64,
+        #[sql_type = "Nullable<Text>"]
+        latest: Option<String>,
+    }
+
+    let what = format!("postgres[{}]", name);
+
+    let conn = match PgConnection::establish(url) {
+        Ok(conn) => conn,
+        Err(e) => {
+            report.fail(
+                &what,
+                format!("can not connect: {}", e),
+                format!(
+                    "make sure the database server is running and that the \
+                     connection string for `{}` in the configuration file is correct",
+                    name
+                ),
+            );
+            return None;
+        }
+    };
+
+    let setting = |name: &str| {
+        sql_query(format!("select current_setting('{}') as setting", name))
+            .get_result::<Setting>(&conn)
+            .map(|s| s.setting)
+    };
+
+    match setting("server_version_num").map(|v| v.parse::<i32>()) {
+        Ok(Ok(version)) if version < MIN_POSTGRES_VERSION => report.fail(
+            &what,
+            format!("Postgres version {} is too old", version),
+            "upgrade to Postgres 9.6 or later",
+        ),
+        Ok(Ok(_)) => report.ok(
+            &what,
+            format!(
+                "connected, Postgres {}",
+                setting("server_version").unwrap_or_default()
+            ),
+        ),
+        Ok(Err(e)) => report.warn(
+            &what,
+            format!("could not parse the Postgres version: {}", e),
+            "check that the server is a Postgres server",
+        ),
+        Err(e) => report.warn(
+            &what,
+            format!("could not determine the Postgres version: {}", e),
+            "check that the server is a Postgres server",
+        ),
+    }
+
+    if !main {
+        return Some(conn);
+    }
+
+    let superuser = setting("is_superuser").map(|s| s == "on").unwrap_or(false);
+    match sql_query(
+        "select name, installed_version is not null as installed from pg_available_extensions",
+    )
+    .load::<Extension>(&conn)
+    {
+        Ok(available) => {
+            for ext in EXTENSIONS.iter() {
+                match available.iter().find(|avail| &avail.name == ext) {
+                    None => report.fail(
+                        &what,
+                        format!("extension `{}` is not available", ext),
+                        "install the Postgres contrib package (often called \
+                         `postgresql-contrib`) on the database server",
+                    ),
+                    Some(avail) if !avail.installed && superuser => report.warn(
+                        &what,
+                        format!("extension `{}` is not installed", ext),
+                        "nothing; graph-node creates it when it starts",
+                    ),
+                    Some(avail) if !avail.installed => report.fail(
+                        &what,
+                        format!("extension `{}` is not installed", ext),
+                        format!(
+                            "connect to the database as a superuser and run \
+                             `create extension {}`",
+                            ext
+                        ),
+                    ),
+                    Some(_) => {}
+                }
+            }
+        }
+        Err(e) => report.warn(
+            &what,
+            format!("could not list extensions: {}", e),
+            "check that the database user can read `pg_available_extensions`",
+        ),
+    }
+
+    // Only superusers can see this setting
+    if let Ok(preload) = setting("shared_preload_libraries") {
+        if !preload
+            .split(',')
+            .any(|lib| lib.trim() == "pg_stat_statements")
+        {
+            report.warn(
+                &what,
+                "`pg_stat_statements` is not in `shared_preload_libraries`",
+                "add it to `shared_preload_libraries` in `postgresql.conf` and restart Postgres",
+            );
+        }
+    }
+
+    match sql_query(
+        "select count(*) as count, max(version) as latest from __diesel_schema_migrations",
+    )
+    .get_result::<Migrations>(&conn)
+    {
+        Ok(Migrations {
+            count,
+            latest: Some(latest),
+        }) => report.ok(
+            &what,
+            format!("{} migrations applied, latest is {}", count, latest),
+        ),
+        Ok(Migrations { latest: None, .. }) | Err(_) => report.warn(
+            &what,
+            "no migrations have been applied",
+            "start graph-node once; it runs all migrations when it starts",
+        ),
+    }
+
+    Some(conn)
+}
+
+/// Check that every provider is reachable and that it is for the same
+/// network as the one we stored in the database for its chain
+async fn check_providers(
+    report: &mut Report,
+    logger: &Logger,
+    config: &Config,
+    registry: Arc<MetricsRegistry>,
+    stored: Vec<block_store::Chain>,
+    timeout: Duration,
+) {
+    let metrics = Arc::new(ProviderEthRpcMetrics::new(registry));
+
+    for (name, chain) in &config.chains.chains {
+        for provider in &chain.providers {
+            let what = format!("provider[{}.{}]", name, provider.label);
+            let web3 = match &provider.details {
+                ProviderDetails::Web3(web3) => web3,
+                ProviderDetails::Firehose(_) => {
+                    report.ok(&what, "firehose providers are not checked");
+                    continue;
+                }
+            };
+
+            let transport = match manager::transport(web3) {
+                Some(transport) => transport,
+                None => {
+                    report.fail(
+                        &what,
+                        "could not create the transport",
+                        "check the `url` and `transport` of the provider",
+                    );
+                    continue;
+                }
+            };
+
+            let logger = logger.new(o!("provider" => provider.label.clone()));
+            let adapter = EthereumAdapter::new(
+                logger,
+                provider.label.clone(),
+                &web3.url,
+                transport,
+                metrics.clone(),
+                !web3.features.contains("no_eip1898"),
+            )
+            .await;
+
+            let ident = match tokio::time::timeout(timeout, adapter.net_identifiers()).await {
+                Ok(Ok(ident)) => ident,
+                Ok(Err(e)) => {
+                    report.fail(
+                        &what,
+                        format!("request failed: {}", e),
+                        "check that the provider's `url` is correct and that the \
+                         provider is up",
+                    );
+                    continue;
+                }
+                Err(_) => {
+                    report.fail(
+                        &what,
+                        format!("no response within {}s", timeout.as_secs()),
+                        "check that the provider is reachable from this machine",
+                    );
+                    continue;
+                }
+            };
+
+            match stored.iter().find(|stored| &stored.name == name) {
+                Some(stored)
+                    if stored.net_version != ident.net_version
+                        || stored.genesis_block != ident.genesis_block_hash.hash_hex() =>
+                {
+                    report.fail(
+                        &what,
+                        format!(
+                            "provider is on network {} with genesis block {}, but the \
+                             database has network {} with genesis block {} for chain `{}`",
+                            ident.net_version,
+                            ident.genesis_block_hash,
+                            stored.net_version,
+                            stored.genesis_block,
+                            name
+                        ),
+                        "point the provider at the right network; if the chain \
+                         really changed, remove it with `graphman chain remove`",
+                    )
+                }
+                _ => {
+                    let features: Vec<_> = web3.features.iter().map(String::as_str).collect();
+                    report.ok(
+                        &what,
+                        format!(
+                            "network {}, genesis block {}, features [{}]",
+                            ident.net_version,
+                            ident.genesis_block_hash,
+                            features.join(", ")
+                        ),
+                    )
+                }
+            }
+        }
+    }
+}
+
+/// Check that all IPFS nodes respond to a version request
+async fn check_ipfs(report: &mut Report, ipfs: Vec<String>, timeout: Duration) {
+    if ipfs.is_empty() {
+        report.warn(
+            "ipfs",
+            "no IPFS nodes were given",
+            "pass the same `--ipfs` addresses that graph-node uses",
+        );
+        return;
+    }
+
+    for address in ipfs {
+        // Accept addresses without a scheme, like graph-node does
+        let address = if address.starts_with("http://") || address.starts_with("https://") {
+            address
+        } else {
+            format!("http://{}", address)
+        };
+        let what = format!("ipfs[{}]", SafeDisplay(&address));
+
+        let client = match IpfsClient::new(&address) {
+            Ok(client) => client,
+            Err(e) => {
+                report.fail(
+                    &what,
+                    format!("invalid address: {}", e),
+                    "pass the address as `HOST:PORT` or as a URL",
+                );
+                continue;
+            }
+        };
+        match tokio::time::timeout(timeout, client.test()).await {
+            Ok(Ok(())) => report.ok(&what, "reachable"),
+            Ok(Err(e)) => report.fail(
+                &what,
+                format!("request failed: {}", e),
+                "make sure an IPFS node is running there and that its API port \
+                 (usually 5001) is reachable from this machine",
+            ),
+            Err(_) => report.fail(
+                &what,
+                format!("no response within {}s", timeout.as_secs()),
+                "make sure the IPFS node is reachable from this machine",
+            ),
+        }
+    }
+}
@@ -0,0 +1,54 @@
+use std::sync::Arc;
+
+use graph::anyhow::Error;
+use graph_store_postgres::SubgraphStore;
+
+use crate::manager::deployment::locate;
+
+pub fn create(
+    store: Arc<SubgraphStore>,
+    hash: String,
+    shard: Option<String>,
+    entity: String,
+    fields: Vec<String>,
+    method: String,
+) -> Result<(), Error> {
+    let deployment = locate(store.as_ref(), hash, shard)?;
+    println!(
+        "Creating index on {}({}) of {} using {}",
+        entity,
+        fields.join(", "),
+        deployment,
+        method
+    );
+    let name = store.create_manual_index(&deployment.hash, &entity, &fields, &method)?;
+    println!("Created index {}", name);
+    Ok(())
+}
+
+pub fn list(
+    store: Arc<SubgraphStore>,
+    hash: String,
+    shard: Option<String>,
+    entity: String,
+) -> Result<(), Error> {
+    let deployment = locate(store.as_ref(), hash, shard)?;
+    for index in store.indexes_for_entity(&deployment.hash, &entity)? {
+        let invalid = if index.valid { "" } else { " (invalid)" };
+        println!("{}{}", index.name, invalid);
+        println!("  {}", index.definition);
+    }
+    Ok(())
+}
+
+pub fn drop(
+    store: Arc<SubgraphStore>,
+    hash: String,
+    shard: Option<String>,
+    name: String,
+) -> Result<(), Error> {
+    let deployment = locate(store.as_ref(), hash, shard)?;
+    store.drop_manual_index(&deployment.hash, &name)?;
+    println!("Dropped index {} of {}", name, deployment);
+    Ok(())
+}
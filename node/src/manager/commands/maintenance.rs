@@ -0,0 +1,52 @@
+use std::sync::Arc;
+
+use graph::prelude::Error;
+use graph_store_postgres::SubgraphStore;
+
+use crate::manager::deployment::locate;
+
+pub fn disable_queries(
+    store: Arc<SubgraphStore>,
+    hash: String,
+    shard: Option<String>,
+) -> Result<(), Error> {
+    let deployment = locate(store.as_ref(), hash, shard)?;
+
+    println!("disabling queries for {}", deployment);
+    store.set_queries_disabled(&deployment, true)?;
+
+    Ok(())
+}
+
+pub fn enable_queries(
+    store: Arc<SubgraphStore>,
+    hash: String,
+    shard: Option<String>,
+) -> Result<(), Error> {
+    let deployment = locate(store.as_ref(), hash, shard)?;
+
+    println!("enabling queries for {}", deployment);
+    store.set_queries_disabled(&deployment, false)?;
+
+    Ok(())
+}
+
+pub fn statement_timeout(
+    store: Arc<SubgraphStore>,
+    hash: String,
+    shard: Option<String>,
+    seconds: Option<u32>,
+) -> Result<(), Error> {
+    let deployment = locate(store.as_ref(), hash, shard)?;
+
+    match seconds {
+        Some(seconds) => println!(
+            "setting the statement timeout for {} to {}s",
+            deployment, seconds
+        ),
+        None => println!("using the default statement timeout for {}", deployment),
+    }
+    store.set_statement_timeout(&deployment, seconds)?;
+
+    Ok(())
+}
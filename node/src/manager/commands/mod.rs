@@ -1,10 +1,17 @@
 pub mod assign;
 pub mod chain;
+pub mod check;
 pub mod config;
 pub mod copy;
 pub mod create;
+pub mod doctor;
+pub mod index;
 pub mod info;
 pub mod listen;
+pub mod maintenance;
+pub mod partition;
+pub mod poi;
+pub mod prune;
 pub mod query;
 pub mod remove;
 pub mod rewind;
@@ -0,0 +1,34 @@
+use std::sync::Arc;
+use std::time::Instant;
+
+use graph::anyhow::Error;
+use graph::prelude::BlockNumber;
+use graph_store_postgres::SubgraphStore;
+
+use crate::manager::deployment::locate;
+
+pub fn run(
+    store: Arc<SubgraphStore>,
+    hash: String,
+    shard: Option<String>,
+    tables: Vec<String>,
+    blocks: BlockNumber,
+    min_rows: i64,
+) -> Result<(), Error> {
+    let deployment = locate(store.as_ref(), hash, shard)?;
+
+    println!(
+        "Partitioning tables of {} into partitions of {} blocks",
+        deployment, blocks
+    );
+    let start = Instant::now();
+    let partitioned = store.partition(&deployment.hash, &tables, blocks, min_rows)?;
+    if partitioned.is_empty() {
+        println!("No table has {} rows or more", min_rows);
+    }
+    for (table, rows) in partitioned {
+        println!("  {:<30} {:>12} rows", table, rows);
+    }
+    println!("Finished in {}s", start.elapsed().as_secs());
+    Ok(())
+}
@@ -0,0 +1,442 @@
+//! Compare the proofs of indexing of a deployment with the ones another
+//! indexer reports through its index node API, find the first block at
+//! which they differ, and show how the entities changed at that block on
+//! both sides
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+
+use graph::anyhow::{anyhow, bail, Error};
+use graph::components::store::{BlockStore as _, ChainStore as _, StatusStore};
+use graph::data::subgraph::status;
+use graph::prelude::{
+    hex, o, reqwest, serde_json, web3::types::Address, BlockNumber, BlockPtr, DeploymentHash,
+    Future01CompatExt, Logger,
+};
+use graph_chain_ethereum::{EthereumAdapter, EthereumAdapterTrait, ProviderEthRpcMetrics};
+use graph_core::MetricsRegistry;
+use graph_store_postgres::{connection_pool::ConnectionPool, Store};
+use serde::Deserialize;
+
+use crate::config::{Config, ProviderDetails};
+use crate::manager::{self, deployment::Deployment};
+
+/// How many entity changes we show for each side
+const MAX_CHANGES: usize = 1000;
+
+const POI_QUERY: &str =
+    "query poi($subgraph: String!, $number: Int!, $hash: Bytes!, $indexer: Bytes) {
+  proofOfIndexing(subgraph: $subgraph, blockNumber: $number, blockHash: $hash, indexer: $indexer)
+}";
+
+const ENTITY_DIFF_QUERY: &str =
+    "query diff($subgraph: String!, $from: Int!, $to: Int!, $first: Int!) {
+  entityDiff(subgraphId: $subgraph, fromBlock: $from, toBlock: $to, first: $first) {
+    entityType id operation data
+  }
+}";
+
+#[derive(Deserialize)]
+struct Response<T> {
+    data: Option<T>,
+    errors: Option<Vec<serde_json::Value>>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PoiData {
+    proof_of_indexing: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct EntityDiffData {
+    entity_diff: Vec<Change>,
+}
+
+/// An entity change in the form in which the index node API returns it
+#[derive(Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "camelCase")]
+struct Change {
+    entity_type: String,
+    id: String,
+    operation: String,
+    data: Option<String>,
+}
+
+impl From<status::EntityDiff> for Change {
+    fn from(diff: status::EntityDiff) -> Self {
+        use graph::data::graphql::IntoValue;
+        use graph::prelude::r;
+
+        // Go through the same conversion as the index node API so that
+        // changes from both sides can be compared as strings
+        let mut value = match diff.into_value() {
+            r::Value::Object(map) => map,
+            _ => unreachable!("entity diffs are objects"),
+        };
+        let mut take = |key: &str| match value.remove(key) {
+            Some(r::Value::String(s)) | Some(r::Value::Enum(s)) => Some(s),
+            _ => None,
+        };
+        Change {
+            entity_type: take("entityType").unwrap_or_default(),
+            id: take("id").unwrap_or_default(),
+            operation: take("operation").unwrap_or_default(),
+            data: take("data"),
+        }
+    }
+}
+
+struct Remote {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl Remote {
+    async fn query<T: serde::de::DeserializeOwned>(
+        &self,
+        query: &str,
+        variables: serde_json::Value,
+    ) -> Result<T, Error> {
+        let body = serde_json::json!({ "query": query, "variables": variables });
+        let response: Response<T> = self
+            .client
+            .post(&self.url)
+            .json(&body)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        match (response.data, response.errors) {
+            (_, Some(errors)) if !errors.is_empty() => bail!(
+                "{} returned errors: {}",
+                self.url,
+                serde_json::to_string(&errors)?
+            ),
+            (Some(data), _) => Ok(data),
+            (None, _) => bail!("{} returned no data", self.url),
+        }
+    }
+
+    async fn poi(
+        &self,
+        deployment: &DeploymentHash,
+        block: &BlockPtr,
+        indexer: &Option<Address>,
+    ) -> Result<Option<String>, Error> {
+        let variables = serde_json::json!({
+            "subgraph": deployment.as_str(),
+            "number": block.number,
+            "hash": format!("0x{}", block.hash_hex()),
+            "indexer": indexer.map(|indexer| format!("{:?}", indexer)),
+        });
+        let data: PoiData = self.query(POI_QUERY, variables).await?;
+        Ok(data.proof_of_indexing)
+    }
+
+    async fn changes(
+        &self,
+        deployment: &DeploymentHash,
+        block: BlockNumber,
+    ) -> Result<Vec<Change>, Error> {
+        let variables = serde_json::json!({
+            "subgraph": deployment.as_str(),
+            "from": block - 1,
+            "to": block,
+            "first": MAX_CHANGES,
+        });
+        let data: EntityDiffData = self.query(ENTITY_DIFF_QUERY, variables).await?;
+        Ok(data.entity_diff)
+    }
+}
+
+/// Connect to the first JSON-RPC provider for `chain` in the
+/// configuration. Returns `None` if there is no such provider
+async fn provider(
+    logger: &Logger,
+    config: &Config,
+    registry: Arc<MetricsRegistry>,
+    chain: &str,
+) -> Result<Option<EthereumAdapter>, Error> {
+    let (label, web3) = match config.chains.chains.get(chain).and_then(|chain| {
+        chain
+            .providers
+            .iter()
+            .find_map(|provider| match &provider.details {
+                ProviderDetails::Web3(web3) => Some((&provider.label, web3)),
+                ProviderDetails::Firehose(_) => None,
+            })
+    }) {
+        Some(provider) => provider,
+        None => return Ok(None),
+    };
+
+    let transport = manager::transport(web3)
+        .ok_or_else(|| anyhow!("could not connect to provider {} for {}", label, chain))?;
+
+    let adapter = EthereumAdapter::new(
+        logger.new(o!("provider" => label.clone())),
+        label.clone(),
+        &web3.url,
+        transport,
+        Arc::new(ProviderEthRpcMetrics::new(registry)),
+        !web3.features.contains("no_eip1898"),
+    )
+    .await;
+    Ok(Some(adapter))
+}
+
+struct Local {
+    logger: Logger,
+    store: Arc<Store>,
+    chain: String,
+    /// A provider for the chain to look up blocks that are not in the
+    /// block cache, for example because they were evicted from it
+    provider: Option<EthereumAdapter>,
+}
+
+impl Local {
+    /// The block pointer for block `number` on our chain
+    async fn block(&self, number: BlockNumber) -> Result<BlockPtr, Error> {
+        let chain_store = self
+            .store
+            .block_store()
+            .chain_store(&self.chain)
+            .ok_or_else(|| anyhow!("can not find chain store for {}", self.chain))?;
+        let hashes = chain_store.block_hashes_by_block_number(number)?;
+        if let [hash] = hashes.as_slice() {
+            return Ok(BlockPtr::from((*hash, number as u64)));
+        }
+
+        // The block cache either does not have the block, or it has
+        // several blocks with that number; the provider knows which block
+        // is on the main chain
+        let provider = match &self.provider {
+            Some(provider) => provider,
+            None if hashes.is_empty() => bail!(
+                "the chain {} has no block with number {} in the block cache, and there \
+                 is no JSON-RPC provider for it in the configuration",
+                self.chain,
+                number
+            ),
+            None => bail!(
+                "the chain {} has {} blocks with number {}; can not tell which one is final",
+                self.chain,
+                hashes.len(),
+                number
+            ),
+        };
+        let hash = provider
+            .block_hash_by_block_number(&self.logger, number)
+            .compat()
+            .await?
+            .ok_or_else(|| {
+                anyhow!(
+                    "provider {} does not know block {} of {}",
+                    provider.provider(),
+                    number,
+                    self.chain
+                )
+            })?;
+        Ok(BlockPtr::from((hash, number as u64)))
+    }
+
+    async fn poi(
+        &self,
+        deployment: &DeploymentHash,
+        block: &BlockPtr,
+        indexer: &Option<Address>,
+    ) -> Result<Option<String>, Error> {
+        let poi = self
+            .store
+            .get_proof_of_indexing(deployment, indexer, block.clone())
+            .await?;
+        Ok(poi.map(|poi| format!("0x{}", hex::encode(&poi))))
+    }
+
+    async fn changes(
+        &self,
+        deployment: &DeploymentHash,
+        block: BlockNumber,
+    ) -> Result<Vec<Change>, Error> {
+        let diffs = self
+            .store
+            .entity_diff(deployment, block - 1, block, None, MAX_CHANGES)
+            .await?;
+        Ok(diffs.into_iter().map(Change::from).collect())
+    }
+
+    fn latest_block(&self, deployment: &DeploymentHash) -> Result<BlockNumber, Error> {
+        let infos = self
+            .store
+            .status(status::Filter::Deployments(vec![deployment.to_string()]))?;
+        infos
+            .iter()
+            .flat_map(|info| info.chains.iter())
+            .filter_map(|chain| chain.latest_block.as_ref())
+            .map(|block| block.number())
+            .next()
+            .ok_or_else(|| anyhow!("deployment {} has not indexed any blocks", deployment))
+    }
+}
+
+/// Whether the local and the remote proof of indexing agree at `number`
+async fn same_poi(
+    local: &Local,
+    remote: &Remote,
+    deployment: &DeploymentHash,
+    indexer: &Option<Address>,
+    number: BlockNumber,
+) -> Result<bool, Error> {
+    let block = local.block(number).await?;
+    let ours = local.poi(deployment, &block, indexer).await?;
+    let theirs = remote.poi(deployment, &block, indexer).await?;
+    match (&ours, &theirs) {
+        (None, _) => bail!("there is no local proof of indexing for block {}", number),
+        (_, None) => bail!(
+            "{} has no proof of indexing for block {}",
+            remote.url,
+            number
+        ),
+        _ => {}
+    }
+    println!(
+        "  block {:>10}: {}",
+        number,
+        if ours == theirs { "same" } else { "different" }
+    );
+    Ok(ours == theirs)
+}
+
+fn print_changes(title: &str, changes: &BTreeSet<&Change>) {
+    println!("{} ({}):", title, changes.len());
+    for change in changes {
+        println!(
+            "  {} {}[{}] {}",
+            change.operation,
+            change.entity_type,
+            change.id,
+            change.data.as_deref().unwrap_or("")
+        );
+    }
+}
+
+pub async fn compare(
+    logger: &Logger,
+    config: &Config,
+    registry: Arc<MetricsRegistry>,
+    primary: ConnectionPool,
+    store: Arc<Store>,
+    name: String,
+    remote: String,
+    from: BlockNumber,
+    to: Option<BlockNumber>,
+    indexer: Option<Address>,
+) -> Result<(), Error> {
+    let deployments = Deployment::lookup(&primary, name.clone())?;
+    let hashes: BTreeMap<_, _> = deployments
+        .iter()
+        .map(|deployment| (deployment.deployment.as_str(), deployment.chain.as_str()))
+        .collect();
+    let (deployment, chain) = match hashes.len() {
+        0 => bail!("no deployment matches `{}`", name),
+        1 => hashes.into_iter().next().unwrap(),
+        _ => bail!(
+            "`{}` matches several deployments; use one of {}",
+            name,
+            hashes.keys().cloned().collect::<Vec<_>>().join(", ")
+        ),
+    };
+    let deployment =
+        DeploymentHash::new(deployment).map_err(|s| anyhow!("illegal deployment hash `{}`", s))?;
+
+    let local = Local {
+        logger: logger.clone(),
+        store,
+        chain: chain.to_string(),
+        provider: provider(logger, config, registry, chain).await?,
+    };
+    let remote = Remote {
+        client: reqwest::Client::new(),
+        url: remote,
+    };
+
+    let to = match to {
+        Some(to) => to,
+        None => local.latest_block(&deployment)?,
+    };
+    if from < 1 || from > to {
+        bail!(
+            "the block range must start after block 0 and not be empty, but is {}..={}",
+            from,
+            to
+        );
+    }
+
+    println!(
+        "Comparing proofs of indexing for {} with {}",
+        deployment, remote.url
+    );
+    if same_poi(&local, &remote, &deployment, &indexer, to).await? {
+        println!("The proofs of indexing agree up to block {}", to);
+        return Ok(());
+    }
+    if !same_poi(&local, &remote, &deployment, &indexer, from).await? {
+        println!(
+            "The proofs of indexing already differ at block {}; try an earlier block",
+            from
+        );
+        return Ok(());
+    }
+
+    // The proof of indexing at a block covers all blocks before it; once
+    // it differs, it differs for all later blocks, too
+    let (mut same, mut different) = (from, to);
+    while different - same > 1 {
+        let middle = same + (different - same) / 2;
+        if same_poi(&local, &remote, &deployment, &indexer, middle).await? {
+            same = middle;
+        } else {
+            different = middle;
+        }
+    }
+    println!(
+        "\nThe proofs of indexing first differ at block {}\n",
+        different
+    );
+
+    let ours = local.changes(&deployment, different).await?;
+    let theirs = match remote.changes(&deployment, different).await {
+        Ok(theirs) => theirs,
+        Err(e) => {
+            print_changes("Local changes", &ours.iter().collect());
+            bail!(
+                "could not get the entity changes from {}: {}",
+                remote.url,
+                e
+            );
+        }
+    };
+    let ours: BTreeSet<_> = ours.iter().collect();
+    let theirs: BTreeSet<_> = theirs.iter().collect();
+    print_changes(
+        "Only local changes",
+        &ours.difference(&theirs).cloned().collect(),
+    );
+    print_changes(
+        "Only remote changes",
+        &theirs.difference(&ours).cloned().collect(),
+    );
+    println!(
+        "{} change(s) are the same on both sides",
+        ours.intersection(&theirs).count()
+    );
+    if ours.len() >= MAX_CHANGES || theirs.len() >= MAX_CHANGES {
+        println!(
+            "Only the first {} changes on each side were compared",
+            MAX_CHANGES
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,65 @@
+use std::io::Write;
+use std::sync::Arc;
+use std::time::Instant;
+
+use graph::anyhow::{anyhow, Error};
+use graph::prelude::BlockNumber;
+use graph_store_postgres::{PruneReporter, SubgraphStore};
+
+use crate::manager::deployment::locate;
+
+/// Print the progress of pruning on one line per table
+struct Progress {
+    start: Instant,
+}
+
+impl PruneReporter for Progress {
+    fn start_table(&mut self, table: &str) {
+        self.start = Instant::now();
+        print!("  {:<30} ", table);
+        std::io::stdout().flush().ok();
+    }
+
+    fn prune_batch(&mut self, table: &str, rows: usize, done: f64) {
+        print!(
+            "\r  {:<30} {:>5.1}% {:>10} rows removed",
+            table,
+            done.min(1.0) * 100.0,
+            rows
+        );
+        std::io::stdout().flush().ok();
+    }
+
+    fn finish_table(&mut self, table: &str, rows: usize) {
+        println!(
+            "\r  {:<30} done   {:>10} rows removed in {}s",
+            table,
+            rows,
+            self.start.elapsed().as_secs()
+        );
+    }
+}
+
+pub fn run(
+    store: Arc<SubgraphStore>,
+    hash: String,
+    shard: Option<String>,
+    history: BlockNumber,
+) -> Result<(), Error> {
+    let deployment = locate(store.as_ref(), hash, shard)?;
+
+    println!("Pruning {} to the last {} blocks", deployment, history);
+    let mut progress = Progress {
+        start: Instant::now(),
+    };
+    match store.prune(&mut progress, &deployment.hash, history)? {
+        Some(earliest) => {
+            println!("Queries can now use block {} and later", earliest);
+            Ok(())
+        }
+        None => Err(anyhow!(
+            "{} is being pruned by somebody else already",
+            deployment
+        )),
+    }
+}
@@ -1,8 +1,12 @@
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
 use graph::{
     components::store::SubscriptionManager,
     prelude::{StoreEventStreamBox, SubscriptionFilter},
 };
 
+use crate::config::{Transport, Web3Provider};
+
 pub mod catalog;
 pub mod commands;
 pub mod deployment;
@@ -16,3 +20,21 @@ impl SubscriptionManager for PanicSubscriptionManager {
         panic!("we were never meant to call `subscribe`");
     }
 }
+
+/// Create the transport for `web3`. Returns `None` if that fails, which
+/// for WS and IPC transports means we could not connect
+///
+/// The event loop of the transport is leaked since the transport stops
+/// working when it is dropped; that is fine for the short-lived commands
+/// of `graphman`
+pub fn transport(web3: &Web3Provider) -> Option<graph_chain_ethereum::Transport> {
+    // Creating a WS or IPC transport panics if it can't connect
+    let (event_loop, transport) = catch_unwind(AssertUnwindSafe(|| match web3.transport {
+        Transport::Rpc => graph_chain_ethereum::Transport::new_rpc(&web3.url, web3.headers.clone()),
+        Transport::Ipc => graph_chain_ethereum::Transport::new_ipc(&web3.url),
+        Transport::Ws => graph_chain_ethereum::Transport::new_ws(&web3.url),
+    }))
+    .ok()?;
+    std::mem::forget(event_loop);
+    Some(transport)
+}
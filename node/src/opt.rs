@@ -1,4 +1,7 @@
+use std::str::FromStr;
+
 use git_testament::{git_testament, render_testament};
+use graph::log::LogFormat;
 use lazy_static::lazy_static;
 use structopt::StructOpt;
 
@@ -29,11 +32,26 @@ pub struct Opt {
     pub check_config: bool,
     #[structopt(
         long,
-        value_name = "[NAME:]IPFS_HASH",
+        help = "list the database migrations that would be run in each shard, \
+                checking that they succeed without applying them, and exit"
+    )]
+    pub check_migrations: bool,
+    #[structopt(
+        long,
+        help = "take over the subgraphs of a running graph-node with the same node id; \
+                waits for it to finish the blocks it is processing and exit before \
+                starting subgraphs"
+    )]
+    pub handoff: bool,
+    #[structopt(
+        long,
+        value_name = "[NAME:]IPFS_HASH,",
+        use_delimiter = true,
         env = "SUBGRAPH",
-        help = "name and IPFS hash of the subgraph manifest"
+        help = "Comma-separated list of names and IPFS hashes of subgraph manifests \
+                to create and deploy on startup unless they are already deployed"
     )]
-    pub subgraph: Option<String>,
+    pub subgraph: Vec<String>,
     #[structopt(
         long,
         value_name = "URL",
@@ -134,6 +152,14 @@ pub struct Opt {
         help = "Port for the Prometheus metrics server"
     )]
     pub metrics_port: u16,
+    #[structopt(
+        long,
+        default_value = "both",
+        possible_values = &["query", "index", "both"],
+        env = "GRAPH_NODE_ROLE",
+        help = "whether this node only serves queries, only indexes subgraphs, or does both"
+    )]
+    pub node_role: NodeRole,
     #[structopt(
         long,
         default_value = "default",
@@ -144,6 +170,14 @@ pub struct Opt {
     pub node_id: String,
     #[structopt(long, help = "Enable debug logging")]
     pub debug: bool,
+    #[structopt(
+        long,
+        default_value = "text",
+        possible_values = &["text", "json"],
+        env = "GRAPH_LOG_FORMAT",
+        help = "whether to log human readable text or one JSON object per line"
+    )]
+    pub log_format: LogFormat,
 
     #[structopt(
         long,
@@ -205,6 +239,42 @@ pub struct Opt {
     pub unsafe_config: bool,
 }
 
+/// What a graph-node process does. A query node does not ingest blocks,
+/// index subgraphs or run store maintenance jobs, and an index node does
+/// not serve GraphQL queries or subscriptions
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NodeRole {
+    Query,
+    Index,
+    Both,
+}
+
+impl NodeRole {
+    pub fn indexes(&self) -> bool {
+        matches!(self, NodeRole::Index | NodeRole::Both)
+    }
+
+    pub fn serves_queries(&self) -> bool {
+        matches!(self, NodeRole::Query | NodeRole::Both)
+    }
+}
+
+impl FromStr for NodeRole {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "query" => Ok(NodeRole::Query),
+            "index" => Ok(NodeRole::Index),
+            "both" => Ok(NodeRole::Both),
+            _ => Err(format!(
+                "invalid node role `{}`, must be one of query, index, or both",
+                s
+            )),
+        }
+    }
+}
+
 impl From<Opt> for config::Opt {
     fn from(opt: Opt) -> Self {
         let Opt {
@@ -0,0 +1,270 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use graph::firehose::endpoints::FirehoseEndpoint;
+use graph::prelude::{anyhow, error, info, o, tokio, warn, Logger};
+use graph::util::security::SafeDisplay;
+use graph_chain_ethereum::Transport;
+use graph_store_postgres::connection_pool::{ConnectionPool, ForeignServer};
+use graph_store_postgres::{
+    ChainHeadUpdateListener, Shard as ShardName, SubscriptionManager, PRIMARY_SHARD,
+};
+
+use crate::config::{self, Config, ProviderDetails, Web3Provider};
+use crate::store_builder::PoolConnection;
+
+/// Reload the configuration when the process receives SIGHUP and switch
+/// to any secrets that changed, for example, because they were rotated in
+/// Vault. Only connection strings, provider URLs and headers, and Firehose
+/// tokens are reloaded; changes to any other setting require a restart
+pub struct SecretRotation {
+    logger: Logger,
+    opt: Arc<config::Opt>,
+    /// The configuration whose secrets are currently in use
+    config: Config,
+    pools: Vec<(ShardName, PoolConnection, ConnectionPool)>,
+    listeners: Vec<Listener>,
+    /// Transports for JSON-RPC providers, keyed by network and provider
+    /// label. They must have been made with `Transport::replaceable`
+    transports: HashMap<(String, String), Transport>,
+    /// Firehose endpoints, keyed by network and provider label
+    firehose_endpoints: HashMap<(String, String), Arc<FirehoseEndpoint>>,
+}
+
+/// The components that listen for notifications from the primary
+enum Listener {
+    StoreEvents(Arc<SubscriptionManager>),
+    ChainHeads(Arc<ChainHeadUpdateListener>),
+}
+
+impl SecretRotation {
+    pub fn new(logger: &Logger, opt: config::Opt, config: Config) -> Self {
+        Self {
+            logger: logger.new(o!("component" => "SecretRotation")),
+            opt: Arc::new(opt),
+            config,
+            pools: Vec::new(),
+            listeners: Vec::new(),
+            transports: HashMap::new(),
+            firehose_endpoints: HashMap::new(),
+        }
+    }
+
+    pub fn add_pools(&mut self, pools: Vec<(ShardName, PoolConnection, ConnectionPool)>) {
+        self.pools.extend(pools);
+    }
+
+    pub fn add_listeners(
+        &mut self,
+        subscription_manager: Arc<SubscriptionManager>,
+        chain_head_update_listener: Arc<ChainHeadUpdateListener>,
+    ) {
+        self.listeners
+            .push(Listener::StoreEvents(subscription_manager));
+        self.listeners
+            .push(Listener::ChainHeads(chain_head_update_listener));
+    }
+
+    pub fn add_transport(&mut self, network: &str, provider: &str, transport: Transport) {
+        let key = (network.to_string(), provider.to_string());
+        self.transports.insert(key, transport);
+    }
+
+    pub fn add_firehose_endpoint(
+        &mut self,
+        network: &str,
+        provider: &str,
+        endpoint: Arc<FirehoseEndpoint>,
+    ) {
+        let key = (network.to_string(), provider.to_string());
+        self.firehose_endpoints.insert(key, endpoint);
+    }
+
+    /// Reload secrets every time the process receives SIGHUP
+    pub async fn watch(mut self) {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut hangup = signal(SignalKind::hangup()).expect("failed to listen for SIGHUP");
+        while hangup.recv().await.is_some() {
+            info!(self.logger, "Received SIGHUP, reloading secrets");
+
+            // Loading the configuration can block while secrets are
+            // fetched from Vault or decrypted
+            let logger = self.logger.clone();
+            let opt = self.opt.clone();
+            let config =
+                graph::spawn_blocking_allow_panic(move || Config::load(&logger, opt.as_ref()))
+                    .await
+                    .map_err(|e| anyhow!(e))
+                    .and_then(|config| config);
+            match config {
+                Ok(config) => self.rotate(config).await,
+                Err(e) => {
+                    error!(self.logger, "Failed to reload configuration, keeping the old secrets";
+                                        "error" => e.to_string())
+                }
+            }
+        }
+    }
+
+    async fn rotate(&mut self, config: Config) {
+        self.rotate_connections(&config).await;
+        self.rotate_providers(&config);
+        self.config = config;
+        info!(self.logger, "Finished reloading secrets");
+    }
+
+    async fn rotate_connections(&self, config: &Config) {
+        let mut servers_changed = false;
+        for (shard, connection, pool) in &self.pools {
+            let (old, new) = match (
+                connection.url(&self.config, shard),
+                connection.url(config, shard),
+            ) {
+                (Some(old), Some(new)) => (old, new),
+                _ => {
+                    warn!(self.logger, "Database is no longer configured, keeping its connection string";
+                                       "shard" => shard.as_str(),
+                                       "pool" => format!("{:?}", connection));
+                    continue;
+                }
+            };
+            if old == new {
+                continue;
+            }
+            info!(self.logger, "Switching connection pool to new connection string";
+                               "shard" => shard.as_str(),
+                               "pool" => format!("{:?}", connection),
+                               "url" => SafeDisplay(new));
+            pool.set_postgres_url(new.to_string());
+            if connection == &PoolConnection::Main {
+                servers_changed = true;
+                if shard == &*PRIMARY_SHARD {
+                    for listener in &self.listeners {
+                        match listener {
+                            Listener::StoreEvents(manager) => {
+                                manager.set_postgres_url(new.to_string())
+                            }
+                            Listener::ChainHeads(listener) => {
+                                listener.set_postgres_url(new.to_string())
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        if !servers_changed {
+            return;
+        }
+
+        // The foreign data wrappers in each shard log into the other
+        // shards with their credentials
+        let servers = config
+            .stores
+            .iter()
+            .map(|(name, shard)| ForeignServer::new_from_raw(name.to_string(), &shard.connection))
+            .collect::<Result<Vec<_>, _>>();
+        let servers = match servers {
+            Ok(servers) => Arc::new(servers),
+            Err(e) => {
+                error!(self.logger, "Failed to update foreign servers"; "error" => e.to_string());
+                return;
+            }
+        };
+        for (shard, connection, pool) in &self.pools {
+            if connection != &PoolConnection::Main {
+                continue;
+            }
+            let pool = pool.clone();
+            let servers = servers.clone();
+            let res = graph::spawn_blocking_allow_panic(move || pool.update_servers(servers))
+                .await
+                .map_err(|e| anyhow!(e))
+                .and_then(|res| res.map_err(|e| anyhow!(e)));
+            if let Err(e) = res {
+                error!(self.logger, "Failed to update foreign servers";
+                                    "shard" => shard.as_str(),
+                                    "error" => e.to_string());
+            }
+        }
+    }
+
+    fn rotate_providers(&self, config: &Config) {
+        fn provider<'a>(
+            config: &'a Config,
+            network: &str,
+            label: &str,
+        ) -> Option<&'a ProviderDetails> {
+            config
+                .chains
+                .chains
+                .get(network)?
+                .providers
+                .iter()
+                .find(|provider| provider.label == label)
+                .map(|provider| &provider.details)
+        }
+
+        fn web3<'a>(config: &'a Config, network: &str, label: &str) -> Option<&'a Web3Provider> {
+            match provider(config, network, label)? {
+                ProviderDetails::Web3(web3) => Some(web3),
+                ProviderDetails::Firehose(_) => None,
+            }
+        }
+
+        for ((network, label), transport) in &self.transports {
+            let (old, new) = match (
+                web3(&self.config, network, label),
+                web3(config, network, label),
+            ) {
+                (Some(old), Some(new)) => (old, new),
+                _ => {
+                    warn!(self.logger, "Provider is no longer configured, keeping its settings";
+                                       "network" => network,
+                                       "provider" => label);
+                    continue;
+                }
+            };
+            if old.url == new.url && old.headers == new.headers {
+                continue;
+            }
+            info!(self.logger, "Switching provider to new URL and headers";
+                               "network" => network,
+                               "provider" => label);
+            let (event_loop, new_transport) = Transport::new_rpc(&new.url, new.headers.clone());
+            // Like the event loops of all transports, this one has to
+            // stay around for as long as the process runs
+            std::mem::forget(event_loop);
+            transport.replace(new_transport);
+        }
+
+        for ((network, label), endpoint) in &self.firehose_endpoints {
+            let (old, new) = match (
+                provider(&self.config, network, label),
+                provider(config, network, label),
+            ) {
+                (Some(ProviderDetails::Firehose(old)), Some(ProviderDetails::Firehose(new))) => {
+                    (old, new)
+                }
+                _ => {
+                    warn!(self.logger, "Provider is no longer configured, keeping its settings";
+                                       "network" => network,
+                                       "provider" => label);
+                    continue;
+                }
+            };
+            if old.url != new.url {
+                warn!(self.logger, "Changing the URL of a Firehose provider requires a restart";
+                                   "network" => network,
+                                   "provider" => label);
+            }
+            if old.token != new.token {
+                info!(self.logger, "Switching Firehose provider to new token";
+                                   "network" => network,
+                                   "provider" => label);
+                endpoint.set_token(new.token.clone());
+            }
+        }
+    }
+}
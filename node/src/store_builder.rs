@@ -17,10 +17,38 @@ use graph_store_postgres::{
 
 use crate::config::{Config, Shard};
 
+/// Which of the connection strings in the configuration of a shard a
+/// connection pool uses
+#[derive(Clone, Debug, PartialEq)]
+pub enum PoolConnection {
+    /// The main pool of the shard
+    Main,
+    /// The pool for queries against the main database
+    Query,
+    /// The pool for the replica with the given name
+    Replica(String),
+}
+
+impl PoolConnection {
+    /// The connection string in `config` for this kind of pool of `shard`
+    pub fn url<'a>(&self, config: &'a Config, shard: &ShardName) -> Option<&'a str> {
+        let shard = config.stores.get(shard.as_str())?;
+        match self {
+            PoolConnection::Main | PoolConnection::Query => Some(shard.connection.as_str()),
+            PoolConnection::Replica(name) => shard
+                .replicas
+                .get(name)
+                .map(|replica| replica.connection.as_str()),
+        }
+    }
+}
+
 pub struct StoreBuilder {
     logger: Logger,
     subgraph_store: Arc<SubgraphStore>,
     pools: HashMap<ShardName, ConnectionPool>,
+    /// All connection pools, including the ones for queries and replicas
+    all_pools: Vec<(ShardName, PoolConnection, ConnectionPool)>,
     subscription_manager: Arc<SubscriptionManager>,
     chain_head_update_listener: Arc<PostgresChainHeadUpdateListener>,
     /// Map network names to the shards where they are/should be stored
@@ -45,8 +73,8 @@ impl StoreBuilder {
             registry.clone(),
         ));
 
-        let (store, pools) =
-            Self::make_subgraph_store_and_pools(logger, node, config, registry.cheap_clone());
+        let (store, pools, all_pools) =
+            Self::make_subgraph_store_and_all_pools(logger, node, config, registry.cheap_clone());
 
         // Try to perform setup (migrations etc.) for all the pools. If this
         // attempt doesn't work for all of them because the database is
@@ -70,6 +98,7 @@ impl StoreBuilder {
             logger: logger.cheap_clone(),
             subgraph_store: store,
             pools,
+            all_pools,
             subscription_manager,
             chain_head_update_listener,
             chains,
@@ -85,6 +114,23 @@ impl StoreBuilder {
         config: &Config,
         registry: Arc<impl MetricsRegistry>,
     ) -> (Arc<SubgraphStore>, HashMap<ShardName, ConnectionPool>) {
+        let (store, pools, _) =
+            Self::make_subgraph_store_and_all_pools(logger, node, config, registry);
+        (store, pools)
+    }
+
+    /// Like `make_subgraph_store_and_pools`, but also return all pools
+    /// together with the connection string they use
+    fn make_subgraph_store_and_all_pools(
+        logger: &Logger,
+        node: &NodeId,
+        config: &Config,
+        registry: Arc<impl MetricsRegistry>,
+    ) -> (
+        Arc<SubgraphStore>,
+        HashMap<ShardName, ConnectionPool>,
+        Vec<(ShardName, PoolConnection, ConnectionPool)>,
+    ) {
         let notification_sender = Arc::new(NotificationSender::new(registry.cheap_clone()));
 
         let servers = config
@@ -130,6 +176,24 @@ impl StoreBuilder {
                 .map(|(name, pool, _, _)| (name.clone(), pool.clone())),
         );
 
+        let mut all_pools = Vec::new();
+        for (name, pool, read_only_pools, _) in &shards {
+            let shard = &config.stores[name.as_str()];
+            // `replica_pools` puts the query pool, if there is one, first
+            let query_pool = if read_only_pools.len() > shard.replicas.len() {
+                Some(PoolConnection::Query)
+            } else {
+                None
+            };
+            let connections = std::iter::once(PoolConnection::Main)
+                .chain(query_pool)
+                .chain(shard.replicas.keys().cloned().map(PoolConnection::Replica));
+            let pools = std::iter::once(pool).chain(read_only_pools.iter());
+            for (connection, pool) in connections.zip(pools) {
+                all_pools.push((name.clone(), connection, pool.clone()));
+            }
+        }
+
         let store = Arc::new(SubgraphStore::new(
             logger,
             shards,
@@ -137,7 +201,7 @@ impl StoreBuilder {
             notification_sender,
         ));
 
-        (store, pools)
+        (store, pools, all_pools)
     }
 
     pub fn make_store(
@@ -208,7 +272,10 @@ impl StoreBuilder {
         )
     }
 
-    /// Create connection pools for each of the replicas
+    /// Create connection pools for each of the replicas. If the shard has
+    /// a `query_pool_size`, the first of these pools is for queries
+    /// against the main database, and takes over the weight of the main
+    /// database so that queries never use the main pool
     fn replica_pools(
         logger: &Logger,
         node: &NodeId,
@@ -217,13 +284,39 @@ impl StoreBuilder {
         registry: Arc<dyn MetricsRegistry>,
         servers: Arc<Vec<ForeignServer>>,
     ) -> (Vec<ConnectionPool>, Vec<usize>) {
-        let mut weights: Vec<_> = vec![shard.weight];
+        let query_pool_size = shard.query_pool_size_for(node, name).expect(&format!(
+            "we can determine the query pool size for store {}",
+            name
+        ));
+        let query_pool = query_pool_size.map(|pool_size| {
+            let logger = logger.new(o!("pool" => "query"));
+            info!(
+                logger,
+                "Connecting to Postgres (queries)";
+                "url" => SafeDisplay(shard.connection.as_str()),
+                "conn_pool_size" => pool_size,
+                "weight" => shard.weight
+            );
+            ConnectionPool::create(
+                name,
+                PoolName::Query,
+                shard.connection.to_owned(),
+                pool_size,
+                None,
+                &logger,
+                registry.cheap_clone(),
+                servers.clone(),
+            )
+        });
+
+        let mut weights: Vec<_> = match query_pool {
+            Some(_) => vec![0, shard.weight],
+            None => vec![shard.weight],
+        };
         (
-            shard
-                .replicas
-                .values()
-                .enumerate()
-                .map(|(i, replica)| {
+            query_pool
+                .into_iter()
+                .chain(shard.replicas.values().enumerate().map(|(i, replica)| {
                     let pool = format!("replica{}", i + 1);
                     let logger = logger.new(o!("pool" => pool.clone()));
                     info!(
@@ -247,7 +340,7 @@ impl StoreBuilder {
                         registry.cheap_clone(),
                         servers.clone(),
                     )
-                })
+                }))
                 .collect(),
             weights,
         )
@@ -273,6 +366,10 @@ impl StoreBuilder {
         self.chain_head_update_listener.clone()
     }
 
+    pub fn all_pools(&self) -> Vec<(ShardName, PoolConnection, ConnectionPool)> {
+        self.all_pools.clone()
+    }
+
     pub fn primary_pool(&self) -> ConnectionPool {
         self.pools.get(&*PRIMARY_SHARD).unwrap().clone()
     }
@@ -39,6 +39,7 @@ fn mock_host_exports(
             language: String::from("wasm/assemblyscript"),
             entities: vec![],
             abis: vec![],
+            fallback_abis: vec![],
             event_handlers: vec![],
             call_handlers: vec![],
             block_handlers: vec![],
@@ -57,6 +58,7 @@ fn mock_host_exports(
         Arc::new(templates),
         Arc::new(graph_core::LinkResolver::from(IpfsClient::localhost())),
         store,
+        None,
     )
 }
 
@@ -126,6 +128,7 @@ pub fn mock_data_source(path: &str, api_version: Version) -> DataSource {
             language: String::from("wasm/assemblyscript"),
             entities: vec![],
             abis: vec![],
+            fallback_abis: vec![],
             event_handlers: vec![],
             call_handlers: vec![],
             block_handlers: vec![],
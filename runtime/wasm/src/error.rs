@@ -9,7 +9,7 @@ pub enum DeterminismLevel {
     Deterministic,
 
     /// This error is known to be non-deterministic. For example, an intermittent http failure.
-    #[allow(dead_code)]
+    /// Host calls that fail with such an error are retried.
     NonDeterministic,
 
     /// The runtime is processing a given block, but there is an indication that the blockchain client
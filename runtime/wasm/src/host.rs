@@ -9,7 +9,7 @@ use graph::blockchain::RuntimeAdapter;
 use graph::blockchain::{Blockchain, DataSource};
 use graph::blockchain::{HostFn, TriggerWithHandler};
 use graph::components::store::SubgraphStore;
-use graph::components::subgraph::{MappingError, SharedProofOfIndexing};
+use graph::components::subgraph::{HostCalls, MappingError, SharedProofOfIndexing};
 use graph::prelude::{
     RuntimeHost as RuntimeHostTrait, RuntimeHostBuilder as RuntimeHostBuilderTrait, *,
 };
@@ -128,6 +128,7 @@ where
     ) -> Result<Self, Error> {
         // Create new instance of externally hosted functions invoker. The `Arc` is simply to avoid
         // implementing `Clone` for `HostExports`.
+        let host_calls = HostCalls::for_deployment(&subgraph_id)?;
         let host_exports = Arc::new(HostExports::new(
             subgraph_id,
             &data_source,
@@ -135,6 +136,7 @@ where
             templates,
             link_resolver,
             store,
+            host_calls,
         ));
 
         let host_fns = Arc::new(runtime_adapter.host_fns(&data_source)?);
@@ -159,6 +161,7 @@ where
         proof_of_indexing: SharedProofOfIndexing,
     ) -> Result<BlockState<C>, MappingError> {
         let handler = trigger.handler_name().to_string();
+        let error_count = state.deterministic_errors.len();
 
         let extras = trigger.logging_extras();
         trace!(
@@ -196,6 +199,11 @@ where
 
         let elapsed = start_time.elapsed();
         metrics.observe_handler_execution_time(elapsed.as_secs_f64(), &handler);
+        let failed = match &result {
+            Ok(state) => state.deterministic_errors.len() > error_count,
+            Err(_) => true,
+        };
+        metrics.trigger_processed(&handler, failed);
 
         info!(
             logger, "Done processing trigger";
@@ -217,7 +225,13 @@ impl<C: Blockchain> RuntimeHostTrait<C> for RuntimeHost<C> {
         block: Arc<C::Block>,
         logger: &Logger,
     ) -> Result<Option<TriggerWithHandler<C>>, Error> {
-        self.data_source.match_and_decode(trigger, block, logger)
+        let matched = self.data_source.match_and_decode(trigger, block, logger)?;
+        if matched.is_none() {
+            if let Some(handler) = self.data_source.declared_handler(trigger) {
+                self.metrics.trigger_skipped(&handler);
+            }
+        }
+        Ok(matched)
     }
 
     async fn process_mapping_trigger(
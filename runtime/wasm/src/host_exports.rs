@@ -5,7 +5,9 @@ use graph::blockchain::DataSource;
 use graph::blockchain::{Blockchain, DataSourceTemplate as _};
 use graph::components::store::EntityKey;
 use graph::components::store::EntityType;
-use graph::components::subgraph::{CausalityRegion, ProofOfIndexingEvent, SharedProofOfIndexing};
+use graph::components::subgraph::{
+    CausalityRegion, HostCalls, ProofOfIndexingEvent, SharedProofOfIndexing,
+};
 use graph::data::store;
 use graph::prelude::serde_json;
 use graph::prelude::{slog::b, slog::record_static, *};
@@ -42,13 +44,15 @@ impl IntoTrap for HostExportError {
             HostExportError::Deterministic(_) => DeterminismLevel::Deterministic,
             HostExportError::Unknown(_) => DeterminismLevel::Unimplemented,
             HostExportError::PossibleReorg(_) => DeterminismLevel::PossibleReorg,
+            HostExportError::Transient(_) => DeterminismLevel::NonDeterministic,
         }
     }
     fn into_trap(self) -> Trap {
         match self {
             HostExportError::Unknown(e)
             | HostExportError::PossibleReorg(e)
-            | HostExportError::Deterministic(e) => Trap::from(e),
+            | HostExportError::Deterministic(e)
+            | HostExportError::Transient(e) => Trap::from(e),
         }
     }
 }
@@ -68,6 +72,8 @@ pub struct HostExports<C: Blockchain> {
     templates: Arc<Vec<C::DataSourceTemplate>>,
     pub(crate) link_resolver: Arc<dyn LinkResolver>,
     store: Arc<dyn SubgraphStore>,
+    /// Set when host calls are recorded or replayed
+    pub(crate) host_calls: Option<Arc<HostCalls>>,
 }
 
 impl<C: Blockchain> HostExports<C> {
@@ -78,6 +84,7 @@ impl<C: Blockchain> HostExports<C> {
         templates: Arc<Vec<C::DataSourceTemplate>>,
         link_resolver: Arc<dyn LinkResolver>,
         store: Arc<dyn SubgraphStore>,
+        host_calls: Option<Arc<HostCalls>>,
     ) -> Self {
         Self {
             subgraph_id,
@@ -90,6 +97,7 @@ impl<C: Blockchain> HostExports<C> {
             templates,
             link_resolver,
             store,
+            host_calls,
         }
     }
 
@@ -219,7 +227,7 @@ impl<C: Blockchain> HostExports<C> {
         state: &mut BlockState<C>,
         entity_type: String,
         entity_id: String,
-    ) -> Result<Option<Entity>, anyhow::Error> {
+    ) -> Result<Option<Entity>, HostExportError> {
         let store_key = EntityKey {
             subgraph_id: self.subgraph_id.clone(),
             entity_type: EntityType::new(entity_type.clone()),
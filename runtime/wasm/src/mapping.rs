@@ -20,6 +20,15 @@ lazy_static! {
         .and_then(|max_stack_size| max_stack_size.parse().ok())
         // 512KiB
         .unwrap_or(ONE_MIB / 2);
+
+    /// Number of WASM instances that each mapping thread instantiates ahead
+    /// of time. Instances are never reused; the pool only moves
+    /// instantiation off the path of handling a trigger. Set to 0 to
+    /// disable the pool.
+    pub static ref INSTANCE_POOL_SIZE: usize = std::env::var("GRAPH_RUNTIME_INSTANCE_POOL_SIZE")
+        .ok()
+        .map(|s| s.parse().expect("invalid GRAPH_RUNTIME_INSTANCE_POOL_SIZE"))
+        .unwrap_or(0);
 }
 
 /// Spawn a wasm module in its own thread.
@@ -47,6 +56,7 @@ pub fn spawn_module<C: Blockchain>(
         thread::Builder::new().name(format!("mapping-{}-{}", &subgraph_id, uuid::Uuid::new_v4()));
     conf.spawn(move || {
         let _runtime_guard = runtime.enter();
+        let mut pool = InstancePool::new(*INSTANCE_POOL_SIZE);
 
         // Pass incoming triggers to the WASM module and return entity changes;
         // Stop when canceled because all RuntimeHosts and their senders were dropped.
@@ -59,16 +69,21 @@ pub fn spawn_module<C: Blockchain>(
                     result_sender,
                 } = request;
                 let logger = ctx.logger.cheap_clone();
+                let template = pool.is_enabled().then(|| ctx.derive_for_instance_pool());
 
-                // Start the WASM module runtime.
+                // Start the WASM module runtime, using a pre-instantiated
+                // instance if one is available.
                 let section = host_metrics.stopwatch.start_section("module_init");
-                let module = WasmInstance::from_valid_module_with_ctx(
-                    valid_module.cheap_clone(),
-                    ctx,
-                    host_metrics.cheap_clone(),
-                    timeout,
-                    experimental_features,
-                )?;
+                let module = match pool.take() {
+                    Some(instance) => instance.with_ctx(ctx),
+                    None => WasmInstance::from_valid_module_with_ctx(
+                        valid_module.cheap_clone(),
+                        ctx,
+                        host_metrics.cheap_clone(),
+                        timeout,
+                        experimental_features,
+                    )?,
+                };
                 section.end();
 
                 let section = host_metrics.stopwatch.start_section("run_handler");
@@ -80,7 +95,22 @@ pub fn spawn_module<C: Blockchain>(
 
                 result_sender
                     .send(result)
-                    .map_err(|_| anyhow::anyhow!("WASM module result receiver dropped."))
+                    .map_err(|_| anyhow::anyhow!("WASM module result receiver dropped."))?;
+
+                // Refill the pool only after the result was sent so that
+                // instantiation overlaps with the work of the caller.
+                if let Some(template) = template {
+                    pool.refill(&logger, || {
+                        WasmInstance::pre_instantiate(
+                            valid_module.cheap_clone(),
+                            template.derive_for_instance_pool(),
+                            host_metrics.cheap_clone(),
+                            timeout,
+                            experimental_features,
+                        )
+                    });
+                }
+                Ok(())
             })
             .wait()
         {
@@ -95,6 +125,60 @@ pub fn spawn_module<C: Blockchain>(
     Ok(mapping_request_sender)
 }
 
+/// Instances of a module that were instantiated ahead of time. Each
+/// instance handles exactly one trigger and is then dropped, so that every
+/// handler runs against freshly initialized WASM state.
+struct InstancePool<T> {
+    size: usize,
+    instances: Vec<T>,
+}
+
+impl<T> InstancePool<T> {
+    fn new(size: usize) -> Self {
+        InstancePool {
+            size,
+            instances: Vec::with_capacity(size),
+        }
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.size > 0
+    }
+
+    fn take(&mut self) -> Option<T> {
+        self.instances.pop()
+    }
+
+    /// Fill the pool back up to its size with instances from
+    /// `pre_instantiate`, which returns `None` if the module can't be
+    /// instantiated ahead of time
+    fn refill(
+        &mut self,
+        logger: &Logger,
+        mut pre_instantiate: impl FnMut() -> Result<Option<T>, anyhow::Error>,
+    ) {
+        while self.instances.len() < self.size {
+            match pre_instantiate() {
+                Ok(Some(instance)) => self.instances.push(instance),
+                Ok(None) => {
+                    // The start function of the module depends on the
+                    // context, so instances can't be prepared in advance
+                    debug!(
+                        logger,
+                        "WASM module calls host exports on start, disabling instance pool"
+                    );
+                    self.size = 0;
+                }
+                Err(e) => {
+                    debug!(logger, "Failed to pre-instantiate WASM module";
+                                   "error" => format!("{:#}", e));
+                    break;
+                }
+            }
+        }
+    }
+}
+
 pub struct MappingRequest<C: Blockchain> {
     pub(crate) ctx: MappingContext<C>,
     pub(crate) trigger: TriggerWithHandler<C>,
@@ -111,6 +195,15 @@ pub struct MappingContext<C: Blockchain> {
 }
 
 impl<C: Blockchain> MappingContext<C> {
+    /// A context for pre-instantiating a module. It must not hold on to the
+    /// proof of indexing, which is unwrapped once the block is done.
+    fn derive_for_instance_pool(&self) -> Self {
+        MappingContext {
+            proof_of_indexing: None,
+            ..self.derive_with_empty_block_state()
+        }
+    }
+
     pub fn derive_with_empty_block_state(&self) -> Self {
         MappingContext {
             logger: self.logger.cheap_clone(),
@@ -173,3 +266,73 @@ impl ValidModule {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn logger() -> Logger {
+        Logger::root(slog::Discard, o!())
+    }
+
+    #[test]
+    fn pool_is_refilled_after_take() {
+        let mut pool = InstancePool::new(2);
+        assert!(pool.is_enabled());
+        assert!(pool.take().is_none());
+
+        let mut next = 0;
+        pool.refill(&logger(), || {
+            next += 1;
+            Ok(Some(next))
+        });
+        assert_eq!(2, next);
+
+        // Instances are handed out once and replaced by fresh ones
+        assert_eq!(Some(2), pool.take());
+        pool.refill(&logger(), || {
+            next += 1;
+            Ok(Some(next))
+        });
+        assert_eq!(3, next);
+        assert_eq!(Some(3), pool.take());
+        assert_eq!(Some(1), pool.take());
+    }
+
+    #[test]
+    fn exhausted_pool_returns_none() {
+        let mut pool = InstancePool::new(1);
+        pool.refill(&logger(), || Ok(Some(())));
+        assert_eq!(Some(()), pool.take());
+        assert_eq!(None, pool.take());
+
+        // A failed instantiation leaves the pool empty but enabled so that
+        // the next refill tries again
+        pool.refill(&logger(), || Err(anyhow::anyhow!("out of memory")));
+        assert!(pool.is_enabled());
+        assert_eq!(None, pool.take());
+    }
+
+    #[test]
+    fn pool_is_disabled_for_modules_that_need_their_context() {
+        let mut pool = InstancePool::new(2);
+        let mut calls = 0;
+        pool.refill(&logger(), || {
+            calls += 1;
+            Ok(None::<()>)
+        });
+        assert_eq!(1, calls);
+        assert!(!pool.is_enabled());
+        assert_eq!(None, pool.take());
+    }
+
+    #[test]
+    fn pool_of_size_zero_is_disabled() {
+        let mut pool = InstancePool::new(0);
+        assert!(!pool.is_enabled());
+        pool.refill(&logger(), || -> Result<Option<()>, anyhow::Error> {
+            panic!("a disabled pool must not instantiate modules")
+        });
+        assert_eq!(None, pool.take());
+    }
+}
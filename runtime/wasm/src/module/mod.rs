@@ -1,4 +1,4 @@
-use std::cell::{RefCell, RefMut};
+use std::cell::{Cell, RefCell, RefMut};
 use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::rc::Rc;
@@ -14,9 +14,11 @@ use crate::error::DeterminismLevel;
 pub use crate::host_exports;
 use crate::mapping::MappingContext;
 use anyhow::Error;
+use graph::components::subgraph::{host_call, HostCallKind};
 use graph::data::store;
 use graph::prelude::*;
 use graph::runtime::{AscHeap, IndexForAscTypeId};
+use graph::util::backoff::ExponentialBackoff;
 use graph::{components::subgraph::MappingError, runtime::AscPtr};
 use graph::{
     data::subgraph::schema::SubgraphError,
@@ -35,11 +37,69 @@ pub use stopwatch::TimeoutStopwatch;
 
 pub const TRAP_TIMEOUT: &str = "trap: interrupt";
 
+lazy_static! {
+    /// How often to retry a host call that failed with a transient error
+    /// before failing the handler
+    static ref HOST_CALL_RETRIES: u64 = std::env::var("GRAPH_HOST_CALL_RETRIES")
+        .ok()
+        .map(|s| s.parse().expect("invalid GRAPH_HOST_CALL_RETRIES"))
+        .unwrap_or(5);
+}
+
 pub trait IntoTrap {
     fn determinism_level(&self) -> DeterminismLevel;
     fn into_trap(self) -> Trap;
 }
 
+/// The host calls that only read data and can therefore safely be run
+/// again when they fail with a transient error
+const RETRIABLE_HOST_FNS: &[&str] = &["store.get", "ethereum.call", "ens.nameByHash"];
+
+/// Run the host call `f`, retrying it with a backoff for as long as it
+/// fails with a non-deterministic error, up to `GRAPH_HOST_CALL_RETRIES`
+/// times. Only the host calls in `RETRIABLE_HOST_FNS` are retried
+fn retry_host_call<T, E>(
+    logger: &Logger,
+    name: &str,
+    f: impl FnMut() -> Result<T, E>,
+) -> Result<T, E>
+where
+    E: IntoTrap + std::fmt::Display,
+{
+    let backoff = ExponentialBackoff::new(Duration::from_secs(1), Duration::from_secs(30));
+    retry_with_backoff(logger, name, backoff, f)
+}
+
+fn retry_with_backoff<T, E>(
+    logger: &Logger,
+    name: &str,
+    mut backoff: ExponentialBackoff,
+    mut f: impl FnMut() -> Result<T, E>,
+) -> Result<T, E>
+where
+    E: IntoTrap + std::fmt::Display,
+{
+    let retriable = RETRIABLE_HOST_FNS.contains(&name);
+    loop {
+        match f() {
+            Err(e)
+                if retriable
+                    && matches!(e.determinism_level(), DeterminismLevel::NonDeterministic)
+                    && backoff.attempt < *HOST_CALL_RETRIES =>
+            {
+                warn!(logger, "Host call failed with a transient error, retrying";
+                              "host_fn" => name,
+                              "attempt" => backoff.attempt + 1,
+                              "error" => e.to_string());
+                // Host calls run on the mapping's thread, which has access
+                // to the tokio runtime
+                graph::block_on(backoff.sleep_async());
+            }
+            result => return result,
+        }
+    }
+}
+
 /// Handle to a WASM instance, which is terminated if and only if this is dropped.
 pub struct WasmInstance<C: Blockchain> {
     pub instance: wasmtime::Instance,
@@ -262,6 +322,67 @@ impl<C: Blockchain> WasmInstance<C> {
         timeout: Option<Duration>,
         experimental_features: ExperimentalFeatures,
     ) -> Result<WasmInstance<C>, anyhow::Error> {
+        Self::instantiate(
+            valid_module,
+            ctx,
+            host_metrics,
+            timeout,
+            experimental_features,
+            true,
+        )
+        .map(|(instance, _)| instance)
+    }
+
+    /// Instantiates the module ahead of time so that it can be kept in an
+    /// instance pool. The timeout only starts running once the instance is
+    /// handed its real context with `with_ctx`.
+    ///
+    /// Returns `None` if the start function of the module calls any host
+    /// exports. Such an instance depends on the context it was created with
+    /// and can therefore not be handed a different one.
+    pub(crate) fn pre_instantiate(
+        valid_module: Arc<ValidModule>,
+        ctx: MappingContext<C>,
+        host_metrics: Arc<HostMetrics>,
+        timeout: Option<Duration>,
+        experimental_features: ExperimentalFeatures,
+    ) -> Result<Option<WasmInstance<C>>, anyhow::Error> {
+        let (instance, start_called_host_exports) = Self::instantiate(
+            valid_module,
+            ctx,
+            host_metrics,
+            timeout,
+            experimental_features,
+            false,
+        )?;
+        Ok(if start_called_host_exports {
+            None
+        } else {
+            Some(instance)
+        })
+    }
+
+    /// Replace the context of a pre-instantiated instance with the context
+    /// for the trigger it will handle and start its timeout.
+    pub(crate) fn with_ctx(self, ctx: MappingContext<C>) -> Self {
+        {
+            let mut instance_ctx = self.instance_ctx_mut();
+            instance_ctx.ctx = ctx;
+            instance_ctx.timeout_stopwatch.lock().unwrap().start();
+        }
+        self
+    }
+
+    /// Instantiates the module and runs its start function. Also returns
+    /// whether any host exports were called while doing that.
+    fn instantiate(
+        valid_module: Arc<ValidModule>,
+        ctx: MappingContext<C>,
+        host_metrics: Arc<HostMetrics>,
+        timeout: Option<Duration>,
+        experimental_features: ExperimentalFeatures,
+        start_timeout: bool,
+    ) -> Result<(WasmInstance<C>, bool), anyhow::Error> {
         let mut linker = wasmtime::Linker::new(&wasmtime::Store::new(valid_module.module.engine()));
         let host_fns = ctx.host_fns.cheap_clone();
         let api_version = ctx.host_exports.api_version.clone();
@@ -276,17 +397,27 @@ impl<C: Blockchain> WasmInstance<C> {
         // it will be moved so we need this ugly thing.
         let ctx: Rc<RefCell<Option<MappingContext<C>>>> = Rc::new(RefCell::new(Some(ctx)));
 
+        // Set by every host export, so we know whether the start function called any.
+        let host_export_called = Rc::new(Cell::new(false));
+
         // Start the timeout watchdog task.
-        let timeout_stopwatch = Arc::new(std::sync::Mutex::new(TimeoutStopwatch::start_new()));
+        let timeout_stopwatch = Arc::new(std::sync::Mutex::new(match start_timeout {
+            true => TimeoutStopwatch::start_new(),
+            false => TimeoutStopwatch::new(),
+        }));
         if let Some(timeout) = timeout {
-            // This task is likely to outlive the instance, which is fine.
+            // This task is likely to outlive the instance, which is fine. It
+            // stops once the instance is dropped, even if it never ran.
             let interrupt_handle = linker.store().interrupt_handle().unwrap();
-            let timeout_stopwatch = timeout_stopwatch.clone();
+            let timeout_stopwatch = Arc::downgrade(&timeout_stopwatch);
             graph::spawn_allow_panic(async move {
                 let minimum_wait = Duration::from_secs(1);
                 loop {
-                    let time_left =
-                        timeout.checked_sub(timeout_stopwatch.lock().unwrap().elapsed());
+                    let elapsed = match timeout_stopwatch.upgrade() {
+                        Some(stopwatch) => stopwatch.lock().unwrap().elapsed(),
+                        None => break,
+                    };
+                    let time_left = timeout.checked_sub(elapsed);
                     match time_left {
                         None => break interrupt_handle.interrupt(), // Timed out.
 
@@ -316,10 +447,12 @@ impl<C: Blockchain> WasmInstance<C> {
                     let host_metrics = host_metrics.cheap_clone();
                     let timeout_stopwatch = timeout_stopwatch.cheap_clone();
                     let ctx = ctx.cheap_clone();
+                    let host_export_called = host_export_called.cheap_clone();
                     linker.func(
                         module,
                         $wasm_name,
                         move |caller: wasmtime::Caller, $($param: u32),*| {
+                            host_export_called.set(true);
                             let instance = func_shared_ctx.upgrade().unwrap();
                             let mut instance = instance.borrow_mut();
 
@@ -339,9 +472,10 @@ impl<C: Blockchain> WasmInstance<C> {
                             let instance = instance.as_mut().unwrap();
                             let _section = instance.host_metrics.stopwatch.start_section($section);
 
-                            let result = instance.$rust_name(
-                                $($param.into()),*
-                            );
+                            let logger = instance.ctx.logger.cheap_clone();
+                            let result = retry_host_call(&logger, $wasm_name, || {
+                                instance.$rust_name($($param.into()),*)
+                            });
                             match result {
                                 Ok(result) => Ok(result.into_wasm_ret()),
                                 Err(e) => {
@@ -398,12 +532,17 @@ impl<C: Blockchain> WasmInstance<C> {
                     let _section =
                         stopwatch.start_section(&format!("host_export_{}", name_for_metrics));
 
-                    let ctx = HostFnCtx {
-                        logger: instance.ctx.logger.cheap_clone(),
-                        block_ptr: instance.ctx.block_ptr.cheap_clone(),
-                        heap: instance,
-                    };
-                    let ret = (host_fn.func)(ctx, call_ptr).map_err(|e| match e {
+                    let logger = instance.ctx.logger.cheap_clone();
+                    let ret = retry_host_call(&logger, host_fn.name, || {
+                        let ctx = HostFnCtx {
+                            logger: instance.ctx.logger.cheap_clone(),
+                            block_ptr: instance.ctx.block_ptr.cheap_clone(),
+                            host_calls: instance.ctx.host_exports.host_calls.cheap_clone(),
+                            heap: &mut *instance,
+                        };
+                        (host_fn.func)(ctx, call_ptr)
+                    })
+                    .map_err(|e| match e {
                         HostExportError::Deterministic(e) => {
                             instance.deterministic_host_trap = true;
                             e
@@ -412,7 +551,7 @@ impl<C: Blockchain> WasmInstance<C> {
                             instance.possible_reorg = true;
                             e
                         }
-                        HostExportError::Unknown(e) => e,
+                        HostExportError::Unknown(e) | HostExportError::Transient(e) => e,
                     })?;
                     instance.host_metrics.observe_host_fn_execution_time(
                         start.elapsed().as_secs_f64(),
@@ -541,10 +680,13 @@ impl<C: Blockchain> WasmInstance<C> {
             }
         }
 
-        Ok(WasmInstance {
-            instance,
-            instance_ctx: shared_ctx,
-        })
+        Ok((
+            WasmInstance {
+                instance,
+                instance_ctx: shared_ctx,
+            },
+            host_export_called.get(),
+        ))
     }
 }
 
@@ -825,12 +967,17 @@ impl<C: Blockchain> WasmInstanceContext<C> {
             .host_metrics
             .cheap_clone()
             .time_host_fn_execution_region("store_get");
-        let entity_ptr = asc_get(self, entity_ptr)?;
-        let id_ptr = asc_get(self, id_ptr)?;
-        let entity_option =
-            self.ctx
-                .host_exports
-                .store_get(&mut self.ctx.state, entity_ptr, id_ptr)?;
+        let entity_type: String = asc_get(self, entity_ptr)?;
+        let id: String = asc_get(self, id_ptr)?;
+        let host_exports = &self.ctx.host_exports;
+        let state = &mut self.ctx.state;
+        let entity_option = host_call(
+            host_exports.host_calls.as_deref(),
+            self.ctx.block_ptr.number,
+            HostCallKind::StoreGet,
+            &(&entity_type, &id),
+            || host_exports.store_get(state, entity_type.clone(), id.clone()),
+        )?;
 
         let ret = match entity_option {
             Some(entity) => {
@@ -963,8 +1110,15 @@ impl<C: Blockchain> WasmInstanceContext<C> {
             )));
         }
 
-        let link = asc_get(self, link_ptr)?;
-        let ipfs_res = self.ctx.host_exports.ipfs_cat(&self.ctx.logger, link);
+        let link: String = asc_get(self, link_ptr)?;
+        let host_exports = &self.ctx.host_exports;
+        let ipfs_res = host_call(
+            host_exports.host_calls.as_deref(),
+            self.ctx.block_ptr.number,
+            HostCallKind::IpfsCat,
+            &link,
+            || host_exports.ipfs_cat(&self.ctx.logger, link.clone()),
+        );
         match ipfs_res {
             Ok(bytes) => asc_new(self, &*bytes).map_err(Into::into),
 
@@ -1445,3 +1599,44 @@ impl<C: Blockchain> WasmInstanceContext<C> {
         )))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn retry(name: &str, failures: u64) -> (Result<(), HostExportError>, u64) {
+        let runtime = graph::prelude::tokio::runtime::Runtime::new().unwrap();
+        let _guard = runtime.enter();
+
+        let backoff = ExponentialBackoff::new(Duration::from_millis(1), Duration::from_millis(1));
+        let mut calls = 0;
+        let res = retry_with_backoff(&Logger::root(slog::Discard, o!()), name, backoff, || {
+            calls += 1;
+            if calls <= failures {
+                Err(HostExportError::Transient(anyhow!("connection reset")))
+            } else {
+                Ok(())
+            }
+        });
+        (res, calls)
+    }
+
+    #[test]
+    fn transient_errors_are_retried() {
+        let (res, calls) = retry("store.get", 2);
+        assert!(res.is_ok());
+        assert_eq!(3, calls);
+
+        // Retries are limited to `GRAPH_HOST_CALL_RETRIES`
+        let (res, calls) = retry("store.get", *HOST_CALL_RETRIES + 1);
+        assert!(matches!(res, Err(HostExportError::Transient(_))));
+        assert_eq!(*HOST_CALL_RETRIES + 1, calls);
+    }
+
+    #[test]
+    fn writes_are_not_retried() {
+        let (res, calls) = retry("store.set", 1);
+        assert!(matches!(res, Err(HostExportError::Transient(_))));
+        assert_eq!(1, calls);
+    }
+}
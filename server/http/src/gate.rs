@@ -0,0 +1,229 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use graph::components::server::query::{GateRejection, GatedRequest, RequestGate};
+use graph::prelude::*;
+
+lazy_static! {
+    /// How many queries per second each deployment can receive over HTTP,
+    /// no matter which subgraph name or id they use. Unlimited if not set
+    static ref DEPLOYMENT_RATE_LIMIT: Option<f64> =
+        rate_from_env("GRAPH_GRAPHQL_DEPLOYMENT_RATE_LIMIT");
+
+    /// How many queries per second each client IP address can send over
+    /// HTTP. Unlimited if not set
+    static ref CLIENT_RATE_LIMIT: Option<f64> = rate_from_env("GRAPH_GRAPHQL_CLIENT_RATE_LIMIT");
+}
+
+/// Read a rate limit from the environment variable `var`. A limit has to be
+/// a positive number since we could otherwise never refill a bucket
+fn rate_from_env(var: &str) -> Option<f64> {
+    std::env::var(var).ok().map(|s| parse_rate(var, &s))
+}
+
+fn parse_rate(var: &str, s: &str) -> f64 {
+    match s.parse::<f64>() {
+        Ok(rate) if rate.is_finite() && rate > 0.0 => rate,
+        _ => panic!("env var {} must be a positive number but is `{}`", var, s),
+    }
+}
+
+/// Once a rate limit tracks this many keys, it forgets the ones that have
+/// not sent requests for the longest time
+const MAX_TRACKED_KEYS: usize = 10_000;
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// The buckets of a rate limit, together with an index by when they were
+/// last updated so that we can find the oldest bucket quickly
+#[derive(Default)]
+struct Buckets {
+    by_key: HashMap<String, Bucket>,
+    by_age: BTreeSet<(Instant, String)>,
+}
+
+/// A token bucket for each key that refills at `rate` tokens per second
+/// and holds at most `burst` tokens; each request takes one token
+struct RateLimit {
+    rate: f64,
+    burst: f64,
+    buckets: Mutex<Buckets>,
+}
+
+impl RateLimit {
+    fn new(rate: f64) -> Self {
+        RateLimit {
+            rate,
+            burst: rate.max(1.0),
+            buckets: Mutex::new(Buckets::default()),
+        }
+    }
+
+    /// Take a token for `key`, or return how long it will take until the
+    /// bucket for `key` has a token again
+    fn take(&self, key: &str, now: Instant) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap();
+        let Buckets { by_key, by_age } = &mut *buckets;
+
+        match by_key.get(key) {
+            Some(bucket) => {
+                by_age.remove(&(bucket.updated, key.to_string()));
+            }
+            None if by_key.len() >= MAX_TRACKED_KEYS => {
+                if let Some(oldest) = by_age.iter().next().cloned() {
+                    by_age.remove(&oldest);
+                    by_key.remove(&oldest.1);
+                }
+            }
+            None => (),
+        }
+        by_age.insert((now, key.to_string()));
+
+        let bucket = by_key.entry(key.to_string()).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate))
+        }
+    }
+}
+
+/// The rate limits for queries that are set with
+/// `GRAPH_GRAPHQL_DEPLOYMENT_RATE_LIMIT` and `GRAPH_GRAPHQL_CLIENT_RATE_LIMIT`
+pub(crate) struct RateLimits {
+    deployment: Option<RateLimit>,
+    client: Option<RateLimit>,
+}
+
+impl RateLimits {
+    /// The rate limits from the environment, or `None` if no limits are
+    /// set
+    pub fn from_env() -> Option<Self> {
+        if DEPLOYMENT_RATE_LIMIT.is_none() && CLIENT_RATE_LIMIT.is_none() {
+            return None;
+        }
+        Some(RateLimits {
+            deployment: DEPLOYMENT_RATE_LIMIT.map(RateLimit::new),
+            client: CLIENT_RATE_LIMIT.map(RateLimit::new),
+        })
+    }
+}
+
+impl RequestGate for RateLimits {
+    fn check(&self, request: &GatedRequest) -> Result<(), GateRejection> {
+        let now = Instant::now();
+        // Check the deployment first so that clients do not use up their
+        // tokens on queries that get rejected anyway
+        if let Some(limit) = &self.deployment {
+            limit
+                .take(request.deployment.as_str(), now)
+                .map_err(GateRejection::RateLimited)?;
+        }
+        if let (Some(limit), Some(client)) = (&self.client, request.client) {
+            limit
+                .take(&client.to_string(), now)
+                .map_err(GateRejection::RateLimited)?;
+        }
+        Ok(())
+    }
+}
+
+/// The gates that a query has to pass, in the order in which they were
+/// added to the server
+#[derive(Clone, Default)]
+pub(crate) struct Gates(Vec<Arc<dyn RequestGate>>);
+
+impl Gates {
+    pub fn new(gates: Vec<Arc<dyn RequestGate>>) -> Self {
+        Gates(gates)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn check(&self, request: &GatedRequest) -> Result<(), GateRejection> {
+        self.0.iter().try_for_each(|gate| gate.check(request))
+    }
+}
+
+impl fmt::Debug for Gates {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Gates {{ {} gates }}", self.0.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{parse_rate, RateLimit, MAX_TRACKED_KEYS};
+
+    #[test]
+    fn rate_limit_refills() {
+        let limit = RateLimit::new(2.0);
+        let start = Instant::now();
+
+        assert_eq!(Ok(()), limit.take("a", start));
+        assert_eq!(Ok(()), limit.take("a", start));
+        assert_eq!(Err(Duration::from_millis(500)), limit.take("a", start));
+        // Other keys have their own bucket
+        assert_eq!(Ok(()), limit.take("b", start));
+
+        let later = start + Duration::from_millis(500);
+        assert_eq!(Ok(()), limit.take("a", later));
+        assert!(limit.take("a", later).is_err());
+    }
+
+    #[test]
+    fn rate_limit_forgets_oldest_key() {
+        let limit = RateLimit::new(1.0);
+        let start = Instant::now();
+
+        for i in 0..MAX_TRACKED_KEYS {
+            let now = start + Duration::from_millis(i as u64);
+            assert_eq!(Ok(()), limit.take(&i.to_string(), now));
+        }
+        // Key 0 has the oldest bucket, but it just used it again
+        let now = start + Duration::from_secs(60);
+        assert!(limit.take("0", now).is_ok());
+
+        // Tracking a new key forgets key 1, which has not sent a request
+        // for the longest time
+        assert_eq!(Ok(()), limit.take("new", now));
+        let buckets = limit.buckets.lock().unwrap();
+        assert_eq!(MAX_TRACKED_KEYS, buckets.by_key.len());
+        assert_eq!(MAX_TRACKED_KEYS, buckets.by_age.len());
+        assert!(buckets.by_key.contains_key("0"));
+        assert!(!buckets.by_key.contains_key("1"));
+    }
+
+    #[test]
+    fn positive_rates_parse() {
+        assert_eq!(2.5, parse_rate("RATE", "2.5"));
+    }
+
+    #[test]
+    #[should_panic]
+    fn zero_rate_is_rejected() {
+        parse_rate("RATE", "0");
+    }
+
+    #[test]
+    #[should_panic]
+    fn nan_rate_is_rejected() {
+        parse_rate("RATE", "NaN");
+    }
+}
@@ -8,6 +8,7 @@ extern crate http;
 extern crate hyper;
 extern crate serde;
 
+mod gate;
 mod request;
 mod server;
 mod service;
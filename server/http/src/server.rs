@@ -1,10 +1,13 @@
 use std::net::{Ipv4Addr, SocketAddrV4};
 
 use hyper;
+use hyper::server::conn::AddrStream;
 use hyper::service::make_service_fn;
 use hyper::Server;
 
+use crate::gate::{Gates, RateLimits};
 use crate::service::{GraphQLService, GraphQLServiceMetrics};
+use graph::components::server::query::RequestGate;
 use graph::prelude::{GraphQLServer as GraphQLServerTrait, *};
 use thiserror::Error;
 
@@ -27,6 +30,7 @@ pub struct GraphQLServer<Q> {
     metrics: Arc<GraphQLServiceMetrics>,
     graphql_runner: Arc<Q>,
     node_id: NodeId,
+    gates: Vec<Arc<dyn RequestGate>>,
 }
 
 impl<Q> GraphQLServer<Q> {
@@ -51,8 +55,17 @@ impl<Q> GraphQLServer<Q> {
             metrics,
             graphql_runner,
             node_id,
+            gates: vec![],
         }
     }
+
+    /// Add a check that queries have to pass before they are run. Gates
+    /// are checked in the order in which they were added, and before the
+    /// rate limits from `GRAPH_GRAPHQL_DEPLOYMENT_RATE_LIMIT` and
+    /// `GRAPH_GRAPHQL_CLIENT_RATE_LIMIT`
+    pub fn add_request_gate(&mut self, gate: Arc<dyn RequestGate>) {
+        self.gates.push(gate);
+    }
 }
 
 impl<Q> GraphQLServerTrait for GraphQLServer<Q>
@@ -81,14 +94,23 @@ where
         let graphql_runner = self.graphql_runner.clone();
         let metrics = self.metrics.clone();
         let node_id = self.node_id.clone();
-        let new_service = make_service_fn(move |_| {
-            futures03::future::ok::<_, Error>(GraphQLService::new(
-                logger_for_service.clone(),
-                metrics.clone(),
-                graphql_runner.clone(),
-                ws_port,
-                node_id.clone(),
-            ))
+        let mut gates = self.gates.clone();
+        if let Some(rate_limits) = RateLimits::from_env() {
+            gates.push(Arc::new(rate_limits));
+        }
+        let gates = Gates::new(gates);
+        let new_service = make_service_fn(move |conn: &AddrStream| {
+            let client = conn.remote_addr().ip();
+            futures03::future::ok::<_, Error>(
+                GraphQLService::new(
+                    logger_for_service.clone(),
+                    metrics.clone(),
+                    graphql_runner.clone(),
+                    ws_port,
+                    node_id.clone(),
+                )
+                .with_gates(gates.clone(), Some(client)),
+            )
         });
 
         // Create a task to run the server and handle HTTP requests
@@ -1,22 +1,40 @@
 use std::convert::TryFrom;
 use std::fmt;
+use std::net::IpAddr;
 use std::pin::Pin;
 use std::task::Context;
 use std::task::Poll;
 use std::time::Instant;
 
 use graph::prelude::*;
-use graph::{components::server::query::GraphQLServerError, data::query::QueryTarget};
+use graph::{
+    components::server::query::{GateRejection, GatedRequest, GraphQLServerError},
+    data::query::{QueryResults, QueryTarget},
+};
 use http::header;
 use http::header::{
-    ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN,
-    CONTENT_TYPE, LOCATION,
+    ACCEPT, ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS,
+    ACCESS_CONTROL_ALLOW_ORIGIN, AUTHORIZATION, CACHE_CONTROL, CONTENT_TYPE, FORWARDED, LOCATION,
+    RETRY_AFTER, WWW_AUTHENTICATE,
 };
 use hyper::service::Service;
 use hyper::{Body, Method, Request, Response, StatusCode};
 
+use crate::gate::Gates;
 use crate::request::GraphQLRequest;
 
+lazy_static! {
+    /// Do not serve the GraphiQL UI at `/subgraphs/.../graphql`
+    static ref GRAPHIQL_DISABLED: bool = std::env::var("GRAPH_DISABLE_GRAPHIQL").is_ok();
+
+    /// Take the address of clients from the `X-Forwarded-For` or
+    /// `Forwarded` header that a proxy in front of `graph-node` adds.
+    /// Clients can set these headers themselves, and they must only be
+    /// trusted if all requests come through such a proxy
+    static ref TRUST_FORWARDED_FOR: bool =
+        std::env::var("GRAPH_GRAPHQL_TRUST_FORWARDED_FOR").is_ok();
+}
+
 pub struct GraphQLServiceMetrics {
     query_execution_time: Box<HistogramVec>,
     failed_query_execution_time: Box<HistogramVec>,
@@ -67,6 +85,89 @@ impl GraphQLServiceMetrics {
     }
 }
 
+/// Whether the client asked for the results of its query as a stream of
+/// server-sent events
+fn accepts_event_stream(request: &Request<Body>) -> bool {
+    request
+        .headers()
+        .get_all(ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .any(|value| value.contains("text/event-stream"))
+}
+
+/// The token from an `Authorization: Bearer <token>` header
+fn bearer_token(request: &Request<Body>) -> Option<&str> {
+    let value = request.headers().get(AUTHORIZATION)?.to_str().ok()?.trim();
+    let (scheme, token) = value.split_at(value.find(' ')?);
+    if scheme.eq_ignore_ascii_case("bearer") {
+        Some(token.trim())
+    } else {
+        None
+    }
+}
+
+/// The client address that the proxy in front of us added to the
+/// `X-Forwarded-For` or `Forwarded` header of `request`. Proxies append
+/// the address they got the request from to these headers, and only the
+/// last address was added by our proxy; the ones before it come from the
+/// client and can not be trusted
+fn forwarded_for(request: &Request<Body>) -> Option<IpAddr> {
+    fn last_element(request: &Request<Body>, name: header::HeaderName) -> Option<&str> {
+        let value = request.headers().get_all(name).iter().last()?;
+        value.to_str().ok()?.rsplit(',').next().map(str::trim)
+    }
+
+    // A node in a `Forwarded` header can be quoted and have a port, and
+    // IPv6 addresses are in brackets then
+    fn parse_node(node: &str) -> Option<IpAddr> {
+        let node = node.trim_matches('"');
+        if let Some(rest) = node.strip_prefix('[') {
+            return rest[..rest.find(']')?].parse().ok();
+        }
+        node.parse()
+            .ok()
+            .or_else(|| node[..node.rfind(':')?].parse().ok())
+    }
+
+    if let Some(addr) = last_element(request, header::HeaderName::from_static("x-forwarded-for")) {
+        return addr.parse().ok();
+    }
+    last_element(request, FORWARDED)?
+        .split(';')
+        .filter_map(|pair| {
+            let (key, value) = pair.trim().split_at(pair.trim().find('=')?);
+            Some((key, &value[1..]))
+        })
+        .find(|(key, _)| key.eq_ignore_ascii_case("for"))
+        .and_then(|(_, node)| parse_node(node))
+}
+
+fn rejection_response(rejection: GateRejection) -> Response<Body> {
+    let builder = Response::builder().header(ACCESS_CONTROL_ALLOW_ORIGIN, "*");
+    match rejection {
+        GateRejection::Unauthorized(msg) => builder
+            .status(StatusCode::UNAUTHORIZED)
+            .header(WWW_AUTHENTICATE, "Bearer")
+            .header(CONTENT_TYPE, "text/plain")
+            .body(Body::from(msg)),
+        GateRejection::RateLimited(retry_after) => {
+            // `Retry-After` is in whole seconds; round up so that clients
+            // that follow it do not get rejected again
+            let secs = retry_after.as_secs() + (retry_after.subsec_nanos() > 0) as u64;
+            builder
+                .status(StatusCode::TOO_MANY_REQUESTS)
+                .header(RETRY_AFTER, secs.to_string())
+                .header(CONTENT_TYPE, "text/plain")
+                .body(Body::from(format!(
+                    "Too many requests, retry in {} seconds",
+                    secs
+                )))
+        }
+    }
+    .unwrap()
+}
+
 pub type GraphQLServiceResult = Result<Response<Body>, GraphQLServerError>;
 /// An asynchronous response to a GraphQL request.
 pub type GraphQLServiceResponse =
@@ -80,6 +181,8 @@ pub struct GraphQLService<Q> {
     graphql_runner: Arc<Q>,
     ws_port: u16,
     node_id: NodeId,
+    gates: Gates,
+    client: Option<IpAddr>,
 }
 
 impl<Q> Clone for GraphQLService<Q> {
@@ -90,6 +193,8 @@ impl<Q> Clone for GraphQLService<Q> {
             graphql_runner: self.graphql_runner.clone(),
             ws_port: self.ws_port,
             node_id: self.node_id.clone(),
+            gates: self.gates.clone(),
+            client: self.client,
         }
     }
 }
@@ -112,6 +217,17 @@ where
             graphql_runner,
             ws_port,
             node_id,
+            gates: Gates::default(),
+            client: None,
+        }
+    }
+
+    /// Make queries from `client` pass `gates` before running them
+    pub(crate) fn with_gates(self, gates: Gates, client: Option<IpAddr>) -> Self {
+        GraphQLService {
+            gates,
+            client,
+            ..self
         }
     }
 
@@ -127,7 +243,8 @@ where
             .header(CONTENT_TYPE, "text/plain")
             .body(Body::from(String::from(
                 "Access deployed subgraphs by deployment ID at \
-                /subgraphs/id/<ID> or by name at /subgraphs/name/<NAME>",
+                /subgraphs/id/<ID> or by name at /subgraphs/name/<NAME>; the \
+                pending version of a subgraph is at /subgraphs/pending/<NAME>",
             )))
             .unwrap())
     }
@@ -163,6 +280,9 @@ where
     }
 
     fn handle_graphiql(&self) -> GraphQLServiceResponse {
+        if *GRAPHIQL_DISABLED {
+            return self.handle_not_found();
+        }
         self.serve_dynamic_file(self.graphiql_html())
     }
 
@@ -175,7 +295,21 @@ where
             GraphQLServerError::ClientError(format!("Invalid subgraph name {:?}", subgraph_name))
         })?;
 
-        self.handle_graphql_query(subgraph_name.into(), request.into_body())
+        self.handle_graphql_query(subgraph_name.into(), request)
+            .await
+    }
+
+    /// Query the pending version of the subgraph with the given name
+    async fn handle_graphql_query_pending(
+        self,
+        subgraph_name: String,
+        request: Request<Body>,
+    ) -> GraphQLServiceResult {
+        let subgraph_name = SubgraphName::new(subgraph_name.as_str()).map_err(|()| {
+            GraphQLServerError::ClientError(format!("Invalid subgraph name {:?}", subgraph_name))
+        })?;
+
+        self.handle_graphql_query(QueryTarget::Pending(subgraph_name), request)
             .await
     }
 
@@ -188,17 +322,49 @@ where
             .map_err(|id| GraphQLServerError::ClientError(format!("Invalid subgraph id `{}`", id)));
         match res {
             Err(_) => self.handle_not_found(),
-            Ok(id) => self
-                .handle_graphql_query(id.into(), request.into_body())
-                .boxed(),
+            Ok(id) => self.handle_graphql_query(id.into(), request).boxed(),
         }
     }
 
     async fn handle_graphql_query(
         self,
         target: QueryTarget,
-        request_body: Body,
+        request: Request<Body>,
     ) -> GraphQLServiceResult {
+        if !self.gates.is_empty() {
+            // Gates see the deployment that the query will go to so that
+            // limits for it can not be avoided by using another name for it
+            let deployment = match self
+                .graphql_runner
+                .cheap_clone()
+                .deployment_for_target(target.clone())
+                .await
+            {
+                Ok(deployment) => deployment,
+                Err(e) => return Ok(QueryResults::from(e).as_http_response()),
+            };
+            let client = if *TRUST_FORWARDED_FOR {
+                forwarded_for(&request).or(self.client)
+            } else {
+                self.client
+            };
+            let gated = GatedRequest {
+                target: &target,
+                deployment: &deployment,
+                client,
+                token: bearer_token(&request),
+            };
+            if let Err(rejection) = self.gates.check(&gated) {
+                return Ok(rejection_response(rejection));
+            }
+        }
+
+        if accepts_event_stream(&request) {
+            return self
+                .handle_graphql_event_stream(target, request.into_body())
+                .await;
+        }
+        let request_body = request.into_body();
         let service = self.clone();
         let service_metrics = self.metrics.clone();
 
@@ -222,13 +388,73 @@ where
         Ok(result.as_http_response())
     }
 
+    /// Run the query in the request body as a subscription and send its
+    /// results to the client as server-sent events, following the
+    /// 'distinct connections' mode of the GraphQL over SSE protocol. Each
+    /// result is a `next` event; when the subscription ends, we send a
+    /// `complete` event. The subscription is stopped when the client
+    /// closes the connection
+    async fn handle_graphql_event_stream(
+        self,
+        target: QueryTarget,
+        request_body: Body,
+    ) -> GraphQLServiceResult {
+        let body = hyper::body::to_bytes(request_body)
+            .map_err(|_| GraphQLServerError::InternalError("Failed to read request body".into()))
+            .await?;
+        let query = match GraphQLRequest::new(body).compat().await {
+            Ok(query) => query,
+            Err(GraphQLServerError::QueryError(e)) => {
+                return Ok(QueryResults::from(QueryResult::from(e)).as_http_response())
+            }
+            Err(e) => return Err(e),
+        };
+
+        let mut results = match self
+            .graphql_runner
+            .cheap_clone()
+            .run_subscription(Subscription { query }, target)
+            .await
+        {
+            Ok(results) => results,
+            Err(SubscriptionError::GraphQLError(e)) => {
+                return Ok(QueryResults::from(QueryResult::from(e)).as_http_response())
+            }
+        };
+
+        let (mut sender, body) = Body::channel();
+        graph::spawn(async move {
+            while let Some(result) = results.next().await {
+                let data = serde_json::to_string(&QueryResults::from(result))
+                    .expect("Failed to serialize GraphQL response to JSON");
+                let event = format!("event: next\ndata: {}\n\n", data);
+                if sender.send_data(event.into()).await.is_err() {
+                    // The client went away
+                    return;
+                }
+            }
+            let _ = sender.send_data("event: complete\ndata:\n\n".into()).await;
+        });
+
+        Ok(Response::builder()
+            .status(200)
+            .header(ACCESS_CONTROL_ALLOW_ORIGIN, "*")
+            .header(CONTENT_TYPE, "text/event-stream")
+            .header(CACHE_CONTROL, "no-cache")
+            .body(body)
+            .unwrap())
+    }
+
     // Handles OPTIONS requests
     fn handle_graphql_options(&self, _request: Request<Body>) -> GraphQLServiceResponse {
         async {
             Ok(Response::builder()
                 .status(200)
                 .header(ACCESS_CONTROL_ALLOW_ORIGIN, "*")
-                .header(ACCESS_CONTROL_ALLOW_HEADERS, "Content-Type, User-Agent")
+                .header(
+                    ACCESS_CONTROL_ALLOW_HEADERS,
+                    "Content-Type, User-Agent, Authorization",
+                )
                 .header(ACCESS_CONTROL_ALLOW_METHODS, "GET, OPTIONS, POST")
                 .header(CONTENT_TYPE, "text/html")
                 .body(Body::from(""))
@@ -292,12 +518,16 @@ where
             (Method::GET, &["subgraphs", "id", _, "graphql"])
             | (Method::GET, &["subgraphs", "name", _, "graphql"])
             | (Method::GET, &["subgraphs", "name", _, _, "graphql"])
+            | (Method::GET, &["subgraphs", "pending", _, "graphql"])
+            | (Method::GET, &["subgraphs", "pending", _, _, "graphql"])
             | (Method::GET, &["subgraphs", "network", _, _, "graphql"])
             | (Method::GET, &["subgraphs", "graphql"]) => self.handle_graphiql(),
 
             (Method::GET, path @ ["subgraphs", "id", _])
             | (Method::GET, path @ ["subgraphs", "name", _])
             | (Method::GET, path @ ["subgraphs", "name", _, _])
+            | (Method::GET, path @ ["subgraphs", "pending", _])
+            | (Method::GET, path @ ["subgraphs", "pending", _, _])
             | (Method::GET, path @ ["subgraphs", "network", _, _])
             | (Method::GET, path @ ["subgraphs"]) => {
                 let dest = format!("/{}/graphql", path.join("/"));
@@ -322,9 +552,19 @@ where
                 self.handle_graphql_query_by_name(subgraph_name, req)
                     .boxed()
             }
+            (Method::POST, &["subgraphs", "pending", subgraph_name]) => self
+                .handle_graphql_query_pending(subgraph_name.to_owned(), req)
+                .boxed(),
+            (Method::POST, ["subgraphs", "pending", subgraph_name_part1, subgraph_name_part2]) => {
+                let subgraph_name = format!("{}/{}", subgraph_name_part1, subgraph_name_part2);
+                self.handle_graphql_query_pending(subgraph_name, req)
+                    .boxed()
+            }
 
             (Method::OPTIONS, ["subgraphs", "name", _])
             | (Method::OPTIONS, ["subgraphs", "name", _, _])
+            | (Method::OPTIONS, ["subgraphs", "pending", _])
+            | (Method::OPTIONS, ["subgraphs", "pending", _, _])
             | (Method::OPTIONS, ["subgraphs", "network", _, _]) => self.handle_graphql_options(req),
 
             _ => self.handle_not_found(),
@@ -401,8 +641,13 @@ mod tests {
 
     use crate::test_utils;
 
+    use super::forwarded_for;
     use super::GraphQLService;
     use super::GraphQLServiceMetrics;
+    use crate::gate::Gates;
+    use graph::components::server::query::{GateRejection, GatedRequest, RequestGate};
+    use http::header::{AUTHORIZATION, RETRY_AFTER};
+    use std::time::Duration;
 
     /// A simple stupid query runner for testing.
     pub struct TestGraphQlRunner;
@@ -425,22 +670,36 @@ mod tests {
             unimplemented!();
         }
 
-        async fn run_query(self: Arc<Self>, _query: Query, _target: QueryTarget) -> QueryResults {
+        async fn run_query(self: Arc<Self>, _query: Query, target: QueryTarget) -> QueryResults {
+            let name = match target {
+                QueryTarget::Pending(_) => "Pending",
+                QueryTarget::Name(_) | QueryTarget::Deployment(_) => "Jordi",
+            };
             QueryResults::from(BTreeMap::from_iter(
+                vec![(String::from("name"), r::Value::String(String::from(name)))].into_iter(),
+            ))
+        }
+
+        async fn run_subscription(
+            self: Arc<Self>,
+            _subscription: Subscription,
+            _target: QueryTarget,
+        ) -> Result<SubscriptionResult, SubscriptionError> {
+            let result = QueryResult::new(BTreeMap::from_iter(
                 vec![(
                     String::from("name"),
                     r::Value::String(String::from("Jordi")),
                 )]
                 .into_iter(),
-            ))
+            ));
+            Ok(Box::new(futures03::stream::iter(vec![Arc::new(result)])))
         }
 
-        async fn run_subscription(
+        async fn deployment_for_target(
             self: Arc<Self>,
-            _subscription: Subscription,
             _target: QueryTarget,
-        ) -> Result<SubscriptionResult, SubscriptionError> {
-            unreachable!();
+        ) -> Result<DeploymentHash, QueryExecutionError> {
+            Ok(USERS.clone())
         }
 
         fn load_manager(&self) -> Arc<LoadManager> {
@@ -515,4 +774,149 @@ mod tests {
             .expect("Query result field \"name\" is not a string");
         assert_eq!(name, "Jordi".to_string());
     }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn posting_to_pending_version_queries_pending_version() {
+        let logger = Logger::root(slog::Discard, o!());
+        let metrics_registry = Arc::new(MockMetricsRegistry::new());
+        let metrics = Arc::new(GraphQLServiceMetrics::new(metrics_registry));
+        let graphql_runner = Arc::new(TestGraphQlRunner);
+
+        let node_id = NodeId::new("test").unwrap();
+        let mut service = GraphQLService::new(logger, metrics, graphql_runner, 8001, node_id);
+
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri("http://localhost:8000/subgraphs/pending/test/users")
+            .body(Body::from("{\"query\": \"{ name }\"}"))
+            .unwrap();
+
+        let response = tokio::spawn(service.call(request))
+            .await
+            .unwrap()
+            .expect("Should return a response");
+        let data = test_utils::assert_successful_response(response);
+
+        let name = data
+            .get("name")
+            .expect("Query result data has no \"name\" field")
+            .as_str()
+            .expect("Query result field \"name\" is not a string");
+        assert_eq!(name, "Pending".to_string());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn posting_with_event_stream_accept_header_streams_results() {
+        let logger = Logger::root(slog::Discard, o!());
+        let metrics_registry = Arc::new(MockMetricsRegistry::new());
+        let metrics = Arc::new(GraphQLServiceMetrics::new(metrics_registry));
+        let subgraph_id = USERS.clone();
+        let graphql_runner = Arc::new(TestGraphQlRunner);
+
+        let node_id = NodeId::new("test").unwrap();
+        let mut service = GraphQLService::new(logger, metrics, graphql_runner, 8001, node_id);
+
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(format!(
+                "http://localhost:8000/subgraphs/id/{}",
+                subgraph_id
+            ))
+            .header(http::header::ACCEPT, "text/event-stream")
+            .body(Body::from("{\"query\": \"subscription { name }\"}"))
+            .unwrap();
+
+        let response = tokio::spawn(service.call(request))
+            .await
+            .unwrap()
+            .expect("Should return a response");
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers().get(http::header::CONTENT_TYPE).unwrap(),
+            "text/event-stream"
+        );
+
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(
+            String::from_utf8(body.to_vec()).unwrap(),
+            "event: next\ndata: {\"data\":{\"name\":\"Jordi\"}}\n\n\
+             event: complete\ndata:\n\n"
+        );
+    }
+
+    struct TokenGate;
+
+    impl RequestGate for TokenGate {
+        fn check(&self, request: &GatedRequest) -> Result<(), GateRejection> {
+            match request.token {
+                Some("secret") => Ok(()),
+                Some("greedy") => Err(GateRejection::RateLimited(Duration::from_millis(1500))),
+                _ => Err(GateRejection::Unauthorized("invalid API key".to_string())),
+            }
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn request_gates_reject_queries() {
+        let logger = Logger::root(slog::Discard, o!());
+        let metrics_registry = Arc::new(MockMetricsRegistry::new());
+        let metrics = Arc::new(GraphQLServiceMetrics::new(metrics_registry));
+        let graphql_runner = Arc::new(TestGraphQlRunner);
+
+        let node_id = NodeId::new("test").unwrap();
+        let service = GraphQLService::new(logger, metrics, graphql_runner, 8001, node_id)
+            .with_gates(Gates::new(vec![Arc::new(TokenGate)]), None);
+
+        let query = |token: Option<&str>| {
+            let mut builder = Request::builder()
+                .method(Method::POST)
+                .uri(format!("http://localhost:8000/subgraphs/id/{}", *USERS));
+            if let Some(token) = token {
+                builder = builder.header(AUTHORIZATION, format!("Bearer {}", token));
+            }
+            let request = builder
+                .body(Body::from("{\"query\": \"{ name }\"}"))
+                .unwrap();
+            tokio::spawn(service.clone().call(request))
+        };
+
+        let response = query(None).await.unwrap().unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = query(Some("greedy")).await.unwrap().unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers().get(RETRY_AFTER).unwrap(), "2");
+
+        let response = query(Some("secret")).await.unwrap().unwrap();
+        test_utils::assert_successful_response(response);
+    }
+
+    #[test]
+    fn forwarded_for_uses_last_proxy() {
+        let forwarded = |name: &str, value: &str| {
+            let request = Request::builder()
+                .header(name, value)
+                .body(Body::empty())
+                .unwrap();
+            forwarded_for(&request).map(|addr| addr.to_string())
+        };
+
+        assert_eq!(
+            Some("10.0.0.1".to_string()),
+            forwarded("X-Forwarded-For", "1.2.3.4, 10.0.0.1")
+        );
+        assert_eq!(
+            Some("2001:db8:cafe::17".to_string()),
+            forwarded(
+                "Forwarded",
+                "for=1.2.3.4;proto=http, for=\"[2001:db8:cafe::17]:4711\""
+            )
+        );
+        assert_eq!(
+            Some("192.0.2.60".to_string()),
+            forwarded("Forwarded", "proto=https;For=192.0.2.60:80")
+        );
+        assert_eq!(None, forwarded("Forwarded", "for=unknown"));
+        assert_eq!(None, forwarded("X-Forwarded-For", "not an address"));
+    }
 }
@@ -69,6 +69,13 @@ impl GraphQlRunner for TestGraphQlRunner {
         unreachable!();
     }
 
+    async fn deployment_for_target(
+        self: Arc<Self>,
+        _target: QueryTarget,
+    ) -> Result<DeploymentHash, QueryExecutionError> {
+        Ok(DeploymentHash::new("testsubgraph").unwrap())
+    }
+
     fn load_manager(&self) -> Arc<LoadManager> {
         unimplemented!()
     }
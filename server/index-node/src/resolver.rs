@@ -13,12 +13,19 @@ use graph_graphql::prelude::{ExecutionContext, Resolver};
 use std::convert::TryInto;
 use web3::types::{Address, H256};
 
+/// The number of entity changes `entityDiff` returns if `first` is not given
+const ENTITY_DIFF_DEFAULT_FIRST: i32 = 100;
+
+/// The largest number of entity changes `entityDiff` returns
+const ENTITY_DIFF_MAX_FIRST: i32 = 1000;
+
 /// Resolver for the index node GraphQL API.
 pub struct IndexNodeResolver<S, R, St> {
     logger: Logger,
     store: Arc<S>,
     link_resolver: Arc<R>,
     subgraph_store: Arc<St>,
+    simulator: Option<Arc<dyn TransactionSimulator>>,
 }
 
 impl<S, R, St> IndexNodeResolver<S, R, St>
@@ -32,6 +39,7 @@ where
         store: Arc<S>,
         link_resolver: Arc<R>,
         subgraph_store: Arc<St>,
+        simulator: Option<Arc<dyn TransactionSimulator>>,
     ) -> Self {
         let logger = logger.new(o!("component" => "IndexNodeResolver"));
         Self {
@@ -39,6 +47,7 @@ where
             store,
             link_resolver,
             subgraph_store,
+            simulator,
         }
     }
 
@@ -90,6 +99,32 @@ where
         Ok(infos.into_value())
     }
 
+    fn resolve_chains(&self) -> Result<r::Value, QueryExecutionError> {
+        let statuses = self.store.chain_statuses()?;
+        Ok(statuses.into_value())
+    }
+
+    fn resolve_deployment_sizes(
+        &self,
+        arguments: &HashMap<&str, r::Value>,
+    ) -> Result<r::Value, QueryExecutionError> {
+        // We can safely unwrap because the argument has been validated.
+        let first = arguments.get_optional::<i32>("first").unwrap();
+
+        let mut sizes = self.store.deployment_sizes()?;
+        if let Some(first) = first {
+            if first < 0 {
+                return Err(QueryExecutionError::RangeArgumentsError(
+                    "first",
+                    i32::MAX as u32,
+                    first as i64,
+                ));
+            }
+            sizes.truncate(first as usize);
+        }
+        Ok(sizes.into_value())
+    }
+
     fn resolve_proof_of_indexing(
         &self,
         argument_values: &HashMap<&str, r::Value>,
@@ -137,6 +172,236 @@ where
         Ok(poi)
     }
 
+    fn resolve_entity_diff(
+        &self,
+        arguments: &HashMap<&str, r::Value>,
+    ) -> Result<r::Value, QueryExecutionError> {
+        // We can safely unwrap because the arguments are non-nullable and
+        // have been validated.
+        let deployment_id = arguments
+            .get_required::<DeploymentHash>("subgraphId")
+            .unwrap();
+        let from = arguments.get_required::<BlockNumber>("fromBlock").unwrap();
+        let to = arguments.get_required::<BlockNumber>("toBlock").unwrap();
+        let first = arguments
+            .get_optional::<i32>("first")
+            .unwrap()
+            .unwrap_or(ENTITY_DIFF_DEFAULT_FIRST);
+
+        if first < 0 || first > ENTITY_DIFF_MAX_FIRST {
+            return Err(QueryExecutionError::RangeArgumentsError(
+                "first",
+                ENTITY_DIFF_MAX_FIRST as u32,
+                first as i64,
+            ));
+        }
+        if from < 0 || from >= to {
+            return Err(QueryExecutionError::StoreError(
+                anyhow!(
+                    "`fromBlock` must not be negative and must be less than `toBlock`, \
+                     but fromBlock is {} and toBlock is {}",
+                    from,
+                    to
+                )
+                .into(),
+            ));
+        }
+
+        let diffs = futures::executor::block_on(self.store.entity_diff(
+            &deployment_id,
+            from,
+            to,
+            None,
+            first as usize,
+        ))?;
+        Ok(diffs.into_value())
+    }
+
+    fn resolve_simulate_transaction(
+        &self,
+        arguments: &HashMap<&str, r::Value>,
+    ) -> Result<r::Value, QueryExecutionError> {
+        // We can safely unwrap because the arguments are non-nullable and
+        // have been validated.
+        let deployment_id = arguments
+            .get_required::<DeploymentHash>("subgraphId")
+            .unwrap();
+        let transaction = arguments.get_required::<H256>("transactionHash").unwrap();
+
+        let simulator = self.simulator.as_ref().ok_or_else(|| {
+            QueryExecutionError::NotSupported(
+                "simulating transactions is turned off; set \
+                 GRAPH_ALLOW_TRANSACTION_SIMULATION to turn it on"
+                    .to_owned(),
+            )
+        })?;
+        let diffs = graph::block_on(simulator.simulate_transaction(
+            &self.logger,
+            &deployment_id,
+            transaction.as_bytes(),
+        ))
+        .map_err(|e| QueryExecutionError::StoreError(e.into()))?;
+        Ok(diffs.into_value())
+    }
+
+    fn resolve_finalized_changes(
+        &self,
+        arguments: &HashMap<&str, r::Value>,
+    ) -> Result<r::Value, QueryExecutionError> {
+        // We can safely unwrap because the arguments have been validated.
+        let deployment_id = arguments
+            .get_required::<DeploymentHash>("subgraphId")
+            .unwrap();
+        let from = arguments.get_required::<BlockNumber>("fromBlock").unwrap();
+        let to = arguments.get_optional::<BlockNumber>("toBlock").unwrap();
+        let after = arguments.get_optional::<String>("after").unwrap();
+        let first = arguments
+            .get_optional::<i32>("first")
+            .unwrap()
+            .unwrap_or(ENTITY_DIFF_DEFAULT_FIRST);
+
+        let error = |msg: String| QueryExecutionError::StoreError(anyhow!(msg).into());
+
+        if first < 0 || first > ENTITY_DIFF_MAX_FIRST {
+            return Err(QueryExecutionError::RangeArgumentsError(
+                "first",
+                ENTITY_DIFF_MAX_FIRST as u32,
+                first as i64,
+            ));
+        }
+        if from < 0 {
+            return Err(error(format!(
+                "`fromBlock` must not be negative, but is {}",
+                from
+            )));
+        }
+        // The cursor is the entity type and id of the last change we
+        // returned; entity type names can not contain a `:`
+        let after = match after {
+            Some(cursor) => {
+                let mut parts = cursor.splitn(2, ':');
+                match (parts.next(), parts.next()) {
+                    (Some(entity_type), Some(id)) => {
+                        Some((entity_type.to_string(), id.to_string()))
+                    }
+                    _ => return Err(error(format!("invalid cursor `{}`", cursor))),
+                }
+            }
+            None => None,
+        };
+
+        let finalized = self.store.finalized_block(&deployment_id)?.unwrap_or(-1);
+        let to = match to {
+            Some(to) if to > finalized => {
+                return Err(error(format!(
+                    "block {} is not final yet; the latest final block of {} is {}",
+                    to, deployment_id, finalized
+                )))
+            }
+            Some(to) if to <= from => {
+                return Err(error(format!(
+                    "`toBlock` must be greater than `fromBlock`, \
+                     but fromBlock is {} and toBlock is {}",
+                    from, to
+                )))
+            }
+            Some(to) => to,
+            // Nothing new is final; the caller should try again later
+            None if finalized <= from => from,
+            None => finalized,
+        };
+
+        let (changes, cursor) = if to > from {
+            // Ask for one more change than we return so we know whether
+            // there are more
+            let mut changes = futures::executor::block_on(self.store.entity_diff(
+                &deployment_id,
+                from,
+                to,
+                after,
+                first as usize + 1,
+            ))?;
+            if changes.len() > first as usize {
+                changes.truncate(first as usize);
+                let cursor = changes
+                    .last()
+                    .map(|change| format!("{}:{}", change.entity_type, change.id));
+                (changes, cursor)
+            } else {
+                (changes, None)
+            }
+        } else {
+            (vec![], None)
+        };
+
+        Ok(object! {
+            __typename: "FinalizedChanges",
+            fromBlock: from,
+            toBlock: to,
+            changes: changes.into_value(),
+            cursor: cursor,
+        })
+    }
+
+    fn resolve_subgraph_artifacts(
+        &self,
+        arguments: &HashMap<&str, r::Value>,
+    ) -> Result<r::Value, QueryExecutionError> {
+        // We can safely unwrap because the argument is non-nullable and has been validated.
+        let subgraph_id = arguments.get_required::<String>("subgraphId").unwrap();
+        let deployment_hash = DeploymentHash::new(subgraph_id)
+            .map_err(QueryExecutionError::SubgraphDeploymentIdError)?;
+
+        if self
+            .subgraph_store
+            .locators(deployment_hash.as_str())?
+            .is_empty()
+        {
+            return Ok(r::Value::Null);
+        }
+
+        let manifest = self.subgraph_store.raw_manifest(&deployment_hash)?;
+        let schema = self
+            .subgraph_store
+            .input_schema(&deployment_hash)?
+            .document
+            .to_string();
+        let abis = match self.subgraph_store.abis(&deployment_hash)? {
+            Some(abis) => {
+                #[derive(Deserialize)]
+                struct StoredAbi {
+                    name: String,
+                    file: String,
+                    abi: serde_json::Value,
+                }
+
+                let abis: Vec<StoredAbi> = serde_json::from_str(&abis).map_err(|e| {
+                    QueryExecutionError::StoreError(
+                        anyhow!("the stored ABIs of {} are invalid: {}", deployment_hash, e).into(),
+                    )
+                })?;
+                abis.into_iter()
+                    .map(|abi| {
+                        object! {
+                            __typename: "Abi",
+                            name: abi.name,
+                            file: abi.file,
+                            abi: abi.abi.to_string(),
+                        }
+                    })
+                    .collect()
+            }
+            None => vec![],
+        };
+
+        Ok(object! {
+            __typename: "SubgraphArtifacts",
+            manifest: manifest,
+            schema: schema,
+            abis: r::Value::List(abis),
+        })
+    }
+
     fn resolve_indexing_status_for_version(
         &self,
         arguments: &HashMap<&str, r::Value>,
@@ -173,15 +438,6 @@ where
         // We can safely unwrap because the argument is non-nullable and has been validated.
         let subgraph_id = arguments.get_required::<String>("subgraphId").unwrap();
 
-        // TODO:
-        //
-        // An interesting optimization would involve trying to get the subgraph manifest from the
-        // SubgraphStore before hitting IPFS, but we must fix a dependency cycle between the `graph`
-        // and `server` crates first.
-        //
-        // 1. implement a new method in subgraph store to retrieve the SubgraphManifest of a given deployment id
-        // 2. try to fetch this subgraph from our SubgraphStore before hitting IPFS
-
         // Try to build a deployment hash with the input string
         let deployment_hash = DeploymentHash::new(subgraph_id).map_err(|invalid_qm_hash| {
             QueryExecutionError::SubgraphDeploymentIdError(invalid_qm_hash)
@@ -193,11 +449,21 @@ where
             network,
         } = {
             let raw: serde_yaml::Mapping = {
-                let file_bytes = self
-                    .link_resolver
-                    .cat(&self.logger, &deployment_hash.to_ipfs_link())
-                    .await
-                    .map_err(SubgraphManifestResolveError::ResolveError)?;
+                // Use the manifest we stored when the subgraph was deployed,
+                // and only go to IPFS for subgraphs that we do not have
+                let stored = self
+                    .subgraph_store
+                    .raw_manifest(&deployment_hash)
+                    .ok()
+                    .flatten();
+                let file_bytes = match stored {
+                    Some(yaml) => yaml.into_bytes(),
+                    None => self
+                        .link_resolver
+                        .cat(&self.logger, &deployment_hash.to_ipfs_link())
+                        .await
+                        .map_err(SubgraphManifestResolveError::ResolveError)?,
+                };
 
                 serde_yaml::from_slice(&file_bytes)
                     .map_err(SubgraphManifestResolveError::ParseError)?
@@ -424,6 +690,20 @@ where
                 self.resolve_indexing_statuses_for_subgraph_name(arguments)
             }
 
+            // The top-level `chains` field
+            (None, "ChainStatus", "chains") => self.resolve_chains(),
+
+            // The top-level `deploymentSizes` field
+            (None, "DeploymentSize", "deploymentSizes") => self.resolve_deployment_sizes(arguments),
+
+            // The top-level `entityDiff` field
+            (None, "EntityDiff", "entityDiff") => self.resolve_entity_diff(arguments),
+
+            // The top-level `simulateTransaction` field
+            (None, "EntityDiff", "simulateTransaction") => {
+                self.resolve_simulate_transaction(arguments)
+            }
+
             // Resolve fields of `Object` values (e.g. the `chains` field of `ChainIndexingStatus`)
             (value, _, _) => Ok(value.unwrap_or(r::Value::Null)),
         }
@@ -448,6 +728,12 @@ where
                 self.resolve_indexing_status_for_version(arguments, false)
             }
 
+            // The top-level `finalizedChanges` field
+            (None, "finalizedChanges") => self.resolve_finalized_changes(arguments),
+
+            // The top-level `subgraphArtifacts` field
+            (None, "subgraphArtifacts") => self.resolve_subgraph_artifacts(arguments),
+
             // The top-level `indexingStatusForPendingVersion` field
             (None, "subgraphFeatures") => {
                 graph::block_on(self.resolve_subgraph_features(arguments))
@@ -31,6 +31,7 @@ pub struct IndexNodeServer<Q, S, R, St> {
     store: Arc<S>,
     link_resolver: Arc<R>,
     subgraph_store: Arc<St>,
+    simulator: Option<Arc<dyn TransactionSimulator>>,
 }
 
 impl<Q, S, R, St> IndexNodeServer<Q, S, R, St> {
@@ -41,6 +42,7 @@ impl<Q, S, R, St> IndexNodeServer<Q, S, R, St> {
         store: Arc<S>,
         link_resolver: Arc<R>,
         subgraph_store: Arc<St>,
+        simulator: Option<Arc<dyn TransactionSimulator>>,
     ) -> Self {
         let logger = logger_factory.component_logger(
             "IndexNodeServer",
@@ -57,6 +59,7 @@ impl<Q, S, R, St> IndexNodeServer<Q, S, R, St> {
             store,
             link_resolver,
             subgraph_store,
+            simulator,
         }
     }
 }
@@ -94,6 +97,7 @@ where
             store.clone(),
             self.link_resolver.clone(),
             self.subgraph_store.clone(),
+            self.simulator.clone(),
         );
         let new_service =
             make_service_fn(move |_| futures03::future::ok::<_, Error>(service.clone()));
@@ -20,7 +20,6 @@ use crate::schema::SCHEMA;
 pub type IndexNodeServiceResponse = DynTryFuture<'static, Response<Body>, GraphQLServerError>;
 
 /// A Hyper Service that serves GraphQL over a POST / endpoint.
-#[derive(Debug)]
 pub struct IndexNodeService<Q, S, R, St> {
     logger: Logger,
     graphql_runner: Arc<Q>,
@@ -28,6 +27,7 @@ pub struct IndexNodeService<Q, S, R, St> {
     explorer: Arc<Explorer<S>>,
     link_resolver: Arc<R>,
     subgraph_store: Arc<St>,
+    simulator: Option<Arc<dyn TransactionSimulator>>,
 }
 
 impl<Q, S, R, St> Clone for IndexNodeService<Q, S, R, St> {
@@ -39,6 +39,7 @@ impl<Q, S, R, St> Clone for IndexNodeService<Q, S, R, St> {
             explorer: self.explorer.clone(),
             link_resolver: self.link_resolver.clone(),
             subgraph_store: self.subgraph_store.clone(),
+            simulator: self.simulator.clone(),
         }
     }
 }
@@ -59,6 +60,7 @@ where
         store: Arc<S>,
         link_resolver: Arc<R>,
         subgraph_store: Arc<St>,
+        simulator: Option<Arc<dyn TransactionSimulator>>,
     ) -> Self {
         let explorer = Arc::new(Explorer::new(store.clone()));
 
@@ -69,6 +71,7 @@ where
             explorer,
             link_resolver,
             subgraph_store,
+            simulator,
         }
     }
 
@@ -130,6 +133,7 @@ where
                     store,
                     self.link_resolver.clone(),
                     self.subgraph_store.clone(),
+                    self.simulator.clone(),
                 ),
                 deadline: None,
                 max_first: std::u32::MAX,
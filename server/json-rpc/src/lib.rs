@@ -14,6 +14,7 @@ use jsonrpc_http_server::{
 use lazy_static::lazy_static;
 
 use std::collections::BTreeMap;
+use std::convert::TryFrom;
 use std::env;
 use std::io;
 use std::net::{Ipv4Addr, SocketAddrV4};
@@ -29,6 +30,8 @@ const JSON_RPC_DEPLOY_ERROR: i64 = 0;
 const JSON_RPC_REMOVE_ERROR: i64 = 1;
 const JSON_RPC_CREATE_ERROR: i64 = 2;
 const JSON_RPC_REASSIGN_ERROR: i64 = 3;
+const JSON_RPC_REWIND_ERROR: i64 = 4;
+const JSON_RPC_FLUSH_ENTITY_CACHE_ERROR: i64 = 5;
 
 #[derive(Debug, Deserialize)]
 struct SubgraphCreateParams {
@@ -53,8 +56,21 @@ struct SubgraphReassignParams {
     node_id: NodeId,
 }
 
+#[derive(Debug, Deserialize)]
+struct SubgraphRewindParams {
+    ipfs_hash: DeploymentHash,
+    block_hash: String,
+    block_number: BlockNumber,
+}
+
+#[derive(Debug, Deserialize)]
+struct SubgraphFlushEntityCacheParams {
+    ipfs_hash: DeploymentHash,
+}
+
 pub struct JsonRpcServer<R> {
     registrar: Arc<R>,
+    entity_caches: Arc<EntityCacheControl>,
     http_port: u16,
     ws_port: u16,
     node_id: NodeId,
@@ -151,6 +167,61 @@ impl<R: SubgraphRegistrar> JsonRpcServer<R> {
             )),
         }
     }
+
+    /// Handler for the `subgraph_rewind` endpoint.
+    async fn rewind_handler(
+        &self,
+        params: SubgraphRewindParams,
+    ) -> Result<Value, jsonrpc_core::Error> {
+        let logger = self.logger.clone();
+
+        info!(logger, "Received subgraph_rewind request"; "params" => format!("{:?}", params));
+
+        let block_ptr_to =
+            BlockPtr::try_from((params.block_hash.as_str(), params.block_number as i64)).map_err(
+                |e| jsonrpc_core::Error::invalid_params(format!("invalid block hash: {}", e)),
+            )?;
+
+        match self
+            .registrar
+            .rewind_subgraph(&params.ipfs_hash, block_ptr_to)
+            .await
+        {
+            Ok(_) => Ok(Value::Null),
+            Err(e) => Err(json_rpc_error(
+                &logger,
+                "subgraph_rewind",
+                e,
+                JSON_RPC_REWIND_ERROR,
+                params,
+            )),
+        }
+    }
+
+    /// Handler for the `subgraph_flush_entity_cache` endpoint.
+    async fn flush_entity_cache_handler(
+        &self,
+        params: SubgraphFlushEntityCacheParams,
+    ) -> Result<Value, jsonrpc_core::Error> {
+        info!(&self.logger, "Received subgraph_flush_entity_cache request"; "params" => format!("{:?}", params));
+
+        // The subgraph clears its cache before it processes the next block
+        if self.entity_caches.request_flush(params.ipfs_hash.clone()) {
+            Ok(Value::Null)
+        } else {
+            let e = SubgraphRegistrarError::DeploymentNotFound(format!(
+                "{} is not being indexed by this node",
+                params.ipfs_hash
+            ));
+            Err(json_rpc_error(
+                &self.logger,
+                "subgraph_flush_entity_cache",
+                e,
+                JSON_RPC_FLUSH_ENTITY_CACHE_ERROR,
+                params,
+            ))
+        }
+    }
 }
 
 impl<R> JsonRpcServerTrait<R> for JsonRpcServer<R>
@@ -164,6 +235,7 @@ where
         http_port: u16,
         ws_port: u16,
         registrar: Arc<R>,
+        entity_caches: Arc<EntityCacheControl>,
         node_id: NodeId,
         logger: Logger,
     ) -> Result<Self::Server, io::Error> {
@@ -180,6 +252,7 @@ where
 
         let arc_self = Arc::new(JsonRpcServer {
             registrar,
+            entity_caches,
             http_port,
             ws_port,
             node_id,
@@ -276,6 +349,36 @@ where
             .compat()
         });
 
+        let me = arc_self.clone();
+        let sender = task_sender.clone();
+        handler.add_method("subgraph_rewind", move |params: Params| {
+            let me = me.clone();
+            Box::pin(tokio02_spawn(
+                sender.clone(),
+                async move {
+                    let params = params.parse()?;
+                    me.rewind_handler(params).await
+                }
+                .boxed(),
+            ))
+            .compat()
+        });
+
+        let me = arc_self.clone();
+        let sender = task_sender.clone();
+        handler.add_method("subgraph_flush_entity_cache", move |params: Params| {
+            let me = me.clone();
+            Box::pin(tokio02_spawn(
+                sender.clone(),
+                async move {
+                    let params = params.parse()?;
+                    me.flush_entity_cache_handler(params).await
+                }
+                .boxed(),
+            ))
+            .compat()
+        });
+
         ServerBuilder::new(handler)
             // Enable REST API:
             // POST /<method>/<param1>/<param2>
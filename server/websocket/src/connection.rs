@@ -1,18 +1,22 @@
 use futures::future::IntoFuture;
 use futures::sync::mpsc;
-use futures03::stream::SplitStream;
+use futures03::stream::{self as stream03, BoxStream, SplitStream};
 use graphql_parser::parse_query;
 use http::StatusCode;
 use lazy_static::lazy_static;
 use std::collections::HashMap;
 use std::env;
+use std::fmt;
 use std::str::FromStr;
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio_tungstenite::tungstenite::{Error as WsError, Message as WsMessage};
 use tokio_tungstenite::WebSocketStream;
 use uuid::Uuid;
 
-use graph::{data::query::QueryTarget, prelude::*};
+use graph::{
+    data::query::{ErrorKind, QueryTarget},
+    prelude::*,
+};
 
 lazy_static! {
     static ref MAX_OPERATIONS_PER_CONNECTION: Option<usize> =
@@ -21,6 +25,29 @@ lazy_static! {
             .map(|s| usize::from_str(&s).unwrap_or_else(|_| panic!(
                 "failed to parse env var GRAPH_GRAPHQL_MAX_OPERATIONS_PER_CONNECTION"
             )));
+
+    /// How often to send keep-alive messages to clients. Turned off if set
+    /// to 0
+    static ref KEEP_ALIVE_INTERVAL: Option<Duration> =
+        interval_from_env("GRAPH_GRAPHQL_WS_KEEP_ALIVE_INTERVAL", 30);
+
+    /// How often to check whether the deployment of a connection has been
+    /// removed, reassigned or has failed. Turned off if set to 0
+    static ref DEPLOYMENT_CHECK_INTERVAL: Option<Duration> =
+        interval_from_env("GRAPH_GRAPHQL_WS_DEPLOYMENT_CHECK_INTERVAL", 60);
+}
+
+/// Read an interval in seconds from the environment variable `name`; an
+/// interval of 0 means `None`
+fn interval_from_env(name: &str, default: u64) -> Option<Duration> {
+    let secs = env::var(name)
+        .ok()
+        .map(|s| u64::from_str(&s).unwrap_or_else(|_| panic!("failed to parse env var {}", name)))
+        .unwrap_or(default);
+    match secs {
+        0 => None,
+        secs => Some(Duration::from_secs(secs)),
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -57,6 +84,8 @@ impl IncomingMessage {
 #[serde(tag = "type", rename_all = "snake_case")]
 enum OutgoingMessage {
     ConnectionAck,
+    #[serde(rename = "ka")]
+    ConnectionKeepAlive,
     Error {
         id: String,
         payload: String,
@@ -160,6 +189,17 @@ impl Operations {
             ),
         }
     }
+
+    /// Stop all operations, telling the client why with a GQL_ERROR
+    /// before the GQL_COMPLETE for each of them
+    fn terminate(&mut self, reason: &str) -> Result<(), WsError> {
+        let ids = Vec::from_iter(self.operations.keys().cloned());
+        for id in ids {
+            send_error_string(&self.msg_sink, id.clone(), reason.to_string())?;
+            self.stop(id)?;
+        }
+        Ok(())
+    }
 }
 
 impl Drop for Operations {
@@ -172,13 +212,106 @@ impl Drop for Operations {
     }
 }
 
+/// Why the operations of a connection can not get any more results
+#[derive(Debug)]
+enum Gone {
+    /// The deployment was removed, or the subgraph name that the connection
+    /// was made for does not resolve anymore
+    Removed(DeploymentHash, String),
+    /// The subgraph name now resolves to a different deployment
+    Reassigned(DeploymentHash, DeploymentHash),
+    /// The deployment failed with a deterministic error and will not index
+    /// any more blocks
+    Failed(DeploymentHash),
+}
+
+impl fmt::Display for Gone {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Gone::Removed(id, reason) => write!(
+                f,
+                "Deployment `{}` is not available anymore: {}; reconnect to subscribe again",
+                id, reason
+            ),
+            Gone::Reassigned(id, new_id) => write!(
+                f,
+                "The subgraph now uses deployment `{}` instead of `{}`; \
+                 reconnect to subscribe to the new deployment",
+                new_id, id
+            ),
+            Gone::Failed(id) => write!(
+                f,
+                "Deployment `{}` has failed and will not produce any more updates",
+                id
+            ),
+        }
+    }
+}
+
+/// Check whether `target` still resolves to `deployment` and whether the
+/// deployment is still indexing
+async fn check_deployment(
+    store: &dyn QueryStoreManager,
+    target: &QueryTarget,
+    deployment: &DeploymentHash,
+) -> Option<Gone> {
+    let state = match store.query_store(target.clone(), true).await {
+        Ok(store) => store.deployment_state().await,
+        Err(e) => Err(e),
+    };
+    gone(deployment, state)
+}
+
+/// Whether `state`, the state of what the connection's target resolves to
+/// now, means that `deployment` is gone. Transient errors, for example when
+/// the database is briefly unavailable, do not count as the deployment
+/// being gone, and neither do non-deterministic failures since the
+/// deployment will be retried
+fn gone(
+    deployment: &DeploymentHash,
+    state: Result<DeploymentState, QueryExecutionError>,
+) -> Option<Gone> {
+    match state {
+        Ok(state) if &state.id != deployment => {
+            Some(Gone::Reassigned(deployment.clone(), state.id))
+        }
+        Ok(state) if state.failed_deterministically => Some(Gone::Failed(deployment.clone())),
+        Ok(_) => None,
+        Err(e) if e.kind() == ErrorKind::Transient => None,
+        Err(e) => Some(Gone::Removed(deployment.clone(), e.to_string())),
+    }
+}
+
+/// What a connection reacts to
+enum Event {
+    Message(Result<WsMessage, WsError>),
+    Closed,
+    KeepAlive,
+    CheckDeployment,
+}
+
+/// A stream that produces `event` every `interval`, or nothing if there is
+/// no interval
+fn ticks(interval: Option<Duration>, event: fn() -> Event) -> BoxStream<'static, Event> {
+    match interval {
+        Some(interval) => stream03::unfold((), move |()| async move {
+            tokio::time::sleep(interval).await;
+            Some((event(), ()))
+        })
+        .boxed(),
+        None => stream03::pending().boxed(),
+    }
+}
+
 /// A WebSocket connection implementing the GraphQL over WebSocket protocol.
 pub struct GraphQlConnection<Q, S> {
     id: String,
     logger: Logger,
     graphql_runner: Arc<Q>,
     stream: WebSocketStream<S>,
+    target: QueryTarget,
     deployment: DeploymentHash,
+    store: Arc<dyn QueryStoreManager>,
 }
 
 impl<Q, S> GraphQlConnection<Q, S>
@@ -187,36 +320,86 @@ where
     S: AsyncRead + AsyncWrite + Send + 'static + Unpin,
 {
     /// Creates a new GraphQL subscription service.
+    /// `target` is what the client connected to and `deployment` what it
+    /// resolved to when the connection was made; `store` is used to check
+    /// that `target` keeps resolving to `deployment`
     pub(crate) fn new(
         logger: &Logger,
+        target: QueryTarget,
         deployment: DeploymentHash,
         stream: WebSocketStream<S>,
         graphql_runner: Arc<Q>,
+        store: Arc<dyn QueryStoreManager>,
     ) -> Self {
         GraphQlConnection {
             id: Uuid::new_v4().to_string(),
             logger: logger.new(o!("component" => "GraphQlConnection")),
             graphql_runner,
             stream,
+            target,
             deployment,
+            store,
         }
     }
 
     async fn handle_incoming_messages(
-        mut ws_stream: SplitStream<WebSocketStream<S>>,
+        ws_stream: SplitStream<WebSocketStream<S>>,
         mut msg_sink: mpsc::UnboundedSender<WsMessage>,
         logger: Logger,
         connection_id: String,
+        target: QueryTarget,
         deployment: DeploymentHash,
         graphql_runner: Arc<Q>,
+        store: Arc<dyn QueryStoreManager>,
     ) -> Result<(), WsError> {
         let mut operations = Operations::new(msg_sink.clone());
 
+        // Whether the client initialized the connection; keep-alive
+        // messages are only sent after that
+        let mut initialized = false;
+
+        // Set when the deployment can not be subscribed to anymore
+        let mut gone: Option<Gone> = None;
+
+        let messages = ws_stream
+            .map(Event::Message)
+            .chain(stream03::once(futures03::future::ready(Event::Closed)));
+        let mut events = stream03::select(
+            messages,
+            stream03::select(
+                ticks(*KEEP_ALIVE_INTERVAL, || Event::KeepAlive),
+                ticks(*DEPLOYMENT_CHECK_INTERVAL, || Event::CheckDeployment),
+            ),
+        );
+
         // Process incoming messages as long as the WebSocket is open
-        while let Some(ws_msg) = ws_stream.try_next().await? {
+        while let Some(event) = events.next().await {
             use self::IncomingMessage::*;
             use self::OutgoingMessage::*;
 
+            let ws_msg = match event {
+                Event::Message(ws_msg) => ws_msg?,
+                Event::Closed => break,
+                Event::KeepAlive => {
+                    if initialized {
+                        send_message(&msg_sink, ConnectionKeepAlive)?;
+                    }
+                    continue;
+                }
+                Event::CheckDeployment => {
+                    gone = check_deployment(store.as_ref(), &target, &deployment).await;
+                    if let Some(gone) = &gone {
+                        if !operations.operations.is_empty() {
+                            info!(logger, "Terminating operations";
+                                  "connection" => &connection_id,
+                                  "reason" => gone.to_string());
+                            operations.terminate(&gone.to_string())?;
+                        }
+                    }
+                    continue;
+                }
+            };
+
             debug!(logger, "Received message";
                    "connection" => &connection_id,
                    "msg" => format!("{}", ws_msg).as_str());
@@ -229,7 +412,10 @@ where
 
             match msg {
                 // Always accept connection init requests
-                ConnectionInit { payload: _ } => send_message(&msg_sink, ConnectionAck),
+                ConnectionInit { payload: _ } => {
+                    initialized = true;
+                    send_message(&msg_sink, ConnectionAck)
+                }
 
                 // When receiving a connection termination request
                 ConnectionTerminate => {
@@ -243,6 +429,14 @@ where
                 // When receiving a stop request
                 Stop { id } => operations.stop(id),
 
+                // Refuse to start operations once the deployment is gone,
+                // and tell the client why
+                Start { id, payload: _ } if gone.is_some() => send_error_string(
+                    &msg_sink,
+                    id,
+                    gone.as_ref().map(Gone::to_string).unwrap_or_default(),
+                ),
+
                 // When receiving a start request
                 Start { id, payload } => {
                     // Respond with a GQL_ERROR if we already have an operation with this ID
@@ -415,8 +609,10 @@ where
             msg_sink,
             self.logger.clone(),
             self.id.clone(),
+            self.target.clone(),
             self.deployment.clone(),
             self.graphql_runner.clone(),
+            self.store.clone(),
         );
 
         // Send outgoing messages asynchronously
@@ -439,3 +635,93 @@ where
         }))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use futures::sync::mpsc;
+    use graph::prelude::{
+        futures03::StreamExt, tokio, CancelGuard, DeploymentHash, DeploymentState,
+        QueryExecutionError, Stream as _,
+    };
+    use std::time::Duration;
+
+    use super::{gone, ticks, Event, Gone, Operations};
+
+    fn state(id: &str, failed_deterministically: bool) -> DeploymentState {
+        DeploymentState {
+            id: DeploymentHash::new(id).unwrap(),
+            reorg_count: 0,
+            max_reorg_depth: 0,
+            latest_ethereum_block_number: 1,
+            queries_disabled: false,
+            pruned_block: None,
+            failed_deterministically,
+        }
+    }
+
+    #[test]
+    fn deployment_check() {
+        let id = DeploymentHash::new("QmDeployment").unwrap();
+
+        assert!(gone(&id, Ok(state("QmDeployment", false))).is_none());
+        assert!(matches!(
+            gone(&id, Ok(state("QmOther", false))),
+            Some(Gone::Reassigned(_, _))
+        ));
+        assert!(matches!(
+            gone(&id, Ok(state("QmDeployment", true))),
+            Some(Gone::Failed(_))
+        ));
+        assert!(matches!(
+            gone(
+                &id,
+                Err(QueryExecutionError::SubgraphDeploymentIdError(
+                    "QmDeployment".to_string()
+                ))
+            ),
+            Some(Gone::Removed(_, _))
+        ));
+        // Transient errors do not end the connection
+        let transient = QueryExecutionError::BlockNotYetIndexed(id.clone(), "0x00".to_string(), 1);
+        assert!(gone(&id, Err(transient)).is_none());
+        assert!(gone(&id, Err(QueryExecutionError::Timeout)).is_none());
+    }
+
+    #[test]
+    fn terminate_sends_error_then_complete() {
+        let (sink, messages) = mpsc::unbounded();
+        let mut operations = Operations::new(sink);
+        operations.insert("1".to_string(), CancelGuard::new());
+
+        operations.terminate("deployment is gone").unwrap();
+        assert!(!operations.contains("1"));
+        drop(operations);
+
+        let messages: Vec<String> = messages
+            .wait()
+            .map(|msg| msg.unwrap().into_text().unwrap())
+            .collect();
+        assert_eq!(
+            vec![
+                r#"{"type":"error","id":"1","payload":"deployment is gone"}"#.to_string(),
+                r#"{"type":"complete","id":"1"}"#.to_string(),
+            ],
+            messages
+        );
+    }
+
+    #[test]
+    fn keep_alive_ticks() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let mut keep_alive = ticks(Some(Duration::from_millis(1)), || Event::KeepAlive);
+            assert!(matches!(keep_alive.next().await, Some(Event::KeepAlive)));
+            assert!(matches!(keep_alive.next().await, Some(Event::KeepAlive)));
+
+            // Without an interval, there are no ticks
+            let mut never = ticks(None, || Event::KeepAlive);
+            let next = tokio::time::timeout(Duration::from_millis(10), never.next()).await;
+            assert!(next.is_err());
+        });
+    }
+}
@@ -35,7 +35,7 @@ where
     async fn subgraph_id_from_url_path(
         store: Arc<S>,
         path: &str,
-    ) -> Result<Option<DeploymentState>, Error> {
+    ) -> Result<Option<(QueryTarget, DeploymentState)>, Error> {
         fn target_from_name(name: String) -> Option<QueryTarget> {
             SubgraphName::new(name).ok().map(QueryTarget::Name)
         }
@@ -47,13 +47,17 @@ where
         async fn state<S: QueryStoreManager>(
             store: Arc<S>,
             target: Option<QueryTarget>,
-        ) -> Option<DeploymentState> {
+        ) -> Option<(QueryTarget, DeploymentState)> {
             let target = match target {
                 Some(target) => target,
                 None => return None,
             };
-            match store.query_store(target, false).await.ok() {
-                Some(query_store) => query_store.deployment_state().await.ok(),
+            match store.query_store(target.clone(), false).await.ok() {
+                Some(query_store) => query_store
+                    .deployment_state()
+                    .await
+                    .ok()
+                    .map(|state| (target, state)),
                 None => None,
             }
         }
@@ -80,6 +84,12 @@ where
             &["subgraphs", "network", _, _] => {
                 Ok(state(store, target_from_name(path_segments[1..].join("/"))).await)
             }
+            &["subgraphs", "pending", _] | &["subgraphs", "pending", _, _] => {
+                let target = SubgraphName::new(path_segments[2..].join("/"))
+                    .ok()
+                    .map(QueryTarget::Pending);
+                Ok(state(store, target).await)
+            }
             _ => Ok(None),
         }
     }
@@ -114,8 +124,9 @@ where
             let logger2 = self.logger.clone();
             let graphql_runner = self.graphql_runner.clone();
             let store = self.store.clone();
+            let store2: Arc<dyn QueryStoreManager> = self.store.clone();
 
-            // Subgraph that the request is resolved to (if any)
+            // What the request asked for and the deployment it resolved to (if any)
             let subgraph_id = Arc::new(Mutex::new(None));
             let accept_subgraph_id = subgraph_id.clone();
 
@@ -147,8 +158,8 @@ where
                         .body(None)
                         .unwrap()
                 })
-                .and_then(|state| {
-                    state.ok_or_else(|| {
+                .and_then(|target_and_state| {
+                    target_and_state.ok_or_else(|| {
                         Response::builder()
                             .status(StatusCode::NOT_FOUND)
                             .header(ACCESS_CONTROL_ALLOW_ORIGIN, "*")
@@ -157,6 +168,7 @@ where
                             .unwrap()
                     })
                 })?;
+                let (target, state) = state;
 
                 // Check if the subgraph is deployed
                 if !state.is_deployed() {
@@ -171,7 +183,7 @@ where
                             .unwrap());
                     }
 
-                *accept_subgraph_id.lock().unwrap() = Some(state.id);
+                *accept_subgraph_id.lock().unwrap() = Some((target, state.id));
                 response.headers_mut().insert(
                     "Sec-WebSocket-Protocol",
                     HeaderValue::from_static("graphql-ws"),
@@ -182,14 +194,16 @@ where
                 match result {
                     Ok(ws_stream) => {
                         // Obtain the subgraph ID or name that we resolved the request to
-                        let subgraph_id = subgraph_id.lock().unwrap().clone().unwrap();
+                        let (target, subgraph_id) = subgraph_id.lock().unwrap().clone().unwrap();
 
                         // Spawn a GraphQL over WebSocket connection
                         let service = GraphQlConnection::new(
                             &logger2,
+                            target,
                             subgraph_id,
                             ws_stream,
                             graphql_runner.clone(),
+                            store2,
                         );
 
                         graph::spawn_allow_panic(service.into_future().compat());
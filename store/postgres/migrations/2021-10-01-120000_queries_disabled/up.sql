@@ -0,0 +1,2 @@
+alter table subgraphs.subgraph_deployment
+    add column queries_disabled boolean not null default false;
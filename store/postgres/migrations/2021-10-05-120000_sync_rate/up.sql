@@ -0,0 +1,2 @@
+alter table subgraphs.subgraph_deployment
+    add column blocks_per_second float8;
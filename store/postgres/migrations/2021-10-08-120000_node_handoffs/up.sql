@@ -0,0 +1,9 @@
+-- This is populated in the primary. A graph-node process that wants to
+-- take over the deployments of a running process with the same node id
+-- inserts a row here; the running process stops indexing and sets
+-- 'released_at' once it is done
+create table node_handoffs(
+   node_id      text primary key,
+   requested_at timestamptz not null,
+   released_at  timestamptz
+);
@@ -0,0 +1,4 @@
+alter table ethereum_networks
+    drop column provider_head_number,
+    drop column provider_head_at,
+    drop column provider_error;
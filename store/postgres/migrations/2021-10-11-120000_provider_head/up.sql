@@ -0,0 +1,4 @@
+alter table ethereum_networks
+    add column provider_head_number int8,
+    add column provider_head_at timestamptz,
+    add column provider_error text;
@@ -0,0 +1,2 @@
+alter table subgraphs.subgraph_deployment
+    add column pruned_block int4;
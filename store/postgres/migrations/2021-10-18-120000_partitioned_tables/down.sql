@@ -0,0 +1,2 @@
+alter table subgraphs.table_stats
+    drop column partition_blocks;
@@ -0,0 +1,2 @@
+alter table subgraphs.table_stats
+    add column partition_blocks int4;
@@ -0,0 +1,8 @@
+-- This is populated in the primary. A copy of a deployment that is listed
+-- here becomes the active deployment for its IPFS hash as soon as copying
+-- has finished and the copy has caught up with the deployment that is
+-- active now
+create table copy_activations(
+   dst          int primary key references deployment_schemas(id) on delete cascade,
+   requested_at timestamptz not null
+);
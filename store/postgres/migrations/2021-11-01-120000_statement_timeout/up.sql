@@ -0,0 +1,2 @@
+alter table subgraphs.subgraph_deployment
+    add column statement_timeout int;
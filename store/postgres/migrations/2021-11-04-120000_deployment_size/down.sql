@@ -0,0 +1,2 @@
+alter table subgraphs.subgraph_deployment
+    drop column size_bytes;
@@ -0,0 +1,4 @@
+-- Tables that were created with the layout for immutable entity types;
+-- tables of deployments created before that layout existed are mutable
+alter table subgraphs.table_stats
+    add column is_immutable bool;
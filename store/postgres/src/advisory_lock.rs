@@ -11,6 +11,10 @@
 //! We use the following 2x 32-bit locks
 //!   * 1, n: to lock copying of the deployment with id n in the destination
 //!           shard
+//!   * 2, n: to lock pruning of the deployment with id n
+//!   * 3, n: held by the graph-node process that indexes the deployment
+//!           with id n so that no other process can index it at the same
+//!           time
 
 use diesel::{sql_query, PgConnection, RunQueryDsl};
 use graph::prelude::StoreError;
@@ -44,3 +48,125 @@ pub(crate) fn unlock_copying(conn: &PgConnection, dst: &Site) -> Result<(), Stor
         .map(|_| ())
         .map_err(StoreError::from)
 }
+
+/// Try to get the lock for pruning the deployment `site`. Returns `false`
+/// if somebody else is pruning it already
+pub(crate) fn try_lock_pruning(conn: &PgConnection, site: &Site) -> Result<bool, StoreError> {
+    #[derive(QueryableByName)]
+    struct Locked {
+        #[sql_type = "diesel::sql_types::Bool"]
+        locked: bool,
+    }
+
+    sql_query(&format!(
+        "select pg_try_advisory_lock(2, {}) as locked",
+        site.id
+    ))
+    .get_result::<Locked>(conn)
+    .map(|res| res.locked)
+    .map_err(StoreError::from)
+}
+
+pub(crate) fn unlock_pruning(conn: &PgConnection, site: &Site) -> Result<(), StoreError> {
+    sql_query(&format!("select pg_advisory_unlock(2, {})", site.id))
+        .execute(conn)
+        .map(|_| ())
+        .map_err(StoreError::from)
+}
+
+/// Try to get the lock for indexing the deployment `site`. Returns `false`
+/// if another process is indexing it already. The lock is a session lock
+/// and is held until `unlock_indexing` is called or `conn` is closed
+pub(crate) fn try_lock_indexing(conn: &PgConnection, site: &Site) -> Result<bool, StoreError> {
+    #[derive(QueryableByName)]
+    struct Locked {
+        #[sql_type = "diesel::sql_types::Bool"]
+        locked: bool,
+    }
+
+    sql_query(&format!(
+        "select pg_try_advisory_lock(3, {}) as locked",
+        site.id
+    ))
+    .get_result::<Locked>(conn)
+    .map(|res| res.locked)
+    .map_err(StoreError::from)
+}
+
+/// Like `try_lock_indexing`, but the lock is a transaction lock that is
+/// released automatically when the current transaction on `conn` ends
+pub(crate) fn try_lock_indexing_xact(conn: &PgConnection, site: &Site) -> Result<bool, StoreError> {
+    #[derive(QueryableByName)]
+    struct Locked {
+        #[sql_type = "diesel::sql_types::Bool"]
+        locked: bool,
+    }
+
+    sql_query(&format!(
+        "select pg_try_advisory_xact_lock(3, {}) as locked",
+        site.id
+    ))
+    .get_result::<Locked>(conn)
+    .map(|res| res.locked)
+    .map_err(StoreError::from)
+}
+
+/// Release the lock for indexing the deployment `site`. Returns `false` if
+/// `conn` did not hold the lock
+pub(crate) fn unlock_indexing(conn: &PgConnection, site: &Site) -> Result<bool, StoreError> {
+    #[derive(QueryableByName)]
+    struct Unlocked {
+        #[sql_type = "diesel::sql_types::Bool"]
+        unlocked: bool,
+    }
+
+    sql_query(&format!(
+        "select pg_advisory_unlock(3, {}) as unlocked",
+        site.id
+    ))
+    .get_result::<Unlocked>(conn)
+    .map(|res| res.unlocked)
+    .map_err(StoreError::from)
+}
+
+/// Check whether any session holds the lock for indexing the deployment
+/// `site`
+pub(crate) fn is_indexing(conn: &PgConnection, site: &Site) -> Result<bool, StoreError> {
+    #[derive(QueryableByName)]
+    struct Held {
+        #[sql_type = "diesel::sql_types::Bool"]
+        held: bool,
+    }
+
+    sql_query(&format!(
+        "select exists (select 1 from pg_locks \
+                         where locktype = 'advisory' \
+                           and classid = 3 and objid = {} and objsubid = 2 \
+                           and granted) as held",
+        site.id
+    ))
+    .get_result::<Held>(conn)
+    .map(|res| res.held)
+    .map_err(StoreError::from)
+}
+
+/// Check whether `conn` still holds the lock for indexing the deployment
+/// `site`
+pub(crate) fn holds_indexing_lock(conn: &PgConnection, site: &Site) -> Result<bool, StoreError> {
+    #[derive(QueryableByName)]
+    struct Held {
+        #[sql_type = "diesel::sql_types::Bool"]
+        held: bool,
+    }
+
+    sql_query(&format!(
+        "select exists (select 1 from pg_locks \
+                         where locktype = 'advisory' \
+                           and classid = 3 and objid = {} and objsubid = 2 \
+                           and pid = pg_backend_pid() and granted) as held",
+        site.id
+    ))
+    .get_result::<Held>(conn)
+    .map(|res| res.held)
+    .map_err(StoreError::from)
+}
@@ -100,26 +100,46 @@ impl<'a> QueryFragment<Pg> for BlockRangeContainsClause<'a> {
     fn walk_ast(&self, mut out: AstPass<Pg>) -> QueryResult<()> {
         out.unsafe_to_cache_prepared();
 
+        if self.table.immutable {
+            // The block ranges of immutable entities are never closed, so
+            // that checking the lower bound is enough. Postgres can use the
+            // BRIN index and partition bounds for that check, but not for
+            // `@>`
+            out.push_sql("lower(");
+            out.push_sql(self.table_prefix);
+            out.push_identifier(BLOCK_RANGE_COLUMN)?;
+            out.push_sql(") <= ");
+            out.push_bind_param::<Integer, _>(&self.block)?;
+            return Ok(());
+        }
+
         out.push_sql(self.table_prefix);
         out.push_identifier(BLOCK_RANGE_COLUMN)?;
         out.push_sql(" @> ");
         out.push_bind_param::<Integer, _>(&self.block)?;
-        if self.table.is_account_like && self.block < BLOCK_NUMBER_MAX {
-            // When block is BLOCK_NUMBER_MAX, these checks would be wrong; we
-            // don't worry about adding the equivalent in that case since
-            // we generally only see BLOCK_NUMBER_MAX here for metadata
-            // queries where block ranges don't matter anyway
+        if self.block == BLOCK_NUMBER_MAX {
+            // When block is BLOCK_NUMBER_MAX, the checks below would be
+            // wrong; we don't worry about adding the equivalent in that
+            // case since we generally only see BLOCK_NUMBER_MAX here for
+            // metadata queries where block ranges don't matter anyway
+            return Ok(());
+        }
+        if self.table.is_account_like {
             out.push_sql(" and coalesce(upper(");
             out.push_identifier(BLOCK_RANGE_COLUMN)?;
             out.push_sql("), 2147483647) > ");
             out.push_bind_param::<Integer, _>(&self.block)?;
+        }
+        // Partitioned tables are partitioned by the lower bound of the
+        // block range; spelling out the bound lets Postgres skip
+        // partitions that only contain later entity versions
+        if self.table.is_account_like || self.table.partition_blocks.is_some() {
             out.push_sql(" and lower(");
             out.push_identifier(BLOCK_RANGE_COLUMN)?;
             out.push_sql(") <= ");
-            out.push_bind_param::<Integer, _>(&self.block)
-        } else {
-            Ok(())
+            out.push_bind_param::<Integer, _>(&self.block)?;
         }
+        Ok(())
     }
 }
 
@@ -8,6 +8,7 @@ use std::{
 use graph::{
     blockchain::ChainIdentifier,
     components::store::BlockStore as BlockStoreTrait,
+    data::subgraph::status,
     prelude::{error, warn, BlockNumber, BlockPtr, Logger},
 };
 use graph::{
@@ -408,6 +409,33 @@ impl BlockStore {
         Ok(map)
     }
 
+    /// The status of every chain we know about, sorted by name
+    pub fn chain_statuses(&self) -> Result<Vec<status::ChainStatus>, StoreError> {
+        let stores: Vec<_> = self
+            .stores
+            .read()
+            .unwrap()
+            .values()
+            .map(CheapClone::cheap_clone)
+            .collect();
+        let mut statuses = stores
+            .iter()
+            .map(|store| store.status())
+            .collect::<Result<Vec<_>, _>>()?;
+        statuses.sort_by(|a, b| a.network.cmp(&b.network));
+        Ok(statuses)
+    }
+
+    /// The stores of all chains we know about
+    pub(crate) fn chain_stores(&self) -> Vec<Arc<ChainStore>> {
+        self.stores
+            .read()
+            .unwrap()
+            .values()
+            .map(CheapClone::cheap_clone)
+            .collect()
+    }
+
     pub fn chain_head_block(&self, chain: &str) -> Result<Option<BlockNumber>, StoreError> {
         let store = self
             .store(chain)
@@ -1,4 +1,4 @@
-use diesel::sql_types::Integer;
+use diesel::sql_types::{BigInt, Bool, Integer};
 use diesel::{connection::SimpleConnection, prelude::RunQueryDsl, select};
 use diesel::{insert_into, OptionalExtension};
 use diesel::{pg::PgConnection, sql_query};
@@ -12,11 +12,14 @@ use std::iter::FromIterator;
 use std::sync::Arc;
 
 use graph::prelude::anyhow::anyhow;
-use graph::{data::subgraph::schema::POI_TABLE, prelude::StoreError};
+use graph::{
+    data::subgraph::schema::POI_TABLE,
+    prelude::{BlockNumber, StoreError},
+};
 
 use crate::connection_pool::ForeignServer;
 use crate::{
-    primary::{Namespace, Site},
+    primary::{DeploymentId, Namespace, Site},
     relational::SqlName,
 };
 
@@ -44,6 +47,8 @@ table! {
         deployment -> Integer,
         table_name -> Text,
         is_account_like -> Nullable<Bool>,
+        partition_blocks -> Nullable<Integer>,
+        is_immutable -> Nullable<Bool>,
     }
 }
 
@@ -167,6 +172,46 @@ pub fn server_options(
     Ok(HashMap::from_iter(entries))
 }
 
+/// The process id of the backend that serves `conn`
+pub fn backend_pid(conn: &PgConnection) -> Result<i32, StoreError> {
+    #[derive(QueryableByName)]
+    struct Pid {
+        #[sql_type = "Integer"]
+        pid: i32,
+    }
+    Ok(sql_query("select pg_backend_pid() as pid")
+        .get_result::<Pid>(conn)?
+        .pid)
+}
+
+/// Cancel the query that the backend with process id `pid` is running. If
+/// the backend is idle, nothing happens
+pub fn cancel_backend(conn: &PgConnection, pid: i32) -> Result<(), StoreError> {
+    sql_query("select pg_cancel_backend($1)")
+        .bind::<Integer, _>(pid)
+        .execute(conn)?;
+    Ok(())
+}
+
+/// The size on disk in bytes of all tables in `namespace`, including
+/// their indexes and TOAST data
+pub fn namespace_size(conn: &PgConnection, namespace: &Namespace) -> Result<i64, StoreError> {
+    #[derive(QueryableByName)]
+    struct Size {
+        #[sql_type = "BigInt"]
+        size: i64,
+    }
+    let query = "select coalesce(sum(pg_total_relation_size(c.oid)), 0)::int8 as size \
+                   from pg_class c, pg_namespace n \
+                  where c.relnamespace = n.oid \
+                    and c.relkind = 'r' \
+                    and n.nspname = $1";
+    Ok(sql_query(query)
+        .bind::<Text, _>(namespace.as_str())
+        .get_result::<Size>(conn)?
+        .size)
+}
+
 pub fn has_namespace(conn: &PgConnection, namespace: &Namespace) -> Result<bool, StoreError> {
     use pg_namespace as nsp;
 
@@ -246,6 +291,161 @@ pub fn set_account_like(
     Ok(())
 }
 
+/// The tables of `site` that are partitioned, together with how many
+/// blocks each of their partitions covers
+pub fn partition_blocks(
+    conn: &PgConnection,
+    site: &Site,
+) -> Result<HashMap<String, BlockNumber>, StoreError> {
+    use table_stats as ts;
+    let tables = ts::table
+        .filter(ts::deployment.eq(site.id))
+        .filter(ts::partition_blocks.is_not_null())
+        .select((ts::table_name, ts::partition_blocks))
+        .get_results::<(String, Option<i32>)>(conn)?
+        .into_iter()
+        .filter_map(|(name, blocks)| blocks.map(|blocks| (name, blocks)))
+        .collect();
+    Ok(tables)
+}
+
+pub fn set_partition_blocks(
+    conn: &PgConnection,
+    site: &Site,
+    table_name: &SqlName,
+    blocks: BlockNumber,
+) -> Result<(), StoreError> {
+    use table_stats as ts;
+    insert_into(ts::table)
+        .values((
+            ts::deployment.eq(site.id),
+            ts::table_name.eq(table_name.as_str()),
+            ts::partition_blocks.eq(blocks),
+        ))
+        .on_conflict((ts::deployment, ts::table_name))
+        .do_update()
+        .set(ts::partition_blocks.eq(blocks))
+        .execute(conn)?;
+    Ok(())
+}
+
+/// The tables of `site` that were created with the layout for immutable
+/// entity types
+pub fn immutable_tables(conn: &PgConnection, site: &Site) -> Result<HashSet<String>, StoreError> {
+    use table_stats as ts;
+    let tables = ts::table
+        .filter(ts::deployment.eq(site.id))
+        .filter(ts::is_immutable.eq(true))
+        .select(ts::table_name)
+        .get_results::<String>(conn)?
+        .into_iter()
+        .collect();
+    Ok(tables)
+}
+
+pub fn set_immutable(
+    conn: &PgConnection,
+    site: &Site,
+    table_name: &SqlName,
+) -> Result<(), StoreError> {
+    use table_stats as ts;
+    insert_into(ts::table)
+        .values((
+            ts::deployment.eq(site.id),
+            ts::table_name.eq(table_name.as_str()),
+            ts::is_immutable.eq(true),
+        ))
+        .on_conflict((ts::deployment, ts::table_name))
+        .do_update()
+        .set(ts::is_immutable.eq(true))
+        .execute(conn)?;
+    Ok(())
+}
+
+/// The ids of the deployments in this shard that have partitioned tables
+pub fn partitioned_deployments(conn: &PgConnection) -> Result<Vec<DeploymentId>, StoreError> {
+    use table_stats as ts;
+    Ok(ts::table
+        .filter(ts::partition_blocks.is_not_null())
+        .select(ts::deployment)
+        .distinct()
+        .get_results(conn)?)
+}
+
+/// An index on a table of a deployment
+pub struct IndexInfo {
+    pub name: String,
+    /// The `create index` statement for the index
+    pub definition: String,
+    /// Whether Postgres can use the index. Indexes that failed to be
+    /// created concurrently are left behind as invalid indexes
+    pub valid: bool,
+}
+
+/// The indexes on the table `table` in `namespace`, ordered by name
+pub fn table_indexes(
+    conn: &PgConnection,
+    namespace: &Namespace,
+    table: &SqlName,
+) -> Result<Vec<IndexInfo>, StoreError> {
+    #[derive(QueryableByName)]
+    struct Index {
+        #[sql_type = "Text"]
+        name: String,
+        #[sql_type = "Text"]
+        definition: String,
+        #[sql_type = "Bool"]
+        valid: bool,
+    }
+    let query = "select i.relname as name, \
+                        pg_get_indexdef(x.indexrelid) as definition, \
+                        x.indisvalid as valid \
+                   from pg_index x, pg_class i, pg_class t, pg_namespace n \
+                  where i.oid = x.indexrelid \
+                    and t.oid = x.indrelid \
+                    and n.oid = t.relnamespace \
+                    and n.nspname = $1 \
+                    and t.relname = $2 \
+                  order by i.relname";
+    Ok(sql_query(query)
+        .bind::<Text, _>(namespace.as_str())
+        .bind::<Text, _>(table.as_str())
+        .get_results::<Index>(conn)?
+        .into_iter()
+        .map(|index| IndexInfo {
+            name: index.name,
+            definition: index.definition,
+            valid: index.valid,
+        })
+        .collect())
+}
+
+/// Whether the index `name` in `namespace` is an index on a partitioned
+/// table. Returns `None` if there is no such index
+pub fn index_is_partitioned(
+    conn: &PgConnection,
+    namespace: &Namespace,
+    name: &str,
+) -> Result<Option<bool>, StoreError> {
+    #[derive(QueryableByName)]
+    struct Kind {
+        #[sql_type = "Bool"]
+        partitioned: bool,
+    }
+    let query = "select c.relkind = 'I' as partitioned \
+                   from pg_class c, pg_namespace n \
+                  where c.relnamespace = n.oid \
+                    and c.relkind in ('i', 'I') \
+                    and n.nspname = $1 \
+                    and c.relname = $2";
+    Ok(sql_query(query)
+        .bind::<Text, _>(namespace.as_str())
+        .bind::<Text, _>(name)
+        .get_result::<Kind>(conn)
+        .optional()?
+        .map(|kind| kind.partitioned))
+}
+
 pub fn copy_account_like(conn: &PgConnection, src: &Site, dst: &Site) -> Result<usize, StoreError> {
     let src_nsp = if src.shard == dst.shard {
         "subgraphs".to_string()
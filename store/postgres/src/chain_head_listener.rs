@@ -88,7 +88,7 @@ struct ChainHeadUpdate {
 pub struct ChainHeadUpdateListener {
     /// Update watchers keyed by network.
     watchers: Arc<RwLock<BTreeMap<String, Watcher>>>,
-    _listener: NotificationListener,
+    listener: NotificationListener,
 }
 
 /// Sender for messages that the `ChainHeadUpdateListener` on other nodes
@@ -130,10 +130,16 @@ impl ChainHeadUpdateListener {
             // We keep the listener around to tie its stream's lifetime to
             // that of the chain head update listener and prevent it from
             // terminating early
-            _listener: listener,
+            listener,
         }
     }
 
+    /// Use `postgres_url` when the listener for chain head updates needs
+    /// to reconnect to the database
+    pub fn set_postgres_url(&self, postgres_url: String) {
+        self.listener.set_postgres_url(postgres_url)
+    }
+
     fn listen(
         logger: Logger,
         metrics: Arc<BlockIngestorMetrics>,
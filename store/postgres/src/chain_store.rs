@@ -1,9 +1,11 @@
+use diesel::dsl::sql;
 use diesel::pg::PgConnection;
 use diesel::prelude::*;
 use diesel::r2d2::{ConnectionManager, PooledConnection};
-use diesel::sql_types::Text;
-use diesel::{insert_into, update};
+use diesel::sql_types::{BigInt, Double, Nullable, Text};
+use diesel::{insert_into, sql_query, update};
 use graph::blockchain::{Block, ChainIdentifier};
+use graph::data::subgraph::status;
 use graph::prelude::web3::types::H256;
 use graph::{
     constraint_violation,
@@ -14,11 +16,14 @@ use graph::{
 };
 
 use graph::ensure;
+use graph::util::memory_budget::{BudgetHandle, CacheKind, MEMORY_BUDGET};
 use std::{
     collections::HashMap,
     convert::{TryFrom, TryInto},
+    fmt,
     iter::FromIterator,
-    sync::Arc,
+    sync::{Arc, Mutex},
+    time::Duration,
 };
 
 use graph::prelude::{
@@ -30,6 +35,37 @@ use crate::{
     connection_pool::ConnectionPool,
 };
 
+/// The reasons why `ChainStore::clear_call_cache` can fail
+#[derive(Debug)]
+pub enum ClearCallCacheError {
+    /// The named chain shares its call cache with the other chains in its
+    /// shard and clearing it was not explicitly asked for
+    Shared(String),
+    Store(Error),
+}
+
+impl fmt::Display for ClearCallCacheError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ClearCallCacheError::Shared(chain) => write!(
+                f,
+                "chain {} shares its call cache with all other chains in its shard, \
+                 and clearing it would also remove their cached calls",
+                chain
+            ),
+            ClearCallCacheError::Store(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for ClearCallCacheError {}
+
+impl From<Error> for ClearCallCacheError {
+    fn from(e: Error) -> Self {
+        ClearCallCacheError::Store(e)
+    }
+}
+
 /// Tables in the 'public' database schema that store chain-specific data
 mod public {
     table! {
@@ -40,6 +76,9 @@ mod public {
             head_block_number -> Nullable<BigInt>,
             net_version -> Varchar,
             genesis_block_hash -> Varchar,
+            provider_head_number -> Nullable<BigInt>,
+            provider_head_at -> Nullable<Timestamptz>,
+            provider_error -> Nullable<Text>,
         }
     }
 }
@@ -60,7 +99,7 @@ mod data {
         types::{FromSql, ToSql},
     };
     use diesel::{
-        sql_types::{BigInt, Bytea, Integer, Jsonb},
+        sql_types::{BigInt, Bytea, Integer, Jsonb, Nullable},
         update,
     };
     use diesel_dynamic_schema as dds;
@@ -130,6 +169,56 @@ mod data {
         hash: Vec<u8>,
     }
 
+    // Helpers for literal SQL queries that look up a block hash together
+    // with the hash of its parent
+    #[derive(QueryableByName)]
+    struct BlockHeaderText {
+        #[sql_type = "Text"]
+        hash: String,
+        #[sql_type = "Nullable<Text>"]
+        parent_hash: Option<String>,
+    }
+
+    #[derive(QueryableByName)]
+    struct BlockHeaderBytea {
+        #[sql_type = "Bytea"]
+        hash: Vec<u8>,
+        #[sql_type = "Bytea"]
+        parent_hash: Vec<u8>,
+    }
+
+    #[derive(QueryableByName)]
+    struct BlockTimestamp {
+        #[sql_type = "BigInt"]
+        number: i64,
+        #[sql_type = "Nullable<Text>"]
+        timestamp: Option<String>,
+    }
+
+    impl BlockTimestamp {
+        // Depending on how a block was stored, the block is either the
+        // entire `data` or nested under `data.block`
+        const COLUMN: &'static str =
+            "coalesce(data->'block'->>'timestamp', data->>'timestamp') as timestamp";
+
+        /// The block number and the timestamp. Errors if the block has no
+        /// valid timestamp
+        fn parse(self) -> Result<(BlockNumber, u64), Error> {
+            let timestamp = self
+                .timestamp
+                .as_deref()
+                .and_then(|ts| u64::from_str_radix(ts.trim_start_matches("0x"), 16).ok())
+                .ok_or_else(|| {
+                    anyhow::anyhow!(
+                        "block {} has an invalid timestamp {:?}",
+                        self.number,
+                        self.timestamp
+                    )
+                })?;
+            Ok((self.number as BlockNumber, timestamp))
+        }
+    }
+
     // Like H256::from_slice, but returns an error instead of panicking
     // when `bytes` does not have the right length
     fn h256_from_bytes(bytes: &[u8]) -> Result<H256, StoreError> {
@@ -829,6 +918,160 @@ mod data {
             Ok(data)
         }
 
+        /// Return the hashes of the block `block_ptr` and of its ancestors,
+        /// together with the hash of each block's parent, starting with
+        /// `block_ptr` and going back at most `count` blocks. The walk
+        /// stops early at the first block that is not in the store. Unlike
+        /// `ancestor_block`, this never loads block data
+        pub(super) fn ancestor_headers(
+            &self,
+            conn: &PgConnection,
+            block_ptr: &BlockPtr,
+            count: BlockNumber,
+        ) -> Result<Vec<(H256, H256)>, Error> {
+            match self {
+                Storage::Shared => {
+                    const ANCESTOR_HEADERS_SQL: &str = "
+        with recursive ancestors(block_hash, parent_hash, block_offset) as (
+            select b.hash, b.parent_hash, 1
+              from ethereum_blocks b
+             where b.hash = $1
+               and $2 > 0
+            union all
+            select b.hash, b.parent_hash, a.block_offset+1
+              from ancestors a, ethereum_blocks b
+             where b.hash = a.parent_hash
+               and a.block_offset < $2
+        )
+        select a.block_hash as hash, a.parent_hash
+          from ancestors a
+         order by a.block_offset;";
+
+                    let headers = sql_query(ANCESTOR_HEADERS_SQL)
+                        .bind::<Text, _>(block_ptr.hash_hex())
+                        .bind::<BigInt, _>(count as i64)
+                        .load::<BlockHeaderText>(conn)?;
+
+                    let mut path: Vec<(H256, H256)> = Vec::with_capacity(headers.len());
+                    for header in headers {
+                        let parent_hash = match header.parent_hash {
+                            Some(parent_hash) => parent_hash.parse()?,
+                            None => break,
+                        };
+                        path.push((header.hash.parse()?, parent_hash));
+                    }
+                    Ok(path)
+                }
+                Storage::Private(Schema { blocks, .. }) => {
+                    // Same as ANCESTOR_HEADERS_SQL except for the table name
+                    let query = format!(
+                        "
+        with recursive ancestors(block_hash, parent_hash, block_offset) as (
+            select b.hash, b.parent_hash, 1
+              from {} b
+             where b.hash = $1
+               and $2 > 0
+            union all
+            select b.hash, b.parent_hash, a.block_offset+1
+              from ancestors a, {} b
+             where b.hash = a.parent_hash
+               and a.block_offset < $2
+        )
+        select a.block_hash as hash, a.parent_hash
+          from ancestors a
+         order by a.block_offset;",
+                        blocks.qname, blocks.qname
+                    );
+
+                    sql_query(query)
+                        .bind::<Bytea, _>(block_ptr.hash_slice())
+                        .bind::<BigInt, _>(count as i64)
+                        .load::<BlockHeaderBytea>(conn)?
+                        .into_iter()
+                        .map(|header| {
+                            Ok((
+                                h256_from_bytes(&header.hash)?,
+                                h256_from_bytes(&header.parent_hash)?,
+                            ))
+                        })
+                        .collect()
+                }
+            }
+        }
+
+        /// Return the number and timestamp of the block with the highest
+        /// number in `from..=to`, or `None` if there is no block in that
+        /// range. Errors if the block has no valid timestamp
+        pub(super) fn last_block_timestamp(
+            &self,
+            conn: &PgConnection,
+            chain: &str,
+            from: BlockNumber,
+            to: BlockNumber,
+        ) -> Result<Option<(BlockNumber, u64)>, Error> {
+            let block = match self {
+                Storage::Shared => sql_query(format!(
+                    "select number, {} from ethereum_blocks
+                      where network_name = $1 and number between $2 and $3
+                      order by number desc limit 1",
+                    BlockTimestamp::COLUMN
+                ))
+                .bind::<Text, _>(chain)
+                .bind::<BigInt, _>(from as i64)
+                .bind::<BigInt, _>(to as i64)
+                .get_result::<BlockTimestamp>(conn)
+                .optional()?,
+                Storage::Private(Schema { blocks, .. }) => sql_query(format!(
+                    "select number, {} from {}
+                      where number between $1 and $2
+                      order by number desc limit 1",
+                    BlockTimestamp::COLUMN,
+                    blocks.qname
+                ))
+                .bind::<BigInt, _>(from as i64)
+                .bind::<BigInt, _>(to as i64)
+                .get_result::<BlockTimestamp>(conn)
+                .optional()?,
+            };
+
+            block.map(BlockTimestamp::parse).transpose()
+        }
+
+        /// Return the timestamp of the block with the given hash, or `None`
+        /// if we do not have that block or it has no timestamp. Errors if
+        /// the timestamp is not valid
+        pub(super) fn block_timestamp(
+            &self,
+            conn: &PgConnection,
+            chain: &str,
+            hash: H256,
+        ) -> Result<Option<u64>, Error> {
+            let block = match self {
+                Storage::Shared => sql_query(format!(
+                    "select number, {} from ethereum_blocks
+                      where network_name = $1 and hash = $2",
+                    BlockTimestamp::COLUMN
+                ))
+                .bind::<Text, _>(chain)
+                .bind::<Text, _>(format!("{:x}", hash))
+                .get_result::<BlockTimestamp>(conn)
+                .optional()?,
+                Storage::Private(Schema { blocks, .. }) => sql_query(format!(
+                    "select number, {} from {} where hash = $1",
+                    BlockTimestamp::COLUMN,
+                    blocks.qname
+                ))
+                .bind::<Bytea, _>(hash.as_bytes())
+                .get_result::<BlockTimestamp>(conn)
+                .optional()?,
+            };
+
+            block
+                .filter(|block| block.timestamp.is_some())
+                .map(|block| block.parse().map(|(_, timestamp)| timestamp))
+                .transpose()
+        }
+
         pub(super) fn delete_blocks_before(
             &self,
             conn: &PgConnection,
@@ -859,6 +1102,67 @@ mod data {
             }
         }
 
+        /// Remove the cached results of all calls made against blocks in
+        /// the range `from..=to`. With `Storage::Shared`, the call cache is
+        /// shared between all chains in the shard, and results for all of
+        /// them are removed
+        pub(super) fn clear_call_cache(
+            &self,
+            conn: &PgConnection,
+            from: BlockNumber,
+            to: BlockNumber,
+        ) -> Result<usize, Error> {
+            match self {
+                Storage::Shared => {
+                    use public::eth_call_cache as cache;
+
+                    delete(cache::table)
+                        .filter(cache::block_number.ge(from))
+                        .filter(cache::block_number.le(to))
+                        .execute(conn)
+                        .map_err(Error::from)
+                }
+                Storage::Private(Schema { call_cache, .. }) => {
+                    let query = format!(
+                        "delete from {} where block_number >= $1 and block_number <= $2",
+                        call_cache.qname
+                    );
+                    sql_query(query)
+                        .bind::<Integer, _>(from)
+                        .bind::<Integer, _>(to)
+                        .execute(conn)
+                        .map_err(Error::from)
+                }
+            }
+        }
+
+        pub(super) fn block_count(&self, conn: &PgConnection, chain: &str) -> Result<i64, Error> {
+            #[derive(QueryableByName)]
+            struct BlockCount {
+                #[sql_type = "BigInt"]
+                count: i64,
+            }
+
+            match self {
+                Storage::Shared => {
+                    use public::ethereum_blocks as b;
+
+                    b::table
+                        .filter(b::network_name.eq(chain))
+                        .count()
+                        .get_result(conn)
+                        .map_err(Error::from)
+                }
+                Storage::Private(Schema { blocks, .. }) => {
+                    let query = format!("select count(*) as count from {}", blocks.qname);
+                    sql_query(query)
+                        .get_result::<BlockCount>(conn)
+                        .map(|count| count.count)
+                        .map_err(Error::from)
+                }
+            }
+        }
+
         pub(super) fn get_call_and_access(
             &self,
             conn: &PgConnection,
@@ -1138,6 +1442,74 @@ from (
     }
 }
 
+/// An in-memory index of the parents of recent blocks. Block streams walk
+/// back from the chain head every time it changes to check for reorgs;
+/// with this index, they mostly don't need to go to the database for that.
+/// Since a block's parent never changes, entries can never become stale,
+/// and we only prune blocks that are far behind the highest block we know,
+/// or when the index would use more than its share of the memory budget
+struct RecentBlocks {
+    /// Maps the hash of a block to its number and the hash of its parent
+    parents: HashMap<H256, (BlockNumber, H256)>,
+    max_number: BlockNumber,
+    budget: BudgetHandle,
+}
+
+impl RecentBlocks {
+    /// How far behind the highest block we know we keep blocks
+    const DEPTH: BlockNumber = 500;
+
+    /// The approximate memory needed for one entry in `parents`
+    const ENTRY_SIZE: usize =
+        std::mem::size_of::<H256>() + std::mem::size_of::<(BlockNumber, H256)>();
+
+    fn new() -> Self {
+        RecentBlocks {
+            parents: HashMap::new(),
+            max_number: 0,
+            budget: MEMORY_BUDGET.register(CacheKind::Block),
+        }
+    }
+
+    fn insert(&mut self, hash: H256, number: BlockNumber, parent_hash: H256) {
+        if number < self.max_number - Self::DEPTH {
+            return;
+        }
+        self.parents.insert(hash, (number, parent_hash));
+        let mut evicted = 0;
+        if number > self.max_number {
+            self.max_number = number;
+            // Prune in bulk so that we don't have to do it on every insert
+            let max_entries = self
+                .budget
+                .limit(2 * Self::DEPTH as usize * Self::ENTRY_SIZE)
+                / Self::ENTRY_SIZE;
+            if self.parents.len() > max_entries {
+                let depth = (Self::DEPTH as usize).min(max_entries / 2) as BlockNumber;
+                let cutoff = self.max_number - depth;
+                let before = self.parents.len();
+                self.parents.retain(|_, (number, _)| *number >= cutoff);
+                evicted = before - self.parents.len();
+            }
+        }
+        self.budget.report(
+            self.parents.len() * Self::ENTRY_SIZE,
+            evicted * Self::ENTRY_SIZE,
+        );
+    }
+
+    /// Return the ancestor of `block_ptr` at `offset` if we know all the
+    /// blocks in between
+    fn ancestor(&self, block_ptr: &BlockPtr, offset: BlockNumber) -> Option<BlockPtr> {
+        let mut hash = block_ptr.hash_as_h256();
+        for _ in 0..offset {
+            let (_, parent_hash) = self.parents.get(&hash)?;
+            hash = *parent_hash;
+        }
+        Some(BlockPtr::from((hash, block_ptr.number - offset)))
+    }
+}
+
 pub struct ChainStore {
     pool: ConnectionPool,
     pub chain: String,
@@ -1145,6 +1517,7 @@ pub struct ChainStore {
     genesis_block_ptr: BlockPtr,
     status: ChainStatus,
     chain_head_update_sender: ChainHeadUpdateSender,
+    recent_blocks: Mutex<RecentBlocks>,
 }
 
 impl ChainStore {
@@ -1163,6 +1536,7 @@ impl ChainStore {
             genesis_block_ptr: BlockPtr::new(net_identifier.genesis_block_hash.clone(), 0),
             status,
             chain_head_update_sender,
+            recent_blocks: Mutex::new(RecentBlocks::new()),
         };
 
         store
@@ -1176,6 +1550,65 @@ impl ChainStore {
         self.pool.get().map_err(Error::from)
     }
 
+    /// Find the number of the first block whose timestamp is at or after
+    /// `timestamp` with a binary search over the blocks in the store. Since
+    /// the store does not necessarily have all blocks, the answer is only
+    /// known if the store also has the block before it; if it doesn't,
+    /// return `None`
+    pub(crate) fn block_number_by_timestamp(
+        &self,
+        timestamp: u64,
+    ) -> Result<Option<BlockNumber>, Error> {
+        let head = match self.chain_head_ptr()? {
+            Some(head) => head.number,
+            None => return Ok(None),
+        };
+
+        let conn = self.get_conn()?;
+        // The answer is always in `lo..=hi`, and `hi` is a block that we
+        // have with a timestamp at or after `timestamp`
+        let mut lo = 0;
+        let mut hi = match self
+            .storage
+            .last_block_timestamp(&conn, &self.chain, lo, head)?
+        {
+            Some((number, ts)) if ts >= timestamp => number,
+            _ => return Ok(None),
+        };
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            match self
+                .storage
+                .last_block_timestamp(&conn, &self.chain, lo, mid)?
+            {
+                Some((number, ts)) if ts >= timestamp => hi = number,
+                // Either all blocks up to `mid` are before `timestamp`, or
+                // we don't have any of them
+                _ => lo = mid + 1,
+            }
+        }
+
+        // `hi` is the first block we have that is at or after `timestamp`,
+        // but if its parent was evicted from the store, or never made it
+        // there, an earlier block might also qualify
+        if hi > 0
+            && self
+                .storage
+                .last_block_timestamp(&conn, &self.chain, hi - 1, hi - 1)?
+                .is_none()
+        {
+            return Ok(None);
+        }
+        Ok(Some(hi))
+    }
+
+    /// The timestamp of the block with the given hash, in seconds since the
+    /// epoch, or `None` if the store does not have that block
+    pub(crate) fn block_timestamp(&self, hash: H256) -> Result<Option<u64>, Error> {
+        let conn = self.get_conn()?;
+        self.storage.block_timestamp(&conn, &self.chain, hash)
+    }
+
     pub(crate) fn create(&self, ident: &ChainIdentifier) -> Result<(), Error> {
         use public::ethereum_networks::dsl::*;
 
@@ -1254,6 +1687,83 @@ impl ChainStore {
         )
     }
 
+    /// The state of this chain and of the block ingestor that follows it
+    pub fn status(&self) -> Result<status::ChainStatus, StoreError> {
+        #[derive(QueryableByName)]
+        struct Network {
+            #[sql_type = "Nullable<Text>"]
+            head_block_hash: Option<String>,
+            #[sql_type = "Nullable<BigInt>"]
+            head_block_number: Option<i64>,
+            #[sql_type = "Nullable<BigInt>"]
+            provider_head_number: Option<i64>,
+            #[sql_type = "Nullable<Double>"]
+            provider_head_age: Option<f64>,
+            #[sql_type = "Nullable<Text>"]
+            provider_error: Option<String>,
+        }
+
+        let conn = self.get_conn()?;
+        let network = sql_query(
+            "select head_block_hash, head_block_number, provider_head_number,
+                    extract(epoch from now() - provider_head_at)::float8 as provider_head_age,
+                    provider_error
+               from ethereum_networks
+              where name = $1",
+        )
+        .bind::<Text, _>(&self.chain)
+        .get_result::<Network>(&conn)?;
+        let cached_blocks = self.storage.block_count(&conn, &self.chain)?;
+
+        let chain_head_block = match (network.head_block_hash, network.head_block_number) {
+            (Some(hash), Some(number)) => Some(BlockPtr::try_from((hash.as_str(), number))?.into()),
+            _ => None,
+        };
+        Ok(status::ChainStatus {
+            network: self.chain.clone(),
+            chain_head_block,
+            provider_head_block: network
+                .provider_head_number
+                .map(|number| number as BlockNumber),
+            provider_head_age: network
+                .provider_head_age
+                .map(|age| Duration::from_secs_f64(age.max(0.0))),
+            provider_error: network.provider_error,
+            cached_blocks: cached_blocks as u64,
+        })
+    }
+
+    /// Remove all blocks with a number lower than `block` from the block
+    /// cache, except for the genesis block, and return how many blocks
+    /// were removed
+    pub(crate) fn delete_blocks_before(&self, block: BlockNumber) -> Result<usize, Error> {
+        let conn = self.get_conn()?;
+        self.storage
+            .delete_blocks_before(&conn, &self.chain, block as i64)
+    }
+
+    /// Remove the cached results of calls made against blocks in the range
+    /// `from..=to` and return how many were removed. Calls for these blocks
+    /// will be sent to the Ethereum node again the next time a mapping
+    /// makes them.
+    ///
+    /// If this chain shares its call cache with the other chains in its
+    /// shard, clearing it removes their cached calls, too. That is only
+    /// done if `all_chains` is `true`, and fails with
+    /// `ClearCallCacheError::Shared` otherwise
+    pub fn clear_call_cache(
+        &self,
+        from: BlockNumber,
+        to: BlockNumber,
+        all_chains: bool,
+    ) -> Result<usize, ClearCallCacheError> {
+        if matches!(self.storage, data::Storage::Shared) && !all_chains {
+            return Err(ClearCallCacheError::Shared(self.chain.clone()));
+        }
+        let conn = self.get_conn()?;
+        Ok(self.storage.clear_call_cache(&conn, from, to)?)
+    }
+
     /// Store the given chain as the blocks for the `network` set the
     /// network's genesis block to `genesis_hash`, and head block to
     /// `null`
@@ -1376,6 +1886,26 @@ impl ChainStoreTrait for ChainStore {
             .map_err(Error::from)
     }
 
+    fn set_provider_head(&self, head: Result<BlockNumber, String>) -> Result<(), Error> {
+        use public::ethereum_networks as n;
+
+        let conn = self.get_conn()?;
+        let network = n::table.filter(n::name.eq(&self.chain));
+        match head {
+            Ok(number) => update(network)
+                .set((
+                    n::provider_head_number.eq(number as i64),
+                    n::provider_head_at.eq(sql("now()")),
+                    n::provider_error.eq(None::<String>),
+                ))
+                .execute(&conn)?,
+            Err(error) => update(network)
+                .set(n::provider_error.eq(error))
+                .execute(&conn)?,
+        };
+        Ok(())
+    }
+
     fn blocks(&self, hashes: &[H256]) -> Result<Vec<json::Value>, Error> {
         let conn = self.get_conn()?;
         self.storage.blocks(&conn, &self.chain, hashes)
@@ -1397,72 +1927,44 @@ impl ChainStoreTrait for ChainStore {
         self.storage.ancestor_block(&conn, block_ptr, offset)
     }
 
-    fn cleanup_cached_blocks(
+    fn ancestor_ptr(
         &self,
-        ancestor_count: BlockNumber,
-    ) -> Result<Option<(BlockNumber, usize)>, Error> {
-        use diesel::sql_types::Integer;
+        block_ptr: BlockPtr,
+        offset: BlockNumber,
+    ) -> Result<Option<BlockPtr>, Error> {
+        ensure!(
+            block_ptr.number >= offset,
+            "block offset {} for block `{}` points to before genesis block",
+            offset,
+            block_ptr.hash_hex()
+        );
 
-        #[derive(QueryableByName)]
-        struct MinBlock {
-            #[sql_type = "Integer"]
-            block: i32,
+        if let Some(ancestor) = self
+            .recent_blocks
+            .lock()
+            .unwrap()
+            .ancestor(&block_ptr, offset)
+        {
+            return Ok(Some(ancestor));
         }
 
-        // Remove all blocks from the cache that are behind the slowest
-        // subgraph's head block, but retain the genesis block. We stay
-        // behind the slowest subgraph so that we do not interfere with its
-        // syncing activity.
-        // We also stay `ancestor_count` many blocks behind the head of the
-        // chain since the block ingestor consults these blocks frequently
-        //
-        // Only consider active subgraphs that have not failed
-
-        // This assumes that subgraph metadata and blocks are stored in the
-        // same shard. We disallow setting GRAPH_ETHEREUM_CLEANUP_BLOCKS in
-        // graph_node::config so that we only run this query when we know
-        // it will work. Running this with a sharded store might remove
-        // blocks that are still needed by deployments in other shard
-        //
-        // See 8b6ad0c64e244023ac20ced7897fe666
-
         let conn = self.get_conn()?;
-        let query = "
-            select coalesce(
-                   least(a.block,
-                        (select head_block_number::int - $1
-                           from ethereum_networks
-                          where name = $2)), -1)::int as block
-              from (
-                select min(d.latest_ethereum_block_number) as block
-                  from subgraphs.subgraph_deployment d,
-                       subgraphs.subgraph_deployment_assignment a,
-                       deployment_schemas ds
-                 where ds.subgraph = d.deployment
-                   and a.id = d.id
-                   and not d.failed
-                   and ds.network = $2) a;";
-        let ancestor_count = i32::try_from(ancestor_count)
-            .expect("ancestor_count fits into a signed 32 bit integer");
-        diesel::sql_query(query)
-            .bind::<Integer, _>(ancestor_count)
-            .bind::<Text, _>(&self.chain)
-            .load::<MinBlock>(&conn)?
-            .first()
-            .map(|MinBlock { block }| {
-                // If we could not determine a minimum block, the query
-                // returns -1, and we should not do anything. We also guard
-                // against removing the genesis block
-                if *block > 0 {
-                    self.storage
-                        .delete_blocks_before(&conn, &self.chain, *block as i64)
-                        .map(|rows| Some((*block, rows)))
-                } else {
-                    Ok(None)
-                }
-            })
-            .unwrap_or(Ok(None))
-            .map_err(|e| e.into())
+        let headers = self.storage.ancestor_headers(&conn, &block_ptr, offset)?;
+
+        let mut recent_blocks = self.recent_blocks.lock().unwrap();
+        for (number, (hash, parent_hash)) in (0..=block_ptr.number).rev().zip(headers.iter()) {
+            recent_blocks.insert(*hash, number, *parent_hash);
+        }
+
+        // The ancestor is the parent of the last block we found, but only
+        // if we found all the blocks in between
+        if headers.len() < offset as usize {
+            return Ok(None);
+        }
+        Ok(Some(match headers.last() {
+            Some((_, parent_hash)) => BlockPtr::from((*parent_hash, block_ptr.number - offset)),
+            None => block_ptr,
+        }))
     }
 
     fn block_hashes_by_block_number(&self, number: BlockNumber) -> Result<Vec<H256>, Error> {
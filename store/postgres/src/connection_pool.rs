@@ -295,6 +295,9 @@ impl fmt::Debug for ConnectionPool {
 pub enum PoolName {
     Main,
     Replica(String),
+    /// A pool for queries against the main database, separate from the
+    /// main pool that is used for indexing
+    Query,
 }
 
 impl PoolName {
@@ -302,13 +305,16 @@ impl PoolName {
         match self {
             PoolName::Main => "main",
             PoolName::Replica(name) => name,
+            PoolName::Query => "query",
         }
     }
 
+    /// Only the main pool sets up the database; all other pools can be
+    /// used right away
     fn is_replica(&self) -> bool {
         match self {
             PoolName::Main => false,
-            PoolName::Replica(_) => true,
+            PoolName::Replica(_) | PoolName::Query => true,
         }
     }
 }
@@ -486,11 +492,52 @@ impl ConnectionPool {
         self.get_ready()?.get_fdw(logger, timeout)
     }
 
+    /// Open a connection that is not part of the pool and is closed when
+    /// it is dropped. Session state like advisory locks that is set up on
+    /// such a connection can therefore never leak to other users of the
+    /// pool
+    pub fn dedicated_conn(&self) -> Result<PgConnection, StoreError> {
+        let pool = self.get_ready()?;
+        PgConnection::establish(&pool.postgres_url).map_err(|e| StoreError::Unknown(e.into()))
+    }
+
     pub fn connection_detail(&self) -> Result<ForeignServer, StoreError> {
         let pool = self.get_ready()?;
         ForeignServer::new(pool.shard.clone(), &pool.postgres_url).map_err(|e| e.into())
     }
 
+    /// Open all new connections with `postgres_url`, for example, because
+    /// the password in it was rotated. Connections that are in use when
+    /// this is called are closed when they are returned to the pool
+    pub fn set_postgres_url(&self, postgres_url: String) {
+        let mut guard = self.inner.lock(&self.logger);
+        let state = match &*guard {
+            PoolState::Created(pool, servers) => {
+                PoolState::Created(Arc::new(pool.with_url(postgres_url)), servers.clone())
+            }
+            PoolState::Ready(pool) => PoolState::Ready(Arc::new(pool.with_url(postgres_url))),
+        };
+        *guard = state;
+    }
+
+    /// Change the user mappings for the foreign servers in this database
+    /// to match `servers`. Only the main pool of a shard should be used for
+    /// this, since the other pools may connect to read-only replicas
+    pub fn update_servers(&self, servers: Arc<Vec<ForeignServer>>) -> Result<(), StoreError> {
+        let pool = {
+            let mut guard = self.inner.lock(&self.logger);
+            match &*guard {
+                PoolState::Created(pool, _) => {
+                    // `setup` will create the mappings from `servers`
+                    *guard = PoolState::Created(pool.clone(), servers);
+                    return Ok(());
+                }
+                PoolState::Ready(pool) => pool.clone(),
+            }
+        };
+        pool.configure_fdw(servers.as_ref())
+    }
+
     /// Check that we can connect to the database
     pub fn check(&self) -> bool {
         true
@@ -710,6 +757,8 @@ pub struct PoolInner {
     fdw_pool: Option<Pool<ConnectionManager<PgConnection>>>,
     limiter: Arc<Semaphore>,
     postgres_url: String,
+    error_handler: ErrorHandler,
+    event_handler: EventHandler,
     pub(crate) wait_stats: PoolWaitStats,
 
     // Limits the number of graphql queries that may execute concurrently. Since one graphql query
@@ -747,41 +796,25 @@ impl PoolInner {
                 HashMap::new(),
             )
             .expect("failed to create `store_connection_error_count` counter");
-        let error_handler = Box::new(ErrorHandler::new(
-            logger_pool.clone(),
-            error_counter,
-            state_tracker.clone(),
-        ));
+        let error_handler =
+            ErrorHandler::new(logger_pool.clone(), error_counter, state_tracker.clone());
         let wait_stats = Arc::new(RwLock::new(MovingStats::default()));
-        let event_handler = Box::new(EventHandler::new(
+        let event_handler = EventHandler::new(
             logger_pool.clone(),
             registry.cheap_clone(),
             wait_stats.clone(),
             const_labels.clone(),
             state_tracker,
-        ));
+        );
 
         // Connect to Postgres
-        let conn_manager = ConnectionManager::new(postgres_url.clone());
-        let builder: Builder<ConnectionManager<PgConnection>> = Pool::builder()
-            .error_handler(error_handler.clone())
-            .event_handler(event_handler.clone())
-            .connection_timeout(*CONNECTION_TIMEOUT)
-            .max_size(pool_size)
-            .min_idle(*MIN_IDLE)
-            .idle_timeout(Some(*IDLE_TIMEOUT));
-        let pool = builder.build_unchecked(conn_manager);
-        let fdw_pool = fdw_pool_size.map(|pool_size| {
-            let conn_manager = ConnectionManager::new(postgres_url.clone());
-            let builder: Builder<ConnectionManager<PgConnection>> = Pool::builder()
-                .error_handler(error_handler)
-                .event_handler(event_handler)
-                .connection_timeout(*CONNECTION_TIMEOUT)
-                .max_size(pool_size)
-                .min_idle(Some(1))
-                .idle_timeout(Some(FDW_IDLE_TIMEOUT));
-            builder.build_unchecked(conn_manager)
-        });
+        let (pool, fdw_pool) = Self::build_pools(
+            &postgres_url,
+            pool_size,
+            fdw_pool_size,
+            &error_handler,
+            &event_handler,
+        );
 
         let limiter = Arc::new(Semaphore::new(pool_size as usize));
         info!(logger_store, "Pool successfully connected to Postgres");
@@ -803,6 +836,8 @@ impl PoolInner {
             pool,
             fdw_pool,
             limiter,
+            error_handler,
+            event_handler,
             wait_stats,
             semaphore_wait_stats: Arc::new(RwLock::new(MovingStats::default())),
             query_semaphore,
@@ -810,6 +845,60 @@ impl PoolInner {
         }
     }
 
+    fn build_pools(
+        postgres_url: &str,
+        pool_size: u32,
+        fdw_pool_size: Option<u32>,
+        error_handler: &ErrorHandler,
+        event_handler: &EventHandler,
+    ) -> (
+        Pool<ConnectionManager<PgConnection>>,
+        Option<Pool<ConnectionManager<PgConnection>>>,
+    ) {
+        let conn_manager = ConnectionManager::new(postgres_url);
+        let builder: Builder<ConnectionManager<PgConnection>> = Pool::builder()
+            .error_handler(Box::new(error_handler.clone()))
+            .event_handler(Box::new(event_handler.clone()))
+            .connection_timeout(*CONNECTION_TIMEOUT)
+            .max_size(pool_size)
+            .min_idle(*MIN_IDLE)
+            .idle_timeout(Some(*IDLE_TIMEOUT));
+        let pool = builder.build_unchecked(conn_manager);
+        let fdw_pool = fdw_pool_size.map(|pool_size| {
+            let conn_manager = ConnectionManager::new(postgres_url);
+            let builder: Builder<ConnectionManager<PgConnection>> = Pool::builder()
+                .error_handler(Box::new(error_handler.clone()))
+                .event_handler(Box::new(event_handler.clone()))
+                .connection_timeout(*CONNECTION_TIMEOUT)
+                .max_size(pool_size)
+                .min_idle(Some(1))
+                .idle_timeout(Some(FDW_IDLE_TIMEOUT));
+            builder.build_unchecked(conn_manager)
+        });
+        (pool, fdw_pool)
+    }
+
+    /// Return a copy of this pool that opens new connections with
+    /// `postgres_url`. The copy shares the limits on concurrency and the
+    /// metrics with this pool. Connections that are checked out of this
+    /// pool stay usable, and are closed once this pool is dropped and they
+    /// are returned
+    fn with_url(&self, postgres_url: String) -> PoolInner {
+        let (pool, fdw_pool) = Self::build_pools(
+            &postgres_url,
+            self.pool.max_size(),
+            self.fdw_pool.as_ref().map(|pool| pool.max_size()),
+            &self.error_handler,
+            &self.event_handler,
+        );
+        PoolInner {
+            pool,
+            fdw_pool,
+            postgres_url,
+            ..self.clone()
+        }
+    }
+
     /// Execute a closure with a connection to the database.
     ///
     /// # API
@@ -1103,3 +1192,74 @@ fn migrate_schema(logger: &Logger, conn: &PgConnection) -> Result<(), StoreError
 
     Ok(())
 }
+
+/// Check which migrations are pending for the database behind `conn`
+/// without changing it: all pending migrations are run in a transaction
+/// that is rolled back afterwards. Returns the versions of the migrations
+/// that `graph-node` would run when it starts, and an error if one of them
+/// fails. Since the migrations really run, they take the same locks as
+/// they would during an upgrade, and the check should not be run against
+/// a busy database
+pub fn check_migrations(conn: &PgConnection) -> Result<Vec<String>, StoreError> {
+    enum DryRun {
+        Done,
+        Failed(anyhow::Error),
+    }
+
+    impl From<diesel::result::Error> for DryRun {
+        fn from(e: diesel::result::Error) -> Self {
+            DryRun::Failed(e.into())
+        }
+    }
+
+    let mut output = vec![];
+    let result = conn.transaction::<(), _, _>(|| {
+        embedded_migrations::run_with_output(conn, &mut output)
+            .map_err(|e| DryRun::Failed(e.into()))?;
+        // Roll back whatever the migrations did
+        Err(DryRun::Done)
+    });
+    match result {
+        Ok(()) | Err(DryRun::Done) => {}
+        Err(DryRun::Failed(e)) => return Err(StoreError::Unknown(e)),
+    }
+
+    // Diesel reports each migration it runs as `Running migration <version>`
+    let output = String::from_utf8(output).unwrap_or_default();
+    Ok(output
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("Running migration "))
+        .map(|version| version.trim().to_string())
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::fs;
+    use std::path::Path;
+
+    /// Every migration needs a `down.sql` so it can be reverted, even if
+    /// that does nothing, and versions need to be unique since Diesel
+    /// identifies migrations by their version
+    #[test]
+    fn migrations_are_reversible() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("migrations");
+        let mut versions = HashSet::new();
+        for entry in fs::read_dir(&dir).unwrap() {
+            let path = entry.unwrap().path();
+            let name = path.file_name().unwrap().to_string_lossy().to_string();
+            assert!(path.join("up.sql").is_file(), "{} has no up.sql", name);
+            assert!(path.join("down.sql").is_file(), "{} has no down.sql", name);
+
+            let version: String = name.chars().take_while(|c| *c != '_').collect();
+            let version = version.replace('-', "");
+            assert!(
+                !version.is_empty() && version.chars().all(|c| c.is_ascii_digit()),
+                "{} does not start with a version",
+                name
+            );
+            assert!(versions.insert(version), "{} reuses a version", name);
+        }
+    }
+}
@@ -4,7 +4,7 @@
 use crate::detail::GraphNodeVersion;
 use diesel::{
     connection::SimpleConnection,
-    dsl::{count, delete, insert_into, select, sql, update},
+    dsl::{any, count, delete, insert_into, min, select, sql, update},
     sql_types::Integer,
 };
 use diesel::{expression::SqlLiteral, pg::PgConnection, sql_types::Numeric};
@@ -24,7 +24,10 @@ use std::str::FromStr;
 use std::{collections::BTreeSet, convert::TryFrom, ops::Bound};
 
 use crate::connection_pool::ForeignServer;
-use crate::{block_range::BLOCK_RANGE_COLUMN, primary::Site};
+use crate::{
+    block_range::BLOCK_RANGE_COLUMN,
+    primary::{DeploymentId, Site},
+};
 use graph::constraint_violation;
 
 #[derive(DbEnum, Debug, Clone, Copy)]
@@ -79,6 +82,11 @@ table! {
         current_reorg_depth -> Integer,
         max_reorg_depth -> Integer,
         firehose_cursor -> Nullable<Text>,
+        queries_disabled -> Bool,
+        blocks_per_second -> Nullable<Double>,
+        pruned_block -> Nullable<Integer>,
+        statement_timeout -> Nullable<Integer>,
+        size_bytes -> Nullable<BigInt>,
     }
 }
 
@@ -104,6 +112,8 @@ table! {
         features -> Array<Text>,
         schema -> Text,
         graph_node_version_id -> Nullable<Integer>,
+        raw_yaml -> Nullable<Text>,
+        abis -> Nullable<Text>,
     }
 }
 
@@ -211,6 +221,29 @@ pub fn manifest_info(
         .map(|schema| (schema, description, repository))
 }
 
+/// The manifest of the deployment as it was deployed, if it was stored
+pub fn raw_manifest(conn: &PgConnection, site: &Site) -> Result<Option<String>, StoreError> {
+    use subgraph_manifest as sm;
+
+    sm::table
+        .select(sm::raw_yaml)
+        .filter(sm::id.eq(site.id))
+        .first::<Option<String>>(conn)
+        .map_err(StoreError::from)
+}
+
+/// The ABIs of the deployment as a JSON list of their names, files and
+/// contents, if they were stored
+pub fn abis(conn: &PgConnection, site: &Site) -> Result<Option<String>, StoreError> {
+    use subgraph_manifest as sm;
+
+    sm::table
+        .select(sm::abis)
+        .filter(sm::id.eq(site.id))
+        .first::<Option<String>>(conn)
+        .map_err(StoreError::from)
+}
+
 #[allow(dead_code)]
 pub fn features(conn: &PgConnection, site: &Site) -> Result<BTreeSet<SubgraphFeature>, StoreError> {
     use subgraph_manifest as sm;
@@ -387,6 +420,7 @@ fn latest_as_block_number(
 
 pub fn state(conn: &PgConnection, id: DeploymentHash) -> Result<DeploymentState, StoreError> {
     use subgraph_deployment as d;
+    use subgraph_error as e;
 
     match d::table
         .filter(d::deployment.eq(id.as_str()))
@@ -395,31 +429,199 @@ pub fn state(conn: &PgConnection, id: DeploymentHash) -> Result<DeploymentState,
             d::reorg_count,
             d::max_reorg_depth,
             d::latest_ethereum_block_number,
+            d::queries_disabled,
+            d::pruned_block,
+            d::failed,
+            d::fatal_error,
         ))
-        .first::<(String, i32, i32, Option<BigDecimal>)>(conn)
+        .first::<(
+            String,
+            i32,
+            i32,
+            Option<BigDecimal>,
+            bool,
+            Option<i32>,
+            bool,
+            Option<String>,
+        )>(conn)
         .optional()?
     {
         None => Err(StoreError::QueryExecutionError(format!(
             "No data found for subgraph {}",
             id
         ))),
-        Some((_, reorg_count, max_reorg_depth, latest_ethereum_block_number)) => {
+        Some((
+            _,
+            reorg_count,
+            max_reorg_depth,
+            latest_ethereum_block_number,
+            queries_disabled,
+            pruned_block,
+            failed,
+            fatal_error,
+        )) => {
             let reorg_count = convert_to_u32(Some(reorg_count), "reorg_count", id.as_str())?;
             let max_reorg_depth =
                 convert_to_u32(Some(max_reorg_depth), "max_reorg_depth", id.as_str())?;
             let latest_ethereum_block_number =
                 latest_as_block_number(latest_ethereum_block_number, id.as_str())?;
+            // A deployment that failed with a non-deterministic error
+            // will be retried and might recover
+            let failed_deterministically = match fatal_error {
+                Some(fatal_error) if failed => e::table
+                    .filter(e::id.eq(fatal_error))
+                    .select(e::deterministic)
+                    .first::<bool>(conn)
+                    .optional()?
+                    .unwrap_or(false),
+                _ => false,
+            };
 
             Ok(DeploymentState {
                 id,
                 reorg_count,
                 max_reorg_depth,
                 latest_ethereum_block_number,
+                queries_disabled,
+                pruned_block,
+                failed_deterministically,
             })
         }
     }
 }
 
+/// Stop or resume serving queries for the deployment `id`
+pub fn set_queries_disabled(
+    conn: &PgConnection,
+    id: &DeploymentHash,
+    disabled: bool,
+) -> Result<(), StoreError> {
+    use subgraph_deployment as d;
+
+    update(d::table.filter(d::deployment.eq(id.as_str())))
+        .set(d::queries_disabled.eq(disabled))
+        .execute(conn)?;
+    Ok(())
+}
+
+/// Record how many blocks per second the deployment `id` processed
+/// recently
+pub fn set_blocks_per_second(
+    conn: &PgConnection,
+    id: &DeploymentHash,
+    blocks_per_second: f64,
+) -> Result<(), StoreError> {
+    use subgraph_deployment as d;
+
+    update(d::table.filter(d::deployment.eq(id.as_str())))
+        .set(d::blocks_per_second.eq(blocks_per_second))
+        .execute(conn)?;
+    Ok(())
+}
+
+/// The block before which entity versions of the deployment `id` have
+/// been removed, if the deployment was ever pruned
+pub fn pruned_block(
+    conn: &PgConnection,
+    id: &DeploymentHash,
+) -> Result<Option<BlockNumber>, StoreError> {
+    use subgraph_deployment as d;
+
+    d::table
+        .filter(d::deployment.eq(id.as_str()))
+        .select(d::pruned_block)
+        .first::<Option<BlockNumber>>(conn)
+        .map_err(StoreError::from)
+}
+
+/// The statement timeout in seconds for queries against the deployment
+/// `id` if it overrides `GRAPH_SQL_STATEMENT_TIMEOUT`
+pub fn statement_timeout(
+    conn: &PgConnection,
+    id: &DeploymentHash,
+) -> Result<Option<u64>, StoreError> {
+    use subgraph_deployment as d;
+
+    d::table
+        .filter(d::deployment.eq(id.as_str()))
+        .select(d::statement_timeout)
+        .first::<Option<i32>>(conn)
+        .map(|timeout| timeout.map(|timeout| timeout as u64))
+        .map_err(StoreError::from)
+}
+
+/// Set the statement timeout in seconds for queries against the deployment
+/// `id`, or go back to the default for `None`
+pub fn set_statement_timeout(
+    conn: &PgConnection,
+    id: &DeploymentHash,
+    timeout: Option<u32>,
+) -> Result<(), StoreError> {
+    use subgraph_deployment as d;
+
+    let timeout = timeout
+        .map(|timeout| {
+            i32::try_from(timeout)
+                .map_err(|_| anyhow!("the statement timeout {}s is too large", timeout))
+        })
+        .transpose()?;
+    update(d::table.filter(d::deployment.eq(id.as_str())))
+        .set(d::statement_timeout.eq(timeout))
+        .execute(conn)?;
+    Ok(())
+}
+
+/// Record that the tables and indexes of the deployment `id` take up
+/// `size` bytes on disk
+pub fn set_size_bytes(
+    conn: &PgConnection,
+    id: &DeploymentHash,
+    size: i64,
+) -> Result<(), StoreError> {
+    use subgraph_deployment as d;
+
+    update(d::table.filter(d::deployment.eq(id.as_str())))
+        .set(d::size_bytes.eq(size))
+        .execute(conn)?;
+    Ok(())
+}
+
+/// Record that entity versions of the deployment `id` that are not visible
+/// at `block` or later are being removed
+pub fn set_pruned_block(
+    conn: &PgConnection,
+    id: &DeploymentHash,
+    block: BlockNumber,
+) -> Result<(), StoreError> {
+    use subgraph_deployment as d;
+
+    update(d::table.filter(d::deployment.eq(id.as_str())))
+        .set(d::pruned_block.eq(block))
+        .execute(conn)?;
+    Ok(())
+}
+
+/// The lowest block that any of the deployments `ids` that have not
+/// failed has processed, or `None` if none of them has processed a block
+pub fn min_latest_block(
+    conn: &PgConnection,
+    ids: &[DeploymentId],
+) -> Result<Option<BlockNumber>, StoreError> {
+    use subgraph_deployment as d;
+
+    let min = d::table
+        .filter(d::id.eq(any(ids)))
+        .filter(d::failed.eq(false))
+        .select(min(d::latest_ethereum_block_number))
+        .first::<Option<BigDecimal>>(conn)?;
+    min.map(|min| {
+        min.to_i32().ok_or_else(|| {
+            constraint_violation!("latest block number {} does not fit into an i32", min)
+        })
+    })
+    .transpose()
+}
+
 /// Mark the deployment `id` as synced
 pub fn set_synced(conn: &PgConnection, id: &DeploymentHash) -> Result<(), StoreError> {
     use subgraph_deployment as d;
@@ -768,6 +970,8 @@ pub fn create_deployment(
                 repository,
                 features,
                 schema,
+                raw_yaml,
+                abis,
             },
         failed,
         health: _,
@@ -811,6 +1015,8 @@ pub fn create_deployment(
         m::features.eq(features),
         m::schema.eq(schema),
         m::graph_node_version_id.eq(graph_node_version_id),
+        m::raw_yaml.eq(raw_yaml),
+        m::abis.eq(abis),
     );
 
     if exists && replace {
@@ -3,7 +3,9 @@ use diesel::connection::SimpleConnection;
 use diesel::pg::PgConnection;
 use diesel::prelude::*;
 use diesel::r2d2::{ConnectionManager, PooledConnection};
-use graph::components::store::{EntityType, StoredDynamicDataSource};
+use graph::components::store::{
+    AggregateQuery, EntityType, StoredDynamicDataSource, REORG_THRESHOLD,
+};
 use graph::data::subgraph::status;
 use graph::prelude::{
     tokio, CancelHandle, CancelToken, CancelableError, PoolWaitStats, SubgraphDeploymentEntity,
@@ -19,7 +21,10 @@ use std::iter::FromIterator;
 use std::ops::Bound;
 use std::ops::Deref;
 use std::str::FromStr;
-use std::sync::{atomic::AtomicUsize, Arc, Mutex};
+use std::sync::{
+    atomic::{AtomicBool, AtomicUsize},
+    Arc, Mutex,
+};
 use std::time::Duration;
 use std::time::Instant;
 
@@ -28,7 +33,7 @@ use graph::components::subgraph::ProofOfIndexingFinisher;
 use graph::constraint_violation;
 use graph::data::subgraph::schema::{SubgraphError, POI_OBJECT};
 use graph::prelude::{
-    anyhow, debug, info, lazy_static, o, warn, web3, ApiSchema, AttributeNames, BlockNumber,
+    anyhow, debug, info, lazy_static, o, r, warn, web3, ApiSchema, AttributeNames, BlockNumber,
     BlockPtr, CheapClone, DeploymentHash, DeploymentState, Entity, EntityKey, EntityModification,
     EntityQuery, Error, Logger, QueryExecutionError, Schema, StopwatchMetrics, StoreError,
     StoreEvent, Value, BLOCK_NUMBER_MAX,
@@ -36,13 +41,18 @@ use graph::prelude::{
 use graph_graphql::prelude::api_schema;
 use web3::types::Address;
 
+use crate::advisory_lock;
 use crate::block_range::block_number;
 use crate::catalog;
 use crate::deployment;
-use crate::relational::{Layout, LayoutCache};
+use crate::relational::{Layout, LayoutCache, PruneReporter, STRING_PREFIX_SIZE};
 use crate::relational_queries::FromEntityData;
+use crate::write_queue::Request;
 use crate::{connection_pool::ConnectionPool, detail};
-use crate::{dynds, primary::Site};
+use crate::{
+    dynds,
+    primary::{DeploymentId, Site},
+};
 
 lazy_static! {
     /// `GRAPH_QUERY_STATS_REFRESH_INTERVAL` is how long statistics that
@@ -58,6 +68,44 @@ lazy_static! {
             Duration::from_secs(secs)
         }).unwrap_or(Duration::from_secs(300))
     };
+
+    /// `GRAPH_STORE_REPLICA_MAX_LAG` is how many seconds a read replica
+    /// can be behind its main database before we stop sending queries to
+    /// it. Replicas are sent queries again once they have caught up. By
+    /// default, the replication lag is not checked
+    static ref REPLICA_MAX_LAG: Option<f64> = {
+        env::var("GRAPH_STORE_REPLICA_MAX_LAG")
+        .ok()
+        .map(|s| {
+            f64::from_str(&s).unwrap_or_else(|_| {
+                panic!("GRAPH_STORE_REPLICA_MAX_LAG must be a number, but is `{}`", s)
+            })
+        })
+    };
+}
+
+/// How often we check how far read replicas are behind
+const REPLICA_LAG_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The index methods that `create_manual_index` accepts
+const MANUAL_INDEX_METHODS: &[&str] = &["btree", "hash", "gist", "spgist", "gin", "brin"];
+
+/// The problems that `DeploymentStore::check` found with a deployment
+#[derive(Debug, Default)]
+pub struct Inconsistencies {
+    /// The block pointer of the deployment
+    pub head: Option<BlockPtr>,
+    /// The entity types that have versions that were created or changed
+    /// after the block pointer, and how many such versions each has
+    pub versions: Vec<(EntityType, i64)>,
+    /// How many dynamic data sources were created after the block pointer
+    pub data_sources: i64,
+}
+
+impl Inconsistencies {
+    pub fn is_empty(&self) -> bool {
+        self.versions.is_empty() && self.data_sources == 0
+    }
 }
 
 /// When connected to read replicas, this allows choosing which DB server to use for an operation.
@@ -99,6 +147,9 @@ pub struct StoreInner {
     /// The current position in `replica_order` so we know which one to
     /// pick next
     conn_round_robin_counter: AtomicUsize,
+    /// For each read replica, whether it was more than
+    /// `REPLICA_MAX_LAG` behind the main database when we last checked
+    replica_lagging: Vec<AtomicBool>,
 
     /// A cache of commonly needed data about a subgraph.
     subgraph_cache: Mutex<LruCache<DeploymentHash, SubgraphInfo>>,
@@ -107,6 +158,22 @@ pub struct StoreInner {
     /// hosts this because it lives long enough, but it is managed from
     /// the entities module
     pub(crate) layout_cache: LayoutCache,
+
+    /// The advisory locks for the deployments that this process indexes.
+    /// Each lock is held on its own connection so that losing one
+    /// connection only affects the deployment whose lock it held. The
+    /// connections do not come from the pool, since a pooled connection
+    /// would be handed to other callers with our locks still held
+    indexing_locks: Mutex<HashMap<DeploymentId, IndexingLock>>,
+}
+
+/// A session lock for indexing a deployment, and how often this process
+/// has taken it
+struct IndexingLock {
+    /// The connection holding the lock; `None` if that connection was lost
+    /// and the lock with it
+    conn: Option<PgConnection>,
+    count: usize,
 }
 
 /// Storage of the data for individual deployments. Each `DeploymentStore`
@@ -154,6 +221,11 @@ impl DeploymentStore {
         replica_order.shuffle(&mut rng);
         debug!(logger, "Using postgres host order {:?}", replica_order);
 
+        let replica_lagging = read_only_pools
+            .iter()
+            .map(|_| AtomicBool::new(false))
+            .collect();
+
         // Create the store
         let store = StoreInner {
             logger: logger.clone(),
@@ -161,11 +233,17 @@ impl DeploymentStore {
             read_only_pools,
             replica_order,
             conn_round_robin_counter: AtomicUsize::new(0),
+            replica_lagging,
             subgraph_cache: Mutex::new(LruCache::with_capacity(100)),
             layout_cache: LayoutCache::new(*STATS_REFRESH_INTERVAL),
+            indexing_locks: Mutex::new(HashMap::new()),
         };
         let store = DeploymentStore(Arc::new(store));
 
+        if REPLICA_MAX_LAG.is_some() && !store.read_only_pools.is_empty() {
+            store.periodically_check_replica_lag();
+        }
+
         // Return the store
         store
     }
@@ -257,14 +335,31 @@ impl DeploymentStore {
         )
     }
 
+    pub(crate) fn aggregate(
+        &self,
+        conn: &PgConnection,
+        site: Arc<Site>,
+        query: AggregateQuery,
+    ) -> Result<Vec<BTreeMap<String, r::Value>>, QueryExecutionError> {
+        let layout = self.layout(conn, site)?;
+        layout.aggregate(
+            conn,
+            &query.entity_type,
+            query.filter.as_ref(),
+            query.group_by.as_deref(),
+            &query.attributes,
+            query.range,
+            query.block,
+        )
+    }
+
     fn check_interface_entity_uniqueness(
         &self,
         conn: &PgConnection,
         layout: &Layout,
-        key: &EntityKey,
+        entity_type: &EntityType,
+        entity_ids: &[&str],
     ) -> Result<(), StoreError> {
-        assert_eq!(&key.subgraph_id, &layout.site.deployment);
-
         // Collect all types that share an interface implementation with this
         // entity type, and make sure there are no conflicting IDs.
         //
@@ -277,25 +372,24 @@ impl DeploymentStore {
         // This assumes that there are no concurrent writes to a subgraph.
         let schema = self.subgraph_info_with_conn(&conn, &layout.site)?.api;
         let types_for_interface = schema.types_for_interface();
-        let entity_type = key.entity_type.to_string();
         let types_with_shared_interface = Vec::from_iter(
             schema
-                .interfaces_for_type(&key.entity_type)
+                .interfaces_for_type(entity_type)
                 .into_iter()
                 .flatten()
                 .map(|interface| &types_for_interface[&interface.into()])
                 .flatten()
                 .map(EntityType::from)
-                .filter(|type_name| type_name != &key.entity_type),
+                .filter(|type_name| type_name != entity_type),
         );
 
         if !types_with_shared_interface.is_empty() {
-            if let Some(conflicting_entity) =
-                layout.conflicting_entity(conn, &key.entity_id, types_with_shared_interface)?
+            if let Some((conflicting_entity, entity_id)) =
+                layout.conflicting_entities(conn, entity_ids, types_with_shared_interface)?
             {
                 return Err(StoreError::ConflictingId(
-                    entity_type.clone(),
-                    key.entity_id.clone(),
+                    entity_type.to_string(),
+                    entity_id,
                     conflicting_entity,
                 ));
             }
@@ -379,10 +473,8 @@ impl DeploymentStore {
         stopwatch: &StopwatchMetrics,
     ) -> Result<usize, StoreError> {
         let section = stopwatch.start_section("check_interface_entity_uniqueness");
-        for (key, _) in data.iter() {
-            // WARNING: This will potentially execute 2 queries for each entity key.
-            self.check_interface_entity_uniqueness(conn, layout, key)?;
-        }
+        let entity_ids: Vec<_> = data.iter().map(|(key, _)| key.entity_id.as_str()).collect();
+        self.check_interface_entity_uniqueness(conn, layout, entity_type, &entity_ids)?;
         section.end();
 
         let _section = stopwatch.start_section("apply_entity_modifications_insert");
@@ -399,10 +491,8 @@ impl DeploymentStore {
         stopwatch: &StopwatchMetrics,
     ) -> Result<usize, StoreError> {
         let section = stopwatch.start_section("check_interface_entity_uniqueness");
-        for (key, _) in data.iter() {
-            // WARNING: This will potentially execute 2 queries for each entity key.
-            self.check_interface_entity_uniqueness(conn, layout, key)?;
-        }
+        let entity_ids: Vec<_> = data.iter().map(|(key, _)| key.entity_id.as_str()).collect();
+        self.check_interface_entity_uniqueness(conn, layout, entity_type, &entity_ids)?;
         section.end();
 
         let _section = stopwatch.start_section("apply_entity_modifications_update");
@@ -506,6 +596,20 @@ impl DeploymentStore {
         Ok(conn)
     }
 
+    /// Cancel the query that the backend with process id `backend` in
+    /// `replica` is running
+    pub(crate) fn cancel_query(&self, replica: ReplicaId, backend: i32) {
+        let res = self
+            .get_replica_conn(replica)
+            .map_err(StoreError::from)
+            .and_then(|conn| catalog::cancel_backend(&conn, backend));
+        if let Err(e) = res {
+            warn!(self.logger, "Failed to cancel query";
+                               "backend" => backend,
+                               "error" => e.to_string());
+        }
+    }
+
     pub(crate) async fn query_permit(
         &self,
         replica: ReplicaId,
@@ -620,6 +724,20 @@ impl DeploymentStore {
         })
     }
 
+    /// Measure how much space the deployment takes up on disk and record
+    /// it in its metadata
+    pub(crate) fn refresh_size(&self, site: &Site) -> Result<i64, StoreError> {
+        let conn = self.get_conn()?;
+        let size = catalog::namespace_size(&conn, &site.namespace)?;
+        deployment::set_size_bytes(&conn, &site.deployment, size)?;
+        Ok(size)
+    }
+
+    pub(crate) fn deployment_sizes(&self) -> Result<Vec<status::DeploymentSize>, StoreError> {
+        let conn = self.get_conn()?;
+        detail::deployment_sizes(&conn, self.pool.shard.as_str())
+    }
+
     pub(crate) fn deployment_exists_and_synced(
         &self,
         id: &DeploymentHash,
@@ -633,6 +751,15 @@ impl DeploymentStore {
         conn.transaction(|| deployment::set_synced(&conn, id))
     }
 
+    pub(crate) fn update_sync_rate(
+        &self,
+        id: &DeploymentHash,
+        blocks_per_second: f64,
+    ) -> Result<(), StoreError> {
+        let conn = self.get_conn()?;
+        deployment::set_blocks_per_second(&conn, id, blocks_per_second)
+    }
+
     // Only used for tests
     #[cfg(debug_assertions)]
     pub(crate) fn drop_deployment_schema(
@@ -707,6 +834,50 @@ impl DeploymentStore {
         .map_err(Into::into)
     }
 
+    pub(crate) async fn entity_diff(
+        &self,
+        site: Arc<Site>,
+        from: BlockNumber,
+        to: BlockNumber,
+        after: Option<(String, String)>,
+        first: usize,
+    ) -> Result<Vec<status::EntityDiff>, StoreError> {
+        let store = self.clone();
+        self.with_conn(move |conn, cancel| {
+            let latest = Self::block_ptr_with_conn(&site.deployment, conn)?
+                .map(|ptr| ptr.number)
+                .unwrap_or(-1);
+            if to > latest {
+                return Err(StoreError::QueryExecutionError(format!(
+                    "subgraph {} has only indexed up to block number {} \
+                     and changes up to block number {} are therefore not yet available",
+                    site.deployment, latest, to
+                ))
+                .into());
+            }
+            if let Some(pruned_block) = deployment::pruned_block(conn, &site.deployment)? {
+                if from < pruned_block {
+                    return Err(StoreError::QueryExecutionError(format!(
+                        "subgraph {} was pruned and only has data from block number {} on",
+                        site.deployment, pruned_block
+                    ))
+                    .into());
+                }
+            }
+
+            cancel.check_cancel()?;
+            let layout = store.layout(conn, site)?;
+            cancel.check_cancel()?;
+            let after = after
+                .as_ref()
+                .map(|(entity_type, id)| (entity_type.as_str(), id.as_str()));
+            layout
+                .entity_diff(conn, from, to, after, first)
+                .map_err(CancelableError::from)
+        })
+        .await
+    }
+
     pub(crate) async fn get_proof_of_indexing(
         &self,
         site: Arc<Site>,
@@ -838,19 +1009,18 @@ impl DeploymentStore {
         self.execute_query(&conn, site, query)
     }
 
+    /// Write the changes for `blocks`, which must be consecutive and
+    /// ordered oldest first, in one transaction. Writing several blocks at
+    /// once saves a commit and the block pointer update for each of them
     pub(crate) fn transact_block_operations(
         &self,
         site: Arc<Site>,
-        block_ptr_to: &BlockPtr,
-        firehose_cursor: Option<&str>,
-        mods: &[EntityModification],
-        stopwatch: StopwatchMetrics,
-        data_sources: &[StoredDynamicDataSource],
-        deterministic_errors: &[SubgraphError],
+        blocks: &[Arc<Request>],
     ) -> Result<StoreEvent, StoreError> {
         // All operations should apply only to data or metadata for this subgraph
-        if mods
+        if blocks
             .iter()
+            .flat_map(|block| block.mods.iter())
             .map(|modification| modification.entity_key())
             .any(|key| key.subgraph_id != site.deployment)
         {
@@ -860,8 +1030,15 @@ impl DeploymentStore {
             );
         }
 
+        let last = match blocks.last() {
+            Some(last) => last,
+            None => return Ok(StoreEvent::new(vec![])),
+        };
+
+        self.check_indexing_lock(site.as_ref())?;
+
         let conn = {
-            let _section = stopwatch.start_section("transact_blocks_get_conn");
+            let _section = last.stopwatch.start_section("transact_blocks_get_conn");
             self.get_conn()?
         };
 
@@ -870,40 +1047,48 @@ impl DeploymentStore {
             // wait with sending it until we have done all our other work
             // so that we do not hold a lock on the notification queue
             // for longer than we have to
-            let event: StoreEvent = mods.iter().collect();
+            let event: StoreEvent = blocks.iter().flat_map(|block| block.mods.iter()).collect();
 
             // Make the changes
             let layout = self.layout(&conn, site.clone())?;
-            let section = stopwatch.start_section("apply_entity_modifications");
-            let count = self.apply_entity_modifications(
-                &conn,
-                layout.as_ref(),
-                mods,
-                &block_ptr_to,
-                stopwatch,
-            )?;
+            let mut count = 0;
+            for block in blocks {
+                let section = block.stopwatch.start_section("apply_entity_modifications");
+                count += self.apply_entity_modifications(
+                    &conn,
+                    layout.as_ref(),
+                    &block.mods,
+                    &block.block_ptr,
+                    block.stopwatch.cheap_clone(),
+                )?;
+                section.end();
+
+                dynds::insert(
+                    &conn,
+                    &site.deployment,
+                    &block.data_sources,
+                    &block.block_ptr,
+                )?;
+
+                if !block.deterministic_errors.is_empty() {
+                    deployment::insert_subgraph_errors(
+                        &conn,
+                        &site.deployment,
+                        &block.deterministic_errors,
+                        block.block_ptr.block_number(),
+                    )?;
+                }
+            }
             deployment::update_entity_count(
                 &conn,
                 site.as_ref(),
                 layout.count_query.as_str(),
                 count,
             )?;
-            section.end();
-
-            dynds::insert(&conn, &site.deployment, data_sources, &block_ptr_to)?;
-
-            if !deterministic_errors.is_empty() {
-                deployment::insert_subgraph_errors(
-                    &conn,
-                    &site.deployment,
-                    deterministic_errors,
-                    block_ptr_to.block_number(),
-                )?;
-            }
 
-            deployment::forward_block_ptr(&conn, &site.deployment, block_ptr_to)?;
+            deployment::forward_block_ptr(&conn, &site.deployment, &last.block_ptr)?;
 
-            if let Some(cursor) = firehose_cursor {
+            if let Some(cursor) = last.firehose_cursor.as_deref() {
                 if cursor != "" {
                     deployment::update_firehose_cursor(&conn, &site.deployment, &cursor)?;
                 }
@@ -922,6 +1107,21 @@ impl DeploymentStore {
         block_ptr_to: BlockPtr,
     ) -> Result<StoreEvent, StoreError> {
         let event = conn.transaction(|| -> Result<_, StoreError> {
+            // Don't revert past the point to which the deployment was
+            // pruned; we don't have the data to do that anymore
+            if let Some(pruned_block) = deployment::pruned_block(&conn, &site.deployment)? {
+                if pruned_block > block_ptr_to.number {
+                    return Err(anyhow!(
+                        "Can not revert subgraph `{}` to block {} as it was \
+                        pruned and only has data from block {} on",
+                        site.deployment.clone(),
+                        block_ptr_to.number,
+                        pruned_block
+                    )
+                    .into());
+                }
+            }
+
             // Don't revert past a graft point
             let info = self.subgraph_info_with_conn(&conn, site.as_ref())?;
             if let Some(graft_block) = info.graft_block {
@@ -973,6 +1173,418 @@ impl DeploymentStore {
         Ok(event)
     }
 
+    /// Remove entity versions of the deployment that are only visible more
+    /// than `history_blocks` blocks before its current block. Returns the
+    /// earliest block for which the deployment still has complete data,
+    /// or `None` if somebody else is pruning the deployment right now
+    pub(crate) fn prune(
+        &self,
+        reporter: &mut dyn PruneReporter,
+        site: Arc<Site>,
+        history_blocks: BlockNumber,
+    ) -> Result<Option<BlockNumber>, StoreError> {
+        if history_blocks <= *REORG_THRESHOLD {
+            return Err(StoreError::QueryExecutionError(format!(
+                "deployments must keep more than {} blocks of history so that \
+                 they can handle reorgs, but only {} blocks should be kept",
+                *REORG_THRESHOLD, history_blocks
+            )));
+        }
+
+        let conn = self.get_conn()?;
+        if !advisory_lock::try_lock_pruning(&conn, &site)? {
+            return Ok(None);
+        }
+        let res = (|| -> Result<_, StoreError> {
+            let latest = Self::block_ptr_with_conn(&site.deployment, &conn)?
+                .map(|ptr| ptr.number)
+                .unwrap_or(0);
+            // Pruning with more history than before can not bring back
+            // what was already removed
+            let pruned_block = deployment::pruned_block(&conn, &site.deployment)?.unwrap_or(0);
+            let earliest = (latest - history_blocks).max(pruned_block);
+
+            // Stop queries for blocks we are about to remove before we
+            // remove anything
+            deployment::set_pruned_block(&conn, &site.deployment, earliest)?;
+            let layout = self.layout(&conn, site.clone())?;
+            layout.prune(&conn, earliest, reporter)?;
+            Ok(Some(earliest))
+        })();
+        advisory_lock::unlock_pruning(&conn, &site)?;
+        res
+    }
+
+    /// Try to get the lock that keeps other graph-node processes from
+    /// indexing `site`. Returns `false` if another process holds it.
+    /// Locking the same deployment several times from this process
+    /// succeeds, and each lock needs to be released with `unlock_indexing`
+    pub(crate) fn try_lock_indexing(&self, site: &Site) -> Result<bool, StoreError> {
+        let mut locks = self.indexing_locks.lock().unwrap();
+        let lock = locks.entry(site.id).or_insert(IndexingLock {
+            conn: None,
+            count: 0,
+        });
+        let res = Self::relock_indexing(&self.pool, lock, site);
+        match res {
+            Ok(true) => lock.count += 1,
+            Ok(false) | Err(_) => {
+                if lock.count == 0 {
+                    locks.remove(&site.id);
+                }
+            }
+        }
+        res
+    }
+
+    /// Take the indexing lock for `site` on the connection of `lock`,
+    /// opening a new connection if the old one was lost
+    fn relock_indexing(
+        pool: &ConnectionPool,
+        lock: &mut IndexingLock,
+        site: &Site,
+    ) -> Result<bool, StoreError> {
+        if lock.conn.is_none() {
+            lock.conn = Some(pool.dedicated_conn()?);
+        }
+        let res = advisory_lock::try_lock_indexing(lock.conn.as_ref().unwrap(), site);
+        if !matches!(res, Ok(true)) {
+            // The connection might be broken. Closing it makes the database
+            // release the lock if it still holds it
+            lock.conn = None;
+        }
+        res
+    }
+
+    /// Make sure that this process still holds the indexing lock for
+    /// `site` if it took it. If the connection holding the lock was lost,
+    /// return an error so that the deployment fails; the next check then
+    /// tries to take the lock again
+    fn check_indexing_lock(&self, site: &Site) -> Result<(), StoreError> {
+        let mut locks = self.indexing_locks.lock().unwrap();
+        let lock = match locks.get_mut(&site.id) {
+            Some(lock) => lock,
+            None => return Ok(()),
+        };
+        let held = match lock.conn.as_ref() {
+            Some(conn) => advisory_lock::holds_indexing_lock(conn, site).unwrap_or(false),
+            None => Self::relock_indexing(&self.pool, lock, site)?,
+        };
+        if held {
+            return Ok(());
+        }
+        lock.conn = None;
+        Err(StoreError::Unknown(anyhow!(
+            "lost the lock that keeps other graph-node processes from indexing {}",
+            site.deployment
+        )))
+    }
+
+    /// Whether any process, including this one, holds the indexing lock
+    /// for `site`
+    pub(crate) fn is_indexing(&self, site: &Site) -> Result<bool, StoreError> {
+        let conn = self.get_conn()?;
+        advisory_lock::is_indexing(&conn, site)
+    }
+
+    pub(crate) fn unlock_indexing(&self, site: &Site) -> Result<(), StoreError> {
+        let mut locks = self.indexing_locks.lock().unwrap();
+        let lock = match locks.get_mut(&site.id) {
+            Some(lock) => lock,
+            None => {
+                return Err(constraint_violation!(
+                    "the indexing lock for {} was not held",
+                    site.deployment
+                ))
+            }
+        };
+        // The connection holds the lock until the last of our users
+        // releases it, and closing the connection releases the lock
+        lock.count -= 1;
+        if lock.count == 0 {
+            locks.remove(&site.id);
+        }
+        Ok(())
+    }
+
+    /// Partition tables of `site` into partitions of `blocks` blocks each.
+    /// If `tables` is empty, partition all tables that are not partitioned
+    /// yet and have an estimated `min_rows` rows or more; otherwise,
+    /// partition the tables for the entity types in `tables`. Returns the
+    /// names of the tables that were partitioned and how many rows each of
+    /// them has
+    pub(crate) fn partition(
+        &self,
+        site: Arc<Site>,
+        tables: &[String],
+        blocks: BlockNumber,
+        min_rows: i64,
+    ) -> Result<Vec<(String, usize)>, StoreError> {
+        if blocks <= 0 {
+            return Err(StoreError::QueryExecutionError(format!(
+                "partitions must cover at least one block, but would cover {}",
+                blocks
+            )));
+        }
+
+        let conn = self.get_conn()?;
+        let layout = self.layout(&conn, site.clone())?;
+        let mut candidates = Vec::new();
+        if tables.is_empty() {
+            for table in layout.tables.values() {
+                if table.object == *POI_OBJECT || table.partition_blocks.is_some() {
+                    continue;
+                }
+                if layout.estimated_rows(&conn, table)? >= min_rows {
+                    candidates.push(table.clone());
+                }
+            }
+        } else {
+            for name in tables {
+                let table = layout.table_for_entity(&EntityType::from(name.as_str()))?;
+                if table.partition_blocks.is_some() {
+                    return Err(StoreError::QueryExecutionError(format!(
+                        "the table for {} is already partitioned",
+                        name
+                    )));
+                }
+                candidates.push(table.clone());
+            }
+        }
+        candidates.sort_by(|a, b| a.name.as_str().cmp(b.name.as_str()));
+
+        // Pruning the deployment while we copy its tables would be wasted
+        // effort at best
+        if !advisory_lock::try_lock_pruning(&conn, &site)? {
+            return Err(StoreError::QueryExecutionError(format!(
+                "{} is being pruned; try again once that is finished",
+                site.deployment
+            )));
+        }
+        let res = (|| -> Result<_, StoreError> {
+            let head = Self::block_ptr_with_conn(&site.deployment, &conn)?
+                .map(|ptr| ptr.number)
+                .unwrap_or(0);
+            let mut partitioned = Vec::new();
+            for table in candidates {
+                let rows = conn.transaction(|| layout.partition(&conn, &table, blocks, head))?;
+                partitioned.push((table.name.to_string(), rows));
+            }
+            Ok(partitioned)
+        })();
+        advisory_lock::unlock_pruning(&conn, &site)?;
+        res
+    }
+
+    /// Create the partitions that the partitioned tables of `site` need
+    /// for the blocks that the deployment will index next. Returns the
+    /// number of partitions that were created
+    pub(crate) fn extend_partitions(&self, site: Arc<Site>) -> Result<usize, StoreError> {
+        let conn = self.get_conn()?;
+        let layout = self.layout(&conn, site.clone())?;
+        let head = Self::block_ptr_with_conn(&site.deployment, &conn)?
+            .map(|ptr| ptr.number)
+            .unwrap_or(0);
+        let mut created = 0;
+        for table in layout.tables.values() {
+            created += layout.extend_partitions(&conn, table, head)?;
+        }
+        Ok(created)
+    }
+
+    /// Check that the data of the deployment matches its block pointer:
+    /// there must be no entity versions and no dynamic data sources from
+    /// blocks after it. Such leftovers mean that the block pointer and the
+    /// data were not changed together, for example because of a crash
+    /// while the database was being restored. With `repair`, remove the
+    /// leftovers, the same way a revert to the block pointer would. The
+    /// deployment must not be indexed while it is repaired; the indexing
+    /// lock is held for the transaction that repairs it so that it is
+    /// released when the transaction ends, no matter how it ends
+    pub(crate) fn check(
+        &self,
+        site: Arc<Site>,
+        repair: bool,
+    ) -> Result<Inconsistencies, StoreError> {
+        let conn = self.get_conn()?;
+        let layout = self.layout(&conn, site.clone())?;
+        conn.transaction(|| {
+            if repair && !advisory_lock::try_lock_indexing_xact(&conn, &site)? {
+                return Err(StoreError::QueryExecutionError(format!(
+                    "{} is being indexed; unassign it before repairing it",
+                    site.deployment
+                )));
+            }
+            let head = Self::block_ptr_with_conn(&site.deployment, &conn)?;
+            // A deployment that has not processed any blocks yet should
+            // not have any data
+            let number = head.as_ref().map(|ptr| ptr.number).unwrap_or(-1);
+            let found = Inconsistencies {
+                head,
+                versions: layout.versions_after(&conn, number)?,
+                data_sources: dynds::count_after(&conn, &site.deployment, number)?,
+            };
+            if repair && !found.is_empty() {
+                let (_, count) = layout.revert_block(&conn, &site.deployment, number + 1)?;
+                dynds::revert(&conn, &site.deployment, number + 1)?;
+                deployment::update_entity_count(
+                    &conn,
+                    site.as_ref(),
+                    layout.count_query.as_str(),
+                    count,
+                )?;
+            }
+            Ok(found)
+        })
+    }
+
+    /// Create an index named `manual_<table>_<columns>` on the attributes
+    /// `fields` of `entity`, using the index method `method`. The index is
+    /// built concurrently so that indexing and queries are not blocked
+    /// while it is created, except for partitioned tables, where Postgres
+    /// does not support that. Returns the name of the index
+    pub(crate) fn create_manual_index(
+        &self,
+        site: Arc<Site>,
+        entity: &str,
+        fields: &[String],
+        method: &str,
+    ) -> Result<String, StoreError> {
+        if !MANUAL_INDEX_METHODS.contains(&method) {
+            return Err(StoreError::QueryExecutionError(format!(
+                "unknown index method `{}`; it must be one of {}",
+                method,
+                MANUAL_INDEX_METHODS.join(", ")
+            )));
+        }
+        if fields.is_empty() {
+            return Err(StoreError::QueryExecutionError(
+                "an index needs at least one attribute".to_string(),
+            ));
+        }
+
+        let conn = self.get_conn()?;
+        let layout = self.layout(&conn, site)?;
+        let table = layout.table_for_entity(&EntityType::from(entity))?;
+        let columns = fields
+            .iter()
+            .map(|field| table.column_for_field(field))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let name = format!(
+            "manual_{}_{}",
+            table.name,
+            columns
+                .iter()
+                .map(|column| column.name.as_str())
+                .collect::<Vec<_>>()
+                .join("_")
+        );
+        // Postgres would silently truncate longer names, and we could then
+        // not find the index by its name anymore
+        if name.len() > 63 {
+            return Err(StoreError::QueryExecutionError(format!(
+                "the index name `{}` is longer than 63 characters; use fewer attributes",
+                name
+            )));
+        }
+        let exprs = columns
+            .iter()
+            .map(|column| {
+                // Like the default indexes, only index a prefix of strings
+                // in BTrees since Postgres limits the size of their entries
+                if method == "btree" && column.is_text() {
+                    format!("left({}, {})", column.name.quoted(), STRING_PREFIX_SIZE)
+                } else {
+                    column.name.quoted()
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        let concurrently = if table.partition_blocks.is_some() {
+            ""
+        } else {
+            "concurrently"
+        };
+        let query = format!(
+            "create index {concurrently} if not exists {name} on {table} using {method}({exprs})",
+            concurrently = concurrently,
+            name = name,
+            table = table.qualified_name,
+            method = method,
+            exprs = exprs
+        );
+        conn.batch_execute(&query)?;
+        Ok(name)
+    }
+
+    /// The indexes on the table for `entity`
+    pub(crate) fn indexes_for_entity(
+        &self,
+        site: Arc<Site>,
+        entity: &str,
+    ) -> Result<Vec<catalog::IndexInfo>, StoreError> {
+        let conn = self.get_conn()?;
+        let layout = self.layout(&conn, site.clone())?;
+        let table = layout.table_for_entity(&EntityType::from(entity))?;
+        catalog::table_indexes(&conn, &site.namespace, &table.name)
+    }
+
+    /// Drop the index `name` that was created with `create_manual_index`.
+    /// Indexes that `graph-node` creates itself can not be dropped
+    pub(crate) fn drop_manual_index(&self, site: Arc<Site>, name: &str) -> Result<(), StoreError> {
+        if !name.starts_with("manual_") {
+            return Err(StoreError::QueryExecutionError(format!(
+                "only indexes created with `graphman index create` can be dropped, but `{}` was not",
+                name
+            )));
+        }
+        let conn = self.get_conn()?;
+        let partitioned =
+            catalog::index_is_partitioned(&conn, &site.namespace, name)?.ok_or_else(|| {
+                StoreError::QueryExecutionError(format!(
+                    "deployment {} has no index `{}`",
+                    site.deployment, name
+                ))
+            })?;
+        let concurrently = if partitioned { "" } else { "concurrently" };
+        let query = format!(
+            "drop index {} if exists \"{}\".\"{}\"",
+            concurrently, site.namespace, name
+        );
+        Ok(conn.batch_execute(&query)?)
+    }
+
+    pub(crate) fn raw_manifest(&self, site: &Site) -> Result<Option<String>, StoreError> {
+        let conn = self.get_conn()?;
+        deployment::raw_manifest(&conn, site)
+    }
+
+    pub(crate) fn abis(&self, site: &Site) -> Result<Option<String>, StoreError> {
+        let conn = self.get_conn()?;
+        deployment::abis(&conn, site)
+    }
+
+    pub(crate) fn min_latest_block(
+        &self,
+        ids: &[DeploymentId],
+    ) -> Result<Option<BlockNumber>, StoreError> {
+        let conn = self.get_conn()?;
+        deployment::min_latest_block(&conn, ids)
+    }
+
+    /// The block before which entity versions of the deployment have been
+    /// removed, if it was ever pruned
+    pub(crate) fn pruned_block(&self, site: &Site) -> Result<Option<BlockNumber>, StoreError> {
+        let conn = self.get_conn()?;
+        deployment::pruned_block(&conn, &site.deployment)
+    }
+
+    pub(crate) fn partitioned_deployments(&self) -> Result<Vec<DeploymentId>, StoreError> {
+        let conn = self.get_conn()?;
+        catalog::partitioned_deployments(&conn)
+    }
+
     pub(crate) fn rewind(
         &self,
         site: Arc<Site>,
@@ -980,16 +1592,23 @@ impl DeploymentStore {
     ) -> Result<StoreEvent, StoreError> {
         let conn = self.get_conn()?;
 
-        // Unwrap: If we are reverting then the block ptr is not `None`.
-        let block_ptr_from = Self::block_ptr_with_conn(&site.deployment, &conn)?.unwrap();
+        let block_ptr_from = match Self::block_ptr_with_conn(&site.deployment, &conn)? {
+            Some(ptr) => ptr,
+            None => {
+                return Err(constraint_violation!(
+                    "can not rewind deployment {} since it has not processed any blocks",
+                    site.deployment
+                ))
+            }
+        };
 
         // Sanity check on block numbers
         if block_ptr_from.number <= block_ptr_to.number {
-            constraint_violation!(
+            return Err(constraint_violation!(
                 "rewind must go backwards, but would go from block {} to block {}",
                 block_ptr_from.number,
                 block_ptr_to.number
-            );
+            ));
         }
         self.rewind_with_conn(&conn, site, block_ptr_to)
     }
@@ -1040,12 +1659,21 @@ impl DeploymentStore {
 
         let replica_id = match for_subscription {
             // Pick a weighted ReplicaId. `replica_order` contains a list of
-            // replicas with repetitions according to their weight
+            // replicas with repetitions according to their weight. Skip
+            // replicas that are too far behind, and use the main database
+            // if all of them are
             false => {
                 let weights_count = self.replica_order.len();
-                let index =
-                    self.conn_round_robin_counter.fetch_add(1, Ordering::SeqCst) % weights_count;
-                *self.replica_order.get(index).unwrap()
+                let start = self.conn_round_robin_counter.fetch_add(1, Ordering::SeqCst);
+                (0..weights_count)
+                    .map(|offset| self.replica_order[(start + offset) % weights_count])
+                    .find(|replica| match replica {
+                        ReplicaId::Main => true,
+                        ReplicaId::ReadOnly(idx) => {
+                            !self.replica_lagging[*idx].load(Ordering::SeqCst)
+                        }
+                    })
+                    .unwrap_or(ReplicaId::Main)
             }
             // Subscriptions always go to the main replica.
             true => ReplicaId::Main,
@@ -1054,6 +1682,77 @@ impl DeploymentStore {
         Ok(replica_id)
     }
 
+    /// Check how far the read replicas are behind every
+    /// `REPLICA_LAG_CHECK_INTERVAL` in the background so that picking a
+    /// replica for a query never waits for the check. The task stops once
+    /// the store is dropped
+    fn periodically_check_replica_lag(&self) {
+        let store = Arc::downgrade(&self.0);
+        graph::spawn(async move {
+            let mut interval = tokio::time::interval(REPLICA_LAG_CHECK_INTERVAL);
+            loop {
+                interval.tick().await;
+                let store = match store.upgrade() {
+                    Some(store) => DeploymentStore(store),
+                    None => return,
+                };
+                let _ = graph::spawn_blocking_allow_panic(move || store.check_replica_lag()).await;
+            }
+        });
+    }
+
+    /// Mark the read replicas that are more than `REPLICA_MAX_LAG` behind
+    /// their main database as lagging
+    fn check_replica_lag(&self) {
+        use std::sync::atomic::Ordering;
+
+        #[derive(QueryableByName)]
+        struct Lag {
+            #[sql_type = "diesel::sql_types::Double"]
+            lag: f64,
+        }
+
+        let max_lag = match *REPLICA_MAX_LAG {
+            Some(max_lag) if !self.read_only_pools.is_empty() => max_lag,
+            _ => return,
+        };
+
+        // A replica that has replayed everything it received is not
+        // behind, even if the last transaction it replayed is old because
+        // nothing was written to the main database in a while
+        const LAG_QUERY: &str = "select case \
+               when pg_last_wal_receive_lsn() = pg_last_wal_replay_lsn() then 0 \
+               else coalesce(extract(epoch from now() - pg_last_xact_replay_timestamp()), 0) \
+             end::float8 as lag";
+
+        for (idx, lagging) in self.replica_lagging.iter().enumerate() {
+            let lag = self
+                .read_only_conn(idx)
+                .and_then(|conn| Ok(diesel::sql_query(LAG_QUERY).get_result::<Lag>(&conn)?));
+            let lag = match lag {
+                Ok(Lag { lag }) => lag,
+                Err(e) => {
+                    // We can't tell; keep using the replica and let
+                    // queries fail if it really is unusable
+                    warn!(self.logger, "Failed to check replication lag";
+                                       "replica" => idx, "error" => e.to_string());
+                    lagging.store(false, Ordering::SeqCst);
+                    continue;
+                }
+            };
+
+            let is_lagging = lag > max_lag;
+            let was_lagging = lagging.swap(is_lagging, Ordering::SeqCst);
+            if is_lagging && !was_lagging {
+                warn!(self.logger, "Read replica is too far behind, not sending queries to it";
+                                   "replica" => idx, "lag_s" => lag, "max_lag_s" => max_lag);
+            } else if !is_lagging && was_lagging {
+                info!(self.logger, "Read replica caught up, sending queries to it again";
+                                   "replica" => idx, "lag_s" => lag);
+            }
+        }
+    }
+
     pub(crate) async fn load_dynamic_data_sources(
         &self,
         id: DeploymentHash,
@@ -1351,6 +2050,24 @@ impl DeploymentStore {
         deployment::error_count(&conn, id)
     }
 
+    pub(crate) fn set_queries_disabled(
+        &self,
+        site: Arc<Site>,
+        disabled: bool,
+    ) -> Result<(), StoreError> {
+        let conn = self.get_conn()?;
+        deployment::set_queries_disabled(&conn, &site.deployment, disabled)
+    }
+
+    pub(crate) fn set_statement_timeout(
+        &self,
+        site: Arc<Site>,
+        timeout: Option<u32>,
+    ) -> Result<(), StoreError> {
+        let conn = self.get_conn()?;
+        deployment::set_statement_timeout(&conn, &site.deployment, timeout)
+    }
+
     pub(crate) async fn mirror_primary_tables(&self, logger: &Logger) {
         self.pool.mirror_primary_tables().await.unwrap_or_else(|e| {
             warn!(logger, "Mirroring primary tables failed. We will try again in a few minutes";
@@ -62,6 +62,11 @@ pub struct DeploymentDetail {
     current_reorg_depth: i32,
     max_reorg_depth: i32,
     firehose_cursor: Option<String>,
+    queries_disabled: bool,
+    blocks_per_second: Option<f64>,
+    pruned_block: Option<i32>,
+    statement_timeout: Option<i32>,
+    size_bytes: Option<i64>,
 }
 
 #[derive(Queryable, QueryableByName)]
@@ -181,6 +186,7 @@ impl<'a> TryFrom<DetailAndError<'a>> for status::Info {
             graft_base: _,
             graft_block_hash: _,
             graft_block_number: _,
+            blocks_per_second,
             ..
         } = detail;
 
@@ -229,6 +235,7 @@ impl<'a> TryFrom<DetailAndError<'a>> for status::Info {
             chains: vec![chain],
             entity_count,
             node: None,
+            blocks_per_second,
         })
     }
 }
@@ -279,6 +286,34 @@ pub(crate) fn deployment_statuses(
     }
 }
 
+/// The sizes of all deployments in the shard that `conn` is connected to
+pub(crate) fn deployment_sizes(
+    conn: &PgConnection,
+    shard: &str,
+) -> Result<Vec<status::DeploymentSize>, StoreError> {
+    use subgraph_deployment as d;
+
+    d::table
+        .select((d::deployment, d::entity_count, d::size_bytes))
+        .load::<(String, BigDecimal, Option<i64>)>(conn)?
+        .into_iter()
+        .map(|(deployment, entity_count, size_bytes)| {
+            let entity_count = entity_count.to_u64().ok_or_else(|| {
+                constraint_violation!(
+                    "the entityCount for {} is not representable as a u64",
+                    deployment
+                )
+            })?;
+            Ok(status::DeploymentSize {
+                subgraph: deployment,
+                shard: shard.to_string(),
+                entity_count,
+                size_bytes: size_bytes.map(|size| size.max(0) as u64),
+            })
+        })
+        .collect()
+}
+
 #[derive(Queryable, QueryableByName, Identifiable, Associations)]
 #[table_name = "subgraph_manifest"]
 #[belongs_to(GraphNodeVersion)]
@@ -293,6 +328,8 @@ struct StoredSubgraphManifest {
     features: Vec<String>,
     schema: String,
     graph_node_version_id: Option<i32>,
+    raw_yaml: Option<String>,
+    abis: Option<String>,
 }
 
 impl From<StoredSubgraphManifest> for SubgraphManifestEntity {
@@ -303,6 +340,8 @@ impl From<StoredSubgraphManifest> for SubgraphManifestEntity {
             repository: value.repository,
             features: value.features,
             schema: value.schema,
+            raw_yaml: value.raw_yaml,
+            abis: value.abis,
         }
     }
 }
@@ -224,6 +224,22 @@ pub(crate) fn revert(
     Ok(())
 }
 
+/// The number of dynamic data sources of deployment `id` that were
+/// created at a block after `block`
+pub(crate) fn count_after(
+    conn: &PgConnection,
+    id: &DeploymentHash,
+    block: BlockNumber,
+) -> Result<i64, StoreError> {
+    use dynamic_ethereum_contract_data_source as decds;
+
+    Ok(decds::table
+        .filter(decds::deployment.eq(id.as_str()))
+        .filter(decds::ethereum_block_number.gt(sql(&block.to_string())))
+        .select(count(decds::vid))
+        .get_result(conn)?)
+}
+
 pub(crate) fn drop(conn: &PgConnection, id: &DeploymentHash) -> Result<usize, StoreError> {
     use dynamic_ethereum_contract_data_source as decds;
 
@@ -6,12 +6,48 @@ use std::time::Duration;
 use async_trait::async_trait;
 use diesel::{prelude::RunQueryDsl, sql_query, sql_types::Double};
 
-use graph::prelude::{error, Logger, MetricsRegistry, StoreError};
-use graph::prometheus::Gauge;
+use graph::components::store::REORG_THRESHOLD;
+use graph::prelude::{
+    error, info, lazy_static, o, BlockNumber, Logger, MetricsRegistry, StoreError,
+};
+use graph::prometheus::{Gauge, GaugeVec};
 use graph::util::jobs::{Job, Runner};
 
 use crate::connection_pool::ConnectionPool;
-use crate::{Store, SubgraphStore};
+use crate::{PruneReporter, Store, SubgraphStore};
+
+lazy_static! {
+    /// `GRAPH_STORE_HISTORY_BLOCKS` is how many blocks of history each
+    /// deployment keeps. Older entity versions are removed periodically.
+    /// By default, all history is kept
+    static ref HISTORY_BLOCKS: Option<BlockNumber> = std::env::var("GRAPH_STORE_HISTORY_BLOCKS")
+        .ok()
+        .map(|s| {
+            s.parse::<BlockNumber>().unwrap_or_else(|_| {
+                panic!("GRAPH_STORE_HISTORY_BLOCKS must be a number, but is `{}`", s)
+            })
+        });
+
+    /// Whether to periodically remove blocks that no deployment needs
+    /// anymore from the block cache
+    static ref CLEANUP_BLOCKS: bool = std::env::var("GRAPH_ETHEREUM_CLEANUP_BLOCKS")
+        .ok()
+        .map(|s| s.eq_ignore_ascii_case("true"))
+        .unwrap_or(false);
+
+    /// `GRAPH_STORE_BLOCK_CACHE_RETENTION` is how many blocks behind the
+    /// chain head the block cache keeps when blocks are cleaned up. It
+    /// can not be lower than the reorg threshold
+    static ref BLOCK_CACHE_RETENTION: BlockNumber = std::env::var("GRAPH_STORE_BLOCK_CACHE_RETENTION")
+        .ok()
+        .map(|s| {
+            s.parse::<BlockNumber>().unwrap_or_else(|_| {
+                panic!("GRAPH_STORE_BLOCK_CACHE_RETENTION must be a number, but is `{}`", s)
+            })
+        })
+        .unwrap_or(*REORG_THRESHOLD)
+        .max(*REORG_THRESHOLD);
+}
 
 pub fn register(
     runner: &mut Runner,
@@ -25,7 +61,7 @@ pub fn register(
     );
 
     runner.register(
-        Arc::new(NotificationQueueUsage::new(primary_pool, registry)),
+        Arc::new(NotificationQueueUsage::new(primary_pool, registry.clone())),
         Duration::from_secs(60),
     );
 
@@ -33,6 +69,35 @@ pub fn register(
         Arc::new(MirrorPrimary::new(store.subgraph_store())),
         Duration::from_secs(15 * 60),
     );
+
+    runner.register(
+        Arc::new(ExtendPartitionsJob::new(store.subgraph_store())),
+        Duration::from_secs(10 * 60),
+    );
+
+    runner.register(
+        Arc::new(ActivateCopiesJob::new(store.subgraph_store())),
+        Duration::from_secs(60),
+    );
+
+    runner.register(
+        Arc::new(DeploymentSizeJob::new(store.subgraph_store(), registry)),
+        Duration::from_secs(30 * 60),
+    );
+
+    if let Some(history_blocks) = *HISTORY_BLOCKS {
+        runner.register(
+            Arc::new(PruneJob::new(store.subgraph_store(), history_blocks)),
+            Duration::from_secs(60 * 60),
+        );
+    }
+
+    if *CLEANUP_BLOCKS {
+        runner.register(
+            Arc::new(BlockCacheGcJob::new(store, *BLOCK_CACHE_RETENTION)),
+            Duration::from_secs(5 * 60),
+        );
+    }
 }
 
 /// A job that vacuums `subgraphs.subgraph_deployment`. With a large number
@@ -141,3 +206,246 @@ impl Job for MirrorPrimary {
         self.store.mirror_primary_tables(logger).await;
     }
 }
+
+/// A job that creates partitions for partitioned tables before the
+/// deployments they belong to reach the blocks those partitions are for
+struct ExtendPartitionsJob {
+    store: Arc<SubgraphStore>,
+}
+
+impl ExtendPartitionsJob {
+    fn new(store: Arc<SubgraphStore>) -> ExtendPartitionsJob {
+        ExtendPartitionsJob { store }
+    }
+}
+
+#[async_trait]
+impl Job for ExtendPartitionsJob {
+    fn name(&self) -> &str {
+        "Create partitions for partitioned tables"
+    }
+
+    async fn run(&self, logger: &Logger) {
+        let store = self.store.clone();
+        let logger2 = logger.clone();
+        let res =
+            graph::spawn_blocking_allow_panic(move || store.extend_partitions(&logger2)).await;
+        match res {
+            Ok(Ok(0)) => {}
+            Ok(Ok(created)) => info!(logger, "Created partitions"; "count" => created),
+            Ok(Err(e)) => error!(logger, "Failed to create partitions: {}", e),
+            Err(e) => error!(logger, "Creating partitions panicked: {}", e),
+        }
+    }
+}
+
+/// A job that activates copies of deployments once they have caught up
+/// with the deployment they were copied from
+struct ActivateCopiesJob {
+    store: Arc<SubgraphStore>,
+}
+
+impl ActivateCopiesJob {
+    fn new(store: Arc<SubgraphStore>) -> ActivateCopiesJob {
+        ActivateCopiesJob { store }
+    }
+}
+
+#[async_trait]
+impl Job for ActivateCopiesJob {
+    fn name(&self) -> &str {
+        "Activate copies that have caught up"
+    }
+
+    async fn run(&self, logger: &Logger) {
+        let store = self.store.clone();
+        let res =
+            graph::spawn_blocking_allow_panic(move || store.activate_caught_up_copies()).await;
+        match res {
+            Ok(Ok(activated)) => {
+                for loc in activated {
+                    info!(logger, "Activated copy"; "deployment" => loc.to_string());
+                }
+            }
+            Ok(Err(e)) => error!(logger, "Failed to activate copies: {}", e),
+            Err(e) => error!(logger, "Activating copies panicked: {}", e),
+        }
+    }
+}
+
+/// A job that measures how much space each deployment takes up on disk
+/// and reports that and the number of entities of each deployment as
+/// metrics, so that it is easy to see which deployments are the largest
+struct DeploymentSizeJob {
+    store: Arc<SubgraphStore>,
+    size_gauges: Box<GaugeVec>,
+    entity_count_gauges: Box<GaugeVec>,
+}
+
+impl DeploymentSizeJob {
+    fn new(store: Arc<SubgraphStore>, registry: Arc<impl MetricsRegistry>) -> DeploymentSizeJob {
+        let labels = vec![String::from("deployment"), String::from("shard")];
+        let size_gauges = registry
+            .new_gauge_vec(
+                "deployment_size_bytes",
+                "The size of the tables and indexes of a deployment on disk",
+                labels.clone(),
+            )
+            .expect("Can register the deployment_size_bytes gauge");
+        let entity_count_gauges = registry
+            .new_gauge_vec(
+                "deployment_entity_count",
+                "The number of entities in a deployment",
+                labels,
+            )
+            .expect("Can register the deployment_entity_count gauge");
+        DeploymentSizeJob {
+            store,
+            size_gauges,
+            entity_count_gauges,
+        }
+    }
+}
+
+#[async_trait]
+impl Job for DeploymentSizeJob {
+    fn name(&self) -> &str {
+        "Measure the size of deployments"
+    }
+
+    async fn run(&self, logger: &Logger) {
+        let store = self.store.clone();
+        let res = graph::spawn_blocking_allow_panic(move || {
+            store.refresh_sizes()?;
+            store.deployment_sizes()
+        })
+        .await;
+        let sizes = match res {
+            Ok(Ok(sizes)) => sizes,
+            Ok(Err(e)) => {
+                error!(logger, "Failed to measure the size of deployments: {}", e);
+                return;
+            }
+            Err(e) => {
+                error!(logger, "Measuring the size of deployments panicked: {}", e);
+                return;
+            }
+        };
+
+        // Drop the values for deployments that were removed
+        self.size_gauges.reset();
+        self.entity_count_gauges.reset();
+        for size in sizes {
+            let labels = [size.subgraph.as_str(), size.shard.as_str()];
+            if let Some(size_bytes) = size.size_bytes {
+                self.size_gauges
+                    .with_label_values(&labels)
+                    .set(size_bytes as f64);
+            }
+            self.entity_count_gauges
+                .with_label_values(&labels)
+                .set(size.entity_count as f64);
+        }
+    }
+}
+
+/// A job that removes entity versions that are more than `HISTORY_BLOCKS`
+/// behind the head of their deployment
+struct PruneJob {
+    store: Arc<SubgraphStore>,
+    history_blocks: BlockNumber,
+}
+
+impl PruneJob {
+    fn new(store: Arc<SubgraphStore>, history_blocks: BlockNumber) -> PruneJob {
+        PruneJob {
+            store,
+            history_blocks,
+        }
+    }
+}
+
+/// Log the progress of pruning a deployment
+struct PruneLogger(Logger);
+
+impl PruneReporter for PruneLogger {
+    fn finish_table(&mut self, table: &str, rows: usize) {
+        info!(self.0, "Pruned table"; "table" => table, "rows" => rows);
+    }
+}
+
+#[async_trait]
+impl Job for PruneJob {
+    fn name(&self) -> &str {
+        "Prune old entity versions"
+    }
+
+    async fn run(&self, logger: &Logger) {
+        let deployments = match self.store.deployments_to_prune(self.history_blocks).await {
+            Ok(deployments) => deployments,
+            Err(e) => {
+                error!(logger, "Failed to find deployments to prune: {}", e);
+                return;
+            }
+        };
+
+        for deployment in deployments {
+            let logger = logger.new(o!("deployment" => deployment.to_string()));
+            let store = self.store.clone();
+            let history_blocks = self.history_blocks;
+            let mut reporter = PruneLogger(logger.clone());
+            let res = graph::spawn_blocking_allow_panic(move || {
+                store.prune(&mut reporter, &deployment, history_blocks)
+            })
+            .await;
+            match res {
+                Ok(Ok(Some(earliest))) => {
+                    info!(logger, "Pruned deployment"; "earliest_block" => earliest)
+                }
+                // Somebody else is pruning this deployment
+                Ok(Ok(None)) => {}
+                Ok(Err(e)) => error!(logger, "Pruning failed: {}", e),
+                Err(e) => error!(logger, "Pruning panicked: {}", e),
+            }
+        }
+    }
+}
+
+/// A job that removes blocks that no deployment needs anymore from the
+/// block cache of all chains
+struct BlockCacheGcJob {
+    store: Arc<Store>,
+    retention: BlockNumber,
+}
+
+impl BlockCacheGcJob {
+    fn new(store: Arc<Store>, retention: BlockNumber) -> BlockCacheGcJob {
+        BlockCacheGcJob { store, retention }
+    }
+}
+
+#[async_trait]
+impl Job for BlockCacheGcJob {
+    fn name(&self) -> &str {
+        "Remove unneeded blocks from the block cache"
+    }
+
+    async fn run(&self, logger: &Logger) {
+        let store = self.store.clone();
+        let retention = self.retention;
+        let res =
+            graph::spawn_blocking_allow_panic(move || store.cleanup_block_cache(retention)).await;
+        match res {
+            Ok(Ok(cleaned)) => {
+                for (chain, block, count) in cleaned {
+                    info!(logger, "Cleaned blocks from the block cache";
+                                  "chain" => chain,
+                                  "count" => count,
+                                  "oldest_block" => block);
+                }
+            }
+            Ok(Err(e)) => error!(logger, "Failed to clean blocks from the block cache: {}", e),
+            Err(e) => error!(logger, "Cleaning the block cache panicked: {}", e),
+        }
+    }
+}
@@ -48,6 +48,7 @@ mod store;
 mod store_events;
 mod subgraph_store;
 pub mod transaction_receipt;
+mod write_queue;
 
 #[cfg(debug_assertions)]
 pub mod layout_for_tests {
@@ -62,11 +63,13 @@ pub mod layout_for_tests {
 
 pub use self::block_store::BlockStore;
 pub use self::chain_head_listener::ChainHeadUpdateListener;
-pub use self::chain_store::ChainStore;
+pub use self::chain_store::{ChainStore, ClearCallCacheError};
+pub use self::deployment_store::Inconsistencies;
 pub use self::detail::DeploymentDetail;
 pub use self::jobs::register as register_jobs;
 pub use self::notification_listener::NotificationSender;
-pub use self::primary::UnusedDeployment;
+pub use self::primary::{Handoff, UnusedDeployment};
+pub use self::relational::PruneReporter;
 pub use self::store::Store;
 pub use self::store_events::SubscriptionManager;
 pub use self::subgraph_store::{unused, DeploymentPlacer, Shard, SubgraphStore, PRIMARY_SHARD};
@@ -76,7 +79,7 @@ pub use self::subgraph_store::{unused, DeploymentPlacer, Shard, SubgraphStore, P
 pub mod command_support {
     pub mod catalog {
         pub use crate::block_store::primary as block_store;
-        pub use crate::catalog::{account_like, set_account_like};
+        pub use crate::catalog::{account_like, set_account_like, IndexInfo};
         pub use crate::copy::{copy_state, copy_table_state};
         pub use crate::primary::Connection;
         pub use crate::primary::{
@@ -9,7 +9,7 @@ use postgres::{fallible_iterator::FallibleIterator, Client, NoTls};
 use std::env;
 use std::str::FromStr;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, Barrier, Mutex};
+use std::sync::{Arc, Barrier, Mutex, RwLock};
 use std::thread;
 use std::time::{Duration, Instant};
 use tokio::sync::mpsc::{channel, Receiver};
@@ -75,6 +75,8 @@ pub struct NotificationListener {
     terminate_worker: Arc<AtomicBool>,
     worker_barrier: Arc<Barrier>,
     started: bool,
+    /// The database to connect to when the worker (re)connects
+    postgres_url: Arc<RwLock<String>>,
 }
 
 impl NotificationListener {
@@ -92,9 +94,11 @@ impl NotificationListener {
         postgres_url: String,
         channel_name: SafeChannelName,
     ) -> (Self, Receiver<JsonNotification>) {
+        let postgres_url = Arc::new(RwLock::new(postgres_url));
+
         // Listen to Postgres notifications in a worker thread
         let (receiver, worker_handle, terminate_worker, worker_barrier) =
-            Self::listen(logger, postgres_url, channel_name);
+            Self::listen(logger, postgres_url.clone(), channel_name);
 
         (
             NotificationListener {
@@ -102,11 +106,18 @@ impl NotificationListener {
                 terminate_worker,
                 worker_barrier,
                 started: false,
+                postgres_url,
             },
             receiver,
         )
     }
 
+    /// Use `postgres_url` the next time the listener needs to connect to
+    /// the database. The current connection is kept
+    pub fn set_postgres_url(&self, postgres_url: String) {
+        *self.postgres_url.write().unwrap() = postgres_url;
+    }
+
     /// Start accepting notifications.
     /// Must be called for any notifications to be received.
     pub fn start(&mut self) {
@@ -118,7 +129,7 @@ impl NotificationListener {
 
     fn listen(
         logger: &Logger,
-        postgres_url: String,
+        postgres_url: Arc<RwLock<String>>,
         channel_name: SafeChannelName,
     ) -> (
         Receiver<JsonNotification>,
@@ -137,14 +148,15 @@ impl NotificationListener {
         /// do not unduly block everything.
         fn connect_and_listen(
             logger: &Logger,
-            postgres_url: &str,
+            postgres_url: &RwLock<String>,
             channel_name: &str,
             barrier: Option<&Barrier>,
         ) -> Client {
             let mut backoff =
                 ExponentialBackoff::new(Duration::from_secs(1), Duration::from_secs(30));
             loop {
-                let res = Client::connect(postgres_url, NoTls).and_then(|mut conn| {
+                let url = postgres_url.read().unwrap().clone();
+                let res = Client::connect(&url, NoTls).and_then(|mut conn| {
                     conn.execute(format!("LISTEN {}", channel_name).as_str(), &[])?;
                     Ok(conn)
                 });
@@ -190,7 +202,7 @@ impl NotificationListener {
                 let mut connected = true;
                 let mut conn = connect_and_listen(
                     &logger,
-                    postgres_url.as_str(),
+                    postgres_url.as_ref(),
                     &channel_name.0,
                     Some(barrier.as_ref()),
                 );
@@ -202,7 +214,7 @@ impl NotificationListener {
                     if !connected {
                         conn = connect_and_listen(
                             &logger,
-                            postgres_url.as_str(),
+                            postgres_url.as_ref(),
                             &channel_name.0,
                             None,
                         );
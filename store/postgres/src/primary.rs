@@ -107,6 +107,26 @@ table! {
     }
 }
 
+table! {
+    /// Coordinates handing the deployments assigned to a node from a
+    /// running graph-node process to a new one with the same node id
+    node_handoffs(node_id) {
+        node_id -> Text,
+        requested_at -> Timestamptz,
+        // Set by the running process once it has stopped indexing
+        released_at -> Nullable<Timestamptz>,
+    }
+}
+
+table! {
+    /// Copies that become the active deployment for their IPFS hash once
+    /// they have caught up with the deployment that is active now
+    copy_activations(dst) {
+        dst -> Integer,
+        requested_at -> Timestamptz,
+    }
+}
+
 table! {
     public.ens_names(hash) {
         hash -> Varchar,
@@ -180,6 +200,16 @@ allow_tables_to_appear_in_same_query!(
     active_copies,
 );
 
+/// Where a handoff between two graph-node processes with the same node id
+/// stands
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Handoff {
+    /// A new process is waiting for the running one to stop indexing
+    Requested,
+    /// The running process has stopped indexing
+    Released,
+}
+
 /// Information about the database schema that stores the entities for a
 /// subgraph.
 #[derive(Clone, Queryable, QueryableByName, Debug)]
@@ -1170,6 +1200,22 @@ impl<'a> Connection<'a> {
             .collect()
     }
 
+    /// The sites of all deployments of `network` that are assigned to a
+    /// node
+    pub fn assigned_sites_for_network(&self, network: &str) -> Result<Vec<Site>, StoreError> {
+        use deployment_schemas as ds;
+        use subgraph_deployment_assignment as a;
+
+        ds::table
+            .inner_join(a::table.on(a::id.eq(ds::id)))
+            .filter(ds::network.eq(network))
+            .select(ds::all_columns)
+            .load::<Schema>(self.conn.as_ref())?
+            .into_iter()
+            .map(|schema| schema.try_into())
+            .collect()
+    }
+
     pub fn sites(&self) -> Result<Vec<Site>, StoreError> {
         use deployment_schemas as ds;
 
@@ -1437,6 +1483,103 @@ impl<'a> Connection<'a> {
 
         Ok(())
     }
+
+    /// Whether copying data into `dst` has not finished yet
+    pub fn copy_in_progress(&self, dst: &Site) -> Result<bool, StoreError> {
+        use active_copies as cp;
+
+        select(exists(cp::table.filter(cp::dst.eq(dst.id))))
+            .get_result::<bool>(self.conn.as_ref())
+            .map_err(StoreError::from)
+    }
+
+    /// Make the copy `dst` the active deployment once it has caught up
+    /// with the deployment that is active now
+    pub fn request_copy_activation(&self, dst: &Site) -> Result<(), StoreError> {
+        use copy_activations as ca;
+
+        insert_into(ca::table)
+            .values((ca::dst.eq(dst.id), ca::requested_at.eq(sql("now()"))))
+            .on_conflict_do_nothing()
+            .execute(self.conn.as_ref())?;
+        Ok(())
+    }
+
+    /// The copies that are waiting to be activated
+    pub fn copy_activations(&self) -> Result<Vec<DeploymentId>, StoreError> {
+        use copy_activations as ca;
+
+        ca::table
+            .select(ca::dst)
+            .order_by(ca::requested_at)
+            .load::<DeploymentId>(self.conn.as_ref())
+            .map_err(StoreError::from)
+    }
+
+    pub fn clear_copy_activation(&self, dst: &Site) -> Result<(), StoreError> {
+        use copy_activations as ca;
+
+        delete(ca::table.filter(ca::dst.eq(dst.id))).execute(self.conn.as_ref())?;
+        Ok(())
+    }
+
+    /// Ask the process that is currently running as `node` to stop
+    /// indexing. This replaces any earlier request
+    pub fn request_handoff(&self, node: &NodeId) -> Result<(), StoreError> {
+        use node_handoffs as h;
+
+        insert_into(h::table)
+            .values((
+                h::node_id.eq(node.as_str()),
+                h::requested_at.eq(sql("now()")),
+            ))
+            .on_conflict(h::node_id)
+            .do_update()
+            .set((
+                h::requested_at.eq(sql("now()")),
+                h::released_at.eq(sql("null")),
+            ))
+            .execute(self.conn.as_ref())?;
+        Ok(())
+    }
+
+    pub fn handoff(&self, node: &NodeId) -> Result<Option<Handoff>, StoreError> {
+        use node_handoffs as h;
+
+        Ok(h::table
+            .filter(h::node_id.eq(node.as_str()))
+            .select(h::released_at.is_not_null())
+            .get_result::<bool>(self.conn.as_ref())
+            .optional()?
+            .map(|released| {
+                if released {
+                    Handoff::Released
+                } else {
+                    Handoff::Requested
+                }
+            }))
+    }
+
+    /// Record that the process running as `node` has stopped indexing
+    pub fn release_handoff(&self, node: &NodeId) -> Result<(), StoreError> {
+        use node_handoffs as h;
+
+        update(
+            h::table
+                .filter(h::node_id.eq(node.as_str()))
+                .filter(h::released_at.is_null()),
+        )
+        .set(h::released_at.eq(sql("now()")))
+        .execute(self.conn.as_ref())?;
+        Ok(())
+    }
+
+    pub fn clear_handoff(&self, node: &NodeId) -> Result<(), StoreError> {
+        use node_handoffs as h;
+
+        delete(h::table.filter(h::node_id.eq(node.as_str()))).execute(self.conn.as_ref())?;
+        Ok(())
+    }
 }
 
 /// A struct that reads from pools in order, trying each pool in turn until
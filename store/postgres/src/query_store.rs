@@ -1,18 +1,36 @@
 use std::collections::BTreeMap;
+use std::sync::Mutex;
 
+use diesel::r2d2::{ConnectionManager, PooledConnection};
+use diesel::PgConnection;
 use web3::types::H256;
 
 use crate::deployment_store::{DeploymentStore, ReplicaId};
-use graph::components::store::QueryStore as QueryStoreTrait;
+use graph::components::store::{AggregateQuery, QueryStore as QueryStoreTrait, REORG_THRESHOLD};
 use graph::prelude::*;
 
 use crate::primary::Site;
 
+/// What a `QueryStore` is doing right now, so that its queries can be
+/// cancelled from another thread
+#[derive(Default)]
+struct Running {
+    cancelled: bool,
+    /// The process id of the backend that is running a query for us
+    backend: Option<i32>,
+}
+
+/// The process id of the backend for a pooled connection. It is stored in
+/// the connection's extensions since it never changes for the lifetime of
+/// the connection
+struct BackendPid(i32);
+
 pub(crate) struct QueryStore {
     site: Arc<Site>,
     replica_id: ReplicaId,
     store: Arc<DeploymentStore>,
     chain_store: Arc<crate::ChainStore>,
+    running: Arc<Mutex<Running>>,
 }
 
 impl QueryStore {
@@ -27,7 +45,46 @@ impl QueryStore {
             replica_id,
             store,
             chain_store,
+            running: Arc::new(Mutex::new(Running::default())),
+        }
+    }
+
+    /// Run `query` on a replica connection in a way that `cancel` can
+    /// interrupt
+    fn run_cancelable<T>(
+        &self,
+        query: impl FnOnce(&PgConnection) -> Result<T, QueryExecutionError>,
+    ) -> Result<T, QueryExecutionError> {
+        let mut conn = self
+            .store
+            .get_replica_conn(self.replica_id)
+            .map_err(|e| QueryExecutionError::StoreError(e.into()))?;
+        let backend = Self::backend_pid(&mut conn)?;
+        {
+            let mut running = self.running.lock().unwrap();
+            if running.cancelled {
+                return Err(QueryExecutionError::Cancelled);
+            }
+            running.backend = Some(backend);
         }
+        let result = query(&conn);
+        // This waits for a cancellation that is in progress so that it
+        // can not hit the next query that uses this connection
+        self.running.lock().unwrap().backend = None;
+        result
+    }
+
+    /// The process id of the backend for `conn`. We only ask the database
+    /// the first time we see a connection
+    fn backend_pid(
+        conn: &mut PooledConnection<ConnectionManager<PgConnection>>,
+    ) -> Result<i32, StoreError> {
+        if let Some(BackendPid(pid)) = PooledConnection::extensions(conn).get::<BackendPid>() {
+            return Ok(*pid);
+        }
+        let pid = crate::catalog::backend_pid(conn)?;
+        PooledConnection::extensions_mut(conn).insert(BackendPid(pid));
+        Ok(pid)
     }
 }
 
@@ -38,11 +95,15 @@ impl QueryStoreTrait for QueryStore {
         query: EntityQuery,
     ) -> Result<Vec<BTreeMap<String, r::Value>>, QueryExecutionError> {
         assert_eq!(&self.site.deployment, &query.subgraph_id);
-        let conn = self
-            .store
-            .get_replica_conn(self.replica_id)
-            .map_err(|e| QueryExecutionError::StoreError(e.into()))?;
-        self.store.execute_query(&conn, self.site.clone(), query)
+        self.run_cancelable(|conn| self.store.execute_query(conn, self.site.clone(), query))
+    }
+
+    fn aggregate(
+        &self,
+        query: AggregateQuery,
+    ) -> Result<Vec<BTreeMap<String, r::Value>>, QueryExecutionError> {
+        assert_eq!(&self.site.deployment, &query.subgraph_id);
+        self.run_cancelable(|conn| self.store.aggregate(conn, self.site.clone(), query))
     }
 
     /// Return true if the deployment with the given id is fully synced,
@@ -82,6 +143,25 @@ impl QueryStoreTrait for QueryStore {
             .transpose()
     }
 
+    fn block_number_by_timestamp(&self, timestamp: u64) -> Result<Option<BlockNumber>, StoreError> {
+        self.chain_store
+            .block_number_by_timestamp(timestamp)
+            .map_err(StoreError::from)
+    }
+
+    fn block_timestamp(&self, block_hash: H256) -> Result<Option<u64>, StoreError> {
+        self.chain_store
+            .block_timestamp(block_hash)
+            .map_err(StoreError::from)
+    }
+
+    fn finalized_block_number(&self) -> Result<Option<BlockNumber>, StoreError> {
+        Ok(self
+            .chain_store
+            .chain_head_ptr()?
+            .map(|head| (head.number - *REORG_THRESHOLD).max(0)))
+    }
+
     fn wait_stats(&self) -> PoolWaitStats {
         self.store.wait_stats(self.replica_id)
     }
@@ -111,7 +191,34 @@ impl QueryStoreTrait for QueryStore {
         &self.site.network
     }
 
+    fn deployment_id(&self) -> DeploymentHash {
+        self.site.deployment.clone()
+    }
+
     async fn query_permit(&self) -> tokio::sync::OwnedSemaphorePermit {
         self.store.query_permit(self.replica_id).await
     }
+
+    fn cancel(&self) {
+        let backend = {
+            let mut running = self.running.lock().unwrap();
+            running.cancelled = true;
+            running.backend
+        };
+        if backend.is_none() {
+            return;
+        }
+
+        let running = self.running.cheap_clone();
+        let store = self.store.cheap_clone();
+        let replica_id = self.replica_id;
+        graph::spawn_blocking_allow_panic(move || {
+            // Hold the lock so that the connection can not be used for
+            // another query until the cancellation has gone through
+            let running = running.lock().unwrap();
+            if let Some(backend) = running.backend {
+                store.cancel_query(replica_id, backend);
+            }
+        });
+    }
 }
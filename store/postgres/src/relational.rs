@@ -6,10 +6,11 @@
 //!
 //! The pivotal struct in this module is the `Layout` which handles all the
 //! information about mapping a GraphQL schema to database tables
+use diesel::sql_types::{BigInt, Bool, Integer, Nullable, Text};
 use diesel::{connection::SimpleConnection, Connection};
-use diesel::{debug_query, OptionalExtension, PgConnection, RunQueryDsl};
+use diesel::{debug_query, sql_query, OptionalExtension, PgConnection, RunQueryDsl};
 use graph::cheap_clone::CheapClone;
-use graph::prelude::{q, s, StopwatchMetrics};
+use graph::prelude::{q, r, s, Attribute, StopwatchMetrics};
 use graph::slog::warn;
 use inflector::Inflector;
 use lazy_static::lazy_static;
@@ -18,6 +19,7 @@ use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::convert::{From, TryFrom};
 use std::env;
 use std::fmt::{self, Write};
+use std::iter::FromIterator;
 use std::str::FromStr;
 use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
@@ -25,8 +27,9 @@ use std::time::{Duration, Instant};
 use crate::{
     primary::{Namespace, Site},
     relational_queries::{
-        ClampRangeQuery, ConflictingEntityQuery, EntityData, FilterCollection, FilterQuery,
-        FindManyQuery, FindQuery, InsertQuery, RevertClampQuery, RevertRemoveQuery,
+        AggregateData, AggregateQuery, ClampRangeQuery, ConflictingEntityQuery, EntityData,
+        FilterCollection, FilterQuery, FindManyQuery, FindQuery, InsertQuery, RevertClampQuery,
+        RevertRemoveQuery,
     },
 };
 use graph::components::store::EntityType;
@@ -34,6 +37,7 @@ use graph::data::graphql::ext::{DirectiveFinder, DocumentExt, ObjectTypeExt};
 use graph::data::schema::{FulltextConfig, FulltextDefinition, Schema, SCHEMA_TYPE_NAME};
 use graph::data::store::BYTES_SCALAR;
 use graph::data::subgraph::schema::{POI_OBJECT, POI_TABLE};
+use graph::data::subgraph::status;
 use graph::prelude::{
     anyhow, info, BlockNumber, DeploymentHash, Entity, EntityChange, EntityCollection,
     EntityFilter, EntityKey, EntityOrder, EntityRange, Logger, QueryExecutionError, StoreError,
@@ -47,6 +51,8 @@ use crate::{catalog, deployment};
 
 const POSTGRES_MAX_PARAMETERS: usize = u16::MAX as usize; // 65535
 const DELETE_OPERATION_CHUNK_SIZE: usize = 1_000;
+/// How many consecutive `vid`s to look at in each statement when pruning
+const PRUNE_BATCH_SIZE: i64 = 10_000;
 
 /// The size of string prefixes that we index. This is chosen so that we
 /// will index strings that people will do string comparisons like
@@ -79,15 +85,16 @@ lazy_static! {
     /// `GRAPH_SQL_STATEMENT_TIMEOUT` is the timeout for queries in seconds.
     /// If it is not set, no statement timeout will be enforced. The statement
     /// timeout is local, i.e., can only be used within a transaction and
-    /// will be cleared at the end of the transaction
-    static ref STATEMENT_TIMEOUT: Option<String> = {
+    /// will be cleared at the end of the transaction. Deployments can
+    /// override it with `graphman statement-timeout`
+    static ref STATEMENT_TIMEOUT: Option<u64> = {
         env::var("GRAPH_SQL_STATEMENT_TIMEOUT")
         .ok()
         .map(|s| {
             u64::from_str(&s).unwrap_or_else(|_| {
                 panic!("GRAPH_SQL_STATEMENT_TIMEOUT must be a number, but is `{}`", s)
             })
-        }).map(|timeout| format!("set local statement_timeout={}", timeout * 1000))
+        })
     };
 }
 
@@ -216,6 +223,16 @@ type IdTypeMap = HashMap<EntityType, IdType>;
 
 type EnumMap = BTreeMap<String, Arc<BTreeSet<String>>>;
 
+/// Callbacks through which `Layout::prune` reports its progress. All
+/// methods do nothing by default
+pub trait PruneReporter {
+    fn start_table(&mut self, _table: &str) {}
+    /// Called after each batch with the number of rows removed from
+    /// `table` so far and the fraction of the table that has been processed
+    fn prune_batch(&mut self, _table: &str, _rows: usize, _done: f64) {}
+    fn finish_table(&mut self, _table: &str, _rows: usize) {}
+}
+
 #[derive(Debug, Clone)]
 pub struct Layout {
     /// Details of where the subgraph is stored
@@ -229,6 +246,9 @@ pub struct Layout {
     pub enums: EnumMap,
     /// The query to count all entities
     pub count_query: String,
+    /// The statement timeout in seconds for queries against this
+    /// deployment if it overrides `GRAPH_SQL_STATEMENT_TIMEOUT`
+    pub statement_timeout: Option<u64>,
 }
 
 impl Layout {
@@ -350,6 +370,7 @@ impl Layout {
             tables,
             enums,
             count_query,
+            statement_timeout: None,
         })
     }
 
@@ -386,6 +407,8 @@ impl Layout {
             /// predictable
             position: position as u32,
             is_account_like: false,
+            partition_blocks: None,
+            immutable: false,
         }
     }
 
@@ -404,9 +427,26 @@ impl Layout {
             .as_ddl()
             .map_err(|_| StoreError::Unknown(anyhow!("failed to generate DDL for layout")))?;
         conn.batch_execute(&sql)?;
+        // Remember which tables have the layout for immutable entity types
+        // so that loading the layout later does not have to guess
+        for table in layout.tables.values().filter(|table| table.immutable) {
+            crate::catalog::set_immutable(conn, &layout.site, &table.name)?;
+        }
         Ok(layout)
     }
 
+    /// Treat only the tables in `immutable` as immutable. Deployments that
+    /// were created before immutable entity types were supported have
+    /// tables with the layout for mutable entities, even if their schema
+    /// declares the entity type immutable
+    fn restrict_immutable(&mut self, immutable: &HashSet<String>) {
+        for table in self.tables.values_mut() {
+            if table.immutable && !immutable.contains(table.name.as_str()) {
+                Arc::make_mut(table).immutable = false;
+            }
+        }
+    }
+
     /// Determine if it is possible to copy the data of `source` into `self`
     /// by checking that our schema is compatible with `source`.
     /// Returns a list of errors if copying is not possible. An empty
@@ -578,9 +618,7 @@ impl Layout {
         // We add 1 to account for the `block_range` bind parameter
         let chunk_size = POSTGRES_MAX_PARAMETERS / (table.columns.len() + 1);
         for chunk in entities.chunks_mut(chunk_size) {
-            count += InsertQuery::new(table, chunk, block)?
-                .get_results(conn)
-                .map(|ids| ids.len())?
+            count += InsertQuery::new(table, chunk, block)?.execute(conn)?;
         }
         Ok(count)
     }
@@ -591,10 +629,24 @@ impl Layout {
         entity_id: &str,
         entities: Vec<EntityType>,
     ) -> Result<Option<String>, StoreError> {
-        Ok(ConflictingEntityQuery::new(self, entities, entity_id)?
+        Ok(self
+            .conflicting_entities(conn, &[entity_id], entities)?
+            .map(|(entity, _)| entity))
+    }
+
+    /// Check whether any of the `entity_ids` is already used by an entity
+    /// of one of the types in `entities`, and return the type and id of
+    /// one such entity
+    pub fn conflicting_entities(
+        &self,
+        conn: &PgConnection,
+        entity_ids: &[&str],
+        entities: Vec<EntityType>,
+    ) -> Result<Option<(String, String)>, StoreError> {
+        Ok(ConflictingEntityQuery::new(self, entities, entity_ids)?
             .load(conn)?
             .pop()
-            .map(|data| data.entity))
+            .map(|data| (data.entity, data.id)))
     }
 
     /// order is a tuple (attribute, value_type, direction)
@@ -639,7 +691,7 @@ impl Layout {
             );
         }
 
-        let filter_collection = FilterCollection::new(&self, collection, filter.as_ref())?;
+        let filter_collection = FilterCollection::new(&self, collection, filter.as_ref(), block)?;
         let query = FilterQuery::new(
             &filter_collection,
             filter.as_ref(),
@@ -653,8 +705,8 @@ impl Layout {
         let start = Instant::now();
         let values = conn
             .transaction(|| {
-                if let Some(ref timeout_sql) = *STATEMENT_TIMEOUT {
-                    conn.batch_execute(timeout_sql)?;
+                if let Some(timeout) = self.statement_timeout.or(*STATEMENT_TIMEOUT) {
+                    conn.batch_execute(&format!("set local statement_timeout={}", timeout * 1000))?;
                 }
                 query.load::<EntityData>(conn)
             })
@@ -676,6 +728,40 @@ impl Layout {
             .collect()
     }
 
+    /// Count the entities of type `entity_type` that match `filter` and
+    /// aggregate their numeric `attributes`. See `AggregateQuery` in
+    /// `graph::components::store` for details
+    pub fn aggregate(
+        &self,
+        conn: &PgConnection,
+        entity_type: &EntityType,
+        filter: Option<&EntityFilter>,
+        group_by: Option<&str>,
+        attributes: &[Attribute],
+        range: EntityRange,
+        block: BlockNumber,
+    ) -> Result<Vec<BTreeMap<String, r::Value>>, QueryExecutionError> {
+        let table = self.table_for_entity(entity_type)?;
+        let query = AggregateQuery::new(self, table, filter, group_by, attributes, range, block)?;
+        let query_clone = query.clone();
+
+        let rows = conn
+            .transaction(|| {
+                if let Some(timeout) = self.statement_timeout.or(*STATEMENT_TIMEOUT) {
+                    conn.batch_execute(&format!("set local statement_timeout={}", timeout * 1000))?;
+                }
+                query.load::<AggregateData>(conn)
+            })
+            .map_err(|e| {
+                QueryExecutionError::ResolveEntitiesError(format!(
+                    "{}, query = {:?}",
+                    e,
+                    debug_query(&query_clone).to_string()
+                ))
+            })?;
+        Ok(rows.into_iter().map(AggregateData::into_map).collect())
+    }
+
     pub fn update<'a>(
         &'a self,
         conn: &PgConnection,
@@ -685,6 +771,12 @@ impl Layout {
         stopwatch: &StopwatchMetrics,
     ) -> Result<usize, StoreError> {
         let table = self.table_for_entity(&entity_type)?;
+        if let (true, Some((key, _))) = (table.immutable, entities.first()) {
+            return Err(StoreError::ImmutableEntityChanged(
+                entity_type.to_string(),
+                key.entity_id.clone(),
+            ));
+        }
         let entity_keys: Vec<&str> = entities
             .iter()
             .map(|(key, _)| key.entity_id.as_str())
@@ -717,6 +809,12 @@ impl Layout {
         stopwatch: &StopwatchMetrics,
     ) -> Result<usize, StoreError> {
         let table = self.table_for_entity(&entity_type)?;
+        if let (true, Some(id)) = (table.immutable, entity_ids.first()) {
+            return Err(StoreError::ImmutableEntityChanged(
+                entity_type.to_string(),
+                id.to_string(),
+            ));
+        }
         let _section = stopwatch.start_section("delete_modification_clamp_range_query");
         let mut count = 0;
         for chunk in entity_ids.chunks(DELETE_OPERATION_CHUNK_SIZE) {
@@ -744,12 +842,17 @@ impl Layout {
                 .collect::<HashSet<_>>();
             // Make the versions current that existed at `block - 1` but that
             // are not current yet. Those are the ones that were updated or
-            // deleted at `block`
-            let unclamped = RevertClampQuery::new(table, block - 1)
-                .get_results(conn)?
-                .into_iter()
-                .map(|data| data.id)
-                .collect::<HashSet<_>>();
+            // deleted at `block`. Versions of immutable entities are never
+            // closed, and there is nothing to undo
+            let unclamped = if table.immutable {
+                HashSet::new()
+            } else {
+                RevertClampQuery::new(table, block - 1)
+                    .get_results(conn)?
+                    .into_iter()
+                    .map(|data| data.id)
+                    .collect::<HashSet<_>>()
+            };
             // Adjust the entity count; we can tell which operation was
             // initially performed by
             //   id in (unset - unclamped)  => insert (we now deleted)
@@ -777,6 +880,514 @@ impl Layout {
         Ok((StoreEvent::new(changes), count))
     }
 
+    /// Find the entities that are different at block `to` from what they
+    /// were at block `from`. Entities that were created and deleted again
+    /// between the two blocks are not reported. Returns at most `first`
+    /// entities, ordered by entity type and id. If `after` is given, only
+    /// entities that come after that entity type and id are returned
+    pub fn entity_diff(
+        &self,
+        conn: &PgConnection,
+        from: BlockNumber,
+        to: BlockNumber,
+        after: Option<(&str, &str)>,
+        first: usize,
+    ) -> Result<Vec<status::EntityDiff>, StoreError> {
+        #[derive(QueryableByName)]
+        struct Change {
+            #[sql_type = "Text"]
+            id: String,
+            #[sql_type = "Text"]
+            operation: String,
+        }
+
+        let mut tables: Vec<_> = self
+            .tables
+            .values()
+            .filter(|table| table.object != *POI_OBJECT)
+            .filter(|table| match after {
+                Some((entity_type, _)) => table.object.as_str() >= entity_type,
+                None => true,
+            })
+            .collect();
+        tables.sort_by(|a, b| a.object.as_str().cmp(b.object.as_str()));
+
+        let mut diffs = Vec::new();
+        for table in tables {
+            if diffs.len() >= first {
+                break;
+            }
+            let after_id = match after {
+                Some((entity_type, id)) if table.object.as_str() == entity_type => Some(id),
+                _ => None,
+            };
+
+            let id = match table.primary_key().column_type.id_type() {
+                IdType::String => "c.id",
+                IdType::Bytes => "'0x' || encode(c.id, 'hex')",
+            };
+            // A version that is current at `to` but started after `from`
+            // is an update if there was a version at `from`, and an
+            // insert otherwise. A version that was current at `from` with
+            // no version at `to` means the entity was deleted
+            let query = format!(
+                "select id, operation from (
+                   select {id} as id,
+                          case when exists (select 1 from \"{nsp}\".\"{table}\" p
+                                             where p.id = c.id and p.{br} @> $1)
+                               then 'updated' else 'created' end as operation
+                     from \"{nsp}\".\"{table}\" c
+                    where c.{br} @> $2 and lower(c.{br}) > $1
+                   union all
+                   select {id} as id, 'deleted' as operation
+                     from \"{nsp}\".\"{table}\" c
+                    where c.{br} @> $1
+                      and not exists (select 1 from \"{nsp}\".\"{table}\" n
+                                       where n.id = c.id and n.{br} @> $2)) changes
+                  where $4::text is null or id > $4
+                  order by id
+                  limit $3",
+                id = id,
+                nsp = &self.catalog.site.namespace,
+                table = table.name,
+                br = BLOCK_RANGE_COLUMN
+            );
+            let changes = sql_query(query)
+                .bind::<Integer, _>(from)
+                .bind::<Integer, _>(to)
+                .bind::<BigInt, _>((first - diffs.len()) as i64)
+                .bind::<Nullable<Text>, _>(after_id)
+                .load::<Change>(conn)?;
+
+            let ids: Vec<_> = changes
+                .iter()
+                .filter(|change| change.operation != "deleted")
+                .map(|change| change.id.as_str())
+                .collect();
+            let mut entities: HashMap<String, Entity> = HashMap::new();
+            if !ids.is_empty() {
+                let ids_for_type = BTreeMap::from_iter(vec![(&table.object, ids)]);
+                for entity in self
+                    .find_many(conn, &ids_for_type, to)?
+                    .into_iter()
+                    .flat_map(|(_, entities)| entities)
+                {
+                    entities.insert(entity.id()?, entity);
+                }
+            }
+
+            for change in changes {
+                let operation = match change.operation.as_str() {
+                    "created" => status::EntityDiffOperation::Created,
+                    "updated" => status::EntityDiffOperation::Updated,
+                    _ => status::EntityDiffOperation::Deleted,
+                };
+                diffs.push(status::EntityDiff {
+                    entity_type: table.object.to_string(),
+                    data: entities.remove(&change.id),
+                    id: change.id,
+                    operation,
+                });
+            }
+        }
+        Ok(diffs)
+    }
+
+    /// Remove all entity versions that are not visible at block `earliest`
+    /// or any later block, i.e., versions that were replaced or deleted at
+    /// or before `earliest`. The history of the proof of indexing is kept
+    /// so that proofs of indexing for earlier blocks can still be computed.
+    ///
+    /// Rows are deleted in batches of consecutive `vid`s, and each batch is
+    /// its own statement, so that `conn` must not be in a transaction and
+    /// concurrent writes never wait for more than one batch
+    pub fn prune(
+        &self,
+        conn: &PgConnection,
+        earliest: BlockNumber,
+        reporter: &mut dyn PruneReporter,
+    ) -> Result<usize, StoreError> {
+        #[derive(QueryableByName)]
+        struct VidRange {
+            #[sql_type = "Nullable<BigInt>"]
+            min_vid: Option<i64>,
+            #[sql_type = "Nullable<BigInt>"]
+            max_vid: Option<i64>,
+        }
+
+        let mut tables: Vec<_> = self
+            .tables
+            .values()
+            .filter(|table| table.object != *POI_OBJECT)
+            .collect();
+        tables.sort_by(|a, b| a.name.as_str().cmp(b.name.as_str()));
+
+        let mut total = 0;
+        for table in tables {
+            reporter.start_table(table.name.as_str());
+
+            // Whole partitions can be dropped much more cheaply than
+            // deleting their rows
+            let mut rows = match table.partition_blocks {
+                Some(blocks) => self.drop_pruned_partitions(conn, table, blocks, earliest)?,
+                None => 0,
+            };
+
+            let range = sql_query(format!(
+                "select min({vid}) as min_vid, max({vid}) as max_vid from {qname}",
+                vid = VID_COLUMN,
+                qname = table.qualified_name
+            ))
+            .get_result::<VidRange>(conn)?;
+            let (min_vid, max_vid) = match (range.min_vid, range.max_vid) {
+                (Some(min_vid), Some(max_vid)) => (min_vid, max_vid),
+                _ => {
+                    reporter.finish_table(table.name.as_str(), rows);
+                    continue;
+                }
+            };
+
+            let query = format!(
+                "delete from {qname} \
+                  where {vid} >= $1 and {vid} < $2 \
+                    and coalesce(upper({br}), {block_max}) <= $3",
+                qname = table.qualified_name,
+                vid = VID_COLUMN,
+                br = BLOCK_RANGE_COLUMN,
+                block_max = BLOCK_NUMBER_MAX
+            );
+            let mut start = min_vid;
+            while start <= max_vid {
+                let end = start + PRUNE_BATCH_SIZE;
+                rows += sql_query(&query)
+                    .bind::<BigInt, _>(start)
+                    .bind::<BigInt, _>(end)
+                    .bind::<Integer, _>(earliest)
+                    .execute(conn)?;
+                start = end;
+                reporter.prune_batch(
+                    table.name.as_str(),
+                    rows,
+                    (start - min_vid) as f64 / (max_vid - min_vid + 1) as f64,
+                );
+            }
+            reporter.finish_table(table.name.as_str(), rows);
+            total += rows;
+        }
+        Ok(total)
+    }
+
+    /// Drop the partitions of `table` that only contain entity versions
+    /// that are not visible at `earliest` or any later block, and return
+    /// how many rows they contained
+    fn drop_pruned_partitions(
+        &self,
+        conn: &PgConnection,
+        table: &Table,
+        blocks: BlockNumber,
+        earliest: BlockNumber,
+    ) -> Result<usize, StoreError> {
+        #[derive(QueryableByName)]
+        struct Count {
+            #[sql_type = "BigInt"]
+            live: i64,
+            #[sql_type = "BigInt"]
+            total: i64,
+        }
+
+        let mut rows = 0;
+        for (k, name) in self.partitions(conn, table)? {
+            if (k + 1) * blocks > earliest {
+                continue;
+            }
+            let qname = format!("\"{}\".\"{}\"", self.catalog.site.namespace, name);
+            // Versions that started in this partition can still be
+            // visible, for example, for entities that were never updated
+            let count = sql_query(format!(
+                "select count(*) filter (where coalesce(upper({br}), {block_max}) > $1) as live, \
+                        count(*) as total \
+                   from {qname}",
+                br = BLOCK_RANGE_COLUMN,
+                block_max = BLOCK_NUMBER_MAX,
+                qname = qname
+            ))
+            .bind::<Integer, _>(earliest)
+            .get_result::<Count>(conn)?;
+            if count.live == 0 {
+                conn.batch_execute(&format!("drop table {}", qname))?;
+                rows += count.total as usize;
+            }
+        }
+        Ok(rows)
+    }
+
+    /// The partitions of `table` as pairs `(k, name)` where partition `k`
+    /// holds the entity versions whose block range starts in
+    /// `[k * blocks, (k + 1) * blocks)`. The default partition is not
+    /// included
+    fn partitions(
+        &self,
+        conn: &PgConnection,
+        table: &Table,
+    ) -> Result<Vec<(BlockNumber, String)>, StoreError> {
+        #[derive(QueryableByName)]
+        struct Partition {
+            #[sql_type = "Text"]
+            name: String,
+        }
+
+        let prefix = format!("{}_p", table.name);
+        let partitions = sql_query(
+            "select c.relname::text as name \
+               from pg_inherits i, pg_class c \
+              where c.oid = i.inhrelid \
+                and i.inhparent = $1::regclass",
+        )
+        .bind::<Text, _>(table.qualified_name.as_str())
+        .load::<Partition>(conn)?
+        .into_iter()
+        .filter_map(|part| {
+            let k = part.name.strip_prefix(&prefix)?.parse().ok()?;
+            Some((k, part.name))
+        })
+        .collect();
+        Ok(partitions)
+    }
+
+    /// Count the entity versions in each table that were created or
+    /// changed at a block after `head`, and return the counts for the
+    /// tables that have such versions. Since the block pointer of a
+    /// deployment and its entity versions are changed in the same
+    /// transaction, a deployment at block `head` should not have any
+    pub fn versions_after(
+        &self,
+        conn: &PgConnection,
+        head: BlockNumber,
+    ) -> Result<Vec<(EntityType, i64)>, StoreError> {
+        #[derive(QueryableByName)]
+        struct Count {
+            #[sql_type = "BigInt"]
+            count: i64,
+        }
+
+        let mut counts = Vec::new();
+        for table in self.tables.values() {
+            let query = format!(
+                "select count(*) as count from {qname} \
+                  where lower({br}) > $1 or upper({br}) > $1",
+                qname = table.qualified_name,
+                br = BLOCK_RANGE_COLUMN
+            );
+            let count = sql_query(query)
+                .bind::<Integer, _>(head)
+                .get_result::<Count>(conn)?
+                .count;
+            if count > 0 {
+                counts.push((table.object.clone(), count));
+            }
+        }
+        counts.sort_by(|(a, _), (b, _)| a.as_str().cmp(b.as_str()));
+        Ok(counts)
+    }
+
+    /// An estimate of the number of rows in `table` from the statistics
+    /// Postgres keeps
+    pub fn estimated_rows(&self, conn: &PgConnection, table: &Table) -> Result<i64, StoreError> {
+        #[derive(QueryableByName)]
+        struct Estimate {
+            #[sql_type = "BigInt"]
+            rows: i64,
+        }
+
+        let estimate =
+            sql_query("select reltuples::int8 as rows from pg_class where oid = $1::regclass")
+                .bind::<Text, _>(table.qualified_name.as_str())
+                .get_result::<Estimate>(conn)?;
+        Ok(estimate.rows)
+    }
+
+    /// Replace `table` with a table that is partitioned by the start of
+    /// the block range of its entity versions into partitions of `blocks`
+    /// blocks each, and create partitions up to block `head + blocks`.
+    /// Later entity versions go into a default partition until
+    /// `extend_partitions` creates partitions for them.
+    ///
+    /// The data is copied and indexed while the deployment keeps writing
+    /// to `table`. Writes are only blocked while the changes made during
+    /// the copy are applied to the new table and the tables are swapped.
+    /// This must be run in a transaction. Returns the number of rows of
+    /// the new table
+    pub fn partition(
+        &self,
+        conn: &PgConnection,
+        table: &Table,
+        blocks: BlockNumber,
+        head: BlockNumber,
+    ) -> Result<usize, StoreError> {
+        #[derive(QueryableByName)]
+        struct Sequence {
+            #[sql_type = "Text"]
+            name: String,
+        }
+
+        let nsp = &self.catalog.site.namespace;
+        let tmp_name = format!("{}_partitioned", table.name);
+        let parent = format!("\"{}\".\"{}\"", nsp, tmp_name);
+
+        let qname = &table.qualified_name;
+        let mut ddl = format!(
+            "create table {parent} (like {qname} including defaults)\n    \
+                 partition by range (lower({br}));\n",
+            qname = qname,
+            parent = parent,
+            br = BLOCK_RANGE_COLUMN
+        );
+        for k in 0..=(head + blocks) / blocks {
+            ddl.push_str(&partition_ddl(nsp, &parent, table, Some((k, blocks))));
+        }
+        ddl.push_str(&partition_ddl(nsp, &parent, table, None));
+        conn.batch_execute(&ddl)?;
+
+        let copied =
+            sql_query(format!("insert into {} select * from {}", parent, qname)).execute(conn)?;
+
+        let mut ddl = String::new();
+        table
+            .index_ddl(&mut ddl, self, &tmp_name)
+            .map_err(|_| StoreError::Unknown(anyhow!("failed to generate DDL for indexes")))?;
+        conn.batch_execute(&ddl)?;
+
+        // Block writes and catch up with what changed during the copy:
+        // versions that were deleted by reverts or pruning, versions whose
+        // block range was closed or reopened, and new versions. Versions
+        // are never changed otherwise
+        conn.batch_execute(&format!("lock table {} in share mode", qname))?;
+        let deleted = sql_query(format!(
+            "delete from {parent} p \
+              where not exists (select 1 from {qname} t where t.{vid} = p.{vid})",
+            parent = parent,
+            qname = qname,
+            vid = VID_COLUMN
+        ))
+        .execute(conn)?;
+        sql_query(format!(
+            "update {parent} p set {br} = t.{br} \
+               from {qname} t \
+              where t.{vid} = p.{vid} and t.{br} <> p.{br}",
+            parent = parent,
+            qname = qname,
+            br = BLOCK_RANGE_COLUMN,
+            vid = VID_COLUMN
+        ))
+        .execute(conn)?;
+        let inserted = sql_query(format!(
+            "insert into {parent} \
+             select * from {qname} t \
+              where not exists (select 1 from {parent} p where p.{vid} = t.{vid})",
+            parent = parent,
+            qname = qname,
+            vid = VID_COLUMN
+        ))
+        .execute(conn)?;
+
+        // The sequence for `vid` belongs to the old table and would be
+        // dropped with it
+        let seq = sql_query("select pg_get_serial_sequence($1, $2) as name")
+            .bind::<Text, _>(table.qualified_name.as_str())
+            .bind::<Text, _>(VID_COLUMN)
+            .get_result::<Sequence>(conn)?;
+        conn.batch_execute(&format!(
+            "alter sequence {seq} owned by {parent}.{vid};\n\
+             drop table {qname};\n\
+             alter table {parent} rename to {name};\n",
+            seq = seq.name,
+            parent = parent,
+            vid = VID_COLUMN,
+            qname = table.qualified_name,
+            name = table.name.quoted()
+        ))?;
+        catalog::set_partition_blocks(conn, &self.site, &table.name, blocks)?;
+        Ok(copied + inserted - deleted)
+    }
+
+    /// Create the partitions that the entity versions of a partitioned
+    /// `table` will need until block `head + blocks`. Postgres refuses to
+    /// create a partition if the default partition already contains
+    /// versions that belong into it; such versions are moved from the
+    /// default partition into the new partition. Returns the number of
+    /// partitions that were created
+    ///
+    /// Creating a partition takes an `access exclusive` lock on `table`,
+    /// and scans its default partition, while holding that lock, to check
+    /// that none of its versions belong into the new partition. Writes to
+    /// and queries of `table` wait until that is done, which is quick as
+    /// long as the default partition is small
+    pub fn extend_partitions(
+        &self,
+        conn: &PgConnection,
+        table: &Table,
+        head: BlockNumber,
+    ) -> Result<usize, StoreError> {
+        #[derive(QueryableByName)]
+        struct Misplaced {
+            #[sql_type = "Bool"]
+            misplaced: bool,
+        }
+
+        let blocks = match table.partition_blocks {
+            Some(blocks) => blocks,
+            None => return Ok(0),
+        };
+        let last = self
+            .partitions(conn, table)?
+            .into_iter()
+            .map(|(k, _)| k)
+            .max()
+            .unwrap_or(-1);
+        let nsp = &self.catalog.site.namespace;
+        let default = format!("\"{}\".\"{}_pdefault\"", nsp, table.name);
+        let mut created = 0;
+        for k in last + 1..=(head + blocks) / blocks {
+            let ddl = partition_ddl(nsp, table.qualified_name.as_str(), table, Some((k, blocks)));
+            let range = format!(
+                "lower({br}) >= {start} and lower({br}) < {end}",
+                br = BLOCK_RANGE_COLUMN,
+                start = k * blocks,
+                end = (k + 1) * blocks
+            );
+            let misplaced = sql_query(format!(
+                "select exists (select 1 from {} where {}) as misplaced",
+                default, range
+            ))
+            .get_result::<Misplaced>(conn)?
+            .misplaced;
+            if misplaced {
+                // Take the default partition out of the table while we
+                // move the versions that belong into the new partition
+                conn.transaction(|| {
+                    conn.batch_execute(&format!(
+                        "alter table {qname} detach partition {default};\n\
+                         {ddl}\
+                         with moved as (delete from {default} where {range} returning *)\n\
+                         insert into \"{nsp}\".\"{name}_p{k}\" select * from moved;\n\
+                         alter table {qname} attach partition {default} default;\n",
+                        qname = table.qualified_name,
+                        default = default,
+                        ddl = ddl,
+                        range = range,
+                        nsp = nsp,
+                        name = table.name,
+                        k = k
+                    ))
+                })?;
+            } else {
+                conn.batch_execute(&ddl)?;
+            }
+            created += 1;
+        }
+        Ok(created)
+    }
+
     /// Revert the metadata (dynamic data sources and related entities) for
     /// the given `subgraph`.
     ///
@@ -801,8 +1412,9 @@ impl Layout {
     }
 
     /// Update the layout with the latest information from the database; for
-    /// now, an update only changes the `is_account_like` flag for tables or
-    /// the layout's site. If no update is needed, just return `self`.
+    /// now, an update only changes the `is_account_like` flag and the
+    /// partitioning of tables, the statement timeout, or the layout's site.
+    /// If no update is needed, just return `self`.
     pub fn refresh(
         self: Arc<Self>,
         conn: &PgConnection,
@@ -816,25 +1428,71 @@ impl Layout {
             }
         };
 
+        let partition_blocks = crate::catalog::partition_blocks(conn, &self.site)?;
+        let partition_blocks = |table: &Table| partition_blocks.get(table.name.as_str()).cloned();
+
         let changed_tables: Vec<_> = self
             .tables
             .values()
-            .filter(|table| table.is_account_like != is_account_like(table.as_ref()))
+            .filter(|table| {
+                table.is_account_like != is_account_like(table.as_ref())
+                    || table.partition_blocks != partition_blocks(table.as_ref())
+            })
             .collect();
-        if changed_tables.is_empty() && site == self.site {
+        let statement_timeout = deployment::statement_timeout(conn, &self.site.deployment)?;
+
+        if changed_tables.is_empty()
+            && site == self.site
+            && statement_timeout == self.statement_timeout
+        {
             return Ok(self);
         }
         let mut layout = (*self).clone();
         for table in changed_tables.into_iter() {
             let mut table = (*table.as_ref()).clone();
             table.is_account_like = is_account_like(&table);
+            table.partition_blocks = partition_blocks(&table);
             layout.tables.insert(table.object.clone(), Arc::new(table));
         }
         layout.site = site;
+        layout.statement_timeout = statement_timeout;
         Ok(Arc::new(layout))
     }
 }
 
+/// The DDL for partition `k` of `parent`, which holds the entity versions
+/// of `table` whose block range starts in `[k * blocks, (k + 1) * blocks)`
+/// for `range = Some((k, blocks))`, or for the default partition of
+/// `parent` for `range = None`. Constraints can only be checked per
+/// partition since Postgres does not support exclusion constraints on
+/// partitioned tables
+fn partition_ddl(
+    nsp: &Namespace,
+    parent: &str,
+    table: &Table,
+    range: Option<(BlockNumber, BlockNumber)>,
+) -> String {
+    let (name, bounds) = match range {
+        Some((k, blocks)) => (
+            format!("{}_p{}", table.name, k),
+            format!("for values from ({}) to ({})", k * blocks, (k + 1) * blocks),
+        ),
+        None => (format!("{}_pdefault", table.name), "default".to_string()),
+    };
+    format!(
+        "create table \"{nsp}\".\"{name}\" partition of {parent} {bounds};\n\
+         alter table \"{nsp}\".\"{name}\"\n    \
+             add primary key ({vid}),\n    \
+             add {id_constraint};\n",
+        nsp = nsp,
+        name = name,
+        parent = parent,
+        bounds = bounds,
+        vid = VID_COLUMN,
+        id_constraint = table.id_constraint()
+    )
+}
+
 /// A user-defined enum
 #[derive(Clone, Debug, PartialEq)]
 pub struct EnumType {
@@ -1134,6 +1792,17 @@ pub struct Table {
     /// entities are updated frequently on average
     pub is_account_like: bool,
 
+    /// If the table is partitioned, how many blocks each partition
+    /// covers. Entity versions are assigned to partitions by the start of
+    /// their block range
+    pub partition_blocks: Option<BlockNumber>,
+
+    /// Whether the entity type is declared with `@entity(immutable: true)`
+    /// and its table was created for immutable entities. Entities in such
+    /// a table are only ever inserted; the upper bound of their block range
+    /// is always open
+    pub immutable: bool,
+
     /// The position of this table in all the tables for this layout; this
     /// is really only needed for the tests to make the names of indexes
     /// predictable
@@ -1161,11 +1830,14 @@ impl Table {
             .collect::<Result<Vec<Column>, StoreError>>()?;
         let qualified_name = SqlName::qualified_name(&catalog.site.namespace, &table_name);
         let is_account_like = ACCOUNT_TABLES.contains(qualified_name.as_str());
+        let immutable = defn.is_immutable();
         let table = Table {
             object: EntityType::from(defn),
             name: table_name.clone(),
             qualified_name,
             is_account_like,
+            partition_blocks: None,
+            immutable,
             columns,
             position,
         };
@@ -1194,6 +1866,16 @@ impl Table {
     }
 
     fn can_copy_from(&self, source: &Self) -> Vec<String> {
+        // A mutable source can have several versions of the same entity,
+        // which can not be copied into an immutable table
+        let immutable = if self.immutable && !source.immutable {
+            Some(format!(
+                "The entity type {} is immutable, but it is mutable in the source",
+                self.object
+            ))
+        } else {
+            None
+        };
         self.columns
             .iter()
             .filter_map(|dcol| match source.column(&dcol.name) {
@@ -1210,6 +1892,7 @@ impl Table {
                     }
                 }
             })
+            .chain(immutable)
             .collect()
     }
 
@@ -1242,11 +1925,34 @@ impl Table {
             out,
             "\n        {vid}                  bigserial primary key,\
              \n        {block_range}          int4range not null,
-        exclude using gist   (id with =, {block_range} with &&)\n);\n",
+        {id_constraint}\n);\n",
             vid = VID_COLUMN,
-            block_range = BLOCK_RANGE_COLUMN
+            block_range = BLOCK_RANGE_COLUMN,
+            id_constraint = self.id_constraint()
         )?;
 
+        self.index_ddl(out, layout, self.name.as_str())
+    }
+
+    /// The constraint that makes sure that there is only one version of an
+    /// entity at any block. Immutable entities only ever have one version,
+    /// and a unique index on `id` is much cheaper to maintain and faster
+    /// for lookups by `id` than the exclusion constraint
+    fn id_constraint(&self) -> String {
+        if self.immutable {
+            "unique(id)".to_string()
+        } else {
+            format!(
+                "exclude using gist   (id with =, {} with &&)",
+                BLOCK_RANGE_COLUMN
+            )
+        }
+    }
+
+    /// Generate the `create index` statements for the table, using
+    /// `table_name` both for the table that gets indexed and in the names
+    /// of the indexes
+    fn index_ddl(&self, out: &mut String, layout: &Layout, table_name: &str) -> fmt::Result {
         // Add a BRIN index on the block_range bounds to exploit the fact
         // that block ranges closely correlate with where in a table an
         // entity appears physically. This index is incredibly efficient for
@@ -1271,31 +1977,37 @@ impl Table {
         write!(out,"create index brin_{table_name}\n    \
                     on {schema_name}.{table_name}\n \
                        using brin(lower(block_range), coalesce(upper(block_range), {block_max}), vid);\n",
-            table_name = self.name,
+            table_name = table_name,
             schema_name = layout.catalog.site.namespace,
             block_max = BLOCK_NUMBER_MAX)?;
 
         // Add a BTree index that helps with the `RevertClampQuery` by making
-        // it faster to find entity versions that have been modified
-        write!(
-            out,
-            "create index {table_name}_block_range_closed\n    \
-                     on {schema_name}.{table_name}(coalesce(upper(block_range), {block_max}))\n \
-                     where coalesce(upper(block_range), {block_max}) < {block_max};\n",
-            table_name = self.name,
-            schema_name = layout.catalog.site.namespace,
-            block_max = BLOCK_NUMBER_MAX
-        )?;
+        // it faster to find entity versions that have been modified. The
+        // block ranges of immutable entities are never closed
+        if !self.immutable {
+            write!(
+                out,
+                "create index {table_name}_block_range_closed\n    \
+                         on {schema_name}.{table_name}(coalesce(upper(block_range), {block_max}))\n \
+                         where coalesce(upper(block_range), {block_max}) < {block_max};\n",
+                table_name = table_name,
+                schema_name = layout.catalog.site.namespace,
+                block_max = BLOCK_NUMBER_MAX
+            )?;
+        }
 
         // Create indexes. Skip columns whose type is an array of enum,
         // since there is no good way to index them with Postgres 9.6.
         // Once we move to Postgres 11, we can enable that
         // (tracked in graph-node issue #1330)
+        // For immutable entities, the index for the `id` constraint already
+        // covers the primary key
         for (i, column) in self
             .columns
             .iter()
             .filter(|col| !(col.is_list() && col.is_enum()))
             .enumerate()
+            .filter(|(_, col)| !(self.immutable && col.is_primary_key()))
         {
             let (method, index_expr) = if column.is_reference() && !column.is_list() {
                 // For foreign keys, index the key together with the block range
@@ -1326,7 +2038,7 @@ impl Table {
                 out,
                 "create index attr_{table_index}_{column_index}_{table_name}_{column_name}\n    on {schema_name}.\"{table_name}\" using {method}({index_expr});\n",
                 table_index = self.position,
-                table_name = self.name,
+                table_name = table_name,
                 column_index = i,
                 column_name = column.name,
                 schema_name = layout.catalog.site.namespace,
@@ -1385,13 +2097,10 @@ impl LayoutCache {
         let subgraph_schema = deployment::schema(conn, site.as_ref())?;
         let has_poi = crate::catalog::supports_proof_of_indexing(conn, &site.namespace)?;
         let catalog = Catalog::new(conn, site.clone())?;
-        let layout = Arc::new(Layout::new(
-            site.clone(),
-            &subgraph_schema,
-            catalog,
-            has_poi,
-        )?);
-        layout.refresh(conn, site)
+        let mut layout = Layout::new(site.clone(), &subgraph_schema, catalog, has_poi)?;
+        layout.restrict_immutable(&crate::catalog::immutable_tables(conn, &site)?);
+        layout.statement_timeout = deployment::statement_timeout(conn, &site.deployment)?;
+        Arc::new(layout).refresh(conn, site)
     }
 
     fn cache(&self, layout: Arc<Layout>) {
@@ -1542,6 +2251,10 @@ mod tests {
         let layout = test_layout(FORWARD_ENUM_GQL);
         let sql = layout.as_ddl().expect("Failed to generate DDL");
         assert_eq!(FORWARD_ENUM_SQL, sql);
+
+        let layout = test_layout(IMMUTABLE_GQL);
+        let sql = layout.as_ddl().expect("Failed to generate DDL");
+        assert_eq!(IMMUTABLE_SQL, sql);
     }
 
     #[test]
@@ -1607,6 +2320,14 @@ mod tests {
             ],
             dest.can_copy_from(&source)
         );
+        // We can not make a mutable type immutable
+        let dest = test_layout("type Thing @entity(immutable: true) { id: ID!, bigThing: Thing! }");
+        assert_eq!(
+            vec!["The entity type Thing is immutable, but it is mutable in the source"],
+            dest.can_copy_from(&source)
+        );
+        assert!(source.can_copy_from(&dest).is_empty());
+
         // We can not change the underlying type of a field in arrays
         let source = test_layout("type Scalar { id: ID, color: [Int!]! }");
         let dest = test_layout("type Scalar { id: ID, color: [String!]! }");
@@ -2029,5 +2750,28 @@ create index attr_0_0_thing_id
 create index attr_0_1_thing_orientation
     on sgd0815.\"thing\" using btree(\"orientation\");
 
+";
+
+    const IMMUTABLE_GQL: &str = "
+type Transfer @entity(immutable: true) {
+    id: ID!,
+    amount: BigInt!
+}
+";
+
+    const IMMUTABLE_SQL: &str = "create table sgd0815.\"transfer\" (
+        \"id\"                 text not null,
+        \"amount\"             numeric not null,
+
+        vid                  bigserial primary key,
+        block_range          int4range not null,
+        unique(id)
+);
+create index brin_transfer
+    on sgd0815.transfer
+ using brin(lower(block_range), coalesce(upper(block_range), 2147483647), vid);
+create index attr_0_1_transfer_amount
+    on sgd0815.\"transfer\" using btree(\"amount\");
+
 ";
 }
@@ -9,7 +9,7 @@ use diesel::pg::{Pg, PgConnection};
 use diesel::query_builder::{AstPass, QueryFragment, QueryId};
 use diesel::query_dsl::{LoadQuery, RunQueryDsl};
 use diesel::result::{Error as DieselError, QueryResult};
-use diesel::sql_types::{Array, BigInt, Binary, Bool, Integer, Jsonb, Range, Text};
+use diesel::sql_types::{Array, BigInt, Binary, Bool, Integer, Jsonb, Nullable, Range, Text};
 use diesel::Connection;
 use lazy_static::lazy_static;
 
@@ -19,7 +19,7 @@ use graph::prelude::{
     QueryExecutionError, StoreError, Value,
 };
 use graph::{
-    components::store::{AttributeNames, EntityType},
+    components::store::{AttributeNames, Child as StoreChild, EntityType, REVERSIBLE_ORDER_BY_OFF},
     data::{schema::FulltextAlgorithm, store::scalar},
 };
 use itertools::Itertools;
@@ -76,18 +76,6 @@ lazy_static! {
             })
             .unwrap_or(false)
     };
-    /// Reversible order by. Change our `order by` clauses so that `asc`
-    /// and `desc` ordering produce reverse orders. Setting this
-    /// turns the new, correct behavior off
-    static ref REVERSIBLE_ORDER_BY_OFF: bool = {
-        env::var("REVERSIBLE_ORDER_BY_OFF")
-            .ok()
-            .map(|s| {
-                s == "1"
-            })
-            .unwrap_or(false)
-    };
-
     /// Those are columns that we always want to fetch from the database.
     static ref BASE_SQL_COLUMNS: BTreeSet<String> =
         ["id"].iter().map(ToString::to_string).collect();
@@ -774,27 +762,59 @@ impl<'a> QueryFragment<Pg> for PrefixComparison<'a> {
 /// the `where` clause of a SQL query. The attributes mentioned in
 /// the `filter` must all come from the given `table`, which is used to
 /// map GraphQL names to column names, and to determine the type of the
-/// column an attribute refers to
+/// column an attribute refers to. The `table` must be aliased to `c` in
+/// the query, since filters on child entities refer to it that way
 #[derive(Debug, Clone)]
 pub struct QueryFilter<'a> {
     filter: &'a EntityFilter,
+    layout: &'a Layout,
     table: &'a Table,
+    block: BlockNumber,
 }
 
 impl<'a> QueryFilter<'a> {
-    pub fn new(filter: &'a EntityFilter, table: &'a Table) -> Result<Self, StoreError> {
-        Self::valid_attributes(filter, table)?;
-        Ok(QueryFilter { filter, table })
+    pub fn new(
+        filter: &'a EntityFilter,
+        layout: &'a Layout,
+        table: &'a Table,
+        block: BlockNumber,
+    ) -> Result<Self, StoreError> {
+        Self::valid_attributes(filter, layout, table, false)?;
+        Ok(QueryFilter {
+            filter,
+            layout,
+            table,
+            block,
+        })
     }
 
-    fn valid_attributes(filter: &'a EntityFilter, table: &'a Table) -> Result<(), StoreError> {
+    fn valid_attributes(
+        filter: &'a EntityFilter,
+        layout: &'a Layout,
+        table: &'a Table,
+        is_child: bool,
+    ) -> Result<(), StoreError> {
         use EntityFilter::*;
         match filter {
             And(filters) | Or(filters) => {
                 for filter in filters {
-                    Self::valid_attributes(filter, table)?;
+                    Self::valid_attributes(filter, layout, table, is_child)?;
                 }
             }
+            Child(child) => {
+                if is_child {
+                    return Err(StoreError::QueryExecutionError(
+                        "nested child filters are not supported".to_string(),
+                    ));
+                }
+                let child_table = layout.table_for_entity(&child.entity_type)?;
+                if child.derived {
+                    child_table.column_for_field(&child.attr)?;
+                } else {
+                    table.column_for_field(&child.attr)?;
+                }
+                Self::valid_attributes(&child.filter, layout, child_table, true)?;
+            }
 
             Contains(attr, _)
             | NotContains(attr, _)
@@ -819,7 +839,9 @@ impl<'a> QueryFilter<'a> {
     fn with(&self, filter: &'a EntityFilter) -> Self {
         QueryFilter {
             filter,
+            layout: self.layout,
             table: self.table,
+            block: self.block,
         }
     }
 
@@ -1076,6 +1098,63 @@ impl<'a> QueryFilter<'a> {
         Ok(())
     }
 
+    fn child(&self, child: &StoreChild, mut out: AstPass<Pg>) -> QueryResult<()> {
+        let child_table = self
+            .layout
+            .table_for_entity(&child.entity_type)
+            .expect("the constructor already checked that the child table exists");
+        let (parent_column, child_column) = if child.derived {
+            let column = child_table
+                .column_for_field(&child.attr)
+                .expect("the constructor already checked that all attribute names are valid");
+            (self.table.primary_key(), column)
+        } else {
+            (self.column(&child.attr), child_table.primary_key())
+        };
+
+        // Generate
+        //   exists (select 1 from children i
+        //            where {join condition}
+        //              and i.block_range @> $block
+        //              and {child filter})
+        // where the join condition depends on which of the two columns,
+        // if any, is a list. Columns in the child filter are not qualified
+        // and therefore refer to the child table
+        out.push_sql("exists (select 1 from ");
+        out.push_sql(child_table.qualified_name.as_str());
+        out.push_sql(" i where ");
+        if parent_column.is_list() {
+            out.push_sql("i.");
+            out.push_identifier(child_column.name.as_str())?;
+            out.push_sql(" = any(c.");
+            out.push_identifier(parent_column.name.as_str())?;
+            out.push_sql(")");
+        } else if child_column.is_list() {
+            out.push_sql("c.");
+            out.push_identifier(parent_column.name.as_str())?;
+            out.push_sql(" = any(i.");
+            out.push_identifier(child_column.name.as_str())?;
+            out.push_sql(")");
+        } else {
+            out.push_sql("i.");
+            out.push_identifier(child_column.name.as_str())?;
+            out.push_sql(" = c.");
+            out.push_identifier(parent_column.name.as_str())?;
+        }
+        out.push_sql(" and ");
+        BlockRangeContainsClause::new(child_table, "i.", self.block).walk_ast(out.reborrow())?;
+        out.push_sql(" and ");
+        QueryFilter {
+            filter: &child.filter,
+            layout: self.layout,
+            table: child_table,
+            block: self.block,
+        }
+        .walk_ast(out.reborrow())?;
+        out.push_sql(")");
+        Ok(())
+    }
+
     fn starts_or_ends_with(
         &self,
         attribute: &Attribute,
@@ -1149,6 +1228,7 @@ impl<'a> QueryFragment<Pg> for QueryFilter<'a> {
             NotEndsWith(attr, value) => {
                 self.starts_or_ends_with(attr, value, " not like ", false, out)?
             }
+            Child(child) => self.child(child, out)?,
         }
         Ok(())
     }
@@ -1395,13 +1475,13 @@ impl<'a, Conn> RunQueryDsl<Conn> for InsertQuery<'a> {}
 pub struct ConflictingEntityQuery<'a> {
     layout: &'a Layout,
     tables: Vec<&'a Table>,
-    entity_id: &'a str,
+    entity_ids: &'a [&'a str],
 }
 impl<'a> ConflictingEntityQuery<'a> {
     pub fn new(
         layout: &'a Layout,
         entities: Vec<EntityType>,
-        entity_id: &'a str,
+        entity_ids: &'a [&'a str],
     ) -> Result<Self, StoreError> {
         let tables = entities
             .iter()
@@ -1410,7 +1490,7 @@ impl<'a> ConflictingEntityQuery<'a> {
         Ok(ConflictingEntityQuery {
             layout,
             tables,
-            entity_id,
+            entity_ids,
         })
     }
 }
@@ -1420,22 +1500,30 @@ impl<'a> QueryFragment<Pg> for ConflictingEntityQuery<'a> {
         out.unsafe_to_cache_prepared();
 
         // Construct a query
-        //   select 'Type1' as entity from schema.table1 where id = $1
+        //   select 'Type1' as entity, id from schema.table1 where id = any($1)
         //   union all
-        //   select 'Type2' as entity from schema.table2 where id = $1
+        //   select 'Type2' as entity, id from schema.table2 where id = any($1)
         //   union all
         //   ...
+        //   limit 1
         for (i, table) in self.tables.iter().enumerate() {
             if i > 0 {
                 out.push_sql("\nunion all\n");
             }
             out.push_sql("select ");
             out.push_bind_param::<Text, _>(&table.object.as_str())?;
-            out.push_sql(" as entity from ");
+            out.push_sql(" as entity, ");
+            match table.primary_key().column_type.id_type() {
+                IdType::String => out.push_sql("id"),
+                IdType::Bytes => out.push_sql("'0x' || encode(id, 'hex')"),
+            }
+            out.push_sql(" as id from ");
             out.push_sql(table.qualified_name.as_str());
-            out.push_sql(" where id = ");
-            out.push_bind_param::<Text, _>(&self.entity_id)?;
+            out.push_sql(" where id = any(");
+            table.primary_key().bind_ids(self.entity_ids, &mut out)?;
+            out.push_sql(")");
         }
+        out.push_sql("\nlimit 1");
         Ok(())
     }
 }
@@ -1450,6 +1538,8 @@ impl<'a> QueryId for ConflictingEntityQuery<'a> {
 pub struct ConflictingEntityData {
     #[sql_type = "Text"]
     pub entity: String,
+    #[sql_type = "Text"]
+    pub id: String,
 }
 
 impl<'a> LoadQuery<PgConnection, ConflictingEntityData> for ConflictingEntityQuery<'a> {
@@ -1602,6 +1692,7 @@ impl<'a> FilterWindow<'a> {
         layout: &'a Layout,
         window: EntityWindow,
         query_filter: Option<&'a EntityFilter>,
+        block: BlockNumber,
     ) -> Result<Self, QueryExecutionError> {
         let EntityWindow {
             child_type,
@@ -1611,7 +1702,7 @@ impl<'a> FilterWindow<'a> {
         } = window;
         let table = layout.table_for_entity(&child_type).map(|rc| rc.as_ref())?;
         let query_filter = query_filter
-            .map(|filter| QueryFilter::new(filter, table))
+            .map(|filter| QueryFilter::new(filter, layout, table, block))
             .transpose()?;
         let link = TableLink::new(table, link)?;
         Ok(FilterWindow {
@@ -1930,6 +2021,7 @@ impl<'a> FilterCollection<'a> {
         layout: &'a Layout,
         collection: EntityCollection,
         filter: Option<&'a EntityFilter>,
+        block: BlockNumber,
     ) -> Result<Self, QueryExecutionError> {
         match collection {
             EntityCollection::All(entities) => {
@@ -1945,7 +2037,7 @@ impl<'a> FilterCollection<'a> {
                             .map(|rc| rc.as_ref())
                             .and_then(|table| {
                                 filter
-                                    .map(|filter| QueryFilter::new(filter, table))
+                                    .map(|filter| QueryFilter::new(filter, layout, table, block))
                                     .transpose()
                                     .map(|filter| (table, filter, column_names.clone()))
                             })
@@ -1956,7 +2048,7 @@ impl<'a> FilterCollection<'a> {
             EntityCollection::Window(windows) => {
                 let windows = windows
                     .into_iter()
-                    .map(|window| FilterWindow::new(layout, window, filter))
+                    .map(|window| FilterWindow::new(layout, window, filter, block))
                     .collect::<Result<Vec<_>, _>>()?;
                 let collection = if windows.len() == 1 {
                     let mut windows = windows;
@@ -2562,6 +2654,204 @@ impl<'a> LoadQuery<PgConnection, EntityData> for FilterQuery<'a> {
 
 impl<'a, Conn> RunQueryDsl<Conn> for FilterQuery<'a> {}
 
+/// Count the entities in a table and compute the sum, average, minimum and
+/// maximum of some of their numeric columns, optionally grouped by the
+/// value of another column
+#[derive(Debug, Clone)]
+pub struct AggregateQuery<'a> {
+    table: &'a Table,
+    filter: Option<QueryFilter<'a>>,
+    group_by: Option<&'a Column>,
+    columns: Vec<&'a Column>,
+    range: FilterRange,
+    block: BlockNumber,
+}
+
+impl<'a> AggregateQuery<'a> {
+    pub fn new(
+        layout: &'a Layout,
+        table: &'a Table,
+        filter: Option<&'a EntityFilter>,
+        group_by: Option<&str>,
+        attributes: &[Attribute],
+        range: EntityRange,
+        block: BlockNumber,
+    ) -> Result<Self, QueryExecutionError> {
+        let filter = filter
+            .map(|filter| QueryFilter::new(filter, layout, table, block))
+            .transpose()?;
+        let group_by = group_by
+            .map(|attr| table.column_for_field(attr))
+            .transpose()?;
+        let columns = attributes
+            .iter()
+            .map(|attr| table.column_for_field(attr))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(AggregateQuery {
+            table,
+            filter,
+            group_by,
+            columns,
+            range: FilterRange(range),
+            block,
+        })
+    }
+
+    /// Generate `jsonb_build_object('attr', {func}(c.attr)::text, ..)`.
+    /// Postgres functions take at most 100 arguments; for more columns,
+    /// generate several `jsonb_build_object` and concatenate them with `||`
+    fn aggregates(&self, func: &str, out: &mut AstPass<Pg>) -> QueryResult<()> {
+        // Each column takes two arguments
+        const MAX_COLUMNS: usize = 50;
+
+        if self.columns.is_empty() {
+            out.push_sql("jsonb_build_object()");
+            return Ok(());
+        }
+        for (i, chunk) in self.columns.chunks(MAX_COLUMNS).enumerate() {
+            if i > 0 {
+                out.push_sql(" || ");
+            }
+            out.push_sql("jsonb_build_object(");
+            for (j, column) in chunk.iter().enumerate() {
+                if j > 0 {
+                    out.push_sql(", ");
+                }
+                out.push_bind_param::<Text, _>(&column.field)?;
+                out.push_sql(", ");
+                out.push_sql(func);
+                out.push_sql("(c.");
+                out.push_identifier(column.name.as_str())?;
+                out.push_sql(")::text");
+            }
+            out.push_sql(")");
+        }
+        Ok(())
+    }
+}
+
+impl<'a> QueryFragment<Pg> for AggregateQuery<'a> {
+    fn walk_ast(&self, mut out: AstPass<Pg>) -> QueryResult<()> {
+        out.unsafe_to_cache_prepared();
+
+        // Generate
+        //   select {group column}::text as "group", count(*) as count,
+        //          jsonb_build_object('attr', sum(c.attr)::text, ..) as sum,
+        //          .. the same for avg, min, and max ..
+        //     from {table} c
+        //    where c.block_range @> $block
+        //      and {filter}
+        //    group by 1
+        //    order by 1
+        //    limit .. offset ..
+        //
+        // Without `group_by`, the query returns exactly one row, and
+        // `range` is ignored since it would only ever hide that row
+        out.push_sql("select ");
+        match self.group_by {
+            Some(column) => {
+                if matches!(column.column_type, ColumnType::Bytes | ColumnType::BytesId) {
+                    out.push_sql("'0x' || encode(c.");
+                    out.push_identifier(column.name.as_str())?;
+                    out.push_sql(", 'hex')");
+                } else {
+                    out.push_sql("c.");
+                    out.push_identifier(column.name.as_str())?;
+                    out.push_sql("::text");
+                }
+            }
+            None => out.push_sql("null::text"),
+        }
+        out.push_sql(" as \"group\", count(*) as count");
+        for func in &["sum", "avg", "min", "max"] {
+            out.push_sql(",\n       ");
+            self.aggregates(func, &mut out)?;
+            out.push_sql(" as ");
+            out.push_sql(func);
+        }
+        out.push_sql("\n  from ");
+        out.push_sql(self.table.qualified_name.as_str());
+        out.push_sql(" c\n where ");
+        BlockRangeContainsClause::new(&self.table, "c.", self.block).walk_ast(out.reborrow())?;
+        if let Some(filter) = &self.filter {
+            out.push_sql(" and ");
+            filter.walk_ast(out.reborrow())?;
+        }
+        if self.group_by.is_some() {
+            out.push_sql("\n group by 1\n order by 1");
+            self.range.walk_ast(out)?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> QueryId for AggregateQuery<'a> {
+    type QueryId = ();
+
+    const HAS_STATIC_QUERY_ID: bool = false;
+}
+
+impl<'a> LoadQuery<PgConnection, AggregateData> for AggregateQuery<'a> {
+    fn internal_load(self, conn: &PgConnection) -> QueryResult<Vec<AggregateData>> {
+        conn.query_by_name(&self)
+    }
+}
+
+impl<'a, Conn> RunQueryDsl<Conn> for AggregateQuery<'a> {}
+
+/// One group of entities as computed by an `AggregateQuery`. The
+/// aggregates are Jsonb objects mapping attribute names to the aggregate
+/// of that attribute as a string
+#[derive(QueryableByName)]
+pub struct AggregateData {
+    #[sql_type = "Nullable<Text>"]
+    group: Option<String>,
+    #[sql_type = "BigInt"]
+    count: i64,
+    #[sql_type = "Jsonb"]
+    sum: serde_json::Value,
+    #[sql_type = "Jsonb"]
+    avg: serde_json::Value,
+    #[sql_type = "Jsonb"]
+    min: serde_json::Value,
+    #[sql_type = "Jsonb"]
+    max: serde_json::Value,
+}
+
+impl AggregateData {
+    pub fn into_map(self) -> BTreeMap<String, r::Value> {
+        fn aggregates(json: serde_json::Value) -> r::Value {
+            use serde_json::Value as j;
+            match json {
+                j::Object(map) => r::Value::Object(
+                    map.into_iter()
+                        .map(|(attr, value)| {
+                            let value = match value {
+                                j::String(s) => r::Value::String(s),
+                                _ => r::Value::Null,
+                            };
+                            (attr, value)
+                        })
+                        .collect(),
+                ),
+                _ => r::Value::Null,
+            }
+        }
+
+        let mut map = BTreeMap::new();
+        map.insert(
+            "group".to_string(),
+            self.group.map(r::Value::String).unwrap_or(r::Value::Null),
+        );
+        map.insert("count".to_string(), r::Value::Int(self.count));
+        map.insert("sum".to_string(), aggregates(self.sum));
+        map.insert("avg".to_string(), aggregates(self.avg));
+        map.insert("min".to_string(), aggregates(self.min));
+        map.insert("max".to_string(), aggregates(self.max));
+        map
+    }
+}
+
 /// Reduce the upper bound of the current entry's block range to `block` as
 /// long as that does not result in an empty block range
 #[derive(Debug, Clone, Constructor)]
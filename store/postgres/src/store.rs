@@ -9,12 +9,16 @@ use graph::{
     constraint_violation,
     data::subgraph::status,
     prelude::{
-        tokio, web3::types::Address, BlockPtr, CheapClone, DeploymentHash, QueryExecutionError,
-        StoreError,
+        tokio, web3::types::Address, BlockNumber, BlockPtr, CheapClone, DeploymentHash,
+        QueryExecutionError, StoreError,
     },
 };
 
-use crate::{block_store::BlockStore, query_store::QueryStore, SubgraphStore};
+use crate::{
+    block_store::BlockStore,
+    query_store::{QueryStore, REORG_THRESHOLD},
+    SubgraphStore,
+};
 
 /// The overall store of the system, consisting of a [SubgraphStore] and a
 /// [BlockStore], each of which multiplex across multiple database shards.
@@ -45,6 +49,45 @@ impl Store {
     pub fn block_store(&self) -> Arc<BlockStore> {
         self.block_store.cheap_clone()
     }
+
+    /// Remove blocks that no deployment needs anymore from the block cache
+    /// of every chain. For each chain, we keep the blocks that are less
+    /// than `retention` blocks behind the chain head, since the block
+    /// ingestor consults them frequently, and the blocks from the one that
+    /// the slowest deployment has processed onwards, only considering
+    /// deployments that are assigned and have not failed. Since the
+    /// deployments of a chain can be spread over several shards, this has
+    /// to look at all of them. The genesis block is never removed.
+    ///
+    /// Return the name of each chain from which blocks were removed, the
+    /// number of the oldest block retained, and how many blocks were
+    /// removed
+    pub fn cleanup_block_cache(
+        &self,
+        retention: BlockNumber,
+    ) -> Result<Vec<(String, BlockNumber, usize)>, StoreError> {
+        let mut cleaned = Vec::new();
+        for chain_store in self.block_store.chain_stores() {
+            let chain = chain_store.chain.as_str();
+            let head = chain_store
+                .chain_head_block(chain)?
+                .map(|head| head - retention);
+            let slowest = self.subgraph_store.min_assigned_block(chain)?;
+            let keep = match (head, slowest) {
+                (Some(head), Some(slowest)) => head.min(slowest),
+                (Some(block), None) | (None, Some(block)) => block,
+                (None, None) => continue,
+            };
+            if keep <= 0 {
+                continue;
+            }
+            let removed = chain_store.delete_blocks_before(keep)?;
+            if removed > 0 {
+                cleaned.push((chain.to_string(), keep, removed));
+            }
+        }
+        Ok(cleaned)
+    }
 }
 
 #[async_trait]
@@ -93,6 +136,10 @@ impl StatusStore for Store {
         Ok(infos)
     }
 
+    fn chain_statuses(&self) -> Result<Vec<status::ChainStatus>, StoreError> {
+        self.block_store.chain_statuses()
+    }
+
     fn version_info(&self, version_id: &str) -> Result<VersionInfo, StoreError> {
         let mut info = self.subgraph_store.version_info(version_id)?;
 
@@ -127,6 +174,46 @@ impl StatusStore for Store {
             .await
     }
 
+    async fn entity_diff(
+        &self,
+        subgraph_id: &DeploymentHash,
+        from: BlockNumber,
+        to: BlockNumber,
+        after: Option<(String, String)>,
+        first: usize,
+    ) -> Result<Vec<status::EntityDiff>, StoreError> {
+        self.subgraph_store
+            .entity_diff(subgraph_id, from, to, after, first)
+            .await
+    }
+
+    fn deployment_sizes(&self) -> Result<Vec<status::DeploymentSize>, StoreError> {
+        self.subgraph_store.deployment_sizes()
+    }
+
+    fn finalized_block(
+        &self,
+        subgraph_id: &DeploymentHash,
+    ) -> Result<Option<BlockNumber>, StoreError> {
+        let infos = self
+            .subgraph_store
+            .status(status::Filter::Deployments(vec![subgraph_id.to_string()]))?;
+        let chain = match infos.iter().flat_map(|info| info.chains.iter()).next() {
+            Some(chain) => chain,
+            None => return Err(StoreError::DeploymentNotFound(subgraph_id.to_string())),
+        };
+        let latest = match &chain.latest_block {
+            Some(latest) => latest.number(),
+            None => return Ok(None),
+        };
+        let head = match self.block_store.chain_head_block(&chain.network)? {
+            Some(head) => head,
+            None => return Ok(None),
+        };
+        let finalized = latest.min(head - *REORG_THRESHOLD);
+        Ok(Some(finalized).filter(|finalized| *finalized >= 0))
+    }
+
     async fn query_permit(&self) -> tokio::sync::OwnedSemaphorePermit {
         // Status queries go to the primary shard.
         self.block_store.query_permit_primary().await
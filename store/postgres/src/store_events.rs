@@ -81,6 +81,10 @@ impl StoreEventListener {
     pub fn start(&mut self) {
         self.notification_listener.start()
     }
+
+    pub fn set_postgres_url(&self, postgres_url: String) {
+        self.notification_listener.set_postgres_url(postgres_url)
+    }
 }
 
 /// Manage subscriptions to the `StoreEvent` stream. Keep a list of
@@ -111,6 +115,12 @@ impl SubscriptionManager {
         manager
     }
 
+    /// Use `postgres_url` when the listener for store events needs to
+    /// reconnect to the database
+    pub fn set_postgres_url(&self, postgres_url: String) {
+        self.listener.set_postgres_url(postgres_url)
+    }
+
     /// Receive store events from Postgres and send them to all active
     /// subscriptions. Detect stale subscriptions in the process and
     /// close them.
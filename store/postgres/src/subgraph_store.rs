@@ -4,9 +4,17 @@ use diesel::{
     sql_types::Text,
     types::{FromSql, ToSql},
 };
-use std::{collections::BTreeMap, collections::HashMap, sync::Arc};
+use std::{
+    collections::BTreeMap,
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex, Weak},
+};
 use std::{fmt, io::Write};
-use std::{iter::FromIterator, time::Duration};
+use std::{
+    iter::FromIterator,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
 
 use graph::{
     cheap_clone::CheapClone,
@@ -22,9 +30,9 @@ use graph::{
     prelude::SubgraphDeploymentEntity,
     prelude::{
         anyhow, futures03::future::join_all, lazy_static, o, web3::types::Address, ApiSchema,
-        BlockPtr, DeploymentHash, Entity, EntityKey, EntityModification, Error, Logger, NodeId,
-        Schema, StopwatchMetrics, StoreError, SubgraphName, SubgraphStore as SubgraphStoreTrait,
-        SubgraphVersionSwitchingMode,
+        BlockNumber, BlockPtr, DeploymentHash, Entity, EntityKey, EntityModification, Error,
+        Logger, NodeId, Schema, StopwatchMetrics, StoreError, SubgraphName,
+        SubgraphStore as SubgraphStoreTrait, SubgraphVersionSwitchingMode,
     },
     slog::{error, warn},
     util::{backoff::ExponentialBackoff, timed_cache::TimedCache},
@@ -32,14 +40,16 @@ use graph::{
 use store::StoredDynamicDataSource;
 
 use crate::{
+    catalog::IndexInfo,
     connection_pool::ConnectionPool,
     primary,
-    primary::{DeploymentId, Mirror as PrimaryMirror, Site},
-    relational::Layout,
+    primary::{DeploymentId, Handoff, Mirror as PrimaryMirror, Site},
+    relational::{Layout, PruneReporter},
+    write_queue::{Request, WriteQueue, WRITE_QUEUE_SIZE},
     NotificationSender,
 };
 use crate::{
-    deployment_store::{DeploymentStore, ReplicaId},
+    deployment_store::{DeploymentStore, Inconsistencies, ReplicaId},
     detail::DeploymentDetail,
     primary::UnusedDeployment,
 };
@@ -246,6 +256,9 @@ pub struct SubgraphStoreInner {
     sites: TimedCache<DeploymentHash, Site>,
     placer: Arc<dyn DeploymentPlacer + Send + Sync + 'static>,
     sender: Arc<NotificationSender>,
+    /// The write queues of deployments, shared by all writable stores for
+    /// the same deployment
+    write_queues: Mutex<HashMap<DeploymentId, Weak<WriteQueue>>>,
 }
 
 impl SubgraphStoreInner {
@@ -299,7 +312,25 @@ impl SubgraphStoreInner {
             sites,
             placer,
             sender,
+            write_queues: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Get the write queue for `site`, using `create` to make a new one
+    /// if there is no queue for it yet
+    fn write_queue(
+        &self,
+        site: &Site,
+        create: impl FnOnce() -> Arc<WriteQueue>,
+    ) -> Arc<WriteQueue> {
+        let mut queues = self.write_queues.lock().unwrap();
+        if let Some(queue) = queues.get(&site.id).and_then(Weak::upgrade) {
+            return queue;
         }
+        queues.retain(|_, queue| queue.strong_count() > 0);
+        let queue = create();
+        queues.insert(site.id, Arc::downgrade(&queue));
+        queue
     }
 
     // Only needed for tests
@@ -377,6 +408,23 @@ impl SubgraphStoreInner {
         store.find_layout(site)
     }
 
+    /// Check that `base` still has the data that a graft or a copy from
+    /// it at `block` needs, i.e., that `block` was not pruned away
+    fn check_base_block(&self, base: &Site, block: BlockNumber) -> Result<(), StoreError> {
+        if let Some(pruned_block) = self.for_site(base)?.pruned_block(base)? {
+            if block < pruned_block {
+                return Err(StoreError::Unknown(anyhow!(
+                    "can not use block {} of deployment {} as the base since it was pruned \
+                     and only has data from block {} on",
+                    block,
+                    base.deployment,
+                    pruned_block
+                )));
+            }
+        }
+        Ok(())
+    }
+
     fn place(
         &self,
         name: &SubgraphName,
@@ -463,6 +511,9 @@ impl SubgraphStoreInner {
             .transpose()?;
 
         if let Some(graft_base) = &graft_base {
+            if let Some(block) = &deployment.graft_block {
+                self.check_base_block(graft_base.site.as_ref(), block.number)?;
+            }
             self.primary_conn()?
                 .record_active_copy(graft_base.site.as_ref(), site.as_ref())?;
         }
@@ -508,6 +559,7 @@ impl SubgraphStoreInner {
     ) -> Result<DeploymentLocator, StoreError> {
         let src = self.find_site(src.id.into())?;
         let src_store = self.for_site(src.as_ref())?;
+        self.check_base_block(src.as_ref(), block.number)?;
         let src_info = src_store.subgraph_info(src.as_ref())?;
         let src_loc = DeploymentLocator::from(src.as_ref());
 
@@ -598,6 +650,56 @@ impl SubgraphStoreInner {
         Ok(())
     }
 
+    /// Make the copy `dst` the active deployment for its IPFS hash once
+    /// copying has finished and it has caught up with the deployment that
+    /// is active now. `activate_caught_up_copies` does the actual work
+    pub fn request_copy_activation(&self, dst: &DeploymentLocator) -> Result<(), StoreError> {
+        let dst = self.find_site(dst.id.into())?;
+        self.primary_conn()?.request_copy_activation(dst.as_ref())
+    }
+
+    /// Activate all copies for which `request_copy_activation` was called
+    /// and which have caught up with the deployment that is active now.
+    /// Returns the copies that were activated
+    pub fn activate_caught_up_copies(&self) -> Result<Vec<DeploymentLocator>, StoreError> {
+        let mut activated = Vec::new();
+        for id in self.primary_conn()?.copy_activations()? {
+            let dst = self.find_site(id)?;
+            let loc = DeploymentLocator::from(dst.as_ref());
+            let pconn = self.primary_conn()?;
+            if pconn.copy_in_progress(dst.as_ref())? {
+                continue;
+            }
+
+            let src = match pconn.find_active_site(&dst.deployment)? {
+                Some(src) if src.id != dst.id => src,
+                // The copy is already active, or nothing else is
+                _ => {
+                    pconn.activate(&loc)?;
+                    pconn.clear_copy_activation(dst.as_ref())?;
+                    continue;
+                }
+            };
+            let src_ptr = self.for_site(&src)?.block_ptr(&src)?;
+            let dst_ptr = self.for_site(dst.as_ref())?.block_ptr(dst.as_ref())?;
+            let caught_up = match (src_ptr, dst_ptr) {
+                (None, _) => true,
+                (Some(_), None) => false,
+                (Some(src_ptr), Some(dst_ptr)) => dst_ptr.number >= src_ptr.number,
+            };
+            if !caught_up {
+                continue;
+            }
+
+            pconn.transaction(|| -> Result<_, StoreError> {
+                pconn.activate(&loc)?;
+                pconn.clear_copy_activation(dst.as_ref())
+            })?;
+            activated.push(loc);
+        }
+        Ok(activated)
+    }
+
     // Only for tests to simplify their handling of test fixtures, so that
     // tests can reset the block pointer of a subgraph by recreating it
     #[cfg(debug_assertions)]
@@ -636,6 +738,16 @@ impl SubgraphStoreInner {
     ) -> Result<(Arc<DeploymentStore>, Arc<Site>, ReplicaId), StoreError> {
         let id = match target {
             QueryTarget::Name(name) => self.mirror.current_deployment_for_subgraph(&name)?,
+            QueryTarget::Pending(name) => self
+                .mirror
+                .subgraph_version(name.as_str(), false)?
+                .map(|site| site.deployment.clone())
+                .ok_or_else(|| {
+                    StoreError::QueryExecutionError(format!(
+                        "Subgraph `{}` has no pending version",
+                        name.as_str()
+                    ))
+                })?,
             QueryTarget::Deployment(id) => id,
         };
 
@@ -874,6 +986,235 @@ impl SubgraphStoreInner {
         self.send_store_event(&event)
     }
 
+    /// Remove entity versions of `deployment` that are only visible more
+    /// than `history_blocks` blocks before its current block. Returns the
+    /// earliest block for which the deployment still has complete data, or
+    /// `None` if it is already being pruned by somebody else
+    pub fn prune(
+        &self,
+        reporter: &mut dyn PruneReporter,
+        deployment: &DeploymentHash,
+        history_blocks: BlockNumber,
+    ) -> Result<Option<BlockNumber>, StoreError> {
+        let (store, site) = self.store(deployment)?;
+        store.prune(reporter, site, history_blocks)
+    }
+
+    /// Partition the tables of `deployment`; see
+    /// `DeploymentStore::partition` for details
+    pub fn partition(
+        &self,
+        deployment: &DeploymentHash,
+        tables: &[String],
+        blocks: BlockNumber,
+        min_rows: i64,
+    ) -> Result<Vec<(String, usize)>, StoreError> {
+        let (store, site) = self.store(deployment)?;
+        store.partition(site, tables, blocks, min_rows)
+    }
+
+    /// Check that the data of `deployment` matches its block pointer and,
+    /// with `repair`, remove data from later blocks; see
+    /// `DeploymentStore::check` for details
+    pub fn check(
+        &self,
+        deployment: &DeploymentHash,
+        repair: bool,
+    ) -> Result<Inconsistencies, StoreError> {
+        let (store, site) = self.store(deployment)?;
+        store.check(site, repair)
+    }
+
+    /// Create an index on the attributes `fields` of `entity` and return
+    /// its name
+    pub fn create_manual_index(
+        &self,
+        deployment: &DeploymentHash,
+        entity: &str,
+        fields: &[String],
+        method: &str,
+    ) -> Result<String, StoreError> {
+        let (store, site) = self.store(deployment)?;
+        store.create_manual_index(site, entity, fields, method)
+    }
+
+    pub fn indexes_for_entity(
+        &self,
+        deployment: &DeploymentHash,
+        entity: &str,
+    ) -> Result<Vec<IndexInfo>, StoreError> {
+        let (store, site) = self.store(deployment)?;
+        store.indexes_for_entity(site, entity)
+    }
+
+    pub fn drop_manual_index(
+        &self,
+        deployment: &DeploymentHash,
+        name: &str,
+    ) -> Result<(), StoreError> {
+        let (store, site) = self.store(deployment)?;
+        store.drop_manual_index(site, name)
+    }
+
+    /// Create the partitions that partitioned tables in all shards will
+    /// need soon. Returns the number of partitions that were created.
+    /// Failures for a shard or a deployment are logged and do not keep
+    /// the partitions of other deployments from being created
+    pub fn extend_partitions(&self, logger: &Logger) -> Result<usize, StoreError> {
+        let mut partitioned = HashMap::new();
+        for (shard, store) in &self.stores {
+            match store.partitioned_deployments() {
+                Ok(ids) => {
+                    partitioned.insert(shard, ids);
+                }
+                Err(e) => error!(logger, "Failed to find partitioned deployments";
+                                 "shard" => shard.as_str(), "error" => e.to_string()),
+            }
+        }
+
+        let mut created = 0;
+        for site in self.primary_conn()?.sites()? {
+            let store = match self.stores.get(&site.shard) {
+                Some(store) => store,
+                None => continue,
+            };
+            if partitioned
+                .get(&site.shard)
+                .map_or(false, |ids| ids.contains(&site.id))
+            {
+                let deployment = site.deployment.to_string();
+                match store.extend_partitions(Arc::new(site)) {
+                    Ok(count) => created += count,
+                    Err(e) => error!(logger, "Failed to create partitions";
+                                     "deployment" => deployment, "error" => e.to_string()),
+                }
+            }
+        }
+        Ok(created)
+    }
+
+    /// Measure how much space each deployment takes up on disk and record
+    /// that in its metadata. Return the number of deployments that were
+    /// measured
+    pub fn refresh_sizes(&self) -> Result<usize, StoreError> {
+        let mut refreshed = 0;
+        for site in self.primary_conn()?.sites()? {
+            if let Some(store) = self.stores.get(&site.shard) {
+                store.refresh_size(&site)?;
+                refreshed += 1;
+            }
+        }
+        Ok(refreshed)
+    }
+
+    /// The sizes of all deployments in all shards, largest first. The
+    /// entity counts are always current, but the sizes on disk are only as
+    /// current as the last call to `refresh_sizes`
+    pub fn deployment_sizes(&self) -> Result<Vec<status::DeploymentSize>, StoreError> {
+        let mut sizes = Vec::new();
+        for store in self.stores.values() {
+            sizes.extend(store.deployment_sizes()?);
+        }
+        sizes.sort_by(|a, b| {
+            b.size_bytes
+                .cmp(&a.size_bytes)
+                .then_with(|| b.entity_count.cmp(&a.entity_count))
+        });
+        Ok(sizes)
+    }
+
+    /// The lowest block that any deployment of `network` that is assigned
+    /// to a node and has not failed has processed, or `None` if there is
+    /// no such deployment
+    pub(crate) fn min_assigned_block(
+        &self,
+        network: &str,
+    ) -> Result<Option<BlockNumber>, StoreError> {
+        let mut ids: HashMap<Shard, Vec<DeploymentId>> = HashMap::new();
+        for site in self.primary_conn()?.assigned_sites_for_network(network)? {
+            ids.entry(site.shard).or_default().push(site.id);
+        }
+
+        let mut min_block: Option<BlockNumber> = None;
+        for (shard, ids) in ids {
+            let store = self
+                .stores
+                .get(&shard)
+                .ok_or_else(|| StoreError::UnknownShard(shard.as_str().to_string()))?;
+            if let Some(block) = store.min_latest_block(&ids)? {
+                min_block = Some(min_block.map_or(block, |min_block| min_block.min(block)));
+            }
+        }
+        Ok(min_block)
+    }
+
+    /// The active deployments that have advanced by more than
+    /// `history_blocks` blocks since they were last pruned, i.e., that
+    /// have about twice as much history as they need to keep
+    pub async fn deployments_to_prune(
+        &self,
+        history_blocks: BlockNumber,
+    ) -> Result<Vec<DeploymentHash>, StoreError> {
+        let sites = self.primary_conn()?.sites()?;
+        let mut deployments = Vec::new();
+        for site in sites.into_iter().filter(|site| site.active) {
+            let (store, _) = self.store(&site.deployment)?;
+            let state = store
+                .deployment_state_from_id(site.deployment.clone())
+                .await?;
+            let pruned_block = state.pruned_block.unwrap_or(0);
+            if state.latest_ethereum_block_number - pruned_block > 2 * history_blocks {
+                deployments.push(site.deployment);
+            }
+        }
+        Ok(deployments)
+    }
+
+    /// Ask the process that currently runs as `node` to stop indexing so
+    /// that a new process can take over
+    pub fn request_handoff(&self, node: &NodeId) -> Result<(), StoreError> {
+        self.primary_conn()?.request_handoff(node)
+    }
+
+    pub fn handoff(&self, node: &NodeId) -> Result<Option<Handoff>, StoreError> {
+        self.primary_conn()?.handoff(node)
+    }
+
+    pub fn release_handoff(&self, node: &NodeId) -> Result<(), StoreError> {
+        self.primary_conn()?.release_handoff(node)
+    }
+
+    pub fn clear_handoff(&self, node: &NodeId) -> Result<(), StoreError> {
+        self.primary_conn()?.clear_handoff(node)
+    }
+
+    /// Stop or resume serving queries for `deployment`. Queries for a
+    /// deployment with disabled queries fail with an error while indexing
+    /// continues as usual
+    pub fn set_queries_disabled(
+        &self,
+        deployment: &DeploymentLocator,
+        disabled: bool,
+    ) -> Result<(), StoreError> {
+        let site = self.find_site(deployment.id.into())?;
+        let store = self.for_site(site.as_ref())?;
+        store.set_queries_disabled(site, disabled)
+    }
+
+    /// Set the statement timeout in seconds for queries against
+    /// `deployment`, overriding `GRAPH_SQL_STATEMENT_TIMEOUT`, or go back
+    /// to that default for `None`. Query nodes pick up the change the next
+    /// time they refresh their cached layout of the deployment
+    pub fn set_statement_timeout(
+        &self,
+        deployment: &DeploymentLocator,
+        timeout: Option<u32>,
+    ) -> Result<(), StoreError> {
+        let site = self.find_site(deployment.id.into())?;
+        let store = self.for_site(site.as_ref())?;
+        store.set_statement_timeout(site, timeout)
+    }
+
     pub(crate) async fn get_proof_of_indexing(
         &self,
         id: &DeploymentHash,
@@ -884,6 +1225,18 @@ impl SubgraphStoreInner {
         store.get_proof_of_indexing(site, indexer, block).await
     }
 
+    pub(crate) async fn entity_diff(
+        &self,
+        id: &DeploymentHash,
+        from: BlockNumber,
+        to: BlockNumber,
+        after: Option<(String, String)>,
+        first: usize,
+    ) -> Result<Vec<status::EntityDiff>, StoreError> {
+        let (store, site) = self.store(&id)?;
+        store.entity_diff(site, from, to, after, first).await
+    }
+
     // Only used by tests
     #[cfg(debug_assertions)]
     pub fn find(
@@ -980,6 +1333,15 @@ impl SubgraphStoreTrait for SubgraphStore {
             .map(|sites| sites.iter().map(|site| site.into()).collect())
     }
 
+    fn rewind(&self, id: DeploymentHash, block_ptr_to: BlockPtr) -> Result<(), StoreError> {
+        self.inner.rewind(id, block_ptr_to)
+    }
+
+    fn is_indexing(&self, id: &DeploymentHash) -> Result<bool, StoreError> {
+        let (store, site) = self.store(id)?;
+        store.is_indexing(site.as_ref())
+    }
+
     fn subgraph_exists(&self, name: &SubgraphName) -> Result<bool, StoreError> {
         self.mirror.subgraph_exists(name)
     }
@@ -990,6 +1352,16 @@ impl SubgraphStoreTrait for SubgraphStore {
         Ok(info.input)
     }
 
+    fn raw_manifest(&self, id: &DeploymentHash) -> Result<Option<String>, StoreError> {
+        let (store, site) = self.store(&id)?;
+        store.raw_manifest(site.as_ref())
+    }
+
+    fn abis(&self, id: &DeploymentHash) -> Result<Option<String>, StoreError> {
+        let (store, site) = self.store(&id)?;
+        store.abis(site.as_ref())
+    }
+
     fn api_schema(&self, id: &DeploymentHash) -> Result<Arc<ApiSchema>, StoreError> {
         let (store, site) = self.store(&id)?;
         let info = store.subgraph_info(&site)?;
@@ -1047,6 +1419,11 @@ impl SubgraphStoreTrait for SubgraphStore {
             .map(|site| site.into())
             .collect())
     }
+
+    fn network_name(&self, deployment: &DeploymentLocator) -> Result<String, StoreError> {
+        let site = self.find_site(deployment.id.into())?;
+        Ok(site.network.clone())
+    }
 }
 
 /// A wrapper around `SubgraphStore` that only exposes functions that are
@@ -1054,6 +1431,7 @@ impl SubgraphStoreTrait for SubgraphStore {
 /// deal with anything that depends on a specific deployment
 /// location/instance, or where the result is independent of the deployment
 /// instance
+#[derive(Clone)]
 struct WritableSubgraphStore(SubgraphStore);
 
 impl WritableSubgraphStore {
@@ -1075,11 +1453,19 @@ struct WritableStore {
     store: WritableSubgraphStore,
     writable: Arc<DeploymentStore>,
     site: Arc<Site>,
+    /// Whether we hold the lock that keeps other graph-node processes from
+    /// indexing this deployment
+    indexing_locked: AtomicBool,
+    /// Changes that have not been written yet, if writes are queued
+    queue: Option<Arc<WriteQueue>>,
 }
 
 impl WritableStore {
     const BACKOFF_BASE: Duration = Duration::from_millis(100);
     const BACKOFF_CEIL: Duration = Duration::from_secs(10);
+    /// How long to wait for another process to stop indexing the
+    /// deployment before giving up
+    const INDEXING_LOCK_TIMEOUT: Duration = Duration::from_secs(300);
 
     fn new(
         subgraph_store: SubgraphStore,
@@ -1088,39 +1474,79 @@ impl WritableStore {
     ) -> Result<Self, StoreError> {
         let store = WritableSubgraphStore(subgraph_store.clone());
         let writable = subgraph_store.for_site(site.as_ref())?.clone();
+        let queue = if *WRITE_QUEUE_SIZE > 0 {
+            let queue = subgraph_store.inner.write_queue(site.as_ref(), || {
+                let logger = logger.clone();
+                let store = store.clone();
+                let writable = writable.clone();
+                let site = site.clone();
+                WriteQueue::new(
+                    *WRITE_QUEUE_SIZE,
+                    Box::new(move |requests: &[Arc<Request>]| {
+                        commit(&logger, &store, &writable, &site, requests)
+                    }),
+                )
+            });
+            Some(queue)
+        } else {
+            None
+        };
         Ok(Self {
             logger,
             store,
             writable,
             site,
+            indexing_locked: AtomicBool::new(false),
+            queue,
         })
     }
 
-    fn log_backoff_warning(&self, op: &str, backoff: &ExponentialBackoff) {
-        warn!(self.logger,
-            "database unavailable, will retry";
-            "operation" => op,
-            "attempt" => backoff.attempt,
-            "delay_ms" => backoff.delay().as_millis());
+    /// Wait until all queued changes have been written
+    fn flush_queue(&self) -> Result<(), StoreError> {
+        match &self.queue {
+            Some(queue) => queue.flush(),
+            None => Ok(()),
+        }
     }
 
-    fn retry<T, F>(&self, op: &str, f: F) -> Result<T, StoreError>
-    where
-        F: Fn() -> Result<T, StoreError>,
-    {
+    /// Get the lock that keeps other graph-node processes from indexing
+    /// this deployment, waiting for a while if another process holds it
+    fn lock_indexing(&self) -> Result<(), StoreError> {
+        if self.indexing_locked.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+
+        let start = Instant::now();
         let mut backoff = ExponentialBackoff::new(Self::BACKOFF_BASE, Self::BACKOFF_CEIL);
         loop {
-            match f() {
-                Ok(v) => return Ok(v),
-                Err(StoreError::DatabaseUnavailable) => {
-                    self.log_backoff_warning(op, &backoff);
-                }
-                Err(e) => return Err(e),
+            if self.retry("lock_indexing", || {
+                self.writable.try_lock_indexing(self.site.as_ref())
+            })? {
+                self.indexing_locked.store(true, Ordering::SeqCst);
+                return Ok(());
+            }
+            if start.elapsed() >= Self::INDEXING_LOCK_TIMEOUT {
+                return Err(StoreError::Unknown(anyhow!(
+                    "deployment {} is being indexed by another graph-node process; \
+                     make sure that only one process indexes it",
+                    self.site.deployment
+                )));
             }
+            warn!(self.logger,
+                "deployment is being indexed by another graph-node process, will retry";
+                "attempt" => backoff.attempt,
+                "delay_ms" => backoff.delay().as_millis());
             backoff.sleep();
         }
     }
 
+    fn retry<T, F>(&self, op: &str, f: F) -> Result<T, StoreError>
+    where
+        F: Fn() -> Result<T, StoreError>,
+    {
+        retry(&self.logger, op, f)
+    }
+
     async fn retry_async<T, F, Fut>(&self, op: &str, f: F) -> Result<T, StoreError>
     where
         F: Fn() -> Fut,
@@ -1131,7 +1557,7 @@ impl WritableStore {
             match f().await {
                 Ok(v) => return Ok(v),
                 Err(StoreError::DatabaseUnavailable) => {
-                    self.log_backoff_warning(op, &backoff);
+                    log_backoff_warning(&self.logger, op, &backoff);
                 }
                 Err(e) => return Err(e),
             }
@@ -1139,31 +1565,98 @@ impl WritableStore {
         }
     }
 
-    /// Try to send a `StoreEvent`; if sending fails, log the error but
-    /// return `Ok(())`
     fn try_send_store_event(&self, event: StoreEvent) -> Result<(), StoreError> {
-        if *SEND_SUBSCRIPTION_NOTIFICATIONS {
-            let _ = self.store.send_store_event(&event).map_err(
-                |e| error!(self.logger, "Could not send store event"; "error" => e.to_string()),
-            );
-            Ok(())
-        } else {
-            Ok(())
+        try_send_store_event(&self.logger, &self.store, event)
+    }
+}
+
+fn log_backoff_warning(logger: &Logger, op: &str, backoff: &ExponentialBackoff) {
+    warn!(logger,
+        "database unavailable, will retry";
+        "operation" => op,
+        "attempt" => backoff.attempt,
+        "delay_ms" => backoff.delay().as_millis());
+}
+
+/// Run `f` until it succeeds or fails with an error other than
+/// `StoreError::DatabaseUnavailable`
+fn retry<T, F>(logger: &Logger, op: &str, f: F) -> Result<T, StoreError>
+where
+    F: Fn() -> Result<T, StoreError>,
+{
+    let mut backoff =
+        ExponentialBackoff::new(WritableStore::BACKOFF_BASE, WritableStore::BACKOFF_CEIL);
+    loop {
+        match f() {
+            Ok(v) => return Ok(v),
+            Err(StoreError::DatabaseUnavailable) => {
+                log_backoff_warning(logger, op, &backoff);
+            }
+            Err(e) => return Err(e),
         }
+        backoff.sleep();
+    }
+}
+
+/// Try to send a `StoreEvent`; if sending fails, log the error but
+/// return `Ok(())`
+fn try_send_store_event(
+    logger: &Logger,
+    store: &WritableSubgraphStore,
+    event: StoreEvent,
+) -> Result<(), StoreError> {
+    if *SEND_SUBSCRIPTION_NOTIFICATIONS {
+        let _ = store
+            .send_store_event(&event)
+            .map_err(|e| error!(logger, "Could not send store event"; "error" => e.to_string()));
+        Ok(())
+    } else {
+        Ok(())
     }
 }
 
+/// Write the changes for consecutive blocks to the database in one
+/// transaction
+fn commit(
+    logger: &Logger,
+    store: &WritableSubgraphStore,
+    writable: &DeploymentStore,
+    site: &Arc<Site>,
+    requests: &[Arc<Request>],
+) -> Result<(), StoreError> {
+    retry(logger, "transact_block_operations", || {
+        let event = writable.transact_block_operations(site.clone(), requests)?;
+
+        match requests.last() {
+            Some(request) => {
+                let _section = request.stopwatch.start_section("send_store_event");
+                try_send_store_event(logger, store, event)
+            }
+            None => Ok(()),
+        }
+    })
+}
+
 #[async_trait::async_trait]
 impl WritableStoreTrait for WritableStore {
     fn block_ptr(&self) -> Result<Option<BlockPtr>, StoreError> {
+        if let Some(ptr) = self.queue.as_ref().and_then(|queue| queue.block_ptr()) {
+            return Ok(Some(ptr));
+        }
         self.retry("block_ptr", || self.writable.block_ptr(self.site.as_ref()))
     }
 
     fn block_cursor(&self) -> Result<Option<String>, StoreError> {
+        if let Some(cursor) = self.queue.as_ref().and_then(|queue| queue.block_cursor()) {
+            return Ok(cursor);
+        }
         self.writable.block_cursor(self.site.as_ref())
     }
 
     fn start_subgraph_deployment(&self, logger: &Logger) -> Result<(), StoreError> {
+        self.lock_indexing()?;
+        self.flush_queue()?;
+
         self.retry("start_subgraph_deployment", || {
             let store = &self.writable;
 
@@ -1180,6 +1673,7 @@ impl WritableStoreTrait for WritableStore {
     }
 
     fn revert_block_operations(&self, block_ptr_to: BlockPtr) -> Result<(), StoreError> {
+        self.flush_queue()?;
         self.retry("revert_block_operations", || {
             let event = self
                 .writable
@@ -1193,6 +1687,7 @@ impl WritableStoreTrait for WritableStore {
         current_ptr: Option<BlockPtr>,
         parent_ptr: Option<BlockPtr>,
     ) -> Result<(), StoreError> {
+        self.flush_queue()?;
         self.retry("unfail", || {
             let current_ptr = current_ptr.as_ref();
             let parent_ptr = parent_ptr.as_ref();
@@ -1203,6 +1698,7 @@ impl WritableStoreTrait for WritableStore {
     }
 
     async fn fail_subgraph(&self, error: SubgraphError) -> Result<(), StoreError> {
+        self.flush_queue()?;
         self.retry_async("fail_subgraph", || {
             let error = error.clone();
             async {
@@ -1225,6 +1721,9 @@ impl WritableStoreTrait for WritableStore {
     }
 
     fn get(&self, key: &EntityKey) -> Result<Option<Entity>, StoreError> {
+        if let Some(entity) = self.queue.as_ref().and_then(|queue| queue.get(key)) {
+            return Ok(entity);
+        }
         self.retry("get", || self.writable.get(self.site.cheap_clone(), key))
     }
 
@@ -1241,30 +1740,75 @@ impl WritableStoreTrait for WritableStore {
             same_subgraph(&mods, &self.site.deployment),
             "can only transact operations within one shard"
         );
-        self.retry("transact_block_operations", move || {
-            let event = self.writable.transact_block_operations(
-                self.site.clone(),
-                &block_ptr_to,
-                firehose_cursor.as_deref(),
-                &mods,
-                stopwatch.cheap_clone(),
-                &data_sources,
-                &deterministic_errors,
-            )?;
-
-            let _section = stopwatch.start_section("send_store_event");
-            self.try_send_store_event(event)
-        })
+        let request = Request {
+            block_ptr: block_ptr_to,
+            firehose_cursor,
+            mods,
+            stopwatch,
+            data_sources,
+            deterministic_errors,
+        };
+        match &self.queue {
+            Some(queue) => queue.push(request),
+            None => commit(
+                &self.logger,
+                &self.store,
+                &self.writable,
+                &self.site,
+                &[Arc::new(request)],
+            ),
+        }
     }
 
     fn get_many(
         &self,
         ids_for_type: BTreeMap<&EntityType, Vec<&str>>,
     ) -> Result<BTreeMap<EntityType, Vec<Entity>>, StoreError> {
-        self.retry("get_many", || {
-            self.writable
-                .get_many(self.site.cheap_clone(), &ids_for_type)
-        })
+        let changes = match &self.queue {
+            Some(queue) => queue.changes(&ids_for_type),
+            None => HashMap::new(),
+        };
+        if changes.is_empty() {
+            return self.retry("get_many", || {
+                self.writable
+                    .get_many(self.site.cheap_clone(), &ids_for_type)
+            });
+        }
+
+        // Only load entities without queued changes from the database,
+        // and use the queued version for the others
+        let queued: HashSet<(&EntityType, &str)> = changes
+            .keys()
+            .map(|key| (&key.entity_type, key.entity_id.as_str()))
+            .collect();
+        let ids_for_type: BTreeMap<&EntityType, Vec<&str>> = ids_for_type
+            .into_iter()
+            .map(|(entity_type, ids)| {
+                let ids = ids
+                    .into_iter()
+                    .filter(|id| !queued.contains(&(entity_type, *id)))
+                    .collect::<Vec<_>>();
+                (entity_type, ids)
+            })
+            .filter(|(_, ids)| !ids.is_empty())
+            .collect();
+        let mut entities = if ids_for_type.is_empty() {
+            BTreeMap::new()
+        } else {
+            self.retry("get_many", || {
+                self.writable
+                    .get_many(self.site.cheap_clone(), &ids_for_type)
+            })?
+        };
+        for (key, entity) in changes {
+            if let Some(entity) = entity {
+                entities
+                    .entry(key.entity_type)
+                    .or_insert_with(Vec::new)
+                    .push(entity);
+            }
+        }
+        Ok(entities)
     }
 
     async fn is_deployment_synced(&self) -> Result<bool, StoreError> {
@@ -1277,6 +1821,7 @@ impl WritableStoreTrait for WritableStore {
     }
 
     fn unassign_subgraph(&self) -> Result<(), StoreError> {
+        self.flush_queue()?;
         self.retry("unassign_subgraph", || {
             let pconn = self.store.primary_conn()?;
             pconn.transaction(|| -> Result<_, StoreError> {
@@ -1287,6 +1832,7 @@ impl WritableStoreTrait for WritableStore {
     }
 
     async fn load_dynamic_data_sources(&self) -> Result<Vec<StoredDynamicDataSource>, StoreError> {
+        self.flush_queue()?;
         self.retry_async("load_dynamic_data_sources", || async {
             self.writable
                 .load_dynamic_data_sources(self.site.deployment.clone())
@@ -1296,6 +1842,7 @@ impl WritableStoreTrait for WritableStore {
     }
 
     fn deployment_synced(&self) -> Result<(), StoreError> {
+        self.flush_queue()?;
         self.retry("deployment_synced", || {
             let event = {
                 // Make sure we drop `pconn` before we call into the deployment
@@ -1314,11 +1861,39 @@ impl WritableStoreTrait for WritableStore {
         })
     }
 
+    fn update_sync_rate(&self, blocks_per_second: f64) -> Result<(), StoreError> {
+        self.retry("update_sync_rate", || {
+            self.writable
+                .update_sync_rate(&self.site.deployment, blocks_per_second)
+        })
+    }
+
+    fn flush(&self) -> Result<(), StoreError> {
+        self.flush_queue()
+    }
+
     fn shard(&self) -> &str {
         self.site.shard.as_str()
     }
 }
 
+impl Drop for WritableStore {
+    fn drop(&mut self) {
+        if self.indexing_locked.load(Ordering::SeqCst) {
+            // Other processes must not start indexing before our queued
+            // changes are written
+            if let Err(e) = self.flush_queue() {
+                error!(self.logger, "Failed to write queued changes";
+                                    "error" => e.to_string());
+            }
+            if let Err(e) = self.writable.unlock_indexing(self.site.as_ref()) {
+                error!(self.logger, "Failed to release indexing lock";
+                                    "error" => e.to_string());
+            }
+        }
+    }
+}
+
 fn same_subgraph(mods: &Vec<EntityModification>, id: &DeploymentHash) -> bool {
     mods.iter().all(|md| &md.entity_key().subgraph_id == id)
 }
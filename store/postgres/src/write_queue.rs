@@ -0,0 +1,342 @@
+//! A bounded queue of block writes for a deployment. Indexing hands the
+//! changes for a block to the queue and can go on to process the next
+//! block while a background task commits queued blocks to the database in
+//! order. When the queue is full, indexing waits until the oldest block has
+//! been written. All blocks that are queued when the background task gets
+//! to them are written in one transaction, which speeds up syncing when
+//! the database is the bottleneck.
+//!
+//! Since queued changes are not in the database yet, reads of entities and
+//! of the block pointer have to look at the queue first. All writable
+//! stores for a deployment therefore share the same queue, so that, for
+//! example, the block stream sees the same block pointer as the code that
+//! processes blocks.
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+
+use graph::{
+    components::store::{EntityType, StoredDynamicDataSource},
+    data::subgraph::schema::SubgraphError,
+    prelude::{
+        anyhow, lazy_static, BlockPtr, Entity, EntityKey, EntityModification, StopwatchMetrics,
+        StoreError,
+    },
+};
+
+lazy_static! {
+    /// How many blocks of changes a deployment can have queued for writing
+    /// before processing further blocks has to wait for the database. If
+    /// this is 0, the changes for each block are written before the next
+    /// block is processed
+    pub static ref WRITE_QUEUE_SIZE: usize = std::env::var("GRAPH_STORE_WRITE_QUEUE")
+        .ok()
+        .map(|s| {
+            s.parse::<usize>()
+                .expect("GRAPH_STORE_WRITE_QUEUE must be a number")
+        })
+        .unwrap_or(0);
+}
+
+/// The changes for one block, as they are passed to
+/// `WritableStore::transact_block_operations`
+pub(crate) struct Request {
+    pub block_ptr: BlockPtr,
+    pub firehose_cursor: Option<String>,
+    pub mods: Vec<EntityModification>,
+    pub stopwatch: StopwatchMetrics,
+    pub data_sources: Vec<StoredDynamicDataSource>,
+    pub deterministic_errors: Vec<SubgraphError>,
+}
+
+type Commit = Box<dyn Fn(&[Arc<Request>]) -> Result<(), StoreError> + Send + Sync>;
+
+#[derive(Default)]
+struct State {
+    /// Blocks that have not been committed yet, oldest first. Blocks stay
+    /// in the queue while they are being written so that reads can see
+    /// their changes until they are in the database
+    pending: VecDeque<Arc<Request>>,
+    /// Whether a background task is writing the pending blocks
+    writing: bool,
+    /// The error that made writing a block fail. It is reported to the
+    /// next caller of `push` or `flush`
+    error: Option<StoreError>,
+}
+
+pub(crate) struct WriteQueue {
+    capacity: usize,
+    commit: Commit,
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+impl WriteQueue {
+    /// Create a queue that holds at most `capacity` blocks and uses
+    /// `commit` to write them. `commit` gets all blocks that are queued
+    /// when the writer is ready for more work, oldest first
+    pub fn new(capacity: usize, commit: Commit) -> Arc<Self> {
+        Arc::new(WriteQueue {
+            capacity: capacity.max(1),
+            commit,
+            state: Mutex::new(State::default()),
+            changed: Condvar::new(),
+        })
+    }
+
+    fn state(&self) -> MutexGuard<State> {
+        self.state.lock().unwrap()
+    }
+
+    fn take_error(state: &mut State) -> Result<(), StoreError> {
+        match state.error.take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// Add the changes for a block to the queue, waiting for room in the
+    /// queue if it is full. If writing an earlier block failed, return
+    /// that error and drop `request`
+    pub fn push(self: &Arc<Self>, request: Request) -> Result<(), StoreError> {
+        let mut state = self.state();
+        while state.pending.len() >= self.capacity && state.error.is_none() {
+            state = self.changed.wait(state).unwrap();
+        }
+        Self::take_error(&mut state)?;
+
+        state.pending.push_back(Arc::new(request));
+        if !state.writing {
+            state.writing = true;
+            let queue = self.clone();
+            graph::spawn_blocking_allow_panic(move || queue.write());
+        }
+        Ok(())
+    }
+
+    /// Write pending blocks until the queue is empty
+    fn write(&self) {
+        // Make sure that waiting callers do not hang if committing panics
+        struct Guard<'a>(&'a WriteQueue);
+
+        impl Drop for Guard<'_> {
+            fn drop(&mut self) {
+                if !std::thread::panicking() {
+                    return;
+                }
+                let mut state = self.0.state();
+                state.writing = false;
+                if !state.pending.is_empty() {
+                    state.pending.clear();
+                    state.error = Some(StoreError::Unknown(anyhow!(
+                        "writing queued changes to the database panicked"
+                    )));
+                }
+                self.0.changed.notify_all();
+            }
+        }
+
+        let _guard = Guard(self);
+        loop {
+            // Finding the queue empty and clearing `writing` have to happen
+            // under the same lock; otherwise a `push` in between would not
+            // start a writer and its block would never be written
+            let batch: Vec<_> = {
+                let mut state = self.state();
+                if state.pending.is_empty() {
+                    state.writing = false;
+                    return;
+                }
+                state.pending.iter().cloned().collect()
+            };
+
+            let res = (self.commit)(&batch);
+
+            let mut state = self.state();
+            match res {
+                Ok(()) => {
+                    state.pending.drain(..batch.len());
+                }
+                Err(e) => {
+                    // Later blocks build on the one that failed; drop them
+                    // so that indexing can restart from what is in the
+                    // database
+                    state.pending.clear();
+                    state.error = Some(e);
+                }
+            }
+            self.changed.notify_all();
+        }
+    }
+
+    /// Wait until all queued blocks have been written. Return the error
+    /// that happened while writing if there was one
+    pub fn flush(&self) -> Result<(), StoreError> {
+        let mut state = self.state();
+        while !state.pending.is_empty() {
+            state = self.changed.wait(state).unwrap();
+        }
+        Self::take_error(&mut state)
+    }
+
+    /// The block pointer of the latest queued block, or `None` if nothing
+    /// is queued
+    pub fn block_ptr(&self) -> Option<BlockPtr> {
+        self.state()
+            .pending
+            .back()
+            .map(|request| request.block_ptr.clone())
+    }
+
+    /// The firehose cursor of the latest queued block, or `None` if nothing
+    /// is queued
+    pub fn block_cursor(&self) -> Option<Option<String>> {
+        self.state()
+            .pending
+            .back()
+            .map(|request| request.firehose_cursor.clone())
+    }
+
+    /// The queued changes for the given entities. An entry that is `None`
+    /// means that the entity was removed; entities that do not appear in
+    /// the result have no queued changes
+    pub fn changes(
+        &self,
+        ids_for_type: &BTreeMap<&EntityType, Vec<&str>>,
+    ) -> HashMap<EntityKey, Option<Entity>> {
+        let state = self.state();
+        if state.pending.is_empty() {
+            return HashMap::new();
+        }
+
+        let wanted: HashSet<(&EntityType, &str)> = ids_for_type
+            .iter()
+            .flat_map(|(entity_type, ids)| ids.iter().map(move |id| (*entity_type, *id)))
+            .collect();
+        let mut changes = HashMap::new();
+        for request in state.pending.iter() {
+            for modification in request.mods.iter() {
+                let key = modification.entity_key();
+                if !wanted.contains(&(&key.entity_type, key.entity_id.as_str())) {
+                    continue;
+                }
+                let data = match modification {
+                    EntityModification::Insert { data, .. }
+                    | EntityModification::Overwrite { data, .. } => Some(data.clone()),
+                    EntityModification::Remove { .. } => None,
+                };
+                changes.insert(key.clone(), data);
+            }
+        }
+        changes
+    }
+
+    /// The queued change for `key`, if there is one. The inner `None`
+    /// means that the entity was removed
+    pub fn get(&self, key: &EntityKey) -> Option<Option<Entity>> {
+        let mut ids_for_type = BTreeMap::new();
+        ids_for_type.insert(&key.entity_type, vec![key.entity_id.as_str()]);
+        self.changes(&ids_for_type).remove(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{mpsc, Arc, Mutex};
+
+    use graph::prelude::*;
+    use graph_mock::MockMetricsRegistry;
+
+    use super::{Request, WriteQueue};
+
+    fn request(number: i32) -> Request {
+        let deployment = DeploymentHash::new("writequeue").unwrap();
+        let logger = Logger::root(slog::Discard, o!());
+        let registry = Arc::new(MockMetricsRegistry::new());
+        Request {
+            block_ptr: BlockPtr::from((web3::types::H256::zero(), number)),
+            firehose_cursor: None,
+            mods: vec![],
+            stopwatch: StopwatchMetrics::new(logger, deployment, registry),
+            data_sources: vec![],
+            deterministic_errors: vec![],
+        }
+    }
+
+    /// Pushing while the writer is about to find the queue empty must not
+    /// strand the pushed block; before that was fixed, `flush` would hang
+    /// in this test every so often
+    #[tokio::test(flavor = "multi_thread")]
+    async fn push_and_drain_concurrently() {
+        const BLOCKS: usize = 2_000;
+
+        let written = Arc::new(AtomicUsize::new(0));
+        let counter = written.clone();
+        let queue = WriteQueue::new(
+            4,
+            Box::new(move |batch| {
+                counter.fetch_add(batch.len(), Ordering::SeqCst);
+                Ok(())
+            }),
+        );
+
+        let pusher = {
+            let queue = queue.clone();
+            graph::spawn_blocking_allow_panic(move || {
+                for number in 0..BLOCKS {
+                    queue.push(request(number as i32)).unwrap();
+                    if number % 3 == 0 {
+                        queue.flush().unwrap();
+                    }
+                }
+            })
+        };
+        tokio::time::timeout(std::time::Duration::from_secs(60), pusher)
+            .await
+            .expect("pushing blocks hung")
+            .unwrap();
+
+        let flush = graph::spawn_blocking_allow_panic(move || queue.flush());
+        tokio::time::timeout(std::time::Duration::from_secs(60), flush)
+            .await
+            .expect("flushing the queue hung")
+            .unwrap()
+            .unwrap();
+        assert_eq!(BLOCKS, written.load(Ordering::SeqCst));
+    }
+
+    /// Blocks that are pushed while the writer is busy are written
+    /// together in the next batch
+    #[tokio::test(flavor = "multi_thread")]
+    async fn batch_queued_blocks() {
+        let (started_tx, started_rx) = mpsc::channel();
+        let (done_tx, done_rx) = mpsc::channel::<()>();
+        let (started_tx, done_rx) = (Mutex::new(started_tx), Mutex::new(done_rx));
+        let queue = WriteQueue::new(
+            10,
+            Box::new(move |batch| {
+                let numbers: Vec<_> = batch.iter().map(|req| req.block_ptr.number).collect();
+                started_tx.lock().unwrap().send(numbers).unwrap();
+                done_rx.lock().unwrap().recv().unwrap();
+                Ok(())
+            }),
+        );
+
+        let test = graph::spawn_blocking_allow_panic(move || {
+            queue.push(request(0)).unwrap();
+            assert_eq!(vec![0], started_rx.recv().unwrap());
+            for number in 1..4 {
+                queue.push(request(number)).unwrap();
+            }
+            done_tx.send(()).unwrap();
+            assert_eq!(vec![1, 2, 3], started_rx.recv().unwrap());
+            done_tx.send(()).unwrap();
+            queue.flush().unwrap();
+            assert!(queue.block_ptr().is_none());
+        });
+        tokio::time::timeout(std::time::Duration::from_secs(60), test)
+            .await
+            .expect("writing batches hung")
+            .unwrap();
+    }
+}
@@ -12,7 +12,9 @@ use graph::{cheap_clone::CheapClone, prelude::web3::types::H160};
 use graph::{components::store::BlockStore as _, prelude::DeploymentHash};
 use graph::{components::store::ChainStore as _, prelude::EthereumCallCache as _};
 use graph_store_postgres::Store as DieselStore;
-use graph_store_postgres::{layout_for_tests::FAKE_NETWORK_SHARED, ChainStore as DieselChainStore};
+use graph_store_postgres::{
+    layout_for_tests::FAKE_NETWORK_SHARED, ChainStore as DieselChainStore, ClearCallCacheError,
+};
 
 use test_store::block_store::{
     FakeBlock, FakeBlockList, BLOCK_FIVE, BLOCK_FOUR, BLOCK_ONE, BLOCK_ONE_NO_PARENT,
@@ -197,6 +199,49 @@ fn block_number() {
     })
 }
 
+/// Check that looking up the first block at or after timestamp 0 on
+/// `chain` gives `expected`. All fake blocks have timestamp 0
+fn check_block_number_by_timestamp(chain: FakeBlockList, expected: Option<BlockNumber>) {
+    let subgraph = DeploymentHash::new("nonExistentSubgraph").unwrap();
+
+    run_test_async(chain, move |store, subgraph_store| {
+        let subgraph = subgraph.cheap_clone();
+        async move {
+            create_test_subgraph(&subgraph, "type Dummy @entity { id: ID! }");
+
+            store
+                .clone()
+                .attempt_chain_head_update(ANCESTOR_COUNT)
+                .await
+                .expect("attempt_chain_head_update failed");
+
+            let query_store = subgraph_store
+                .query_store(subgraph.cheap_clone().into(), false)
+                .await
+                .unwrap();
+
+            let block = query_store
+                .block_number_by_timestamp(0)
+                .expect("looked up block by timestamp");
+            assert_eq!(expected, block);
+        }
+    })
+}
+
+#[test]
+fn block_number_by_timestamp() {
+    check_block_number_by_timestamp(vec![&*GENESIS_BLOCK, &*BLOCK_ONE, &*BLOCK_TWO], Some(0));
+}
+
+#[test]
+fn block_number_by_timestamp_missing_blocks() {
+    // BLOCK_ONE is the first block we have with a timestamp at or after
+    // 0, but since its parent is missing, we can't tell that it is the
+    // first such block on the chain
+    let chain = vec![&*BLOCK_ONE, &*BLOCK_TWO, &*BLOCK_THREE, &*BLOCK_FOUR];
+    check_block_number_by_timestamp(chain, None);
+}
+
 #[test]
 fn block_hashes_by_number() {
     let chain = vec![
@@ -242,6 +287,26 @@ fn block_hashes_by_number() {
     })
 }
 
+#[test]
+fn chain_status() {
+    let chain = vec![&*GENESIS_BLOCK, &*BLOCK_ONE, &*BLOCK_TWO];
+    run_test(chain, move |store, _| {
+        store.set_provider_head(Ok(5))?;
+        let status = store.status()?;
+        assert_eq!(3, status.cached_blocks);
+        assert!(status.chain_head_block.is_none());
+        assert_eq!(Some(5), status.provider_head_block);
+        assert_eq!(None, status.provider_error);
+
+        // A failed poll keeps the last head we saw
+        store.set_provider_head(Err("provider is down".to_string()))?;
+        let status = store.status()?;
+        assert_eq!(Some(5), status.provider_head_block);
+        assert_eq!(Some("provider is down".to_string()), status.provider_error);
+        Ok(())
+    })
+}
+
 #[track_caller]
 fn check_ancestor(
     store: &Arc<DieselChainStore>,
@@ -317,6 +382,45 @@ fn ancestor_block_ommers() {
     });
 }
 
+#[test]
+fn ancestor_ptr() {
+    let chain = vec![
+        &*GENESIS_BLOCK,
+        &*BLOCK_ONE,
+        &*BLOCK_ONE_SIBLING,
+        &*BLOCK_TWO,
+        &*BLOCK_THREE,
+    ];
+
+    run_test(chain, move |store, _| -> Result<(), Error> {
+        // Ask twice so that the second lookup is answered from memory
+        for _ in 0..2 {
+            for (offset, exp) in [
+                (0, &*BLOCK_THREE),
+                (1, &*BLOCK_TWO),
+                (2, &*BLOCK_ONE),
+                (3, &*GENESIS_BLOCK),
+            ]
+            .iter()
+            {
+                let act = store.ancestor_ptr(BLOCK_THREE.block_ptr(), *offset)?;
+                assert_eq!(Some(exp.block_ptr()), act, "offset {}", offset);
+            }
+        }
+
+        let act = store.ancestor_ptr(BLOCK_ONE_SIBLING.block_ptr(), 1)?;
+        assert_eq!(Some(GENESIS_BLOCK.block_ptr()), act);
+
+        assert!(store.ancestor_ptr(BLOCK_THREE.block_ptr(), 4).is_err());
+        // BLOCK_TWO_NO_PARENT is not in the store
+        assert_eq!(
+            None,
+            store.ancestor_ptr(BLOCK_TWO_NO_PARENT.block_ptr(), 2)?
+        );
+        Ok(())
+    });
+}
+
 #[test]
 fn eth_call_cache() {
     let chain = vec![&*GENESIS_BLOCK, &*BLOCK_ONE, &*BLOCK_TWO];
@@ -372,3 +476,39 @@ fn test_transaction_receipts_in_block_function() {
         assert!(receipts.is_empty())
     })
 }
+
+#[test]
+fn clear_call_cache() {
+    let chain = vec![&*GENESIS_BLOCK, &*BLOCK_ONE, &*BLOCK_TWO];
+
+    run_test(chain, |store, _| {
+        let address = H160([1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+        let call: [u8; 6] = [1, 2, 3, 4, 5, 6];
+        let return_value: [u8; 3] = [7, 8, 9];
+
+        store.set_call(address, &call, BLOCK_ONE.block_ptr(), &return_value)?;
+        store.set_call(address, &call, BLOCK_TWO.block_ptr(), &return_value)?;
+
+        // Clearing a shared call cache would remove the cached calls of
+        // other chains, too, and needs to be asked for explicitly
+        if store.chain == FAKE_NETWORK_SHARED {
+            assert!(matches!(
+                store.clear_call_cache(1, 1, false),
+                Err(ClearCallCacheError::Shared(_))
+            ));
+            assert!(store
+                .get_call(address, &call, BLOCK_ONE.block_ptr())?
+                .is_some());
+        }
+        let all_chains = store.chain == FAKE_NETWORK_SHARED;
+        assert_eq!(1, store.clear_call_cache(1, 1, all_chains)?);
+
+        assert!(store
+            .get_call(address, &call, BLOCK_ONE.block_ptr())?
+            .is_none());
+        assert!(store
+            .get_call(address, &call, BLOCK_TWO.block_ptr())?
+            .is_some());
+        Ok(())
+    })
+}
@@ -1,10 +1,12 @@
 //! Test mapping of GraphQL schema to a relational schema
 use diesel::connection::SimpleConnection as _;
 use diesel::pg::PgConnection;
+use diesel::Connection as _;
+use diesel::RunQueryDsl as _;
 use graph::prelude::{
-    o, slog, tokio, web3::types::H256, DeploymentHash, Entity, EntityCollection, EntityFilter,
-    EntityKey, EntityOrder, EntityQuery, EntityRange, Logger, Schema, StopwatchMetrics, Value,
-    ValueType, BLOCK_NUMBER_MAX,
+    o, r, slog, tokio, web3::types::H256, DeploymentHash, Entity, EntityCollection, EntityFilter,
+    EntityKey, EntityOrder, EntityQuery, EntityRange, Logger, Schema, StopwatchMetrics, StoreError,
+    Value, ValueType, BLOCK_NUMBER_MAX,
 };
 use graph_mock::MockMetricsRegistry;
 use graph_store_postgres::layout_for_tests::set_account_like;
@@ -21,10 +23,12 @@ use std::time::Duration;
 use graph::{
     components::store::{AttributeNames, EntityType},
     data::store::scalar::{BigDecimal, BigInt, Bytes},
+    data::subgraph::status::EntityDiffOperation,
 };
 use graph_store_postgres::{
     layout_for_tests::make_dummy_site,
     layout_for_tests::{Layout, Namespace, STRING_PREFIX_SIZE},
+    PruneReporter,
 };
 
 use test_store::*;
@@ -120,6 +124,11 @@ const THINGS_GQL: &str = r#"
         description: String,
         test: String
     }
+
+    type Transfer @entity(immutable: true) {
+        id: ID!,
+        amount: Int!
+    }
 "#;
 
 lazy_static! {
@@ -760,6 +769,41 @@ fn delete() {
     });
 }
 
+#[test]
+fn immutable_entities_can_not_change() {
+    run_test(|conn, layout| {
+        let mut transfer = Entity::new();
+        transfer.set("id", "t1");
+        transfer.set("amount", 10);
+        transfer.set("__typename", "Transfer");
+        insert_entity(&conn, &layout, "Transfer", vec![transfer.clone()]);
+
+        let entity_type = EntityType::from("Transfer");
+        let key = EntityKey::data(
+            THINGS_SUBGRAPH_ID.clone(),
+            "Transfer".to_owned(),
+            "t1".to_owned(),
+        );
+        transfer.set("amount", 20);
+        let mut entities = vec![(&key, Cow::from(&transfer))];
+        let err = layout
+            .update(&conn, &entity_type, &mut entities, 1, &MOCK_STOPWATCH)
+            .expect_err("updating an immutable entity fails");
+        assert!(matches!(err, StoreError::ImmutableEntityChanged(_, _)));
+
+        let err = layout
+            .delete(&conn, &entity_type, &["t1"], 1, &MOCK_STOPWATCH)
+            .expect_err("deleting an immutable entity fails");
+        assert!(matches!(err, StoreError::ImmutableEntityChanged(_, _)));
+
+        let entity = layout
+            .find(&conn, &entity_type, "t1", BLOCK_NUMBER_MAX)
+            .expect("Failed to read Transfer[t1]")
+            .unwrap();
+        assert_eq!(Some(&Value::from(10)), entity.get("amount"));
+    });
+}
+
 #[test]
 fn insert_many_and_delete_many() {
     run_test(|conn, layout| {
@@ -784,6 +828,129 @@ fn insert_many_and_delete_many() {
     });
 }
 
+#[test]
+fn entity_diff() {
+    run_test(|conn, layout| {
+        insert_pets(&conn, &layout);
+
+        let dog = EntityType::from("Dog");
+        let cat = EntityType::from("Cat");
+
+        // At block 2, rename pluto and remove garfield
+        let mut pluto = Entity::new();
+        pluto.set("id", "pluto");
+        pluto.set("name", "Pluto the Pup");
+        let key = EntityKey::data(
+            THINGS_SUBGRAPH_ID.clone(),
+            "Dog".to_owned(),
+            "pluto".to_owned(),
+        );
+        let mut entities = vec![(&key, Cow::from(&pluto))];
+        layout
+            .update(&conn, &dog, &mut entities, 2, &MOCK_STOPWATCH)
+            .expect("Failed to update");
+        layout
+            .delete(&conn, &cat, &vec!["garfield"], 2, &MOCK_STOPWATCH)
+            .expect("Failed to delete");
+
+        let diff = |from, to, first| {
+            layout
+                .entity_diff(&conn, from, to, None, first)
+                .expect("Failed to compute entity diff")
+                .into_iter()
+                .map(|diff| {
+                    (
+                        diff.entity_type,
+                        diff.id,
+                        diff.operation.as_str(),
+                        diff.data.map(|data| data.get("name").cloned()),
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(Vec::<(_, _, _, _)>::new(), diff(0, 1, 100));
+        assert_eq!(
+            vec![
+                ("Cat".to_owned(), "garfield".to_owned(), "DELETED", None),
+                (
+                    "Dog".to_owned(),
+                    "pluto".to_owned(),
+                    "UPDATED",
+                    Some(Some(Value::from("Pluto the Pup")))
+                ),
+            ],
+            diff(1, 2, 100)
+        );
+        assert_eq!(1, diff(1, 2, 1).len());
+
+        // Continue after the first change
+        let diffs = layout
+            .entity_diff(&conn, 1, 2, Some(("Cat", "garfield")), 100)
+            .unwrap();
+        assert_eq!(
+            vec![("Dog", "pluto")],
+            diffs
+                .iter()
+                .map(|diff| (diff.entity_type.as_str(), diff.id.as_str()))
+                .collect::<Vec<_>>()
+        );
+
+        // Entities that did not exist at the first block were created
+        let diffs = layout.entity_diff(&conn, -1, 0, None, 100).unwrap();
+        assert_eq!(2, diffs.len());
+        assert!(diffs
+            .iter()
+            .all(|diff| diff.operation == EntityDiffOperation::Created));
+    });
+}
+
+#[test]
+fn prune() {
+    struct Silent;
+    impl PruneReporter for Silent {}
+
+    run_test(|conn, layout| {
+        insert_pets(&conn, &layout);
+
+        let dog = EntityType::from("Dog");
+        let cat = EntityType::from("Cat");
+
+        // At block 2, rename pluto and remove garfield
+        let mut pluto = Entity::new();
+        pluto.set("id", "pluto");
+        pluto.set("name", "Pluto the Pup");
+        let key = EntityKey::data(
+            THINGS_SUBGRAPH_ID.clone(),
+            "Dog".to_owned(),
+            "pluto".to_owned(),
+        );
+        let mut entities = vec![(&key, Cow::from(&pluto))];
+        layout
+            .update(&conn, &dog, &mut entities, 2, &MOCK_STOPWATCH)
+            .expect("Failed to update");
+        layout
+            .delete(&conn, &cat, &vec!["garfield"], 2, &MOCK_STOPWATCH)
+            .expect("Failed to delete");
+
+        // Versions that are still visible at block 1 are kept
+        assert_eq!(0, layout.prune(&conn, 1, &mut Silent).unwrap());
+        let old = layout.find(&conn, &dog, "pluto", 1).unwrap().unwrap();
+        assert_eq!(Some(&Value::from("Pluto")), old.get("name"));
+
+        // The old version of pluto and garfield are only visible before
+        // block 2
+        assert_eq!(2, layout.prune(&conn, 2, &mut Silent).unwrap());
+        assert_eq!(None, layout.find(&conn, &dog, "pluto", 1).unwrap());
+        assert_eq!(None, layout.find(&conn, &cat, "garfield", 1).unwrap());
+        let new = layout
+            .find(&conn, &dog, "pluto", BLOCK_NUMBER_MAX)
+            .unwrap()
+            .unwrap();
+        assert_eq!(Some(&Value::from("Pluto the Pup")), new.get("name"));
+    });
+}
+
 #[tokio::test]
 async fn layout_cache() {
     run_test_with_conn(|conn| {
@@ -825,6 +992,160 @@ async fn layout_cache() {
     })
 }
 
+#[tokio::test]
+async fn partition() {
+    run_test_with_conn(|conn| {
+        let id = DeploymentHash::new("primaryPartition").unwrap();
+        let _loc = create_test_subgraph(&id, THINGS_GQL);
+        let site = Arc::new(primary_mirror().find_active_site(&id).unwrap().unwrap());
+        let table_name = SqlName::verbatim("scalar".to_string());
+
+        let cache = LayoutCache::new(Duration::from_millis(10));
+        let layout = cache
+            .get(&*LOGGER, &conn, site.clone())
+            .expect("we can get the layout");
+        insert_entity(&conn, &layout, "Scalar", vec![SCALAR_ENTITY.clone()]);
+
+        let table = layout.table(&table_name).unwrap();
+        assert_eq!(None, table.partition_blocks);
+        let rows = conn
+            .transaction(|| layout.partition(&conn, table, 10, 25))
+            .expect("we can partition 'scalar'");
+        assert_eq!(1, rows);
+        sleep(Duration::from_millis(50));
+
+        let layout = cache
+            .get(&*LOGGER, &conn, site.clone())
+            .expect("we can get the layout");
+        let table = layout.table(&table_name).unwrap();
+        assert_eq!(Some(10), table.partition_blocks);
+
+        // Partitions up to block 40 exist already
+        assert_eq!(0, layout.extend_partitions(&conn, table, 25).unwrap());
+
+        // Versions for blocks that have no partition yet go into the
+        // default partition, and move into the partition for their block
+        // once it is created
+        let mut two = SCALAR_ENTITY.clone();
+        two.set("id", "two");
+        let key = EntityKey::data(
+            THINGS_SUBGRAPH_ID.clone(),
+            "Scalar".to_owned(),
+            "two".to_owned(),
+        );
+        let mut entities = vec![(&key, Cow::from(&two))];
+        layout
+            .insert(&conn, &*SCALAR, &mut entities, 45, &MOCK_STOPWATCH)
+            .expect("we can insert into the default partition");
+        assert_eq!(2, layout.extend_partitions(&conn, table, 45).unwrap());
+        let in_default = diesel::select(diesel::dsl::sql::<diesel::sql_types::BigInt>(&format!(
+            "count(*) from \"{}\".\"scalar_pdefault\"",
+            layout.site.namespace
+        )))
+        .get_result::<i64>(conn)
+        .unwrap();
+        assert_eq!(0, in_default);
+        let entity = layout
+            .find(conn, &*SCALAR, "two", 45)
+            .expect("Failed to read Scalar[two]")
+            .unwrap();
+        assert_entity_eq!(scrub(&two), entity);
+
+        let entity = layout
+            .find(conn, &*SCALAR, "one", 0)
+            .expect("Failed to read Scalar[one]")
+            .unwrap();
+        assert_entity_eq!(scrub(&*SCALAR_ENTITY), entity);
+    })
+}
+
+#[test]
+fn aggregate() {
+    fn aggregate(
+        conn: &PgConnection,
+        layout: &Layout,
+        group_by: Option<&str>,
+        attributes: Vec<String>,
+        range: EntityRange,
+    ) -> Vec<(r::Value, r::Value, r::Value)> {
+        let user = EntityType::from("User");
+        layout
+            .aggregate(
+                conn,
+                &user,
+                None,
+                group_by,
+                &attributes,
+                range,
+                BLOCK_NUMBER_MAX,
+            )
+            .expect("aggregation succeeds")
+            .into_iter()
+            .map(|mut row| {
+                let sum = match row.remove("sum") {
+                    Some(r::Value::Object(mut sum)) => sum.remove("age").unwrap(),
+                    _ => panic!("the sum is an object"),
+                };
+                (
+                    row.remove("group").unwrap(),
+                    row.remove("count").unwrap(),
+                    sum,
+                )
+            })
+            .collect()
+    }
+
+    fn row(group: Option<&str>, count: i64, sum: &str) -> (r::Value, r::Value, r::Value) {
+        let group = group
+            .map(|group| r::Value::String(group.to_string()))
+            .unwrap_or(r::Value::Null);
+        (
+            group,
+            r::Value::Int(count),
+            r::Value::String(sum.to_string()),
+        )
+    }
+
+    run_test(|conn, layout| {
+        insert_users(conn, layout);
+        let age = vec!["age".to_string()];
+
+        // Without grouping, there is always exactly one row, even when
+        // skipping
+        let rows = aggregate(conn, layout, None, age.clone(), EntityRange::first(100));
+        assert_eq!(vec![row(None, 3, "138")], rows);
+        let range = EntityRange {
+            first: Some(100),
+            skip: 1,
+        };
+        let rows = aggregate(conn, layout, None, age.clone(), range);
+        assert_eq!(vec![row(None, 3, "138")], rows);
+
+        let rows = aggregate(
+            conn,
+            layout,
+            Some("coffee"),
+            age.clone(),
+            EntityRange::first(100),
+        );
+        assert_eq!(
+            vec![row(Some("false"), 2, "95"), row(Some("true"), 1, "43")],
+            rows
+        );
+        let range = EntityRange {
+            first: Some(1),
+            skip: 1,
+        };
+        let rows = aggregate(conn, layout, Some("coffee"), age.clone(), range);
+        assert_eq!(vec![row(Some("true"), 1, "43")], rows);
+
+        // More attributes than `jsonb_build_object` takes arguments
+        let many = vec!["age".to_string(); 60];
+        let rows = aggregate(conn, layout, None, many, EntityRange::first(100));
+        assert_eq!(vec![row(None, 3, "138")], rows);
+    })
+}
+
 #[test]
 fn conflicting_entity() {
     run_test(|conn, layout| {
@@ -850,6 +1171,16 @@ fn conflicting_entity() {
             .unwrap();
         assert_eq!(None, conflict);
 
+        // Several ids can be checked at once
+        let conflict = layout
+            .conflicting_entities(&conn, &["wilma", id], vec![cat.clone(), ferret.clone()])
+            .unwrap();
+        assert_eq!(Some(("Cat".to_owned(), id.to_owned())), conflict);
+        let conflict = layout
+            .conflicting_entities(&conn, &["wilma", "barney"], vec![cat.clone()])
+            .unwrap();
+        assert_eq!(None, conflict);
+
         // Chairs are not pets
         let chair = EntityType::from("Chair");
         let result = layout.conflicting_entity(
@@ -1301,6 +1632,36 @@ fn check_find() {
                     .first(5),
             );
 
+        // BigInt attributes; they must be compared and sorted as numbers and
+        // not as strings, which would put 883612800 after 2114359200
+        let checker = checker
+            .check(
+                vec!["2", "1"],
+                user_query()
+                    .filter(EntityFilter::GreaterThan(
+                        "seconds_age".to_owned(),
+                        Value::BigInt(BigInt::from(1_000_000_000)),
+                    ))
+                    .asc("seconds_age"),
+            )
+            .check(
+                vec!["3"],
+                user_query().filter(EntityFilter::LessThan(
+                    "seconds_age".to_owned(),
+                    Value::BigInt(BigInt::from(1_000_000_000)),
+                )),
+            )
+            .check(
+                vec!["1", "2", "3"],
+                user_query()
+                    .filter(EntityFilter::LessThan(
+                        "seconds_age".to_owned(),
+                        Value::BigInt(BigInt::from_str("1000000000000000000").unwrap()),
+                    ))
+                    .desc("seconds_age"),
+            )
+            .check(vec!["3", "2", "1"], user_query().asc("seconds_age"));
+
         // bool attributes
         let checker = checker
             .check(
@@ -1625,3 +1986,79 @@ fn text_not_in() {
     text_find(vec!["a2b", "a3"], filter(vec![&a1, &a2]));
     text_find(vec!["a2", "a2b"], filter(vec![&a1, &a3]));
 }
+
+#[test]
+fn page_with_cursor_filter() {
+    fn find(conn: &PgConnection, layout: &Layout, query: EntityQuery) -> Vec<Entity> {
+        layout
+            .query::<Entity>(
+                &*LOGGER,
+                conn,
+                query.collection,
+                query.filter,
+                query.order,
+                query.range,
+                BLOCK_NUMBER_MAX,
+                None,
+            )
+            .expect("layout.query failed to execute query")
+    }
+
+    run_test(|conn, layout| {
+        insert_users(conn, layout);
+        // Add ties for `red` and for `null`
+        for (id, color) in &[("4", Some("red")), ("5", None), ("6", Some("red"))] {
+            insert_user_entity(
+                conn,
+                layout,
+                id,
+                "User",
+                "Tie",
+                "tie@email.com",
+                30,
+                150.0,
+                false,
+                *color,
+                None,
+            );
+        }
+
+        let queries = vec![
+            user_query().asc("favorite_color"),
+            user_query().desc("favorite_color"),
+            user_query().asc("id"),
+            user_query().desc("id"),
+        ];
+        for query in queries {
+            let attr = match &query.order {
+                EntityOrder::Ascending(attr, _) | EntityOrder::Descending(attr, _) => attr.clone(),
+                _ => unreachable!(),
+            };
+            let expected: Vec<_> = find(conn, layout, query.clone())
+                .iter()
+                .map(|entity| entity.id().unwrap())
+                .collect();
+            assert_eq!(6, expected.len());
+
+            // Page through the users two at a time, starting each page
+            // after the last user of the previous one
+            let mut ids = Vec::new();
+            let mut last: Option<Entity> = None;
+            loop {
+                let mut page = query.clone().first(2);
+                if let Some(last) = &last {
+                    let value = last.get(&attr).cloned().unwrap_or(Value::Null);
+                    let id = last.id().unwrap();
+                    page = page.filter(EntityFilter::after(&query.order, value, id));
+                }
+                let entities = find(conn, layout, page);
+                if entities.is_empty() {
+                    break;
+                }
+                ids.extend(entities.iter().map(|entity| entity.id().unwrap()));
+                last = entities.into_iter().last();
+            }
+            assert_eq!(expected, ids, "paging by {:?}", query.order);
+        }
+    });
+}
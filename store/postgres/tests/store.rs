@@ -1147,6 +1147,7 @@ fn mock_data_source() -> graph_chain_ethereum::DataSource {
             language: String::from("wasm/assemblyscript"),
             entities: vec![],
             abis: vec![],
+            fallback_abis: vec![],
             event_handlers: vec![],
             call_handlers: vec![],
             block_handlers: vec![],
@@ -1837,14 +1838,7 @@ fn find_at_block() {
 }
 
 #[test]
-fn cleanup_cached_blocks() {
-    if store_is_sharded() {
-        // When the store is sharded, the setup in main.rs makes sure we
-        // don't ever try to clean up cached blocks
-        println!("store is sharded, skipping test");
-        return;
-    }
-
+fn cleanup_block_cache() {
     run_test(|store, _, _| async move {
         use block_store::*;
         // The test subgraph is at block 2. Since we don't ever delete
@@ -1854,13 +1848,11 @@ fn cleanup_cached_blocks() {
             vec![&*GENESIS_BLOCK, &*BLOCK_ONE, &*BLOCK_TWO, &*BLOCK_THREE],
             NETWORK_NAME,
         );
-        let chain_store = store
-            .block_store()
-            .chain_store(NETWORK_NAME)
-            .expect("fake chain store");
-        let cleaned = chain_store
-            .cleanup_cached_blocks(10)
-            .expect("cleanup succeeds");
+        let cleaned = store.cleanup_block_cache(10).expect("cleanup succeeds");
+        let cleaned = cleaned
+            .into_iter()
+            .find(|(chain, _, _)| chain == NETWORK_NAME)
+            .map(|(_, block, count)| (block, count));
         assert_eq!(Some((2, 1)), cleaned);
     })
 }
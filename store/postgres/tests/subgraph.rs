@@ -1,5 +1,12 @@
+use diesel::{
+    connection::SimpleConnection,
+    dsl::sql,
+    sql_types::{Bool, Text},
+    PgConnection, RunQueryDsl,
+};
 use graph::{
     components::store::{DeploymentLocator, StatusStore},
+    data::graphql::DocumentExt,
     data::subgraph::schema::SubgraphError,
     data::subgraph::schema::SubgraphHealth,
     prelude::EntityChange,
@@ -12,6 +19,7 @@ use graph::{
     prelude::SubgraphVersionSwitchingMode,
     prelude::{futures03, StoreEvent},
     prelude::{CheapClone, DeploymentHash, NodeId, SubgraphStore as _},
+    prelude::{Entity, EntityKey, EntityOperation, Value},
     semver::Version,
 };
 use graph_store_postgres::layout_for_tests::Connection as Primary;
@@ -617,3 +625,253 @@ fn fail_unfail() {
         test_store::remove_subgraphs();
     })
 }
+
+#[test]
+fn raw_manifest() {
+    const NAME: &str = "raw/manifest";
+    const YAML: &str = "specVersion: 0.0.2\nschema:\n  file:\n    /: /ipfs/QmSchema\n";
+
+    run_test_sequentially(|store| async move {
+        remove_subgraphs();
+        let store = store.subgraph_store();
+
+        let id = DeploymentHash::new("rawManifest").unwrap();
+        let schema = Schema::parse(SUBGRAPH_GQL, id.clone()).unwrap();
+        let manifest = SubgraphManifest::<graph_chain_ethereum::Chain> {
+            id: id.clone(),
+            spec_version: Version::new(1, 0, 0),
+            features: Default::default(),
+            description: None,
+            repository: None,
+            schema: schema.clone(),
+            data_sources: vec![],
+            graft: None,
+            templates: vec![],
+            chain: PhantomData,
+        };
+        let mut deployment = SubgraphDeploymentEntity::new(&manifest, false, None);
+        deployment.manifest.raw_yaml = Some(YAML.to_string());
+
+        let name = SubgraphName::new(NAME.to_string()).unwrap();
+        store.create_subgraph(name.clone()).unwrap();
+        store
+            .create_subgraph_deployment(
+                name,
+                &schema,
+                deployment,
+                NodeId::new("left").unwrap(),
+                NETWORK_NAME.to_string(),
+                SubgraphVersionSwitchingMode::Instant,
+            )
+            .unwrap();
+
+        assert_eq!(Some(YAML.to_string()), store.raw_manifest(&id).unwrap());
+
+        test_store::remove_subgraphs();
+    })
+}
+
+#[test]
+fn timeseries_schema_round_trip() {
+    const GQL: &str = "
+        type Trade @entity(timeseries: true) {
+            id: ID!
+            timestamp: Int!
+            amount: BigDecimal!
+        }
+    ";
+
+    run_test_sequentially(|store| async move {
+        remove_subgraphs();
+        let store = store.subgraph_store();
+
+        let id = DeploymentHash::new("timeseriesRoundTrip").unwrap();
+        create_test_subgraph(&id, GQL);
+
+        // Loading the stored schema parses it again, and the stored schema
+        // already contains the rollup types
+        let schema = store.input_schema(&id).unwrap();
+        assert!(schema
+            .document
+            .get_object_type_definition("TradeHour")
+            .is_some());
+        let api = store.api_schema(&id).unwrap();
+        assert!(api
+            .document()
+            .get_object_type_definition("TradeDay")
+            .is_some());
+
+        test_store::remove_subgraphs();
+    })
+}
+
+#[test]
+fn indexing_lock() {
+    /// Whether `conn` can get the indexing lock for `deployment`; the lock
+    /// is released again right away
+    fn try_lock(conn: &PgConnection, deployment: &DeploymentLocator) -> bool {
+        let query = |f: &str| {
+            diesel::select(sql::<Bool>(&format!("{}(3, {})", f, deployment.id)))
+                .get_result::<bool>(conn)
+                .unwrap()
+        };
+        let locked = query("pg_try_advisory_lock");
+        if locked {
+            assert!(query("pg_advisory_unlock"));
+        }
+        locked
+    }
+
+    run_test_sequentially(|store| async move {
+        remove_subgraphs();
+        let store = store.subgraph_store();
+
+        let id = DeploymentHash::new("indexingLock").unwrap();
+        let deployment = create_test_subgraph(&id, SUBGRAPH_GQL);
+
+        let writable = store
+            .cheap_clone()
+            .writable(LOGGER.clone(), deployment.id)
+            .await
+            .unwrap();
+        writable.start_subgraph_deployment(&*LOGGER).unwrap();
+
+        // The lock is held on a connection outside of the pool, so that
+        // none of the pooled connections can take it
+        let conns: Vec<_> = (0..5).map(|_| primary_pg_conn()).collect();
+        for conn in &conns {
+            assert!(!try_lock(conn, &deployment));
+        }
+        drop(conns);
+
+        // Dropping the writable store releases the lock
+        drop(writable);
+        assert!(try_lock(&primary_pg_conn(), &deployment));
+
+        test_store::remove_subgraphs();
+    })
+}
+
+#[test]
+fn immutable_type_in_existing_deployment() {
+    const GQL: &str = "type Transfer @entity(immutable: true) { id: ID!, amount: Int! }";
+
+    fn transfer(id: &DeploymentHash, amount: i32) -> EntityOperation {
+        let mut data = Entity::new();
+        data.set("id", "t1");
+        data.set("amount", amount);
+        EntityOperation::Set {
+            key: EntityKey::data(id.clone(), "Transfer".to_owned(), "t1".to_owned()),
+            data,
+        }
+    }
+
+    run_test_sequentially(|store| async move {
+        remove_subgraphs();
+        let store = store.subgraph_store();
+
+        let id = DeploymentHash::new("immutableExisting").unwrap();
+        let deployment = create_test_subgraph(&id, GQL);
+
+        // Make the deployment look like one that was created before
+        // immutable entity types were supported: its table for `Transfer`
+        // has the layout for mutable entities
+        let conn = primary_pg_conn();
+        let nsp = diesel::select(sql::<Text>(&format!(
+            "name from subgraphs.deployment_schemas where id = {}",
+            deployment.id
+        )))
+        .get_result::<String>(&conn)
+        .unwrap();
+        conn.batch_execute(&format!(
+            "update subgraphs.table_stats set is_immutable = null where deployment = {id};
+             alter table {nsp}.transfer
+               drop constraint transfer_id_key,
+               add exclude using gist (id with =, block_range with &&);",
+            id = deployment.id,
+            nsp = nsp
+        ))
+        .unwrap();
+
+        // Its entities can be changed like those of a mutable type
+        transact_entity_operations(
+            &store,
+            &deployment,
+            BLOCKS[1].clone(),
+            vec![transfer(&id, 1)],
+        )
+        .unwrap();
+        transact_entity_operations(
+            &store,
+            &deployment,
+            BLOCKS[2].clone(),
+            vec![transfer(&id, 2)],
+        )
+        .unwrap();
+
+        let writable = store
+            .cheap_clone()
+            .writable(LOGGER.clone(), deployment.id)
+            .await
+            .unwrap();
+        let key = EntityKey::data(id.clone(), "Transfer".to_owned(), "t1".to_owned());
+        let entity = writable.get(&key).unwrap().unwrap();
+        assert_eq!(Some(&Value::Int(2)), entity.get("amount"));
+
+        test_store::remove_subgraphs();
+    })
+}
+
+#[test]
+fn lost_indexing_lock() {
+    run_test_sequentially(|store| async move {
+        remove_subgraphs();
+        let store = store.subgraph_store();
+
+        let id = DeploymentHash::new("lostIndexingLock").unwrap();
+        let deployment = create_test_subgraph(&id, SUBGRAPH_GQL);
+
+        let writable = store
+            .cheap_clone()
+            .writable(LOGGER.clone(), deployment.id)
+            .await
+            .unwrap();
+        writable.start_subgraph_deployment(&*LOGGER).unwrap();
+
+        // Kill the connection that holds the lock
+        diesel::select(sql::<Bool>(&format!(
+            "bool_and(pg_terminate_backend(pid)) from pg_locks \
+              where locktype = 'advisory' and classid = 3 and objid = {}",
+            deployment.id
+        )))
+        .get_result::<bool>(&primary_pg_conn())
+        .unwrap();
+
+        // Writing fails once because the lock was lost, and succeeds once
+        // the lock has been taken again
+        let res = transact_entity_operations(&store, &deployment, BLOCK_ONE.clone(), vec![]);
+        assert!(res.is_err());
+        transact_entity_operations(&store, &deployment, BLOCK_ONE.clone(), vec![]).unwrap();
+
+        drop(writable);
+        test_store::remove_subgraphs();
+    })
+}
+
+#[test]
+fn rewind_without_blocks() {
+    run_test_sequentially(|store| async move {
+        remove_subgraphs();
+        let store = store.subgraph_store();
+
+        let id = DeploymentHash::new("rewindWithoutBlocks").unwrap();
+        create_test_subgraph(&id, SUBGRAPH_GQL);
+
+        // The deployment has not processed any blocks yet, and rewinding it
+        // must fail instead of panicking
+        let res = store.rewind(id.clone(), GENESIS_PTR.clone());
+        assert!(res.is_err());
+
+        test_store::remove_subgraphs();
+    })
+}
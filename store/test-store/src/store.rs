@@ -517,6 +517,12 @@ pub fn primary_connection() -> graph_store_postgres::layout_for_tests::Connectio
     graph_store_postgres::layout_for_tests::Connection::new(conn)
 }
 
+/// A connection from the pool for the primary shard, for running raw SQL
+pub fn primary_pg_conn(
+) -> diesel::r2d2::PooledConnection<diesel::r2d2::ConnectionManager<PgConnection>> {
+    PRIMARY_POOL.get().unwrap()
+}
+
 pub fn primary_mirror() -> graph_store_postgres::layout_for_tests::Mirror {
     let pool = PRIMARY_POOL.clone();
     let map = HashMap::from_iter(Some((PRIMARY_SHARD.clone(), pool)));